@@ -0,0 +1,32 @@
+#version 450
+
+// Exposed HDR scene color
+layout(set = 0, binding = 0) uniform sampler2D samplerScene;
+
+layout(binding = 1) uniform UniformBufferObject {
+    vec4 params; // x - threshold, y - soft knee, z - intensity
+} ubo;
+
+layout(location = 0) in vec2 inUV;
+
+layout(location = 0) out vec4 outColor;
+
+void main() {
+    // the target is half resolution, 4 bilinear taps average a 4x4 block of the scene
+    vec2 texel = 1.0 / vec2(textureSize(samplerScene, 0));
+    vec3 color = texture(samplerScene, inUV + texel * vec2(-1.0, -1.0)).rgb;
+    color += texture(samplerScene, inUV + texel * vec2(1.0, -1.0)).rgb;
+    color += texture(samplerScene, inUV + texel * vec2(-1.0, 1.0)).rgb;
+    color += texture(samplerScene, inUV + texel * vec2(1.0, 1.0)).rgb;
+    color *= 0.25;
+
+    // quadratic soft knee around the threshold instead of a hard cut
+    float threshold = ubo.params.x;
+    float knee = threshold * ubo.params.y + 1e-4;
+    float brightness = max(color.r, max(color.g, color.b));
+    float soft = clamp(brightness - threshold + knee, 0.0, 2.0 * knee);
+    soft = soft * soft / (4.0 * knee);
+    float contribution = max(soft, brightness - threshold) / max(brightness, 1e-4);
+
+    outColor = vec4(color * contribution, 1.0);
+}
@@ -0,0 +1,27 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+layout(set = 0, binding = 0) uniform sampler2D samplerScene;
+// Top level of the upsample chain
+layout(set = 0, binding = 1) uniform sampler2D samplerBloom;
+
+layout(binding = 2) uniform UniformBufferObject {
+    vec4 params; // x - threshold, y - soft knee, z - intensity
+} ubo;
+
+// Writes to the swapchain or the editor viewport, see OutputEncoding
+layout(constant_id = 0) const uint OUTPUT_TRANSFER = 0;
+layout(constant_id = 1) const uint PAPER_WHITE_NITS = 200;
+
+#include "include/output_encoding.glsl"
+
+layout(location = 0) in vec2 inUV;
+
+layout(location = 0) out vec4 outFragColor;
+
+void main() {
+    vec3 color = texture(samplerScene, inUV).rgb;
+    color += texture(samplerBloom, inUV).rgb * ubo.params.z;
+
+    outFragColor = vec4(encodeOutput(color), 1.0);
+}
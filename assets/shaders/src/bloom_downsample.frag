@@ -0,0 +1,20 @@
+#version 450
+
+// Previous, twice larger level of the chain
+layout(set = 0, binding = 0) uniform sampler2D samplerSource;
+
+layout(location = 0) in vec2 inUV;
+
+layout(location = 0) out vec4 outColor;
+
+void main() {
+    vec2 texel = 1.0 / vec2(textureSize(samplerSource, 0));
+
+    vec3 color = texture(samplerSource, inUV).rgb * 4.0;
+    color += texture(samplerSource, inUV + texel * vec2(-1.0, -1.0)).rgb;
+    color += texture(samplerSource, inUV + texel * vec2(1.0, -1.0)).rgb;
+    color += texture(samplerSource, inUV + texel * vec2(-1.0, 1.0)).rgb;
+    color += texture(samplerSource, inUV + texel * vec2(1.0, 1.0)).rgb;
+
+    outColor = vec4(color / 8.0, 1.0);
+}
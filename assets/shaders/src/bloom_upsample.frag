@@ -0,0 +1,26 @@
+#version 450
+
+// Smaller level already blurred by the chain, and the downsampled level of the target size
+layout(set = 0, binding = 0) uniform sampler2D samplerLower;
+layout(set = 0, binding = 1) uniform sampler2D samplerCurrent;
+
+layout(location = 0) in vec2 inUV;
+
+layout(location = 0) out vec4 outColor;
+
+void main() {
+    // 3x3 tent filter
+    vec2 texel = 1.0 / vec2(textureSize(samplerLower, 0));
+
+    vec3 color = texture(samplerLower, inUV).rgb * 4.0;
+    color += texture(samplerLower, inUV + texel * vec2(-1.0, 0.0)).rgb * 2.0;
+    color += texture(samplerLower, inUV + texel * vec2(1.0, 0.0)).rgb * 2.0;
+    color += texture(samplerLower, inUV + texel * vec2(0.0, -1.0)).rgb * 2.0;
+    color += texture(samplerLower, inUV + texel * vec2(0.0, 1.0)).rgb * 2.0;
+    color += texture(samplerLower, inUV + texel * vec2(-1.0, -1.0)).rgb;
+    color += texture(samplerLower, inUV + texel * vec2(1.0, -1.0)).rgb;
+    color += texture(samplerLower, inUV + texel * vec2(-1.0, 1.0)).rgb;
+    color += texture(samplerLower, inUV + texel * vec2(1.0, 1.0)).rgb;
+
+    outColor = vec4(color / 16.0 + texture(samplerCurrent, inUV).rgb, 1.0);
+}
@@ -0,0 +1,52 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+layout(location = 0) in vec2 inUV;
+
+// x - scale, y - bias of F0
+layout(location = 0) out vec2 outBrdf;
+
+const float PI = 3.14159265359;
+
+#include "include/ggx_sampling.glsl"
+
+const uint SAMPLE_COUNT = 512u;
+
+// Smith-Schlick with k of image based lighting
+float geometrySmith(float NdotV, float NdotL, float roughness)
+{
+    float k = roughness * roughness / 2.0;
+    return NdotV / (NdotV * (1.0 - k) + k) * NdotL / (NdotL * (1.0 - k) + k);
+}
+
+// Second sum of the split-sum approximation, x - cos of view angle, y - roughness.
+// Specular ambient is prefiltered * (F0 * lut.x + lut.y)
+void main()
+{
+    float NdotV = max(inUV.x, 1e-3);
+    float roughness = inUV.y;
+
+    vec3 V = vec3(sqrt(1.0 - NdotV * NdotV), 0.0, NdotV);
+    vec3 N = vec3(0.0, 0.0, 1.0);
+
+    float scale = 0.0;
+    float bias = 0.0;
+    for (uint i = 0u; i < SAMPLE_COUNT; i++) {
+        vec3 H = importanceSampleGGX(hammersley(i, SAMPLE_COUNT), N, roughness);
+        vec3 L = normalize(2.0 * dot(V, H) * H - V);
+
+        float NdotL = max(L.z, 0.0);
+        float NdotH = max(H.z, 0.0);
+        float VdotH = max(dot(V, H), 0.0);
+        if (NdotL > 0.0) {
+            float G = geometrySmith(NdotV, NdotL, roughness);
+            float visibility = G * VdotH / (NdotH * NdotV);
+            float fresnel = pow(1.0 - VdotH, 5.0);
+
+            scale += (1.0 - fresnel) * visibility;
+            bias += fresnel * visibility;
+        }
+    }
+
+    outBrdf = vec2(scale, bias) / float(SAMPLE_COUNT);
+}
@@ -0,0 +1,568 @@
+#define SHADOW_MAP_CASCADE_COUNT 4
+#define USE_PCF true
+
+#include "include/gbuffer_sampler.glsl"
+
+// The `color_input` parameter of the `draw` method.
+layout(set = 0, binding = 0) uniform gbufferSampler samplerAlbedo;
+layout(set = 0, binding = 1) uniform gbufferSampler samplerDepth;
+layout(set = 0, binding = 2) uniform gbufferSampler samplerNormal;
+
+layout(set = 0, binding = 3) uniform sampler2DArray shadowMap;
+
+layout(binding = 4) uniform UniformBufferObject {
+    vec4 cascadeSplits;
+    mat4 view;
+    mat4 cascadeVP[SHADOW_MAP_CASCADE_COUNT];
+    vec4 pointLight; // xyz - position, w - far plane
+    vec4 pointLightParams; // x - near plane, y - intensity
+    vec4 water; // x - water plane height, y - caustics strength, z - time
+    vec4 weather; // x - rain, y - surface wetness
+    vec4 exposure; // x - exposure multiplier of raw units, from EV100
+    vec4 shadowParams; // x - width of blend band between cascades, y - PCF tap spacing in texels, z - PCF kernel half size, w - 1 tints cascades
+    vec4 ambientParams; // x - strength of terrain sky occlusion, y - environment light intensity, z - lod of the roughest prefiltered mip
+    vec4 godRays; // xy - sun uv, z - intensity (0 - off), w - decay
+    mat4 invViewProj; // positions are reconstructed from depth
+    vec4 debugView; // x - G-buffer view: 0 lit scene, 1 albedo, 2 normal, 3 position, 4 depth
+    mat4 rsmViewProj;
+    vec4 indirectParams; // x - strength (0 - off), y - sampling radius in RSM uv, z - sample count, w - sampling radius in world units
+    vec4 sun; // xyz - sun light direction of the shadow cascades, the sun itself is at -xyz
+    vec4 fog; // x - density at the base altitude (0 - off), y - height falloff, z - base altitude, w - distance of the sky
+    vec4 fogColor; // rgb - fog color lit by the sun, w - atmospheric scattering strength (0 - plain fog)
+} ubo;
+
+layout(set = 0, binding = 5) uniform samplerCube pointShadowMap;
+// x - metallic, y - roughness, z - ambient occlusion, w - 1 for PBR lit surfaces
+layout(set = 0, binding = 6) uniform gbufferSampler samplerMaterial;
+// sun disk over the sky, half resolution
+layout(set = 0, binding = 7) uniform sampler2D godRaysOcclusion;
+
+struct Light {
+    vec4 position; // w - kind: 0 point, 1 spot, 2 directional
+    vec4 direction; // w - cos of spot outer angle
+    vec4 color; // rgb - color * raw intensity (lux or cd over sun illuminance), w - radius
+    vec4 params; // x - cos of spot inner angle
+};
+
+// unshadowed dynamic lights
+layout(std430, set = 0, binding = 8) readonly buffer LightBuffer {
+    uvec4 lightCount;
+    Light lights[];
+} lightBuffer;
+
+// image based lighting: cosine convolved environment, environment with GGX prefiltered mips and the split-sum BRDF table
+layout(set = 0, binding = 9) uniform samplerCube irradianceMap;
+layout(set = 0, binding = 10) uniform samplerCube prefilteredMap;
+layout(set = 0, binding = 11) uniform sampler2D brdfLut;
+
+// reflective shadow map of the sun: flux, world normal and world position of the lit texels
+layout(set = 0, binding = 12) uniform sampler2D rsmFlux;
+layout(set = 0, binding = 13) uniform sampler2D rsmNormal;
+layout(set = 0, binding = 14) uniform sampler2D rsmPosition;
+
+layout(location = 0) out vec4 outFragcolor;
+layout(constant_id = 0) const int NUM_SAMPLES = 2;
+
+layout (location = 0) in vec2 inUV;
+
+#include "include/depth.glsl"
+#include "include/normal_encoding.glsl"
+#include "include/fog.glsl"
+
+
+const float PI = 3.14159265359;
+// matches brightness of the old lambert-only sun
+const float SUN_RADIANCE = 1.5 * PI;
+// range of the RGBM sky in the albedo attachment, as in skybox.frag
+const float SKY_RANGE = 16.0;
+
+const mat4 biasMat = mat4(
+0.5, 0.0, 0.0, 0.0,
+0.0, 0.5, 0.0, 0.0,
+0.0, 0.0, 1.0, 0.0,
+0.5, 0.5, 0.0, 1.0
+);
+
+
+float textureProj(vec4 posInLightView, vec2 offset, uint cascadeIndex) {
+    float shadow = 1.0;
+    float bias = 0.005;
+    float ambient = 0.3;
+
+    if (posInLightView.z > -1.0 && posInLightView.z < 1.0) {
+        float dist = texture(shadowMap, vec3(posInLightView.st + offset, cascadeIndex)).r;
+
+        if (posInLightView.w > 0 && dist < posInLightView.z - bias) {
+            shadow = ambient;
+        }
+    }
+    return shadow;
+
+}
+
+
+vec3 cameraPosition()
+{
+    return inverse(ubo.view)[3].xyz;
+}
+
+vec3 sunDirection()
+{
+    return normalize(ubo.sun.xyz);
+}
+
+// Cook-Torrance: GGX distribution, Smith-Schlick geometry, Schlick fresnel. Radiance scale is left to the caller
+vec3 cookTorrance(vec3 N, vec3 V, vec3 L, vec3 albedo, float metallic, float roughness)
+{
+    vec3 H = normalize(V + L);
+    float NdotL = max(dot(N, L), 0.0);
+    float NdotV = max(dot(N, V), 1e-4);
+    float NdotH = max(dot(N, H), 0.0);
+
+    float a = roughness * roughness;
+    float a2 = a * a;
+    float d = NdotH * NdotH * (a2 - 1.0) + 1.0;
+    float D = a2 / (PI * d * d);
+
+    float k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+    float G = NdotV / (NdotV * (1.0 - k) + k) * NdotL / (NdotL * (1.0 - k) + k);
+
+    vec3 F0 = mix(vec3(0.04), albedo, metallic);
+    vec3 F = F0 + (1.0 - F0) * pow(1.0 - max(dot(H, V), 0.0), 5.0);
+
+    vec3 specular = D * G * F / (4.0 * NdotV * NdotL + 1e-4);
+    vec3 diffuse = (1.0 - F) * (1.0 - metallic) * albedo / PI;
+
+    return (diffuse + specular) * NdotL;
+}
+
+vec3 calculateLighting(vec3 pos, vec3 normal, vec4 albedo, vec4 material)
+{
+    if (normal == vec3(0.0)) {
+        // RGBM sky of skybox.frag
+        return albedo.rgb * albedo.a * SKY_RANGE;
+    }
+
+    vec3 L = sunDirection();
+    if (material.w > 0.5) {
+        vec3 V = normalize(cameraPosition() - pos);
+        return cookTorrance(normalize(normal), V, L, albedo.rgb, material.x, max(material.y, 0.04)) * SUN_RADIANCE;
+    }
+
+    float light_percent = dot(L, normal);
+    light_percent = max(light_percent, 0.0);
+
+    return albedo.rgb * 1.5 * light_percent;
+}
+
+// Split-sum image based lighting of PBR surfaces, flat ambient for the rest
+vec3 calculateAmbient(vec3 pos, vec3 normal, vec4 albedo, vec4 material)
+{
+    if (normal == vec3(0.0) || material.w < 0.5) {
+        return albedo.rgb * 0.4;
+    }
+
+    vec3 N = normalize(normal);
+    vec3 V = normalize(cameraPosition() - pos);
+    float NdotV = max(dot(N, V), 1e-4);
+    float metallic = material.x;
+    float roughness = max(material.y, 0.04);
+
+    // roughness aware fresnel, rough surfaces don't get bright rims
+    vec3 F0 = mix(vec3(0.04), albedo.rgb, metallic);
+    vec3 F = F0 + (max(vec3(1.0 - roughness), F0) - F0) * pow(1.0 - NdotV, 5.0);
+
+    vec3 diffuse = texture(irradianceMap, N).rgb * albedo.rgb * (1.0 - F) * (1.0 - metallic);
+
+    vec3 prefiltered = textureLod(prefilteredMap, reflect(-V, N), roughness * ubo.ambientParams.z).rgb;
+    vec2 brdf = texture(brdfLut, vec2(NdotV, roughness)).rg;
+    vec3 specular = prefiltered * (F * brdf.x + brdf.y);
+
+    return (diffuse + specular) * ubo.ambientParams.y;
+}
+
+// Depth written by the cube face projection for the given light to fragment vector
+float pointLightDepth(vec3 lightToFrag)
+{
+    vec3 absVec = abs(lightToFrag);
+    float localZ = max(absVec.x, max(absVec.y, absVec.z));
+
+    float n = ubo.pointLightParams.x;
+    float f = ubo.pointLight.w;
+    return f / (f - n) - (f * n) / ((f - n) * localZ);
+}
+
+vec3 calculatePointLight(vec3 pos, vec3 normal, vec4 albedo, vec4 material)
+{
+    vec3 lightToFrag = pos - ubo.pointLight.xyz;
+    float dist = length(lightToFrag);
+    if (normal == vec3(0.0) || dist >= ubo.pointLight.w) {
+        return vec3(0.0);
+    }
+
+    float bias = 0.0005;
+    float closest = texture(pointShadowMap, lightToFrag).r;
+    if (closest < pointLightDepth(lightToFrag) - bias) {
+        return vec3(0.0);
+    }
+
+    float attenuation = 1.0 - dist / ubo.pointLight.w;
+    if (material.w > 0.5) {
+        vec3 V = normalize(cameraPosition() - pos);
+        vec3 lit = cookTorrance(normalize(normal), V, -lightToFrag / dist, albedo.rgb, material.x, max(material.y, 0.04));
+        return lit * PI * ubo.pointLightParams.y * attenuation * attenuation;
+    }
+
+    float light_percent = max(dot(-lightToFrag / dist, normal), 0.0);
+
+    return albedo.rgb * ubo.pointLightParams.y * light_percent * attenuation * attenuation;
+}
+
+vec3 calculateDynamicLights(vec3 pos, vec3 normal, vec4 albedo, vec4 material)
+{
+    if (normal == vec3(0.0)) {
+        return vec3(0.0);
+    }
+
+    vec3 N = normalize(normal);
+    vec3 V = normalize(cameraPosition() - pos);
+    vec3 result = vec3(0.0);
+
+    for (uint i = 0; i < lightBuffer.lightCount.x; i++) {
+        Light light = lightBuffer.lights[i];
+
+        vec3 L;
+        float attenuation = 1.0;
+        if (light.position.w == 2.0) {
+            L = -light.direction.xyz;
+        } else {
+            vec3 toLight = light.position.xyz - pos;
+            float dist = length(toLight);
+            if (dist >= light.color.w) {
+                continue;
+            }
+            L = toLight / dist;
+
+            // inverse square law windowed to zero at the radius
+            float falloff = clamp(1.0 - pow(dist / light.color.w, 4.0), 0.0, 1.0);
+            attenuation = falloff * falloff / max(dist * dist, 0.01);
+
+            if (light.position.w == 1.0) {
+                float cosAngle = dot(-L, light.direction.xyz);
+                attenuation *= smoothstep(light.direction.w, light.params.x, cosAngle);
+            }
+        }
+
+        if (attenuation <= 0.0) {
+            continue;
+        }
+
+        if (material.w > 0.5) {
+            result += cookTorrance(N, V, L, albedo.rgb, material.x, max(material.y, 0.04)) * PI * light.color.rgb * attenuation;
+        } else {
+            result += albedo.rgb * max(dot(N, L), 0.0) * light.color.rgb * attenuation;
+        }
+    }
+
+    return result;
+}
+
+// Procedural caustics pattern: a few layers of warped interference lines
+float causticsPattern(vec2 uv, float time)
+{
+    vec2 p = uv;
+    float value = 0.0;
+    for (int i = 0; i < 3; i++) {
+        float t = time * (1.0 + 0.3 * float(i));
+        p += vec2(sin(p.y * 1.7 + t), cos(p.x * 1.3 - t * 0.8)) * 0.5;
+        value += 1.0 - abs(sin(p.x + p.y));
+    }
+
+    return pow(value / 3.0, 6.0);
+}
+
+vec3 calculateCaustics(vec3 pos, vec3 normal, vec4 albedo)
+{
+    float depth = pos.y - ubo.water.x;
+    if (normal == vec3(0.0) || depth <= 0.0 || ubo.water.y <= 0.0) {
+        return vec3(0.0);
+    }
+
+    // Pattern is projected straight down from the water plane and blurs out with depth
+    float caustics = causticsPattern(pos.xz * 4.0, ubo.water.z);
+    float fade = exp(-depth * 1.5);
+
+    return albedo.rgb * caustics * ubo.water.y * fade;
+}
+
+// Wet surfaces are darker and get a sharp highlight of the sun
+vec3 applyWetness(vec3 color, vec3 pos, vec3 normal, vec4 albedo)
+{
+    float wetness = ubo.weather.y;
+    if (normal == vec3(0.0) || wetness <= 0.0) {
+        return color;
+    }
+
+    vec3 viewNormal = normalize(mat3(ubo.view) * normal);
+    vec3 viewLight = normalize(mat3(ubo.view) * sunDirection());
+    vec3 viewDir = normalize(-(ubo.view * vec4(pos, 1.0)).xyz);
+    vec3 halfDir = normalize(viewLight + viewDir);
+    float specular = pow(max(dot(viewNormal, halfDir), 0.0), 64.0) * wetness;
+
+    return color * mix(1.0, 0.6, wetness) + vec3(specular);
+}
+
+float hash(vec2 p)
+{
+    return fract(sin(dot(p, vec2(127.1, 311.7))) * 43758.5453);
+}
+
+// Falling streaks in screen space, one random drop per column cell
+float rainStreaks(vec2 uv, float time)
+{
+    float rain = ubo.weather.x;
+    if (rain <= 0.0) {
+        return 0.0;
+    }
+
+    vec2 grid = vec2(160.0, 6.0);
+    float column = floor(uv.x * grid.x);
+    float speed = 1.5 + hash(vec2(column, 0.0));
+    vec2 cell = vec2(uv.x * grid.x, (uv.y - time * speed) * grid.y + hash(vec2(column, 1.0)));
+
+    float drop = hash(vec2(column, floor(cell.y)));
+    if (drop > rain) {
+        return 0.0;
+    }
+
+    float along = fract(cell.y);
+    float across = abs(fract(cell.x) - 0.5);
+    return smoothstep(0.15, 0.0, across) * smoothstep(0.0, 0.8, along) * step(along, 0.8) * 0.35;
+}
+
+const int GOD_RAY_SAMPLES = 48;
+const vec3 GOD_RAY_COLOR = vec3(1.0, 0.9, 0.7);
+
+// Radial blur of the occlusion buffer from the fragment towards the sun
+float godRays(vec2 uv)
+{
+    if (ubo.godRays.z <= 0.0) {
+        return 0.0;
+    }
+
+    vec2 delta = (uv - ubo.godRays.xy) / float(GOD_RAY_SAMPLES) * 0.9;
+    vec2 samplePos = uv;
+    float weight = 1.0;
+    float illumination = 0.0;
+    for (int i = 0; i < GOD_RAY_SAMPLES; i++) {
+        samplePos -= delta;
+        illumination += texture(godRaysOcclusion, samplePos).r * weight;
+        weight *= ubo.godRays.w;
+    }
+
+    return illumination / float(GOD_RAY_SAMPLES) * ubo.godRays.z;
+}
+
+// Height fog over the lit `color` of `pos`, see include/fog.glsl
+vec3 applyFog(vec3 color, vec3 pos)
+{
+    return applyHeightFog(color, pos, cameraPosition(), sunDirection(), SUN_RADIANCE, ubo.fog, ubo.fogColor);
+}
+
+const float GOLDEN_ANGLE = 2.39996323;
+
+// One bounce diffuse light from the texels of the reflective shadow map around the fragment, every texel is
+// a small area light. Samples cover a disc evenly, visibility of the texels is not checked
+vec3 calculateIndirect(vec3 pos, vec3 normal, vec4 albedo, vec4 material)
+{
+    if (normal == vec3(0.0) || ubo.indirectParams.x <= 0.0) {
+        return vec3(0.0);
+    }
+
+    vec4 posInLightView = biasMat * ubo.rsmViewProj * vec4(pos, 1.0);
+    vec3 N = normalize(normal);
+    int sampleCount = int(ubo.indirectParams.z);
+    // per pixel rotation of the spiral trades banding for noise
+    float rotation = hash(gl_FragCoord.xy) * 2.0 * PI;
+
+    vec3 irradiance = vec3(0.0);
+    for (int i = 0; i < sampleCount; i++) {
+        float r = sqrt((float(i) + 0.5) / float(sampleCount));
+        float angle = float(i) * GOLDEN_ANGLE + rotation;
+        vec2 uv = posInLightView.xy + vec2(cos(angle), sin(angle)) * r * ubo.indirectParams.y;
+
+        vec3 texelNormal = texture(rsmNormal, uv).xyz;
+        if (texelNormal == vec3(0.0)) {
+            continue;
+        }
+
+        vec3 toFragment = pos - texture(rsmPosition, uv).xyz;
+        float dist2 = max(dot(toFragment, toFragment), 0.05);
+        float cosines = max(dot(texelNormal, toFragment), 0.0) * max(dot(N, -toFragment), 0.0);
+        irradiance += texture(rsmFlux, uv).rgb * cosines / (dist2 * dist2);
+    }
+
+    // every sample stands for pi * radius^2 / count of the surface, exitance over pi is its radiance
+    float radius = ubo.indirectParams.w;
+    irradiance *= radius * radius / float(max(sampleCount, 1));
+
+    return albedo.rgb / PI * (1.0 - material.x * material.w) * irradiance * ubo.indirectParams.x;
+}
+
+float filterPCF(vec4 posInLightView, uint cascadeIndex)
+{
+    ivec2 texDim = textureSize(shadowMap, 0).xy;
+    float scale = ubo.shadowParams.y;
+    float dx = scale * 1.0 / float(texDim.x);
+    float dy = scale * 1.0 / float(texDim.y);
+
+    float shadowFactor = 0.0;
+    int count = 0;
+    int range = int(ubo.shadowParams.z);
+
+    for (int x = -range; x <= range; x++) {
+        for (int y = -range; y <= range; y++) {
+            shadowFactor += textureProj(posInLightView, vec2(dx*x, dy*y), cascadeIndex);
+            count++;
+        }
+    }
+    return shadowFactor / count;
+}
+
+
+float cascadeShadow(vec3 pos, uint cascadeIndex)
+{
+    vec4 posInLightView = (biasMat * ubo.cascadeVP[cascadeIndex]) * vec4(pos, 1.0);
+    posInLightView /= posInLightView.w;
+
+    if (USE_PCF && ubo.shadowParams.z > 0.0) {
+        return filterPCF(posInLightView, cascadeIndex);
+    }
+    return textureProj(posInLightView, vec2(0.0), cascadeIndex);
+}
+
+// Raw G-buffer of the first sample: normals mapped to [0, 1], positions repeat every world unit
+vec3 gbufferView(uint mode, ivec2 UV, ivec2 attDim)
+{
+    vec3 normal = decodeGBufferNormal(gbufferFetch(samplerNormal, UV, 0));
+    float depth = gbufferFetch(samplerDepth, UV, 0).r;
+    vec3 pos = reconstructWorldPosition((vec2(UV) + 0.5) / vec2(attDim), depth, ubo.invViewProj);
+
+    switch (mode) {
+        case 1: return gbufferFetch(samplerAlbedo, UV, 0).rgb;
+        case 2: return normal == vec3(0.0) ? vec3(0.0) : normalize(normal) * 0.5 + 0.5;
+        case 3: return normal == vec3(0.0) ? vec3(0.0) : fract(pos);
+        default: return vec3(1.0 - exp(-linearViewDepth(pos, ubo.view) * 0.05));
+    }
+}
+
+void main() {
+    ivec2 attDim = gbufferSize(samplerAlbedo);
+    ivec2 UV = ivec2(inUV * attDim);
+
+    uint debugView = uint(ubo.debugView.x);
+    if (debugView != 0u) {
+        outFragcolor = vec4(gbufferView(debugView, UV, attDim), 1.0);
+        return;
+    }
+
+    vec3 ambient = vec3(0.0);
+    vec3 fragColor = vec3(0.0);
+    float shadow = 0.0;
+    vec3 pointColor = vec3(0.0);
+    vec3 indirect = vec3(0.0);
+    float skyVisibility = 0.0;
+    float materialOcclusion = 0.0;
+
+    // Calualte lighting for every MSAA sample
+    for (int i = 0; i < NUM_SAMPLES; i++)
+    {
+        float depth = gbufferFetch(samplerDepth, UV, i).r;
+        vec3 pos = reconstructWorldPosition((vec2(UV) + 0.5) / vec2(attDim), depth, ubo.invViewProj);
+
+        vec4 normalSample = gbufferFetch(samplerNormal, UV, i);
+        vec3 normal = decodeGBufferNormal(normalSample);
+        vec4 albedo = gbufferFetch(samplerAlbedo, UV, i);
+        vec4 material = gbufferFetch(samplerMaterial, UV, i);
+        skyVisibility += normalSample.b;
+        materialOcclusion += mix(1.0, material.z, material.w);
+
+        ambient += calculateAmbient(pos, normal, albedo, material);
+        vec3 outSampleColor = calculateLighting(pos, normal, albedo, material);
+        vec3 pointLightColor = calculatePointLight(pos, normal, albedo, material);
+        pointLightColor += calculateDynamicLights(pos, normal, albedo, material);
+        // bounce light is smooth and expensive, the first sample is enough
+        if (i == 0) {
+            indirect = calculateIndirect(pos, normal, albedo, material);
+        }
+        outSampleColor += calculateCaustics(pos, normal, albedo);
+        outSampleColor = applyWetness(outSampleColor, pos, normal, albedo);
+
+        vec3 view_pos = (ubo.view * vec4(pos, 1.0)).xyz;
+
+        // Get cascade index for the current fragment's view position
+        uint shadowCascadeIndex = 0;
+        for (uint i = 0; i < SHADOW_MAP_CASCADE_COUNT - 1; ++i) {
+            if (view_pos.z < ubo.cascadeSplits[i]) {
+                shadowCascadeIndex = i + 1;
+            }
+        }
+
+        if (ubo.shadowParams.w > 0.5) {
+            switch (shadowCascadeIndex) {
+                case 0 :
+                outSampleColor.rgb *= vec3(1.0f, 0.25f, 0.25f);
+                break;
+                case 1 :
+                outSampleColor.rgb *= vec3(0.25f, 1.0f, 0.25f);
+                break;
+                case 2 :
+                outSampleColor.rgb *= vec3(0.25f, 0.25f, 1.0f);
+                break;
+                case 3 :
+                outSampleColor.rgb *= vec3(1.0f, 1.0f, 0.25f);
+                break;
+            }
+        }
+        fragColor += outSampleColor;
+        pointColor += pointLightColor;
+
+        float sampleShadow = cascadeShadow(pos, shadowCascadeIndex);
+
+        // Near the far end of the cascade fade into the next one to hide the seam
+        float band = ubo.shadowParams.x;
+        if (band > 0.0 && shadowCascadeIndex < SHADOW_MAP_CASCADE_COUNT - 1) {
+            float distToSplit = view_pos.z - ubo.cascadeSplits[shadowCascadeIndex];
+            if (distToSplit < band) {
+                float nextShadow = cascadeShadow(pos, shadowCascadeIndex + 1);
+                sampleShadow = mix(nextShadow, sampleShadow, smoothstep(0.0, band, distToSplit));
+            }
+        }
+        shadow += sampleShadow;
+    }
+
+    shadow /= NUM_SAMPLES;
+    float ambientOcclusion = mix(1.0, skyVisibility / float(NUM_SAMPLES), ubo.ambientParams.x);
+    ambientOcclusion *= materialOcclusion / float(NUM_SAMPLES);
+    fragColor = (ambient / float(NUM_SAMPLES) * ambientOcclusion) + fragColor / float(NUM_SAMPLES);
+
+    vec3 color = fragColor * shadow + pointColor / float(NUM_SAMPLES) + indirect * ambientOcclusion;
+
+    // fog of the first sample, the sky is a surface at the sky distance
+    vec2 pixelUV = (vec2(UV) + 0.5) / vec2(attDim);
+    vec3 fogPos = reconstructWorldPosition(pixelUV, gbufferFetch(samplerDepth, UV, 0).r, ubo.invViewProj);
+    if (decodeGBufferNormal(gbufferFetch(samplerNormal, UV, 0)) == vec3(0.0)) {
+        // a depth between the planes of any depth mode gives the view direction
+        vec3 camera = cameraPosition();
+        fogPos = camera + normalize(reconstructWorldPosition(pixelUV, 0.5, ubo.invViewProj) - camera) * ubo.fog.w;
+    }
+    color = applyFog(color, fogPos);
+
+    color += GOD_RAY_COLOR * godRays(inUV);
+    color *= ubo.exposure.x;
+
+    // overlays below are not exposed to stay readable in dark scenes
+    color = mix(color, vec3(0.7, 0.75, 0.8), rainStreaks(inUV, ubo.water.z));
+
+    outFragcolor = vec4(color, 1.0);
+}
@@ -0,0 +1,35 @@
+// Level 0 of the Hi-Z pyramid from the G-buffer depth, one invocation per texel covering 2x2 pixels.
+// Keeps the farthest depth of the pixels and all their samples, so the pyramid never hides what is visible.
+// Requires `#extension GL_GOOGLE_include_directive : require` in the including shader.
+#include "../include/gbuffer_sampler.glsl"
+
+layout(local_size_x = 8, local_size_y = 8) in;
+
+layout(constant_id = 0) const uint SAMPLE_COUNT = 1;
+layout(constant_id = 1) const bool REVERSE_Z = false;
+
+layout(binding = 0) uniform gbufferSampler depthTex;
+layout(binding = 1, r32f) uniform writeonly image2D dst;
+
+float farthest(float a, float b) {
+    return REVERSE_Z ? min(a, b) : max(a, b);
+}
+
+void main() {
+    ivec2 texel = ivec2(gl_GlobalInvocationID.xy);
+    if (any(greaterThanEqual(texel, imageSize(dst)))) {
+        return;
+    }
+
+    ivec2 lastPixel = gbufferSize(depthTex) - 1;
+    // the near plane
+    float depth = REVERSE_Z ? 1.0 : 0.0;
+    for (int i = 0; i < 4; i++) {
+        ivec2 pixel = min(texel * 2 + ivec2(i & 1, i >> 1), lastPixel);
+        for (int sampleIdx = 0; sampleIdx < int(SAMPLE_COUNT); sampleIdx++) {
+            depth = farthest(depth, gbufferFetch(depthTex, pixel, sampleIdx).r);
+        }
+    }
+
+    imageStore(dst, texel, vec4(depth));
+}
@@ -0,0 +1,23 @@
+// Lines are drawn in the HDR pass without a depth attachment, the G-buffer depth is tested here.
+// Hidden parts stay visible but faded, so shapes inside the geometry can still be followed
+
+#include "../include/gbuffer_sampler.glsl"
+
+layout(binding = 1) uniform gbufferSampler samplerDepth;
+
+layout(constant_id = 0) const bool REVERSE_Z = false;
+
+layout(location = 0) in vec4 inColor;
+
+layout(location = 0) out vec4 outColor;
+
+const float HIDDEN_ALPHA = 0.25;
+
+void main() {
+    float sceneDepth = gbufferFetch(samplerDepth, ivec2(gl_FragCoord.xy), 0).r;
+    bool hidden = REVERSE_Z ? gl_FragCoord.z < sceneDepth : gl_FragCoord.z > sceneDepth;
+
+    // premultiplied alpha, the pipeline blends with ONE, ONE_MINUS_SRC_ALPHA
+    float alpha = inColor.a * (hidden ? HIDDEN_ALPHA : 1.0);
+    outColor = vec4(inColor.rgb * alpha, alpha);
+}
@@ -0,0 +1,65 @@
+// Decals are drawn as boxes over the G-buffer albedo. The surface under a pixel is reconstructed
+// from G-buffer depth and the texture is projected onto it along the local Y axis of the box
+
+#include "../include/gbuffer_sampler.glsl"
+#include "../include/depth.glsl"
+
+struct Decal {
+    mat4 model;
+    mat4 invModel;
+    vec4 params; // x - opacity
+};
+
+layout(set = 0, binding = 0) uniform gbufferSampler samplerDepth;
+
+layout(set = 0, binding = 1) uniform UniformBufferObject {
+    mat4 viewProj;
+    mat4 invViewProj;
+    vec4 cameraPosition;
+} ubo;
+
+layout(std430, set = 0, binding = 2) readonly buffer Decals {
+    Decal decals[];
+};
+
+layout(set = 1, binding = 0) uniform sampler2D decalTexture;
+
+layout(constant_id = 0) const bool REVERSE_Z = false;
+
+layout(location = 0) flat in uint inDecal;
+layout(location = 1) flat in float inFacing;
+
+layout(location = 0) out vec4 outAlbedo;
+
+// share of the box depth at each end where the decal fades out
+const float EDGE_FADE = 0.2;
+
+void main() {
+    if (inFacing > 0.0) {
+        discard;
+    }
+
+    // depth of the first sample, with MSAA the decal covers whole pixels
+    ivec2 pixel = ivec2(gl_FragCoord.xy);
+    float depth = gbufferFetch(samplerDepth, pixel, 0).r;
+    bool farPlane = REVERSE_Z ? depth <= 0.0 : depth >= 1.0;
+    if (farPlane) {
+        discard;
+    }
+
+    vec2 uv = gl_FragCoord.xy / vec2(gbufferSize(samplerDepth));
+    vec3 worldPos = reconstructWorldPosition(uv, depth, ubo.invViewProj);
+
+    Decal decal = decals[inDecal];
+    vec3 local = (decal.invModel * vec4(worldPos, 1.0)).xyz;
+    if (any(greaterThan(abs(local), vec3(0.5)))) {
+        discard;
+    }
+
+    vec4 color = texture(decalTexture, local.xz + 0.5);
+    float fade = 1.0 - smoothstep(0.5 - EDGE_FADE, 0.5, abs(local.y));
+
+    // premultiplied alpha, the pipeline blends with ONE, ONE_MINUS_SRC_ALPHA
+    float alpha = color.a * decal.params.x * fade;
+    outAlbedo = vec4(color.rgb * alpha, alpha);
+}
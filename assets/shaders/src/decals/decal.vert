@@ -0,0 +1,57 @@
+#version 450
+
+// Unit box of a decal, the decal transform places and scales it. Vertices come from the tables below,
+// there is no vertex buffer
+
+struct Decal {
+    mat4 model;
+    mat4 invModel;
+    vec4 params; // x - opacity
+};
+
+layout(set = 0, binding = 1) uniform UniformBufferObject {
+    mat4 viewProj;
+    mat4 invViewProj;
+    vec4 cameraPosition;
+} ubo;
+
+layout(std430, set = 0, binding = 2) readonly buffer Decals {
+    Decal decals[];
+};
+
+layout(location = 0) flat out uint outDecal;
+layout(location = 1) flat out float outFacing;
+
+const vec3 CORNERS[8] = vec3[](
+    vec3(-0.5, -0.5, -0.5), vec3(0.5, -0.5, -0.5), vec3(-0.5, 0.5, -0.5), vec3(0.5, 0.5, -0.5),
+    vec3(-0.5, -0.5, 0.5), vec3(0.5, -0.5, 0.5), vec3(-0.5, 0.5, 0.5), vec3(0.5, 0.5, 0.5)
+);
+
+// two triangles per face in the order of FACE_NORMALS
+const int INDICES[36] = int[](
+    1, 3, 7, 1, 7, 5,
+    0, 6, 2, 0, 4, 6,
+    2, 6, 7, 2, 7, 3,
+    0, 1, 5, 0, 5, 4,
+    4, 5, 7, 4, 7, 6,
+    0, 2, 3, 0, 3, 1
+);
+
+const vec3 FACE_NORMALS[6] = vec3[](
+    vec3(1.0, 0.0, 0.0), vec3(-1.0, 0.0, 0.0),
+    vec3(0.0, 1.0, 0.0), vec3(0.0, -1.0, 0.0),
+    vec3(0.0, 0.0, 1.0), vec3(0.0, 0.0, -1.0)
+);
+
+void main() {
+    Decal decal = decals[gl_InstanceIndex];
+    vec3 corner = CORNERS[INDICES[gl_VertexIndex]];
+
+    // faces turned to the camera are dropped in the fragment shader, the far ones cover the box
+    // even with the camera inside it, whatever the winding after projection
+    vec3 localCamera = (decal.invModel * vec4(ubo.cameraPosition.xyz, 1.0)).xyz;
+    outFacing = dot(FACE_NORMALS[gl_VertexIndex / 6], localCamera - corner);
+    outDecal = gl_InstanceIndex;
+
+    gl_Position = ubo.viewProj * decal.model * vec4(corner, 1.0);
+}
@@ -0,0 +1,6 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+// Fragment stage of depth only draws, e.g. the depth pre-pass: depth comes from the rasterizer
+void main() {
+}
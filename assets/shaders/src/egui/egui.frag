@@ -1,4 +1,5 @@
 #version 450
+#extension GL_GOOGLE_include_directive : require
 
 layout(location = 0) in vec4 inColor;
 layout(location = 1) in vec2 inUV;
@@ -7,7 +8,14 @@ layout(location = 0) out vec4 outColor;
 
 layout(binding = 0, set = 0) uniform sampler2D font_texture;
 
+// Textures already encoded for the output (the editor viewport) are drawn with HARDWARE_SRGB
+layout(constant_id = 0) const uint OUTPUT_TRANSFER = 0;
+layout(constant_id = 1) const uint PAPER_WHITE_NITS = 200;
+
+#include "../include/output_encoding.glsl"
+
 void main() {
 //    outColor = vec4(1.0, 1.0, 1.0, 1.0);
-    outColor = inColor * texture(font_texture, inUV);
+    vec4 color = inColor * texture(font_texture, inUV);
+    outColor = vec4(encodeOutput(color.rgb), color.a);
 }
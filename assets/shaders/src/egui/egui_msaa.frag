@@ -1,4 +1,5 @@
 #version 450
+#extension GL_GOOGLE_include_directive : require
 
 layout(location = 0) in vec4 inColor;
 layout(location = 1) in vec2 inUV;
@@ -8,6 +9,10 @@ layout(location = 0) out vec4 outColor;
 layout(binding = 0, set = 0) uniform sampler2DMS user_texture;
 
 layout(constant_id = 0) const int NUM_SAMPLES = 8;
+layout(constant_id = 1) const uint OUTPUT_TRANSFER = 0;
+layout(constant_id = 2) const uint PAPER_WHITE_NITS = 200;
+
+#include "../include/output_encoding.glsl"
 
 
 vec4 resolve(sampler2DMS tex, ivec2 uv)
@@ -27,5 +32,6 @@ void main() {
     ivec2 attDim = textureSize(user_texture);
     ivec2 UV = ivec2(inUV * attDim);
 
-    outColor = inColor * resolve(user_texture, UV);
+    vec4 color = inColor * resolve(user_texture, UV);
+    outColor = vec4(encodeOutput(color.rgb), color.a);
 }
@@ -0,0 +1,50 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+// Equirectangular RGBE image, decoded and filtered here: hardware filtering would mix the exponents
+layout(set = 0, binding = 0) uniform sampler2D equirect;
+
+layout(push_constant) uniform Face {
+    uint face;
+} pc;
+
+layout(location = 0) in vec2 inUV;
+
+layout(location = 0) out vec4 outColor;
+
+#include "include/cube_face.glsl"
+
+vec3 decodeRgbe(vec4 rgbe)
+{
+    if (rgbe.a == 0.0) {
+        return vec3(0.0);
+    }
+
+    return rgbe.rgb * 255.0 * exp2(rgbe.a * 255.0 - 136.0);
+}
+
+vec3 fetch(ivec2 texel, ivec2 size)
+{
+    // longitude wraps around, latitude is clamped at the poles
+    texel.x = (texel.x % size.x + size.x) % size.x;
+    texel.y = clamp(texel.y, 0, size.y - 1);
+
+    return decodeRgbe(texelFetch(equirect, texel, 0));
+}
+
+void main()
+{
+    vec3 dir = cubeFaceDirection(pc.face, inUV);
+    // +Y is the top row of the image, as the top face of six face skyboxes
+    vec2 uv = vec2(atan(dir.z, dir.x) / (2.0 * PI) + 0.5, acos(clamp(dir.y, -1.0, 1.0)) / PI);
+
+    ivec2 size = textureSize(equirect, 0);
+    vec2 texel = uv * vec2(size) - 0.5;
+    ivec2 base = ivec2(floor(texel));
+    vec2 f = fract(texel);
+
+    vec3 top = mix(fetch(base, size), fetch(base + ivec2(1, 0), size), f.x);
+    vec3 bottom = mix(fetch(base + ivec2(0, 1), size), fetch(base + ivec2(1, 1), size), f.x);
+
+    outColor = vec4(mix(top, bottom, f.y), 1.0);
+}
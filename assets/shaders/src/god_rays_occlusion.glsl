@@ -0,0 +1,34 @@
+#include "include/gbuffer_sampler.glsl"
+
+// G-buffer depth, the sun is visible only where nothing was drawn
+layout(set = 0, binding = 0) uniform gbufferSampler samplerDepth;
+
+layout(binding = 1) uniform UniformBufferObject {
+    vec4 sun; // xy - sun uv, z - disk radius, w - aspect ratio
+} ubo;
+
+layout(constant_id = 0) const int NUM_SAMPLES = 2;
+layout(constant_id = 1) const bool REVERSE_Z = false;
+
+layout(location = 0) in vec2 inUV;
+
+layout(location = 0) out vec4 outOcclusion;
+
+void main() {
+    ivec2 depthDim = gbufferSize(samplerDepth);
+    ivec2 UV = ivec2(inUV * depthDim);
+
+    float sky = 0.0;
+    for (int i = 0; i < NUM_SAMPLES; i++) {
+        float depth = gbufferFetch(samplerDepth, UV, i).r;
+        bool farPlane = REVERSE_Z ? depth <= 0.0 : depth >= 1.0;
+        sky += farPlane ? 1.0 : 0.0;
+    }
+    sky /= float(NUM_SAMPLES);
+
+    vec2 toSun = (inUV - ubo.sun.xy) * vec2(ubo.sun.w, 1.0);
+    float disk = 1.0 - smoothstep(ubo.sun.z * 0.5, ubo.sun.z, length(toSun));
+
+    // faint sky glow makes shafts through gaps visible even with the disk hidden
+    outOcclusion = vec4(sky * (disk + 0.05));
+}
@@ -1,19 +1,45 @@
 #version 450
 
 #extension GL_ARB_separate_shader_objects : enable
+#extension GL_GOOGLE_include_directive : require
+
+#include "../include/normal_encoding.glsl"
+#include "../include/motion_vector.glsl"
 
 layout(binding = 1) uniform sampler2D texSampler;
 
+// x - cos of max walkable slope, y, z - walkable height range, w - overlay opacity, 0 disables it
+layout(push_constant) uniform Walkability {
+    vec4 params;
+} walkability;
+
 layout(location = 0) in vec2 fragTexCoord;
 layout(location = 1) in vec4 fragPosition;
 layout(location = 2) in vec3 fragNormal;
+layout(location = 3) in float fragSkyVisibility;
+layout(location = 4) in vec4 fragCurrClip;
+layout(location = 5) in vec4 fragPrevClip;
 
 layout(location = 0) out vec4 outColor;
-layout(location = 1) out vec4 outPosition;
-layout(location = 2) out vec4 outNormal;
+layout(location = 1) out vec4 outNormal;
+layout(location = 2) out vec4 outMaterial;
+layout(location = 3) out vec2 outVelocity;
 
 void main() {
     outColor = texture(texSampler, fragTexCoord); //vec4(fragNormal, 1.0);
-    outPosition = fragPosition;
-    outNormal = vec4(-fragNormal, 1.0);
+
+    if (walkability.params.w > 0.0) {
+        // heights go up along -Y
+        float height = -fragPosition.y;
+        bool walkable = abs(normalize(fragNormal).y) >= walkability.params.x
+            && height >= walkability.params.y && height <= walkability.params.z;
+
+        vec3 tint = walkable ? vec3(0.1, 0.9, 0.2) : vec3(0.9, 0.1, 0.1);
+        outColor.rgb = mix(outColor.rgb, tint, walkability.params.w);
+    }
+    // sky visibility scales ambient light in compose pass
+    outNormal = encodeGBufferNormal(-fragNormal, fragSkyVisibility);
+    // rough dielectric ground, occlusion comes from sky visibility in compose pass
+    outMaterial = vec4(0.0, 0.9, 1.0, 1.0);
+    outVelocity = motionVector(fragCurrClip, fragPrevClip);
 }
@@ -5,15 +5,22 @@ layout(binding = 0) uniform UniformBufferObject {
     mat4 model;
     mat4 view;
     mat4 proj;
+    // unjittered view projections of this and the previous frame
+    mat4 viewProj;
+    mat4 prevViewProj;
 } ubo;
 
 layout(location = 0) in vec3 inPosition;
 layout(location = 1) in vec3 inNormal;
 layout(location = 2) in vec2 inTexCoord;
+layout(location = 3) in float inSkyVisibility;
 
 layout(location = 0) out vec2 fragTexCoord;
 layout(location = 1) out vec4 fragWorldPosition;
 layout(location = 2) out vec3 fragNormal;
+layout(location = 3) out float fragSkyVisibility;
+layout(location = 4) out vec4 fragCurrClip;
+layout(location = 5) out vec4 fragPrevClip;
 
 out gl_PerVertex {
     vec4 gl_Position;
@@ -25,4 +32,7 @@ void main() {
 
     fragWorldPosition = ubo.model * vec4(inPosition, 1.0);
     fragNormal = inNormal;
+    fragCurrClip = ubo.viewProj * fragWorldPosition;
+    fragPrevClip = ubo.prevViewProj * fragWorldPosition;
+    fragSkyVisibility = inSkyVisibility;
 }
@@ -0,0 +1,17 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+// Corners of a terrain chunk, the tessellation stages fill the inside from the height texture
+
+layout(location = 0) in vec3 inPosition;
+layout(location = 1) in vec3 inNormal;
+layout(location = 2) in vec2 inTexCoord;
+layout(location = 3) in float inSkyVisibility;
+
+layout(location = 0) out vec3 outPosition;
+layout(location = 1) out vec2 outTexCoord;
+
+void main() {
+    outPosition = inPosition;
+    outTexCoord = inTexCoord;
+}
@@ -0,0 +1,22 @@
+// Cube map helpers for passes rendering into cube faces.
+// Requires `#extension GL_GOOGLE_include_directive : require` in the including shader.
+
+const float PI = 3.14159265359;
+
+// Direction through a texel of a cube face, `uv` is in [0, 1] with y going down.
+// Faces are in the +X, -X, +Y, -Y, +Z, -Z layer order
+vec3 cubeFaceDirection(uint face, vec2 uv)
+{
+    vec2 p = uv * 2.0 - 1.0;
+    vec3 dir;
+    switch (face) {
+        case 0: dir = vec3(1.0, -p.y, -p.x); break;
+        case 1: dir = vec3(-1.0, -p.y, p.x); break;
+        case 2: dir = vec3(p.x, 1.0, p.y); break;
+        case 3: dir = vec3(p.x, -1.0, -p.y); break;
+        case 4: dir = vec3(p.x, -p.y, 1.0); break;
+        default: dir = vec3(-p.x, -p.y, -1.0); break;
+    }
+
+    return normalize(dir);
+}
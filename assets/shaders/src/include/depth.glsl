@@ -0,0 +1,18 @@
+// Helpers for G-buffers without a position attachment.
+// Requires `#extension GL_GOOGLE_include_directive : require` in the including shader.
+
+// World position of a pixel from its depth buffer value. `uv` is in [0, 1] with y going down,
+// same as the screen, `invViewProj` is the inverse of the projection * view used to draw the depth
+vec3 reconstructWorldPosition(vec2 uv, float depth, mat4 invViewProj)
+{
+    vec4 ndc = vec4(uv * 2.0 - 1.0, depth, 1.0);
+    vec4 world = invViewProj * ndc;
+
+    return world.xyz / world.w;
+}
+
+// Positive distance along the view direction, for effects working in view space
+float linearViewDepth(vec3 worldPos, mat4 view)
+{
+    return -(view * vec4(worldPos, 1.0)).z;
+}
@@ -0,0 +1,47 @@
+// Height fog of the composite pass, shared with the transparent pass drawn over it.
+// `fog` and `fogColor` are laid out as Atmosphere::fog_params() of the demo:
+// fog: x - density at the base altitude (0 - off), y - height falloff, z - base altitude, w - distance of the sky
+// fogColor: rgb - fog color lit by the sun, w - atmospheric scattering strength (0 - plain fog)
+
+// extinction of red and green relative to blue, ~ 1 / wavelength^4 of 680, 550 and 440 nm
+const vec3 RAYLEIGH = vec3(0.18, 0.41, 1.0);
+// forward scattering of haze around the sun
+const float MIE_G = 0.75;
+const vec3 SUN_HAZE_COLOR = vec3(1.0, 0.85, 0.6);
+
+// Fog along the ray from the camera to `pos`: density * exp(-falloff * (altitude - base)) integrated in closed form.
+// World Y points down, so the altitude is -y
+float fogOpticalDepth(vec4 fog, vec3 camera, vec3 pos)
+{
+    float atCamera = fog.x * exp(-fog.y * (-camera.y - fog.z));
+    // the altitude change over the ray in units of the falloff, clamped against overflow looking down into dense fog
+    float t = max(fog.y * (camera.y - pos.y), -40.0);
+    float ratio = abs(t) > 1e-4 ? (1.0 - exp(-t)) / t : 1.0;
+
+    return atCamera * length(pos - camera) * ratio;
+}
+
+// Henyey-Greenstein phase function relative to the isotropic one
+float hazePhase(float cosTheta)
+{
+    float g2 = MIE_G * MIE_G;
+    return (1.0 - g2) / pow(1.0 + g2 - 2.0 * MIE_G * cosTheta, 1.5);
+}
+
+// Height fog over the lit `color` of `pos`. With scattering, blue is scattered more than red, so distant surfaces
+// turn blue and the fog gets a bright haze around the sun. `sunDirection` is the light direction, the sun is at -sunDirection
+vec3 applyHeightFog(vec3 color, vec3 pos, vec3 camera, vec3 sunDirection, float sunRadiance, vec4 fog, vec4 fogColor)
+{
+    if (fog.x <= 0.0) {
+        return color;
+    }
+
+    vec3 viewDir = normalize(pos - camera);
+    float scattering = fogColor.w;
+
+    vec3 transmittance = exp(-fogOpticalDepth(fog, camera, pos) * mix(vec3(1.0), RAYLEIGH, min(scattering, 1.0)));
+    float haze = hazePhase(dot(viewDir, -sunDirection)) * scattering;
+    vec3 inscattered = fogColor.rgb * sunRadiance * (vec3(1.0) + SUN_HAZE_COLOR * haze);
+
+    return color * transmittance + inscattered * (1.0 - transmittance);
+}
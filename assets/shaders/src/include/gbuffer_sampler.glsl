@@ -0,0 +1,12 @@
+// G-buffer attachments are multisampled unless GBUFFER_SINGLE_SAMPLE is defined before the include
+// (MSAA is off, edges are smoothed by FXAA in post processing). Multisampled sampler types
+// can't be bound to single sample images, so such shaders have a *_single_sample variant
+#ifdef GBUFFER_SINGLE_SAMPLE
+#define gbufferSampler sampler2D
+#define gbufferFetch(tex, pixel, sampleIdx) texelFetch(tex, pixel, 0)
+#define gbufferSize(tex) textureSize(tex, 0)
+#else
+#define gbufferSampler sampler2DMS
+#define gbufferFetch(tex, pixel, sampleIdx) texelFetch(tex, pixel, sampleIdx)
+#define gbufferSize(tex) textureSize(tex)
+#endif
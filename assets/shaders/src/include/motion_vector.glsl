@@ -0,0 +1,15 @@
+// Motion vectors of the G-buffer velocity attachment (see GBufferLayout on the Rust side).
+// Requires `#extension GL_GOOGLE_include_directive : require` in the including shader.
+//
+// Clip positions of this and the previous frame come from the unjittered view projections,
+// the result is the screen uv of this frame minus the one of the previous frame
+
+vec2 motionVector(vec4 currClip, vec4 prevClip)
+{
+    // far behind the previous camera, no history to point at
+    if (prevClip.w <= 1e-5) {
+        return vec2(0.0);
+    }
+
+    return (currClip.xy / currClip.w - prevClip.xy / prevClip.w) * 0.5;
+}
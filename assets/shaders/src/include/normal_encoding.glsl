@@ -0,0 +1,42 @@
+// Octahedral normal packing for the G-buffer normal attachment (see GBufferLayout on the Rust side).
+// Requires `#extension GL_GOOGLE_include_directive : require` in the including shader.
+//
+// Attachment layout: rg - encoded normal in [0, 1], b - terrain sky visibility,
+// a - 1 when the pixel has a normal, 0 for the sky
+
+vec2 octWrap(vec2 v)
+{
+    return (1.0 - abs(v.yx)) * vec2(v.x >= 0.0 ? 1.0 : -1.0, v.y >= 0.0 ? 1.0 : -1.0);
+}
+
+// Unit vector to [0, 1]^2
+vec2 octEncode(vec3 n)
+{
+    n /= abs(n.x) + abs(n.y) + abs(n.z);
+    vec2 e = n.z >= 0.0 ? n.xy : octWrap(n.xy);
+
+    return e * 0.5 + 0.5;
+}
+
+vec3 octDecode(vec2 e)
+{
+    e = e * 2.0 - 1.0;
+
+    // https://twitter.com/Stubbesaurus/status/937994790553227264
+    vec3 n = vec3(e, 1.0 - abs(e.x) - abs(e.y));
+    float t = clamp(-n.z, 0.0, 1.0);
+    n.xy += vec2(n.x >= 0.0 ? -t : t, n.y >= 0.0 ? -t : t);
+
+    return normalize(n);
+}
+
+vec4 encodeGBufferNormal(vec3 n, float skyVisibility)
+{
+    return vec4(octEncode(normalize(n)), skyVisibility, 1.0);
+}
+
+// Zero vector for sky pixels
+vec3 decodeGBufferNormal(vec4 encoded)
+{
+    return encoded.a > 0.5 ? octDecode(encoded.rg) : vec3(0.0);
+}
@@ -0,0 +1,52 @@
+// Encoding of linear color for the swapchain image, see TransferFunction of the swapchain.
+// Requires `#extension GL_GOOGLE_include_directive : require` and the constants
+// OUTPUT_TRANSFER and PAPER_WHITE_NITS declared in the including shader.
+
+const uint TRANSFER_HARDWARE_SRGB = 0;
+const uint TRANSFER_SRGB = 1;
+const uint TRANSFER_PQ = 2;
+const uint TRANSFER_LINEAR = 3;
+
+vec3 srgbFromLinear(vec3 color)
+{
+    color = clamp(color, 0.0, 1.0);
+    vec3 low = color * 12.92;
+    vec3 high = 1.055 * pow(color, vec3(1.0 / 2.4)) - 0.055;
+
+    return mix(high, low, lessThanEqual(color, vec3(0.0031308)));
+}
+
+// SMPTE ST 2084, `nits` up to 10000
+vec3 pqFromNits(vec3 nits)
+{
+    const float m1 = 0.1593017578125;
+    const float m2 = 78.84375;
+    const float c1 = 0.8359375;
+    const float c2 = 18.8515625;
+    const float c3 = 18.6875;
+
+    vec3 y = pow(clamp(nits / 10000.0, 0.0, 1.0), vec3(m1));
+    return pow((c1 + c2 * y) / (1.0 + c3 * y), vec3(m2));
+}
+
+// Scene color is linear with BT.709 primaries, 1.0 is SDR white
+vec3 encodeOutput(vec3 color)
+{
+    if (OUTPUT_TRANSFER == TRANSFER_SRGB) {
+        return srgbFromLinear(color);
+    }
+    if (OUTPUT_TRANSFER == TRANSFER_PQ) {
+        // BT.709 to BT.2020 primaries
+        const mat3 toBt2020 = mat3(
+            0.6274, 0.0691, 0.0164,
+            0.3293, 0.9195, 0.0880,
+            0.0433, 0.0114, 0.8956
+        );
+        return pqFromNits(toBt2020 * max(color, vec3(0.0)) * float(PAPER_WHITE_NITS));
+    }
+    if (OUTPUT_TRANSFER == TRANSFER_LINEAR) {
+        return color * (float(PAPER_WHITE_NITS) / 80.0);
+    }
+
+    return color;
+}
@@ -0,0 +1,44 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+// Environment cube map with mips
+layout(set = 0, binding = 0) uniform samplerCube environment;
+
+layout(push_constant) uniform Irradiance {
+    uint face;
+} pc;
+
+layout(location = 0) in vec2 inUV;
+
+layout(location = 0) out vec4 outColor;
+
+#include "include/cube_face.glsl"
+
+const float SAMPLE_DELTA = 0.05;
+// resolution of the sampled mip, finer mips would alias with the sample spacing
+const float SOURCE_SIZE = 32.0;
+
+// Cosine weighted hemisphere average: diffuse ambient is albedo * irradiance
+void main()
+{
+    vec3 N = cubeFaceDirection(pc.face, inUV);
+    vec3 up = abs(N.y) < 0.999 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0);
+    vec3 right = normalize(cross(up, N));
+    up = cross(N, right);
+
+    float lod = max(log2(float(textureSize(environment, 0).x) / SOURCE_SIZE), 0.0);
+
+    vec3 irradiance = vec3(0.0);
+    float sampleCount = 0.0;
+    for (float phi = 0.0; phi < 2.0 * PI; phi += SAMPLE_DELTA) {
+        for (float theta = 0.0; theta < 0.5 * PI; theta += SAMPLE_DELTA) {
+            vec3 tangentSample = vec3(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            vec3 L = tangentSample.x * right + tangentSample.y * up + tangentSample.z * N;
+
+            irradiance += textureLod(environment, L, lod).rgb * cos(theta) * sin(theta);
+            sampleCount += 1.0;
+        }
+    }
+
+    outColor = vec4(PI * irradiance / sampleCount, 1.0);
+}
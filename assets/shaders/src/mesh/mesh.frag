@@ -1,20 +1,50 @@
 #version 450
 
 #extension GL_ARB_separate_shader_objects : enable
+#extension GL_GOOGLE_include_directive : require
+
+#include "../include/normal_encoding.glsl"
+#include "../include/motion_vector.glsl"
 
 layout(binding = 1) uniform sampler2D texSampler;
+// tangent space normals, UNORM
+layout(binding = 3) uniform sampler2D normalMap;
+// glTF layout: g - roughness, b - metallic
+layout(binding = 4) uniform sampler2D metallicRoughnessMap;
+layout(binding = 5) uniform sampler2D occlusionMap;
+
+// x - metallic, y - roughness, z - occlusion strength
+// after the model matrix of mesh.vert
+layout(push_constant) uniform MaterialFactors {
+    layout(offset = 64) vec4 factors;
+} material;
 
 layout(location = 0) in vec3 fragColor;
 layout(location = 1) in vec2 fragTexCoord;
 layout(location = 2) in vec4 fragPosition;
 layout(location = 3) in vec3 fragNormal;
+layout(location = 4) in vec4 fragTangent;
+layout(location = 5) in vec4 fragCurrClip;
+layout(location = 6) in vec4 fragPrevClip;
 
 layout(location = 0) out vec4 outColor;
-layout(location = 1) out vec4 outPosition;
-layout(location = 2) out vec4 outNormal;
+layout(location = 1) out vec4 outNormal;
+// x - metallic, y - roughness, z - ambient occlusion, w - 1 for PBR lit surfaces
+layout(location = 2) out vec4 outMaterial;
+layout(location = 3) out vec2 outVelocity;
 
 void main() {
     outColor = texture(texSampler, fragTexCoord); // fragPosition; //vec4(fragNormal, 1.0); //texture(texSampler, fragTexCoord);
-    outPosition = fragPosition;
-    outNormal = vec4(fragNormal, 1.0);
+
+    vec3 n = normalize(fragNormal);
+    vec3 t = normalize(fragTangent.xyz - n * dot(n, fragTangent.xyz));
+    vec3 b = cross(n, t) * fragTangent.w;
+    vec3 tangentNormal = texture(normalMap, fragTexCoord).xyz * 2.0 - 1.0;
+
+    outNormal = encodeGBufferNormal(mat3(t, b, n) * tangentNormal, 1.0);
+
+    vec2 metallicRoughness = texture(metallicRoughnessMap, fragTexCoord).bg * material.factors.xy;
+    float occlusion = mix(1.0, texture(occlusionMap, fragTexCoord).r, material.factors.z);
+    outMaterial = vec4(metallicRoughness, occlusion, 1.0);
+    outVelocity = motionVector(fragCurrClip, fragPrevClip);
 }
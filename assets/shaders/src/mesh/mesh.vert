@@ -5,27 +5,56 @@ layout(binding = 0) uniform UniformBufferObject {
     mat4 model;
     mat4 view;
     mat4 proj;
+    // unjittered view projections of this and the previous frame
+    mat4 viewProj;
+    mat4 prevViewProj;
 } ubo;
 
+struct GpuObject {
+    mat4 model;
+    vec4 boundsMin;
+    vec4 boundsMax;
+    uint material;
+};
+
+layout(std430, binding = 2) readonly buffer SceneBuffer {
+    GpuObject objects[];
+} scene;
+
+// transform of the draw applied before the object one, the vertex part of the push constants
+layout(push_constant) uniform DrawConstants {
+    mat4 model;
+} draw;
+
 layout(location = 0) in vec3 inPosition;
 layout(location = 1) in vec3 inColor;
 layout(location = 2) in vec2 inTexCoord;
 layout(location = 3) in vec3 inNormal;
+layout(location = 4) in vec4 inTangent;
 
 layout(location = 0) out vec3 fragColor;
 layout(location = 1) out vec2 fragTexCoord;
 layout(location = 2) out vec4 fragWorldPosition;
 layout(location = 3) out vec3 fragNormal;
+layout(location = 4) out vec4 fragTangent;
+layout(location = 5) out vec4 fragCurrClip;
+layout(location = 6) out vec4 fragPrevClip;
 
 out gl_PerVertex {
     vec4 gl_Position;
 };
 
 void main() {
-    gl_Position = ubo.proj * ubo.view * ubo.model * vec4(inPosition, 1.0);
+    mat4 model = scene.objects[gl_InstanceIndex].model * draw.model;
+    gl_Position = ubo.proj * ubo.view * model * vec4(inPosition, 1.0);
     fragColor = inColor;
     fragTexCoord = inTexCoord;
 
-    fragWorldPosition = ubo.model * vec4(inPosition, 1.0);
-    fragNormal = inNormal;
+    fragWorldPosition = model * vec4(inPosition, 1.0);
+    fragNormal = mat3(model) * inNormal;
+    fragTangent = vec4(mat3(model) * inTangent.xyz, inTangent.w);
+
+    // objects are static, only the camera moves between frames
+    fragCurrClip = ubo.viewProj * fragWorldPosition;
+    fragPrevClip = ubo.prevViewProj * fragWorldPosition;
 }
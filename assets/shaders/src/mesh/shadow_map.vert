@@ -6,12 +6,21 @@ layout (location = 1) in vec2 inColor;
 layout (location = 2) in vec2 inUV;
 layout (location = 3) in vec2 inNormal;
 
-#define SHADOW_MAP_CASCADE_COUNT 4
-
 layout(binding = 0) uniform UniformBufferObject {
-    mat4[SHADOW_MAP_CASCADE_COUNT] cascadeViewProjMat;
+    mat4 lightViewProj;
 } ubo;
 
+struct GpuObject {
+    mat4 model;
+    vec4 boundsMin;
+    vec4 boundsMax;
+    uint material;
+};
+
+layout(std430, binding = 1) readonly buffer SceneBuffer {
+    GpuObject objects[];
+} scene;
+
 out gl_PerVertex {
     vec4 gl_Position;
 };
@@ -19,5 +28,5 @@ layout (location = 0) out vec2 outUV;
 
 void main() {
 	outUV = inUV;
-	gl_Position =  ubo.cascadeViewProjMat[0] * vec4(inPos, 1.0);
+	gl_Position =  ubo.lightViewProj * scene.objects[gl_InstanceIndex].model * vec4(inPos, 1.0);
 }
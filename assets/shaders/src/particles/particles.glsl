@@ -0,0 +1,42 @@
+// Soft particles: there is no depth attachment in the HDR pass, G-buffer depth is tested here and
+// particles fade out close to the geometry instead of cutting through it
+
+#include "../include/gbuffer_sampler.glsl"
+
+layout(binding = 3) uniform gbufferSampler samplerDepth;
+
+layout(binding = 4) uniform FragmentUniforms {
+    mat4 invViewProj;
+    mat4 view;
+    vec4 params; // x - fade distance in meters, y - exposure
+} ubo;
+
+layout(constant_id = 0) const bool REVERSE_Z = false;
+
+#include "../include/depth.glsl"
+
+layout(location = 0) in vec4 inColor;
+layout(location = 1) in vec2 inUV;
+layout(location = 2) in float inViewDepth;
+
+layout(location = 0) out vec4 outColor;
+
+void main() {
+    float falloff = 1.0 - smoothstep(0.5, 1.0, length(inUV));
+
+    ivec2 depthDim = gbufferSize(samplerDepth);
+    ivec2 pixel = ivec2(gl_FragCoord.xy);
+    float depth = gbufferFetch(samplerDepth, pixel, 0).r;
+
+    float soft = 1.0;
+    bool farPlane = REVERSE_Z ? depth <= 0.0 : depth >= 1.0;
+    if (!farPlane) {
+        vec3 scenePos = reconstructWorldPosition((vec2(pixel) + 0.5) / vec2(depthDim), depth, ubo.invViewProj);
+        float sceneDepth = linearViewDepth(scenePos, ubo.view);
+        soft = clamp((sceneDepth - inViewDepth) / max(ubo.params.x, 0.001), 0.0, 1.0);
+    }
+
+    // premultiplied alpha, the pipeline blends with ONE, ONE_MINUS_SRC_ALPHA
+    float alpha = inColor.a * falloff * soft;
+    outColor = vec4(inColor.rgb * ubo.params.y * alpha, alpha);
+}
@@ -0,0 +1,53 @@
+#version 450
+
+// Camera facing quad per alive particle, instances index the draw list written by particles_update.comp
+
+struct Particle {
+    vec4 position; // w - remaining life in seconds
+    vec4 velocity; // w - lifetime the particle was spawned with
+};
+
+layout(std430, binding = 0) readonly buffer Particles {
+    Particle particles[];
+};
+
+layout(std430, binding = 1) readonly buffer DrawList {
+    uint vertexCount;
+    uint instanceCount;
+    uint firstVertex;
+    uint firstInstance;
+    uint spawned;
+    uint reserved[3];
+    uint alive[];
+};
+
+layout(binding = 2) uniform UniformBufferObject {
+    mat4 view;
+    mat4 proj;
+    vec4 size; // x - size at spawn, y - size at death, in meters
+    vec4 startColor;
+    vec4 endColor;
+} ubo;
+
+layout(location = 0) out vec4 outColor;
+layout(location = 1) out vec2 outUV;
+layout(location = 2) out float outViewDepth;
+
+const vec2 corners[6] = vec2[](
+    vec2(-1.0, -1.0), vec2(1.0, -1.0), vec2(1.0, 1.0),
+    vec2(-1.0, -1.0), vec2(1.0, 1.0), vec2(-1.0, 1.0)
+);
+
+void main() {
+    Particle particle = particles[alive[gl_InstanceIndex]];
+    float age = 1.0 - particle.position.w / max(particle.velocity.w, 0.0001);
+
+    vec2 corner = corners[gl_VertexIndex];
+    vec4 viewPos = ubo.view * vec4(particle.position.xyz, 1.0);
+    viewPos.xy += corner * mix(ubo.size.x, ubo.size.y, age) * 0.5;
+
+    gl_Position = ubo.proj * viewPos;
+    outColor = mix(ubo.startColor, ubo.endColor, age);
+    outUV = corner;
+    outViewDepth = -viewPos.z;
+}
@@ -0,0 +1,24 @@
+#version 450
+
+layout(set = 0, binding = 0) uniform sampler2D samplerInput;
+
+layout(push_constant) uniform Constants {
+    vec4 params; // x - channel offset at the screen corner in pixels
+    vec4 texel;  // xy - 1 / size, zw - size
+} constants;
+
+layout(location = 0) in vec2 inUV;
+
+layout(location = 0) out vec4 outFragColor;
+
+void main() {
+    // red and blue move apart towards the screen edges, the center stays sharp
+    vec2 fromCenter = inUV - 0.5;
+    vec2 offset = fromCenter * 2.0 * constants.params.x * constants.texel.xy;
+
+    float r = texture(samplerInput, inUV + offset).r;
+    float g = texture(samplerInput, inUV).g;
+    float b = texture(samplerInput, inUV - offset).b;
+
+    outFragColor = vec4(r, g, b, 1.0);
+}
@@ -0,0 +1,126 @@
+#version 450
+
+layout(set = 0, binding = 0) uniform sampler2D samplerInput;
+
+layout(push_constant) uniform Constants {
+    vec4 params; // x - subpixel blending, y - edge threshold, z - minimal threshold in dark areas
+    vec4 texel;  // xy - 1 / size, zw - size
+} constants;
+
+layout(location = 0) in vec2 inUV;
+
+layout(location = 0) out vec4 outFragColor;
+
+const int SEARCH_STEPS = 10;
+
+float luma(vec3 color) {
+    // perceptual luma, the input is linear when the target format is sRGB
+    return sqrt(dot(color, vec3(0.299, 0.587, 0.114)));
+}
+
+float lumaAt(vec2 uv) {
+    return luma(texture(samplerInput, uv).rgb);
+}
+
+// FXAA 3.11 quality path, simplified: find the edge direction from the neighbour lumas,
+// walk along the edge to its ends and blend across it depending on the distance to the nearer end
+void main() {
+    vec2 texel = constants.texel.xy;
+    vec3 center = texture(samplerInput, inUV).rgb;
+
+    float lumaM = luma(center);
+    float lumaN = lumaAt(inUV + vec2(0.0, -texel.y));
+    float lumaS = lumaAt(inUV + vec2(0.0, texel.y));
+    float lumaW = lumaAt(inUV + vec2(-texel.x, 0.0));
+    float lumaE = lumaAt(inUV + vec2(texel.x, 0.0));
+
+    float lumaMin = min(lumaM, min(min(lumaN, lumaS), min(lumaW, lumaE)));
+    float lumaMax = max(lumaM, max(max(lumaN, lumaS), max(lumaW, lumaE)));
+    float range = lumaMax - lumaMin;
+    if (range < max(constants.params.z, lumaMax * constants.params.y)) {
+        outFragColor = vec4(center, 1.0);
+        return;
+    }
+
+    float lumaNW = lumaAt(inUV + vec2(-texel.x, -texel.y));
+    float lumaNE = lumaAt(inUV + vec2(texel.x, -texel.y));
+    float lumaSW = lumaAt(inUV + vec2(-texel.x, texel.y));
+    float lumaSE = lumaAt(inUV + vec2(texel.x, texel.y));
+
+    float edgeHorizontal = abs(lumaNW + lumaNE - 2.0 * lumaN) + 2.0 * abs(lumaW + lumaE - 2.0 * lumaM) + abs(lumaSW + lumaSE - 2.0 * lumaS);
+    float edgeVertical = abs(lumaNW + lumaSW - 2.0 * lumaW) + 2.0 * abs(lumaN + lumaS - 2.0 * lumaM) + abs(lumaNE + lumaSE - 2.0 * lumaE);
+    bool isHorizontal = edgeHorizontal >= edgeVertical;
+
+    // the pixel pair across the edge
+    float luma1 = isHorizontal ? lumaN : lumaW;
+    float luma2 = isHorizontal ? lumaS : lumaE;
+    float gradient1 = abs(luma1 - lumaM);
+    float gradient2 = abs(luma2 - lumaM);
+    bool isSteeper1 = gradient1 >= gradient2;
+    float gradientScaled = 0.25 * max(gradient1, gradient2);
+
+    float stepLength = isHorizontal ? texel.y : texel.x;
+    float lumaLocalAverage;
+    if (isSteeper1) {
+        stepLength = -stepLength;
+        lumaLocalAverage = 0.5 * (luma1 + lumaM);
+    } else {
+        lumaLocalAverage = 0.5 * (luma2 + lumaM);
+    }
+
+    vec2 edgeUV = inUV;
+    if (isHorizontal) {
+        edgeUV.y += stepLength * 0.5;
+    } else {
+        edgeUV.x += stepLength * 0.5;
+    }
+
+    vec2 offset = isHorizontal ? vec2(texel.x, 0.0) : vec2(0.0, texel.y);
+    vec2 uv1 = edgeUV - offset;
+    vec2 uv2 = edgeUV + offset;
+    float lumaEnd1 = lumaAt(uv1) - lumaLocalAverage;
+    float lumaEnd2 = lumaAt(uv2) - lumaLocalAverage;
+    bool reached1 = abs(lumaEnd1) >= gradientScaled;
+    bool reached2 = abs(lumaEnd2) >= gradientScaled;
+
+    for (int i = 1; i < SEARCH_STEPS && !(reached1 && reached2); i++) {
+        float stepScale = i < 4 ? 1.0 : 2.0;
+        if (!reached1) {
+            uv1 -= offset * stepScale;
+            lumaEnd1 = lumaAt(uv1) - lumaLocalAverage;
+            reached1 = abs(lumaEnd1) >= gradientScaled;
+        }
+        if (!reached2) {
+            uv2 += offset * stepScale;
+            lumaEnd2 = lumaAt(uv2) - lumaLocalAverage;
+            reached2 = abs(lumaEnd2) >= gradientScaled;
+        }
+    }
+
+    float distance1 = isHorizontal ? (inUV.x - uv1.x) : (inUV.y - uv1.y);
+    float distance2 = isHorizontal ? (uv2.x - inUV.x) : (uv2.y - inUV.y);
+    bool isDirection1 = distance1 < distance2;
+    float distanceFinal = min(distance1, distance2);
+    float edgeThickness = distance1 + distance2;
+
+    // blend only when the luma at the nearer end goes the other way than at the center
+    bool isLumaCenterSmaller = lumaM < lumaLocalAverage;
+    bool correctVariation = ((isDirection1 ? lumaEnd1 : lumaEnd2) < 0.0) != isLumaCenterSmaller;
+    float edgeOffset = correctVariation ? -distanceFinal / edgeThickness + 0.5 : 0.0;
+
+    // subpixel aliasing: thin lines and single pixels
+    float lumaAverage = (2.0 * (lumaN + lumaS + lumaW + lumaE) + lumaNW + lumaNE + lumaSW + lumaSE) / 12.0;
+    float subPixelOffset = clamp(abs(lumaAverage - lumaM) / range, 0.0, 1.0);
+    subPixelOffset = (-2.0 * subPixelOffset + 3.0) * subPixelOffset * subPixelOffset;
+    float subPixelOffsetFinal = subPixelOffset * subPixelOffset * constants.params.x;
+
+    float finalOffset = max(edgeOffset, subPixelOffsetFinal);
+    vec2 finalUV = inUV;
+    if (isHorizontal) {
+        finalUV.y += finalOffset * stepLength;
+    } else {
+        finalUV.x += finalOffset * stepLength;
+    }
+
+    outFragColor = vec4(texture(samplerInput, finalUV).rgb, 1.0);
+}
@@ -0,0 +1,26 @@
+#version 450
+
+layout(set = 0, binding = 0) uniform sampler2D samplerInput;
+
+layout(push_constant) uniform Constants {
+    vec4 params; // x - strength
+    vec4 texel;  // xy - 1 / size, zw - size
+} constants;
+
+layout(location = 0) in vec2 inUV;
+
+layout(location = 0) out vec4 outFragColor;
+
+// Unsharp mask with a cross shaped blur
+void main() {
+    vec2 texel = constants.texel.xy;
+    vec3 center = texture(samplerInput, inUV).rgb;
+    vec3 blur = (texture(samplerInput, inUV + vec2(texel.x, 0.0)).rgb +
+                 texture(samplerInput, inUV - vec2(texel.x, 0.0)).rgb +
+                 texture(samplerInput, inUV + vec2(0.0, texel.y)).rgb +
+                 texture(samplerInput, inUV - vec2(0.0, texel.y)).rgb) * 0.25;
+
+    vec3 color = center + (center - blur) * constants.params.x;
+
+    outFragColor = vec4(max(color, vec3(0.0)), 1.0);
+}
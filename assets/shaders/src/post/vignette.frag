@@ -0,0 +1,21 @@
+#version 450
+
+layout(set = 0, binding = 0) uniform sampler2D samplerInput;
+
+layout(push_constant) uniform Constants {
+    vec4 params; // x - strength, y - radius where darkening starts, z - softness
+} constants;
+
+layout(location = 0) in vec2 inUV;
+
+layout(location = 0) out vec4 outFragColor;
+
+void main() {
+    vec3 color = texture(samplerInput, inUV).rgb;
+
+    float dist = length(inUV - 0.5) * 1.414;
+    float vignette = smoothstep(constants.params.y, constants.params.y + constants.params.z, dist);
+    color *= 1.0 - vignette * constants.params.x;
+
+    outFragColor = vec4(color, 1.0);
+}
@@ -0,0 +1,41 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+// Mip 0 of the environment cube map
+layout(set = 0, binding = 0) uniform samplerCube environment;
+
+layout(push_constant) uniform Prefilter {
+    uint face;
+    float roughness;
+} pc;
+
+layout(location = 0) in vec2 inUV;
+
+layout(location = 0) out vec4 outColor;
+
+#include "include/cube_face.glsl"
+#include "include/ggx_sampling.glsl"
+
+const uint SAMPLE_COUNT = 256u;
+
+// Split-sum prefiltering: view direction is taken equal to the normal
+void main()
+{
+    vec3 N = cubeFaceDirection(pc.face, inUV);
+    vec3 V = N;
+
+    vec3 color = vec3(0.0);
+    float weight = 0.0;
+    for (uint i = 0u; i < SAMPLE_COUNT; i++) {
+        vec3 H = importanceSampleGGX(hammersley(i, SAMPLE_COUNT), N, pc.roughness);
+        vec3 L = normalize(2.0 * dot(V, H) * H - V);
+
+        float NdotL = dot(N, L);
+        if (NdotL > 0.0) {
+            color += texture(environment, L).rgb * NdotL;
+            weight += NdotL;
+        }
+    }
+
+    outColor = vec4(color / max(weight, 1e-4), 1.0);
+}
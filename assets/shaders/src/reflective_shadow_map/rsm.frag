@@ -0,0 +1,27 @@
+#version 450
+
+layout(binding = 2) uniform sampler2D texSampler;
+
+layout (location = 0) in vec2 inUV;
+layout (location = 1) in vec3 inWorldPosition;
+layout (location = 2) in vec3 inNormal;
+layout (location = 3) flat in vec3 inSunDirection;
+
+// radiant exitance of the texel lit by the sun
+layout (location = 0) out vec4 outFlux;
+// world normal, zero where nothing was drawn
+layout (location = 1) out vec4 outNormal;
+layout (location = 2) out vec4 outPosition;
+
+// as in compose.glsl
+const float PI = 3.14159265359;
+const float SUN_RADIANCE = 1.5 * PI;
+
+void main() {
+    vec3 normal = normalize(inNormal);
+    vec3 albedo = texture(texSampler, inUV).rgb;
+
+    outFlux = vec4(albedo * max(dot(normal, normalize(inSunDirection)), 0.0) * SUN_RADIANCE, 1.0);
+    outNormal = vec4(normal, 1.0);
+    outPosition = vec4(inWorldPosition, 1.0);
+}
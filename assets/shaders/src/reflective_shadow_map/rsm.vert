@@ -0,0 +1,41 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout (location = 0) in vec3 inPos;
+layout (location = 2) in vec2 inUV;
+layout (location = 3) in vec3 inNormal;
+
+layout(binding = 0) uniform UniformBufferObject {
+    mat4 lightViewProj;
+    vec4 sunDirection; // xyz - light direction of compose.glsl
+} ubo;
+
+struct GpuObject {
+    mat4 model;
+    vec4 boundsMin;
+    vec4 boundsMax;
+    uint material;
+};
+
+layout(std430, binding = 1) readonly buffer SceneBuffer {
+    GpuObject objects[];
+} scene;
+
+out gl_PerVertex {
+    vec4 gl_Position;
+};
+layout (location = 0) out vec2 outUV;
+layout (location = 1) out vec3 outWorldPosition;
+layout (location = 2) out vec3 outNormal;
+layout (location = 3) flat out vec3 outSunDirection;
+
+void main() {
+    mat4 model = scene.objects[gl_InstanceIndex].model;
+    vec4 worldPosition = model * vec4(inPos, 1.0);
+
+    outUV = inUV;
+    outWorldPosition = worldPosition.xyz;
+    outNormal = mat3(model) * inNormal;
+    outSunDirection = ubo.sunDirection.xyz;
+    gl_Position = ubo.lightViewProj * worldPosition;
+}
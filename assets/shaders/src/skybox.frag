@@ -1,19 +1,34 @@
 #version 450
 
 #extension GL_ARB_separate_shader_objects : enable
+#extension GL_GOOGLE_include_directive : require
+
+#include "include/motion_vector.glsl"
 
 layout(binding = 1) uniform samplerCube texSampler;
 
 layout(location = 0) in vec3 fragColor;
 layout(location = 1) in vec3 fragUVW;
+layout(location = 2) in vec4 fragCurrClip;
+layout(location = 3) in vec4 fragPrevClip;
 
 layout(location = 0) out vec4 outColor;
-layout(location = 1) out vec4 outPosition;
-layout(location = 2) out vec4 outNormal;
+layout(location = 1) out vec4 outNormal;
+layout(location = 2) out vec4 outMaterial;
+layout(location = 3) out vec2 outVelocity;
+
+// HDR sky goes to the 8 bit albedo attachment as RGBM, compose.frag decodes it
+const float SKY_RANGE = 16.0;
 
 void main() {
-    outColor = texture(texSampler, fragUVW);
+    // mips of HDR environments are prefiltered for rougher reflections, the sky itself is sharp
+    vec3 color = textureLod(texSampler, fragUVW, 0.0).rgb;
+    float m = clamp(max(max(color.r, color.g), max(color.b, 1e-6)) / SKY_RANGE, 1.0 / 255.0, 1.0);
+    m = ceil(m * 255.0) / 255.0;
+    outColor = vec4(color / (m * SKY_RANGE), m);
 //    outColor = vec4(0.53, 0.81, 0.92, 1.0);
-    outPosition = vec4(1.0);
-    outNormal = vec4(0.0);
+    // no normal, fully visible sky
+    outNormal = vec4(0.5, 0.5, 1.0, 0.0);
+    outMaterial = vec4(0.0);
+    outVelocity = motionVector(fragCurrClip, fragPrevClip);
 }
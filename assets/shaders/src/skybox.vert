@@ -5,13 +5,20 @@ layout(binding = 0) uniform UniformBufferObject {
     mat4 model;
     mat4 view;
     mat4 proj;
+    // unjittered view projections of this and the previous frame
+    mat4 viewProj;
+    mat4 prevViewProj;
 } ubo;
 
 layout(location = 0) in vec3 inPosition;
 layout(location = 1) in vec3 inColor;
 
+layout(constant_id = 0) const bool REVERSE_Z = false;
+
 layout(location = 0) out vec3 fragColor;
 layout(location = 1) out vec3 fragUVW;
+layout(location = 2) out vec4 fragCurrClip;
+layout(location = 3) out vec4 fragPrevClip;
 
 out gl_PerVertex {
     vec4 gl_Position;
@@ -19,9 +26,14 @@ out gl_PerVertex {
 
 void main() {
     vec4 pos = ubo.proj * ubo.view * ubo.model * vec4(inPosition, 1.0);
-    gl_Position = pos.xyww;
+    // depth of the far plane
+    gl_Position = REVERSE_Z ? vec4(pos.xy, 0.0, pos.w) : pos.xyww;
     fragColor = inColor;
 
+    // the sky is infinitely far, only rotation of the camera moves it
+    fragCurrClip = ubo.viewProj * vec4(inPosition, 0.0);
+    fragPrevClip = ubo.prevViewProj * vec4(inPosition, 0.0);
+
     fragUVW = inPosition;
 
     fragUVW.y *= -1.0;
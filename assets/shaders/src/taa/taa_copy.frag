@@ -0,0 +1,12 @@
+#version 450
+
+layout(set = 0, binding = 0) uniform sampler2D samplerResolved;
+
+layout(location = 0) in vec2 inUV;
+
+layout(location = 0) out vec4 outFragColor;
+
+// Puts the resolved image back into the HDR buffer, passes after TAA read it from there
+void main() {
+    outFragColor = vec4(texture(samplerResolved, inUV).rgb, 1.0);
+}
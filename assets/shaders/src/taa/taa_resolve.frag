@@ -0,0 +1,68 @@
+#version 450
+
+// The scene lit by the compose pass, jittered
+layout(set = 0, binding = 0) uniform sampler2D samplerColor;
+// Resolved image of the previous frame
+layout(set = 0, binding = 1) uniform sampler2D samplerHistory;
+// G-buffer, TAA always works with a single sample one
+layout(set = 0, binding = 2) uniform sampler2D samplerVelocity;
+layout(set = 0, binding = 3) uniform sampler2D samplerDepth;
+
+layout(push_constant) uniform Constants {
+    vec4 params; // x - weight of the current frame, y - 0 when the history is invalid
+    // > 1 widens the clamp box, less clamping and more ghosting
+    float tweak_clampScale;
+} constants;
+
+layout(constant_id = 0) const bool REVERSE_Z = false;
+
+layout(location = 0) in vec2 inUV;
+
+layout(location = 0) out vec4 outFragColor;
+
+void main() {
+    ivec2 size = textureSize(samplerColor, 0);
+    ivec2 UV = ivec2(inUV * size);
+
+    vec3 current = texelFetch(samplerColor, UV, 0).rgb;
+    vec3 minColor = current;
+    vec3 maxColor = current;
+
+    // motion of the closest surface around keeps edges of moving objects out of the stale history
+    ivec2 closest = UV;
+    float closestDepth = texelFetch(samplerDepth, UV, 0).r;
+
+    for (int y = -1; y <= 1; y++) {
+        for (int x = -1; x <= 1; x++) {
+            ivec2 pos = clamp(UV + ivec2(x, y), ivec2(0), size - 1);
+
+            vec3 color = texelFetch(samplerColor, pos, 0).rgb;
+            minColor = min(minColor, color);
+            maxColor = max(maxColor, color);
+
+            float depth = texelFetch(samplerDepth, pos, 0).r;
+            bool closer = REVERSE_Z ? depth > closestDepth : depth < closestDepth;
+            if (closer) {
+                closestDepth = depth;
+                closest = pos;
+            }
+        }
+    }
+
+    vec2 velocity = texelFetch(samplerVelocity, closest, 0).xy;
+    vec2 historyUV = inUV - velocity;
+
+    bool offscreen = any(lessThan(historyUV, vec2(0.0))) || any(greaterThan(historyUV, vec2(1.0)));
+    if (constants.params.y == 0.0 || offscreen) {
+        outFragColor = vec4(current, 1.0);
+        return;
+    }
+
+    // neighborhood clamping: history outside of the colors around is disoccluded or changed
+    vec3 history = texture(samplerHistory, historyUV).rgb;
+    vec3 center = (minColor + maxColor) * 0.5;
+    vec3 extent = (maxColor - minColor) * 0.5 * constants.tweak_clampScale;
+    history = clamp(history, center - extent, center + extent);
+
+    outFragColor = vec4(mix(history, current, constants.params.x), 1.0);
+}
@@ -0,0 +1,146 @@
+// Forward shading of translucent meshes over the lit scene. The HDR pass has no depth attachment, the
+// G-buffer depth is tested here. Draws come sorted back to front, surfaces of one mesh are not sorted
+
+#include "../include/gbuffer_sampler.glsl"
+#include "../include/fog.glsl"
+
+// bindings 0 and 2 are of mesh.vert
+layout(binding = 1) uniform sampler2D texSampler;
+layout(binding = 3) uniform sampler2D normalMap;
+// glTF layout: g - roughness, b - metallic
+layout(binding = 4) uniform sampler2D metallicRoughnessMap;
+layout(binding = 5) uniform sampler2D occlusionMap;
+layout(binding = 6) uniform gbufferSampler samplerDepth;
+
+struct Light {
+    vec4 position; // w - kind: 0 point, 1 spot, 2 directional
+    vec4 direction; // w - cos of spot outer angle
+    vec4 color; // rgb - color * raw intensity, w - radius
+    vec4 params; // x - cos of spot inner angle
+};
+
+// dynamic lights of compose.frag
+layout(std430, binding = 7) readonly buffer LightBuffer {
+    uvec4 lightCount;
+    Light lights[];
+} lightBuffer;
+
+layout(binding = 8) uniform FragmentUniforms {
+    vec4 cameraPosition;
+    vec4 params; // x - exposure, y - opacity multiplier, z - ambient strength
+    vec4 sunDirection; // xyz - light direction of compose.glsl
+    vec4 fog; // height fog of compose.glsl, see include/fog.glsl
+    vec4 fogColor;
+} ubo;
+
+// x - metallic, y - roughness, z - occlusion strength
+layout(push_constant) uniform MaterialFactors {
+    layout(offset = 64) vec4 factors;
+} material;
+
+layout(constant_id = 0) const bool REVERSE_Z = false;
+
+layout(location = 0) in vec3 fragColor;
+layout(location = 1) in vec2 fragTexCoord;
+layout(location = 2) in vec4 fragPosition;
+layout(location = 3) in vec3 fragNormal;
+layout(location = 4) in vec4 fragTangent;
+
+layout(location = 0) out vec4 outColor;
+
+const float PI = 3.14159265359;
+// sun of compose.frag
+const float SUN_RADIANCE = 1.5 * PI;
+
+vec3 cookTorrance(vec3 N, vec3 V, vec3 L, vec3 albedo, float metallic, float roughness)
+{
+    vec3 H = normalize(V + L);
+    float NdotL = max(dot(N, L), 0.0);
+    float NdotV = max(dot(N, V), 1e-4);
+    float NdotH = max(dot(N, H), 0.0);
+
+    float a = roughness * roughness;
+    float a2 = a * a;
+    float d = NdotH * NdotH * (a2 - 1.0) + 1.0;
+    float D = a2 / (PI * d * d);
+
+    float k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+    float G = NdotV / (NdotV * (1.0 - k) + k) * NdotL / (NdotL * (1.0 - k) + k);
+
+    vec3 F0 = mix(vec3(0.04), albedo, metallic);
+    vec3 F = F0 + (1.0 - F0) * pow(1.0 - max(dot(H, V), 0.0), 5.0);
+
+    vec3 specular = D * G * F / (4.0 * NdotV * NdotL + 1e-4);
+    vec3 diffuse = (1.0 - F) * (1.0 - metallic) * albedo / PI;
+
+    return (diffuse + specular) * NdotL;
+}
+
+vec3 dynamicLights(vec3 pos, vec3 N, vec3 V, vec3 albedo, float metallic, float roughness)
+{
+    vec3 result = vec3(0.0);
+    for (uint i = 0; i < lightBuffer.lightCount.x; i++) {
+        Light light = lightBuffer.lights[i];
+
+        vec3 L;
+        float attenuation = 1.0;
+        if (light.position.w == 2.0) {
+            L = -light.direction.xyz;
+        } else {
+            vec3 toLight = light.position.xyz - pos;
+            float dist = length(toLight);
+            if (dist >= light.color.w) {
+                continue;
+            }
+            L = toLight / dist;
+
+            float falloff = clamp(1.0 - pow(dist / light.color.w, 4.0), 0.0, 1.0);
+            attenuation = falloff * falloff / max(dist * dist, 0.01);
+
+            if (light.position.w == 1.0) {
+                attenuation *= smoothstep(light.direction.w, light.params.x, dot(-L, light.direction.xyz));
+            }
+        }
+
+        if (attenuation > 0.0) {
+            result += cookTorrance(N, V, L, albedo, metallic, roughness) * PI * light.color.rgb * attenuation;
+        }
+    }
+
+    return result;
+}
+
+void main() {
+    float sceneDepth = gbufferFetch(samplerDepth, ivec2(gl_FragCoord.xy), 0).r;
+    bool hidden = REVERSE_Z ? gl_FragCoord.z < sceneDepth : gl_FragCoord.z > sceneDepth;
+    if (hidden) {
+        discard;
+    }
+
+    vec4 albedo = texture(texSampler, fragTexCoord);
+    float alpha = albedo.a * ubo.params.y;
+    if (alpha <= 0.0) {
+        discard;
+    }
+
+    vec3 n = normalize(fragNormal);
+    vec3 t = normalize(fragTangent.xyz - n * dot(n, fragTangent.xyz));
+    vec3 b = cross(n, t) * fragTangent.w;
+    vec3 N = normalize(mat3(t, b, n) * (texture(normalMap, fragTexCoord).xyz * 2.0 - 1.0));
+    vec3 pos = fragPosition.xyz / fragPosition.w;
+    vec3 V = normalize(ubo.cameraPosition.xyz - pos);
+
+    vec2 metallicRoughness = texture(metallicRoughnessMap, fragTexCoord).bg * material.factors.xy;
+    float roughness = max(metallicRoughness.y, 0.04);
+    float occlusion = mix(1.0, texture(occlusionMap, fragTexCoord).r, material.factors.z);
+
+    vec3 color = cookTorrance(N, V, normalize(ubo.sunDirection.xyz), albedo.rgb, metallicRoughness.x, roughness) * SUN_RADIANCE;
+    color += dynamicLights(pos, N, V, albedo.rgb, metallicRoughness.x, roughness);
+    color += albedo.rgb * ubo.params.z * occlusion;
+    // the scene behind is fogged by the composite pass
+    color = applyHeightFog(color, pos, ubo.cameraPosition.xyz, normalize(ubo.sunDirection.xyz), SUN_RADIANCE, ubo.fog, ubo.fogColor);
+    color *= ubo.params.x;
+
+    // premultiplied alpha, the pipeline blends with ONE, ONE_MINUS_SRC_ALPHA
+    outColor = vec4(color * alpha, alpha);
+}
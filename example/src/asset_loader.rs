@@ -0,0 +1,42 @@
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+// Job running on its own thread, polled from the frame loop until the result arrives
+pub struct LoadHandle<T> {
+    name: String,
+    receiver: Receiver<Result<T, String>>,
+}
+
+impl<T: Send + 'static> LoadHandle<T> {
+    pub fn spawn<F>(name: &str, job: F) -> LoadHandle<T>
+        where F: FnOnce() -> Result<T, String> + Send + 'static
+    {
+        let (sender, receiver) = mpsc::channel();
+
+        thread::Builder::new()
+            .name(format!("load {}", name))
+            .spawn(move || {
+                // nobody waits for the result when the app is closed during loading
+                let _ = sender.send(job());
+            })
+            .expect("Failed to spawn loader thread!");
+
+        LoadHandle {
+            name: name.to_string(),
+            receiver,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // None while the job is running. The result is returned only once, the handle is dropped after that
+    pub fn poll(&self) -> Option<Result<T, String>> {
+        match self.receiver.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err("Loader thread panicked".to_string())),
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use ash_render_env::env::RenderEnv;
+use ash_render_env::pipeline_cache::PipelineWarmup;
+use ash_render_env::utils::ktx2::CompressedFormats;
+use ash_render_env::utils::texture::ImageData;
+
+use crate::asset_loader::LoadHandle;
+use crate::utils::cube_texture::{CubeTexture, FaceOrientation};
+use crate::utils::heightmap_terrain::terrain::{HeightMap, TerrainScale};
+use crate::utils::model::{Model, ModelData};
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum AssetKind {
+    Model,
+    HeightMap,
+    Skybox,
+}
+
+impl AssetKind {
+    fn filter(&self) -> (&'static str, &'static [&'static str]) {
+        match self {
+            AssetKind::Model => ("Model", &["obj", "gltf", "glb"]),
+            AssetKind::HeightMap => ("Heightmap image or raw heightfield", &["png", "jpg", "bmp", "tga", "r16", "r32"]),
+            AssetKind::Skybox => ("Skybox: HDR environment, cube cross or strip", &["hdr", "png", "jpg"]),
+        }
+    }
+}
+
+// Native open dialog starting in the assets directory, None when cancelled
+#[cfg(feature = "file-dialogs")]
+pub fn pick_file(kind: AssetKind) -> Option<PathBuf> {
+    let (name, extensions) = kind.filter();
+
+    rfd::FileDialog::new()
+        .set_title(format!("Open {}", name))
+        .add_filter(name, extensions)
+        .set_directory(Path::new("assets"))
+        .pick_file()
+}
+
+#[cfg(not(feature = "file-dialogs"))]
+pub fn pick_file(_kind: AssetKind) -> Option<PathBuf> {
+    println!("File dialogs are disabled, build with the `file-dialogs` feature");
+    None
+}
+
+// Decoded by a loader thread, not uploaded yet
+enum DecodedAsset {
+    Model(ModelData),
+    Skybox(ImageData),
+    Environment(ImageData),
+}
+
+struct PendingLoad {
+    path: PathBuf,
+    handle: LoadHandle<DecodedAsset>,
+}
+
+// Asset uploaded to the GPU and ready to be used by renderers
+pub enum LoadedAsset {
+    Model(Arc<Model>),
+    Skybox(Box<CubeTexture>),
+}
+
+// Assets loaded at runtime. Files are decoded on loader threads and uploaded in poll(),
+// models are kept by path, so switching back to a model doesn't reload it
+pub struct AssetCache {
+    models: HashMap<PathBuf, Arc<Model>>,
+    current: HashMap<AssetKind, PathBuf>,
+    last_error: Option<String>,
+
+    pending: Vec<PendingLoad>,
+    ready: Vec<LoadedAsset>,
+    // progress of the current batch, reset when everything is loaded
+    batch_size: usize,
+    // loader threads pick KTX2 textures by them
+    compressed_formats: CompressedFormats,
+    // applied to cube faces of skyboxes loaded after a change, equirect ones are not affected
+    pub skybox_orientation: FaceOrientation,
+    // world size of heightmaps, applied to the current one with the "Apply" button
+    pub terrain_scale: TerrainScale,
+}
+
+impl AssetCache {
+    pub fn new(env: &RenderEnv) -> AssetCache {
+        AssetCache {
+            models: HashMap::new(),
+            current: HashMap::new(),
+            last_error: None,
+            pending: vec![],
+            ready: vec![],
+            batch_size: 0,
+            compressed_formats: CompressedFormats::query(env),
+            skybox_orientation: FaceOrientation::default(),
+            terrain_scale: TerrainScale::default(),
+        }
+    }
+
+    // OBJ or glTF model with its materials, delivered by poll()
+    pub fn request_model(&mut self, path: &Path) {
+        if let Some(model) = self.models.get(path).cloned() {
+            self.loaded(AssetKind::Model, path);
+            self.ready.push(LoadedAsset::Model(model));
+            return;
+        }
+
+        let job_path = path.to_path_buf();
+        let formats = self.compressed_formats.clone();
+        self.start(path, move || Ok(DecodedAsset::Model(ModelData::load(&job_path, &formats)?)));
+    }
+
+    // Directory with the six cube faces, an image with a cross or strip of them or an equirectangular
+    // .hdr image, delivered by poll()
+    pub fn request_skybox(&mut self, path: &Path) {
+        let job_path = path.to_path_buf();
+        let orientation = self.skybox_orientation;
+        if path.is_dir() {
+            self.start(path, move || Ok(DecodedAsset::Skybox(CubeTexture::load_faces(&job_path, orientation)?)));
+        } else if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("hdr")) {
+            self.start(path, move || Ok(DecodedAsset::Environment(CubeTexture::load_equirect(&job_path)?)));
+        } else {
+            self.start(path, move || Ok(DecodedAsset::Skybox(CubeTexture::load_cross(&job_path, orientation)?)));
+        }
+    }
+
+    // Uploads assets decoded since the last call
+    pub fn poll(&mut self, env: &Arc<RenderEnv>) -> Vec<LoadedAsset> {
+        let mut idx = 0;
+        while idx < self.pending.len() {
+            let result = match self.pending[idx].handle.poll() {
+                Some(result) => result,
+                None => {
+                    idx += 1;
+                    continue;
+                }
+            };
+
+            let load = self.pending.remove(idx);
+            match result {
+                Ok(DecodedAsset::Model(model)) => {
+                    let model = Arc::new(Model::upload(env, model));
+                    self.models.insert(load.path.clone(), model.clone());
+                    self.loaded(AssetKind::Model, &load.path);
+                    self.ready.push(LoadedAsset::Model(model));
+                }
+                Ok(DecodedAsset::Skybox(faces)) => {
+                    self.ready.push(LoadedAsset::Skybox(Box::new(CubeTexture::upload(env, &faces))));
+                }
+                Ok(DecodedAsset::Environment(equirect)) => {
+                    self.loaded(AssetKind::Skybox, &load.path);
+                    self.ready.push(LoadedAsset::Skybox(Box::new(CubeTexture::from_equirect(env, &equirect))));
+                }
+                Err(err) => self.failed(&load.path, err),
+            }
+        }
+
+        if self.pending.is_empty() {
+            self.batch_size = 0;
+        }
+
+        std::mem::take(&mut self.ready)
+    }
+
+    // Loaded share of the current batch, None when nothing is loading
+    pub fn progress(&self) -> Option<f32> {
+        if self.pending.is_empty() {
+            return None;
+        }
+
+        Some((self.batch_size - self.pending.len()) as f32 / self.batch_size as f32)
+    }
+
+    // File name of the asset in use
+    pub fn current_name(&self, kind: AssetKind) -> Option<String> {
+        self.current.get(&kind)
+            .and_then(|path| path.file_name())
+            .map(|name| name.to_string_lossy().to_string())
+    }
+
+    // Heightmaps are cheap to decode and consumed by terrain, they are not kept
+    pub fn current_path(&self, kind: AssetKind) -> Option<&Path> {
+        self.current.get(&kind).map(|path| path.as_path())
+    }
+
+    pub fn load_height_map(&mut self, path: &Path) -> Option<HeightMap> {
+        match HeightMap::load(path, self.terrain_scale) {
+            Ok(height_map) => {
+                self.loaded(AssetKind::HeightMap, path);
+                Some(height_map)
+            }
+            Err(err) => {
+                self.failed(path, err);
+                None
+            }
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) -> Option<(AssetKind, PathBuf)> {
+        let mut picked = None;
+
+        for &kind in [AssetKind::Model, AssetKind::HeightMap, AssetKind::Skybox].iter() {
+            ui.horizontal(|ui| {
+                if ui.button(format!("{:?}...", kind)).clicked() {
+                    picked = pick_file(kind).map(|path| (kind, path));
+                }
+
+                ui.label(self.current_name(kind).unwrap_or_else(|| "-".to_string()));
+            });
+        }
+
+        // the current skybox is reloaded with the new orientation
+        let orientation = self.skybox_orientation;
+        ui.horizontal(|ui| {
+            ui.label("Skybox faces:");
+            ui.checkbox(&mut self.skybox_orientation.flip_horizontal, "flip X");
+            ui.checkbox(&mut self.skybox_orientation.flip_vertical, "flip Y");
+            ui.checkbox(&mut self.skybox_orientation.swap_front_back, "swap front/back");
+        });
+        if self.skybox_orientation != orientation && picked.is_none() {
+            picked = self.current.get(&AssetKind::Skybox).map(|path| (AssetKind::Skybox, path.clone()));
+        }
+
+        // rebuilding terrain on every drag step would stall, the current heightmap is reloaded on request
+        ui.horizontal(|ui| {
+            ui.label("Heightmap:");
+            ui.add(egui::DragValue::new(&mut self.terrain_scale.vertical).prefix("height: ").speed(0.05)
+                .clamp_range(RangeInclusive::new(0.1, 100.0)));
+            ui.add(egui::DragValue::new(&mut self.terrain_scale.spacing).prefix("spacing: ").speed(0.005)
+                .clamp_range(RangeInclusive::new(0.01, 10.0)));
+            if ui.button("Apply").clicked() && picked.is_none() {
+                picked = self.current.get(&AssetKind::HeightMap).map(|path| (AssetKind::HeightMap, path.clone()));
+            }
+        });
+
+        if let Some(err) = &self.last_error {
+            ui.colored_label(egui::Color32::RED, err);
+        }
+
+        picked
+    }
+
+    // Window in the middle of the screen while anything is loading or pipelines are warmed up
+    pub fn progress_ui(&self, ctx: &egui::CtxRef, warmup: Option<&PipelineWarmup>) {
+        let warmup = warmup.filter(|warmup| !warmup.is_finished());
+        if self.pending.is_empty() && warmup.is_none() {
+            return;
+        }
+
+        egui::Window::new("Loading")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                if let Some(warmup) = warmup {
+                    ui.label(format!("Compiling pipelines: {} of {}", warmup.done(), warmup.total()));
+                    progress_bar(ui, warmup.done() as f32 / warmup.total() as f32);
+                }
+
+                if !self.pending.is_empty() {
+                    let done = self.batch_size - self.pending.len();
+                    ui.label(format!("Loading assets: {} of {}", done, self.batch_size));
+                    progress_bar(ui, self.progress().unwrap_or(1.0));
+                }
+
+                for load in self.pending.iter() {
+                    ui.label(load.handle.name());
+                }
+            });
+    }
+
+    fn start<F>(&mut self, path: &Path, job: F)
+        where F: FnOnce() -> Result<DecodedAsset, String> + Send + 'static
+    {
+        if self.pending.iter().any(|load| load.path == path) {
+            return;
+        }
+
+        let name = path.file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.display().to_string());
+
+        self.pending.push(PendingLoad {
+            path: path.to_path_buf(),
+            handle: LoadHandle::spawn(&name, job),
+        });
+        self.batch_size += 1;
+    }
+
+    fn failed(&mut self, path: &Path, err: String) {
+        println!("{}: {}", path.display(), err);
+        self.last_error = Some(format!("{}: {}", path.display(), err));
+    }
+
+    fn loaded(&mut self, kind: AssetKind, path: &Path) {
+        self.current.insert(kind, path.to_path_buf());
+        self.last_error = None;
+    }
+}
+
+fn progress_bar(ui: &mut egui::Ui, progress: f32) {
+    let (rect, _) = ui.allocate_exact_size(egui::vec2(240.0, 8.0), egui::Sense::hover());
+    let mut filled = rect;
+    filled.max.x = rect.min.x + rect.width() * progress;
+    ui.painter().rect_filled(rect, 2.0, ui.visuals().extreme_bg_color);
+    ui.painter().rect_filled(filled, 2.0, ui.visuals().selection.bg_fill);
+}
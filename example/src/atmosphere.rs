@@ -0,0 +1,82 @@
+use cgmath::{InnerSpace, Vector3};
+
+// Light direction the sun had before it could be moved
+const DEFAULT_SUN_DIRECTION: [f32; 3] = [0.70, 0.25, -0.67];
+
+// Sun position and height fog of the compose pass.
+// The sun direction is shared by the shadow cascades, the reflective shadow map, god rays and the lighting,
+// so moving the sun moves the shadows and the haze around it together
+pub struct Atmosphere {
+    // degrees, world Y points down so the elevation is measured towards -Y
+    pub sun_azimuth: f32,
+    pub sun_elevation: f32,
+
+    pub fog_enabled: bool,
+    // extinction per world unit at the base altitude
+    pub fog_density: f32,
+    // how fast the density falls off with altitude, 0 - uniform fog
+    pub fog_height_falloff: f32,
+    pub fog_base_altitude: f32,
+    // color of the fog lit by the sun, scaled as albedo
+    pub fog_color: [f32; 3],
+    // 0 - plain fog, 1 - wavelength dependent extinction and a haze around the sun
+    pub scattering: f32,
+    // the sky is fogged as a surface at this distance
+    pub sky_distance: f32,
+}
+
+impl Atmosphere {
+    pub fn new() -> Atmosphere {
+        let direction = Vector3::from(DEFAULT_SUN_DIRECTION).normalize();
+
+        Atmosphere {
+            sun_azimuth: direction.z.atan2(direction.x).to_degrees(),
+            sun_elevation: direction.y.asin().to_degrees(),
+            fog_enabled: true,
+            fog_density: 0.03,
+            fog_height_falloff: 0.15,
+            fog_base_altitude: 0.0,
+            fog_color: [0.25, 0.28, 0.32],
+            scattering: 0.5,
+            sky_distance: 48.0,
+        }
+    }
+
+    // Light direction of the shadow cascades and compose.glsl, the sun itself is at -direction
+    pub fn sun_direction(&self) -> Vector3<f32> {
+        let (azimuth, elevation) = (self.sun_azimuth.to_radians(), self.sun_elevation.to_radians());
+        Vector3::new(elevation.cos() * azimuth.cos(), elevation.sin(), elevation.cos() * azimuth.sin())
+    }
+
+    // `fog` and `fogColor` of compose.glsl
+    pub fn fog_params(&self) -> [[f32; 4]; 2] {
+        let density = if self.fog_enabled { self.fog_density } else { 0.0 };
+        let [r, g, b] = self.fog_color;
+
+        [
+            [density, self.fog_height_falloff, self.fog_base_altitude, self.sky_distance],
+            [r, g, b, self.scattering],
+        ]
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        // the light views look at the scene with Y as up, a sun straight above has no valid view
+        ui.add(egui::Slider::new(&mut self.sun_elevation, 2.0..=88.0).text("Sun elevation"));
+        ui.add(egui::Slider::new(&mut self.sun_azimuth, -180.0..=180.0).text("Sun azimuth"));
+
+        ui.checkbox(&mut self.fog_enabled, "Fog");
+        let fog_enabled = self.fog_enabled;
+        ui.scope(|ui| {
+            ui.set_enabled(fog_enabled);
+            ui.add(egui::Slider::new(&mut self.fog_density, 0.0..=0.3).logarithmic(true).text("Fog density"));
+            ui.add(egui::Slider::new(&mut self.fog_height_falloff, 0.0..=1.0).text("Fog height falloff"));
+            ui.add(egui::Slider::new(&mut self.fog_base_altitude, -10.0..=10.0).text("Fog base altitude"));
+            ui.horizontal(|ui| {
+                ui.color_edit_button_rgb(&mut self.fog_color);
+                ui.label("Fog color");
+            });
+            ui.add(egui::Slider::new(&mut self.scattering, 0.0..=1.0).text("Atmospheric scattering"));
+            ui.add(egui::Slider::new(&mut self.sky_distance, 10.0..=200.0).text("Sky distance"));
+        });
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::SystemTime;
+
+use ash_render_env::shader::{self, ShaderReflection};
+use ash_render_env::shader_bundle::ShaderBundle;
+
+const SRC_DIR: &str = "assets/shaders/src";
+const SPV_DIR: &str = "assets/shaders/spv";
+const BUNDLE_PATH: &str = "assets/shaders/shaders.bundle";
+
+const STAGE_EXTENSIONS: [&str; 6] = ["vert", "frag", "geom", "tesc", "tese", "comp"];
+
+// Full-screen passes only have a fragment shader and are drawn with this vertex stage
+const FULLSCREEN_VERTEX: &str = "compose.vert";
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = fs::read_dir(dir)
+        .unwrap_or_else(|err| panic!("Failed to read {:?}: {}", dir, err));
+
+    for entry in entries {
+        let path = entry.expect("Failed to read directory entry!").path();
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| extensions.contains(&ext))
+}
+
+// Source path relative to the shaders directory with `/` separators, the name in the bundle
+fn shader_name(src: &Path) -> String {
+    let relative = src.strip_prefix(SRC_DIR).unwrap();
+    relative.components()
+        .map(|part| part.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// glslc of the Vulkan SDK, includes are resolved relative to the source
+fn compile(src: &Path, dst: &Path) -> Result<(), String> {
+    let status = Command::new("glslc")
+        .arg("-o").arg(dst)
+        .arg(src)
+        .status()
+        .map_err(|err| format!("Failed to run glslc ({}), is the Vulkan SDK in PATH?", err))?;
+
+    if !status.success() {
+        return Err(format!("glslc failed with {}", status));
+    }
+
+    Ok(())
+}
+
+// Pipelines of the app: every shader with the other stages of the same name,
+// fragment shaders without their own vertex stage are full-screen passes
+fn pipelines(names: &[String]) -> Vec<Vec<String>> {
+    let mut by_stem: HashMap<&str, Vec<String>> = HashMap::new();
+    for name in names {
+        let stem = name.rsplit_once('.').map_or(name.as_str(), |(stem, _)| stem);
+        by_stem.entry(stem).or_default().push(name.clone());
+    }
+
+    let mut ret = vec![];
+    for (stem, mut stages) in by_stem {
+        let own_vertex = format!("{}.vert", stem);
+        if stages.iter().any(|name| name.ends_with(".frag")) && !stages.contains(&own_vertex) {
+            stages.push(FULLSCREEN_VERTEX.to_string());
+        }
+        stages.sort();
+        ret.push(stages);
+    }
+    ret.sort();
+
+    ret
+}
+
+// Compiles changed shaders to SPIR-V, checks their descriptor layouts the way the
+// renderer builds them and writes every shader into the bundle: `cook-shaders [--force]`
+fn main() {
+    let force = std::env::args().skip(1).any(|arg| arg == "--force");
+
+    let mut files = vec![];
+    collect_files(Path::new(SRC_DIR), &mut files);
+    files.sort();
+
+    // shaders are rebuilt when any shared include changes
+    let includes_modified = files.iter()
+        .filter(|path| has_extension(path, &["glsl"]))
+        .filter_map(|path| modified(path))
+        .max();
+
+    let mut errors = vec![];
+    let mut reflections = HashMap::new();
+    let mut bundle = ShaderBundle::new();
+    let mut compiled = 0;
+
+    for src in files.iter().filter(|path| has_extension(path, &STAGE_EXTENSIONS)) {
+        let name = shader_name(src);
+        let dst = Path::new(SPV_DIR).join(format!("{}.spv", name));
+        fs::create_dir_all(dst.parent().unwrap()).expect("Failed to create spv directory!");
+
+        let src_modified = modified(src).max(includes_modified);
+        let up_to_date = !force && modified(&dst).is_some_and(|dst_modified| Some(dst_modified) > src_modified);
+        if !up_to_date {
+            println!("Process {}", name);
+            if let Err(err) = compile(src, &dst) {
+                errors.push(format!("{}: {}", name, err));
+                continue;
+            }
+            compiled += 1;
+        }
+
+        let code = fs::read(&dst).expect("Failed to read spv file!");
+        match ShaderReflection::new(&code) {
+            Ok(reflection) => {
+                reflections.insert(name.clone(), reflection);
+            }
+            Err(err) => errors.push(format!("{}: reflection failed: {}", name, err)),
+        }
+        bundle.insert(&name, code);
+    }
+
+    let names: Vec<String> = reflections.keys().cloned().collect();
+    for stages in pipelines(&names) {
+        let stage_reflections: Vec<&ShaderReflection> = stages.iter()
+            .filter_map(|name| reflections.get(name))
+            .collect();
+
+        if let Err(err) = shader::validate_layout(&stage_reflections) {
+            errors.push(format!("{}: {}", stages.join(" + "), err));
+        }
+    }
+
+    if !errors.is_empty() {
+        for err in errors.iter() {
+            eprintln!("error: {}", err);
+        }
+        eprintln!("{} error(s), bundle is not written", errors.len());
+        process::exit(1);
+    }
+
+    bundle.write(Path::new(BUNDLE_PATH)).expect("Failed to write shader bundle!");
+    println!("Compiled {}, bundled {} shaders into {}", compiled, bundle.len(), BUNDLE_PATH);
+}
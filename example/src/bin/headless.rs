@@ -0,0 +1,161 @@
+use std::ptr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use ash::vk;
+
+use ash_render_env::env::RenderEnv;
+use ash_render_env::frame_buffer::{AttachmentDesciption, Framebuffer};
+use ash_render_env::multi_gpu::MultiGpuSupport;
+use ash_render_env::primary_cmd_buffer::PrimaryCommandBuffer;
+
+const DIMENSIONS: [u32; 2] = [640, 480];
+
+// Offscreen frame of one device
+struct FrameRenderer {
+    env: Arc<RenderEnv>,
+    framebuffer: Framebuffer,
+    primary_cmd_buffer: PrimaryCommandBuffer,
+}
+
+impl FrameRenderer {
+    fn new(env: Arc<RenderEnv>) -> FrameRenderer {
+        let mut framebuffer = Framebuffer::new(env.clone(), vec![
+            AttachmentDesciption {
+                format: vk::Format::R8G8B8A8_UNORM,
+                samples_count: vk::SampleCountFlags::TYPE_1,
+            },
+        ]);
+        framebuffer.resize_swapchain(DIMENSIONS);
+
+        let mut primary_cmd_buffer = PrimaryCommandBuffer::new(env.clone(), 1);
+        primary_cmd_buffer.set_dimensions(DIMENSIONS);
+
+        FrameRenderer {
+            env,
+            framebuffer,
+            primary_cmd_buffer,
+        }
+    }
+
+    // Starts the frame without waiting for it
+    fn submit(&mut self, clear_color: [f32; 4]) {
+        let clear_values = vec![
+            vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: clear_color,
+                },
+            },
+        ];
+        let command_buffer = self.primary_cmd_buffer.execute_secondary(
+            clear_values, self.framebuffer.framebuffer.unwrap(), self.framebuffer.render_pass(), &[]);
+
+        let command_buffers = [command_buffer];
+        let submit_infos = [vk::SubmitInfo {
+            s_type: vk::StructureType::SUBMIT_INFO,
+            p_next: ptr::null(),
+            wait_semaphore_count: 0,
+            p_wait_semaphores: ptr::null(),
+            p_wait_dst_stage_mask: ptr::null(),
+            command_buffer_count: command_buffers.len() as u32,
+            p_command_buffers: command_buffers.as_ptr(),
+            signal_semaphore_count: 0,
+            p_signal_semaphores: ptr::null(),
+        }];
+
+        self.env.queue()
+            .submit(self.env.device(), &submit_infos, vk::Fence::null())
+            .expect("Failed to execute queue submit.");
+    }
+
+    // Waits for the submitted frame and copies it to host memory
+    fn finish(&self) -> Vec<u8> {
+        self.env.queue().wait_idle(self.env.device()).unwrap();
+
+        self.framebuffer.read_color_attachment(0)
+    }
+}
+
+impl Drop for FrameRenderer {
+    fn drop(&mut self) {
+        self.framebuffer.destroy();
+    }
+}
+
+fn frame_color(frame: usize) -> [f32; 4] {
+    let t = frame as f32 * 0.1;
+    [0.2 + 0.2 * t.sin().abs(), 0.3, 0.5, 1.0]
+}
+
+// EXPERIMENTAL: alternate frame rendering, every GPU renders each n-th frame.
+// Frames come back through host memory, which is the cross-device transfer here.
+fn alternate_frames(frame_count: usize, output_prefix: &str) {
+    println!("!!! WARNING: multi-GPU mode is an experiment, expect missing features and driver issues !!!");
+
+    let first = Arc::new(RenderEnv::new_headless());
+    let support = MultiGpuSupport::detect(&first);
+    println!("GPUs: {:?}", support.device_names);
+    if support.device_group_sizes.is_empty() {
+        println!("Device groups: not available (Vulkan 1.0 instance)");
+    } else {
+        println!("Device groups: {:?}, linked: {}", support.device_group_sizes, support.has_linked_group());
+    }
+
+    let mut envs = vec![first];
+    if support.can_alternate_frames() {
+        for device_index in 1..support.suitable_device_count {
+            envs.push(Arc::new(RenderEnv::new_headless_on_device(device_index)));
+        }
+    } else {
+        println!("Only one suitable GPU, all frames are rendered by it");
+    }
+
+    let mut renderers: Vec<_> = envs.iter().map(|env| FrameRenderer::new(env.clone())).collect();
+    for (idx, env) in envs.iter().enumerate() {
+        println!("Device {}: {}", idx, env.device_name());
+    }
+
+    let start = Instant::now();
+    let mut frame = 0;
+    while frame < frame_count {
+        // one frame in flight on every device
+        let batch = renderers.len().min(frame_count - frame);
+        for (offset, renderer) in renderers.iter_mut().take(batch).enumerate() {
+            renderer.submit(frame_color(frame + offset));
+        }
+
+        for (offset, renderer) in renderers.iter().take(batch).enumerate() {
+            let pixels = renderer.finish();
+            let path = format!("{}_{:03}.png", output_prefix, frame + offset);
+            ash_render_env::utils::readback::save_rgba(&path, pixels, DIMENSIONS).expect("Failed to save frame!");
+        }
+        frame += batch;
+    }
+
+    let elapsed = start.elapsed().as_secs_f32();
+    println!("{} frames on {} device(s) in {:.3}s ({:.1} fps)", frame_count, renderers.len(), elapsed, frame_count as f32 / elapsed);
+
+    drop(renderers);
+}
+
+// Renders one frame without a window and saves it: `headless [output.png]`.
+// `headless --afr <frames> [prefix]` is the experimental multi-GPU mode
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    if args.first().map(String::as_str) == Some("--afr") {
+        let frame_count = args.get(1).and_then(|count| count.parse().ok()).unwrap_or(8);
+        let prefix = args.get(2).cloned().unwrap_or_else(|| "afr".to_string());
+        alternate_frames(frame_count, &prefix);
+        return;
+    }
+
+    let output = args.first().cloned().unwrap_or_else(|| "headless.png".to_string());
+    let env = Arc::new(RenderEnv::new_headless());
+
+    let mut renderer = FrameRenderer::new(env);
+    renderer.submit([0.2, 0.3, 0.5, 1.0]);
+    let pixels = renderer.finish();
+    ash_render_env::utils::readback::save_rgba(&output, pixels, DIMENSIONS).expect("Failed to save frame!");
+    println!("Saved {}x{} frame to {}", DIMENSIONS[0], DIMENSIONS[1], output);
+}
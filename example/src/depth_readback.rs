@@ -0,0 +1,143 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use ash::vk;
+
+use ash_render_env::egui::Egui;
+use ash_render_env::env::RenderEnv;
+use ash_render_env::utils::readback;
+use ash_render_env::utils::texture::Texture;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DepthSource {
+    Scene,
+    Cascade(usize),
+}
+
+impl DepthSource {
+    pub fn name(&self) -> String {
+        match self {
+            DepthSource::Scene => "Scene depth".to_string(),
+            DepthSource::Cascade(idx) => format!("Shadow cascade {}", idx + 1),
+        }
+    }
+
+    pub fn file_name(&self) -> String {
+        match self {
+            DepthSource::Scene => "depth_scene.png".to_string(),
+            DepthSource::Cascade(idx) => format!("depth_cascade_{}.png", idx + 1),
+        }
+    }
+}
+
+// Requested from the gui, main reads the depth once the frame is finished
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DepthReadbackAction {
+    Show(DepthSource),
+    Save(DepthSource),
+}
+
+// Depth read back to the host and linearized: scene depth in view distance, cascades as stored,
+// orthographic depth is linear already
+pub struct LinearDepth {
+    pub source: DepthSource,
+    pub values: Vec<f32>,
+    pub dimensions: [u32; 2],
+}
+
+impl LinearDepth {
+    // Grayscale PNG stretched over the value range
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> image::ImageResult<()> {
+        let (pixels, _) = readback::grayscale_rgba(&self.values);
+        readback::save_rgba(path, pixels, self.dimensions)
+    }
+}
+
+// Debug view of the scene depth and shadow cascades, to check their contents without a frame debugger
+pub struct DepthReadback {
+    env: Arc<RenderEnv>,
+    source: DepthSource,
+    // shown image, the texture is kept alive while egui samples it
+    texture: Option<(Texture, egui::TextureId)>,
+    dimensions: [u32; 2],
+    range: [f32; 2],
+    shown: Option<DepthSource>,
+}
+
+impl DepthReadback {
+    pub fn new(env: Arc<RenderEnv>) -> DepthReadback {
+        DepthReadback {
+            env,
+            source: DepthSource::Scene,
+            texture: None,
+            dimensions: [0, 0],
+            range: [0.0, 0.0],
+            shown: None,
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui, cascade_count: usize) -> Option<DepthReadbackAction> {
+        let mut action = None;
+
+        egui::ComboBox::from_label("Source")
+            .selected_text(self.source.name())
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut self.source, DepthSource::Scene, DepthSource::Scene.name());
+                for cascade_idx in 0..cascade_count {
+                    let source = DepthSource::Cascade(cascade_idx);
+                    ui.selectable_value(&mut self.source, source, source.name());
+                }
+            });
+
+        ui.horizontal(|ui| {
+            if ui.button("Read").clicked() {
+                action = Some(DepthReadbackAction::Show(self.source));
+            }
+            if ui.button(format!("Save {}", self.source.file_name())).clicked() {
+                action = Some(DepthReadbackAction::Save(self.source));
+            }
+        });
+
+        if let (Some(shown), Some((_, texture_id))) = (self.shown, &self.texture) {
+            ui.label(format!("{}, {}x{}, {:.3} .. {:.3}", shown.name(), self.dimensions[0], self.dimensions[1], self.range[0], self.range[1]));
+            let width = ui.available_width().min(self.dimensions[0] as f32);
+            let height = width * self.dimensions[1] as f32 / self.dimensions[0].max(1) as f32;
+            ui.image(*texture_id, [width, height]);
+        }
+
+        action
+    }
+
+    // Uploads `depth` as the shown image, the previous texture must not be in use by the GPU
+    pub fn show(&mut self, egui: &mut Egui, depth: &LinearDepth) {
+        let (pixels, range) = readback::grayscale_rgba(&depth.values);
+        let texture = Texture::from_pixels(self.env.device().clone(), self.env.command_pool(), self.env.queue(), &self.env.mem_properties,
+                                           vk::Format::R8G8B8A8_UNORM, &pixels, depth.dimensions[0], depth.dimensions[1], false, 1.0);
+        let texture = match texture {
+            Ok(texture) => texture,
+            Err(err) => {
+                println!("Failed to upload depth image: {}", err);
+                return;
+            }
+        };
+
+        let texture_id = match self.texture.take() {
+            Some((_, texture_id)) => {
+                egui.replace_texture(texture_id, texture.texture_image_view, false);
+                texture_id
+            }
+            None => egui.add_texture(texture.texture_image_view, false),
+        };
+
+        self.texture = Some((texture, texture_id));
+        self.dimensions = depth.dimensions;
+        self.range = range;
+        self.shown = Some(depth.source);
+    }
+
+    pub fn destroy(&mut self, egui: &mut Egui) {
+        if let Some((_, texture_id)) = self.texture.take() {
+            egui.unregister_texture(texture_id);
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::ops::RangeInclusive;
+
+use cgmath::Deg;
+
+use crate::utils::decals::{Decal, DecalRenderer};
+use crate::utils::heightmap_terrain::raycast::Hit;
+
+// Decal brush: right clicks on the terrain put the selected texture there, facing along the surface normal
+pub struct DecalTool {
+    pub enabled: bool,
+
+    texture: usize,
+    size: f32,
+    depth: f32,
+    angle: f32,
+    opacity: f32,
+}
+
+impl DecalTool {
+    pub fn new() -> DecalTool {
+        DecalTool {
+            enabled: false,
+            texture: 0,
+            size: 2.0,
+            depth: 1.0,
+            angle: 0.0,
+            opacity: 1.0,
+        }
+    }
+
+    pub fn place(&self, hit: &Hit, decals: &mut DecalRenderer) {
+        let decal = Decal {
+            position: hit.position,
+            normal: hit.normal,
+            size: [self.size, self.size],
+            depth: self.depth,
+            angle: Deg(self.angle).into(),
+            texture: self.texture,
+            opacity: self.opacity,
+        };
+
+        if !decals.add(decal) {
+            println!("Decal is not placed, the limit is reached");
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui, decals: &mut DecalRenderer) {
+        decals.ui(ui);
+        ui.checkbox(&mut self.enabled, "Place decals (right click on terrain)");
+        if !self.enabled {
+            return;
+        }
+
+        let names = decals.texture_names();
+        egui::ComboBox::from_label("Decal texture")
+            .selected_text(names.get(self.texture).copied().unwrap_or("-"))
+            .show_ui(ui, |ui| {
+                for (idx, name) in names.iter().enumerate() {
+                    ui.selectable_value(&mut self.texture, idx, *name);
+                }
+            });
+
+        ui.add(egui::DragValue::new(&mut self.size).speed(0.05).clamp_range(RangeInclusive::new(0.1, 50.0)).prefix("Size: "));
+        ui.add(egui::DragValue::new(&mut self.depth).speed(0.05).clamp_range(RangeInclusive::new(0.1, 20.0)).prefix("Depth: "));
+        ui.add(egui::Slider::new(&mut self.angle, 0.0..=360.0).suffix("°").text("Angle"));
+        ui.add(egui::Slider::new(&mut self.opacity, 0.0..=1.0).text("Opacity"));
+    }
+}
@@ -0,0 +1,285 @@
+use std::any::Any;
+use std::time::{Duration, Instant};
+
+use super::scene::EditorScene;
+
+const MAX_HISTORY: usize = 256;
+
+// Edits of the same value closer than this are stored as a single step (dragging a slider, picking a color)
+const MERGE_INTERVAL: Duration = Duration::from_millis(500);
+
+pub trait Command {
+    fn name(&self) -> String;
+    fn apply(&mut self, scene: &mut EditorScene);
+    fn revert(&mut self, scene: &mut EditorScene);
+
+    // Absorbs `next` edit into this one, returns false if they can't be merged
+    fn merge(&mut self, _next: &dyn Command) -> bool {
+        false
+    }
+
+    fn as_any(&self) -> &dyn Any;
+}
+
+pub struct History {
+    done: Vec<Box<dyn Command>>,
+    undone: Vec<Box<dyn Command>>,
+    last_execute: Option<Instant>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            done: vec![],
+            undone: vec![],
+            last_execute: None,
+        }
+    }
+
+    pub fn execute(&mut self, command: Box<dyn Command>, scene: &mut EditorScene) {
+        self.execute_at(command, scene, Instant::now());
+    }
+
+    // `now` is the time of the edit, it is merged into the previous one within MERGE_INTERVAL
+    pub fn execute_at(&mut self, mut command: Box<dyn Command>, scene: &mut EditorScene, now: Instant) {
+        command.apply(scene);
+        self.undone.clear();
+
+        let recent = match self.last_execute {
+            Some(last) => now.duration_since(last) < MERGE_INTERVAL,
+            None => false,
+        };
+        self.last_execute = Some(now);
+
+        if recent {
+            if let Some(last) = self.done.last_mut() {
+                if last.merge(command.as_ref()) {
+                    return;
+                }
+            }
+        }
+
+        self.done.push(command);
+        if self.done.len() > MAX_HISTORY {
+            self.done.remove(0);
+        }
+    }
+
+    pub fn undo(&mut self, scene: &mut EditorScene) -> bool {
+        self.last_execute = None;
+        match self.done.pop() {
+            Some(mut command) => {
+                command.revert(scene);
+                self.undone.push(command);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn redo(&mut self, scene: &mut EditorScene) -> bool {
+        self.last_execute = None;
+        match self.undone.pop() {
+            Some(mut command) => {
+                command.apply(scene);
+                self.done.push(command);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui, scene: &mut EditorScene) {
+        egui::CollapsingHeader::new("History").show(ui, |ui| {
+            ui.horizontal(|ui| {
+                if ui.add(egui::Button::new("Undo").enabled(!self.done.is_empty())).clicked() {
+                    self.undo(scene);
+                }
+                if ui.add(egui::Button::new("Redo").enabled(!self.undone.is_empty())).clicked() {
+                    self.redo(scene);
+                }
+            });
+
+            egui::ScrollArea::from_max_height(150.0).show(ui, |ui| {
+                for command in self.done.iter() {
+                    ui.label(command.name());
+                }
+                for command in self.undone.iter().rev() {
+                    ui.add(egui::Label::new(command.name()).weak());
+                }
+            });
+        });
+    }
+}
+
+// Replaces one field of the scene, `field` selects it
+pub struct SetValue<V: Clone + 'static> {
+    name: &'static str,
+    field: fn(&mut EditorScene) -> &mut V,
+    old: Option<V>,
+    new: V,
+}
+
+impl<V: Clone + 'static> SetValue<V> {
+    pub fn new(name: &'static str, field: fn(&mut EditorScene) -> &mut V, value: V) -> SetValue<V> {
+        SetValue {
+            name,
+            field,
+            old: None,
+            new: value,
+        }
+    }
+}
+
+impl<V: Clone + 'static> Command for SetValue<V> {
+    fn name(&self) -> String {
+        format!("Change {}", self.name)
+    }
+
+    fn apply(&mut self, scene: &mut EditorScene) {
+        let value = (self.field)(scene);
+        if self.old.is_none() {
+            self.old = Some(value.clone());
+        }
+        *value = self.new.clone();
+        scene.revision += 1;
+    }
+
+    fn revert(&mut self, scene: &mut EditorScene) {
+        if let Some(old) = &self.old {
+            *(self.field)(scene) = old.clone();
+            scene.revision += 1;
+        }
+    }
+
+    fn merge(&mut self, next: &dyn Command) -> bool {
+        match next.as_any().downcast_ref::<SetValue<V>>() {
+            Some(next) if next.name == self.name => {
+                self.new = next.new.clone();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // different fields, so quick edits are never merged
+    fn set_water(value: f32) -> Box<dyn Command> {
+        Box::new(SetValue::new("water level", |scene| &mut scene.water_level, value))
+    }
+
+    fn set_caustics(value: f32) -> Box<dyn Command> {
+        Box::new(SetValue::new("caustics strength", |scene| &mut scene.caustics_strength, value))
+    }
+
+    // edits are timed from a fixed start, `ms` after it
+    fn at(start: Instant, ms: u64) -> Instant {
+        start + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let mut scene = EditorScene::new();
+        let mut history = History::new();
+        let start = Instant::now();
+        let initial_water = scene.water_level;
+
+        history.execute_at(set_water(5.0), &mut scene, at(start, 0));
+        history.execute_at(set_caustics(0.1), &mut scene, at(start, 100));
+        assert_eq!(scene.water_level, 5.0);
+        assert_eq!(scene.caustics_strength, 0.1);
+
+        assert!(history.undo(&mut scene));
+        assert_eq!(scene.caustics_strength, EditorScene::new().caustics_strength);
+        assert_eq!(scene.water_level, 5.0);
+
+        assert!(history.undo(&mut scene));
+        assert_eq!(scene.water_level, initial_water);
+
+        assert!(history.redo(&mut scene));
+        assert_eq!(scene.water_level, 5.0);
+        assert!(history.redo(&mut scene));
+        assert_eq!(scene.caustics_strength, 0.1);
+        assert!(!history.redo(&mut scene));
+    }
+
+    #[test]
+    fn execute_after_undo_clears_redo() {
+        let mut scene = EditorScene::new();
+        let mut history = History::new();
+        let start = Instant::now();
+
+        history.execute_at(set_water(5.0), &mut scene, at(start, 0));
+        history.execute_at(set_caustics(0.1), &mut scene, at(start, 100));
+        assert!(history.undo(&mut scene));
+
+        history.execute_at(set_water(7.0), &mut scene, at(start, 200));
+        assert!(!history.redo(&mut scene));
+        assert_eq!(scene.water_level, 7.0);
+        assert_eq!(scene.caustics_strength, EditorScene::new().caustics_strength);
+
+        // both water edits are kept as separate steps, undo doesn't merge them
+        assert!(history.undo(&mut scene));
+        assert_eq!(scene.water_level, 5.0);
+    }
+
+    #[test]
+    fn empty_history_is_noop() {
+        let mut scene = EditorScene::new();
+        let mut history = History::new();
+        let start = Instant::now();
+
+        assert!(!history.undo(&mut scene));
+        assert!(!history.redo(&mut scene));
+        assert_eq!(scene.revision, 0);
+        assert_eq!(scene.water_level, EditorScene::new().water_level);
+
+        // undoing everything leaves an empty done stack, further undos do nothing
+        history.execute_at(set_water(5.0), &mut scene, at(start, 0));
+        assert!(history.undo(&mut scene));
+        let revision = scene.revision;
+        assert!(!history.undo(&mut scene));
+        assert_eq!(scene.revision, revision);
+    }
+
+    #[test]
+    fn quick_edits_of_one_value_merge() {
+        let mut scene = EditorScene::new();
+        let mut history = History::new();
+        let start = Instant::now();
+        let initial_water = scene.water_level;
+
+        history.execute_at(set_water(5.0), &mut scene, at(start, 0));
+        history.execute_at(set_water(6.0), &mut scene, at(start, 300));
+        // the interval counts from the last edit, a long drag stays one step
+        history.execute_at(set_water(7.0), &mut scene, at(start, 600));
+        assert_eq!(scene.water_level, 7.0);
+
+        assert!(history.undo(&mut scene));
+        assert_eq!(scene.water_level, initial_water);
+        assert!(!history.undo(&mut scene));
+    }
+
+    #[test]
+    fn slow_edits_of_one_value_dont_merge() {
+        let mut scene = EditorScene::new();
+        let mut history = History::new();
+        let start = Instant::now();
+        let interval = MERGE_INTERVAL.as_millis() as u64;
+
+        history.execute_at(set_water(5.0), &mut scene, at(start, 0));
+        history.execute_at(set_water(6.0), &mut scene, at(start, interval));
+        assert_eq!(scene.water_level, 6.0);
+
+        assert!(history.undo(&mut scene));
+        assert_eq!(scene.water_level, 5.0);
+    }
+}
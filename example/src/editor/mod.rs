@@ -0,0 +1,5 @@
+pub mod decal_tool;
+pub mod history;
+pub mod placement;
+pub mod scene;
+pub mod viewport;
@@ -0,0 +1,199 @@
+use std::any::Any;
+use std::fs;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+use cgmath::{EuclideanSpace, InnerSpace, Matrix4, Point3, Quaternion, Vector3};
+
+use crate::utils::heightmap_terrain::raycast::Hit;
+
+use super::history::{Command, History, SetValue};
+use super::scene::EditorScene;
+
+// Meshes which can be placed, index in this list is stored in the scene file
+pub const PALETTE: [&str; 1] = ["chalet"];
+
+#[derive(Clone, Debug)]
+pub struct Placement {
+    pub item: usize,
+    pub position: Point3<f32>,
+    pub normal: Vector3<f32>,
+    pub scale: f32,
+}
+
+impl Placement {
+    pub fn transform(&self) -> Matrix4<f32> {
+        // world up is -Y, meshes are stood up along it by their base orientation
+        let up = Vector3::new(0.0, -1.0, 0.0);
+        let rotation = Quaternion::from_arc(up, self.normal.normalize(), None);
+
+        Matrix4::from_translation(self.position.to_vec()) * Matrix4::from(rotation) * Matrix4::from_scale(self.scale)
+    }
+}
+
+pub struct PlacementTool {
+    pub enabled: bool,
+
+    selected: usize,
+    scale: f32,
+    align_to_normal: bool,
+
+    scene_path: PathBuf,
+    status: String,
+}
+
+impl PlacementTool {
+    pub fn new(scene_path: &Path) -> PlacementTool {
+        PlacementTool {
+            enabled: false,
+            selected: 0,
+            scale: 0.2,
+            align_to_normal: true,
+            scene_path: scene_path.to_path_buf(),
+            status: String::new(),
+        }
+    }
+
+    // `max_placements` - how many placements the scene buffer fits with the current model
+    pub fn place(&mut self, hit: &Hit, max_placements: usize, history: &mut History, scene: &mut EditorScene) {
+        if scene.placements.len() >= max_placements {
+            self.status = format!("Place failed: the scene is limited to {} objects", max_placements);
+            return;
+        }
+
+        let normal = if self.align_to_normal {
+            hit.normal
+        } else {
+            Vector3::new(0.0, -1.0, 0.0)
+        };
+
+        let placement = Placement {
+            item: self.selected,
+            position: hit.position,
+            normal,
+            scale: self.scale,
+        };
+        history.execute(Box::new(PlaceObject { placement }), scene);
+    }
+
+    // One placement per line: <mesh name> <x> <y> <z> <normal x> <normal y> <normal z> <scale>
+    pub fn save(&self, placements: &[Placement]) -> io::Result<()> {
+        let mut data = String::new();
+        for p in placements.iter() {
+            data += &format!(
+                "{} {} {} {} {} {} {} {}\n",
+                PALETTE[p.item], p.position.x, p.position.y, p.position.z, p.normal.x, p.normal.y, p.normal.z, p.scale
+            );
+        }
+
+        fs::write(&self.scene_path, data)
+    }
+
+    pub fn load(&self, max_placements: usize) -> io::Result<Vec<Placement>> {
+        let data = fs::read_to_string(&self.scene_path)?;
+
+        let mut placements = vec![];
+        for (line_idx, line) in data.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Invalid placement at line {}", line_idx + 1));
+
+            let mut parts = line.split_whitespace();
+            let name = parts.next().ok_or_else(invalid)?;
+            let item = PALETTE.iter().position(|&item| item == name).ok_or_else(invalid)?;
+
+            let values = parts
+                .map(|v| v.parse::<f32>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| invalid())?;
+            if values.len() != 7 {
+                return Err(invalid());
+            }
+
+            placements.push(Placement {
+                item,
+                position: Point3::new(values[0], values[1], values[2]),
+                normal: Vector3::new(values[3], values[4], values[5]),
+                scale: values[6],
+            });
+        }
+
+        if placements.len() > max_placements {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} placements, the scene is limited to {}", placements.len(), max_placements),
+            ));
+        }
+
+        Ok(placements)
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui, max_placements: usize, history: &mut History, scene: &mut EditorScene) {
+        ui.checkbox(&mut self.enabled, "Place objects (right click on terrain)");
+        if !self.enabled {
+            return;
+        }
+
+        egui::ComboBox::from_label("Mesh")
+            .selected_text(PALETTE[self.selected])
+            .show_ui(ui, |ui| {
+                for (idx, name) in PALETTE.iter().enumerate() {
+                    ui.selectable_value(&mut self.selected, idx, *name);
+                }
+            });
+
+        ui.add(egui::DragValue::new(&mut self.scale).speed(0.01).clamp_range(RangeInclusive::new(0.01, 10.0)).prefix("Scale: "));
+        ui.checkbox(&mut self.align_to_normal, "Align to surface");
+
+        ui.horizontal(|ui| {
+            if ui.button("Save").clicked() {
+                self.status = match self.save(&scene.placements) {
+                    Ok(_) => format!("Saved {} objects", scene.placements.len()),
+                    Err(err) => format!("Save failed: {}", err),
+                };
+            }
+            if ui.button("Load").clicked() {
+                self.status = match self.load(max_placements) {
+                    Ok(placements) => {
+                        let count = placements.len();
+                        history.execute(Box::new(SetValue::new("placements", |scene| &mut scene.placements, placements)), scene);
+                        format!("Loaded {} objects", count)
+                    }
+                    Err(err) => format!("Load failed: {}", err),
+                };
+            }
+        });
+
+        ui.label(format!("Objects: {} of {}", scene.placements.len(), max_placements));
+        if !self.status.is_empty() {
+            ui.label(&self.status);
+        }
+    }
+}
+
+struct PlaceObject {
+    placement: Placement,
+}
+
+impl Command for PlaceObject {
+    fn name(&self) -> String {
+        format!("Place {}", PALETTE[self.placement.item])
+    }
+
+    fn apply(&mut self, scene: &mut EditorScene) {
+        scene.placements.push(self.placement.clone());
+        scene.revision += 1;
+    }
+
+    fn revert(&mut self, scene: &mut EditorScene) {
+        scene.placements.pop();
+        scene.revision += 1;
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
@@ -0,0 +1,42 @@
+use super::placement::Placement;
+
+// Everything editable from the gui, changed only through history commands
+pub struct EditorScene {
+    pub placements: Vec<Placement>,
+    pub clear_color: [f32; 3],
+    pub cascade_split_lambda: f32,
+    pub cascade_blend_band: f32,
+
+    pub point_light_position: [f32; 3],
+    pub point_light_radius: f32,
+    pub point_light_intensity: f32,
+
+    pub water_level: f32,
+    pub caustics_strength: f32,
+
+    pub sky_occlusion: f32,
+    // image based ambient light of PBR materials
+    pub environment_intensity: f32,
+
+    // bumped by every applied or reverted command
+    pub revision: u64,
+}
+
+impl EditorScene {
+    pub fn new() -> EditorScene {
+        EditorScene {
+            placements: vec![],
+            clear_color: [0.0, 0.0, 0.0],
+            cascade_split_lambda: 0.1,
+            cascade_blend_band: 0.5,
+            point_light_position: [1.5, -1.5, -9.0],
+            point_light_radius: 8.0,
+            point_light_intensity: 2.0,
+            water_level: 2.5,
+            caustics_strength: 0.6,
+            sky_occlusion: 1.0,
+            environment_intensity: 1.0,
+            revision: 0,
+        }
+    }
+}
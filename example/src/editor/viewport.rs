@@ -0,0 +1,168 @@
+use std::ptr;
+use std::sync::Arc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use ash_render_env::attachment_texture::AttachmentImage;
+use ash_render_env::egui::Egui;
+use ash_render_env::env::RenderEnv;
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::primary_cmd_buffer::PrimaryCommandBuffer;
+
+use crate::utils::render_pass;
+
+// Editor viewport: the scene is rendered into an offscreen image shown by an egui image widget.
+// The image follows the widget size, the scene is resized to it by the app at the start of a frame
+pub struct SceneViewport {
+    // compatible with the final pass, so the final pass buffers draw into the viewport
+    render_pass: vk::RenderPass,
+    image: AttachmentImage,
+    framebuffer: vk::Framebuffer,
+    draw_command: PrimaryCommandBuffer,
+    texture_id: egui::TextureId,
+    dimensions: [u32; 2],
+    // size of the widget in pixels from the last frame
+    requested: [u32; 2],
+    // widget rect in points and hover state from the last frame
+    rect: egui::Rect,
+    hovered: bool,
+
+    env: Arc<RenderEnv>,
+}
+
+impl SceneViewport {
+    pub fn new(env: Arc<RenderEnv>, egui: &mut Egui, format: vk::Format, dimensions: [u32; 2], max_inflight_frames: usize) -> SceneViewport {
+        let render_pass = render_pass::create_color_render_pass(&env, format, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+        let (image, framebuffer) = Self::create_target(&env, render_pass, format, dimensions);
+        let texture_id = egui.add_output_texture(image.view);
+
+        let mut draw_command = PrimaryCommandBuffer::new(env.clone(), max_inflight_frames);
+        draw_command.set_name("editor viewport pass");
+        draw_command.set_dimensions(dimensions);
+
+        SceneViewport {
+            render_pass,
+            image,
+            framebuffer,
+            draw_command,
+            texture_id,
+            dimensions,
+            requested: dimensions,
+            rect: egui::Rect::NOTHING,
+            hovered: false,
+            env,
+        }
+    }
+
+    fn create_target(env: &RenderEnv, render_pass: vk::RenderPass, format: vk::Format, dimensions: [u32; 2]) -> (AttachmentImage, vk::Framebuffer) {
+        let image = AttachmentImage::new(
+            env,
+            dimensions,
+            format,
+            1,
+            vk::SampleCountFlags::TYPE_1,
+            vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+        );
+
+        let views = [image.view];
+        let framebuffer_info = vk::FramebufferCreateInfo {
+            s_type: vk::StructureType::FRAMEBUFFER_CREATE_INFO,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            render_pass,
+            attachment_count: views.len() as u32,
+            p_attachments: views.as_ptr(),
+            width: dimensions[0],
+            height: dimensions[1],
+            layers: 1,
+        };
+
+        let framebuffer = unsafe {
+            env.device().create_framebuffer(&framebuffer_info, None)
+                .expect("Failed to create viewport framebuffer!")
+        };
+        object_tracker::created(ObjectKind::Framebuffer);
+
+        (image, framebuffer)
+    }
+
+    pub fn dimensions(&self) -> [u32; 2] {
+        self.dimensions
+    }
+
+    // New size when the widget was resized since the last resize()
+    pub fn pending_resize(&self) -> Option<[u32; 2]> {
+        if self.requested != self.dimensions {
+            Some(self.requested)
+        } else {
+            None
+        }
+    }
+
+    // Device must be idle. The egui texture keeps its id, its descriptor set is replaced
+    pub fn resize(&mut self, egui: &mut Egui, dimensions: [u32; 2]) {
+        unsafe {
+            self.env.device().destroy_framebuffer(self.framebuffer, None);
+        }
+        object_tracker::destroyed(ObjectKind::Framebuffer);
+
+        let (image, framebuffer) = Self::create_target(&self.env, self.render_pass, self.image.format, dimensions);
+        self.image = image;
+        self.framebuffer = framebuffer;
+        egui.replace_output_texture(self.texture_id, self.image.view);
+
+        self.draw_command.set_dimensions(dimensions);
+        self.dimensions = dimensions;
+        self.requested = dimensions;
+    }
+
+    // Image widget filling the available space, the requested size is applied next frame
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        let size = ui.available_size();
+        let pixels_per_point = ui.ctx().pixels_per_point();
+        self.requested = [
+            ((size.x * pixels_per_point).round() as u32).max(1),
+            ((size.y * pixels_per_point).round() as u32).max(1),
+        ];
+
+        let response = ui.image(self.texture_id, size);
+        self.rect = response.rect;
+        self.hovered = response.hovered();
+    }
+
+    // Pointer is over the scene image, scene input should not be blocked by egui
+    pub fn is_hovered(&self) -> bool {
+        self.hovered
+    }
+
+    // Pixel of the viewport image under the given position in points, None outside of it
+    pub fn to_pixel(&self, pos: egui::Pos2) -> Option<[f32; 2]> {
+        if !self.rect.contains(pos) {
+            return None;
+        }
+
+        let uv = (pos - self.rect.min) / self.rect.size();
+        Some([uv.x * self.dimensions[0] as f32, uv.y * self.dimensions[1] as f32])
+    }
+
+    // Position in points of a viewport image pixel, inverse of to_pixel()
+    pub fn to_point(&self, pixel: [f32; 2]) -> egui::Pos2 {
+        let uv = egui::vec2(pixel[0] / self.dimensions[0].max(1) as f32, pixel[1] / self.dimensions[1].max(1) as f32);
+        self.rect.min + uv * self.rect.size()
+    }
+
+    // Final pass buffers recorded for the final render pass, drawn into the viewport image
+    pub fn draw(&mut self, clear_values: Vec<vk::ClearValue>, second_buffers: &[vk::CommandBuffer]) -> vk::CommandBuffer {
+        self.draw_command.execute_secondary(clear_values, self.framebuffer, self.render_pass, second_buffers)
+    }
+
+    pub fn destroy(&mut self, egui: &mut Egui) {
+        egui.unregister_texture(self.texture_id);
+
+        unsafe {
+            self.env.device().destroy_framebuffer(self.framebuffer, None);
+            object_tracker::destroyed(ObjectKind::Framebuffer);
+        }
+    }
+}
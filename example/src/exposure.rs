@@ -0,0 +1,117 @@
+use crate::light_units;
+
+// Middle gray the adapted scene is exposed to
+const KEY_VALUE: f32 = 0.18;
+// Saturation based exposure puts average luminance at 1 / 9.6, this brings it to the key value
+const KEY_COMPENSATION: f32 = 0.79;
+const ADAPTATION_SPEED: f32 = 1.5;
+
+// Luminance thresholds for gui theme switch, the gap keeps it from flickering
+const LIGHT_THEME_LUMINANCE: f32 = 0.45;
+const DARK_THEME_LUMINANCE: f32 = 0.3;
+
+// Eye adaptation to average scene luminance, also picks gui theme readable over the scene.
+// There is no luminance readback from GPU yet, so the average is estimated by the caller.
+// Scene luminance is in raw shader units, exposure is the physical EV100 one
+pub struct Exposure {
+    // EV100 set by hand instead of the adapted one
+    pub manual_ev100: Option<f32>,
+    // added to the adapted EV100, positive values make the image brighter
+    pub compensation: f32,
+
+    luminance: f32,
+    dark_theme: bool,
+    theme_changed: bool,
+}
+
+impl Exposure {
+    pub fn new() -> Exposure {
+        Exposure {
+            manual_ev100: None,
+            compensation: KEY_COMPENSATION,
+            luminance: KEY_VALUE,
+            dark_theme: true,
+            theme_changed: true,
+        }
+    }
+
+    // `dt` - seconds since the previous update
+    pub fn update(&mut self, scene_luminance: f32, dt: f32) {
+
+        // adapt in log space, like eye does
+        let current = self.luminance.max(1e-4).ln();
+        let target = scene_luminance.max(1e-4).ln();
+        let blend = 1.0 - (-ADAPTATION_SPEED * dt).exp();
+        self.luminance = (current + (target - current) * blend).exp();
+
+        let dark_theme = if self.dark_theme {
+            self.luminance < LIGHT_THEME_LUMINANCE
+        } else {
+            self.luminance < DARK_THEME_LUMINANCE
+        };
+        self.theme_changed |= dark_theme != self.dark_theme;
+        self.dark_theme = dark_theme;
+    }
+
+    // Multiplier applied to the lit scene in raw units before output
+    pub fn exposure(&self) -> f32 {
+        light_units::NITS_PER_UNIT * light_units::ev100_to_exposure(self.ev100())
+    }
+
+    // Exposure the scene is shown with, adaptation is limited to two stops around the key value
+    pub fn ev100(&self) -> f32 {
+        match self.manual_ev100 {
+            Some(ev100) => ev100,
+            None => {
+                let luminance = self.luminance.clamp(KEY_VALUE * 0.25, KEY_VALUE * 4.0);
+                light_units::luminance_to_ev100(light_units::raw_to_luminance(luminance)) - self.compensation
+            }
+        }
+    }
+
+    // Average luminance the eye is adapted to, cd/m2
+    pub fn adapted_luminance(&self) -> f32 {
+        light_units::raw_to_luminance(self.luminance)
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        let mut manual = self.manual_ev100.is_some();
+        ui.checkbox(&mut manual, "Manual exposure");
+        if manual {
+            let mut ev100 = self.manual_ev100.unwrap_or_else(|| self.ev100());
+            ui.add(egui::Slider::new(&mut ev100, -2.0..=18.0).text("EV100"));
+            ui.horizontal(|ui| {
+                // f-number, shutter time and ISO of common setups
+                if ui.button("Sunny 16").clicked() {
+                    ev100 = light_units::camera_ev100(16.0, 1.0 / 100.0, 100.0);
+                }
+                if ui.button("Overcast").clicked() {
+                    ev100 = light_units::camera_ev100(8.0, 1.0 / 125.0, 200.0);
+                }
+                if ui.button("Indoor").clicked() {
+                    ev100 = light_units::camera_ev100(2.8, 1.0 / 60.0, 800.0);
+                }
+            });
+            self.manual_ev100 = Some(ev100);
+        } else {
+            self.manual_ev100 = None;
+            ui.add(egui::Slider::new(&mut self.compensation, -3.0..=3.0).text("Compensation, EV"));
+        }
+
+        ui.label(format!("Adapted luminance: {:.0} cd/m2 (raw {:.3})", self.adapted_luminance(), self.luminance));
+        ui.label(format!("EV100: {:.2}, exposure: {:.2}", self.ev100(), self.exposure()));
+    }
+
+    pub fn apply_theme(&mut self, ctx: &egui::CtxRef) {
+        if !self.theme_changed {
+            return;
+        }
+        self.theme_changed = false;
+
+        if self.dark_theme {
+            ctx.set_visuals(egui::Visuals::dark());
+        } else {
+            ctx.set_visuals(egui::Visuals::light());
+        }
+    }
+}
@@ -0,0 +1,77 @@
+#[cfg(feature = "gamepad")]
+use cgmath::Vector3;
+
+use ash_render_env::camera::CameraInput;
+
+// Camera input from the first connected gamepad: left stick moves, right stick looks, triggers go up
+// and down. Without the `gamepad` feature there are no gamepads and the input stays zero
+pub struct Gamepads {
+    // None when the platform backend failed to start
+    #[cfg(feature = "gamepad")]
+    gilrs: Option<gilrs::Gilrs>,
+}
+
+#[cfg(feature = "gamepad")]
+impl Gamepads {
+    pub fn new() -> Gamepads {
+        let gilrs = match gilrs::Gilrs::new() {
+            Ok(gilrs) => Some(gilrs),
+            Err(err) => {
+                println!("Gamepads are not available: {}", err);
+                None
+            }
+        };
+
+        Gamepads {
+            gilrs,
+        }
+    }
+
+    // Call once per frame, pending events are drained so the axes are current
+    pub fn poll(&mut self) -> CameraInput {
+        use gilrs::{Axis, Button};
+
+        let gilrs = match &mut self.gilrs {
+            Some(gilrs) => gilrs,
+            None => return CameraInput::default(),
+        };
+        while gilrs.next_event().is_some() {}
+
+        let gamepad = match gilrs.gamepads().next() {
+            Some((_, gamepad)) => gamepad,
+            None => return CameraInput::default(),
+        };
+        let trigger = |button| gamepad.button_data(button).map_or(0.0, |data| data.value());
+
+        CameraInput {
+            movement: Vector3::new(
+                gamepad.value(Axis::LeftStickX),
+                trigger(Button::RightTrigger2) - trigger(Button::LeftTrigger2),
+                gamepad.value(Axis::LeftStickY),
+            ),
+            // stick up looks up, unlike a mouse drag up
+            look: [gamepad.value(Axis::RightStickX), -gamepad.value(Axis::RightStickY)],
+        }
+    }
+
+    pub fn active_name(&self) -> Option<String> {
+        self.gilrs.as_ref()
+            .and_then(|gilrs| gilrs.gamepads().next())
+            .map(|(_, gamepad)| gamepad.name().to_string())
+    }
+}
+
+#[cfg(not(feature = "gamepad"))]
+impl Gamepads {
+    pub fn new() -> Gamepads {
+        Gamepads {}
+    }
+
+    pub fn poll(&mut self) -> CameraInput {
+        CameraInput::default()
+    }
+
+    pub fn active_name(&self) -> Option<String> {
+        None
+    }
+}
@@ -0,0 +1,98 @@
+use winit::dpi::PhysicalPosition;
+use winit::event::{ElementState, MouseButton, VirtualKeyCode, WindowEvent};
+
+// Where a window event goes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InputRoute {
+    pub gui: bool,
+    pub camera: bool,
+    // keyboard shortcuts of the app: undo, wireframe, fullscreen, Escape to quit
+    pub shortcuts: bool,
+}
+
+// Arbitrates window input between egui and the camera. Keys go to egui while one of its widgets has
+// keyboard focus and to the camera otherwise. A left click in the scene captures the mouse: the cursor
+// is hidden and locked, all input goes to the camera until Escape or the window loses focus
+pub struct InputFocus {
+    captured: bool,
+    // state of the window, apply() makes it follow `captured`
+    grabbed: bool,
+}
+
+impl InputFocus {
+    pub fn new() -> InputFocus {
+        InputFocus {
+            captured: false,
+            grabbed: false,
+        }
+    }
+
+    pub fn is_captured(&self) -> bool {
+        self.captured
+    }
+
+    // `gui_wants_keyboard` - an egui widget has keyboard focus, `scene_has_pointer` - the pointer is over
+    // the scene and not over a gui area
+    pub fn route(&mut self, event: &WindowEvent, gui_wants_keyboard: bool, scene_has_pointer: bool) -> InputRoute {
+        match event {
+            WindowEvent::Focused(false) => {
+                self.captured = false;
+                InputRoute { gui: true, camera: true, shortcuts: false }
+            }
+            WindowEvent::KeyboardInput { input, .. } if self.captured => {
+                if input.virtual_keycode == Some(VirtualKeyCode::Escape) {
+                    if input.state == ElementState::Pressed {
+                        self.captured = false;
+                    }
+                    return InputRoute::default();
+                }
+                InputRoute { gui: false, camera: true, shortcuts: true }
+            }
+            WindowEvent::KeyboardInput { input, .. } => {
+                // releases always reach the camera, so movement keys don't stay held
+                let released = input.state == ElementState::Released;
+                InputRoute {
+                    gui: true,
+                    camera: !gui_wants_keyboard || released,
+                    shortcuts: !gui_wants_keyboard,
+                }
+            }
+            WindowEvent::ReceivedCharacter(_) => InputRoute { gui: !self.captured, camera: false, shortcuts: false },
+            WindowEvent::ModifiersChanged(_) => InputRoute { gui: true, camera: true, shortcuts: false },
+            WindowEvent::MouseInput { state: ElementState::Pressed, button: MouseButton::Left, .. }
+                if !self.captured && scene_has_pointer => {
+                self.captured = true;
+                InputRoute::default()
+            }
+            WindowEvent::CursorMoved { .. } | WindowEvent::MouseInput { .. } | WindowEvent::MouseWheel { .. } => {
+                if self.captured {
+                    InputRoute { gui: false, camera: true, shortcuts: false }
+                } else {
+                    // egui follows the cursor even outside of its areas to end hovers and drags
+                    InputRoute { gui: true, camera: scene_has_pointer, shortcuts: false }
+                }
+            }
+            _ => InputRoute { gui: !self.captured, camera: false, shortcuts: false },
+        }
+    }
+
+    // Locks and hides the cursor while captured. Call once per frame
+    pub fn apply(&mut self, wnd: &winit::window::Window) {
+        if self.grabbed == self.captured {
+            return;
+        }
+
+        if let Err(err) = wnd.set_cursor_grab(self.captured) {
+            println!("Failed to grab the cursor: {}", err);
+        }
+        wnd.set_cursor_visible(!self.captured);
+        self.grabbed = self.captured;
+    }
+
+    // Center of the window the cursor returns to after every move while captured,
+    // so the camera keeps getting deltas when a platform only confines the cursor
+    pub fn lock_position(wnd: &winit::window::Window) -> PhysicalPosition<f64> {
+        let size = wnd.inner_size();
+        PhysicalPosition::new((size.width / 2) as f64, (size.height / 2) as f64)
+    }
+}
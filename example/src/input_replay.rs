@@ -0,0 +1,265 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use winit::dpi::PhysicalPosition;
+use winit::event::{DeviceId, ElementState, KeyboardInput, ModifiersState, MouseButton, MouseScrollDelta, TouchPhase, VirtualKeyCode, WindowEvent};
+
+// Input events the app reacts to, in a form that can be written to a text file.
+// One event per line, every frame is closed by a `frame <dt>` line:
+//
+//   key <scancode> <virtual keycode or -> <pressed|released>
+//   mods <modifier bits>
+//   char <code point>
+//   button <left|right|middle|other id> <pressed|released>
+//   cursor <x> <y>
+//   wheel <line|pixel> <dx> <dy>
+//   focus <0|1>
+#[derive(Clone, Debug, PartialEq)]
+enum InputEvent {
+    Key { scancode: u32, keycode: Option<VirtualKeyCode>, pressed: bool },
+    Modifiers(ModifiersState),
+    Char(char),
+    Button { button: MouseButton, pressed: bool },
+    Cursor(f64, f64),
+    Wheel { pixels: bool, dx: f32, dy: f32 },
+    Focus(bool),
+}
+
+impl InputEvent {
+    fn from_window_event(event: &WindowEvent) -> Option<InputEvent> {
+        let event = match event {
+            WindowEvent::KeyboardInput { input, .. } => InputEvent::Key {
+                scancode: input.scancode,
+                keycode: input.virtual_keycode,
+                pressed: input.state == ElementState::Pressed,
+            },
+            WindowEvent::ModifiersChanged(modifiers) => InputEvent::Modifiers(*modifiers),
+            WindowEvent::ReceivedCharacter(c) => InputEvent::Char(*c),
+            WindowEvent::MouseInput { button, state, .. } => InputEvent::Button {
+                button: *button,
+                pressed: *state == ElementState::Pressed,
+            },
+            WindowEvent::CursorMoved { position, .. } => InputEvent::Cursor(position.x, position.y),
+            WindowEvent::MouseWheel { delta: MouseScrollDelta::LineDelta(dx, dy), .. } => InputEvent::Wheel {
+                pixels: false,
+                dx: *dx,
+                dy: *dy,
+            },
+            WindowEvent::MouseWheel { delta: MouseScrollDelta::PixelDelta(delta), .. } => InputEvent::Wheel {
+                pixels: true,
+                dx: delta.x as f32,
+                dy: delta.y as f32,
+            },
+            WindowEvent::Focused(focused) => InputEvent::Focus(*focused),
+            _ => return None,
+        };
+
+        Some(event)
+    }
+
+    #[allow(deprecated)]
+    fn to_window_event(&self) -> WindowEvent<'static> {
+        // handlers of the app never look at the device
+        let device_id = unsafe { DeviceId::dummy() };
+
+        match self {
+            InputEvent::Key { scancode, keycode, pressed } => WindowEvent::KeyboardInput {
+                device_id,
+                input: KeyboardInput {
+                    scancode: *scancode,
+                    state: element_state(*pressed),
+                    virtual_keycode: *keycode,
+                    modifiers: ModifiersState::empty(),
+                },
+                is_synthetic: false,
+            },
+            InputEvent::Modifiers(modifiers) => WindowEvent::ModifiersChanged(*modifiers),
+            InputEvent::Char(c) => WindowEvent::ReceivedCharacter(*c),
+            InputEvent::Button { button, pressed } => WindowEvent::MouseInput {
+                device_id,
+                state: element_state(*pressed),
+                button: *button,
+                modifiers: ModifiersState::empty(),
+            },
+            InputEvent::Cursor(x, y) => WindowEvent::CursorMoved {
+                device_id,
+                position: PhysicalPosition::new(*x, *y),
+                modifiers: ModifiersState::empty(),
+            },
+            InputEvent::Wheel { pixels, dx, dy } => WindowEvent::MouseWheel {
+                device_id,
+                delta: if *pixels {
+                    MouseScrollDelta::PixelDelta(PhysicalPosition::new(*dx as f64, *dy as f64))
+                } else {
+                    MouseScrollDelta::LineDelta(*dx, *dy)
+                },
+                phase: TouchPhase::Moved,
+                modifiers: ModifiersState::empty(),
+            },
+            InputEvent::Focus(focused) => WindowEvent::Focused(*focused),
+        }
+    }
+
+    fn to_line(&self) -> String {
+        match self {
+            InputEvent::Key { scancode, keycode, pressed } => {
+                let keycode = keycode.map_or("-".to_string(), |key| (key as u32).to_string());
+                format!("key {} {} {}", scancode, keycode, state_name(*pressed))
+            }
+            InputEvent::Modifiers(modifiers) => format!("mods {}", modifiers.bits()),
+            InputEvent::Char(c) => format!("char {}", *c as u32),
+            InputEvent::Button { button, pressed } => {
+                let button = match button {
+                    MouseButton::Left => "left".to_string(),
+                    MouseButton::Right => "right".to_string(),
+                    MouseButton::Middle => "middle".to_string(),
+                    MouseButton::Other(id) => id.to_string(),
+                };
+                format!("button {} {}", button, state_name(*pressed))
+            }
+            InputEvent::Cursor(x, y) => format!("cursor {} {}", x, y),
+            InputEvent::Wheel { pixels, dx, dy } => format!("wheel {} {} {}", if *pixels { "pixel" } else { "line" }, dx, dy),
+            InputEvent::Focus(focused) => format!("focus {}", *focused as u8),
+        }
+    }
+
+    fn parse(line: &str) -> Option<InputEvent> {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+
+        let event = match parts.as_slice() {
+            ["key", scancode, keycode, state] => InputEvent::Key {
+                scancode: scancode.parse().ok()?,
+                keycode: match *keycode {
+                    "-" => None,
+                    code => Some(keycode_from_u32(code.parse().ok()?)?),
+                },
+                pressed: parse_state(state)?,
+            },
+            ["mods", bits] => InputEvent::Modifiers(ModifiersState::from_bits_truncate(bits.parse().ok()?)),
+            ["char", code] => InputEvent::Char(std::char::from_u32(code.parse().ok()?)?),
+            ["button", button, state] => InputEvent::Button {
+                button: match *button {
+                    "left" => MouseButton::Left,
+                    "right" => MouseButton::Right,
+                    "middle" => MouseButton::Middle,
+                    id => MouseButton::Other(id.parse().ok()?),
+                },
+                pressed: parse_state(state)?,
+            },
+            ["cursor", x, y] => InputEvent::Cursor(x.parse().ok()?, y.parse().ok()?),
+            ["wheel", kind, dx, dy] => InputEvent::Wheel {
+                pixels: *kind == "pixel",
+                dx: dx.parse().ok()?,
+                dy: dy.parse().ok()?,
+            },
+            ["focus", focused] => InputEvent::Focus(*focused == "1"),
+            _ => return None,
+        };
+
+        Some(event)
+    }
+}
+
+fn element_state(pressed: bool) -> ElementState {
+    if pressed { ElementState::Pressed } else { ElementState::Released }
+}
+
+fn state_name(pressed: bool) -> &'static str {
+    if pressed { "pressed" } else { "released" }
+}
+
+fn parse_state(state: &str) -> Option<bool> {
+    match state {
+        "pressed" => Some(true),
+        "released" => Some(false),
+        _ => None,
+    }
+}
+
+// VirtualKeyCode is a fieldless repr(u32) enum, its values go from 0 to the last variant
+fn keycode_from_u32(code: u32) -> Option<VirtualKeyCode> {
+    if code > VirtualKeyCode::Cut as u32 {
+        return None;
+    }
+
+    Some(unsafe { std::mem::transmute::<u32, VirtualKeyCode>(code) })
+}
+
+// Writes window input and frame durations, so the session can be replayed later
+pub struct InputRecorder {
+    writer: BufWriter<File>,
+}
+
+impl InputRecorder {
+    pub fn create(path: &Path) -> io::Result<InputRecorder> {
+        Ok(InputRecorder {
+            writer: BufWriter::new(File::create(path)?),
+        })
+    }
+
+    pub fn record(&mut self, event: &WindowEvent) {
+        if let Some(event) = InputEvent::from_window_event(event) {
+            writeln!(self.writer, "{}", event.to_line()).expect("Failed to write input recording!");
+        }
+    }
+
+    // Closes the frame, `dt` is the frame time the app used for animations
+    pub fn end_frame(&mut self, dt: f32) {
+        writeln!(self.writer, "frame {}", dt).expect("Failed to write input recording!");
+    }
+}
+
+struct ReplayFrame {
+    events: Vec<InputEvent>,
+    dt: f32,
+}
+
+// Recorded session fed back frame by frame instead of the live input
+pub struct InputReplay {
+    frames: Vec<ReplayFrame>,
+    next_frame: usize,
+}
+
+impl InputReplay {
+    pub fn load(path: &Path) -> io::Result<InputReplay> {
+        let mut frames = vec![];
+        let mut events = vec![];
+
+        for (line_idx, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(dt) = line.strip_prefix("frame ") {
+                let dt = dt.trim().parse()
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("Bad frame time at line {}", line_idx + 1)))?;
+                frames.push(ReplayFrame { events: std::mem::take(&mut events), dt });
+                continue;
+            }
+
+            let event = InputEvent::parse(line)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Bad input event at line {}", line_idx + 1)))?;
+            events.push(event);
+        }
+
+        Ok(InputReplay {
+            frames,
+            next_frame: 0,
+        })
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    // Events of the next frame and its duration, None when the recording is over
+    pub fn next_frame(&mut self) -> Option<(Vec<WindowEvent<'static>>, f32)> {
+        let frame = self.frames.get(self.next_frame)?;
+        self.next_frame += 1;
+
+        Some((frame.events.iter().map(InputEvent::to_window_event).collect(), frame.dt))
+    }
+}
@@ -0,0 +1,324 @@
+// Every renderer is created and dropped on a headless env, the alive object counts of object_tracker must
+// be back where they were. They need a Vulkan device and compiled shaders, so they are ignored by default:
+// `cargo test -p ash-test leak_tests -- --ignored`
+
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use ash_render_env::dynamic_uniform_buffer::DynamicUniformRing;
+use ash_render_env::env::RenderEnv;
+use ash_render_env::frame_buffer::{AttachmentDesciption, Framebuffer};
+use ash_render_env::object_tracker;
+use ash_render_env::swapchain::{OutputEncoding, TransferFunction};
+use ash_render_env::utils::DepthMode;
+
+use crate::lights::LightManager;
+use crate::shadow_map::{CASCADE_COUNT, PointShadowMap, ShadowMapFramebuffer};
+use crate::utils::bloom::Bloom;
+use crate::utils::debug_draw::DebugDraw;
+use crate::utils::decals::DecalRenderer;
+use crate::utils::environment_map::EnvironmentLighting;
+use crate::utils::gbuffer::GBufferLayout;
+use crate::utils::god_rays::GodRays;
+use crate::utils::heightmap_terrain::terrain::{HeightMap, TerrainData, TerrainScale};
+use crate::utils::heightmap_terrain::terrain_renderer::TerrainRenderer;
+use crate::utils::hi_z::HiZPyramid;
+use crate::utils::instance_field::InstanceField;
+use crate::utils::mesh_render::MeshRenderer;
+use crate::utils::mesh_shadowmap_render::MeshShadowMapRenderer;
+use crate::utils::model::Model;
+use crate::utils::occlusion_culling::OcclusionCulling;
+use crate::utils::particles::ParticleSystem;
+use crate::utils::post_process::{self, PostProcessChain};
+use crate::utils::quad_render::QuadRenderer;
+use crate::utils::reflective_shadow_map::ReflectiveShadowMap;
+use crate::utils::render_pass;
+use crate::utils::scene_buffer::SceneBuffer;
+use crate::utils::skybox_render::SkyboxRenderer;
+use crate::utils::sync::MAX_FRAMES_IN_FLIGHT;
+use crate::utils::taa::TemporalAntiAliasing;
+use crate::utils::transparent_render::TransparentRenderer;
+
+const DIMENSIONS: [u32; 2] = [320, 240];
+const SAMPLES: vk::SampleCountFlags = vk::SampleCountFlags::TYPE_1;
+const DEPTH_MODE: DepthMode = DepthMode::Standard;
+const OUTPUT_FORMAT: vk::Format = vk::Format::B8G8R8A8_SRGB;
+
+// object counts are global, tests of one process must not overlap
+static SERIAL: Mutex<()> = Mutex::new(());
+
+// What main() creates before the renderers: the env, the G-buffer, the HDR target, the final pass and the scene
+struct Fixture {
+    env: Arc<RenderEnv>,
+    gbuffer: Framebuffer,
+    hdr_buffer: Framebuffer,
+    quad_render_pass: vk::RenderPass,
+    model: Arc<Model>,
+    scene_buffer: SceneBuffer,
+    _serial: MutexGuard<'static, ()>,
+}
+
+impl Fixture {
+    fn new() -> Fixture {
+        let serial = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+
+        // shader and asset paths are relative to the workspace root, as for the demo
+        std::env::set_current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/..")).unwrap();
+        assert!(Path::new("assets/shaders/spv").exists(), "Leak tests need compiled shaders in assets/shaders/spv");
+        let env = Arc::new(RenderEnv::new_headless());
+
+        let gbuffer_layout = GBufferLayout::new(&env, SAMPLES, DEPTH_MODE);
+        let mut gbuffer = Framebuffer::new(env.clone(), gbuffer_layout.attachments());
+        gbuffer.set_depth_mode(gbuffer_layout.depth_mode);
+        gbuffer.resize_swapchain(DIMENSIONS);
+
+        let mut hdr_buffer = Framebuffer::new(env.clone(), vec![
+            AttachmentDesciption {
+                samples_count: vk::SampleCountFlags::TYPE_1,
+                format: vk::Format::R16G16B16A16_SFLOAT,
+            },
+        ]);
+        hdr_buffer.resize_swapchain(DIMENSIONS);
+
+        let quad_render_pass = render_pass::create_quad_render_pass(&env, OUTPUT_FORMAT);
+        let model = Arc::new(Model::placeholder(&env));
+        let scene_buffer = SceneBuffer::new(env.clone(), MAX_FRAMES_IN_FLIGHT);
+
+        Fixture {
+            env,
+            gbuffer,
+            hdr_buffer,
+            quad_render_pass,
+            model,
+            scene_buffer,
+            _serial: serial,
+        }
+    }
+
+    // Creates what `create` returns and drops it, everything created in between must be destroyed by then
+    fn assert_no_leaks<R>(&self, create: impl FnOnce() -> R) {
+        let before = object_tracker::snapshot();
+        let renderer = create();
+        unsafe { self.env.device().device_wait_idle().unwrap() };
+        drop(renderer);
+
+        object_tracker::assert_no_growth(&before);
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        unsafe {
+            self.env.device().device_wait_idle().unwrap();
+            self.env.device().destroy_render_pass(self.quad_render_pass, None);
+        }
+        self.gbuffer.destroy();
+        self.hdr_buffer.destroy();
+    }
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn mesh_renderer() {
+    let f = Fixture::new();
+    f.assert_no_leaks(|| MeshRenderer::new(
+        f.env.clone(), f.gbuffer.render_pass(), f.model.clone(), &f.scene_buffer,
+        GBufferLayout::COLOR_ATTACHMENT_COUNT, SAMPLES, DEPTH_MODE, DIMENSIONS,
+    ));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn instance_field() {
+    let f = Fixture::new();
+    f.assert_no_leaks(|| InstanceField::new(
+        f.env.clone(), f.gbuffer.render_pass(), f.model.clone(), SAMPLES, DEPTH_MODE, DIMENSIONS, MAX_FRAMES_IN_FLIGHT,
+    ));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn skybox_renderer() {
+    let f = Fixture::new();
+    f.assert_no_leaks(|| SkyboxRenderer::new(
+        f.env.clone(), f.gbuffer.render_pass(), GBufferLayout::COLOR_ATTACHMENT_COUNT,
+        SAMPLES, DEPTH_MODE, MAX_FRAMES_IN_FLIGHT, DIMENSIONS,
+    ));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn environment_lighting() {
+    let f = Fixture::new();
+    let skybox = SkyboxRenderer::new(
+        f.env.clone(), f.gbuffer.render_pass(), GBufferLayout::COLOR_ATTACHMENT_COUNT,
+        SAMPLES, DEPTH_MODE, MAX_FRAMES_IN_FLIGHT, DIMENSIONS,
+    );
+    f.assert_no_leaks(|| EnvironmentLighting::new(f.env.clone(), skybox.texture()));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn terrain_renderer() {
+    let f = Fixture::new();
+
+    // a flat 64x64 raw heightfield, no heightmap asset needed
+    let path = std::env::temp_dir().join("leak_tests_terrain.r16");
+    std::fs::write(&path, vec![0_u8; 64 * 64 * 2]).unwrap();
+    let height_map = HeightMap::from_raw(&path, TerrainScale::default()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    f.assert_no_leaks(|| TerrainRenderer::new(
+        f.env.clone(), f.gbuffer.render_pass(), GBufferLayout::COLOR_ATTACHMENT_COUNT,
+        TerrainData::new(f.env.clone(), height_map), SAMPLES, DEPTH_MODE, MAX_FRAMES_IN_FLIGHT, DIMENSIONS,
+    ));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn shadow_map_framebuffer() {
+    let f = Fixture::new();
+    f.assert_no_leaks(|| ShadowMapFramebuffer::new(f.env.clone(), 512, CASCADE_COUNT));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn point_shadow_map() {
+    let f = Fixture::new();
+    f.assert_no_leaks(|| PointShadowMap::new(f.env.clone()));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn mesh_shadow_map_renderer() {
+    let f = Fixture::new();
+    let shadow_map = ShadowMapFramebuffer::new(f.env.clone(), 512, CASCADE_COUNT);
+    let uniforms = Rc::new(DynamicUniformRing::new(&f.env, CASCADE_COUNT, MAX_FRAMES_IN_FLIGHT));
+
+    f.assert_no_leaks(|| MeshShadowMapRenderer::new(
+        f.env.clone(), shadow_map.render_pass(), f.model.clone(), &f.scene_buffer, uniforms.clone(), 0, shadow_map.size(),
+    ));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn post_process_chain() {
+    let f = Fixture::new();
+    f.assert_no_leaks(|| {
+        let mut chain = PostProcessChain::new(f.env.clone(), OUTPUT_FORMAT, DIMENSIONS, MAX_FRAMES_IN_FLIGHT);
+        for effect in post_process::demo_effects(f.env.device()) {
+            chain.push(effect).unwrap();
+        }
+        chain
+    });
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn god_rays() {
+    let f = Fixture::new();
+    f.assert_no_leaks(|| GodRays::new(f.env.clone(), &f.gbuffer, MAX_FRAMES_IN_FLIGHT));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn hi_z_pyramid() {
+    let f = Fixture::new();
+    f.assert_no_leaks(|| HiZPyramid::new(f.env.clone(), &f.gbuffer, MAX_FRAMES_IN_FLIGHT));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn occlusion_culling() {
+    let f = Fixture::new();
+    f.assert_no_leaks(|| OcclusionCulling::new(f.env.clone(), DEPTH_MODE, MAX_FRAMES_IN_FLIGHT));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn reflective_shadow_map() {
+    let f = Fixture::new();
+    f.assert_no_leaks(|| ReflectiveShadowMap::new(f.env.clone(), f.model.clone(), &f.scene_buffer));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn decal_renderer() {
+    let f = Fixture::new();
+    f.assert_no_leaks(|| DecalRenderer::new(f.env.clone(), &f.gbuffer, MAX_FRAMES_IN_FLIGHT));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn light_manager() {
+    let f = Fixture::new();
+    f.assert_no_leaks(|| LightManager::new(f.env.clone(), MAX_FRAMES_IN_FLIGHT));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn quad_renderer() {
+    let f = Fixture::new();
+    let shadow_map = ShadowMapFramebuffer::new(f.env.clone(), 512, CASCADE_COUNT);
+    let point_shadow_map = PointShadowMap::new(f.env.clone());
+    let god_rays = GodRays::new(f.env.clone(), &f.gbuffer, MAX_FRAMES_IN_FLIGHT);
+    let lights = LightManager::new(f.env.clone(), MAX_FRAMES_IN_FLIGHT);
+    let skybox = SkyboxRenderer::new(
+        f.env.clone(), f.gbuffer.render_pass(), GBufferLayout::COLOR_ATTACHMENT_COUNT,
+        SAMPLES, DEPTH_MODE, MAX_FRAMES_IN_FLIGHT, DIMENSIONS,
+    );
+    let environment_lighting = EnvironmentLighting::new(f.env.clone(), skybox.texture());
+    let rsm = ReflectiveShadowMap::new(f.env.clone(), f.model.clone(), &f.scene_buffer);
+
+    f.assert_no_leaks(|| QuadRenderer::new(
+        f.env.clone(), &f.gbuffer, shadow_map.view, point_shadow_map.cube_view, god_rays.occlusion_view(),
+        lights.buffers(), environment_lighting.views(), rsm.views(), f.hdr_buffer.render_pass(), MAX_FRAMES_IN_FLIGHT,
+    ));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn particle_system() {
+    let f = Fixture::new();
+    f.assert_no_leaks(|| ParticleSystem::new(f.env.clone(), f.hdr_buffer.render_pass(), &f.gbuffer, MAX_FRAMES_IN_FLIGHT));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn transparent_renderer() {
+    let f = Fixture::new();
+    let lights = LightManager::new(f.env.clone(), MAX_FRAMES_IN_FLIGHT);
+
+    f.assert_no_leaks(|| TransparentRenderer::new(
+        f.env.clone(), f.hdr_buffer.render_pass(), f.model.clone(), &f.scene_buffer, &f.gbuffer, lights.buffers(),
+    ));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn debug_draw() {
+    let f = Fixture::new();
+    f.assert_no_leaks(|| DebugDraw::new(f.env.clone(), f.hdr_buffer.render_pass(), &f.gbuffer, MAX_FRAMES_IN_FLIGHT));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn temporal_anti_aliasing() {
+    let f = Fixture::new();
+    f.assert_no_leaks(|| TemporalAntiAliasing::new(f.env.clone(), &f.gbuffer, &f.hdr_buffer, MAX_FRAMES_IN_FLIGHT));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device and compiled shaders"]
+fn bloom() {
+    let f = Fixture::new();
+    let encoding = OutputEncoding {
+        transfer: TransferFunction::HardwareSrgb,
+        paper_white_nits: 200,
+    };
+    f.assert_no_leaks(|| Bloom::new(f.env.clone(), &f.hdr_buffer, f.quad_render_pass, encoding, MAX_FRAMES_IN_FLIGHT));
+}
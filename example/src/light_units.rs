@@ -0,0 +1,50 @@
+use std::f32::consts::PI;
+
+// Shaders work in raw units: a white lambert surface lit by 1.0 is 1.0 bright.
+// The composite sun is 1.0, so raw 1.0 of light is the sun illuminance
+pub const SUN_ILLUMINANCE: f32 = 100_000.0;
+// Luminance of raw 1.0 of the lit scene, cd/m2
+pub const NITS_PER_UNIT: f32 = SUN_ILLUMINANCE / PI;
+
+// Illuminance of directional lights, lux
+pub fn lux_to_raw(lux: f32) -> f32 {
+    lux / SUN_ILLUMINANCE
+}
+
+pub fn raw_to_lux(raw: f32) -> f32 {
+    raw * SUN_ILLUMINANCE
+}
+
+// Luminous intensity of point and spot lights, candela. Shaders divide it by squared distance
+pub fn candela_to_raw(candela: f32) -> f32 {
+    candela / SUN_ILLUMINANCE
+}
+
+// Luminous power of a point light spread over the whole sphere
+pub fn point_lumens_to_candela(lumens: f32) -> f32 {
+    lumens / (4.0 * PI)
+}
+
+// Spot light power is spread over its cone only, `outer_angle` - cone half angle in degrees
+pub fn spot_lumens_to_candela(lumens: f32, outer_angle: f32) -> f32 {
+    lumens / (2.0 * PI * (1.0 - outer_angle.to_radians().cos())).max(1e-4)
+}
+
+pub fn raw_to_luminance(raw: f32) -> f32 {
+    raw * NITS_PER_UNIT
+}
+
+// Exposure value of average scene luminance, ISO 100 and reflected-light meter constant K = 12.5
+pub fn luminance_to_ev100(nits: f32) -> f32 {
+    (nits.max(1e-6) * 100.0 / 12.5).log2()
+}
+
+// EV100 of camera settings: f-number, shutter time in seconds and ISO
+pub fn camera_ev100(aperture: f32, shutter_time: f32, iso: f32) -> f32 {
+    (aperture * aperture / shutter_time * 100.0 / iso).log2()
+}
+
+// Multiplier from cd/m2 to output, the sensor saturates at 1.2 * 2^EV100
+pub fn ev100_to_exposure(ev100: f32) -> f32 {
+    1.0 / (1.2 * 2.0f32.powf(ev100))
+}
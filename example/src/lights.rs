@@ -0,0 +1,277 @@
+use std::sync::Arc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use ash_render_env::env::RenderEnv;
+use ash_render_env::memory_tracker;
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::utils::buffer_utils::create_buffer;
+
+use crate::light_units;
+
+pub const MAX_LIGHTS: usize = 64;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum LightKind {
+    Point,
+    Spot,
+    Directional,
+}
+
+impl LightKind {
+    const ALL: [LightKind; 3] = [LightKind::Point, LightKind::Spot, LightKind::Directional];
+
+    // Directional lights are given by illuminance, point and spot ones by luminous intensity
+    fn unit(&self) -> &'static str {
+        match self {
+            LightKind::Directional => "lux",
+            LightKind::Point | LightKind::Spot => "cd",
+        }
+    }
+
+    fn default_intensity(&self) -> f32 {
+        match self {
+            LightKind::Point => 25_000.0,
+            LightKind::Spot => 50_000.0,
+            LightKind::Directional => 20_000.0,
+        }
+    }
+
+    fn shader_id(&self) -> f32 {
+        match self {
+            LightKind::Point => 0.0,
+            LightKind::Spot => 1.0,
+            LightKind::Directional => 2.0,
+        }
+    }
+}
+
+// Unshadowed light. World up is -Y, directions point where the light shines
+#[derive(Clone, Debug)]
+pub struct Light {
+    pub kind: LightKind,
+    pub position: [f32; 3],
+    pub direction: [f32; 3],
+    pub color: [f32; 3],
+    // lux for directional lights, candela for point and spot ones
+    pub intensity: f32,
+    // point and spot lights fade out to zero at this distance
+    pub radius: f32,
+    // spot cone half angles, degrees
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+}
+
+impl Light {
+    pub fn new(kind: LightKind) -> Light {
+        Light {
+            kind,
+            position: [0.0, -2.0, 0.0],
+            direction: [0.0, 1.0, 0.0],
+            color: [1.0, 1.0, 1.0],
+            intensity: kind.default_intensity(),
+            radius: 6.0,
+            inner_angle: 20.0,
+            outer_angle: 30.0,
+        }
+    }
+
+    // Intensity in shader units, the same conversion for lux and candela
+    pub fn raw_intensity(&self) -> f32 {
+        match self.kind {
+            LightKind::Directional => light_units::lux_to_raw(self.intensity),
+            LightKind::Point | LightKind::Spot => light_units::candela_to_raw(self.intensity),
+        }
+    }
+
+    // Luminous power of point and spot lights
+    fn lumens(&self) -> Option<f32> {
+        match self.kind {
+            LightKind::Point => Some(self.intensity / light_units::point_lumens_to_candela(1.0)),
+            LightKind::Spot => Some(self.intensity / light_units::spot_lumens_to_candela(1.0, self.outer_angle)),
+            LightKind::Directional => None,
+        }
+    }
+
+    fn to_gpu(&self) -> GpuLight {
+        let d = self.direction;
+        let len = (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt().max(1e-6);
+        let c = self.color;
+        let intensity = self.raw_intensity();
+
+        GpuLight {
+            position: [self.position[0], self.position[1], self.position[2], self.kind.shader_id()],
+            direction: [d[0] / len, d[1] / len, d[2] / len, self.outer_angle.to_radians().cos()],
+            color: [c[0] * intensity, c[1] * intensity, c[2] * intensity, self.radius],
+            params: [self.inner_angle.min(self.outer_angle).to_radians().cos(), 0.0, 0.0, 0.0],
+        }
+    }
+
+    // Returns true when the light should be removed
+    fn ui(&mut self, ui: &mut egui::Ui) -> bool {
+        let old_kind = self.kind;
+        egui::ComboBox::from_label("Kind")
+            .selected_text(format!("{:?}", self.kind))
+            .show_ui(ui, |ui| {
+                for kind in LightKind::ALL.iter() {
+                    ui.selectable_value(&mut self.kind, *kind, format!("{:?}", kind));
+                }
+            });
+        // lux and candela don't convert into each other
+        if self.kind.unit() != old_kind.unit() {
+            self.intensity = self.kind.default_intensity();
+        }
+
+        if self.kind != LightKind::Directional {
+            ui.horizontal(|ui| {
+                ui.label("Position");
+                ui.add(egui::DragValue::new(&mut self.position[0]).speed(0.05).prefix("X: "));
+                ui.add(egui::DragValue::new(&mut self.position[1]).speed(0.05).prefix("Y: "));
+                ui.add(egui::DragValue::new(&mut self.position[2]).speed(0.05).prefix("Z: "));
+            });
+            ui.add(egui::Slider::new(&mut self.radius, 0.5..=50.0).text("Radius"));
+        }
+
+        if self.kind != LightKind::Point {
+            ui.horizontal(|ui| {
+                ui.label("Direction");
+                ui.add(egui::DragValue::new(&mut self.direction[0]).speed(0.02).prefix("X: "));
+                ui.add(egui::DragValue::new(&mut self.direction[1]).speed(0.02).prefix("Y: "));
+                ui.add(egui::DragValue::new(&mut self.direction[2]).speed(0.02).prefix("Z: "));
+            });
+        }
+
+        if self.kind == LightKind::Spot {
+            ui.add(egui::Slider::new(&mut self.inner_angle, 1.0..=89.0).text("Inner angle"));
+            ui.add(egui::Slider::new(&mut self.outer_angle, 1.0..=89.0).text("Outer angle"));
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Color");
+            ui.color_edit_button_rgb(&mut self.color);
+        });
+        ui.add(egui::Slider::new(&mut self.intensity, 0.0..=200_000.0)
+            .logarithmic(true)
+            .suffix(format!(" {}", self.kind.unit()))
+            .text("Intensity"));
+        match self.lumens() {
+            Some(lumens) => ui.label(format!("{:.0} lm, raw {:.3}", lumens, self.raw_intensity())),
+            None => ui.label(format!("Raw {:.3}", self.raw_intensity())),
+        };
+
+        ui.button("Remove").clicked()
+    }
+}
+
+// std430 layout of compose.frag `Light`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct GpuLight {
+    // w - kind: 0 point, 1 spot, 2 directional
+    position: [f32; 4],
+    // w - cos of the spot outer angle
+    direction: [f32; 4],
+    // rgb premultiplied by raw intensity, w - radius
+    color: [f32; 4],
+    // x - cos of the spot inner angle
+    params: [f32; 4],
+}
+
+// Dynamic lights of the compose pass, uploaded every frame to the storage buffer of that frame in flight:
+// uvec4 header (x - light count) followed by the light array
+pub struct LightManager {
+    pub lights: Vec<Light>,
+
+    buffers: Vec<(vk::Buffer, vk::DeviceMemory)>,
+    device: ash::Device,
+}
+
+impl LightManager {
+    pub fn new(env: Arc<RenderEnv>, max_inflight_frames: usize) -> LightManager {
+        let size = 16 + std::mem::size_of::<GpuLight>() * MAX_LIGHTS;
+        let buffers = (0..max_inflight_frames)
+            .map(|_| create_buffer(
+                env.device(),
+                size as u64,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                &env.mem_properties,
+            ))
+            .collect();
+
+        let mut manager = LightManager {
+            lights: vec![],
+            buffers,
+            device: env.device().clone(),
+        };
+        for frame in 0..max_inflight_frames {
+            manager.upload(frame);
+        }
+
+        manager
+    }
+
+    pub fn buffers(&self) -> Vec<vk::Buffer> {
+        self.buffers.iter().map(|(buffer, _)| *buffer).collect()
+    }
+
+    // The buffer of `frame` must not be in use by GPU
+    pub fn upload(&mut self, frame: usize) {
+        let memory = self.buffers[frame].1;
+        let lights: Vec<GpuLight> = self.lights.iter().take(MAX_LIGHTS).map(Light::to_gpu).collect();
+        let header = [lights.len() as u32, 0, 0, 0];
+
+        unsafe {
+            let size = (16 + std::mem::size_of_val(lights.as_slice())) as u64;
+            let data_ptr = self.device
+                .map_memory(memory, 0, size, vk::MemoryMapFlags::empty())
+                .expect("Failed to Map Memory") as *mut u8;
+
+            (data_ptr as *mut [u32; 4]).write(header);
+            (data_ptr.add(16) as *mut GpuLight).copy_from_nonoverlapping(lights.as_ptr(), lights.len());
+
+            self.device.unmap_memory(memory);
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        let mut removed = None;
+        for (idx, light) in self.lights.iter_mut().enumerate() {
+            egui::CollapsingHeader::new(format!("{:?} light {}", light.kind, idx + 1))
+                .id_source(("light", idx))
+                .show(ui, |ui| {
+                    if light.ui(ui) {
+                        removed = Some(idx);
+                    }
+                });
+        }
+
+        if let Some(idx) = removed {
+            self.lights.remove(idx);
+        }
+
+        if self.lights.len() < MAX_LIGHTS {
+            ui.horizontal(|ui| {
+                for kind in LightKind::ALL.iter() {
+                    if ui.button(format!("+ {:?}", kind)).clicked() {
+                        self.lights.push(Light::new(*kind));
+                    }
+                }
+            });
+        }
+    }
+}
+
+impl Drop for LightManager {
+    fn drop(&mut self) {
+        unsafe {
+            for (buffer, memory) in self.buffers.drain(..) {
+                self.device.destroy_buffer(buffer, None);
+                object_tracker::destroyed(ObjectKind::Buffer);
+                self.device.free_memory(memory, None);
+                memory_tracker::freed(memory);
+            }
+        }
+    }
+}
@@ -1,238 +1,840 @@
 use std::ops::RangeInclusive;
 use std::path::Path;
 use std::ptr;
+use std::rc::Rc;
 use std::sync::Arc;
+use std::time::Instant;
 
 use ash::version::DeviceV1_0;
 use ash::vk;
-use cgmath::{Matrix4, SquareMatrix};
-use winit::event::{Event, VirtualKeyCode, WindowEvent};
+use cgmath::{EuclideanSpace, InnerSpace, Matrix4, Point3, SquareMatrix, Vector3};
+use winit::event::{ElementState, Event, ModifiersState, MouseButton, VirtualKeyCode, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
 use winit::platform::run_return::EventLoopExtRunReturn;
 
 use ash_render_env::{env, frame_buffer};
-use ash_render_env::camera::Camera;
+use ash_render_env::aux_pass::{AuxPasses, EnginePass};
+use ash_render_env::bvh::{Aabb, Bvh};
+use ash_render_env::dynamic_uniform_buffer::DynamicUniformRing;
+use ash_render_env::camera::{Camera, CameraInput, CameraSettings};
 use ash_render_env::egui::Egui;
 use ash_render_env::env::RenderEnv;
 use ash_render_env::fps_limiter::FPSLimiter;
+use ash_render_env::frame_capture::{CaptureOutput, FrameCapture};
+use ash_render_env::gpu_profiler::GpuProfiler;
+use ash_render_env::metrics::Metrics;
+use ash_render_env::memory_tracker::{self, MemoryCategory};
+use ash_render_env::object_tracker;
+use ash_render_env::pipeline_cache::PipelineWarmup;
 use ash_render_env::primary_cmd_buffer::PrimaryCommandBuffer;
+use ash_render_env::shader_tweaks;
+use ash_render_env::swapchain::{OutputEncoding, OutputFormat, PresentMode};
+use ash_render_env::utils::DepthMode;
+use ash_render_env::window::{WindowDecorations, WindowMode, WindowModeSwitch};
 use utils::{render_pass, sync};
 
-use crate::shadow_map::{CASCADE_COUNT, CascadeInfo, ShadowMapFramebuffer};
-use crate::utils::heightmap_terrain::terrain::{HeightMap, TerrainData};
+use crate::assets::{AssetCache, AssetKind, LoadedAsset};
+use crate::editor::decal_tool::DecalTool;
+use crate::editor::history::{History, SetValue};
+use crate::editor::placement::PlacementTool;
+use crate::editor::scene::EditorScene;
+use crate::editor::viewport::SceneViewport;
+use crate::gamepad::Gamepads;
+use crate::shadow_map::{CASCADE_COUNT, CASCADE_DEBUG_COLORS, CascadeInfo, POINT_SHADOW_MAP_SIZE, PointLight, PointShadowMap, ShadowMapFramebuffer, ShadowSettings};
+use crate::utils::bloom::Bloom;
+use crate::utils::decals::DecalRenderer;
+use crate::utils::environment_map::EnvironmentLighting;
+use crate::utils::gbuffer::{AntiAliasing, GBufferLayout};
+use crate::utils::god_rays::GodRays;
+use crate::utils::hi_z::HiZPyramid;
+use crate::utils::instance_field::InstanceField;
+use crate::utils::occlusion_culling::OcclusionCulling;
+use crate::utils::taa::TemporalAntiAliasing;
+use crate::utils::reflective_shadow_map::ReflectiveShadowMap;
+use crate::utils::particles::ParticleSystem;
+use crate::utils::transparent_render::TransparentRenderer;
+use crate::utils::post_process::{self, PostProcessChain};
+use crate::utils::debug_draw::DebugDraw;
+use crate::utils::heightmap_terrain::terrain::TerrainData;
 use crate::utils::heightmap_terrain::terrain_renderer::TerrainRenderer;
-use crate::utils::mesh::Mesh;
+use crate::utils::heightmap_terrain::walkability::WalkabilitySettings;
+use crate::utils::model::Model;
 use crate::utils::mesh_render::MeshRenderer;
 use crate::utils::mesh_shadowmap_render::MeshShadowMapRenderer;
-use crate::utils::quad_render::QuadRenderer;
+use crate::utils::quad_render::{GBufferView, QuadRenderer};
+use crate::utils::scene_buffer::{GpuObject, MAX_SCENE_OBJECTS, SceneBuffer};
 use crate::utils::skybox_render::SkyboxRenderer;
 use crate::utils::sync::MAX_FRAMES_IN_FLIGHT;
+use crate::weather::Weather;
+use crate::atmosphere::Atmosphere;
+use crate::exposure::Exposure;
+use crate::depth_readback::{DepthReadback, DepthReadbackAction, DepthSource, LinearDepth};
+use crate::input_focus::InputFocus;
+use crate::input_replay::{InputRecorder, InputReplay};
+use crate::lights::LightManager;
+use crate::settings::Settings;
 
 mod utils;
 mod shadow_map;
+mod editor;
+mod weather;
+mod atmosphere;
+mod exposure;
+mod light_units;
+mod assets;
+mod asset_loader;
+mod lights;
+mod depth_readback;
+mod input_focus;
+mod input_replay;
+mod gamepad;
+mod settings;
+#[cfg(test)]
+mod leak_tests;
+
+const WINDOW_TITLE: &str = "test";
+const WINDOW_ICON: &str = "assets/icon.png";
+// driver pipeline cache and pipelines of the last run, see PipelineCache::warmup
+const PIPELINE_CACHE: &str = "pipeline_cache.bin";
+const PIPELINE_PROFILE: &str = "pipelines.profile";
+const METRICS_CSV: &str = "metrics.csv";
+// window, graphics and startup assets, saved on exit
+const SETTINGS_FILE: &str = "settings.toml";
+// range of the RGBM sky in the G-buffer albedo, SKY_RANGE of skybox.frag and compose.frag
+const SKY_RGBM_RANGE: f32 = 16.0;
+// keeps the near plane out of object boxes
+const CAMERA_RADIUS: f32 = 0.2;
+// camera height over the terrain when clamped to the ground
+const CAMERA_EYE_HEIGHT: f32 = 1.7;
+// G-buffer sample counts offered in the settings, up to the device limit
+const MSAA_SAMPLE_COUNTS: [vk::SampleCountFlags; 4] = [
+    vk::SampleCountFlags::TYPE_1,
+    vk::SampleCountFlags::TYPE_2,
+    vk::SampleCountFlags::TYPE_4,
+    vk::SampleCountFlags::TYPE_8,
+];
 
 struct HelloApplication {
     egui: Egui,
 
     final_pass_draw_command: PrimaryCommandBuffer,
+    compose_pass_draw_command: PrimaryCommandBuffer,
     geometry_pass_draw_command: PrimaryCommandBuffer,
     shadowmap_pass_draw_commands: Vec<PrimaryCommandBuffer>,
+    point_shadow_pass_draw_commands: Vec<PrimaryCommandBuffer>,
 
     quad_renderer: QuadRenderer,
+    god_rays: GodRays,
+    // depth of the last frame and the culling of mesh draws against it
+    hi_z: HiZPyramid,
+    occlusion_culling: OcclusionCulling,
+    taa: TemporalAntiAliasing,
+    decals: DecalRenderer,
+    particles: ParticleSystem,
+    transparent_renderer: TransparentRenderer,
+    debug_draw: DebugDraw,
+    bloom: Bloom,
+    post_process: PostProcessChain,
+    // user offscreen passes, executed after the engine pass each of them is attached to
+    aux_passes: AuxPasses,
+    lights: LightManager,
     swapchain_stuff: ash_render_env::swapchain::SwapChain,
-
-    mesh: Arc<Mesh>,
+    // frame fences to wait before the swapchains replaced by resizes are destroyed
+    retired_swapchain_frames: usize,
+    // the scene follows the window once the frames rendered to its old targets are finished
+    scene_resize_pending: bool,
+
+    // before meshes and renderers: cached meshes must be freed while the device is alive
+    assets: AssetCache,
+    model: Arc<Model>,
     mesh_renderer: MeshRenderer,
+    instance_field: InstanceField,
     mesh_shadow_map_renderers: Vec<MeshShadowMapRenderer>,
+    mesh_point_shadow_renderers: Vec<MeshShadowMapRenderer>,
+    reflective_shadow_map: ReflectiveShadowMap,
+    scene_buffer: SceneBuffer,
 
     skybox_renderer: SkyboxRenderer,
+    environment_lighting: EnvironmentLighting,
 
     sync: sync::SyncObjects,
+    // fence of the frame that renders to the swapchain image, the image may be acquired out of order
+    images_in_flight: Vec<vk::Fence>,
 
+    // index of per-frame resources, cycled through MAX_FRAMES_IN_FLIGHT
     current_frame: usize,
     is_window_resized: bool,
+    // the surface format changed, the final pass can't draw to the swapchain until restart
+    surface_format_lost: bool,
 
     camera: Camera,
 
     offscreen_buffer: frame_buffer::Framebuffer,
+    // lit scene, exposed but not clamped, bloom reads it
+    hdr_buffer: frame_buffer::Framebuffer,
 
     terrain_renderer: TerrainRenderer,
 
     final_render_pass: vk::RenderPass,
 
-    clear_color: [f32; 3],
     tick_counter: FPSLimiter,
+    gpu_profiler: GpuProfiler,
 
     shadow_map_fb: ShadowMapFramebuffer,
+    point_shadow_map: PointShadowMap,
 
     env: Arc<env::RenderEnv>,
     cascades: Vec<CascadeInfo>,
-    cascade_split_lambda: f32,
-    egui_current_shadowmap_cascade_image: u32,
+    // cascades shown by the frozen frusta overlay
+    frozen_cascades: Option<Vec<CascadeInfo>>,
+    egui_normal_texture: egui::TextureId,
+    egui_cascade_textures: Vec<egui::TextureId>,
+    egui_current_shadowmap_cascade_image: usize,
+
+    placement_tool: PlacementTool,
+    decal_tool: DecalTool,
+    scene: EditorScene,
+    history: History,
+    scene_revision: u64,
+    modifiers: ModifiersState,
+    input_focus: InputFocus,
+    // animation time, advanced by frame durations so replays are deterministic
+    time: f32,
+    last_frame: Instant,
+    input_recorder: Option<InputRecorder>,
+    input_replay: Option<InputReplay>,
+    weather: Weather,
+    // sun direction and fog
+    atmosphere: Atmosphere,
+    exposure: Exposure,
+    walkability: WalkabilitySettings,
+    show_walkability: bool,
+    walkable_fraction: f32,
+    shadow_settings: ShadowSettings,
+    // scene is shown in an egui window instead of the whole window, applied at the start of a frame
+    show_viewport: bool,
+    viewport: Option<SceneViewport>,
+    metrics: Metrics,
+    show_metrics: bool,
+    // limits, formats and extensions of the device
+    show_system_info: bool,
+    // device memory by category against the driver budget
+    show_memory: bool,
+    // tweak_* push constants of the loaded shaders
+    show_shader_tweaks: bool,
+    show_depth_readback: bool,
+    depth_readback: DepthReadback,
+    // bounds of scene objects in update_scene_buffer() order, for picking and camera collision
+    scene_bvh: Bvh,
+    show_bvh: bool,
+    show_gizmos: bool,
+    bvh_depth: usize,
+    // camera walks on the terrain at CAMERA_EYE_HEIGHT, see clamp_camera_to_ground()
+    clamp_to_ground: bool,
+    // mesh and terrain are drawn with lines, F3 toggles
+    wireframe: bool,
+    // depth only draws of mesh and terrain before the G-buffer ones, compare the geometry GPU time
+    depth_prepass: bool,
+    // MSAA renders the G-buffer with msaa_samples, FXAA and TAA with a single sample
+    anti_aliasing: AntiAliasing,
+    msaa_samples: vk::SampleCountFlags,
+    // largest sample count offered in the settings
+    max_msaa_samples: vk::SampleCountFlags,
+    gbuffer_view: GBufferView,
+    // compiles pipelines used by the last run in background
+    pipeline_warmup: Option<PipelineWarmup>,
+    // applied by swapchain recreation at the end of the frame
+    present_mode: PresentMode,
+    // saved to the settings, the final pass pipelines depend on it, so it is applied on the next start
+    output_format: OutputFormat,
+    // export of presented frames, see start_capture()
+    frame_capture: Option<FrameCapture>,
+    capture_every_nth: u32,
+    capture_video: bool,
+    window_decorations: WindowDecorations,
+    // fullscreen and resolution, F11 toggles. Applied at the start of a frame
+    window_mode: WindowModeSwitch,
+    // camera sticks, polled every frame
+    gamepads: Gamepads,
+    // loaded from SETTINGS_FILE, updated with the runtime choices and saved on drop
+    settings: Settings,
 }
 
 impl HelloApplication {
-    pub fn new(wnd: &winit::window::Window) -> HelloApplication {
+    pub fn new(wnd: &winit::window::Window, depth_mode: DepthMode, present_mode: PresentMode, output_format: OutputFormat,
+               settings: Settings) -> HelloApplication {
         let env = Arc::new(env::RenderEnv::new(wnd));
 
-        let max_msaa_samples = ash_render_env::utils::get_max_usable_sample_count(&env);
-        let msaa_samples = vk::SampleCountFlags::TYPE_2; //ash_render_env::utils::get_max_usable_sample_count(&env);
+        // both files are missing on the first run
+        if Path::new(PIPELINE_CACHE).exists() {
+            if let Err(err) = env.pipeline_cache().load(Path::new(PIPELINE_CACHE)) {
+                println!("{}", err);
+            }
+        }
+        let pipeline_warmup = if Path::new(PIPELINE_PROFILE).exists() {
+            env.pipeline_cache().warmup(Path::new(PIPELINE_PROFILE))
+                .map_err(|err| println!("{}", err))
+                .ok()
+        } else {
+            None
+        };
+
+        let max_msaa_samples = env.capabilities().max_msaa_samples;
+        let msaa_samples = MSAA_SAMPLE_COUNTS.iter().copied()
+            .rev()
+            .find(|samples| samples.as_raw() <= settings.graphics.msaa_samples && samples.as_raw() <= max_msaa_samples.as_raw())
+            .unwrap_or(vk::SampleCountFlags::TYPE_1);
 
         println!("MSAA: {:?} (max={:?})", msaa_samples, max_msaa_samples);
 
-        let mut swapchain_stuff = ash_render_env::swapchain::SwapChain::new(&env, wnd.inner_size());
+        let mut swapchain_stuff = ash_render_env::swapchain::SwapChain::new(&env, wnd.inner_size(), present_mode, output_format)
+            .expect("Failed to create swapchain!");
+        let output_encoding = OutputEncoding {
+            transfer: swapchain_stuff.transfer,
+            paper_white_nits: settings.graphics.paper_white_nits(),
+        };
+        println!("Output: {:?} {:?} ({:?})", swapchain_stuff.format, swapchain_stuff.color_space, output_encoding.transfer);
 
-        let quad_render_pass = render_pass::create_quad_render_pass(env.device(), swapchain_stuff.format);
+        let quad_render_pass = render_pass::create_quad_render_pass(&env, swapchain_stuff.format);
         swapchain_stuff.create_framebuffers(env.device(), quad_render_pass);
 
         let mut camera = Camera::new();
+        camera.set_depth_mode(depth_mode);
         camera.set_viewport(
             swapchain_stuff.size.width,
             swapchain_stuff.size.height,
         );
 
         let dimensions = [swapchain_stuff.size.width, swapchain_stuff.size.height];
-        let mut offscreen_framebuffer = frame_buffer::Framebuffer::new(env.clone(), vec!(
-            frame_buffer::AttachmentDesciption {  // color
-                samples_count: msaa_samples,
-                format: vk::Format::R8G8B8A8_SRGB,
-            },
-            frame_buffer::AttachmentDesciption {  // pos
-                samples_count: msaa_samples,
-                format: vk::Format::R16G16B16A16_SFLOAT,
-            },
-            frame_buffer::AttachmentDesciption {  // normal
-                samples_count: msaa_samples,
+        let gbuffer_layout = GBufferLayout::new(&env, msaa_samples, depth_mode);
+        let mut offscreen_framebuffer = frame_buffer::Framebuffer::new(env.clone(), gbuffer_layout.attachments());
+        offscreen_framebuffer.set_name("G-buffer");
+        offscreen_framebuffer.set_memory_category(MemoryCategory::GBuffer);
+        offscreen_framebuffer.set_depth_mode(gbuffer_layout.depth_mode);
+        offscreen_framebuffer.resize_swapchain(dimensions);
+
+        let mut hdr_framebuffer = frame_buffer::Framebuffer::new(env.clone(), vec!(
+            frame_buffer::AttachmentDesciption {
+                samples_count: vk::SampleCountFlags::TYPE_1,
                 format: vk::Format::R16G16B16A16_SFLOAT,
             },
-            frame_buffer::AttachmentDesciption {  // depth
-                samples_count: msaa_samples,
-                format: vk::Format::D32_SFLOAT,
-            },
         ));
-        offscreen_framebuffer.resize_swapchain(dimensions);
+        hdr_framebuffer.set_name("HDR");
+        hdr_framebuffer.resize_swapchain(dimensions);
 
         let sync = sync::create_sync_objects(env.device());
 
-        let mut egui = Egui::new(env.clone(), swapchain_stuff.format, wnd.scale_factor(), dimensions, MAX_FRAMES_IN_FLIGHT, msaa_samples);
-        egui.register_texture(0, offscreen_framebuffer.attachments[2].view, true);
+        let mut egui = Egui::new(env.clone(), swapchain_stuff.format, output_encoding, wnd.scale_factor(), dimensions, MAX_FRAMES_IN_FLIGHT, msaa_samples)
+            .expect("Failed to create egui!");
+        let egui_normal_texture = egui.add_texture(offscreen_framebuffer.attachments[GBufferLayout::NORMAL].view, true);
 
         let mut draw_mesh_render_system = PrimaryCommandBuffer::new(env.clone(), MAX_FRAMES_IN_FLIGHT);
+        draw_mesh_render_system.set_name("geometry pass");
         draw_mesh_render_system.set_dimensions(dimensions);
 
         let mut quad_render_system = PrimaryCommandBuffer::new(env.clone(), MAX_FRAMES_IN_FLIGHT);
+        quad_render_system.set_name("final pass");
         quad_render_system.set_dimensions(dimensions);
 
-        let mesh = Arc::new(
-            Mesh::load_from_file(env.clone(), Path::new("assets/chalet2.obj"))
-        );
+        let mut compose_pass_draw_command = PrimaryCommandBuffer::new(env.clone(), MAX_FRAMES_IN_FLIGHT);
+        compose_pass_draw_command.set_name("compose pass");
+        compose_pass_draw_command.set_dimensions(dimensions);
+
+        // the scene starts with placeholders, the model and the skybox appear when their loading is done
+        let mut assets = AssetCache::new(&env);
+        assets.request_model(&settings.assets.model);
+        assets.request_skybox(&settings.assets.skybox);
+        let model = Arc::new(Model::placeholder(&env));
 
-        let mesh_renderer = MeshRenderer::new(
+        let scene_buffer = SceneBuffer::new(env.clone(), MAX_FRAMES_IN_FLIGHT);
+
+        let mut mesh_renderer = MeshRenderer::new(
             env.clone(),
             offscreen_framebuffer.render_pass(),
-            mesh.clone(),
-            offscreen_framebuffer.attachments.len() - 1, // color attachments only
+            model.clone(),
+            &scene_buffer,
+            GBufferLayout::COLOR_ATTACHMENT_COUNT,
             msaa_samples,
-            MAX_FRAMES_IN_FLIGHT,
+            depth_mode,
+            dimensions,
+        );
+        mesh_renderer.set_lod_error(settings.graphics.lod_error_pixels());
+        let instance_field = InstanceField::new(
+            env.clone(),
+            offscreen_framebuffer.render_pass(),
+            model.clone(),
+            msaa_samples,
+            depth_mode,
             dimensions,
+            MAX_FRAMES_IN_FLIGHT,
         );
 
         let skybox_renderer = SkyboxRenderer::new(
             env.clone(),
             offscreen_framebuffer.render_pass(),
-            offscreen_framebuffer.attachments.len() - 1, // color attachments only
+            GBufferLayout::COLOR_ATTACHMENT_COUNT,
             msaa_samples,
+            depth_mode,
             MAX_FRAMES_IN_FLIGHT,
             dimensions,
         );
+        let environment_lighting = EnvironmentLighting::new(env.clone(), skybox_renderer.texture());
 
-        let height_map = HeightMap::from_png(Path::new("./assets/terrain/heightmap2.png"));
+        let height_map = assets.load_height_map(&settings.assets.heightmap)
+            .expect("Failed to load default heightmap!");
         let terrain_data = TerrainData::new(env.clone(), height_map);
         let terrain_renderer = TerrainRenderer::new(
             env.clone(),
             offscreen_framebuffer.render_pass(),
-            offscreen_framebuffer.attachments.len() - 1,
+            GBufferLayout::COLOR_ATTACHMENT_COUNT,
             terrain_data, msaa_samples,
+            depth_mode,
             MAX_FRAMES_IN_FLIGHT,
             dimensions);
 
 
-        let mut shadow_map_fb = ShadowMapFramebuffer::new(env.clone());
-        egui.register_texture_layout(1, shadow_map_fb.get_cascade_view(0), vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL);
-        egui.register_texture_layout(2, shadow_map_fb.get_cascade_view(1), vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL);
-        egui.register_texture_layout(3, shadow_map_fb.get_cascade_view(2), vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL);
-        egui.register_texture_layout(4, shadow_map_fb.get_cascade_view(3), vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL);
+        let mut shadow_map_fb = ShadowMapFramebuffer::new(env.clone(), settings.graphics.shadow_map_size, settings.graphics.cascade_count());
+        let egui_cascade_textures: Vec<_> = (0..shadow_map_fb.cascade_count())
+            .map(|cascade_idx| egui.add_texture_with_layout(shadow_map_fb.get_cascade_view(cascade_idx), vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL))
+            .collect();
 
         let mut shadowmap_pass_draw_commands = Vec::new();
 
-        for cascade_idx in 0..CASCADE_COUNT {
+        for cascade_idx in 0..shadow_map_fb.cascade_count() {
             let mut shadowmap_pass_draw_command = PrimaryCommandBuffer::new(env.clone(), MAX_FRAMES_IN_FLIGHT);
-            shadowmap_pass_draw_command.set_dimensions([4096 as u32, 4096 as u32]);
+            shadowmap_pass_draw_command.set_name(&format!("shadow cascade {} pass", cascade_idx));
+            shadowmap_pass_draw_command.set_dimensions(shadow_map_fb.size());
 
             shadowmap_pass_draw_commands.push(shadowmap_pass_draw_command);
         }
 
+        let point_shadow_map = PointShadowMap::new(env.clone());
+
+        // one slot per cascade and per cube face
+        let shadow_uniforms = Rc::new(
+            DynamicUniformRing::new(&env, CASCADE_COUNT + point_shadow_map.face_count(), MAX_FRAMES_IN_FLIGHT)
+        );
+
         let mut mesh_shadow_map_renderers = Vec::new();
-        for _ in 0..CASCADE_COUNT {
+        for cascade_idx in 0..shadow_map_fb.cascade_count() {
             mesh_shadow_map_renderers.push(
                 MeshShadowMapRenderer::new(
                     env.clone(),
                     shadow_map_fb.render_pass(),
-                    mesh.clone(),
-                    MAX_FRAMES_IN_FLIGHT,
-                    [4096, 4096],
+                    model.clone(),
+                    &scene_buffer,
+                    shadow_uniforms.clone(),
+                    cascade_idx,
+                    shadow_map_fb.size(),
+                )
+            );
+        }
+        let point_shadow_size = [POINT_SHADOW_MAP_SIZE, POINT_SHADOW_MAP_SIZE];
+
+        let mut point_shadow_pass_draw_commands = Vec::new();
+        let mut mesh_point_shadow_renderers = Vec::new();
+        for face_idx in 0..point_shadow_map.face_count() {
+            let mut draw_command = PrimaryCommandBuffer::new(env.clone(), MAX_FRAMES_IN_FLIGHT);
+            draw_command.set_name(&format!("point shadow face {} pass", face_idx));
+            draw_command.set_dimensions(point_shadow_size);
+            point_shadow_pass_draw_commands.push(draw_command);
+
+            mesh_point_shadow_renderers.push(
+                MeshShadowMapRenderer::new(
+                    env.clone(),
+                    point_shadow_map.render_pass(),
+                    model.clone(),
+                    &scene_buffer,
+                    shadow_uniforms.clone(),
+                    CASCADE_COUNT + face_idx,
+                    point_shadow_size,
                 )
             );
         }
 
-        let cascade_split_lambda = 0.1;
-        let cascades = shadow_map_fb.update_cascades(&camera, cascade_split_lambda);
+        let scene = EditorScene::new();
+        let atmosphere = Atmosphere::new();
+        let cascades = shadow_map_fb.update_cascades(&camera.descriptor(), scene.cascade_split_lambda, atmosphere.sun_direction());
+
+        // the post process targets are the largest transient attachments, created first so the others can alias their memory
+        let mut post_process = PostProcessChain::new(env.clone(), swapchain_stuff.format, dimensions, MAX_FRAMES_IN_FLIGHT);
+        for effect in post_process::demo_effects(env.device()) {
+            post_process.push(effect).expect("Failed to create post effect!");
+        }
+        let god_rays = GodRays::new(env.clone(), &offscreen_framebuffer, MAX_FRAMES_IN_FLIGHT);
+        let hi_z = HiZPyramid::new(env.clone(), &offscreen_framebuffer, MAX_FRAMES_IN_FLIGHT);
+        let occlusion_culling = OcclusionCulling::new(env.clone(), offscreen_framebuffer.depth_mode(), MAX_FRAMES_IN_FLIGHT);
+        let reflective_shadow_map = ReflectiveShadowMap::new(env.clone(), model.clone(), &scene_buffer);
+        let mut decals = DecalRenderer::new(env.clone(), &offscreen_framebuffer, MAX_FRAMES_IN_FLIGHT);
+        decals.add_textures_from_dir(&settings.assets.decals);
+        let lights = LightManager::new(env.clone(), MAX_FRAMES_IN_FLIGHT);
 
         let quad_renderer = QuadRenderer::new(
             env.clone(),
             &offscreen_framebuffer,
             shadow_map_fb.view,
-            quad_render_pass,
-            msaa_samples,
-            dimensions);
+            point_shadow_map.cube_view,
+            god_rays.occlusion_view(),
+            lights.buffers(),
+            environment_lighting.views(),
+            reflective_shadow_map.views(),
+            hdr_framebuffer.render_pass(),
+            MAX_FRAMES_IN_FLIGHT);
+
+        let particles = ParticleSystem::new(env.clone(), hdr_framebuffer.render_pass(), &offscreen_framebuffer, MAX_FRAMES_IN_FLIGHT);
+        let transparent_renderer = TransparentRenderer::new(env.clone(), hdr_framebuffer.render_pass(), model.clone(), &scene_buffer,
+                                                            &offscreen_framebuffer, lights.buffers());
+        let debug_draw = DebugDraw::new(env.clone(), hdr_framebuffer.render_pass(), &offscreen_framebuffer, MAX_FRAMES_IN_FLIGHT);
+        let taa = TemporalAntiAliasing::new(env.clone(), &offscreen_framebuffer, &hdr_framebuffer, MAX_FRAMES_IN_FLIGHT);
+        let bloom = Bloom::new(env.clone(), &hdr_framebuffer, quad_render_pass, output_encoding, MAX_FRAMES_IN_FLIGHT);
 
         println!("created");
 
+        let images_in_flight = vec![vk::Fence::null(); swapchain_stuff.images.len()];
         let tick_counter = FPSLimiter::new();
-        HelloApplication {
+        let gpu_profiler = GpuProfiler::new(env.clone(), MAX_FRAMES_IN_FLIGHT);
+        let depth_readback = DepthReadback::new(env.clone());
+        let mut app = HelloApplication {
             env,
             shadow_map_fb,
+            point_shadow_map,
+            point_shadow_pass_draw_commands,
             final_pass_draw_command: quad_render_system,
+            compose_pass_draw_command,
             geometry_pass_draw_command: draw_mesh_render_system,
             shadowmap_pass_draw_commands,
 
             quad_renderer,
+            god_rays,
+            hi_z,
+            occlusion_culling,
+            taa,
+            decals,
+            particles,
+            transparent_renderer,
+            debug_draw,
+            bloom,
+            post_process,
+            aux_passes: AuxPasses::new(),
+            lights,
             swapchain_stuff,
+            retired_swapchain_frames: 0,
+            scene_resize_pending: false,
 
             sync,
+            images_in_flight,
             current_frame: 0,
             is_window_resized: false,
+            surface_format_lost: false,
             camera,
 
             offscreen_buffer: offscreen_framebuffer,
+            hdr_buffer: hdr_framebuffer,
 
             egui,
 
-            clear_color: [0.0, 0.0, 0.0],
             final_render_pass: quad_render_pass,
 
-            mesh,
+            assets,
+            model,
             mesh_renderer,
+            instance_field,
             mesh_shadow_map_renderers,
+            mesh_point_shadow_renderers,
+            reflective_shadow_map,
+            scene_buffer,
 
             skybox_renderer,
+            environment_lighting,
             terrain_renderer,
 
             tick_counter,
+            gpu_profiler,
             cascades,
-            cascade_split_lambda,
-            egui_current_shadowmap_cascade_image: 1,
+            frozen_cascades: None,
+            egui_normal_texture,
+            egui_cascade_textures,
+            egui_current_shadowmap_cascade_image: 0,
+
+            placement_tool: PlacementTool::new(&settings.assets.scene),
+            decal_tool: DecalTool::new(),
+            scene_revision: scene.revision,
+            scene,
+            history: History::new(),
+            modifiers: ModifiersState::empty(),
+            input_focus: InputFocus::new(),
+            time: 0.0,
+            last_frame: Instant::now(),
+            input_recorder: None,
+            input_replay: None,
+            weather: Weather::new(),
+            atmosphere,
+            exposure: Exposure::new(),
+            walkability: WalkabilitySettings::new(),
+            show_walkability: false,
+            walkable_fraction: 0.0,
+            shadow_settings: ShadowSettings::new(),
+            show_viewport: false,
+            viewport: None,
+            metrics: Metrics::new(),
+            show_metrics: false,
+            show_system_info: false,
+            show_memory: false,
+            show_shader_tweaks: false,
+            show_depth_readback: false,
+            depth_readback,
+            scene_bvh: Bvh::new(&[]),
+            show_bvh: false,
+            show_gizmos: false,
+            bvh_depth: 3,
+            clamp_to_ground: false,
+            wireframe: false,
+            depth_prepass: false,
+            anti_aliasing: AntiAliasing::Msaa,
+            msaa_samples,
+            max_msaa_samples,
+            gbuffer_view: GBufferView::Lit,
+            pipeline_warmup,
+            present_mode,
+            output_format,
+            frame_capture: None,
+            capture_every_nth: 1,
+            capture_video: true,
+            window_decorations: WindowDecorations::new(WINDOW_TITLE),
+            window_mode: WindowModeSwitch::new(wnd),
+            gamepads: Gamepads::new(),
+            settings,
+        };
+        app.update_scene_buffer();
+        app.update_walkability();
+        app.update_shadow_bias();
+
+        app
+    }
+
+    // Picks up edits made through history (gui, undo/redo) since the last frame
+    fn apply_scene_changes(&mut self) {
+        if self.scene_revision == self.scene.revision {
+            return;
+        }
+        self.scene_revision = self.scene.revision;
+
+        self.update_scene_buffer();
+    }
+
+    // Every placement writes all model parts to the scene buffer, the model at its default spot takes one slot
+    fn max_placements(&self) -> usize {
+        (MAX_SCENE_OBJECTS / self.model.parts.len().max(1)).saturating_sub(1)
+    }
+
+    // Model at its default spot plus everything placed in editor, shared by geometry and shadow passes
+    fn update_scene_buffer(&mut self) {
+        let mut transforms = vec![Matrix4::from_translation(Vector3::new(0.0, 0.01, -10.0))];
+        transforms.extend(self.scene.placements.iter().map(|p| p.transform()));
+
+        // all instances of the first model part, then of the second one and so on
+        let objects: Vec<GpuObject> = self.model.parts.iter()
+            .flat_map(|part| transforms.iter()
+                .map(move |transform| GpuObject::new(transform * part.transform, part.mesh.bounds, 0)))
+            .collect();
+        let culling_bounds: Vec<Aabb> = self.model.parts.iter()
+            .flat_map(|part| transforms.iter()
+                .map(move |transform| Aabb::from_bounds(part.mesh.bounds).transformed(&(transform * part.transform))))
+            .collect();
+
+        // one box per instance of the whole model, moving placements only refits the tree
+        let model_bounds = self.model.parts.iter()
+            .fold(Aabb::empty(), |bounds, part| bounds.union(&Aabb::from_bounds(part.mesh.bounds).transformed(&part.transform)));
+        let object_bounds: Vec<Aabb> = transforms.iter()
+            .map(|transform| model_bounds.transformed(transform))
+            .collect();
+        if object_bounds.len() == self.scene_bvh.len() {
+            self.scene_bvh.refit(&object_bounds);
+        } else {
+            self.scene_bvh = Bvh::new(&object_bounds);
+        }
+
+        unsafe {
+            self.env.device()
+                .device_wait_idle()
+                .expect("Failed to wait device idle!")
+        };
+
+        for frame in 0..MAX_FRAMES_IN_FLIGHT {
+            self.scene_buffer.write(frame, &objects);
+        }
+
+        self.transparent_renderer.set_objects(culling_bounds.clone());
+        self.mesh_renderer.set_objects(culling_bounds);
+        for renderer in self.mesh_shadow_map_renderers.iter_mut().chain(self.mesh_point_shadow_renderers.iter_mut()) {
+            renderer.set_object_count(transforms.len());
         }
+        self.reflective_shadow_map.set_object_count(transforms.len());
+    }
+
+    // Instances are written to a buffer the culling pass reads, waits for the device
+    fn update_instance_field(&mut self) {
+        unsafe {
+            self.env.device()
+                .device_wait_idle()
+                .expect("Failed to wait device idle!")
+        };
+
+        self.instance_field.update_objects();
+    }
+
+    // Depth bias is baked into the recorded shadow command buffers
+    fn update_shadow_bias(&mut self) {
+        unsafe {
+            self.env.device()
+                .device_wait_idle()
+                .expect("Failed to wait device idle!")
+        };
+
+        let (constant_factor, slope_factor) = self.shadow_settings.depth_bias();
+        for renderer in self.mesh_shadow_map_renderers.iter_mut().chain(self.mesh_point_shadow_renderers.iter_mut()) {
+            renderer.set_depth_bias(constant_factor, slope_factor);
+        }
+    }
+
+    fn load_asset(&mut self, kind: AssetKind, path: &Path) {
+        match kind {
+            // applied in apply_loaded_assets when it is ready
+            AssetKind::Model => self.assets.request_model(path),
+            AssetKind::Skybox => self.assets.request_skybox(path),
+            AssetKind::HeightMap => {
+                if let Some(height_map) = self.assets.load_height_map(path) {
+                    unsafe {
+                        self.env.device()
+                            .device_wait_idle()
+                            .expect("Failed to wait device idle!")
+                    };
+                    self.terrain_renderer.set_terrain(TerrainData::new(self.env.clone(), height_map));
+                    self.update_walkability();
+                }
+            }
+        }
+    }
+
+    // Switches mesh and terrain pipelines, waits for the device
+    fn set_wireframe(&mut self, wireframe: bool) {
+        unsafe {
+            self.env.device()
+                .device_wait_idle()
+                .expect("Failed to wait device idle!")
+        };
+
+        self.wireframe = wireframe;
+        self.mesh_renderer.set_wireframe(wireframe);
+        self.terrain_renderer.set_wireframe(wireframe);
+    }
+
+    // Switches mesh and terrain pipelines, waits for the device
+    fn set_depth_prepass(&mut self, depth_prepass: bool) {
+        unsafe {
+            self.env.device()
+                .device_wait_idle()
+                .expect("Failed to wait device idle!")
+        };
+
+        self.depth_prepass = depth_prepass;
+        self.mesh_renderer.set_depth_prepass(depth_prepass);
+        self.terrain_renderer.set_depth_prepass(depth_prepass);
+    }
+
+    // Switches terrain pipeline, waits for the device
+    fn set_terrain_tessellation(&mut self, tessellation: bool) {
+        unsafe {
+            self.env.device()
+                .device_wait_idle()
+                .expect("Failed to wait device idle!")
+        };
+
+        self.terrain_renderer.set_tessellation(tessellation);
+    }
+
+    // Waits for the device
+    fn set_anti_aliasing(&mut self, anti_aliasing: AntiAliasing) {
+        self.anti_aliasing = anti_aliasing;
+        self.recreate_gbuffer();
+    }
+
+    // Choices made at runtime are kept for the next start
+    fn save_settings(&mut self) {
+        let graphics = &mut self.settings.graphics;
+        graphics.present_mode = self.present_mode.name().to_string();
+        graphics.output_format = self.output_format.name().to_string();
+        graphics.msaa_samples = self.msaa_samples.as_raw();
+        graphics.lod_error_pixels = self.mesh_renderer.lod_error();
+
+        let assets = &mut self.settings.assets;
+        if let Some(path) = self.assets.current_path(AssetKind::Model) {
+            assets.model = path.to_path_buf();
+        }
+        if let Some(path) = self.assets.current_path(AssetKind::Skybox) {
+            assets.skybox = path.to_path_buf();
+        }
+        if let Some(path) = self.assets.current_path(AssetKind::HeightMap) {
+            assets.heightmap = path.to_path_buf();
+        }
+
+        if let Err(err) = self.settings.save(Path::new(SETTINGS_FILE)) {
+            println!("{}", err);
+        }
+    }
+
+    // Sample count of the G-buffer in MSAA mode, waits for the device
+    fn set_msaa_samples(&mut self, msaa_samples: vk::SampleCountFlags) {
+        self.msaa_samples = msaa_samples;
+        self.recreate_gbuffer();
+    }
+
+    // Recreates the G-buffer with the sample count of the anti-aliasing settings, pipelines writing and
+    // reading it and the gui pipeline showing its attachments follow. Waits for the device
+    fn recreate_gbuffer(&mut self) {
+        unsafe {
+            self.env.device()
+                .device_wait_idle()
+                .expect("Failed to wait device idle!")
+        };
+
+        let samples = match self.anti_aliasing {
+            AntiAliasing::Msaa => self.msaa_samples,
+            AntiAliasing::Fxaa | AntiAliasing::Taa => vk::SampleCountFlags::TYPE_1,
+        };
+        let gbuffer_layout = GBufferLayout::new(&self.env, samples, self.offscreen_buffer.depth_mode());
+        let mut offscreen_buffer = frame_buffer::Framebuffer::new(self.env.clone(), gbuffer_layout.attachments());
+        offscreen_buffer.set_name("G-buffer");
+        offscreen_buffer.set_memory_category(MemoryCategory::GBuffer);
+        offscreen_buffer.set_depth_mode(gbuffer_layout.depth_mode);
+        offscreen_buffer.resize_swapchain(self.offscreen_buffer.dimensions());
+        std::mem::replace(&mut self.offscreen_buffer, offscreen_buffer).destroy();
+
+        let render_pass = self.offscreen_buffer.render_pass;
+        self.mesh_renderer.set_render_pass(render_pass, samples);
+        self.instance_field.set_render_pass(render_pass, samples);
+        self.terrain_renderer.set_render_pass(render_pass, samples);
+        self.skybox_renderer.set_render_pass(render_pass, samples);
+
+        self.egui.set_msaa_samples(samples)
+            .expect("Failed to create egui pipeline!");
+
+        self.post_process.set_enabled(post_process::FXAA, self.anti_aliasing == AntiAliasing::Fxaa);
+        self.taa.set_enabled(self.anti_aliasing == AntiAliasing::Taa);
+
+        self.resize_scene(self.offscreen_buffer.dimensions());
+    }
+
+    // Re-records terrain commands, waits for the device
+    fn update_walkability(&mut self) {
+        unsafe {
+            self.env.device()
+                .device_wait_idle()
+                .expect("Failed to wait device idle!")
+        };
+
+        self.terrain_renderer.set_walkability(self.walkability.shader_params(self.show_walkability));
+        self.walkable_fraction = self.terrain_renderer.terrain().walkable_fraction(&self.walkability);
     }
 
     pub fn run(&mut self, mut event_loop: EventLoop<()>, wnd: winit::window::Window) {
@@ -250,34 +852,76 @@ impl HelloApplication {
                         return;
                     }
 
-                    if let WindowEvent::KeyboardInput { input, .. } = event {
-                        if input.virtual_keycode.is_some() && input.virtual_keycode.unwrap() == VirtualKeyCode::Escape {
-                            *control_flow = ControlFlow::Exit;
-                            return;
+                    if let WindowEvent::Resized(size) = event {
+                        self.window_mode.window_resized(size);
+                        // the next start opens with the windowed size, fullscreen ones aren't kept
+                        if self.window_mode.mode == WindowMode::Windowed {
+                            let size = self.window_mode.resolution.to_logical::<u32>(wnd.scale_factor());
+                            self.settings.window.width = size.width;
+                            self.settings.window.height = size.height;
                         }
                     }
 
-                    if let WindowEvent::Resized(_) = event {
+                    if let WindowEvent::Resized(_) | WindowEvent::ScaleFactorChanged { .. } = event {
                         self.is_window_resized = true;
+                        // gui layout follows the window even while the camera holds the mouse or input is replayed
+                        self.egui.handle_event(&event);
                     }
 
-                    if !self.egui.context().is_pointer_over_area() {
-                        let changed = self.camera.handle_event(&event);
-                        if changed {
-                            self.cascades = self.shadow_map_fb.update_cascades(&self.camera, self.cascade_split_lambda);
-                        }
+                    // live input is ignored while a recording is replayed
+                    if self.input_replay.is_some() {
+                        return;
                     }
 
-                    if !self.camera.mouse_acquired() {
-                        self.egui.handle_event(&event);
+                    if let Some(recorder) = &mut self.input_recorder {
+                        recorder.record(&event);
+                    }
+
+                    if self.handle_input(&wnd, &event) {
+                        *control_flow = ControlFlow::Exit;
                     }
                 }
-                Event::MainEventsCleared => {
+                // nothing to present to while minimized, the loop waits for the restore event
+                Event::MainEventsCleared if !is_minimized(&wnd) => {
                     wnd.request_redraw()
                 }
                 Event::RedrawRequested(_) => {
-                    self.draw_frame(&wnd);
+                    if is_minimized(&wnd) {
+                        // the swapchain follows the restored size on the first frame after
+                        self.is_window_resized = true;
+                        self.last_frame = Instant::now();
+                        return;
+                    }
+
+                    let dt = match self.input_replay.as_mut().map(InputReplay::next_frame) {
+                        Some(Some((events, dt))) => {
+                            for event in events.iter() {
+                                if self.handle_input(&wnd, event) {
+                                    *control_flow = ControlFlow::Exit;
+                                    return;
+                                }
+                            }
+                            dt
+                        }
+                        Some(None) => {
+                            println!("Input replay finished");
+                            self.input_replay = None;
+                            self.last_frame.elapsed().as_secs_f32()
+                        }
+                        None => self.last_frame.elapsed().as_secs_f32(),
+                    };
+                    self.last_frame = Instant::now();
+
+                    self.draw_frame(&wnd, dt);
                     self.tick_counter.tick_frame();
+                    if self.surface_format_lost {
+                        *control_flow = ControlFlow::Exit;
+                        return;
+                    }
+
+                    if let Some(recorder) = &mut self.input_recorder {
+                        recorder.end_frame(dt);
+                    }
                 }
                 // Important!
                 Event::LoopDestroyed => {
@@ -288,7 +932,90 @@ impl HelloApplication {
         })
     }
 
-    fn draw_frame(&mut self, wnd: &winit::window::Window) {
+    // Live or replayed window input, returns true when the app should exit
+    fn handle_input(&mut self, wnd: &winit::window::Window, event: &WindowEvent) -> bool {
+        if let WindowEvent::ModifiersChanged(modifiers) = event {
+            self.modifiers = *modifiers;
+        }
+
+        let route = self.input_focus.route(event, self.egui.wants_keyboard(), self.scene_has_pointer());
+        self.camera.set_mouse_captured(self.input_focus.is_captured());
+
+        if let (true, WindowEvent::KeyboardInput { input, .. }) = (route.shortcuts, event) {
+            if input.state == ElementState::Pressed && self.modifiers.ctrl() {
+                match input.virtual_keycode {
+                    Some(VirtualKeyCode::Z) if self.modifiers.shift() => { self.history.redo(&mut self.scene); }
+                    Some(VirtualKeyCode::Z) => { self.history.undo(&mut self.scene); }
+                    Some(VirtualKeyCode::Y) => { self.history.redo(&mut self.scene); }
+                    _ => (),
+                }
+            }
+
+            match input.virtual_keycode {
+                // the release of an Escape that ended the capture is not a quit
+                Some(VirtualKeyCode::Escape) if input.state == ElementState::Pressed => return true,
+                Some(VirtualKeyCode::F3) if input.state == ElementState::Pressed => self.set_wireframe(!self.wireframe),
+                Some(VirtualKeyCode::F11) if input.state == ElementState::Pressed => self.window_mode.toggle_fullscreen(),
+                _ => (),
+            }
+        }
+
+        if let WindowEvent::MouseInput { state: ElementState::Pressed, button: MouseButton::Right, .. } = event {
+            if (self.placement_tool.enabled || self.decal_tool.enabled) && route.camera {
+                if let Some((ray_origin, ray_dir)) = self.cursor_ray() {
+                    if let Some(hit) = self.terrain_renderer.terrain().raycast(ray_origin, ray_dir) {
+                        if self.placement_tool.enabled {
+                            let max_placements = self.max_placements();
+                            self.placement_tool.place(&hit, max_placements, &mut self.history, &mut self.scene);
+                        } else {
+                            self.decal_tool.place(&hit, &mut self.decals);
+                        }
+                    }
+                }
+            }
+        }
+
+        if route.camera {
+            self.camera.handle_event(event);
+        }
+        if route.gui {
+            self.egui.handle_event(event);
+        }
+
+        // the captured cursor goes back to the center, the camera keeps getting deltas at window edges
+        if let (true, WindowEvent::CursorMoved { position, .. }) = (self.input_focus.is_captured(), event) {
+            let center = InputFocus::lock_position(wnd);
+            if *position != center {
+                // the warp comes back as a move to the center, which then turns nothing
+                self.camera.set_cursor_position(center.cast::<i32>().into());
+                let _ = wnd.set_cursor_position(center);
+            }
+        }
+
+        false
+    }
+
+    // `dt` - frame time in seconds, recorded one when replaying input
+    fn draw_frame(&mut self, wnd: &winit::window::Window, dt: f32) {
+        self.apply_scene_changes();
+        self.apply_loaded_assets();
+        self.apply_viewport_changes();
+        self.update_window_title(wnd);
+        self.input_focus.apply(wnd);
+        if self.window_mode.apply(wnd) {
+            // resize events may come late or not at all when the size is the same
+            self.is_window_resized = true;
+        }
+
+        // replays are driven by the recorded window input only
+        let gamepad_input = if self.input_replay.is_some() { CameraInput::default() } else { self.gamepads.poll() };
+        self.camera.handle_input(&gamepad_input);
+
+        let old_position = self.camera.position();
+        self.camera.update(dt);
+        self.collide_camera(old_position);
+        self.clamp_camera_to_ground();
+
         let wait_fences = [self.sync.inflight_fences[self.current_frame]];
 
         let (image_index, _is_sub_optimal) = unsafe {
@@ -296,6 +1023,24 @@ impl HelloApplication {
                 .wait_for_fences(&wait_fences, true, u64::MAX)
                 .expect("Failed to wait for Fence!");
 
+            // frames presented from a retired swapchain are finished once every frame fence was waited
+            if self.retired_swapchain_frames > 0 {
+                self.retired_swapchain_frames -= 1;
+                if self.retired_swapchain_frames == 0 {
+                    self.swapchain_stuff.destroy_retired();
+                }
+            }
+
+            // no frame is drawn until then, so none is left rendering to the old scene targets
+            if self.scene_resize_pending {
+                if self.retired_swapchain_frames > 0 {
+                    self.current_frame = (self.current_frame + 1) % MAX_FRAMES_IN_FLIGHT;
+                    return;
+                }
+                self.scene_resize_pending = false;
+                self.resize_scene(self.scene_dimensions());
+            }
+
             let result = self.swapchain_stuff.swapchain_api
                 .acquire_next_image(
                     self.swapchain_stuff.swapchain,
@@ -314,15 +1059,31 @@ impl HelloApplication {
                 },
             }
         };
+
+        let image_fence = self.images_in_flight[image_index as usize];
+        if image_fence != vk::Fence::null() && image_fence != wait_fences[0] {
+            unsafe {
+                self.env.device()
+                    .wait_for_fences(&[image_fence], true, u64::MAX)
+                    .expect("Failed to wait for Fence!");
+            }
+        }
+        self.images_in_flight[image_index as usize] = wait_fences[0];
+        self.tick_counter.begin_cpu_work();
+        self.gpu_profiler.begin_frame(self.current_frame);
+        self.record_metrics();
+        let frame_start_mark = self.gpu_profiler.mark("frame start");
+
         let wait_semaphores = [self.sync.image_available_semaphores[self.current_frame]];
         let wait_stages = [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
         let first_pass_finished = [self.sync.render_finished_semaphores[self.current_frame]];
-        let second_pass_finished = [self.sync.render_quad_semaphore];
+        let second_pass_finished = [self.sync.render_quad_semaphores[self.current_frame]];
 
         let clear_values = vec![
             vk::ClearValue {
                 color: vk::ClearColorValue {
-                    float32: [self.clear_color[0], self.clear_color[1], self.clear_color[2], 1.0],
+                    // RGBM alpha, unlit pixels are decoded as the sky in compose.frag
+                    float32: [self.scene.clear_color[0], self.scene.clear_color[1], self.scene.clear_color[2], 1.0 / SKY_RGBM_RANGE],
                 },
             },
             vk::ClearValue {
@@ -332,15 +1093,16 @@ impl HelloApplication {
             },
             vk::ClearValue {
                 color: vk::ClearColorValue {
-                    float32: [0.0, 0.0, 0.0, 1.0],
+                    float32: [0.0, 0.0, 0.0, 0.0],
                 },
             },
+            // no motion
             vk::ClearValue {
-                depth_stencil: vk::ClearDepthStencilValue {
-                    depth: 1.0,
-                    stencil: 0,
-                }
+                color: vk::ClearColorValue {
+                    float32: [0.0, 0.0, 0.0, 0.0],
+                },
             },
+            self.offscreen_buffer.depth_clear_value(),
         ];
 
         let shadow_map_clear = vec![vk::ClearValue {
@@ -350,9 +1112,18 @@ impl HelloApplication {
             }
         }];
 
+        let jitter = self.taa.next_jitter(self.offscreen_buffer.dimensions());
+        self.camera.set_jitter(jitter);
+        // every pass of the frame works with this snapshot
+        let camera = self.camera.descriptor();
+        self.camera.end_frame();
+        let sun_direction = self.atmosphere.sun_direction();
+        self.cascades = self.shadow_map_fb.update_cascades(&camera, self.scene.cascade_split_lambda, sun_direction);
+        self.draw_cascade_frusta();
+
         let mut cascade_draws = Vec::new();
         for (cascade_idx, cascade) in self.cascades.iter().enumerate() {
-            let mesh_shadowmap_draw = self.mesh_shadow_map_renderers[cascade_idx].draw(&self.camera, cascade.view_proj_mat);
+            let mesh_shadowmap_draw = self.mesh_shadow_map_renderers[cascade_idx].draw(cascade.view_proj_mat);
 
             cascade_draws.push(
                 self.shadowmap_pass_draw_commands[cascade_idx].execute_secondary(
@@ -364,39 +1135,154 @@ impl HelloApplication {
             );
         }
 
-        self.quad_renderer.write_shadowmap_ubo(self.camera.view_matrix(), &self.cascades);
+        let point_light = self.point_light();
+        for (face_idx, face_view_proj) in point_light.face_view_proj().into_iter().enumerate() {
+            let mesh_shadowmap_draw = self.mesh_point_shadow_renderers[face_idx].draw(face_view_proj);
+
+            cascade_draws.push(
+                self.point_shadow_pass_draw_commands[face_idx].execute_secondary(
+                    shadow_map_clear.clone(),
+                    self.point_shadow_map.framebuffer(face_idx),
+                    self.point_shadow_map.render_pass(),
+                    &[mesh_shadowmap_draw],
+                )
+            );
+        }
 
-        let mesh_draw = self.mesh_renderer.draw(self.camera.view_matrix(), self.camera.proj_matrix());
-        let terrain_draw = self.terrain_renderer.draw(self.camera.view_matrix(), self.camera.proj_matrix());
-        let skybox_draw = self.skybox_renderer.draw(self.camera.skybox_view_matrix(), self.camera.proj_matrix());
+        self.time += dt;
+        let time = self.time;
+        self.quad_renderer.set_water(self.scene.water_level, self.scene.caustics_strength, time);
+        self.weather.update(dt);
+        self.quad_renderer.set_weather(self.weather.rain(), self.weather.wetness());
+        self.exposure.update(self.estimate_scene_luminance(), dt);
+        self.quad_renderer.set_exposure(self.exposure.exposure());
+        self.quad_renderer.set_cascade_blend_band(self.scene.cascade_blend_band);
+        self.quad_renderer.set_pcf(self.shadow_settings.pcf_params());
+        self.quad_renderer.set_show_cascades(self.shadow_settings.show_cascades);
+        self.quad_renderer.set_sky_occlusion(self.scene.sky_occlusion);
+        self.quad_renderer.set_environment_intensity(self.scene.environment_intensity);
+        self.quad_renderer.set_gbuffer_view(self.gbuffer_view);
+        self.quad_renderer.set_sun_direction(sun_direction);
+        self.quad_renderer.set_fog(self.atmosphere.fog_params());
+        let god_rays = self.god_rays.update(self.current_frame, &camera, sun_direction);
+        self.quad_renderer.set_god_rays(god_rays);
+        let (rsm_view_proj, indirect_params) = self.reflective_shadow_map.update(self.current_frame, &camera, sun_direction);
+        self.quad_renderer.set_indirect_light(rsm_view_proj, indirect_params);
+        self.lights.upload(self.current_frame);
+        self.quad_renderer.write_shadowmap_ubo(self.current_frame, &camera, &self.cascades, &point_light);
+        let particles_update = self.particles.update(self.current_frame, dt, &camera, self.exposure.exposure());
+
+        let mesh_draw = self.mesh_renderer.draw(&camera);
+        let (mesh_draw_buffer, mesh_draw_count) = self.mesh_renderer.indirect_draws();
+        let occlusion_cull = self.occlusion_culling.cull(self.current_frame, mesh_draw_buffer, mesh_draw_count,
+                                                         self.mesh_renderer.object_bounds(), &self.hi_z);
+        let terrain_draw = self.terrain_renderer.draw(&camera);
+        let skybox_draw = self.skybox_renderer.draw(&camera);
+
+        // all depth goes first, then every G-buffer draw tests against it
+        let mut geometry_draws: Vec<_> = self.mesh_renderer.depth_prepass_draw().into_iter()
+            .chain(self.terrain_renderer.depth_prepass_draw())
+            .collect();
+        geometry_draws.extend([terrain_draw, mesh_draw, skybox_draw]);
+        let instance_field_cull = if self.instance_field.enabled && self.instance_field.supported() {
+            geometry_draws.push(self.instance_field.draw(self.current_frame));
+            Some(self.instance_field.cull(self.current_frame, &camera))
+        } else {
+            None
+        };
 
         let geometry_pass_cmd = self.geometry_pass_draw_command.execute_secondary(
             clear_values,
             self.offscreen_buffer.framebuffer.unwrap(),
             self.offscreen_buffer.render_pass,
-            &[terrain_draw, mesh_draw, skybox_draw]);
+            &geometry_draws);
 
         self.egui.begin_frame();
+        self.exposure.apply_theme(&self.egui.context());
         self.render_gui();
         let gui_render_op = self.egui.end_frame(wnd);
 
         let clear_values = vec![
             vk::ClearValue {
                 color: vk::ClearColorValue {
-                    float32: [self.clear_color[0], self.clear_color[1], self.clear_color[2], 1.0],
+                    float32: [self.scene.clear_color[0], self.scene.clear_color[1], self.scene.clear_color[2], 1.0],
                 },
             },
         ];
 
-        let quad_cmd_buf = self.final_pass_draw_command.execute_secondary(
+        let shadow_mark = self.gpu_profiler.mark("shadow");
+        let geometry_mark = self.gpu_profiler.mark("geometry");
+        let composite_mark = self.gpu_profiler.mark_in_pass("composite", self.quad_renderer.render_pass);
+        // gui is done, nothing queues lines after this point
+        let debug_draw = self.debug_draw.flush(&camera);
+        let transparent_draw = self.transparent_renderer.draw(
+            self.current_frame, &camera, self.exposure.exposure(), sun_direction, self.atmosphere.fog_params(),
+        );
+
+        let compose_cmd_buf = self.compose_pass_draw_command.execute_secondary(
+            clear_values.clone(),
+            self.hdr_buffer.framebuffer.unwrap(),
+            self.hdr_buffer.render_pass(),
+            &[self.quad_renderer.second_buffer(self.current_frame), transparent_draw, self.particles.draw(self.current_frame), debug_draw, composite_mark],
+        );
+
+        let taa_draws = self.taa.draw(self.current_frame, &self.hdr_buffer);
+        self.bloom.update(self.current_frame);
+        let bloom_draws = self.bloom.draw(self.current_frame);
+        let bloom_mark = self.gpu_profiler.mark("bloom");
+        let (post_process_draws, scene_buffers) = self.post_process.draw(self.current_frame, &[self.bloom.composite_buffer(self.current_frame)]);
+
+        // in the editor viewport the scene goes to the viewport image, the window gets only the gui
+        let mut final_pass = vec![];
+        let final_buffers = match &mut self.viewport {
+            Some(viewport) => {
+                final_pass.push(viewport.draw(clear_values.clone(), &scene_buffers));
+                vec![gui_render_op]
+            }
+            None => {
+                let mut buffers = scene_buffers;
+                buffers.push(gui_render_op);
+                buffers
+            }
+        };
+        final_pass.push(self.final_pass_draw_command.execute_secondary(
             clear_values,
             self.swapchain_stuff.framebuffers[image_index as usize],
-            self.quad_renderer.render_pass,
-            &[self.quad_renderer.second_buffer, gui_render_op],
-        );
+            self.final_render_pass,
+            &final_buffers,
+        ));
+        let egui_mark = self.gpu_profiler.mark("egui");
 
-        let mrt_pass: Vec<vk::CommandBuffer> = cascade_draws.iter().chain(vec!(geometry_pass_cmd).iter()).copied().collect();
-        let composite_pass = [quad_cmd_buf];
+        let mut mrt_pass = vec![frame_start_mark];
+        mrt_pass.extend(cascade_draws);
+        if indirect_params[0] > 0.0 {
+            mrt_pass.push(self.reflective_shadow_map.draw(self.current_frame));
+        }
+        mrt_pass.push(shadow_mark);
+        mrt_pass.extend(self.aux_passes.draw(EnginePass::Shadow));
+        mrt_pass.extend(instance_field_cull);
+        mrt_pass.extend(occlusion_cull);
+        mrt_pass.extend(&[geometry_pass_cmd, geometry_mark]);
+        // tested against by the next frame
+        mrt_pass.push(self.hi_z.build(self.current_frame, camera.view_proj));
+        mrt_pass.push(particles_update);
+        mrt_pass.extend(self.decals.draw(self.current_frame, &camera));
+        if god_rays[2] > 0.0 {
+            mrt_pass.push(self.god_rays.draw(self.current_frame));
+        }
+        mrt_pass.extend(self.aux_passes.draw(EnginePass::Geometry));
+        let mut composite_pass = vec![compose_cmd_buf];
+        composite_pass.extend(taa_draws);
+        composite_pass.extend(self.aux_passes.draw(EnginePass::Composite));
+        composite_pass.extend(bloom_draws);
+        composite_pass.push(bloom_mark);
+        composite_pass.extend(self.aux_passes.draw(EnginePass::Bloom));
+        composite_pass.extend(post_process_draws);
+        composite_pass.extend(final_pass);
+        composite_pass.push(egui_mark);
+        if let Some(capture) = &mut self.frame_capture {
+            composite_pass.extend(capture.record(self.swapchain_stuff.images[image_index as usize]));
+        }
 
         let submit_infos = [
             vk::SubmitInfo {
@@ -416,7 +1302,7 @@ impl HelloApplication {
                 wait_semaphore_count: first_pass_finished.len() as u32,
                 p_wait_semaphores: first_pass_finished.as_ptr(),
                 p_wait_dst_stage_mask: wait_stages.as_ptr(),
-                command_buffer_count: 1,
+                command_buffer_count: composite_pass.len() as u32,
                 p_command_buffers: composite_pass.as_ptr(),
                 signal_semaphore_count: second_pass_finished.len() as u32,
                 p_signal_semaphores: second_pass_finished.as_ptr(),
@@ -427,15 +1313,19 @@ impl HelloApplication {
             self.env.device()
                 .reset_fences(&wait_fences)
                 .expect("Failed to reset Fence!");
+        }
 
-            self.env.device()
-                .queue_submit(
-                    self.env.queue(),
-                    &submit_infos,
-                    self.sync.inflight_fences[self.current_frame],
-                )
-                .expect("Failed to execute queue submit.");
+        self.env.queue()
+            .submit(
+                self.env.device(),
+                &submit_infos,
+                self.sync.inflight_fences[self.current_frame],
+            )
+            .expect("Failed to execute queue submit.");
+        if let Some(capture) = &mut self.frame_capture {
+            capture.submit(self.env.queue());
         }
+        self.tick_counter.end_cpu_work();
         let swapchains = [self.swapchain_stuff.swapchain];
 
         let present_info = vk::PresentInfoKHR {
@@ -449,10 +1339,8 @@ impl HelloApplication {
             p_results: ptr::null_mut(),
         };
 
-        let result = unsafe {
-            self.swapchain_stuff.swapchain_api
-                .queue_present(self.env.queue(), &present_info)
-        };
+        let result = self.env.present_queue()
+            .present(&self.swapchain_stuff.swapchain_api, &present_info);
 
         let is_resized = match result {
             Ok(_) => self.is_window_resized,
@@ -462,22 +1350,122 @@ impl HelloApplication {
             }
         };
 
-        if is_resized {
-            self.recreate_swapchain(wnd);
+        if is_resized || self.present_mode != self.swapchain_stuff.present_mode {
             self.is_window_resized = false;
+            self.recreate_swapchain(wnd);
+        }
+
+        self.current_frame = (self.current_frame + 1) % MAX_FRAMES_IN_FLIGHT;
+    }
+
+    // Samples of the last finished frame, GPU timings are read back by begin_frame()
+    fn record_metrics(&mut self) {
+        self.metrics.record("Frame", "ms", self.tick_counter.delta_time() as f64 * 1000.0);
+
+        let mut gpu_total = 0.0;
+        for &(name, ms) in self.gpu_profiler.timings() {
+            self.metrics.record(&format!("GPU {}", name), "ms", ms as f64);
+            gpu_total += ms as f64;
+        }
+        if self.gpu_profiler.is_supported() {
+            self.metrics.record("GPU total", "ms", gpu_total);
+            self.tick_counter.set_gpu_time(gpu_total as f32);
         }
 
-        // FIXME: если раскомментировать - то теневые карты начнут дрожжать при передвижении
-        // self.current_frame = (self.current_frame + 1) % MAX_FRAMES_IN_FLIGHT;
+        // scene draws, full-screen passes and gui are not counted
+        let shadow_draws: usize = self.mesh_shadow_map_renderers.iter()
+            .chain(self.mesh_point_shadow_renderers.iter())
+            .map(|renderer| renderer.draw_call_count())
+            .sum::<usize>() + self.reflective_shadow_map.draw_call_count();
+        let draw_calls = shadow_draws + self.mesh_renderer.draw_call_count() + self.instance_field.draw_call_count()
+            + self.terrain_renderer.draw_call_count() + 1;
+        self.metrics.record("Draw calls", "count", draw_calls as f64);
+
+        if let Some(bytes) = self.env.device_memory_usage() {
+            self.metrics.record("VRAM", "MB", bytes as f64 / (1024.0 * 1024.0));
+        }
+    }
+
+    // Rough average of what compose pass outputs: sky around, sun and ambient on a mid gray surface
+    fn estimate_scene_luminance(&self) -> f32 {
+        let [r, g, b] = self.scene.clear_color;
+        let sky = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+        let sun = 0.75 * (1.0 - 0.6 * self.weather.rain());
+        let surface = 0.3 * (0.4 + sun) * (1.0 - 0.4 * self.weather.wetness());
+
+        0.5 * sky + 0.5 * surface
+    }
+
+    fn point_light(&self) -> PointLight {
+        let [x, y, z] = self.scene.point_light_position;
+        PointLight {
+            position: Point3::new(x, y, z),
+            near: 0.05,
+            radius: self.scene.point_light_radius,
+            intensity: self.scene.point_light_intensity,
+        }
     }
 
     fn render_gui(&mut self) {
+        let mut picked_asset = None;
+        let mut walkability_changed = false;
+        let mut wireframe = self.wireframe;
+        let mut depth_prepass = self.depth_prepass;
+        let mut terrain_tessellation = self.terrain_renderer.tessellation();
+        let mut anti_aliasing = self.anti_aliasing;
+        let mut msaa_samples = self.msaa_samples;
+
         egui::SidePanel::left("my_side_panel").show(&self.egui.context(), |ui| {
             ui.heading("Hello");
+            ui.checkbox(&mut self.show_viewport, "Editor viewport");
+            ui.checkbox(&mut self.show_metrics, "Metrics");
+            ui.checkbox(&mut self.show_system_info, "System info");
+            ui.checkbox(&mut self.show_memory, "Memory");
+            ui.checkbox(&mut self.show_shader_tweaks, "Shader tweaks");
+            ui.checkbox(&mut self.show_depth_readback, "Depth readback");
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut self.show_bvh, "Scene BVH");
+                ui.add(egui::DragValue::new(&mut self.bvh_depth).prefix("depth: ").clamp_range(RangeInclusive::new(0, 16)));
+            });
+            ui.checkbox(&mut self.show_gizmos, "Light and emitter gizmos");
+            ui.label(format!("Debug lines: {} ({} dropped)", self.debug_draw.line_count(), self.debug_draw.dropped_count()));
+            ui.checkbox(&mut wireframe, "Wireframe (F3)");
+            ui.checkbox(&mut depth_prepass, "Depth pre-pass");
+            if self.terrain_renderer.tessellation_supported() {
+                ui.checkbox(&mut terrain_tessellation, "Tessellated terrain");
+            } else {
+                ui.label("Tessellated terrain: not supported by the device");
+            }
+            ui.horizontal(|ui| {
+                ui.label("Anti-aliasing:");
+                ui.radio_value(&mut anti_aliasing, AntiAliasing::Msaa, format!("MSAA {}x", self.msaa_samples.as_raw()));
+                ui.radio_value(&mut anti_aliasing, AntiAliasing::Fxaa, "FXAA");
+                ui.radio_value(&mut anti_aliasing, AntiAliasing::Taa, "TAA");
+            });
+            if self.anti_aliasing == AntiAliasing::Taa {
+                self.taa.ui(ui);
+            }
+            egui::ComboBox::from_label("MSAA samples")
+                .selected_text(format!("{}x", msaa_samples.as_raw()))
+                .show_ui(ui, |ui| {
+                    for samples in MSAA_SAMPLE_COUNTS.iter().filter(|s| s.as_raw() <= self.max_msaa_samples.as_raw()) {
+                        ui.selectable_value(&mut msaa_samples, *samples, format!("{}x", samples.as_raw()));
+                    }
+                });
+            egui::ComboBox::from_label("G-buffer view")
+                .selected_text(format!("{:?}", self.gbuffer_view))
+                .show_ui(ui, |ui| {
+                    for view in GBufferView::ALL.iter() {
+                        ui.selectable_value(&mut self.gbuffer_view, *view, format!("{:?}", view));
+                    }
+                });
             ui.separator();
 
             // let mut rgb: [f32; 3] = [0.0, 0.0, 0.0];
-            ui.color_edit_button_rgb(&mut self.clear_color);
+            let mut clear_color = self.scene.clear_color;
+            if ui.color_edit_button_rgb(&mut clear_color).changed() {
+                self.history.execute(Box::new(SetValue::new("clear color", |scene| &mut scene.clear_color, clear_color)), &mut self.scene);
+            }
 
             ui.separator();
 
@@ -487,51 +1475,730 @@ impl HelloApplication {
 
             ui.label(format!("X: {:.2}, Y: {:.2}, Z: {:.2}", view_dir.x, view_dir.y, view_dir.z));
             ui.label(format!("FPS: {:.2}", self.tick_counter.fps()));
+            egui::CollapsingHeader::new("Frame timing").show(ui, |ui| {
+                self.tick_counter.ui(ui);
+            });
+            ui.horizontal(|ui| {
+                ui.label("Present mode:");
+                for mode in PresentMode::ALL.iter().copied() {
+                    ui.selectable_value(&mut self.present_mode, mode, mode.name());
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Output:");
+                for format in OutputFormat::ALL.iter().copied() {
+                    ui.selectable_value(&mut self.output_format, format, format.name());
+                }
+            });
+            if self.output_format != self.swapchain_stuff.requested_output {
+                ui.label("Output format is applied on restart");
+            } else if self.swapchain_stuff.output_format != Some(self.output_format) {
+                let fallback = self.swapchain_stuff.output_format.map_or("surface default", |format| format.name());
+                ui.label(format!("Not supported by the monitor, using {}", fallback));
+            }
+            ui.horizontal(|ui| {
+                ui.label("Window (F11):");
+                for mode in WindowMode::ALL.iter().copied() {
+                    ui.selectable_value(&mut self.window_mode.mode, mode, mode.name());
+                }
+            });
+            if self.window_mode.mode != WindowMode::Borderless {
+                let resolutions = self.window_mode.resolutions().to_vec();
+                let resolution = &mut self.window_mode.resolution;
+                egui::ComboBox::from_label("Resolution")
+                    .selected_text(format!("{}x{}", resolution.width, resolution.height))
+                    .show_ui(ui, |ui| {
+                        for size in resolutions {
+                            ui.selectable_value(resolution, size, format!("{}x{}", size.width, size.height));
+                        }
+                    });
+            }
+            ui.label(format!("Scene objects: {}", self.scene_buffer.object_count(self.current_frame)));
+            ui.label(format!("Terrain triangles: {}", self.terrain_renderer.triangle_count()));
+            let (mesh_cull, terrain_cull) = (self.mesh_renderer.cull_stats(), self.terrain_renderer.cull_stats());
+            ui.label(format!("Culled: {} of {} meshes, {} of {} terrain chunks", mesh_cull.culled, mesh_cull.total, terrain_cull.culled, terrain_cull.total));
+            ui.checkbox(&mut self.occlusion_culling.enabled, "Mesh occlusion culling");
+            let occlusion = self.occlusion_culling.stats();
+            ui.label(format!("Occluded: {} of {} meshes", occlusion.culled, occlusion.total));
+            let mut lod_error = self.mesh_renderer.lod_error();
+            if ui.add(egui::Slider::new(&mut lod_error, 0.0..=8.0).text("Mesh LOD error, px")).changed() {
+                self.mesh_renderer.set_lod_error(lod_error);
+            }
+            let lod_stats = self.mesh_renderer.lod_stats();
+            ui.label(format!("Mesh triangles: {} of {}", lod_stats.triangles, lod_stats.full_triangles));
+            ui.label(format!("Render passes: {}", self.env.render_passes().len()));
+            egui::CollapsingHeader::new("Exposure").show(ui, |ui| self.exposure.ui(ui));
+            egui::CollapsingHeader::new("Camera").show(ui, |ui| {
+                let settings = &mut self.camera.settings;
+                ui.add(egui::Slider::new(&mut settings.speed, 0.5..=50.0).logarithmic(true).suffix(" m/s").text("Speed"));
+                ui.add(egui::Slider::new(&mut settings.acceleration, 1.0..=200.0).logarithmic(true).suffix(" m/s²").text("Acceleration"));
+                ui.add(egui::Slider::new(&mut settings.mouse_sensitivity, 0.05..=2.0).suffix("°/px").text("Mouse sensitivity"));
+                ui.add(egui::Slider::new(&mut settings.smoothing, 0.0..=0.5).suffix(" s").text("Look smoothing"));
+                ui.add(egui::Slider::new(&mut settings.stick_look_speed, 10.0..=360.0).suffix("°/s").text("Stick look speed"));
+                if ui.button("Reset").clicked() {
+                    *settings = CameraSettings::default();
+                }
+                ui.checkbox(&mut self.clamp_to_ground, "Clamp to ground");
+                ui.label(format!("Gamepad: {}", self.gamepads.active_name().unwrap_or_else(|| "-".to_string())));
+
+                let position = self.camera.position();
+                let terrain = self.terrain_renderer.terrain();
+                // heights and normals go up along -Y
+                ui.label(match terrain.height_at(position.x, position.z).zip(terrain.normal_at(position.x, position.z)) {
+                    Some((height, normal)) => format!("Ground: height {:.2}, slope {:.0}°", -height, (-normal.y).min(1.0).acos().to_degrees()),
+                    None => "Ground: -".to_string(),
+                });
+            });
 
+            egui::CollapsingHeader::new("GPU time").default_open(true).show(ui, |ui| {
+                if !self.gpu_profiler.is_supported() {
+                    ui.label("Timestamps are not supported");
+                }
+                let mut total = 0.0;
+                for (name, ms) in self.gpu_profiler.timings() {
+                    ui.label(format!("{}: {:.3} ms", name, ms));
+                    total += ms;
+                }
+                ui.label(format!("Total: {:.3} ms", total));
+            });
+
+            let cursor_hit = self.cursor_ray()
+                .and_then(|(ray_origin, ray_dir)| self.terrain_renderer.terrain().raycast(ray_origin, ray_dir));
+            match cursor_hit {
+                Some(hit) => ui.label(format!("Terrain: X: {:.2}, Y: {:.2}, Z: {:.2}", hit.position.x, hit.position.y, hit.position.z)),
+                None => ui.label("Terrain: -"),
+            };
+
+            let object_hit = self.cursor_ray()
+                .and_then(|(ray_origin, ray_dir)| self.scene_bvh.raycast(ray_origin, ray_dir, f32::MAX));
+            match object_hit {
+                Some((object, distance)) => ui.label(format!("Object: #{} at {:.2}", object, distance)),
+                None => ui.label("Object: -"),
+            };
+
+            let cascade_count = self.egui_cascade_textures.len();
             egui::ComboBox::from_label("Shadow map data")
-                .selected_text(format!("{}", self.egui_current_shadowmap_cascade_image))
+                .selected_text(format!("{}", self.egui_current_shadowmap_cascade_image + 1))
                 .show_ui(ui, |ui| {
-                    ui.selectable_value(&mut self.egui_current_shadowmap_cascade_image, 1, "1");
-                    ui.selectable_value(&mut self.egui_current_shadowmap_cascade_image, 2, "2");
-                    ui.selectable_value(&mut self.egui_current_shadowmap_cascade_image, 3, "3");
-                    ui.selectable_value(&mut self.egui_current_shadowmap_cascade_image, 4, "4");
+                    for cascade_idx in 0..cascade_count {
+                        ui.selectable_value(&mut self.egui_current_shadowmap_cascade_image, cascade_idx, format!("{}", cascade_idx + 1));
+                    }
                 });
-            ui.image(egui::TextureId::User(self.egui_current_shadowmap_cascade_image as u64), [200.0, 200.0]);
+            ui.image(self.egui_cascade_textures[self.egui_current_shadowmap_cascade_image], [200.0, 200.0]);
+
+            let mut cascade_split_lambda = self.scene.cascade_split_lambda;
+            let resp = ui.add(egui::DragValue::new(&mut cascade_split_lambda).speed(0.01).clamp_range(RangeInclusive::new(0.1, 1.0)));
+            if resp.changed() {
+                self.history.execute(Box::new(SetValue::new("cascade split", |scene| &mut scene.cascade_split_lambda, cascade_split_lambda)), &mut self.scene);
+            }
 
-            let resp = ui.add(egui::DragValue::new(&mut self.cascade_split_lambda).speed(0.01).clamp_range(RangeInclusive::new(0.1, 1.0)));
+            let mut cascade_blend_band = self.scene.cascade_blend_band;
+            let resp = ui.add(egui::DragValue::new(&mut cascade_blend_band).speed(0.02).clamp_range(RangeInclusive::new(0.0, 5.0)).prefix("Cascade blend: "));
             if resp.changed() {
-                self.cascades = self.shadow_map_fb.update_cascades(&self.camera, self.cascade_split_lambda);
+                self.history.execute(Box::new(SetValue::new("cascade blend", |scene| &mut scene.cascade_blend_band, cascade_blend_band)), &mut self.scene);
+            }
+
+            egui::CollapsingHeader::new("Capture").show(ui, |ui| {
+                match &self.frame_capture {
+                    Some(capture) => {
+                        let stats = capture.stats();
+                        ui.label(format!("Captured: {}, written: {}, dropped: {}", stats.captured, stats.written, stats.dropped));
+                        if ui.button("Stop").clicked() {
+                            self.stop_capture();
+                        }
+                    }
+                    None => {
+                        ui.add(egui::Slider::new(&mut self.capture_every_nth, 1..=10).text("Every Nth frame"));
+                        ui.checkbox(&mut self.capture_video, "Video (y4m), PNG frames otherwise");
+                        if ui.button("Start").clicked() {
+                            self.start_capture();
+                        }
+                    }
+                }
+            });
+
+            let mut instances_changed = false;
+            egui::CollapsingHeader::new("Instance field (GPU culling)").default_open(false).show(ui, |ui| {
+                instances_changed = self.instance_field.ui(ui);
+            });
+            if instances_changed {
+                self.update_instance_field();
             }
+
+            let mut bias_changed = false;
+            egui::CollapsingHeader::new("Shadow filtering").default_open(false).show(ui, |ui| {
+                bias_changed = self.shadow_settings.ui(ui);
+            });
+            if bias_changed {
+                self.update_shadow_bias();
+            }
+
+            ui.separator();
+            ui.label("Point light");
+            let mut position = self.scene.point_light_position;
+            let resp = ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut position[0]).speed(0.05).prefix("X: ")).changed()
+                    | ui.add(egui::DragValue::new(&mut position[1]).speed(0.05).prefix("Y: ")).changed()
+                    | ui.add(egui::DragValue::new(&mut position[2]).speed(0.05).prefix("Z: ")).changed()
+            });
+            if resp.inner {
+                self.history.execute(Box::new(SetValue::new("point light position", |scene| &mut scene.point_light_position, position)), &mut self.scene);
+            }
+
+            let mut radius = self.scene.point_light_radius;
+            if ui.add(egui::DragValue::new(&mut radius).speed(0.05).clamp_range(RangeInclusive::new(0.5, 50.0)).prefix("Radius: ")).changed() {
+                self.history.execute(Box::new(SetValue::new("point light radius", |scene| &mut scene.point_light_radius, radius)), &mut self.scene);
+            }
+
+            let mut intensity = self.scene.point_light_intensity;
+            if ui.add(egui::DragValue::new(&mut intensity).speed(0.05).clamp_range(RangeInclusive::new(0.0, 20.0)).prefix("Intensity: ")).changed() {
+                self.history.execute(Box::new(SetValue::new("point light intensity", |scene| &mut scene.point_light_intensity, intensity)), &mut self.scene);
+            }
+            // shadowed light keeps raw units and its linear falloff, shown as what it gives at 1 m
+            let falloff = (1.0 - 1.0 / self.scene.point_light_radius).max(0.0);
+            ui.label(format!("{:.0} lux at 1 m", light_units::raw_to_lux(intensity * falloff * falloff)));
+
+            ui.separator();
+            ui.label("Water");
+            let mut water_level = self.scene.water_level;
+            if ui.add(egui::DragValue::new(&mut water_level).speed(0.02).clamp_range(RangeInclusive::new(0.0, 4.0)).prefix("Level: ")).changed() {
+                self.history.execute(Box::new(SetValue::new("water level", |scene| &mut scene.water_level, water_level)), &mut self.scene);
+            }
+
+            let mut caustics_strength = self.scene.caustics_strength;
+            if ui.add(egui::Slider::new(&mut caustics_strength, 0.0..=2.0).text("Caustics")).changed() {
+                self.history.execute(Box::new(SetValue::new("caustics strength", |scene| &mut scene.caustics_strength, caustics_strength)), &mut self.scene);
+            }
+
+            ui.separator();
+            let mut sky_occlusion = self.scene.sky_occlusion;
+            if ui.add(egui::Slider::new(&mut sky_occlusion, 0.0..=1.0).text("Terrain sky occlusion")).changed() {
+                self.history.execute(Box::new(SetValue::new("sky occlusion", |scene| &mut scene.sky_occlusion, sky_occlusion)), &mut self.scene);
+            }
+
+            let mut environment_intensity = self.scene.environment_intensity;
+            if ui.add(egui::Slider::new(&mut environment_intensity, 0.0..=4.0).text("Environment light")).changed() {
+                self.history.execute(Box::new(SetValue::new("environment light", |scene| &mut scene.environment_intensity, environment_intensity)), &mut self.scene);
+            }
+
+            ui.separator();
+            self.weather.ui(ui);
+            egui::CollapsingHeader::new("Sun and fog").show(ui, |ui| {
+                self.atmosphere.ui(ui);
+            });
+            self.god_rays.ui(ui);
+            egui::CollapsingHeader::new("Global illumination").show(ui, |ui| {
+                self.reflective_shadow_map.ui(ui);
+            });
+            self.bloom.ui(ui);
+            egui::CollapsingHeader::new("Post processing").show(ui, |ui| {
+                self.post_process.ui(ui);
+            });
+
+            ui.separator();
+            self.particles.ui(ui);
+            egui::CollapsingHeader::new("Transparency").show(ui, |ui| {
+                self.transparent_renderer.ui(ui);
+            });
+
+            ui.separator();
+            egui::CollapsingHeader::new("Lights").show(ui, |ui| {
+                self.lights.ui(ui);
+            });
+
+            ui.separator();
+            let max_placements = self.max_placements();
+            self.placement_tool.ui(ui, max_placements, &mut self.history, &mut self.scene);
+
+            ui.separator();
+            egui::CollapsingHeader::new("Decals").show(ui, |ui| {
+                self.decal_tool.ui(ui, &mut self.decals);
+            });
+
+            ui.separator();
+            self.history.ui(ui, &mut self.scene);
+
+            ui.separator();
+            egui::CollapsingHeader::new("Assets").show(ui, |ui| {
+                picked_asset = self.assets.ui(ui);
+            });
+
+            egui::CollapsingHeader::new("Walkability").show(ui, |ui| {
+                let terrain_height = self.terrain_renderer.terrain().scale.vertical;
+                walkability_changed = self.walkability.ui(ui, &mut self.show_walkability, terrain_height);
+
+                let under_cursor = self.cursor_ray()
+                    .and_then(|(ray_origin, ray_dir)| self.terrain_renderer.terrain().raycast(ray_origin, ray_dir))
+                    .and_then(|hit| self.terrain_renderer.terrain().is_walkable(hit.position.x, hit.position.z, &self.walkability));
+                ui.label(match under_cursor {
+                    Some(true) => "Under cursor: walkable",
+                    Some(false) => "Under cursor: blocked",
+                    None => "Under cursor: -",
+                });
+                ui.label(format!("Walkable: {:.1}%", self.walkable_fraction * 100.0));
+            });
         });
+
+        if let Some(viewport) = &mut self.viewport {
+            egui::Window::new("Viewport")
+                .resizable(true)
+                .default_size([480.0, 320.0])
+                .show(&self.egui.context(), |ui| viewport.ui(ui));
+        }
+
+        let mut show_system_info = self.show_system_info;
+        egui::Window::new("System info")
+            .open(&mut show_system_info)
+            .default_width(360.0)
+            .show(&self.egui.context(), |ui| {
+                self.env.capabilities().ui(ui);
+
+                let transient = self.env.transient_attachments().stats();
+                ui.label(format!("Transient attachments: {} in {} blocks, {:.1} MB instead of {:.1} MB",
+                                 transient.attachments, transient.blocks,
+                                 transient.allocated as f64 / (1024.0 * 1024.0), transient.requested as f64 / (1024.0 * 1024.0)));
+            });
+        self.show_system_info = show_system_info;
+
+        let mut show_memory = self.show_memory;
+        egui::Window::new("Memory")
+            .open(&mut show_memory)
+            .default_width(360.0)
+            .show(&self.egui.context(), |ui| {
+                let heaps = self.env.memory_budget();
+                memory_tracker::stats().ui(ui, heaps.as_deref());
+            });
+        self.show_memory = show_memory;
+
+        let mut show_shader_tweaks = self.show_shader_tweaks;
+        egui::Window::new("Shader tweaks")
+            .open(&mut show_shader_tweaks)
+            .default_width(360.0)
+            .show(&self.egui.context(), shader_tweaks::ui);
+        self.show_shader_tweaks = show_shader_tweaks;
+
+        let mut show_depth_readback = self.show_depth_readback;
+        let mut depth_action = None;
+        let cascade_count = self.shadow_map_fb.cascade_count();
+        egui::Window::new("Depth readback")
+            .open(&mut show_depth_readback)
+            .default_width(360.0)
+            .show(&self.egui.context(), |ui| depth_action = self.depth_readback.ui(ui, cascade_count));
+        self.show_depth_readback = show_depth_readback;
+        if let Some(action) = depth_action {
+            self.apply_depth_readback(action);
+        }
+
+        let mut show_metrics = self.show_metrics;
+        let mut export_metrics = false;
+        egui::Window::new("Metrics")
+            .open(&mut show_metrics)
+            .default_width(360.0)
+            .show(&self.egui.context(), |ui| export_metrics = self.metrics.ui(ui));
+        self.show_metrics = show_metrics;
+        if export_metrics {
+            match self.metrics.export_csv(Path::new(METRICS_CSV)) {
+                Ok(()) => println!("Metrics are saved to {}", METRICS_CSV),
+                Err(err) => println!("Failed to save metrics: {}", err),
+            }
+        }
+
+        if self.show_bvh {
+            self.draw_bvh();
+        }
+        if self.show_gizmos {
+            self.draw_gizmos();
+        }
+        self.paint_debug_labels();
+
+        if walkability_changed {
+            self.update_walkability();
+        }
+
+        if wireframe != self.wireframe {
+            self.set_wireframe(wireframe);
+        }
+
+        if depth_prepass != self.depth_prepass {
+            self.set_depth_prepass(depth_prepass);
+        }
+
+        if terrain_tessellation != self.terrain_renderer.tessellation() {
+            self.set_terrain_tessellation(terrain_tessellation);
+        }
+
+        if anti_aliasing != self.anti_aliasing {
+            self.set_anti_aliasing(anti_aliasing);
+        }
+
+        if msaa_samples != self.msaa_samples {
+            self.set_msaa_samples(msaa_samples);
+        }
+
+        self.assets.progress_ui(&self.egui.context(), self.pipeline_warmup.as_ref());
+        if self.pipeline_warmup.as_ref().is_some_and(|warmup| warmup.is_finished()) {
+            let warmup = self.pipeline_warmup.take().unwrap();
+            println!("Pipeline warmup: {} pipelines compiled, {} skipped", warmup.total() - warmup.skipped(), warmup.skipped());
+        }
+
+        if let Some((kind, path)) = picked_asset {
+            self.load_asset(kind, &path);
+        }
     }
 
-    fn recreate_swapchain(&mut self, wnd: &winit::window::Window) {
+    fn update_window_title(&mut self, wnd: &winit::window::Window) {
+        self.window_decorations.set_scene(self.assets.current_name(AssetKind::Model).as_deref());
+        self.window_decorations.set_fps(self.tick_counter.fps());
+        self.window_decorations.set_progress(self.assets.progress());
+        self.window_decorations.update(wnd);
+    }
+
+    // Hands assets finished by the loader threads to the renderers
+    fn apply_loaded_assets(&mut self) {
+        let loaded = self.assets.poll(&self.env);
+        if loaded.is_empty() {
+            return;
+        }
+
         unsafe {
             self.env.device()
                 .device_wait_idle()
                 .expect("Failed to wait device idle!")
         };
-        self.cleanup_swapchain();
 
-        self.swapchain_stuff = ash_render_env::swapchain::SwapChain::new(&self.env, wnd.inner_size());
-        self.swapchain_stuff.create_framebuffers(self.env.device(), self.final_render_pass);
+        for asset in loaded {
+            match asset {
+                LoadedAsset::Model(model) => {
+                    self.mesh_renderer.set_model(model.clone(), &self.scene_buffer);
+                    self.instance_field.set_model(model.clone());
+                    self.transparent_renderer.set_model(model.clone(), &self.scene_buffer, &self.offscreen_buffer);
+                    for renderer in self.mesh_shadow_map_renderers.iter_mut().chain(self.mesh_point_shadow_renderers.iter_mut()) {
+                        renderer.set_model(model.clone());
+                    }
+                    self.reflective_shadow_map.set_model(model.clone(), &self.scene_buffer);
+                    self.model = model;
+
+                    // bounds of the objects changed
+                    self.update_scene_buffer();
+                }
+                LoadedAsset::Skybox(texture) => {
+                    self.skybox_renderer.set_texture(*texture);
+                    self.environment_lighting.set_environment(self.skybox_renderer.texture());
+                    self.quad_renderer.set_environment(self.environment_lighting.views());
+                    self.quad_renderer.update_framebuffer(&self.offscreen_buffer, self.shadow_map_fb.view, self.god_rays.occlusion_view(),
+                                                          self.offscreen_buffer.dimensions());
+                }
+            }
+        }
+    }
+
+    fn recreate_swapchain(&mut self, wnd: &winit::window::Window) {
+        // the old swapchain is kept until the window is restored, no frame is drawn meanwhile
+        if is_minimized(wnd) {
+            self.is_window_resized = true;
+            return;
+        }
+
+        // the old swapchain is handed to the driver and retired, frames in flight may still present from it
+        self.swapchain_stuff.present_mode = self.present_mode;
+        let format_changed = self.swapchain_stuff.recreate(&self.env, wnd.inner_size(), &mut [&mut self.final_pass_draw_command, &mut self.egui])
+            .expect("Failed to recreate swapchain!");
+        self.retired_swapchain_frames = MAX_FRAMES_IN_FLIGHT;
+        // the final pass, post process targets and gui pipelines are built for the format of the first swapchain,
+        // the new images have no framebuffers
+        if format_changed {
+            println!("Surface format changed to {:?} {:?}, restart to render in it", self.swapchain_stuff.format, self.swapchain_stuff.color_space);
+            self.surface_format_lost = true;
+            return;
+        }
+        self.images_in_flight = vec![vk::Fence::null(); self.swapchain_stuff.images.len()];
 
         let dimensions = [self.swapchain_stuff.size.width, self.swapchain_stuff.size.height];
+        // captured frames have the size of the first one
+        if self.frame_capture.as_ref().is_some_and(|capture| capture.dimensions() != dimensions) {
+            println!("Window resized, capture is stopped");
+            self.stop_capture();
+        }
+
+        // the editor viewport keeps its size, the scene only follows the window
+        if self.viewport.is_none() {
+            self.scene_resize_pending = true;
+        }
+    }
+
+    fn apply_depth_readback(&mut self, action: DepthReadbackAction) {
+        let source = match action {
+            DepthReadbackAction::Show(source) | DepthReadbackAction::Save(source) => source,
+        };
+
+        // the frames writing the depth must be finished, the shown texture may be replaced
+        unsafe {
+            self.env.device()
+                .device_wait_idle()
+                .expect("Failed to wait device idle!")
+        };
+
+        let depth = match source {
+            DepthSource::Scene => {
+                let depth = &self.offscreen_buffer.attachments[GBufferLayout::DEPTH];
+                if depth.samples != vk::SampleCountFlags::TYPE_1 {
+                    println!("Multisampled depth can't be read back, turn MSAA off");
+                    return;
+                }
+
+                let camera = self.camera.descriptor();
+                let values = self.offscreen_buffer.read_depth_attachment(GBufferLayout::DEPTH)
+                    .into_iter()
+                    .map(|depth| camera.depth_mode.view_distance(depth, camera.near, camera.far))
+                    .collect();
+                LinearDepth { source, values, dimensions: self.offscreen_buffer.dimensions() }
+            }
+            DepthSource::Cascade(idx) => LinearDepth {
+                source,
+                values: self.shadow_map_fb.read_cascade(&self.env, idx),
+                dimensions: self.shadow_map_fb.size(),
+            },
+        };
+
+        match action {
+            DepthReadbackAction::Show(_) => self.depth_readback.show(&mut self.egui, &depth),
+            DepthReadbackAction::Save(_) => match depth.save(source.file_name()) {
+                Ok(()) => println!("Depth is saved to {}", source.file_name()),
+                Err(err) => println!("Failed to save depth: {}", err),
+            },
+        }
+    }
+
+    // Scene size: the window or the editor viewport image
+    fn scene_dimensions(&self) -> [u32; 2] {
+        match &self.viewport {
+            Some(viewport) => viewport.dimensions(),
+            None => [self.swapchain_stuff.size.width, self.swapchain_stuff.size.height],
+        }
+    }
+
+    // Frames in flight must be finished
+    fn resize_scene(&mut self, dimensions: [u32; 2]) {
+        // first, as when created: other transient attachments alias its targets
+        self.post_process.resize(dimensions);
         self.geometry_pass_draw_command.set_dimensions(dimensions);
-        self.final_pass_draw_command.set_dimensions(dimensions);
+        self.compose_pass_draw_command.set_dimensions(dimensions);
 
         self.offscreen_buffer.resize_swapchain(dimensions);
-        self.egui.set_dimensions(dimensions);
-        self.egui.register_texture(0, self.offscreen_buffer.attachments[2].view, true);
-
-        self.quad_renderer.update_framebuffer(&self.offscreen_buffer, self.shadow_map_fb.view, dimensions);
+        let normal = &self.offscreen_buffer.attachments[GBufferLayout::NORMAL];
+        self.egui.replace_texture(self.egui_normal_texture, normal.view, normal.samples != vk::SampleCountFlags::TYPE_1);
+
+        self.god_rays.resize(&self.offscreen_buffer);
+        self.hi_z.resize(&self.offscreen_buffer);
+        self.decals.resize(&self.offscreen_buffer);
+        self.particles.resize(&self.offscreen_buffer);
+        self.transparent_renderer.resize(&self.scene_buffer, &self.offscreen_buffer);
+        self.debug_draw.resize(&self.offscreen_buffer);
+        self.hdr_buffer.resize_swapchain(dimensions);
+        self.taa.resize(&self.offscreen_buffer, &self.hdr_buffer);
+        self.bloom.resize(&self.hdr_buffer);
+        self.aux_passes.resize(dimensions);
+        self.quad_renderer.update_framebuffer(&self.offscreen_buffer, self.shadow_map_fb.view, self.god_rays.occlusion_view(), dimensions);
         self.mesh_renderer.resize_framebuffer(dimensions);
+        self.instance_field.resize_framebuffer(dimensions);
         self.skybox_renderer.resize_framebuffer(dimensions);
         self.terrain_renderer.resize_framebuffer(dimensions);
 
         self.camera.set_viewport(dimensions[0], dimensions[1]);
     }
 
+    // Creates, resizes or removes the editor viewport, the scene follows its size
+    fn apply_viewport_changes(&mut self) {
+        let pending_resize = self.viewport.as_ref().and_then(|viewport| viewport.pending_resize());
+        if self.show_viewport == self.viewport.is_some() && pending_resize.is_none() {
+            return;
+        }
+
+        unsafe {
+            self.env.device()
+                .device_wait_idle()
+                .expect("Failed to wait device idle!")
+        };
+
+        match (&mut self.viewport, self.show_viewport) {
+            (Some(viewport), true) => viewport.resize(&mut self.egui, pending_resize.unwrap()),
+            (None, true) => {
+                let window = [self.swapchain_stuff.size.width, self.swapchain_stuff.size.height];
+                let dimensions = [(window[0] / 2).max(1), (window[1] / 2).max(1)];
+                self.viewport = Some(SceneViewport::new(self.env.clone(), &mut self.egui, self.swapchain_stuff.format, dimensions, MAX_FRAMES_IN_FLIGHT));
+            }
+            (_, false) => {
+                if let Some(mut viewport) = self.viewport.take() {
+                    viewport.destroy(&mut self.egui);
+                }
+            }
+        }
+
+        self.resize_scene(self.scene_dimensions());
+        self.scene_resize_pending = false;
+    }
+
+    // Pointer events belong to the scene: not over the gui, or over the editor viewport image
+    fn scene_has_pointer(&self) -> bool {
+        match &self.viewport {
+            Some(viewport) => viewport.is_hovered(),
+            None => !self.egui.wants_pointer(),
+        }
+    }
+
+    // World space ray under the mouse cursor, None when it is outside of the editor viewport
+    fn cursor_ray(&self) -> Option<(Point3<f32>, Vector3<f32>)> {
+        match &self.viewport {
+            Some(viewport) => {
+                let pos = self.egui.context().input().pointer.hover_pos()?;
+                let [x, y] = viewport.to_pixel(pos)?;
+                Some(self.camera.screen_ray(x, y))
+            }
+            None => Some(self.camera.cursor_ray()),
+        }
+    }
+
+    // Moves the camera back when it went into an object box. A camera already inside of a box
+    // (e.g. the object was placed around it) can leave it
+    fn collide_camera(&mut self, old_position: Point3<f32>) {
+        let offset = self.camera.position() - old_position;
+        let distance = offset.magnitude();
+        if distance <= f32::EPSILON {
+            return;
+        }
+
+        let bvh = &self.scene_bvh;
+        let hit = bvh.raycast_with(old_position, offset / distance, distance + CAMERA_RADIUS, |item, box_distance| {
+            Some(box_distance).filter(|_| !bvh.item_bounds(item).contains(old_position))
+        });
+        if hit.is_some() {
+            self.camera.set_position(old_position);
+            self.camera.stop();
+        }
+    }
+
+    // Keeps the camera at eye height over the terrain, it flies freely outside of the terrain
+    fn clamp_camera_to_ground(&mut self) {
+        if !self.clamp_to_ground {
+            return;
+        }
+
+        let mut position = self.camera.position();
+        if let Some(height) = self.terrain_renderer.terrain().height_at(position.x, position.z) {
+            // heights go up along -Y
+            position.y = height - CAMERA_EYE_HEIGHT;
+            self.camera.set_position(position);
+        }
+    }
+
+    // Node boxes of the scene BVH, colored by depth
+    fn draw_bvh(&mut self) {
+        for (bounds, depth) in self.scene_bvh.node_boxes(self.bvh_depth) {
+            let hue = depth as f32 / (self.bvh_depth + 1) as f32;
+            let color = egui::Rgba::from(egui::color::Hsva::new(hue, 0.8, 1.0, 1.0));
+            self.debug_draw.aabb(&bounds, [color.r(), color.g(), color.b(), 1.0]);
+        }
+    }
+
+    // Light frusta of the shadow cascades with the camera slices they cover, in the cascade colors
+    fn draw_cascade_frusta(&mut self) {
+        let settings = self.shadow_settings;
+        if !settings.show_cascade_frusta || !settings.freeze_cascade_frusta {
+            self.frozen_cascades = None;
+        }
+        if !settings.show_cascade_frusta {
+            return;
+        }
+
+        let cascades = match (&self.frozen_cascades, settings.freeze_cascade_frusta) {
+            (Some(frozen), true) => frozen.clone(),
+            (None, true) => {
+                self.frozen_cascades = Some(self.cascades.clone());
+                self.cascades.clone()
+            }
+            (_, false) => self.cascades.clone(),
+        };
+
+        for (cascade_idx, cascade) in cascades.iter().enumerate() {
+            let [r, g, b] = CASCADE_DEBUG_COLORS[cascade_idx % CASCADE_DEBUG_COLORS.len()];
+            // shadow projections are already in the 0..1 depth range
+            self.debug_draw.frustum(&cascade.view_proj_mat, [0.0, 1.0], [r, g, b, 0.6]);
+            self.debug_draw.hexahedron(&cascade.split_corners, [r, g, b, 1.0]);
+
+            let far_center = cascade.split_corners[4..].iter()
+                .fold(Vector3::new(0.0, 0.0, 0.0), |sum, corner| sum + corner.to_vec()) / 4.0;
+            self.debug_draw.label(Point3::from_vec(far_center), format!("cascade {}", cascade_idx), [r, g, b, 1.0]);
+        }
+    }
+
+    // Point light range and its shadow faces, world axes and the particle emitter
+    fn draw_gizmos(&mut self) {
+        let point_light = self.point_light();
+        let light_color = [1.0, 0.9, 0.4, 1.0];
+        self.debug_draw.sphere(point_light.position, point_light.radius, light_color);
+        for face_view_proj in point_light.face_view_proj() {
+            self.debug_draw.frustum(&face_view_proj, [0.0, 1.0], [light_color[0], light_color[1], light_color[2], 0.3]);
+        }
+
+        self.debug_draw.label(point_light.position, "point light", light_color);
+
+        self.debug_draw.axes(&Matrix4::identity(), 1.0);
+        let emitter = self.particles.emitter;
+        self.debug_draw.axes(&Matrix4::from_translation(emitter.position.to_vec()), 0.3);
+        self.debug_draw.label(emitter.position, "particle emitter", [1.0, 1.0, 1.0, 1.0]);
+    }
+
+    // Debug draw labels of the frame at their projected positions
+    fn paint_debug_labels(&self) {
+        let ctx = self.egui.context();
+        let painter = ctx.layer_painter(egui::LayerId::new(egui::Order::Background, egui::Id::new("debug_labels")));
+        let pixels_per_point = ctx.pixels_per_point();
+        let to_point = |pixel: [f32; 2]| match &self.viewport {
+            Some(viewport) => viewport.to_point(pixel),
+            None => egui::pos2(pixel[0] / pixels_per_point, pixel[1] / pixels_per_point),
+        };
+
+        for label in self.debug_draw.labels() {
+            if let Some(pixel) = self.camera.world_to_screen(label.position) {
+                let [r, g, b, a] = label.color;
+                painter.text(to_point(pixel), egui::Align2::CENTER_BOTTOM, &label.text, egui::TextStyle::Body,
+                             egui::Rgba::from_rgba_premultiplied(r * a, g * a, b * a, a).into());
+            }
+        }
+    }
+
+    // Presented frames go to ./capture.y4m or ./capture/frame_N.png
+    fn start_capture(&mut self) {
+        if !self.swapchain_stuff.transfer_src {
+            println!("Swapchain images can't be copied, capture is not supported");
+            return;
+        }
+
+        let output = if self.capture_video {
+            CaptureOutput::Y4m {
+                path: Path::new("capture.y4m").to_path_buf(),
+                fps: (60 / self.capture_every_nth).max(1),
+            }
+        } else {
+            CaptureOutput::Png(Path::new("capture").to_path_buf())
+        };
+        let dimensions = [self.swapchain_stuff.size.width, self.swapchain_stuff.size.height];
+
+        match FrameCapture::new(self.env.clone(), output, self.swapchain_stuff.format, dimensions, self.capture_every_nth, 3) {
+            Ok(capture) => self.frame_capture = Some(capture),
+            Err(err) => println!("{}", err),
+        }
+    }
+
+    fn stop_capture(&mut self) {
+        if let Some(capture) = self.frame_capture.take() {
+            match capture.finish() {
+                Ok(stats) => println!("Capture finished: {} frames written, {} dropped", stats.written, stats.dropped),
+                Err(err) => println!("Capture failed: {}", err),
+            }
+        }
+    }
+
     fn cleanup_swapchain(&mut self) {
         self.swapchain_stuff.destroy();
     }
@@ -539,24 +2206,106 @@ impl HelloApplication {
 
 impl Drop for HelloApplication {
     fn drop(&mut self) {
-        unsafe {
-            self.sync.destroy();
-            self.cleanup_swapchain();
+        self.stop_capture();
+
+        // stops the warmup thread before the cache is saved
+        self.pipeline_warmup = None;
+        if let Err(err) = self.env.pipeline_cache().save(Path::new(PIPELINE_CACHE)) {
+            println!("Failed to save pipeline cache: {}", err);
+        }
+        if let Err(err) = self.env.pipeline_cache().save_profile(Path::new(PIPELINE_PROFILE)) {
+            println!("Failed to save pipeline profile: {}", err);
+        }
+        self.save_settings();
+
+        self.sync.destroy();
+        self.cleanup_swapchain();
 
-            self.offscreen_buffer.destroy();
-            self.env.device().destroy_render_pass(self.final_render_pass, None);
+        if let Some(mut viewport) = self.viewport.take() {
+            viewport.destroy(&mut self.egui);
         }
+        self.depth_readback.destroy(&mut self.egui);
+
+        self.offscreen_buffer.destroy();
+        self.hdr_buffer.destroy();
     }
 }
 
+// Minimized windows report a zero size, a swapchain can't have it
+fn is_minimized(wnd: &winit::window::Window) -> bool {
+    let size = wnd.inner_size();
+    size.width == 0 || size.height == 0
+}
+
 fn main() {
+    // a broken file is reported and replaced with the defaults on exit
+    let settings = Settings::load(Path::new(SETTINGS_FILE)).unwrap_or_else(|err| {
+        println!("{}", err);
+        Settings::default()
+    });
+
     let event_loop = EventLoop::new();
     let wnd = winit::window::WindowBuilder::new()
-        .with_title("test")
-        .with_inner_size(winit::dpi::LogicalSize::new(800, 600))
+        .with_title(WINDOW_TITLE)
+        .with_inner_size(winit::dpi::LogicalSize::new(settings.window.width, settings.window.height))
         .build(&event_loop)
         .expect("Failed to create window");
 
-    let mut app = HelloApplication::new(&wnd);
-    app.run(event_loop, wnd);
+    // the icon is optional, it's not a part of the downloaded assets
+    if Path::new(WINDOW_ICON).exists() {
+        if let Err(err) = WindowDecorations::set_icon(&wnd, Path::new(WINDOW_ICON)) {
+            println!("{}", err);
+        }
+    }
+
+    let objects_before = object_tracker::snapshot();
+    {
+        // `--reverse-z` renders the camera passes with reversed depth
+        let depth_mode = if std::env::args().any(|arg| arg == "--reverse-z") {
+            DepthMode::ReverseZ
+        } else {
+            DepthMode::Standard
+        };
+        // `--present-mode fifo|mailbox|immediate`, the settings one by default
+        let present_mode = std::env::args()
+            .skip_while(|arg| arg != "--present-mode")
+            .nth(1)
+            .map(|name| PresentMode::from_name(&name).expect("Unknown present mode!"))
+            .unwrap_or_else(|| settings.graphics.present_mode());
+        // `--output-format srgb8|unorm10|hdr10|scrgb`, the settings one by default
+        let output_format = std::env::args()
+            .skip_while(|arg| arg != "--output-format")
+            .nth(1)
+            .map(|name| OutputFormat::from_name(&name).expect("Unknown output format!"))
+            .unwrap_or_else(|| settings.graphics.output_format());
+        let mut app = HelloApplication::new(&wnd, depth_mode, present_mode, output_format, settings);
+
+        // `--record <file>` saves the input of the session, `--replay <file>` plays it back
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--record" => {
+                    let path = args.next().expect("--record needs a file!");
+                    app.input_recorder = Some(InputRecorder::create(Path::new(&path)).expect("Failed to create input recording!"));
+                }
+                "--replay" => {
+                    let path = args.next().expect("--replay needs a file!");
+                    let replay = InputReplay::load(Path::new(&path)).expect("Failed to load input recording!");
+                    println!("Replaying {} frames from {}", replay.frame_count(), path);
+                    app.input_replay = Some(replay);
+                }
+                "--reverse-z" => (),
+                "--present-mode" => {
+                    args.next();
+                }
+                _ => println!("Unknown argument: {}", arg),
+            }
+        }
+
+        app.run(event_loop, wnd);
+    }
+
+    if cfg!(debug_assertions) {
+        object_tracker::assert_no_growth(&objects_before);
+    }
 }
@@ -0,0 +1,146 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use ash_render_env::swapchain::{OutputFormat, PresentMode};
+
+use crate::shadow_map::CASCADE_COUNT;
+
+// Engine settings read at startup and written back on exit. Missing keys keep their defaults, so an
+// old file still loads after new settings are added
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub window: WindowSettings,
+    pub graphics: GraphicsSettings,
+    pub assets: AssetPaths,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WindowSettings {
+    // logical size of the window when it's not fullscreen
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Default for WindowSettings {
+    fn default() -> Self {
+        WindowSettings {
+            width: 800,
+            height: 600,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GraphicsSettings {
+    // fifo is vsync, mailbox and immediate aren't. `--present-mode` overrides it
+    pub present_mode: String,
+    // srgb8, unorm10, hdr10 or scrgb, falls back to what the monitor supports. `--output-format` overrides it
+    pub output_format: String,
+    // brightness of SDR white on HDR outputs
+    pub paper_white_nits: u32,
+    // G-buffer samples of MSAA, clamped to the device limit
+    pub msaa_samples: u32,
+    // size of a shadow cascade in texels
+    pub shadow_map_size: u32,
+    // 1 to CASCADE_COUNT
+    pub cascade_count: usize,
+    // screen error of mesh detail levels in pixels, 0 draws full meshes
+    pub lod_error_pixels: f32,
+}
+
+impl Default for GraphicsSettings {
+    fn default() -> Self {
+        GraphicsSettings {
+            present_mode: PresentMode::default().name().to_string(),
+            output_format: OutputFormat::default().name().to_string(),
+            paper_white_nits: 200,
+            msaa_samples: 2,
+            shadow_map_size: 4096,
+            cascade_count: CASCADE_COUNT,
+            lod_error_pixels: 1.0,
+        }
+    }
+}
+
+impl GraphicsSettings {
+    // Unknown names fall back to the default mode
+    pub fn present_mode(&self) -> PresentMode {
+        PresentMode::from_name(&self.present_mode).unwrap_or_else(|| {
+            println!("Unknown present mode in settings: {}", self.present_mode);
+            PresentMode::default()
+        })
+    }
+
+    // Unknown names fall back to 8 bit sRGB
+    pub fn output_format(&self) -> OutputFormat {
+        OutputFormat::from_name(&self.output_format).unwrap_or_else(|| {
+            println!("Unknown output format in settings: {}", self.output_format);
+            OutputFormat::default()
+        })
+    }
+
+    // 80 nits is SDR reference white, 10000 is the PQ peak
+    pub fn paper_white_nits(&self) -> u32 {
+        self.paper_white_nits.clamp(80, 10000)
+    }
+
+    pub fn cascade_count(&self) -> usize {
+        self.cascade_count.clamp(1, CASCADE_COUNT)
+    }
+
+    pub fn lod_error_pixels(&self) -> f32 {
+        self.lod_error_pixels.clamp(0.0, 8.0)
+    }
+}
+
+// Assets loaded at startup, the asset browser replaces them at runtime
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AssetPaths {
+    pub model: PathBuf,
+    // directory with the six face images or a single cross, strip or panorama image
+    pub skybox: PathBuf,
+    pub heightmap: PathBuf,
+    // PNG images added to the generated decal textures
+    pub decals: PathBuf,
+    // placements of the editor, see PlacementTool::save
+    pub scene: PathBuf,
+}
+
+impl Default for AssetPaths {
+    fn default() -> Self {
+        AssetPaths {
+            model: PathBuf::from("assets/chalet2.obj"),
+            skybox: PathBuf::from("./assets/skybox"),
+            heightmap: PathBuf::from("./assets/terrain/heightmap2.png"),
+            decals: PathBuf::from("./assets/decals"),
+            scene: PathBuf::from("./assets/scene.txt"),
+        }
+    }
+}
+
+impl Settings {
+    // Defaults when the file doesn't exist yet, it's created on the first exit
+    pub fn load(path: &Path) -> Result<Settings, String> {
+        if !path.exists() {
+            return Ok(Settings::default());
+        }
+
+        let data = fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        toml::from_str(&data)
+            .map_err(|err| format!("Failed to parse {}: {}", path.display(), err))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let data = toml::to_string_pretty(self)
+            .map_err(|err| format!("Failed to serialize settings: {}", err))?;
+        fs::write(path, data)
+            .map_err(|err| format!("Failed to write {}: {}", path.display(), err))
+    }
+}
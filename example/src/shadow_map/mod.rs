@@ -1,4 +1,8 @@
 mod render_target;
+mod point_light;
+mod settings;
 pub mod uniform_buffer;
 
 pub use render_target::*;
+pub use point_light::*;
+pub use settings::*;
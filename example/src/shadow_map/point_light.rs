@@ -0,0 +1,252 @@
+use std::ptr;
+use std::sync::Arc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+use cgmath::{Deg, EuclideanSpace, Matrix4, Point3, Rad, Vector3};
+
+use ash_render_env::env::RenderEnv;
+use ash_render_env::memory_tracker::{self, MemoryCategory};
+use ash_render_env::object_tracker::{self, ObjectKind};
+
+use super::render_target::create_render_pass;
+
+pub const POINT_SHADOW_MAP_SIZE: u32 = 1024;
+const CUBE_FACE_COUNT: usize = 6;
+
+pub struct PointLight {
+    pub position: Point3<f32>,
+    pub near: f32,
+    pub radius: f32,
+    pub intensity: f32,
+}
+
+impl PointLight {
+    // View-projection for every cube face in the +X, -X, +Y, -Y, +Z, -Z order of cube map layers
+    pub fn face_view_proj(&self) -> Vec<Matrix4<f32>> {
+        let faces = [
+            (Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, -1.0, 0.0)),
+            (Vector3::new(-1.0, 0.0, 0.0), Vector3::new(0.0, -1.0, 0.0)),
+            (Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, 0.0, 1.0)),
+            (Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.0, 0.0, -1.0)),
+            (Vector3::new(0.0, 0.0, 1.0), Vector3::new(0.0, -1.0, 0.0)),
+            (Vector3::new(0.0, 0.0, -1.0), Vector3::new(0.0, -1.0, 0.0)),
+        ];
+
+        let proj = cgmath::perspective(Rad::from(Deg(90.0)), 1.0, self.near, self.radius);
+
+        // OpenGL depth range to vulkan one, same as for cascades
+        let corr_matrix = cgmath::Matrix4::<f32>::new(
+            1.0, 0.0, 0.0, 0.0,
+            0.0, 1.0, 0.0, 0.0,
+            0.0, 0.0, 0.5, 0.0,
+            0.0, 0.0, 0.5, 1.0,
+        );
+
+        faces.iter()
+            .map(|&(dir, up)| {
+                let view = Matrix4::look_at_rh(self.position, self.position + dir, up);
+                corr_matrix * proj * view
+            })
+            .collect()
+    }
+
+    // xyz - position, w - far plane
+    pub fn position_radius(&self) -> [f32; 4] {
+        let position = self.position.to_vec();
+        [position.x, position.y, position.z, self.radius]
+    }
+}
+
+struct CubeFace {
+    view: vk::ImageView,
+    framebuffer: vk::Framebuffer,
+
+    device: ash::Device,
+}
+
+impl Drop for CubeFace {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_framebuffer(self.framebuffer, None);
+            object_tracker::destroyed(ObjectKind::Framebuffer);
+            self.device.destroy_image_view(self.view, None);
+            object_tracker::destroyed(ObjectKind::ImageView);
+        }
+    }
+}
+
+// Depth cube map, every face is rendered by a separate pass
+pub struct PointShadowMap {
+    faces: Vec<CubeFace>,
+
+    pub cube_view: vk::ImageView,
+    image: vk::Image,
+    memory: vk::DeviceMemory,
+
+    render_pass: vk::RenderPass,
+    device: ash::Device,
+}
+
+impl PointShadowMap {
+    pub fn new(env: Arc<RenderEnv>) -> PointShadowMap {
+        let size = POINT_SHADOW_MAP_SIZE;
+        let depth_format = env.capabilities().shadow_depth_format;
+        let render_pass = create_render_pass(&env, depth_format);
+
+        let image_create_info = vk::ImageCreateInfo {
+            s_type: vk::StructureType::IMAGE_CREATE_INFO,
+            p_next: ptr::null(),
+            flags: vk::ImageCreateFlags::CUBE_COMPATIBLE,
+            image_type: vk::ImageType::TYPE_2D,
+            format: depth_format,
+            extent: vk::Extent3D {
+                width: size,
+                height: size,
+                depth: 1,
+            },
+            mip_levels: 1,
+            array_layers: CUBE_FACE_COUNT as u32,
+            samples: vk::SampleCountFlags::TYPE_1,
+            tiling: vk::ImageTiling::OPTIMAL,
+            usage: vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+            sharing_mode: vk::SharingMode::EXCLUSIVE,
+            queue_family_index_count: 0,
+            p_queue_family_indices: ptr::null(),
+            initial_layout: vk::ImageLayout::UNDEFINED,
+        };
+
+        let image = unsafe {
+            env.device()
+                .create_image(&image_create_info, None)
+                .expect("Failed to create Texture Image!")
+        };
+        object_tracker::created(ObjectKind::Image);
+
+        let image_memory_requirement =
+            unsafe { env.device().get_image_memory_requirements(image) };
+
+        let memory_allocate_info = vk::MemoryAllocateInfo {
+            s_type: vk::StructureType::MEMORY_ALLOCATE_INFO,
+            p_next: ptr::null(),
+            allocation_size: image_memory_requirement.size,
+            memory_type_index: env.find_memory_type(
+                image_memory_requirement.memory_type_bits,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            ),
+        };
+
+        let memory = unsafe {
+            env.device()
+                .allocate_memory(&memory_allocate_info, None)
+                .expect("Failed to allocate Texture Image memory!")
+        };
+        memory_tracker::allocated(memory, image_memory_requirement.size, MemoryCategory::ShadowMaps);
+
+        unsafe {
+            env.device()
+                .bind_image_memory(image, memory, 0)
+                .expect("Failed to bind Image Memmory!");
+        }
+
+        let create_view = |view_type: vk::ImageViewType, base_array_layer: u32, layer_count: u32| -> vk::ImageView {
+            let imageview_create_info = vk::ImageViewCreateInfo {
+                s_type: vk::StructureType::IMAGE_VIEW_CREATE_INFO,
+                p_next: ptr::null(),
+                flags: vk::ImageViewCreateFlags::empty(),
+                view_type,
+                format: depth_format,
+                components: vk::ComponentMapping {
+                    r: vk::ComponentSwizzle::IDENTITY,
+                    g: vk::ComponentSwizzle::IDENTITY,
+                    b: vk::ComponentSwizzle::IDENTITY,
+                    a: vk::ComponentSwizzle::IDENTITY,
+                },
+                subresource_range: vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::DEPTH,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer,
+                    layer_count,
+                },
+                image,
+            };
+
+            let view = unsafe {
+                env.device()
+                    .create_image_view(&imageview_create_info, None)
+                    .expect("Failed to create Image View!")
+            };
+            object_tracker::created(ObjectKind::ImageView);
+
+            view
+        };
+
+        let cube_view = create_view(vk::ImageViewType::CUBE, 0, CUBE_FACE_COUNT as u32);
+
+        let mut faces = Vec::with_capacity(CUBE_FACE_COUNT);
+        for i in 0..CUBE_FACE_COUNT {
+            let face_view = create_view(vk::ImageViewType::TYPE_2D, i as u32, 1);
+
+            let face_view_list = [face_view];
+            let framebuffer_info = vk::FramebufferCreateInfo {
+                s_type: vk::StructureType::FRAMEBUFFER_CREATE_INFO,
+                p_next: ptr::null(),
+                flags: Default::default(),
+                render_pass,
+                attachment_count: face_view_list.len() as u32,
+                p_attachments: face_view_list.as_ptr(),
+                width: size,
+                height: size,
+                layers: 1,
+            };
+
+            let framebuffer = unsafe {
+                env.device().create_framebuffer(&framebuffer_info, None).unwrap()
+            };
+            object_tracker::created(ObjectKind::Framebuffer);
+
+            faces.push(CubeFace {
+                device: env.device().clone(),
+                view: face_view,
+                framebuffer,
+            });
+        }
+
+        PointShadowMap {
+            device: env.device().clone(),
+            render_pass,
+            image,
+            memory,
+            faces,
+            cube_view,
+        }
+    }
+
+    pub fn face_count(&self) -> usize {
+        self.faces.len()
+    }
+
+    pub fn framebuffer(&self, face: usize) -> vk::Framebuffer {
+        self.faces[face].framebuffer
+    }
+
+    pub fn render_pass(&self) -> vk::RenderPass {
+        self.render_pass
+    }
+}
+
+impl Drop for PointShadowMap {
+    fn drop(&mut self) {
+        unsafe {
+            self.faces.clear();
+
+            self.device.destroy_image_view(self.cube_view, None);
+            object_tracker::destroyed(ObjectKind::ImageView);
+            self.device.destroy_image(self.image, None);
+            object_tracker::destroyed(ObjectKind::Image);
+            self.device.free_memory(self.memory, None);
+            memory_tracker::freed(self.memory);
+        }
+    }
+}
@@ -1,21 +1,31 @@
+use std::ops::{Add, Sub};
 use std::ptr;
 use std::sync::Arc;
 
 use ash::version::DeviceV1_0;
 use ash::vk;
-use cgmath::{InnerSpace, Matrix4, MetricSpace, Point3, SquareMatrix, Transform, Vector3, Vector4};
+use cgmath::{EuclideanSpace, Matrix4, MetricSpace, Point3, Vector3, Vector4};
 
-use ash_render_env::camera::Camera;
+use ash_render_env::camera::CameraDescriptor;
 use ash_render_env::env::RenderEnv;
-use std::ops::{Sub, Add};
+use ash_render_env::memory_tracker::{self, MemoryCategory};
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::utils::readback;
 
+// most cascades a shadow map can have, sizes the cascade arrays of the compose uniforms
 pub const CASCADE_COUNT: usize = 4;
 
+#[derive(Clone, Copy, Debug)]
 pub struct CascadeInfo {
     pub view_proj_mat: Matrix4<f32>,
     pub max_z: f32,
+    // world space slice of the camera frustum covered by the cascade, in Aabb::corners() order
+    pub split_corners: [Point3<f32>; 8],
 }
 
+// Corners of update_cascades() in Aabb::corners() order: -x-y, +x-y, -x+y, +x+y, near plane first
+const SPLIT_CORNER_ORDER: [usize; 8] = [3, 2, 0, 1, 7, 6, 4, 5];
+
 struct Cascade {
     view: vk::ImageView,
     framebuffer: vk::Framebuffer,
@@ -27,7 +37,9 @@ impl Drop for Cascade {
     fn drop(&mut self) {
         unsafe {
             self.device.destroy_framebuffer(self.framebuffer, None);
+            object_tracker::destroyed(ObjectKind::Framebuffer);
             self.device.destroy_image_view(self.view, None);
+            object_tracker::destroyed(ObjectKind::ImageView);
         }
     }
 }
@@ -47,10 +59,12 @@ pub struct ShadowMapFramebuffer {
 }
 
 impl ShadowMapFramebuffer {
-    pub fn new(env: Arc<RenderEnv>) -> ShadowMapFramebuffer {
-        let (cascade_width, cascade_height) = (4096 as u32, 4096 as u32);
-        let depth_format = vk::Format::D32_SFLOAT;
-        let render_pass = create_render_pass(env.device(), depth_format);
+    // `cascade_count` layers of `size` texels, up to CASCADE_COUNT
+    pub fn new(env: Arc<RenderEnv>, size: u32, cascade_count: usize) -> ShadowMapFramebuffer {
+        assert!(cascade_count > 0 && cascade_count <= CASCADE_COUNT);
+        let (cascade_width, cascade_height) = (size, size);
+        let depth_format = env.capabilities().shadow_depth_format;
+        let render_pass = create_render_pass(&env, depth_format);
 
 
         let image_create_info = vk::ImageCreateInfo {
@@ -65,10 +79,11 @@ impl ShadowMapFramebuffer {
                 depth: 1,
             },
             mip_levels: 1,
-            array_layers: CASCADE_COUNT as u32,
+            array_layers: cascade_count as u32,
             samples: vk::SampleCountFlags::TYPE_1,
             tiling: vk::ImageTiling::OPTIMAL,
-            usage: vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+            // TRANSFER_SRC for depth readback
+            usage: vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_SRC,
             sharing_mode: vk::SharingMode::EXCLUSIVE,
             queue_family_index_count: 0,
             p_queue_family_indices: ptr::null(),
@@ -80,6 +95,7 @@ impl ShadowMapFramebuffer {
                 .create_image(&image_create_info, None)
                 .expect("Failed to create Texture Image!")
         };
+        object_tracker::created(ObjectKind::Image);
 
         let image_memory_requirement =
             unsafe { env.device().get_image_memory_requirements(shadow_map_image) };
@@ -99,6 +115,7 @@ impl ShadowMapFramebuffer {
                 .allocate_memory(&memory_allocate_info, None)
                 .expect("Failed to allocate Texture Image memory!")
         };
+        memory_tracker::allocated(shadow_map_memory, image_memory_requirement.size, MemoryCategory::ShadowMaps);
 
         unsafe {
             env
@@ -124,7 +141,7 @@ impl ShadowMapFramebuffer {
                 base_mip_level: 0,
                 level_count: 1,
                 base_array_layer: 0,
-                layer_count: CASCADE_COUNT as u32,
+                layer_count: cascade_count as u32,
             },
             image: shadow_map_image,
         };
@@ -134,11 +151,12 @@ impl ShadowMapFramebuffer {
                 .create_image_view(&imageview_create_info, None)
                 .expect("Failed to create Image View!")
         };
+        object_tracker::created(ObjectKind::ImageView);
 
 
         // CREATE CASCADES VIEWS AND FRAMEBUFFERS
-        let mut cascades = Vec::with_capacity(CASCADE_COUNT);
-        for i in 0..CASCADE_COUNT {
+        let mut cascades = Vec::with_capacity(cascade_count);
+        for i in 0..cascade_count {
             let imageview_create_info = vk::ImageViewCreateInfo {
                 s_type: vk::StructureType::IMAGE_VIEW_CREATE_INFO,
                 p_next: ptr::null(),
@@ -166,6 +184,7 @@ impl ShadowMapFramebuffer {
                     .create_image_view(&imageview_create_info, None)
                     .expect("Failed to create Image View!")
             };
+            object_tracker::created(ObjectKind::ImageView);
 
             let cascade_image_view_list = [cascade_image_view];
             let framebuffer_info = vk::FramebufferCreateInfo {
@@ -183,6 +202,7 @@ impl ShadowMapFramebuffer {
             let cascade_framebuffer = unsafe {
                 env.device().create_framebuffer(&framebuffer_info, None).unwrap()
             };
+            object_tracker::created(ObjectKind::Framebuffer);
 
             cascades.push(Cascade {
                 device: env.device().clone(),
@@ -212,16 +232,30 @@ impl ShadowMapFramebuffer {
         self.cascades[index].framebuffer.clone()
     }
 
+    pub fn cascade_count(&self) -> usize {
+        self.cascades.len()
+    }
+
+    pub fn size(&self) -> [u32; 2] {
+        [self.width, self.height]
+    }
+
     pub fn render_pass(&self) -> vk::RenderPass {
         self.render_pass.clone()
     }
 
-    pub fn update_cascades(&mut self, camera: &Camera, cascade_split_lambda: f32) -> Vec<CascadeInfo> {
-        let near_clip = camera.near_clip;
-        let far_clip = camera.far_clip;
+    // Depth of a cascade as stored, linear in light space. Shadow passes writing it must be finished
+    pub fn read_cascade(&self, env: &RenderEnv, index: usize) -> Vec<f32> {
+        readback::read_depth_image(env, self.image, env.capabilities().shadow_depth_format, self.size(), index as u32)
+    }
+
+    // `light_dir` - Atmosphere::sun_direction
+    pub fn update_cascades(&mut self, camera: &CameraDescriptor, cascade_split_lambda: f32, light_dir: Vector3<f32>) -> Vec<CascadeInfo> {
+        let near_clip = camera.near;
+        let far_clip = camera.far;
         let clip_range = far_clip - near_clip;
 
-        let min_z = camera.near_clip;
+        let min_z = near_clip;
         let max_z = near_clip + clip_range;
 
         let z_range = max_z - min_z;
@@ -230,8 +264,9 @@ impl ShadowMapFramebuffer {
         // Calculate split depths based on view camera frustum
         // Based on method presented in https://developer.nvidia.com/gpugems/GPUGems3/gpugems3_ch10.html
         let mut camera_splits = Vec::new();
-        for i in 0..CASCADE_COUNT {
-            let p = (i + 1) as f32 / CASCADE_COUNT as f32;
+        let cascade_count = self.cascades.len();
+        for i in 0..cascade_count {
+            let p = (i + 1) as f32 / cascade_count as f32;
             let log = min_z * ratio.powf(p);
             let uniform = min_z + z_range * p;
             let d = cascade_split_lambda * (log - uniform) + uniform;
@@ -239,29 +274,30 @@ impl ShadowMapFramebuffer {
             camera_splits.push((d - near_clip) / clip_range);
         }
 
+        let ndc_near = camera.depth_mode.ndc_near();
+        let ndc_far = camera.depth_mode.ndc_far();
         let frustum_corners = [
-            cgmath::Vector3::<f32>::new(-1.0, 1.0, -1.0),
-            cgmath::Vector3::<f32>::new(1.0, 1.0, -1.0),
-            cgmath::Vector3::<f32>::new(1.0, -1.0, -1.0),
-            cgmath::Vector3::<f32>::new(-1.0, -1.0, -1.0),
-            cgmath::Vector3::<f32>::new(-1.0, 1.0, 1.0),
-            cgmath::Vector3::<f32>::new(1.0, 1.0, 1.0),
-            cgmath::Vector3::<f32>::new(1.0, -1.0, 1.0),
-            cgmath::Vector3::<f32>::new(-1.0, -1.0, 1.0),
+            cgmath::Vector3::<f32>::new(-1.0, 1.0, ndc_near),
+            cgmath::Vector3::<f32>::new(1.0, 1.0, ndc_near),
+            cgmath::Vector3::<f32>::new(1.0, -1.0, ndc_near),
+            cgmath::Vector3::<f32>::new(-1.0, -1.0, ndc_near),
+            cgmath::Vector3::<f32>::new(-1.0, 1.0, ndc_far),
+            cgmath::Vector3::<f32>::new(1.0, 1.0, ndc_far),
+            cgmath::Vector3::<f32>::new(1.0, -1.0, ndc_far),
+            cgmath::Vector3::<f32>::new(-1.0, -1.0, ndc_far),
         ];
 
-        let inv_cam = (camera.proj_matrix() * camera.view_matrix()).invert().unwrap();
+        let inv_cam = camera.inv_view_proj;
 
         let mut last_split_dist = 0.0;
         let mut cascades = Vec::<CascadeInfo>::new();
-        for cascade_index in 0..CASCADE_COUNT {
+        for &split_dist in camera_splits.iter() {
             let mut camera_corners = vec![];
             for corner in frustum_corners.iter().cloned() {
                 let inv_corner: Vector4<f32> = inv_cam * corner.extend(1.0);
                 camera_corners.push(inv_corner.truncate() / inv_corner.w)
             }
 
-            let split_dist = camera_splits[cascade_index];
             for i in 0..4 {
                 let dist = camera_corners[i + 4].sub(camera_corners[i]);
 
@@ -285,7 +321,6 @@ impl ShadowMapFramebuffer {
             let max_extents = cgmath::Vector3::new(radius, radius, radius);
             let min_extents = -max_extents;
 
-            let light_dir = (Vector3::new(0.70, 0.25, -0.67)).normalize();
             let light_pos = frustum_center - light_dir * (-min_extents.z);
 
             let view: Matrix4<f32> = cgmath::Matrix4::look_at_rh(
@@ -307,14 +342,15 @@ impl ShadowMapFramebuffer {
                 0.0, 0.0, 0.5, 0.0,
                 0.0, 0.0, 0.5, 1.0,
             );
-            let split_depth = (camera.near_clip + split_dist * clip_range) * -1.0;
+            let split_depth = -(near_clip + split_dist * clip_range);
+            let split_corners = SPLIT_CORNER_ORDER.map(|idx| Point3::from_vec(camera_corners[idx]));
             cascades.push(CascadeInfo {
                 view_proj_mat: corr_matrix * proj * view,
                 max_z: split_depth,
+                split_corners,
             });
-            println!("cascade_idx={:?}, min={:?}, max={:?}, split_depth={:?}", cascade_index, min_extents, max_extents, split_depth);
 
-            last_split_dist = camera_splits[cascade_index];
+            last_split_dist = split_dist;
         }
 
         cascades
@@ -325,14 +361,17 @@ impl Drop for ShadowMapFramebuffer {
     fn drop(&mut self) {
         unsafe {
             self.device.destroy_image_view(self.view, None);
+            object_tracker::destroyed(ObjectKind::ImageView);
             self.device.destroy_image(self.image, None);
+            object_tracker::destroyed(ObjectKind::Image);
             self.device.free_memory(self.memory, None);
-            self.device.destroy_render_pass(self.render_pass, None);
+            memory_tracker::freed(self.memory);
         }
     }
 }
 
-fn create_render_pass(device: &ash::Device, depth_format: vk::Format) -> vk::RenderPass {
+// Shared by cascade and point light shadow maps, the pass is the same for both
+pub(super) fn create_render_pass(env: &RenderEnv, depth_format: vk::Format) -> vk::RenderPass {
     let attachments = [vk::AttachmentDescription {
         flags: vk::AttachmentDescriptionFlags::empty(),
         format: depth_format,
@@ -398,7 +437,7 @@ fn create_render_pass(device: &ash::Device, depth_format: vk::Format) -> vk::Ren
         p_dependencies: subpass_deps.as_ptr(),
     };
 
-    unsafe {
-        device.create_render_pass(&render_pass_create_info, None).unwrap()
-    }
+    env.render_passes()
+        .get_or_create(&render_pass_create_info)
+        .expect("Failed to create render pass!")
 }
@@ -0,0 +1,64 @@
+// Shadow filtering and depth bias, tuned from the gui
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ShadowSettings {
+    // distance between PCF taps in shadow map texels
+    pub pcf_radius: f32,
+    // taps per side of the PCF kernel (even counts round up to odd), 1 - single hard test
+    pub pcf_samples: u32,
+    // rasterizer depth bias of shadow casters
+    pub depth_bias_constant: f32,
+    pub depth_bias_slope: f32,
+    // debug coloring of cascades in the composite pass
+    pub show_cascades: bool,
+    // light frusta of the cascades and the camera slices they cover as debug lines
+    pub show_cascade_frusta: bool,
+    // keeps the frusta of the moment it was turned on, to look at them from aside
+    pub freeze_cascade_frusta: bool,
+}
+
+// Same colors as the cascade coloring in compose.frag
+pub const CASCADE_DEBUG_COLORS: [[f32; 3]; 4] = [
+    [1.0, 0.25, 0.25],
+    [0.25, 1.0, 0.25],
+    [0.25, 0.25, 1.0],
+    [1.0, 1.0, 0.25],
+];
+
+impl ShadowSettings {
+    pub fn new() -> ShadowSettings {
+        ShadowSettings {
+            pcf_radius: 0.75,
+            pcf_samples: 5,
+            depth_bias_constant: 1.25,
+            depth_bias_slope: 1.75,
+            show_cascades: false,
+            show_cascade_frusta: false,
+            freeze_cascade_frusta: false,
+        }
+    }
+
+    // compose.frag shadowParams.yz: tap spacing in texels and kernel half size
+    pub fn pcf_params(&self) -> [f32; 2] {
+        [self.pcf_radius, (self.pcf_samples.max(1) / 2) as f32]
+    }
+
+    pub fn depth_bias(&self) -> (f32, f32) {
+        (self.depth_bias_constant, self.depth_bias_slope)
+    }
+
+    // Returns true when depth bias changed, shadow command buffers must be re-recorded then
+    pub fn ui(&mut self, ui: &mut egui::Ui) -> bool {
+        ui.add(egui::Slider::new(&mut self.pcf_radius, 0.0..=3.0).text("PCF radius"));
+        ui.add(egui::Slider::new(&mut self.pcf_samples, 1..=9).text("PCF samples"));
+        ui.checkbox(&mut self.show_cascades, "Show cascades");
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.show_cascade_frusta, "Cascade frusta");
+            ui.checkbox(&mut self.freeze_cascade_frusta, "Freeze");
+        });
+
+        let mut changed = ui.add(egui::Slider::new(&mut self.depth_bias_constant, 0.0..=8.0).text("Depth bias")).changed();
+        changed |= ui.add(egui::Slider::new(&mut self.depth_bias_slope, 0.0..=8.0).text("Slope bias")).changed();
+
+        changed
+    }
+}
@@ -0,0 +1,375 @@
+use std::ptr;
+use std::sync::Arc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use ash_render_env::descriptor_set::DescriptorSet;
+use ash_render_env::env::RenderEnv;
+use ash_render_env::frame_buffer::{AttachmentDesciption, Framebuffer};
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::pipeline_builder::{Pipeline, PipelineBuilder};
+use ash_render_env::primary_cmd_buffer::PrimaryCommandBuffer;
+use ash_render_env::shader;
+use ash_render_env::std140_struct;
+use ash_render_env::swapchain::OutputEncoding;
+use ash_render_env::uniform_buffer::UniformBuffer;
+
+use crate::utils::frame_passes;
+
+// Levels of the blur chain, the first one is half of the scene resolution
+const LEVEL_COUNT: usize = 5;
+const FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+std140_struct! {
+    struct Uniforms {
+        // x - threshold, y - soft knee, z - intensity
+        params: [f32; 4],
+    }
+}
+
+// Offscreen fullscreen draw of the chain
+struct BloomPass {
+    framebuffer: vk::Framebuffer,
+    render_pass: vk::RenderPass,
+    // per frame in flight when the pass reads the uniforms, a single one otherwise.
+    // Descriptor sets must live while the command buffers are used
+    _descriptor_sets: Vec<DescriptorSet>,
+    second_buffers: Vec<vk::CommandBuffer>,
+    draw_command: PrimaryCommandBuffer,
+}
+
+// Bright parts of the HDR scene are extracted into a half resolution buffer, blurred by
+// a downsample chain and accumulated back up. The final pass adds the result to the scene
+// while copying it to the swapchain.
+pub struct Bloom {
+    pub enabled: bool,
+    // scene brightness where bloom starts
+    pub threshold: f32,
+    // 0 - hard threshold, 1 - smooth ramp from zero
+    pub soft_knee: f32,
+    pub intensity: f32,
+
+    down: Vec<Framebuffer>,
+    up: Vec<Framebuffer>,
+    passes: Vec<BloomPass>,
+
+    bright_pipeline: Pipeline,
+    down_pipeline: Pipeline,
+    up_pipeline: Pipeline,
+    composite_pipeline: Pipeline,
+    composite_render_pass: vk::RenderPass,
+    // per frame in flight, created with the chain on resize
+    composite_descriptor_sets: Vec<DescriptorSet>,
+    composite_buffers: Vec<vk::CommandBuffer>,
+
+    sampler: vk::Sampler,
+    // per frame in flight
+    uniform_buffers: Vec<UniformBuffer<Uniforms>>,
+    max_inflight_frames: usize,
+
+    env: Arc<RenderEnv>,
+}
+
+impl Bloom {
+    // `composite_render_pass` is the final (swapchain) pass, its framebuffer has the size of the scene.
+    // The composite is encoded with `encoding` there
+    pub fn new(env: Arc<RenderEnv>, scene: &Framebuffer, composite_render_pass: vk::RenderPass, encoding: OutputEncoding,
+               max_inflight_frames: usize) -> Bloom {
+        let down: Vec<_> = (0..LEVEL_COUNT).map(|_| Self::create_down_level(&env)).collect();
+        let up: Vec<_> = (0..LEVEL_COUNT - 1).map(|_| Self::create_level(&env)).collect();
+
+        // all levels have the same attachments, so their render passes are compatible
+        let level_render_pass = down[0].render_pass();
+        let bright_pipeline = Self::create_pipeline(&env, level_render_pass, "assets/shaders/spv/bloom_bright.frag.spv", None);
+        let down_pipeline = Self::create_pipeline(&env, level_render_pass, "assets/shaders/spv/bloom_downsample.frag.spv", None);
+        let up_pipeline = Self::create_pipeline(&env, level_render_pass, "assets/shaders/spv/bloom_upsample.frag.spv", None);
+        let composite_pipeline = Self::create_pipeline(&env, composite_render_pass, "assets/shaders/spv/bloom_composite.frag.spv",
+                                                       Some(encoding.add_constants(shader::ConstantsBuilder::new())));
+
+        let sampler_create_info = vk::SamplerCreateInfo::builder()
+            .min_filter(vk::Filter::LINEAR)
+            .mag_filter(vk::Filter::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+
+        let sampler = unsafe {
+            env.device().create_sampler(&sampler_create_info, None).unwrap()
+        };
+        object_tracker::created(ObjectKind::Sampler);
+
+        let uniform_buffers = (0..max_inflight_frames)
+            .map(|_| UniformBuffer::new(env.clone()))
+            .collect();
+
+        let mut bloom = Bloom {
+            enabled: true,
+            threshold: 1.0,
+            soft_knee: 0.5,
+            intensity: 0.1,
+            down,
+            up,
+            passes: vec![],
+            bright_pipeline,
+            down_pipeline,
+            up_pipeline,
+            composite_pipeline,
+            composite_render_pass,
+            composite_descriptor_sets: vec![],
+            composite_buffers: vec![],
+            sampler,
+            uniform_buffers,
+            max_inflight_frames,
+            env,
+        };
+        bloom.resize(scene);
+
+        bloom
+    }
+
+    fn create_level(env: &Arc<RenderEnv>) -> Framebuffer {
+        Framebuffer::new(env.clone(), vec![
+            AttachmentDesciption {
+                format: FORMAT,
+                samples_count: vk::SampleCountFlags::TYPE_1,
+            },
+        ])
+    }
+
+    // Down levels are read only by the bloom passes, up levels by the composite too
+    fn create_down_level(env: &Arc<RenderEnv>) -> Framebuffer {
+        let mut framebuffer = Self::create_level(env);
+        framebuffer.set_transient(frame_passes::range(frame_passes::BLOOM, frame_passes::BLOOM));
+        framebuffer
+    }
+
+    fn create_pipeline(env: &RenderEnv, render_pass: vk::RenderPass, frag_shader: &str, constants: Option<shader::ConstantsBuilder>) -> Pipeline {
+        let vert_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/compose.vert.spv").expect("Failed to load shader!");
+        let mut frag_shader_module = shader::Shader::load(env.device(), frag_shader).expect("Failed to load shader!");
+        if let Some(constants) = constants {
+            frag_shader_module = frag_shader_module.specialize(constants);
+        }
+
+        PipelineBuilder::new(env.device().clone(), render_pass, 0)
+            .pipeline_cache(env.pipeline_cache())
+            .vertex_shader(vert_shader_module)
+            .fragment_shader(frag_shader_module)
+            .build()
+            .expect("Failed to create pipeline!")
+    }
+
+    fn level_dimensions(dimensions: [u32; 2], level: usize) -> [u32; 2] {
+        [(dimensions[0] >> (level + 1)).max(1), (dimensions[1] >> (level + 1)).max(1)]
+    }
+
+    // Device must be idle
+    pub fn resize(&mut self, scene: &Framebuffer) {
+        let dimensions = scene.dimensions();
+        for (level, framebuffer) in self.down.iter_mut().enumerate() {
+            framebuffer.resize_swapchain(Self::level_dimensions(dimensions, level));
+        }
+        for (level, framebuffer) in self.up.iter_mut().enumerate() {
+            framebuffer.resize_swapchain(Self::level_dimensions(dimensions, level));
+        }
+
+        self.free_command_buffers();
+        let mut passes = Vec::with_capacity(self.down.len() + self.up.len());
+
+        let bright_sets = self.uniform_buffers.iter()
+            .map(|uniform_buffer| DescriptorSet::builder(self.env.device(), &self.bright_pipeline.descriptor_set_layouts[0])
+                .add_attachment(&scene.attachments[0], self.sampler)
+                .add_buffer(uniform_buffer.buffer)
+                .build()
+                .expect("Failed to create descriptor set!"))
+            .collect();
+        passes.push(self.create_pass(&self.bright_pipeline, bright_sets, &self.down[0], "bloom bright pass"));
+
+        for level in 1..self.down.len() {
+            let set = DescriptorSet::builder(self.env.device(), &self.down_pipeline.descriptor_set_layouts[0])
+                .add_attachment(&self.down[level - 1].attachments[0], self.sampler)
+                .build()
+                .expect("Failed to create descriptor set!");
+            passes.push(self.create_pass(&self.down_pipeline, vec![set], &self.down[level], &format!("bloom down {} pass", level)));
+        }
+
+        for level in (0..self.up.len()).rev() {
+            let lower = self.up.get(level + 1).unwrap_or(&self.down[level + 1]);
+            let set = DescriptorSet::builder(self.env.device(), &self.up_pipeline.descriptor_set_layouts[0])
+                .add_attachment(&lower.attachments[0], self.sampler)
+                .add_attachment(&self.down[level].attachments[0], self.sampler)
+                .build()
+                .expect("Failed to create descriptor set!");
+            passes.push(self.create_pass(&self.up_pipeline, vec![set], &self.up[level], &format!("bloom up {} pass", level)));
+        }
+        self.passes = passes;
+
+        let result = self.up.first().unwrap_or(&self.down[0]);
+        self.composite_descriptor_sets = self.uniform_buffers.iter()
+            .map(|uniform_buffer| DescriptorSet::builder(self.env.device(), &self.composite_pipeline.descriptor_set_layouts[0])
+                .add_attachment(&scene.attachments[0], self.sampler)
+                .add_attachment(&result.attachments[0], self.sampler)
+                .add_buffer(uniform_buffer.buffer)
+                .build()
+                .expect("Failed to create descriptor set!"))
+            .collect();
+        self.composite_buffers = self.composite_descriptor_sets.iter()
+            .map(|descriptor_set| Self::build_cmd_buf(&self.env, &self.composite_pipeline, descriptor_set,
+                                                      self.composite_render_pass, dimensions))
+            .collect();
+    }
+
+    fn create_pass(&self, pipeline: &Pipeline, descriptor_sets: Vec<DescriptorSet>, target: &Framebuffer, name: &str) -> BloomPass {
+        let second_buffers = descriptor_sets.iter()
+            .map(|descriptor_set| Self::build_cmd_buf(&self.env, pipeline, descriptor_set, target.render_pass(), target.dimensions()))
+            .collect();
+        let mut draw_command = PrimaryCommandBuffer::new(self.env.clone(), self.max_inflight_frames);
+        draw_command.set_name(name);
+        draw_command.set_dimensions(target.dimensions());
+
+        BloomPass {
+            framebuffer: target.framebuffer.unwrap(),
+            render_pass: target.render_pass(),
+            _descriptor_sets: descriptor_sets,
+            second_buffers,
+            draw_command,
+        }
+    }
+
+    fn build_cmd_buf(env: &RenderEnv, pipeline: &Pipeline, descriptor_set: &DescriptorSet, render_pass: vk::RenderPass, dimensions: [u32; 2]) -> vk::CommandBuffer {
+        let device = env.device();
+        let cmd_buf = env.create_secondary_command_buffer();
+
+        let inheritance_info = vk::CommandBufferInheritanceInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_INHERITANCE_INFO,
+            p_next: ptr::null(),
+            render_pass,
+            subpass: 0,
+            framebuffer: vk::Framebuffer::null(),
+            occlusion_query_enable: 0,
+            query_flags: Default::default(),
+            pipeline_statistics: Default::default(),
+        };
+
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_BEGIN_INFO,
+            p_next: ptr::null(),
+            p_inheritance_info: &inheritance_info,
+            flags: vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE | vk::CommandBufferUsageFlags::SIMULTANEOUS_USE,
+        };
+
+        let viewports = [vk::Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: dimensions[0] as f32,
+            height: dimensions[1] as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        }];
+
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: vk::Extent2D {
+                width: dimensions[0],
+                height: dimensions[1],
+            },
+        }];
+
+        unsafe {
+            device
+                .begin_command_buffer(cmd_buf, &command_buffer_begin_info)
+                .expect("Failed to begin recording Command Buffer at beginning!");
+
+            device.cmd_set_viewport(cmd_buf, 0, viewports.as_ref());
+            device.cmd_set_scissor(cmd_buf, 0, scissors.as_ref());
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pipeline.graphics_pipeline);
+
+            let descriptor_sets_to_bind = [descriptor_set.set];
+            device.cmd_bind_descriptor_sets(
+                cmd_buf,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.pipeline_layout,
+                0,
+                &descriptor_sets_to_bind,
+                &[],
+            );
+
+            device.cmd_draw(cmd_buf, 3, 1, 0, 0);
+
+            device
+                .end_command_buffer(cmd_buf)
+                .expect("Failed to record Command Buffer at Ending!");
+        }
+
+        cmd_buf
+    }
+
+    fn free_command_buffers(&mut self) {
+        let mut buffers: Vec<_> = self.passes.iter().flat_map(|pass| pass.second_buffers.iter().copied()).collect();
+        buffers.extend(self.composite_buffers.iter().copied());
+
+        if !buffers.is_empty() {
+            unsafe {
+                self.env.device().free_command_buffers(self.env.command_pool(), &buffers);
+            }
+        }
+        self.passes.clear();
+        self.composite_buffers.clear();
+    }
+
+    // Uniforms of `frame` must not be in use by GPU
+    pub fn update(&mut self, frame: usize) {
+        let intensity = if self.enabled { self.intensity } else { 0.0 };
+        self.uniform_buffers[frame].write_data(Uniforms {
+            params: [self.threshold, self.soft_knee, intensity, 0.0],
+        });
+    }
+
+    // Primary command buffers of the chain, must run after the scene is rendered.
+    // Empty when bloom is disabled
+    pub fn draw(&mut self, frame: usize) -> Vec<vk::CommandBuffer> {
+        if !self.enabled {
+            return vec![];
+        }
+
+        let clear_values = vec![vk::ClearValue {
+            color: vk::ClearColorValue {
+                float32: [0.0, 0.0, 0.0, 0.0],
+            },
+        }];
+
+        self.passes.iter_mut()
+            .map(|pass| pass.draw_command.execute_secondary(
+                clear_values.clone(),
+                pass.framebuffer,
+                pass.render_pass,
+                &[pass.second_buffers[frame % pass.second_buffers.len()]],
+            ))
+            .collect()
+    }
+
+    // Secondary command buffer of the final pass: copies the scene with bloom added
+    pub fn composite_buffer(&self, frame: usize) -> vk::CommandBuffer {
+        self.composite_buffers[frame]
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.checkbox(&mut self.enabled, "Bloom");
+        ui.add(egui::Slider::new(&mut self.threshold, 0.0..=4.0).text("Threshold"));
+        ui.add(egui::Slider::new(&mut self.soft_knee, 0.0..=1.0).text("Soft knee"));
+        ui.add(egui::Slider::new(&mut self.intensity, 0.0..=1.0).text("Intensity"));
+    }
+}
+
+impl Drop for Bloom {
+    fn drop(&mut self) {
+        self.free_command_buffers();
+        unsafe {
+            self.env.device().destroy_sampler(self.sampler, None);
+            object_tracker::destroyed(ObjectKind::Sampler);
+        }
+        for framebuffer in self.down.iter().chain(self.up.iter()) {
+            framebuffer.destroy();
+        }
+    }
+}
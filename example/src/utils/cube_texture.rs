@@ -1,10 +1,126 @@
+use std::fs::File;
+use std::io::BufReader;
 use std::path::Path;
+use std::sync::Arc;
 
 use ash::version::{DeviceV1_0};
 use ash::vk;
-use image::GenericImageView;
+use ash_render_env::env::RenderEnv;
+use ash_render_env::memory_tracker;
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::queue_guard::QueueGuard;
+use ash_render_env::utils::texture::ImageData;
 use ash_render_env::utils::texture_utils::{create_texture_image, create_image_view, create_texture_sampler};
+use image::codecs::hdr::HdrDecoder;
 
+use crate::utils::environment_map::{self, ENVIRONMENT_FORMAT};
+
+const FACE_COUNT: u32 = 6;
+const FACE_PLUS_Z: usize = 4;
+const FACE_MINUS_Z: usize = 5;
+
+// Fixes for face images made for another convention, applied to all six faces
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FaceOrientation {
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+    pub swap_front_back: bool,
+}
+
+impl FaceOrientation {
+    // `faces` - six square faces stacked in +X, -X, +Y, -Y, +Z, -Z order
+    fn apply(&self, faces: &mut ImageData) {
+        let size = faces.width as usize;
+        let row = size * 4;
+        let face_len = row * size;
+
+        for face in faces.pixels.chunks_exact_mut(face_len) {
+            if self.flip_horizontal {
+                for texels in face.chunks_exact_mut(row) {
+                    for x in 0..size / 2 {
+                        for c in 0..4 {
+                            texels.swap(x * 4 + c, (size - 1 - x) * 4 + c);
+                        }
+                    }
+                }
+            }
+
+            if self.flip_vertical {
+                for y in 0..size / 2 {
+                    let (top, bottom) = face.split_at_mut((size - 1 - y) * row);
+                    top[y * row..(y + 1) * row].swap_with_slice(&mut bottom[..row]);
+                }
+            }
+        }
+
+        if self.swap_front_back {
+            let (front, back) = faces.pixels.split_at_mut(FACE_MINUS_Z * face_len);
+            front[FACE_PLUS_Z * face_len..].swap_with_slice(&mut back[..face_len]);
+        }
+    }
+}
+
+// Faces of a single image. Crosses have +Z in the middle with -X, +X on its sides and +Y, -Y above and below,
+// -Z is right of +X in the horizontal cross and below -Y, upside down, in the vertical one.
+// Strips have the faces in +X, -X, +Y, -Y, +Z, -Z order
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum CubeLayout {
+    HorizontalCross,
+    VerticalCross,
+    HorizontalStrip,
+    VerticalStrip,
+}
+
+impl CubeLayout {
+    // By the aspect ratio, with the face size
+    fn detect(width: u32, height: u32) -> Option<(CubeLayout, u32)> {
+        if width * 3 == height * 4 {
+            Some((CubeLayout::HorizontalCross, width / 4))
+        } else if width * 4 == height * 3 {
+            Some((CubeLayout::VerticalCross, width / 3))
+        } else if width == height * 6 {
+            Some((CubeLayout::HorizontalStrip, height))
+        } else if height == width * 6 {
+            Some((CubeLayout::VerticalStrip, width))
+        } else {
+            None
+        }
+    }
+
+    // (column, row, rotated by 180 degrees) of the faces in +X, -X, +Y, -Y, +Z, -Z order
+    fn cells(&self) -> [(u32, u32, bool); FACE_COUNT as usize] {
+        match self {
+            CubeLayout::HorizontalCross => [(2, 1, false), (0, 1, false), (1, 0, false), (1, 2, false), (1, 1, false), (3, 1, false)],
+            CubeLayout::VerticalCross => [(2, 1, false), (0, 1, false), (1, 0, false), (1, 2, false), (1, 1, false), (1, 3, true)],
+            CubeLayout::HorizontalStrip => [(0, 0, false), (1, 0, false), (2, 0, false), (3, 0, false), (4, 0, false), (5, 0, false)],
+            CubeLayout::VerticalStrip => [(0, 0, false), (0, 1, false), (0, 2, false), (0, 3, false), (0, 4, false), (0, 5, false)],
+        }
+    }
+
+    // Six faces stacked as load_faces() does
+    fn slice(&self, image: &ImageData, face_size: u32) -> ImageData {
+        let size = face_size as usize;
+        let stride = image.width as usize * 4;
+        let mut pixels = Vec::with_capacity(size * size * 4 * FACE_COUNT as usize);
+
+        for &(column, row, rotated) in self.cells().iter() {
+            let origin = row as usize * size * stride + column as usize * size * 4;
+            for y in 0..size {
+                if rotated {
+                    let src = origin + (size - 1 - y) * stride;
+                    for x in (0..size).rev() {
+                        pixels.extend_from_slice(&image.pixels[src + x * 4..src + x * 4 + 4]);
+                    }
+                } else {
+                    let src = origin + y * stride;
+                    pixels.extend_from_slice(&image.pixels[src..src + size * 4]);
+                }
+            }
+        }
+
+        ImageData { pixels, width: face_size, height: face_size }
+    }
+}
 
 #[allow(dead_code)]
 pub struct CubeTexture {
@@ -14,19 +130,14 @@ pub struct CubeTexture {
 
     pub texture_image_view: vk::ImageView,
     pub texture_sampler: vk::Sampler,
-    _mip_levels: u32,
+    mip_levels: u32,
     format: vk::Format,
 }
 
 
 impl CubeTexture {
-    pub fn new(
-        device: ash::Device,
-        command_pool: vk::CommandPool,
-        submit_queue: vk::Queue,
-        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
-        image_path: &Path,
-    ) -> CubeTexture {
+    // Six faces of the directory stacked in one image, uploaded by CubeTexture::upload
+    pub fn load_faces(image_path: &Path, orientation: FaceOrientation) -> Result<ImageData, String> {
         // Face order: +X, -X, +Y, -Y, +Z, -Z
         // FROM: https://www.khronos.org/registry/vulkan/specs/1.2-extensions/man/html/VkImageSubresourceRange.html#_description
         let faces = [
@@ -38,51 +149,167 @@ impl CubeTexture {
             "back.jpg",
         ];
 
-        let mut initialized = false;
-        let mut image_width = 0;
-        let mut image_height = 0;
-        let mut image_array_data = Vec::new();
-
+        let mut cube: Option<ImageData> = None;
         for face in faces.iter() {
-            let image_object = image::open(image_path.join(face)).unwrap();
+            let image = ImageData::load(&image_path.join(face), false)?;
+
+            match cube.as_mut() {
+                None => {
+                    let mut pixels = Vec::with_capacity(image.pixels.len() * faces.len());
+                    pixels.extend(image.pixels);
+                    cube = Some(ImageData { pixels, width: image.width, height: image.height });
+                }
+                Some(cube) => {
+                    if (image.width, image.height) != (cube.width, cube.height) {
+                        return Err(format!("Cube face {} is {}x{}, expected {}x{}", face, image.width, image.height, cube.width, cube.height));
+                    }
+                    cube.pixels.extend(image.pixels);
+                }
+            }
+        }
 
-            let image_data = match &image_object {
-                image::DynamicImage::ImageLumaA8(_)
-                | image::DynamicImage::ImageBgra8(_)
-                | image::DynamicImage::ImageRgba8(_) => image_object.to_rgba8().into_raw(),
-                _ => image_object.to_rgba8().into_raw(),
-            };
+        let mut cube = cube.unwrap();
+        if cube.width != cube.height {
+            return Err(format!("Cube faces are {}x{}, expected square ones", cube.width, cube.height));
+        }
+        orientation.apply(&mut cube);
+
+        Ok(cube)
+    }
+
+    // Cross or strip of the six faces in one image, sliced into the layout of load_faces()
+    pub fn load_cross(image_path: &Path, orientation: FaceOrientation) -> Result<ImageData, String> {
+        let image = ImageData::load(image_path, false)?;
+        let (layout, face_size) = CubeLayout::detect(image.width, image.height)
+            .ok_or_else(|| format!("Skybox {} is {}x{}, expected a 4:3 or 3:4 cross or a 6:1 or 1:6 strip",
+                                   image_path.display(), image.width, image.height))?;
 
-            if !initialized {
-                image_width = image_object.width();
-                image_height = image_object.height();
+        let mut faces = layout.slice(&image, face_size);
+        orientation.apply(&mut faces);
 
-                image_array_data.reserve_exact((4 * image_width * image_height) as usize * faces.len());
-                initialized = true;
+        Ok(faces)
+    }
+
+    // Radiance .hdr panorama, stored as RGBE in RGBA8 pixels: rgb * 2^(a - 136)
+    pub fn load_equirect(image_path: &Path) -> Result<ImageData, String> {
+        let file = File::open(image_path)
+            .map_err(|err| format!("Failed to open image {}: {}", image_path.display(), err))?;
+        let decoder = HdrDecoder::new(BufReader::new(file))
+            .map_err(|err| format!("Failed to load image {}: {}", image_path.display(), err))?;
+        let metadata = decoder.metadata();
+        let texels = decoder.read_image_hdr()
+            .map_err(|err| format!("Failed to load image {}: {}", image_path.display(), err))?;
+
+        let mut pixels = Vec::with_capacity(texels.len() * 4);
+        for texel in texels {
+            let [r, g, b] = texel.0;
+            let max = r.max(g).max(b);
+            if max < 1e-32 {
+                pixels.extend_from_slice(&[0, 0, 0, 0]);
+                continue;
             }
 
-            image_array_data.extend(image_data);
+            let exponent = max.log2().floor() as i32 + 1;
+            let scale = 256.0 / 2.0f32.powi(exponent);
+            pixels.extend_from_slice(&[
+                (r * scale).min(255.0) as u8,
+                (g * scale).min(255.0) as u8,
+                (b * scale).min(255.0) as u8,
+                (exponent + 128).clamp(0, 255) as u8,
+            ]);
+        }
+
+        Ok(ImageData { pixels, width: metadata.width, height: metadata.height })
+    }
+
+    // Cube map rendered from an equirectangular panorama, with GGX prefiltered mips
+    pub fn from_equirect(env: &Arc<RenderEnv>, equirect: &ImageData) -> CubeTexture {
+        let device = env.device().clone();
+
+        // RGBE can't be filtered, equirect_to_cube.frag decodes texels before blending them
+        let (equirect_image, equirect_memory, _) = create_texture_image(
+            &device, env.command_pool(), env.queue(), &env.mem_properties, vk::Format::R8G8B8A8_UNORM,
+            &equirect.pixels, equirect.width, equirect.height, 1, false)
+            .expect("Failed to create equirect image!");
+        let equirect_view = create_image_view(&device, equirect_image, vk::Format::R8G8B8A8_UNORM, vk::ImageAspectFlags::COLOR, 1, 1)
+            .expect("Failed to create equirect image view!");
+        let equirect_sampler = create_texture_sampler(&device, 1, 1.0)
+            .expect("Failed to create equirect sampler!");
+
+        let face_size = environment_map::face_size(equirect.width);
+        let (texture_image, texture_image_memory, mip_levels) =
+            environment_map::render_environment(env, equirect_view, equirect_sampler, face_size);
+
+        unsafe {
+            device.destroy_sampler(equirect_sampler, None);
+            object_tracker::destroyed(ObjectKind::Sampler);
+            device.destroy_image_view(equirect_view, None);
+            object_tracker::destroyed(ObjectKind::ImageView);
+            device.destroy_image(equirect_image, None);
+            object_tracker::destroyed(ObjectKind::Image);
+            device.free_memory(equirect_memory, None);
+            memory_tracker::freed(equirect_memory);
+        }
+
+        let texture_image_view = create_image_view(
+            &device, texture_image, ENVIRONMENT_FORMAT,
+            vk::ImageAspectFlags::COLOR,
+            mip_levels, FACE_COUNT)
+            .expect("Failed to create cube texture image view!");
+        let texture_sampler = create_texture_sampler(&device, mip_levels, 1.0)
+            .expect("Failed to create cube texture sampler!");
+
+        CubeTexture {
+            device,
+            texture_image,
+            texture_image_memory,
+            texture_image_view,
+            texture_sampler,
+            mip_levels,
+            format: ENVIRONMENT_FORMAT,
         }
+    }
+
+    pub fn mip_levels(&self) -> u32 {
+        self.mip_levels
+    }
+
+    pub fn upload(env: &Arc<RenderEnv>, faces: &ImageData) -> CubeTexture {
+        CubeTexture::from_pixels(env.device().clone(), env.transfer_command_pool(), env.transfer_queue(), &env.mem_properties,
+                                 vk::Format::R8G8B8A8_SRGB, &faces.pixels, faces.width, faces.height, FACE_COUNT, true, env.max_sampler_anisotropy())
+    }
+
+    // Flat sky color until the skybox is loaded
+    pub fn placeholder(env: &Arc<RenderEnv>) -> CubeTexture {
+        let faces = ImageData {
+            pixels: [150u8, 180, 210, 255].repeat(FACE_COUNT as usize),
+            width: 1,
+            height: 1,
+        };
 
-        CubeTexture::from_pixels(device, command_pool, submit_queue, device_memory_properties, vk::Format::R8G8B8A8_SRGB,
-                                 &image_array_data, image_width, image_height, faces.len() as u32, true)
+        CubeTexture::from_pixels(env.device().clone(), env.transfer_command_pool(), env.transfer_queue(), &env.mem_properties,
+                                 vk::Format::R8G8B8A8_SRGB, &faces.pixels, 1, 1, FACE_COUNT, false, env.max_sampler_anisotropy())
     }
 
     pub fn from_pixels(device: ash::Device,
                        command_pool: vk::CommandPool,
-                       submit_queue: vk::Queue,
+                       submit_queue: &QueueGuard,
                        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
                        format: vk::Format,
-                       pixel_data: &Vec<u8>, width: u32, height: u32, array_size: u32, create_mips: bool) -> CubeTexture
+                       pixel_data: &Vec<u8>, width: u32, height: u32, array_size: u32, create_mips: bool,
+                       max_anisotropy: f32) -> CubeTexture
     {
         let (texture_image, texture_image_memory, mip_levels) = create_texture_image(
-            &device, command_pool, submit_queue, device_memory_properties, format, pixel_data, width, height, array_size, create_mips);
+            &device, command_pool, submit_queue, device_memory_properties, format, pixel_data, width, height, array_size, create_mips)
+            .expect("Failed to create cube texture image!");
 
         let texture_image_view = create_image_view(
             &device, texture_image, format,
             vk::ImageAspectFlags::COLOR,
-            mip_levels, array_size);
-        let texture_sampler = create_texture_sampler(&device, mip_levels);
+            mip_levels, array_size)
+            .expect("Failed to create cube texture image view!");
+        let texture_sampler = create_texture_sampler(&device, mip_levels, max_anisotropy)
+            .expect("Failed to create cube texture sampler!");
 
         CubeTexture {
             device,
@@ -90,7 +317,7 @@ impl CubeTexture {
             texture_image_memory,
             texture_image_view,
             texture_sampler,
-            _mip_levels: mip_levels,
+            mip_levels,
             format,
         }
     }
@@ -100,9 +327,13 @@ impl Drop for CubeTexture {
     fn drop(&mut self) {
         unsafe {
             self.device.destroy_sampler(self.texture_sampler, None);
+            object_tracker::destroyed(ObjectKind::Sampler);
             self.device.destroy_image_view(self.texture_image_view, None);
+            object_tracker::destroyed(ObjectKind::ImageView);
             self.device.destroy_image(self.texture_image, None);
+            object_tracker::destroyed(ObjectKind::Image);
             self.device.free_memory(self.texture_image_memory, None);
+            memory_tracker::freed(self.texture_image_memory);
         }
     }
 }
@@ -0,0 +1,440 @@
+use std::ptr;
+use std::sync::Arc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+use cgmath::{Matrix4, Point3, SquareMatrix, Transform, Vector3, Vector4};
+
+use ash_render_env::bvh::Aabb;
+use ash_render_env::camera::CameraDescriptor;
+use ash_render_env::descriptor_set::DescriptorSet;
+use ash_render_env::env::RenderEnv;
+use ash_render_env::frame_buffer::Framebuffer;
+use ash_render_env::memory_tracker;
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::pipeline_builder::{Pipeline, PipelineBuilder};
+use ash_render_env::shader;
+use ash_render_env::std140_struct;
+use ash_render_env::uniform_buffer::UniformBuffer;
+use ash_render_env::utils::buffer_utils::create_buffer;
+
+use crate::utils::gbuffer::reader_shader_path;
+
+// Vertices of one frame, lines past it are dropped
+pub const MAX_DEBUG_VERTICES: usize = 65536;
+
+// Segments of every circle of a sphere
+const SPHERE_SEGMENTS: usize = 32;
+
+const BOX_EDGES: [(usize, usize); 12] = [
+    (0, 1), (1, 3), (3, 2), (2, 0),
+    (4, 5), (5, 7), (7, 6), (6, 4),
+    (0, 4), (1, 5), (2, 6), (3, 7),
+];
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DebugVertex {
+    position: [f32; 3],
+    color: [f32; 4],
+}
+
+// Text at a world position, painted by the gui over the scene
+#[derive(Clone, Debug)]
+pub struct DebugLabel {
+    pub position: Point3<f32>,
+    pub text: String,
+    pub color: [f32; 4],
+}
+
+std140_struct! {
+    struct Uniforms {
+        view_proj: Matrix4<f32>,
+    }
+}
+
+// Resources of one frame in flight, the command buffer is recorded once and draws whatever flush() wrote
+struct FrameResources {
+    vertex_buffer: (vk::Buffer, vk::DeviceMemory),
+    indirect_buffer: (vk::Buffer, vk::DeviceMemory),
+    uniforms: UniformBuffer<Uniforms>,
+    // used by the recorded command buffer
+    _descriptor_set: DescriptorSet,
+    second_buffer: vk::CommandBuffer,
+}
+
+// Immediate mode lines: shapes are queued from anywhere during the frame and drawn over the lit scene
+// in the HDR pass by flush(), the queue is emptied after that
+pub struct DebugDraw {
+    vertices: Vec<DebugVertex>,
+    dropped: usize,
+    labels: Vec<DebugLabel>,
+
+    pipeline: Pipeline,
+    gbuffer_samples: vk::SampleCountFlags,
+    render_pass: vk::RenderPass,
+    sampler: vk::Sampler,
+    frames: Vec<FrameResources>,
+    current_frame: usize,
+
+    env: Arc<RenderEnv>,
+}
+
+impl DebugDraw {
+    // `render_pass` is the HDR pass, lines behind G-buffer depth are faded
+    pub fn new(env: Arc<RenderEnv>, render_pass: vk::RenderPass, gbuffer: &Framebuffer, max_inflight_frames: usize) -> DebugDraw {
+        let gbuffer_samples = gbuffer.attachments.last().unwrap().samples;
+        let pipeline = Self::create_pipeline(&env, render_pass, gbuffer);
+
+        let sampler_create_info = vk::SamplerCreateInfo::builder()
+            .min_filter(vk::Filter::NEAREST)
+            .mag_filter(vk::Filter::NEAREST)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+
+        let sampler = unsafe {
+            env.device().create_sampler(&sampler_create_info, None).unwrap()
+        };
+        object_tracker::created(ObjectKind::Sampler);
+
+        let frames = (0..max_inflight_frames)
+            .map(|_| Self::create_frame(&env, &pipeline, render_pass, gbuffer, sampler))
+            .collect();
+
+        DebugDraw {
+            vertices: Vec::new(),
+            dropped: 0,
+            labels: Vec::new(),
+            pipeline,
+            gbuffer_samples,
+            render_pass,
+            sampler,
+            frames,
+            current_frame: 0,
+            env,
+        }
+    }
+
+    fn create_frame(env: &Arc<RenderEnv>, pipeline: &Pipeline, render_pass: vk::RenderPass, gbuffer: &Framebuffer, sampler: vk::Sampler) -> FrameResources {
+        let vertex_buffer = create_buffer(
+            env.device(),
+            (std::mem::size_of::<DebugVertex>() * MAX_DEBUG_VERTICES) as u64,
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            &env.mem_properties,
+        );
+        let indirect_buffer = create_buffer(
+            env.device(),
+            std::mem::size_of::<vk::DrawIndirectCommand>() as u64,
+            vk::BufferUsageFlags::INDIRECT_BUFFER,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            &env.mem_properties,
+        );
+        // nothing is drawn until the first flush
+        Self::write_memory(env.device(), indirect_buffer.1, &[vk::DrawIndirectCommand::default()]);
+
+        let uniforms = UniformBuffer::new(env.clone());
+        let descriptor_set = DescriptorSet::builder(env.device(), &pipeline.descriptor_set_layouts[0])
+            .add_buffer(uniforms.buffer)
+            .add_attachment(gbuffer.attachments.last().unwrap(), sampler)
+            .build()
+            .expect("Failed to create descriptor set!");
+
+        let second_buffer = Self::build_cmd_buf(env, pipeline, &descriptor_set, vertex_buffer.0, indirect_buffer.0, render_pass, gbuffer.dimensions());
+
+        FrameResources {
+            vertex_buffer,
+            indirect_buffer,
+            uniforms,
+            _descriptor_set: descriptor_set,
+            second_buffer,
+        }
+    }
+
+    fn destroy_frame(&self, frame: &FrameResources) {
+        let device = self.env.device();
+        unsafe {
+            device.free_command_buffers(self.env.command_pool(), &[frame.second_buffer]);
+
+            for &(buffer, memory) in [frame.vertex_buffer, frame.indirect_buffer].iter() {
+                device.destroy_buffer(buffer, None);
+                object_tracker::destroyed(ObjectKind::Buffer);
+                device.free_memory(memory, None);
+                memory_tracker::freed(memory);
+            }
+        }
+    }
+
+    fn write_memory<T: Copy>(device: &ash::Device, memory: vk::DeviceMemory, data: &[T]) {
+        if data.is_empty() {
+            return;
+        }
+
+        let size = std::mem::size_of_val(data) as u64;
+        unsafe {
+            let data_ptr = device
+                .map_memory(memory, 0, size, vk::MemoryMapFlags::empty())
+                .expect("Failed to Map Memory") as *mut T;
+
+            data_ptr.copy_from_nonoverlapping(data.as_ptr(), data.len());
+
+            device.unmap_memory(memory);
+        }
+    }
+
+    fn build_cmd_buf(env: &RenderEnv, pipeline: &Pipeline, descriptor_set: &DescriptorSet, vertex_buffer: vk::Buffer,
+                     indirect_buffer: vk::Buffer, render_pass: vk::RenderPass, dimensions: [u32; 2]) -> vk::CommandBuffer {
+        let device = env.device();
+        let cmd_buf = env.create_secondary_command_buffer();
+
+        let inheritance_info = vk::CommandBufferInheritanceInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_INHERITANCE_INFO,
+            p_next: ptr::null(),
+            render_pass,
+            subpass: 0,
+            framebuffer: vk::Framebuffer::null(),
+            occlusion_query_enable: 0,
+            query_flags: Default::default(),
+            pipeline_statistics: Default::default(),
+        };
+
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_BEGIN_INFO,
+            p_next: ptr::null(),
+            p_inheritance_info: &inheritance_info,
+            flags: vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE | vk::CommandBufferUsageFlags::SIMULTANEOUS_USE,
+        };
+
+        let viewports = [vk::Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: dimensions[0] as f32,
+            height: dimensions[1] as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        }];
+
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: vk::Extent2D {
+                width: dimensions[0],
+                height: dimensions[1],
+            },
+        }];
+
+        unsafe {
+            device
+                .begin_command_buffer(cmd_buf, &command_buffer_begin_info)
+                .expect("Failed to begin recording Command Buffer at beginning!");
+
+            device.cmd_set_viewport(cmd_buf, 0, viewports.as_ref());
+            device.cmd_set_scissor(cmd_buf, 0, scissors.as_ref());
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pipeline.graphics_pipeline);
+            descriptor_set.bind(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pipeline.pipeline_layout, 0, &[]);
+            device.cmd_bind_vertex_buffers(cmd_buf, 0, &[vertex_buffer], &[0]);
+
+            // vertex count is written by flush() every frame
+            device.cmd_draw_indirect(cmd_buf, indirect_buffer, 0, 1, std::mem::size_of::<vk::DrawIndirectCommand>() as u32);
+
+            device
+                .end_command_buffer(cmd_buf)
+                .expect("Failed to record Command Buffer at Ending!");
+        }
+
+        cmd_buf
+    }
+
+    fn create_pipeline(env: &RenderEnv, render_pass: vk::RenderPass, gbuffer: &Framebuffer) -> Pipeline {
+        let vert_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/debug_draw/debug_draw.vert.spv").expect("Failed to load shader!");
+        let frag_shader_module = shader::Shader::load(env.device(), &reader_shader_path("debug_draw/debug_draw", gbuffer.attachments.last().unwrap().samples)).expect("Failed to load shader!")
+            .specialize(shader::ConstantsBuilder::new()
+                .add_u32(gbuffer.depth_mode().is_reversed() as u32));
+
+        let bindings = vec![
+            vk::VertexInputBindingDescription {
+                binding: 0,
+                stride: std::mem::size_of::<DebugVertex>() as u32,
+                input_rate: vk::VertexInputRate::VERTEX,
+            },
+        ];
+        let attributes = vec![
+            vk::VertexInputAttributeDescription {
+                location: 0,
+                binding: 0,
+                format: vk::Format::R32G32B32_SFLOAT,
+                offset: 0,
+            },
+            vk::VertexInputAttributeDescription {
+                location: 1,
+                binding: 0,
+                format: vk::Format::R32G32B32A32_SFLOAT,
+                offset: 12,
+            },
+        ];
+
+        PipelineBuilder::new(env.device().clone(), render_pass, 0)
+            .pipeline_cache(env.pipeline_cache())
+            .device_features(env.enabled_features())
+            .vertex_shader(vert_shader_module)
+            .fragment_shader(frag_shader_module)
+            .vertex_input(bindings, attributes)
+            .topology(vk::PrimitiveTopology::LINE_LIST)
+            .disable_culling()
+            .blend()
+            .build()
+            .expect("Failed to create pipeline!")
+    }
+
+    // Device must be idle
+    pub fn resize(&mut self, gbuffer: &Framebuffer) {
+        let gbuffer_samples = gbuffer.attachments.last().unwrap().samples;
+        if gbuffer_samples != self.gbuffer_samples {
+            self.pipeline = Self::create_pipeline(&self.env, self.render_pass, gbuffer);
+            self.gbuffer_samples = gbuffer_samples;
+        }
+
+        for frame in self.frames.iter() {
+            self.destroy_frame(frame);
+        }
+
+        let max_inflight_frames = self.frames.len();
+        self.frames = (0..max_inflight_frames)
+            .map(|_| Self::create_frame(&self.env, &self.pipeline, self.render_pass, gbuffer, self.sampler))
+            .collect();
+    }
+
+    pub fn line(&mut self, from: Point3<f32>, to: Point3<f32>, color: [f32; 4]) {
+        if self.vertices.len() + 2 > MAX_DEBUG_VERTICES {
+            self.dropped += 1;
+            return;
+        }
+
+        self.vertices.push(DebugVertex { position: from.into(), color });
+        self.vertices.push(DebugVertex { position: to.into(), color });
+    }
+
+    pub fn aabb(&mut self, bounds: &Aabb, color: [f32; 4]) {
+        self.hexahedron(&bounds.corners(), color);
+    }
+
+    // Box of any shape given by its corners in Aabb::corners() order, e.g. a frustum slice
+    pub fn hexahedron(&mut self, corners: &[Point3<f32>; 8], color: [f32; 4]) {
+        for &(a, b) in BOX_EDGES.iter() {
+            self.line(corners[a], corners[b], color);
+        }
+    }
+
+    // Three circles in the axis planes
+    pub fn sphere(&mut self, center: Point3<f32>, radius: f32, color: [f32; 4]) {
+        let axes = [Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z()];
+        for plane in 0..3 {
+            let (u, v) = (axes[plane], axes[(plane + 1) % 3]);
+            let point = |segment: usize| {
+                let angle = segment as f32 / SPHERE_SEGMENTS as f32 * std::f32::consts::TAU;
+                center + (u * angle.cos() + v * angle.sin()) * radius
+            };
+
+            for segment in 0..SPHERE_SEGMENTS {
+                self.line(point(segment), point(segment + 1), color);
+            }
+        }
+    }
+
+    // Axes of a transform: x red, y green, z blue, `size` long in the transformed space
+    pub fn axes(&mut self, transform: &Matrix4<f32>, size: f32) {
+        let origin = transform.transform_point(Point3::new(0.0, 0.0, 0.0));
+        let axes = [
+            (Vector3::unit_x(), [1.0, 0.2, 0.2, 1.0]),
+            (Vector3::unit_y(), [0.2, 1.0, 0.2, 1.0]),
+            (Vector3::unit_z(), [0.2, 0.4, 1.0, 1.0]),
+        ];
+        for (axis, color) in axes.iter() {
+            let end = transform.transform_point(Point3::new(0.0, 0.0, 0.0) + axis * size);
+            self.line(origin, end, *color);
+        }
+    }
+
+    // View volume of a projection, e.g. a light or a shadow cascade. `depth_range` is NDC depth of the near
+    // and far planes: [-1, 1] for plain cgmath projections, [0, 1] for the shadow ones, [1, 0] for reverse-Z
+    pub fn frustum(&mut self, view_proj: &Matrix4<f32>, depth_range: [f32; 2], color: [f32; 4]) {
+        let inv_view_proj = match view_proj.invert() {
+            Some(inv) => inv,
+            None => return,
+        };
+        let [near, far] = depth_range;
+
+        let mut corners = [Point3::new(0.0, 0.0, 0.0); 8];
+        for (idx, corner) in corners.iter_mut().enumerate() {
+            let ndc = Vector4::new(
+                if idx & 1 == 0 { -1.0 } else { 1.0 },
+                if idx & 2 == 0 { -1.0 } else { 1.0 },
+                if idx & 4 == 0 { near } else { far },
+                1.0,
+            );
+            let world = inv_view_proj * ndc;
+            // far corners of an infinite projection
+            if world.w.abs() < f32::EPSILON {
+                return;
+            }
+            *corner = Point3::from_homogeneous(world);
+        }
+
+        self.hexahedron(&corners, color);
+    }
+
+    pub fn label(&mut self, position: Point3<f32>, text: impl Into<String>, color: [f32; 4]) {
+        self.labels.push(DebugLabel { position, text: text.into(), color });
+    }
+
+    // Labels go to the gui of the frame, queued after it is painted they show up in the next one
+    pub fn labels(&self) -> &[DebugLabel] {
+        &self.labels
+    }
+
+    // Lines queued for the next flush and the ones over MAX_DEBUG_VERTICES since the last flush
+    pub fn line_count(&self) -> usize {
+        self.vertices.len() / 2
+    }
+
+    pub fn dropped_count(&self) -> usize {
+        self.dropped
+    }
+
+    // Uploads the queued lines for this frame and clears the queue.
+    // Returns the secondary command buffer for the HDR pass
+    pub fn flush(&mut self, camera: &CameraDescriptor) -> vk::CommandBuffer {
+        let frame = &self.frames[self.current_frame];
+        self.current_frame = (self.current_frame + 1) % self.frames.len();
+
+        frame.uniforms.write_data(Uniforms {
+            view_proj: camera.view_proj,
+        });
+        Self::write_memory(self.env.device(), frame.vertex_buffer.1, &self.vertices);
+        Self::write_memory(self.env.device(), frame.indirect_buffer.1, &[vk::DrawIndirectCommand {
+            vertex_count: self.vertices.len() as u32,
+            instance_count: 1,
+            first_vertex: 0,
+            first_instance: 0,
+        }]);
+
+        self.vertices.clear();
+        self.labels.clear();
+        self.dropped = 0;
+
+        frame.second_buffer
+    }
+}
+
+impl Drop for DebugDraw {
+    fn drop(&mut self) {
+        for frame in self.frames.iter() {
+            self.destroy_frame(frame);
+        }
+
+        unsafe {
+            self.env.device().destroy_sampler(self.sampler, None);
+            object_tracker::destroyed(ObjectKind::Sampler);
+        }
+    }
+}
@@ -0,0 +1,651 @@
+use std::fs;
+use std::path::Path;
+use std::ptr;
+use std::sync::Arc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+use cgmath::{EuclideanSpace, InnerSpace, Matrix4, Point3, Quaternion, Rad, SquareMatrix, Vector3};
+
+use ash_render_env::camera::CameraDescriptor;
+use ash_render_env::descriptor_set::DescriptorSet;
+use ash_render_env::env::RenderEnv;
+use ash_render_env::frame_buffer::Framebuffer;
+use ash_render_env::memory_tracker;
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::pipeline_builder::{Pipeline, PipelineBuilder};
+use ash_render_env::primary_cmd_buffer::PrimaryCommandBuffer;
+use ash_render_env::shader;
+use ash_render_env::std140_struct;
+use ash_render_env::uniform_buffer::UniformBuffer;
+use ash_render_env::utils::buffer_utils::create_buffer;
+use ash_render_env::utils::texture::{ImageData, Texture};
+
+use crate::utils::gbuffer::{reader_shader_path, GBufferLayout};
+
+// Placed decals, add() refuses more
+pub const MAX_DECALS: usize = 256;
+// every texture is a separate indirect draw of the recorded command buffers
+pub const MAX_DECAL_TEXTURES: usize = 8;
+
+// Size of the generated textures
+const BUILTIN_TEXTURE_SIZE: u32 = 128;
+
+// Texture projected onto the scene, placed in world space. The box of a decal is `size` wide along the
+// surface and `depth` deep along the normal, surfaces inside it get the texture
+#[derive(Clone, Debug)]
+pub struct Decal {
+    pub position: Point3<f32>,
+    // direction the decal faces, the texture is projected against it
+    pub normal: Vector3<f32>,
+    pub size: [f32; 2],
+    pub depth: f32,
+    // rotation around the normal
+    pub angle: Rad<f32>,
+    // index returned by DecalRenderer::add_texture()
+    pub texture: usize,
+    pub opacity: f32,
+}
+
+impl Decal {
+    pub fn transform(&self) -> Matrix4<f32> {
+        // world up is -Y, the box projects along its local Y axis
+        let up = Vector3::new(0.0, -1.0, 0.0);
+        let rotation = Quaternion::from_arc(up, self.normal.normalize(), None);
+
+        Matrix4::from_translation(self.position.to_vec())
+            * Matrix4::from(rotation)
+            * Matrix4::from_angle_y(self.angle)
+            * Matrix4::from_nonuniform_scale(self.size[0], self.depth, self.size[1])
+    }
+}
+
+std140_struct! {
+    struct Uniforms {
+        view_proj: Matrix4<f32>,
+        inv_view_proj: Matrix4<f32>,
+        camera_position: [f32; 4],
+    }
+}
+
+// Decal of decal.vert and decal.glsl
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DecalInstance {
+    model: Matrix4<f32>,
+    inv_model: Matrix4<f32>,
+    // x - opacity
+    params: [f32; 4],
+}
+
+struct FrameResources {
+    uniforms: UniformBuffer<Uniforms>,
+    instance_buffer: (vk::Buffer, vk::DeviceMemory),
+    // one draw per texture, instances of a texture follow each other in the instance buffer
+    indirect_buffer: (vk::Buffer, vk::DeviceMemory),
+    descriptor_set: DescriptorSet,
+}
+
+struct DecalTexture {
+    name: String,
+    texture: Texture,
+    descriptor_set: DescriptorSet,
+}
+
+// Deferred decals: after the geometry pass boxes of the decals are drawn into the G-buffer albedo
+// (loaded, not cleared), the surface under every pixel is reconstructed from G-buffer depth and gets
+// the texture if it's inside the box. Lighting of the compose pass applies to decals as to everything else
+pub struct DecalRenderer {
+    pub enabled: bool,
+    decals: Vec<Decal>,
+    textures: Vec<DecalTexture>,
+
+    pipeline: Pipeline,
+    render_pass: vk::RenderPass,
+    // the albedo attachment of the G-buffer
+    framebuffer: vk::Framebuffer,
+    gbuffer_samples: vk::SampleCountFlags,
+    dimensions: [u32; 2],
+    sampler: vk::Sampler,
+    frames: Vec<FrameResources>,
+    second_buffers: Vec<vk::CommandBuffer>,
+    draw_command: PrimaryCommandBuffer,
+
+    env: Arc<RenderEnv>,
+}
+
+impl DecalRenderer {
+    pub fn new(env: Arc<RenderEnv>, gbuffer: &Framebuffer, max_inflight_frames: usize) -> DecalRenderer {
+        let gbuffer_samples = gbuffer.attachments[GBufferLayout::ALBEDO].samples;
+        let render_pass = create_render_pass(&env, gbuffer.attachments[GBufferLayout::ALBEDO].format, gbuffer_samples);
+        let pipeline = Self::create_pipeline(&env, render_pass, gbuffer);
+        let framebuffer = Self::create_framebuffer(&env, render_pass, gbuffer);
+
+        let sampler_create_info = vk::SamplerCreateInfo::builder()
+            .min_filter(vk::Filter::NEAREST)
+            .mag_filter(vk::Filter::NEAREST)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+
+        let sampler = unsafe {
+            env.device().create_sampler(&sampler_create_info, None).unwrap()
+        };
+        object_tracker::created(ObjectKind::Sampler);
+
+        let frames = (0..max_inflight_frames)
+            .map(|_| Self::create_frame(&env, &pipeline, gbuffer, sampler))
+            .collect();
+
+        let mut draw_command = PrimaryCommandBuffer::new(env.clone(), max_inflight_frames);
+        draw_command.set_name("decal pass");
+        draw_command.set_dimensions(gbuffer.dimensions());
+
+        let mut renderer = DecalRenderer {
+            enabled: true,
+            decals: Vec::new(),
+            textures: Vec::new(),
+            pipeline,
+            render_pass,
+            framebuffer,
+            gbuffer_samples,
+            dimensions: gbuffer.dimensions(),
+            sampler,
+            frames,
+            second_buffers: Vec::new(),
+            draw_command,
+            env,
+        };
+
+        // the demo has no decal images, these are generated
+        renderer.add_texture_pixels("crater", &crater_pixels(BUILTIN_TEXTURE_SIZE), BUILTIN_TEXTURE_SIZE, BUILTIN_TEXTURE_SIZE);
+        renderer.add_texture_pixels("road mark", &road_mark_pixels(BUILTIN_TEXTURE_SIZE), BUILTIN_TEXTURE_SIZE, BUILTIN_TEXTURE_SIZE);
+
+        renderer
+    }
+
+    fn create_frame(env: &Arc<RenderEnv>, pipeline: &Pipeline, gbuffer: &Framebuffer, sampler: vk::Sampler) -> FrameResources {
+        let uniforms = UniformBuffer::new(env.clone());
+        let instance_buffer = create_buffer(
+            env.device(),
+            (std::mem::size_of::<DecalInstance>() * MAX_DECALS) as u64,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            &env.mem_properties,
+        );
+        let indirect_buffer = create_buffer(
+            env.device(),
+            (std::mem::size_of::<vk::DrawIndirectCommand>() * MAX_DECAL_TEXTURES) as u64,
+            vk::BufferUsageFlags::INDIRECT_BUFFER,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            &env.mem_properties,
+        );
+        // nothing is drawn until the first draw()
+        write_memory(env.device(), indirect_buffer.1, &[vk::DrawIndirectCommand::default(); MAX_DECAL_TEXTURES]);
+
+        let descriptor_set = DescriptorSet::builder(env.device(), &pipeline.descriptor_set_layouts[0])
+            .add_attachment(gbuffer.attachments.last().unwrap(), sampler)
+            .add_buffer(uniforms.buffer)
+            .add_storage_buffer(instance_buffer.0)
+            .build()
+            .expect("Failed to create descriptor set!");
+
+        FrameResources {
+            uniforms,
+            instance_buffer,
+            indirect_buffer,
+            descriptor_set,
+        }
+    }
+
+    fn destroy_frame(&self, frame: &FrameResources) {
+        let device = self.env.device();
+        unsafe {
+            for &(buffer, memory) in [frame.instance_buffer, frame.indirect_buffer].iter() {
+                device.destroy_buffer(buffer, None);
+                object_tracker::destroyed(ObjectKind::Buffer);
+                device.free_memory(memory, None);
+                memory_tracker::freed(memory);
+            }
+        }
+    }
+
+    fn create_framebuffer(env: &RenderEnv, render_pass: vk::RenderPass, gbuffer: &Framebuffer) -> vk::Framebuffer {
+        let dimensions = gbuffer.dimensions();
+        let attachments = [gbuffer.attachments[GBufferLayout::ALBEDO].view];
+        let framebuffer_info = vk::FramebufferCreateInfo {
+            s_type: vk::StructureType::FRAMEBUFFER_CREATE_INFO,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            render_pass,
+            attachment_count: attachments.len() as u32,
+            p_attachments: attachments.as_ptr(),
+            width: dimensions[0],
+            height: dimensions[1],
+            layers: 1,
+        };
+
+        let framebuffer = unsafe {
+            env.device().create_framebuffer(&framebuffer_info, None).unwrap()
+        };
+        object_tracker::created(ObjectKind::Framebuffer);
+        env.set_debug_name(framebuffer, "decals");
+
+        framebuffer
+    }
+
+    fn destroy_framebuffer(&self) {
+        unsafe {
+            self.env.device().destroy_framebuffer(self.framebuffer, None);
+        }
+        object_tracker::destroyed(ObjectKind::Framebuffer);
+    }
+
+    fn create_pipeline(env: &RenderEnv, render_pass: vk::RenderPass, gbuffer: &Framebuffer) -> Pipeline {
+        let gbuffer_samples = gbuffer.attachments.last().unwrap().samples;
+        let vert_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/decals/decal.vert.spv").expect("Failed to load shader!");
+        let frag_shader_module = shader::Shader::load(env.device(), &reader_shader_path("decals/decal", gbuffer_samples)).expect("Failed to load shader!")
+            .specialize(shader::ConstantsBuilder::new()
+                .add_u32(gbuffer.depth_mode().is_reversed() as u32));
+
+        // culling depends on the winding after projection, decal.vert drops faces turned to the camera itself
+        PipelineBuilder::new(env.device().clone(), render_pass, 0)
+            .pipeline_cache(env.pipeline_cache())
+            .vertex_shader(vert_shader_module)
+            .fragment_shader(frag_shader_module)
+            .msaa(gbuffer_samples)
+            .disable_culling()
+            .blend()
+            .build()
+            .expect("Failed to create pipeline!")
+    }
+
+    // Secondary command buffers are recorded once per resize and texture change, draw() only rewrites buffers
+    fn build_cmd_bufs(&mut self) {
+        self.free_cmd_bufs();
+
+        let device = self.env.device();
+        let inheritance_info = vk::CommandBufferInheritanceInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_INHERITANCE_INFO,
+            p_next: ptr::null(),
+            render_pass: self.render_pass,
+            subpass: 0,
+            framebuffer: vk::Framebuffer::null(),
+            occlusion_query_enable: 0,
+            query_flags: Default::default(),
+            pipeline_statistics: Default::default(),
+        };
+
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_BEGIN_INFO,
+            p_next: ptr::null(),
+            p_inheritance_info: &inheritance_info,
+            flags: vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE | vk::CommandBufferUsageFlags::SIMULTANEOUS_USE,
+        };
+
+        let viewports = [vk::Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: self.dimensions[0] as f32,
+            height: self.dimensions[1] as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        }];
+
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: vk::Extent2D {
+                width: self.dimensions[0],
+                height: self.dimensions[1],
+            },
+        }];
+
+        for frame in self.frames.iter() {
+            let cmd_buf = self.env.create_secondary_command_buffer();
+
+            unsafe {
+                device
+                    .begin_command_buffer(cmd_buf, &command_buffer_begin_info)
+                    .expect("Failed to begin recording Command Buffer at beginning!");
+
+                device.cmd_set_viewport(cmd_buf, 0, viewports.as_ref());
+                device.cmd_set_scissor(cmd_buf, 0, scissors.as_ref());
+                device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, self.pipeline.graphics_pipeline);
+                frame.descriptor_set.bind(cmd_buf, vk::PipelineBindPoint::GRAPHICS, self.pipeline.pipeline_layout, 0, &[]);
+
+                // instance counts are written by draw() every frame
+                let stride = std::mem::size_of::<vk::DrawIndirectCommand>();
+                for (texture_idx, texture) in self.textures.iter().enumerate() {
+                    texture.descriptor_set.bind(cmd_buf, vk::PipelineBindPoint::GRAPHICS, self.pipeline.pipeline_layout, 1, &[]);
+                    device.cmd_draw_indirect(cmd_buf, frame.indirect_buffer.0, (texture_idx * stride) as u64, 1, stride as u32);
+                }
+
+                device
+                    .end_command_buffer(cmd_buf)
+                    .expect("Failed to record Command Buffer at Ending!");
+            }
+
+            self.second_buffers.push(cmd_buf);
+        }
+    }
+
+    fn free_cmd_bufs(&mut self) {
+        if !self.second_buffers.is_empty() {
+            unsafe {
+                self.env.device().free_command_buffers(self.env.command_pool(), &self.second_buffers);
+            }
+        }
+        self.second_buffers.clear();
+    }
+
+    // Device must be idle. Follows the G-buffer size and sample count
+    pub fn resize(&mut self, gbuffer: &Framebuffer) {
+        let gbuffer_samples = gbuffer.attachments.last().unwrap().samples;
+        if gbuffer_samples != self.gbuffer_samples {
+            self.render_pass = create_render_pass(&self.env, gbuffer.attachments[GBufferLayout::ALBEDO].format, gbuffer_samples);
+            self.pipeline = Self::create_pipeline(&self.env, self.render_pass, gbuffer);
+            self.gbuffer_samples = gbuffer_samples;
+        }
+
+        self.destroy_framebuffer();
+        self.framebuffer = Self::create_framebuffer(&self.env, self.render_pass, gbuffer);
+        self.dimensions = gbuffer.dimensions();
+        self.draw_command.set_dimensions(self.dimensions);
+
+        for frame in self.frames.iter() {
+            self.destroy_frame(frame);
+        }
+        let max_inflight_frames = self.frames.len();
+        self.frames = (0..max_inflight_frames)
+            .map(|_| Self::create_frame(&self.env, &self.pipeline, gbuffer, self.sampler))
+            .collect();
+
+        // texture sets are made for the pipeline layout
+        for texture in self.textures.iter_mut() {
+            texture.descriptor_set = Self::create_texture_set(&self.env, &self.pipeline, &texture.texture);
+        }
+        self.build_cmd_bufs();
+    }
+
+    fn create_texture_set(env: &RenderEnv, pipeline: &Pipeline, texture: &Texture) -> DescriptorSet {
+        DescriptorSet::builder(env.device(), &pipeline.descriptor_set_layouts[1])
+            .add_texture(texture)
+            .build()
+            .expect("Failed to create descriptor set!")
+    }
+
+    // Device must be idle. RGBA8 sRGB pixels, the alpha channel is the decal coverage.
+    // Returns the texture index for Decal::texture, None when MAX_DECAL_TEXTURES are already added
+    pub fn add_texture_pixels(&mut self, name: &str, pixels: &Vec<u8>, width: u32, height: u32) -> Option<usize> {
+        if self.textures.len() >= MAX_DECAL_TEXTURES {
+            return None;
+        }
+
+        let texture = Texture::from_pixels(self.env.device().clone(), self.env.command_pool(), self.env.queue(), &self.env.mem_properties,
+                                           vk::Format::R8G8B8A8_SRGB, pixels, width, height, true, self.env.max_sampler_anisotropy())
+            .expect("Failed to create decal texture!");
+        self.env.set_debug_name(texture.texture_image, &format!("decal {}", name));
+        let descriptor_set = Self::create_texture_set(&self.env, &self.pipeline, &texture);
+
+        self.textures.push(DecalTexture {
+            name: name.to_string(),
+            texture,
+            descriptor_set,
+        });
+        self.build_cmd_bufs();
+
+        Some(self.textures.len() - 1)
+    }
+
+    // Device must be idle. Transparent parts of the image are not projected
+    pub fn add_texture(&mut self, path: &Path) -> Result<usize, String> {
+        let image = ImageData::load(path, false)?;
+        let name = path.file_stem().map_or_else(|| path.display().to_string(), |stem| stem.to_string_lossy().to_string());
+
+        self.add_texture_pixels(&name, &image.pixels, image.width, image.height)
+            .ok_or_else(|| format!("Failed to add decal texture {}: at most {} textures", path.display(), MAX_DECAL_TEXTURES))
+    }
+
+    // Device must be idle. Adds every PNG of the directory, a missing directory adds nothing
+    pub fn add_textures_from_dir(&mut self, dir: &Path) {
+        let mut paths: Vec<_> = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png")))
+                .collect(),
+            Err(_) => return,
+        };
+        paths.sort();
+
+        for path in paths {
+            if let Err(err) = self.add_texture(&path) {
+                println!("{}", err);
+            }
+        }
+    }
+
+    pub fn texture_names(&self) -> Vec<&str> {
+        self.textures.iter().map(|texture| texture.name.as_str()).collect()
+    }
+
+    // Returns false when MAX_DECALS are placed already or the texture is unknown
+    pub fn add(&mut self, decal: Decal) -> bool {
+        if self.decals.len() >= MAX_DECALS || decal.texture >= self.textures.len() {
+            return false;
+        }
+
+        self.decals.push(decal);
+        true
+    }
+
+    pub fn remove_last(&mut self) -> Option<Decal> {
+        self.decals.pop()
+    }
+
+    pub fn clear(&mut self) {
+        self.decals.clear();
+    }
+
+    // Primary command buffer of the decal pass for `frame`, whose buffers must not be in use by GPU.
+    // Goes after the geometry pass, None when there is nothing to draw
+    pub fn draw(&mut self, frame: usize, camera: &CameraDescriptor) -> Option<vk::CommandBuffer> {
+        if !self.enabled || self.decals.is_empty() {
+            return None;
+        }
+
+        let resources = &self.frames[frame];
+        resources.uniforms.write_data(Uniforms {
+            view_proj: camera.view_proj,
+            inv_view_proj: camera.inv_view_proj,
+            camera_position: camera.position.to_homogeneous().into(),
+        });
+
+        let mut instances = Vec::with_capacity(self.decals.len());
+        let mut commands = [vk::DrawIndirectCommand::default(); MAX_DECAL_TEXTURES];
+        for (texture_idx, command) in commands.iter_mut().enumerate().take(self.textures.len()) {
+            command.vertex_count = 36;
+            command.first_instance = instances.len() as u32;
+
+            for decal in self.decals.iter().filter(|decal| decal.texture == texture_idx) {
+                let model = decal.transform();
+                // boxes without volume can't be inverted, they would not cover anything anyway
+                if let Some(inv_model) = model.invert() {
+                    instances.push(DecalInstance {
+                        model,
+                        inv_model,
+                        params: [decal.opacity, 0.0, 0.0, 0.0],
+                    });
+                }
+            }
+            command.instance_count = instances.len() as u32 - command.first_instance;
+        }
+
+        write_memory(self.env.device(), resources.instance_buffer.1, &instances);
+        write_memory(self.env.device(), resources.indirect_buffer.1, &commands);
+
+        // the albedo is loaded, the clear value is not used
+        let clear_values = vec![vk::ClearValue {
+            color: vk::ClearColorValue {
+                float32: [0.0, 0.0, 0.0, 0.0],
+            },
+        }];
+
+        Some(self.draw_command.execute_secondary(
+            clear_values,
+            self.framebuffer,
+            self.render_pass,
+            &[self.second_buffers[frame]],
+        ))
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.checkbox(&mut self.enabled, "Decals");
+        ui.horizontal(|ui| {
+            ui.label(format!("{} of {} placed", self.decals.len(), MAX_DECALS));
+            if ui.button("Undo").clicked() {
+                self.remove_last();
+            }
+            if ui.button("Clear").clicked() {
+                self.clear();
+            }
+        });
+    }
+}
+
+impl Drop for DecalRenderer {
+    fn drop(&mut self) {
+        self.free_cmd_bufs();
+        for frame in self.frames.iter() {
+            self.destroy_frame(frame);
+        }
+        self.destroy_framebuffer();
+
+        unsafe {
+            self.env.device().destroy_sampler(self.sampler, None);
+            object_tracker::destroyed(ObjectKind::Sampler);
+        }
+    }
+}
+
+fn write_memory<T: Copy>(device: &ash::Device, memory: vk::DeviceMemory, data: &[T]) {
+    if data.is_empty() {
+        return;
+    }
+
+    let size = std::mem::size_of_val(data) as u64;
+    unsafe {
+        let data_ptr = device
+            .map_memory(memory, 0, size, vk::MemoryMapFlags::empty())
+            .expect("Failed to Map Memory") as *mut T;
+
+        data_ptr.copy_from_nonoverlapping(data.as_ptr(), data.len());
+
+        device.unmap_memory(memory);
+    }
+}
+
+// Single subpass over the G-buffer albedo, which stays in the sampled layout outside of the passes
+fn create_render_pass(env: &RenderEnv, format: vk::Format, samples: vk::SampleCountFlags) -> vk::RenderPass {
+    let attachments = [vk::AttachmentDescription {
+        flags: vk::AttachmentDescriptionFlags::empty(),
+        format,
+        samples,
+        load_op: vk::AttachmentLoadOp::LOAD,
+        store_op: vk::AttachmentStoreOp::STORE,
+        stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+        stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+        initial_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        final_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+    }];
+
+    let color_attachment_ref = vk::AttachmentReference {
+        attachment: 0,
+        layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+    };
+
+    let subpasses = [vk::SubpassDescription {
+        flags: vk::SubpassDescriptionFlags::empty(),
+        pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
+        input_attachment_count: 0,
+        p_input_attachments: ptr::null(),
+        color_attachment_count: 1,
+        p_color_attachments: &color_attachment_ref,
+        p_resolve_attachments: ptr::null(),
+        p_depth_stencil_attachment: ptr::null(),
+        preserve_attachment_count: 0,
+        p_preserve_attachments: ptr::null(),
+    }];
+
+    let dependencies = [
+        // the geometry pass wrote albedo and depth
+        vk::SubpassDependency {
+            src_subpass: vk::SUBPASS_EXTERNAL,
+            dst_subpass: 0,
+            src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::FRAGMENT_SHADER,
+            src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::SHADER_READ,
+            dependency_flags: vk::DependencyFlags::empty(),
+        },
+        // albedo is sampled by the compose pass
+        vk::SubpassDependency {
+            src_subpass: 0,
+            dst_subpass: vk::SUBPASS_EXTERNAL,
+            src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            dst_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER,
+            src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            dst_access_mask: vk::AccessFlags::SHADER_READ,
+            dependency_flags: vk::DependencyFlags::empty(),
+        },
+    ];
+
+    let render_pass_create_info = vk::RenderPassCreateInfo {
+        s_type: vk::StructureType::RENDER_PASS_CREATE_INFO,
+        p_next: ptr::null(),
+        flags: vk::RenderPassCreateFlags::empty(),
+        attachment_count: attachments.len() as u32,
+        p_attachments: attachments.as_ptr(),
+        subpass_count: subpasses.len() as u32,
+        p_subpasses: subpasses.as_ptr(),
+        dependency_count: dependencies.len() as u32,
+        p_dependencies: dependencies.as_ptr(),
+    };
+
+    env.render_passes()
+        .get_or_create(&render_pass_create_info)
+        .expect("Failed to create render pass!")
+}
+
+// Dark bowl with a lighter rim, fading out to the edge
+fn crater_pixels(size: u32) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity((size * size * 4) as usize);
+    for y in 0..size {
+        for x in 0..size {
+            let u = (x as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+            let v = (y as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+            let r = (u * u + v * v).sqrt();
+
+            let shade = if r < 0.6 { 20.0 + r * 60.0 } else { 90.0 };
+            let alpha = (1.0 - ((r - 0.7) / 0.3).clamp(0.0, 1.0)) * 230.0;
+            pixels.extend_from_slice(&[shade as u8, (shade * 0.9) as u8, (shade * 0.8) as u8, alpha as u8]);
+        }
+    }
+    pixels
+}
+
+// Dashed white line along the texture v axis
+fn road_mark_pixels(size: u32) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity((size * size * 4) as usize);
+    for y in 0..size {
+        for x in 0..size {
+            let u = (x as f32 + 0.5) / size as f32;
+            let v = (y as f32 + 0.5) / size as f32;
+
+            let line = (u - 0.5).abs() < 0.1;
+            let dash = (v * 4.0).fract() < 0.6;
+            let alpha = if line && dash { 220 } else { 0 };
+            pixels.extend_from_slice(&[235, 235, 225, alpha]);
+        }
+    }
+    pixels
+}
@@ -0,0 +1,461 @@
+use std::ptr;
+use std::sync::Arc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use ash_render_env::descriptor_set::DescriptorSet;
+use ash_render_env::env::RenderEnv;
+use ash_render_env::memory_tracker;
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::pipeline_builder::{Pipeline, PipelineBuilder};
+use ash_render_env::shader;
+use ash_render_env::utils::buffer_utils::{begin_single_time_command, end_single_time_command};
+use ash_render_env::utils::texture_utils::{create_image, create_image_view, create_texture_sampler};
+
+use crate::utils::cube_texture::CubeTexture;
+
+pub const ENVIRONMENT_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+pub const BRDF_LUT_FORMAT: vk::Format = vk::Format::R16G16_SFLOAT;
+const MAX_FACE_SIZE: u32 = 1024;
+const IRRADIANCE_SIZE: u32 = 32;
+const BRDF_LUT_SIZE: u32 = 128;
+const FACE_COUNT: u32 = 6;
+
+// Cube face resolution for an equirectangular image: a face covers a quarter of its width
+pub fn face_size(equirect_width: u32) -> u32 {
+    (equirect_width / 4).max(1).next_power_of_two().min(MAX_FACE_SIZE)
+}
+
+// Cube image in SHADER_READ_ONLY_OPTIMAL from an equirectangular RGBE texture. Mip 0 is the environment itself,
+// mip N is prefiltered with GGX for roughness N / (mip count - 1), for image based lighting.
+// Returns the image, its memory and the mip count. Waits for the graphics queue
+pub fn render_environment(env: &Arc<RenderEnv>, equirect_view: vk::ImageView, equirect_sampler: vk::Sampler,
+                          face_size: u32) -> (vk::Image, vk::DeviceMemory, u32) {
+    let device = env.device();
+    let mip_levels = 32 - face_size.leading_zeros();
+
+    let (image, memory) = create_target(env, face_size, FACE_COUNT, mip_levels, ENVIRONMENT_FORMAT);
+
+    let render_pass = create_render_pass(env, ENVIRONMENT_FORMAT);
+    let equirect_pipeline = create_pipeline(env, render_pass, "assets/shaders/spv/equirect_to_cube.frag.spv");
+    let prefilter_pipeline = create_pipeline(env, render_pass, "assets/shaders/spv/prefilter_env.frag.spv");
+
+    // prefiltered mips read mip 0, which is already rendered by then
+    let source_view = create_image_view(device, image, ENVIRONMENT_FORMAT, vk::ImageAspectFlags::COLOR, 1, FACE_COUNT)
+        .expect("Failed to create environment image view!");
+    let source_sampler = create_texture_sampler(device, 1, 1.0)
+        .expect("Failed to create environment sampler!");
+
+    let equirect_set = DescriptorSet::builder(device, &equirect_pipeline.descriptor_set_layouts[0])
+        .add_image(equirect_view, equirect_sampler)
+        .build()
+        .expect("Failed to create descriptor set!");
+    let prefilter_set = DescriptorSet::builder(device, &prefilter_pipeline.descriptor_set_layouts[0])
+        .add_image(source_view, source_sampler)
+        .build()
+        .expect("Failed to create descriptor set!");
+
+    render_layers(env, image, ENVIRONMENT_FORMAT, face_size, mip_levels, FACE_COUNT, |cmd_buf, mip, face| {
+        let (pipeline, descriptor_set) = if mip == 0 {
+            (&equirect_pipeline, &equirect_set)
+        } else {
+            (&prefilter_pipeline, &prefilter_set)
+        };
+
+        let roughness = mip as f32 / (mip_levels - 1).max(1) as f32;
+        let mut push_constants = [0u8; 8];
+        push_constants[..4].copy_from_slice(&face.to_ne_bytes());
+        push_constants[4..].copy_from_slice(&roughness.to_ne_bytes());
+        // the equirect pass has the face only
+        let push_size = if mip == 0 { 4 } else { 8 };
+
+        unsafe {
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pipeline.graphics_pipeline);
+            descriptor_set.bind(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pipeline.pipeline_layout, 0, &[]);
+            device.cmd_push_constants(cmd_buf, pipeline.pipeline_layout, vk::ShaderStageFlags::FRAGMENT, 0,
+                                      &push_constants[..push_size]);
+            device.cmd_draw(cmd_buf, 3, 1, 0, 0);
+        }
+    });
+
+    unsafe {
+        device.destroy_image_view(source_view, None);
+        object_tracker::destroyed(ObjectKind::ImageView);
+        device.destroy_sampler(source_sampler, None);
+        object_tracker::destroyed(ObjectKind::Sampler);
+    }
+
+    (image, memory, mip_levels)
+}
+
+// Diffuse irradiance cube of an environment cube view, one mip. Waits for the graphics queue
+fn render_irradiance(env: &Arc<RenderEnv>, environment_view: vk::ImageView, environment_sampler: vk::Sampler) -> (vk::Image, vk::DeviceMemory) {
+    let device = env.device();
+    let (image, memory) = create_target(env, IRRADIANCE_SIZE, FACE_COUNT, 1, ENVIRONMENT_FORMAT);
+
+    let render_pass = create_render_pass(env, ENVIRONMENT_FORMAT);
+    let pipeline = create_pipeline(env, render_pass, "assets/shaders/spv/irradiance.frag.spv");
+    let descriptor_set = DescriptorSet::builder(device, &pipeline.descriptor_set_layouts[0])
+        .add_image(environment_view, environment_sampler)
+        .build()
+        .expect("Failed to create descriptor set!");
+
+    render_layers(env, image, ENVIRONMENT_FORMAT, IRRADIANCE_SIZE, 1, FACE_COUNT, |cmd_buf, _, face| {
+        unsafe {
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pipeline.graphics_pipeline);
+            descriptor_set.bind(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pipeline.pipeline_layout, 0, &[]);
+            device.cmd_push_constants(cmd_buf, pipeline.pipeline_layout, vk::ShaderStageFlags::FRAGMENT, 0, &face.to_ne_bytes());
+            device.cmd_draw(cmd_buf, 3, 1, 0, 0);
+        }
+    });
+
+    (image, memory)
+}
+
+// Split-sum BRDF table, x - cos of view angle, y - roughness. Independent of the environment
+fn render_brdf_lut(env: &Arc<RenderEnv>) -> (vk::Image, vk::DeviceMemory) {
+    let device = env.device();
+    let (image, memory) = create_target(env, BRDF_LUT_SIZE, 1, 1, BRDF_LUT_FORMAT);
+
+    let render_pass = create_render_pass(env, BRDF_LUT_FORMAT);
+    let pipeline = create_pipeline(env, render_pass, "assets/shaders/spv/brdf_lut.frag.spv");
+
+    render_layers(env, image, BRDF_LUT_FORMAT, BRDF_LUT_SIZE, 1, 1, |cmd_buf, _, _| {
+        unsafe {
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pipeline.graphics_pipeline);
+            device.cmd_draw(cmd_buf, 3, 1, 0, 0);
+        }
+    });
+
+    (image, memory)
+}
+
+// Handles of the image based lighting maps for the composite pass
+#[derive(Copy, Clone)]
+pub struct EnvironmentViews {
+    pub irradiance: vk::ImageView,
+    pub specular: vk::ImageView,
+    pub specular_sampler: vk::Sampler,
+    // lod of the roughest prefiltered mip
+    pub specular_max_lod: f32,
+    pub brdf_lut: vk::ImageView,
+    pub sampler: vk::Sampler,
+}
+
+// Irradiance map of the current environment and the BRDF table. The prefiltered specular map
+// is the environment cube itself, it stays owned by the skybox
+pub struct EnvironmentLighting {
+    irradiance_image: vk::Image,
+    irradiance_memory: vk::DeviceMemory,
+    irradiance_view: vk::ImageView,
+    brdf_lut_image: vk::Image,
+    brdf_lut_memory: vk::DeviceMemory,
+    brdf_lut_view: vk::ImageView,
+    sampler: vk::Sampler,
+
+    specular: vk::ImageView,
+    specular_sampler: vk::Sampler,
+    specular_mip_levels: u32,
+    env: Arc<RenderEnv>,
+}
+
+impl EnvironmentLighting {
+    pub fn new(env: Arc<RenderEnv>, environment: &CubeTexture) -> EnvironmentLighting {
+        let device = env.device();
+
+        let (brdf_lut_image, brdf_lut_memory) = render_brdf_lut(&env);
+        let brdf_lut_view = create_image_view(device, brdf_lut_image, BRDF_LUT_FORMAT, vk::ImageAspectFlags::COLOR, 1, 1)
+            .expect("Failed to create BRDF LUT view!");
+        let sampler = create_texture_sampler(device, 1, 1.0)
+            .expect("Failed to create environment sampler!");
+
+        let (irradiance_image, irradiance_memory) = render_irradiance(&env, environment.texture_image_view, environment.texture_sampler);
+        let irradiance_view = create_image_view(device, irradiance_image, ENVIRONMENT_FORMAT, vk::ImageAspectFlags::COLOR, 1, FACE_COUNT)
+            .expect("Failed to create irradiance view!");
+
+        EnvironmentLighting {
+            irradiance_image,
+            irradiance_memory,
+            irradiance_view,
+            brdf_lut_image,
+            brdf_lut_memory,
+            brdf_lut_view,
+            sampler,
+            specular: environment.texture_image_view,
+            specular_sampler: environment.texture_sampler,
+            specular_mip_levels: environment.mip_levels(),
+            env,
+        }
+    }
+
+    // Recomputes the irradiance of a new environment, waits for the graphics queue.
+    // The old irradiance map is destroyed, so it must not be in use
+    pub fn set_environment(&mut self, environment: &CubeTexture) {
+        self.destroy_irradiance();
+
+        let (image, memory) = render_irradiance(&self.env, environment.texture_image_view, environment.texture_sampler);
+        self.irradiance_image = image;
+        self.irradiance_memory = memory;
+        self.irradiance_view = create_image_view(self.env.device(), image, ENVIRONMENT_FORMAT, vk::ImageAspectFlags::COLOR, 1, FACE_COUNT)
+            .expect("Failed to create irradiance view!");
+
+        self.specular = environment.texture_image_view;
+        self.specular_sampler = environment.texture_sampler;
+        self.specular_mip_levels = environment.mip_levels();
+    }
+
+    pub fn views(&self) -> EnvironmentViews {
+        EnvironmentViews {
+            irradiance: self.irradiance_view,
+            specular: self.specular,
+            specular_sampler: self.specular_sampler,
+            specular_max_lod: (self.specular_mip_levels - 1) as f32,
+            brdf_lut: self.brdf_lut_view,
+            sampler: self.sampler,
+        }
+    }
+
+    fn destroy_irradiance(&mut self) {
+        let device = self.env.device();
+        unsafe {
+            device.destroy_image_view(self.irradiance_view, None);
+            object_tracker::destroyed(ObjectKind::ImageView);
+            device.destroy_image(self.irradiance_image, None);
+            object_tracker::destroyed(ObjectKind::Image);
+            device.free_memory(self.irradiance_memory, None);
+            memory_tracker::freed(self.irradiance_memory);
+        }
+    }
+}
+
+impl Drop for EnvironmentLighting {
+    fn drop(&mut self) {
+        self.destroy_irradiance();
+
+        let device = self.env.device();
+        unsafe {
+            device.destroy_sampler(self.sampler, None);
+            object_tracker::destroyed(ObjectKind::Sampler);
+            device.destroy_image_view(self.brdf_lut_view, None);
+            object_tracker::destroyed(ObjectKind::ImageView);
+            device.destroy_image(self.brdf_lut_image, None);
+            object_tracker::destroyed(ObjectKind::Image);
+            device.free_memory(self.brdf_lut_memory, None);
+            memory_tracker::freed(self.brdf_lut_memory);
+        }
+    }
+}
+
+fn create_target(env: &RenderEnv, size: u32, layer_count: u32, mip_levels: u32, format: vk::Format) -> (vk::Image, vk::DeviceMemory) {
+    create_image(
+        env.device(), size, size, layer_count, mip_levels,
+        vk::SampleCountFlags::TYPE_1,
+        format,
+        vk::ImageTiling::OPTIMAL,
+        vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        &env.mem_properties)
+        .expect("Failed to create environment image!")
+}
+
+// Renders every layer of every mip of a square image in order, mips first. `draw` records the draw into the begun pass
+// with viewport and scissor set, it gets the mip and the layer. Leaves the image in SHADER_READ_ONLY_OPTIMAL
+fn render_layers<F>(env: &RenderEnv, image: vk::Image, format: vk::Format, size: u32, mip_levels: u32, layer_count: u32, mut draw: F)
+    where F: FnMut(vk::CommandBuffer, u32, u32)
+{
+    let device = env.device();
+    let render_pass = create_render_pass(env, format);
+
+    let mut targets = vec![];
+    for mip in 0..mip_levels {
+        let mip_size = (size >> mip).max(1);
+        for layer in 0..layer_count {
+            let view = create_layer_view(device, image, format, mip, layer);
+            let framebuffer = create_framebuffer(device, render_pass, view, mip_size);
+            targets.push((mip, layer, mip_size, view, framebuffer));
+        }
+    }
+
+    let cmd_buf = begin_single_time_command(device, env.command_pool());
+    for &(mip, layer, mip_size, _, framebuffer) in targets.iter() {
+        let render_area = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: vk::Extent2D { width: mip_size, height: mip_size },
+        };
+        let render_pass_begin_info = vk::RenderPassBeginInfo {
+            s_type: vk::StructureType::RENDER_PASS_BEGIN_INFO,
+            p_next: ptr::null(),
+            render_pass,
+            framebuffer,
+            render_area,
+            clear_value_count: 0,
+            p_clear_values: ptr::null(),
+        };
+        let viewports = [vk::Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: mip_size as f32,
+            height: mip_size as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        }];
+
+        unsafe {
+            device.cmd_begin_render_pass(cmd_buf, &render_pass_begin_info, vk::SubpassContents::INLINE);
+            device.cmd_set_viewport(cmd_buf, 0, &viewports);
+            device.cmd_set_scissor(cmd_buf, 0, &[render_area]);
+        }
+        draw(cmd_buf, mip, layer);
+        unsafe {
+            device.cmd_end_render_pass(cmd_buf);
+        }
+    }
+    end_single_time_command(device, env.command_pool(), env.queue(), cmd_buf);
+
+    unsafe {
+        for (_, _, _, view, framebuffer) in targets {
+            device.destroy_framebuffer(framebuffer, None);
+            object_tracker::destroyed(ObjectKind::Framebuffer);
+            device.destroy_image_view(view, None);
+            object_tracker::destroyed(ObjectKind::ImageView);
+        }
+    }
+}
+
+fn create_pipeline(env: &RenderEnv, render_pass: vk::RenderPass, frag_shader: &str) -> Pipeline {
+    let vert_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/compose.vert.spv").expect("Failed to load shader!");
+    let frag_shader_module = shader::Shader::load(env.device(), frag_shader).expect("Failed to load shader!");
+
+    PipelineBuilder::new(env.device().clone(), render_pass, 0)
+        .pipeline_cache(env.pipeline_cache())
+        .vertex_shader(vert_shader_module)
+        .fragment_shader(frag_shader_module)
+        .build()
+        .expect("Failed to create pipeline!")
+}
+
+// One layer of one mip
+fn create_layer_view(device: &ash::Device, image: vk::Image, format: vk::Format, mip: u32, layer: u32) -> vk::ImageView {
+    let imageview_create_info = vk::ImageViewCreateInfo {
+        s_type: vk::StructureType::IMAGE_VIEW_CREATE_INFO,
+        p_next: ptr::null(),
+        flags: vk::ImageViewCreateFlags::empty(),
+        view_type: vk::ImageViewType::TYPE_2D,
+        format,
+        components: vk::ComponentMapping::default(),
+        subresource_range: vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: mip,
+            level_count: 1,
+            base_array_layer: layer,
+            layer_count: 1,
+        },
+        image,
+    };
+
+    let view = unsafe {
+        device
+            .create_image_view(&imageview_create_info, None)
+            .expect("Failed to create environment layer view!")
+    };
+    object_tracker::created(ObjectKind::ImageView);
+
+    view
+}
+
+fn create_framebuffer(device: &ash::Device, render_pass: vk::RenderPass, view: vk::ImageView, size: u32) -> vk::Framebuffer {
+    let views = [view];
+    let framebuffer_info = vk::FramebufferCreateInfo {
+        s_type: vk::StructureType::FRAMEBUFFER_CREATE_INFO,
+        p_next: ptr::null(),
+        flags: Default::default(),
+        render_pass,
+        attachment_count: views.len() as u32,
+        p_attachments: views.as_ptr(),
+        width: size,
+        height: size,
+        layers: 1,
+    };
+
+    let framebuffer = unsafe {
+        device.create_framebuffer(&framebuffer_info, None)
+            .expect("Failed to create environment framebuffer!")
+    };
+    object_tracker::created(ObjectKind::Framebuffer);
+
+    framebuffer
+}
+
+// Every layer and mip is written whole, nothing is loaded
+fn create_render_pass(env: &RenderEnv, format: vk::Format) -> vk::RenderPass {
+    let color_attachment = vk::AttachmentDescription {
+        format,
+        flags: vk::AttachmentDescriptionFlags::empty(),
+        samples: vk::SampleCountFlags::TYPE_1,
+        load_op: vk::AttachmentLoadOp::DONT_CARE,
+        store_op: vk::AttachmentStoreOp::STORE,
+        stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+        stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+        initial_layout: vk::ImageLayout::UNDEFINED,
+        final_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+    };
+
+    let color_attachment_ref = vk::AttachmentReference {
+        attachment: 0,
+        layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+    };
+
+    let subpasses = [vk::SubpassDescription {
+        color_attachment_count: 1,
+        p_color_attachments: &color_attachment_ref,
+        p_depth_stencil_attachment: ptr::null(),
+        flags: vk::SubpassDescriptionFlags::empty(),
+        pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
+        input_attachment_count: 0,
+        p_input_attachments: ptr::null(),
+        p_resolve_attachments: ptr::null(),
+        preserve_attachment_count: 0,
+        p_preserve_attachments: ptr::null(),
+    }];
+
+    let render_pass_attachments = [color_attachment];
+
+    let subpass_dependencies = [
+        // mip 0 is written by earlier passes and read by the prefiltering ones
+        vk::SubpassDependency {
+            src_subpass: vk::SUBPASS_EXTERNAL,
+            dst_subpass: 0,
+            src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            dst_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            dst_access_mask: vk::AccessFlags::SHADER_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            dependency_flags: vk::DependencyFlags::empty(),
+        },
+        vk::SubpassDependency {
+            src_subpass: 0,
+            dst_subpass: vk::SUBPASS_EXTERNAL,
+            src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            dst_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER,
+            src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            dst_access_mask: vk::AccessFlags::SHADER_READ,
+            dependency_flags: vk::DependencyFlags::empty(),
+        },
+    ];
+
+    let renderpass_create_info = vk::RenderPassCreateInfo {
+        s_type: vk::StructureType::RENDER_PASS_CREATE_INFO,
+        flags: vk::RenderPassCreateFlags::empty(),
+        p_next: ptr::null(),
+        attachment_count: render_pass_attachments.len() as u32,
+        p_attachments: render_pass_attachments.as_ptr(),
+        subpass_count: subpasses.len() as u32,
+        p_subpasses: subpasses.as_ptr(),
+        dependency_count: subpass_dependencies.len() as u32,
+        p_dependencies: subpass_dependencies.as_ptr(),
+    };
+
+    env.render_passes()
+        .get_or_create(&renderpass_create_info)
+        .expect("Failed to create render pass!")
+}
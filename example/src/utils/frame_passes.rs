@@ -0,0 +1,14 @@
+use ash_render_env::transient_pool::PassRange;
+
+// Order of the passes of a frame, transient attachments are alive in a range of them.
+// Shadow maps are kept between frames, so their passes aren't listed
+pub const GEOMETRY: u32 = 0;
+pub const COMPOSE: u32 = 1;
+pub const BLOOM: u32 = 2;
+pub const POST_PROCESS: u32 = 3;
+// the swapchain pass or the editor viewport
+pub const FINAL: u32 = 4;
+
+pub fn range(first: u32, last: u32) -> PassRange {
+    PassRange::new(first, last)
+}
@@ -0,0 +1,94 @@
+use ash::vk;
+
+use ash_render_env::env::RenderEnv;
+use ash_render_env::frame_buffer::AttachmentDesciption;
+use ash_render_env::utils::DepthMode;
+
+// Attachments of the deferred geometry pass. Depth goes last, the framebuffer expects it there.
+// Normals are octahedral encoded (assets/shaders/src/include/normal_encoding.glsl),
+// so a 32 bit format is enough instead of RGBA16F.
+pub struct GBufferLayout {
+    pub samples: vk::SampleCountFlags,
+    pub normal_format: vk::Format,
+    // depth is always D32_SFLOAT, reverse-Z needs a float format
+    pub depth_mode: DepthMode,
+}
+
+impl GBufferLayout {
+    pub const ALBEDO: usize = 0;
+    pub const NORMAL: usize = 1;
+    // metallic, roughness, occlusion, 1 - lit with PBR
+    pub const MATERIAL: usize = 2;
+    // screen uv motion since the previous frame, for temporal anti-aliasing
+    pub const VELOCITY: usize = 3;
+    pub const DEPTH: usize = 4;
+
+    pub const COLOR_ATTACHMENT_COUNT: usize = 4;
+
+    pub fn new(env: &RenderEnv, samples: vk::SampleCountFlags, depth_mode: DepthMode) -> GBufferLayout {
+        // RGB10A2 keeps 10 bits per encoded component, RGBA16F is a fallback with the same channel layout
+        let attachment_features = vk::FormatFeatureFlags::COLOR_ATTACHMENT | vk::FormatFeatureFlags::SAMPLED_IMAGE;
+        let normal_format = [vk::Format::A2B10G10R10_UNORM_PACK32, vk::Format::R16G16B16A16_SFLOAT]
+            .iter()
+            .copied()
+            .find(|format| env.capabilities().supports_format(*format, attachment_features))
+            .expect("Failed to find G-buffer normal format!");
+
+        GBufferLayout {
+            samples,
+            normal_format,
+            depth_mode,
+        }
+    }
+
+    pub fn format(&self, attachment: usize) -> vk::Format {
+        match attachment {
+            Self::ALBEDO => vk::Format::R8G8B8A8_SRGB,
+            Self::NORMAL => self.normal_format,
+            Self::MATERIAL => vk::Format::R8G8B8A8_UNORM,
+            Self::VELOCITY => vk::Format::R16G16_SFLOAT,
+            Self::DEPTH => vk::Format::D32_SFLOAT,
+            _ => panic!("Unknown G-buffer attachment {}", attachment),
+        }
+    }
+
+    pub fn attachments(&self) -> Vec<AttachmentDesciption> {
+        (0..=Self::DEPTH)
+            .map(|attachment| AttachmentDesciption {
+                format: self.format(attachment),
+                samples_count: self.samples,
+            })
+            .collect()
+    }
+}
+
+// Shaders reading the G-buffer come in two variants: sampler2DMS and a `_single_sample` one with sampler2D
+// for the G-buffer without MSAA (assets/shaders/src/include/gbuffer_sampler.glsl)
+pub fn reader_shader_path(name: &str, samples: vk::SampleCountFlags) -> String {
+    if samples == vk::SampleCountFlags::TYPE_1 {
+        format!("assets/shaders/spv/{}_single_sample.frag.spv", name)
+    } else {
+        format!("assets/shaders/spv/{}.frag.spv", name)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AntiAliasing {
+    // multisampled G-buffer, edges are resolved in compose pass
+    Msaa,
+    // single sample G-buffer and an FXAA post effect, cheaper but blurs fine details
+    Fxaa,
+    // single sample G-buffer with a jittered camera, frames are accumulated by TemporalAntiAliasing
+    Taa,
+}
+
+// Pipelines of a renderer drawing into the G-buffer
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum GeometryPipeline {
+    Fill,
+    // depth is tested but not written, the depth pre-pass has laid it down
+    FillAfterPrepass,
+    Wireframe,
+    // the renderer's vertex shader with assets/shaders/src/depth_only.frag, colors are not written
+    DepthPrepass,
+}
@@ -0,0 +1,198 @@
+use std::path::Path;
+use std::time;
+
+use cgmath::{Matrix4, SquareMatrix};
+use gltf::image::Format;
+
+use ash_render_env::utils::texture::{ImageData, TextureData};
+
+use crate::utils::material::MaterialData;
+use crate::utils::mesh::MeshData;
+use crate::utils::model::{ModelData, PartData};
+
+// glTF 2.0 model, text with external or embedded buffers or binary .glb.
+// Every triangle primitive of the nodes of the default scene becomes a model part,
+// node hierarchy is flattened into part transforms
+pub fn load(path: &Path) -> Result<ModelData, String> {
+    let t1 = time::Instant::now();
+    let (document, buffers, images) = gltf::import(path)
+        .map_err(|err| format!("Failed to load glTF: {}", err))?;
+
+    let mut materials = document.materials()
+        .map(|material| load_material(&material, &images))
+        .collect::<Result<Vec<_>, String>>()?;
+    // for primitives without a material, added when needed
+    let mut default_material = None;
+
+    // meshes[gltf mesh] = (mesh data index, material index) for every primitive
+    let mut mesh_data = vec![];
+    let mut meshes = vec![];
+    for mesh in document.meshes() {
+        let mut primitives = vec![];
+        for primitive in mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                println!("{}: skipped {:?} primitive of mesh {}", path.display(), primitive.mode(), mesh.index());
+                continue;
+            }
+
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+            let positions: Vec<[f32; 3]> = reader.read_positions()
+                .ok_or("Primitive has no positions.")?
+                .collect();
+            let normals: Vec<[f32; 3]> = reader.read_normals()
+                .ok_or("Missing normals for the model.")?
+                .collect();
+            let tex_coords: Vec<[f32; 2]> = match reader.read_tex_coords(0) {
+                Some(tex_coords) => tex_coords.into_f32().collect(),
+                None => vec![[0.0; 2]; positions.len()],
+            };
+            let indices: Vec<u32> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => (0..positions.len() as u32).collect(),
+            };
+
+            let material = match primitive.material().index() {
+                Some(idx) => idx,
+                None => *default_material.get_or_insert_with(|| {
+                    materials.push(white_material());
+                    materials.len() - 1
+                }),
+            };
+
+            primitives.push((mesh_data.len(), material));
+            mesh_data.push(MeshData::new(&positions, &normals, &tex_coords, indices)?);
+        }
+        meshes.push(primitives);
+    }
+
+    let scene = document.default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or("glTF file has no scenes.")?;
+
+    let mut parts = vec![];
+    for node in scene.nodes() {
+        add_node(&node, Matrix4::identity(), &meshes, &mut parts);
+    }
+
+    if parts.is_empty() {
+        return Err("Model has no faces.".to_string());
+    }
+    println!("Model loaded: {}", t1.elapsed().as_secs_f32());
+
+    Ok(ModelData {
+        meshes: mesh_data,
+        materials,
+        parts,
+    })
+}
+
+fn add_node(node: &gltf::Node, parent: Matrix4<f32>, meshes: &[Vec<(usize, usize)>], parts: &mut Vec<PartData>) {
+    let transform = parent * Matrix4::from(node.transform().matrix());
+
+    if let Some(mesh) = node.mesh() {
+        for &(mesh, material) in meshes[mesh.index()].iter() {
+            parts.push(PartData { mesh, material, transform });
+        }
+    }
+
+    for child in node.children() {
+        add_node(&child, transform, meshes, parts);
+    }
+}
+
+// Base color factor is baked into the albedo, the other factors are passed to the shader.
+// Only the first texture coordinate set is supported
+fn load_material(material: &gltf::Material, images: &[gltf::image::Data]) -> Result<MaterialData, String> {
+    let image = |texture: gltf::Texture| to_image_data(&images[texture.source().index()]).map(TextureData::Pixels);
+    let pbr = material.pbr_metallic_roughness();
+
+    let mut albedo = match pbr.base_color_texture() {
+        Some(info) => to_image_data(&images[info.texture().source().index()])?,
+        None => white_image(),
+    };
+    let factor = pbr.base_color_factor();
+    for texel in albedo.pixels.chunks_exact_mut(4) {
+        for (value, factor) in texel.iter_mut().zip(factor.iter()) {
+            *value = (*value as f32 * factor).round() as u8;
+        }
+    }
+
+    Ok(MaterialData {
+        albedo: TextureData::Pixels(albedo),
+        metallic_roughness: pbr.metallic_roughness_texture().map(|info| image(info.texture())).transpose()?,
+        normal: material.normal_texture().map(|info| image(info.texture())).transpose()?,
+        occlusion: material.occlusion_texture().map(|info| image(info.texture())).transpose()?,
+        metallic: pbr.metallic_factor(),
+        roughness: pbr.roughness_factor(),
+        occlusion_strength: material.occlusion_texture().map_or(1.0, |info| info.strength()),
+        // masked materials are drawn opaque
+        transparent: material.alpha_mode() == gltf::material::AlphaMode::Blend,
+    })
+}
+
+// glTF default material
+fn white_material() -> MaterialData {
+    MaterialData {
+        albedo: TextureData::Pixels(white_image()),
+        metallic_roughness: None,
+        normal: None,
+        occlusion: None,
+        metallic: 1.0,
+        roughness: 1.0,
+        occlusion_strength: 1.0,
+        transparent: false,
+    }
+}
+
+fn white_image() -> ImageData {
+    ImageData {
+        pixels: vec![255; 4],
+        width: 1,
+        height: 1,
+    }
+}
+
+// Any glTF image as RGBA8, grayscale is replicated to RGB and 16 bit channels keep the high byte
+fn to_image_data(image: &gltf::image::Data) -> Result<ImageData, String> {
+    let (channels, channel_size) = match image.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 | Format::B8G8R8 => (3, 1),
+        Format::R8G8B8A8 | Format::B8G8R8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+    };
+    let bgr = matches!(image.format, Format::B8G8R8 | Format::B8G8R8A8);
+
+    let texel_count = (image.width * image.height) as usize;
+    if image.pixels.len() != texel_count * channels * channel_size {
+        return Err(format!("Image data size mismatch for {}x{} {:?}", image.width, image.height, image.format));
+    }
+
+    let mut pixels = Vec::with_capacity(texel_count * 4);
+    for texel in image.pixels.chunks_exact(channels * channel_size) {
+        // little endian, the last byte of a channel is the most significant one
+        let channel = |idx: usize| texel[idx * channel_size + channel_size - 1];
+
+        let rgba = match channels {
+            1 => [channel(0), channel(0), channel(0), 255],
+            2 => [channel(0), channel(0), channel(0), channel(1)],
+            3 => [channel(0), channel(1), channel(2), 255],
+            _ => [channel(0), channel(1), channel(2), channel(3)],
+        };
+
+        if bgr {
+            pixels.extend_from_slice(&[rgba[2], rgba[1], rgba[0], rgba[3]]);
+        } else {
+            pixels.extend_from_slice(&rgba);
+        }
+    }
+
+    Ok(ImageData {
+        pixels,
+        width: image.width,
+        height: image.height,
+    })
+}
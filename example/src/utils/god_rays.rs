@@ -0,0 +1,300 @@
+use std::ptr;
+use std::sync::Arc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+use cgmath::{Vector3, Vector4};
+
+use ash_render_env::camera::CameraDescriptor;
+use ash_render_env::descriptor_set::DescriptorSet;
+use ash_render_env::env::RenderEnv;
+use ash_render_env::frame_buffer::{AttachmentDesciption, Framebuffer};
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::pipeline_builder::{Pipeline, PipelineBuilder};
+use ash_render_env::primary_cmd_buffer::PrimaryCommandBuffer;
+use ash_render_env::shader;
+use ash_render_env::std140_struct;
+use ash_render_env::uniform_buffer::UniformBuffer;
+
+use crate::utils::frame_passes;
+use crate::utils::gbuffer::reader_shader_path;
+
+// Sun disk radius in uv units of the screen height
+const SUN_RADIUS: f32 = 0.04;
+
+std140_struct! {
+    struct Uniforms {
+        // xy - sun uv, z - disk radius, w - aspect ratio
+        sun: [f32; 4],
+    }
+}
+
+// Screen space light shafts, a cheap replacement for volumetric lighting.
+// The sun disk is drawn where G-buffer depth is empty (sky) into a half resolution occlusion buffer,
+// compose pass blurs it radially towards the sun and adds the result.
+pub struct GodRays {
+    pub enabled: bool,
+    pub intensity: f32,
+    // weight falloff of every next blur sample
+    pub decay: f32,
+
+    framebuffer: Framebuffer,
+    pipeline: Pipeline,
+    // the shader variant depends on whether the G-buffer is multisampled
+    gbuffer_samples: vk::SampleCountFlags,
+    sampler: vk::Sampler,
+    // per frame in flight
+    descriptor_sets: Vec<DescriptorSet>,
+    uniform_buffers: Vec<UniformBuffer<Uniforms>>,
+    second_buffers: Vec<vk::CommandBuffer>,
+    draw_command: PrimaryCommandBuffer,
+
+    env: Arc<RenderEnv>,
+}
+
+impl GodRays {
+    pub fn new(env: Arc<RenderEnv>, gbuffer: &Framebuffer, max_inflight_frames: usize) -> GodRays {
+        let mut framebuffer = Framebuffer::new(env.clone(), vec![
+            AttachmentDesciption {
+                format: vk::Format::R8_UNORM,
+                samples_count: vk::SampleCountFlags::TYPE_1,
+            },
+        ]);
+        let dimensions = Self::occlusion_dimensions(gbuffer.dimensions());
+        framebuffer.set_name("god rays occlusion");
+        // sampled by the compose pass only when the god rays were drawn
+        framebuffer.set_transient(frame_passes::range(frame_passes::GEOMETRY, frame_passes::COMPOSE));
+        framebuffer.resize_swapchain(dimensions);
+
+        let gbuffer_samples = gbuffer.attachments.last().unwrap().samples;
+        let pipeline = Self::create_pipeline(&env, framebuffer.render_pass(), gbuffer);
+
+        let sampler_create_info = vk::SamplerCreateInfo::builder()
+            .min_filter(vk::Filter::NEAREST)
+            .mag_filter(vk::Filter::NEAREST)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+
+        let sampler = unsafe {
+            env.device().create_sampler(&sampler_create_info, None).unwrap()
+        };
+        object_tracker::created(ObjectKind::Sampler);
+
+        let uniform_buffers: Vec<_> = (0..max_inflight_frames)
+            .map(|_| UniformBuffer::new(env.clone()))
+            .collect();
+        let descriptor_sets: Vec<_> = uniform_buffers.iter()
+            .map(|uniform_buffer| Self::create_descriptor_set(&env, &pipeline, gbuffer, sampler, uniform_buffer))
+            .collect();
+
+        let mut draw_command = PrimaryCommandBuffer::new(env.clone(), max_inflight_frames);
+        draw_command.set_name("god rays occlusion pass");
+        draw_command.set_dimensions(dimensions);
+
+        let second_buffers = descriptor_sets.iter()
+            .map(|descriptor_set| Self::build_cmd_buf(&env, &pipeline, descriptor_set, framebuffer.render_pass(), dimensions))
+            .collect();
+
+        GodRays {
+            enabled: true,
+            intensity: 0.6,
+            decay: 0.96,
+            framebuffer,
+            pipeline,
+            gbuffer_samples,
+            sampler,
+            descriptor_sets,
+            uniform_buffers,
+            second_buffers,
+            draw_command,
+            env,
+        }
+    }
+
+    fn occlusion_dimensions(dimensions: [u32; 2]) -> [u32; 2] {
+        [(dimensions[0] / 2).max(1), (dimensions[1] / 2).max(1)]
+    }
+
+    fn create_descriptor_set(env: &RenderEnv, pipeline: &Pipeline, gbuffer: &Framebuffer, sampler: vk::Sampler,
+                             uniform_buffer: &UniformBuffer<Uniforms>) -> DescriptorSet {
+        DescriptorSet::builder(env.device(), &pipeline.descriptor_set_layouts[0])
+            .add_attachment(gbuffer.attachments.last().unwrap(), sampler)
+            .add_buffer(uniform_buffer.buffer)
+            .build()
+            .expect("Failed to create descriptor set!")
+    }
+
+    fn build_cmd_buf(env: &RenderEnv, pipeline: &Pipeline, descriptor_set: &DescriptorSet, render_pass: vk::RenderPass, dimensions: [u32; 2]) -> vk::CommandBuffer {
+        let device = env.device();
+        let cmd_buf = env.create_secondary_command_buffer();
+
+        let inheritance_info = vk::CommandBufferInheritanceInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_INHERITANCE_INFO,
+            p_next: ptr::null(),
+            render_pass,
+            subpass: 0,
+            framebuffer: vk::Framebuffer::null(),
+            occlusion_query_enable: 0,
+            query_flags: Default::default(),
+            pipeline_statistics: Default::default(),
+        };
+
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_BEGIN_INFO,
+            p_next: ptr::null(),
+            p_inheritance_info: &inheritance_info,
+            flags: vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE | vk::CommandBufferUsageFlags::SIMULTANEOUS_USE,
+        };
+
+        let viewports = [vk::Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: dimensions[0] as f32,
+            height: dimensions[1] as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        }];
+
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: vk::Extent2D {
+                width: dimensions[0],
+                height: dimensions[1],
+            },
+        }];
+
+        unsafe {
+            device
+                .begin_command_buffer(cmd_buf, &command_buffer_begin_info)
+                .expect("Failed to begin recording Command Buffer at beginning!");
+
+            device.cmd_set_viewport(cmd_buf, 0, viewports.as_ref());
+            device.cmd_set_scissor(cmd_buf, 0, scissors.as_ref());
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pipeline.graphics_pipeline);
+
+            let descriptor_sets_to_bind = [descriptor_set.set];
+            device.cmd_bind_descriptor_sets(
+                cmd_buf,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline.pipeline_layout,
+                0,
+                &descriptor_sets_to_bind,
+                &[],
+            );
+
+            device.cmd_draw(cmd_buf, 3, 1, 0, 0);
+
+            device
+                .end_command_buffer(cmd_buf)
+                .expect("Failed to record Command Buffer at Ending!");
+        }
+
+        cmd_buf
+    }
+
+    fn create_pipeline(env: &RenderEnv, render_pass: vk::RenderPass, gbuffer: &Framebuffer) -> Pipeline {
+        let depth_samples = gbuffer.attachments.last().unwrap().samples;
+        let vert_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/compose.vert.spv").expect("Failed to load shader!");
+        let frag_shader_module = shader::Shader::load(env.device(), &reader_shader_path("god_rays_occlusion", depth_samples)).expect("Failed to load shader!")
+            .specialize(shader::ConstantsBuilder::new()
+                .add_u32(depth_samples.as_raw())
+                .add_u32(gbuffer.depth_mode().is_reversed() as u32));
+
+        PipelineBuilder::new(env.device().clone(), render_pass, 0)
+            .pipeline_cache(env.pipeline_cache())
+            .vertex_shader(vert_shader_module)
+            .fragment_shader(frag_shader_module)
+            .build()
+            .expect("Failed to create pipeline!")
+    }
+
+    // Occlusion buffer sampled by compose pass, changes on resize
+    pub fn occlusion_view(&self) -> vk::ImageView {
+        self.framebuffer.attachments[0].view
+    }
+
+    // Device must be idle
+    pub fn resize(&mut self, gbuffer: &Framebuffer) {
+        let dimensions = Self::occlusion_dimensions(gbuffer.dimensions());
+        self.framebuffer.resize_swapchain(dimensions);
+        self.draw_command.set_dimensions(dimensions);
+
+        let gbuffer_samples = gbuffer.attachments.last().unwrap().samples;
+        if gbuffer_samples != self.gbuffer_samples {
+            self.pipeline = Self::create_pipeline(&self.env, self.framebuffer.render_pass(), gbuffer);
+            self.gbuffer_samples = gbuffer_samples;
+        }
+
+        self.descriptor_sets = self.uniform_buffers.iter()
+            .map(|uniform_buffer| Self::create_descriptor_set(&self.env, &self.pipeline, gbuffer, self.sampler, uniform_buffer))
+            .collect();
+
+        unsafe {
+            self.env.device().free_command_buffers(self.env.command_pool(), &self.second_buffers);
+        }
+        self.second_buffers = self.descriptor_sets.iter()
+            .map(|descriptor_set| Self::build_cmd_buf(&self.env, &self.pipeline, descriptor_set, self.framebuffer.render_pass(), dimensions))
+            .collect();
+    }
+
+    // Projects the sun onto the screen and returns compose pass parameters:
+    // xy - sun uv, z - intensity (0 when the sun is behind the camera or far off screen), w - decay.
+    // `sun_direction` - Atmosphere::sun_direction. Uniforms of `frame` must not be in use by GPU
+    pub fn update(&mut self, frame: usize, camera: &CameraDescriptor, sun_direction: Vector3<f32>) -> [f32; 4] {
+        // the sun is opposite to the light direction
+        let sun = -sun_direction;
+        let camera_pos = camera.position;
+        let clip = camera.view_proj * Vector4::new(camera_pos.x + sun.x, camera_pos.y + sun.y, camera_pos.z + sun.z, 1.0);
+
+        let (uv, fade) = if clip.w > 0.0 {
+            let ndc = [clip.x / clip.w, clip.y / clip.w];
+
+            // rays keep going for a while after the sun leaves the screen
+            let outside = (ndc[0].abs().max(ndc[1].abs()) - 1.0).max(0.0);
+            ([ndc[0] * 0.5 + 0.5, ndc[1] * 0.5 + 0.5], (1.0 - outside * 2.0).clamp(0.0, 1.0))
+        } else {
+            ([0.5, 0.5], 0.0)
+        };
+
+        self.uniform_buffers[frame].write_data(Uniforms {
+            sun: [uv[0], uv[1], SUN_RADIUS, camera.aspect],
+        });
+
+        let intensity = if self.enabled { self.intensity * fade } else { 0.0 };
+        [uv[0], uv[1], intensity, self.decay]
+    }
+
+    // Primary command buffer of the occlusion pass, must run after the geometry pass
+    pub fn draw(&mut self, frame: usize) -> vk::CommandBuffer {
+        let clear_values = vec![vk::ClearValue {
+            color: vk::ClearColorValue {
+                float32: [0.0, 0.0, 0.0, 0.0],
+            },
+        }];
+
+        self.draw_command.execute_secondary(
+            clear_values,
+            self.framebuffer.framebuffer.unwrap(),
+            self.framebuffer.render_pass(),
+            &[self.second_buffers[frame]],
+        )
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.checkbox(&mut self.enabled, "God rays");
+        ui.add(egui::Slider::new(&mut self.intensity, 0.0..=2.0).text("Intensity"));
+        ui.add(egui::Slider::new(&mut self.decay, 0.8..=1.0).text("Decay"));
+    }
+}
+
+impl Drop for GodRays {
+    fn drop(&mut self) {
+        unsafe {
+            self.env.device().free_command_buffers(self.env.command_pool(), &self.second_buffers);
+            self.env.device().destroy_sampler(self.sampler, None);
+            object_tracker::destroyed(ObjectKind::Sampler);
+        }
+        self.framebuffer.destroy();
+    }
+}
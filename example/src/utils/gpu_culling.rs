@@ -0,0 +1,283 @@
+use std::sync::Arc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use ash_render_env::camera::CameraDescriptor;
+use ash_render_env::culling::CullStats;
+use ash_render_env::descriptor_set::DescriptorSet;
+use ash_render_env::env::RenderEnv;
+use ash_render_env::memory_tracker;
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::pipeline_builder::{ComputePipelineBuilder, Pipeline};
+use ash_render_env::shader;
+use ash_render_env::utils::buffer_utils::create_buffer;
+
+use crate::utils::scene_buffer::GpuObject;
+
+// Must match local_size_x of cull_objects.comp
+const WORKGROUP_SIZE: u32 = 64;
+
+#[repr(C)]
+struct CullConstants {
+    // frustum planes of the camera, normals point inside
+    planes: [[f32; 4]; 6],
+    // x - objects per part, y - object count
+    counts: [u32; 4],
+}
+
+// Storage buffers of one frame in flight
+struct CullFrame {
+    // VkDrawIndexedIndirectCommand per part, host visible to read the visible count back
+    draw_buffer: (vk::Buffer, vk::DeviceMemory),
+    // visible objects of part N start at N * max_objects_per_part
+    visible_buffer: (vk::Buffer, vk::DeviceMemory),
+    descriptor_set: DescriptorSet,
+    command: vk::CommandBuffer,
+}
+
+// Frustum culling on GPU. Objects are laid out like in the scene buffer: all instances of the first part,
+// then of the second one and so on. A compute pass appends visible objects of every part to the visible buffer
+// and counts them in the draw-command buffer, so one vkCmdDrawIndexedIndirect per part draws them
+// with the instance count determined on GPU. Draws index the visible buffer by gl_InstanceIndex
+// and need the drawIndirectFirstInstance feature.
+pub struct GpuCulling {
+    pipeline: Pipeline,
+    objects_buffer: (vk::Buffer, vk::DeviceMemory),
+    frames: Vec<CullFrame>,
+    // index count of every part
+    index_counts: Vec<u32>,
+    // capacity of one part in the visible buffer
+    max_objects_per_part: usize,
+    // written by write_objects
+    objects_per_part: usize,
+    stats: CullStats,
+
+    env: Arc<RenderEnv>,
+}
+
+impl GpuCulling {
+    // `index_counts` - index count of every part, `max_objects_per_part` - capacity of one part
+    pub fn new(env: Arc<RenderEnv>, index_counts: Vec<u32>, max_objects_per_part: usize, max_inflight_frames: usize) -> GpuCulling {
+        let comp_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/culling/cull_objects.comp.spv").expect("Failed to load shader!");
+        let pipeline = ComputePipelineBuilder::new(env.device().clone(), comp_shader_module)
+            .pipeline_cache(env.pipeline_cache())
+            .build()
+            .expect("Failed to create pipeline!");
+
+        let object_count = (index_counts.len() * max_objects_per_part).max(1);
+        let objects_size = (std::mem::size_of::<GpuObject>() * object_count) as u64;
+        let objects_buffer = create_buffer(
+            env.device(),
+            objects_size,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            &env.mem_properties,
+        );
+
+        let draws_size = (std::mem::size_of::<vk::DrawIndexedIndirectCommand>() * index_counts.len().max(1)) as u64;
+        let frames = (0..max_inflight_frames)
+            .map(|_| {
+                let draw_buffer = create_buffer(
+                    env.device(),
+                    draws_size,
+                    vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::INDIRECT_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+                    vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                    &env.mem_properties,
+                );
+                // no visible objects until the first cull pass
+                unsafe {
+                    let data_ptr = env.device()
+                        .map_memory(draw_buffer.1, 0, draws_size, vk::MemoryMapFlags::empty())
+                        .expect("Failed to Map Memory") as *mut u8;
+                    data_ptr.write_bytes(0, draws_size as usize);
+                    env.device().unmap_memory(draw_buffer.1);
+                }
+                let visible_buffer = create_buffer(
+                    env.device(),
+                    objects_size,
+                    vk::BufferUsageFlags::STORAGE_BUFFER,
+                    vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                    &env.mem_properties,
+                );
+                let descriptor_set = DescriptorSet::builder(env.device(), &pipeline.descriptor_set_layouts[0])
+                    .add_storage_buffer(objects_buffer.0)
+                    .add_storage_buffer(visible_buffer.0)
+                    .add_storage_buffer(draw_buffer.0)
+                    .build()
+                    .expect("Failed to create descriptor set!");
+
+                CullFrame {
+                    draw_buffer,
+                    visible_buffer,
+                    descriptor_set,
+                    command: env.create_primary_command_buffer(),
+                }
+            })
+            .collect();
+
+        GpuCulling {
+            pipeline,
+            objects_buffer,
+            frames,
+            index_counts,
+            max_objects_per_part,
+            objects_per_part: 0,
+            stats: CullStats::default(),
+            env,
+        }
+    }
+
+    // The same number of objects for every part, at most `max_objects_per_part` each. Device must be idle
+    pub fn write_objects(&mut self, objects: &[GpuObject]) {
+        let objects_per_part = objects.len() / self.index_counts.len().max(1);
+        assert!(objects_per_part <= self.max_objects_per_part, "Too many culled objects!");
+        self.objects_per_part = objects_per_part;
+
+        if !objects.is_empty() {
+            unsafe {
+                let size = std::mem::size_of_val(objects) as u64;
+                let data_ptr = self.env.device()
+                    .map_memory(self.objects_buffer.1, 0, size, vk::MemoryMapFlags::empty())
+                    .expect("Failed to Map Memory") as *mut GpuObject;
+
+                data_ptr.copy_from_nonoverlapping(objects.as_ptr(), objects.len());
+
+                self.env.device().unmap_memory(self.objects_buffer.1);
+            }
+        }
+    }
+
+    // Buffer with the draw command of every part, for vkCmdDrawIndexedIndirect
+    pub fn draw_buffer(&self, frame: usize) -> vk::Buffer {
+        self.frames[frame].draw_buffer.0
+    }
+
+    // Visible objects, read by the vertex shader instead of the scene buffer
+    pub fn visible_buffer(&self, frame: usize) -> vk::Buffer {
+        self.frames[frame].visible_buffer.0
+    }
+
+    // Counted on GPU, the last finished frame
+    pub fn stats(&self) -> CullStats {
+        self.stats
+    }
+
+    fn read_stats(&mut self, frame: usize, object_count: usize) {
+        let memory = self.frames[frame].draw_buffer.1;
+        let visible: usize = unsafe {
+            let data_ptr = self.env.device()
+                .map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())
+                .expect("Failed to Map Memory") as *const vk::DrawIndexedIndirectCommand;
+
+            let visible = std::slice::from_raw_parts(data_ptr, self.index_counts.len())
+                .iter()
+                .map(|draw| draw.instance_count as usize)
+                .sum();
+
+            self.env.device().unmap_memory(memory);
+            visible
+        };
+
+        self.stats = CullStats {
+            total: object_count,
+            culled: object_count.saturating_sub(visible),
+        };
+    }
+
+    // Primary command buffer of the culling pass, must be submitted before the pass drawing the objects.
+    // Resources of `frame` must not be in use by GPU
+    pub fn cull(&mut self, frame: usize, camera: &CameraDescriptor) -> vk::CommandBuffer {
+        let object_count = self.index_counts.len() * self.objects_per_part;
+        // the buffers of the frame were used the last time max_inflight_frames ago
+        self.read_stats(frame, object_count);
+
+        let mut planes = [[0.0; 4]; 6];
+        for (dst, plane) in planes.iter_mut().zip(camera.frustum.planes.iter()) {
+            *dst = (*plane).into();
+        }
+        let constants = CullConstants {
+            planes,
+            counts: [self.objects_per_part as u32, object_count as u32, 0, 0],
+        };
+        let constants_bytes = unsafe {
+            std::slice::from_raw_parts(&constants as *const CullConstants as *const u8, std::mem::size_of::<CullConstants>())
+        };
+
+        // instance counts start from zero, visible objects of a part follow its first instance
+        let draws: Vec<u8> = self.index_counts.iter()
+            .enumerate()
+            .flat_map(|(part_idx, &index_count)| {
+                [index_count, 0, 0, 0, (part_idx * self.max_objects_per_part) as u32]
+            })
+            .flat_map(|word| word.to_ne_bytes())
+            .collect();
+
+        let device = self.env.device();
+        let cull_frame = &self.frames[frame];
+        let cmd = cull_frame.command;
+
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+
+        unsafe {
+            device.reset_command_buffer(cmd, vk::CommandBufferResetFlags::default())
+                .expect("Failed to reset Command Buffer!");
+            device.begin_command_buffer(cmd, &command_buffer_begin_info)
+                .expect("Failed to begin recording Command Buffer at beginning!");
+
+            device.cmd_update_buffer(cmd, cull_frame.draw_buffer.0, 0, &draws);
+            Self::memory_barrier(device, cmd,
+                                 vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE,
+                                 vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.pipeline.graphics_pipeline);
+            cull_frame.descriptor_set.bind(cmd, vk::PipelineBindPoint::COMPUTE, self.pipeline.pipeline_layout, 0, &[]);
+            device.cmd_push_constants(cmd, self.pipeline.pipeline_layout, vk::ShaderStageFlags::COMPUTE, 0, constants_bytes);
+            device.cmd_dispatch(cmd, (object_count as u32).div_ceil(WORKGROUP_SIZE).max(1), 1, 1);
+
+            Self::memory_barrier(device, cmd,
+                                 vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE,
+                                 vk::PipelineStageFlags::DRAW_INDIRECT | vk::PipelineStageFlags::VERTEX_SHADER,
+                                 vk::AccessFlags::INDIRECT_COMMAND_READ | vk::AccessFlags::SHADER_READ);
+
+            device.end_command_buffer(cmd)
+                .expect("Failed to record Command Buffer at Ending!");
+        }
+
+        cmd
+    }
+
+    unsafe fn memory_barrier(device: &ash::Device, cmd: vk::CommandBuffer,
+                             src_stage: vk::PipelineStageFlags, src_access: vk::AccessFlags,
+                             dst_stage: vk::PipelineStageFlags, dst_access: vk::AccessFlags) {
+        let barrier = vk::MemoryBarrier::builder()
+            .src_access_mask(src_access)
+            .dst_access_mask(dst_access)
+            .build();
+
+        device.cmd_pipeline_barrier(cmd, src_stage, dst_stage, vk::DependencyFlags::empty(), &[barrier], &[], &[]);
+    }
+}
+
+impl Drop for GpuCulling {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.env.device();
+            for frame in self.frames.iter() {
+                device.free_command_buffers(self.env.command_pool(), &[frame.command]);
+                for &(buffer, memory) in [frame.draw_buffer, frame.visible_buffer].iter() {
+                    device.destroy_buffer(buffer, None);
+                    object_tracker::destroyed(ObjectKind::Buffer);
+                    device.free_memory(memory, None);
+                    memory_tracker::freed(memory);
+                }
+            }
+
+            device.destroy_buffer(self.objects_buffer.0, None);
+            object_tracker::destroyed(ObjectKind::Buffer);
+            device.free_memory(self.objects_buffer.1, None);
+            memory_tracker::freed(self.objects_buffer.1);
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::f32::consts::PI;
+
+const DIRECTION_COUNT: usize = 8;
+// Distances in grid cells the horizon is searched at, sparser further away
+const SEARCH_STEPS: [f32; 10] = [1.0, 2.0, 3.0, 4.0, 6.0, 8.0, 12.0, 16.0, 24.0, 32.0];
+
+// Fraction of the sky seen from every grid point, 1 - flat open ground, 0 - bottom of a narrow pit.
+// `heights` are world y of the grid (up is -Y), `scale` is the distance between grid points.
+pub fn sky_visibility(w: u32, h: u32, scale: f32, heights: &[f32]) -> Vec<f32> {
+    assert_eq!(heights.len(), (w * h) as usize);
+
+    let height_at = |x: i32, y: i32| -> Option<f32> {
+        if x < 0 || y < 0 || x >= w as i32 || y >= h as i32 {
+            return None;
+        }
+        Some(-heights[(y as u32 * w + x as u32) as usize])
+    };
+
+    let directions: Vec<(f32, f32)> = (0..DIRECTION_COUNT)
+        .map(|i| {
+            let angle = 2.0 * PI * i as f32 / DIRECTION_COUNT as f32;
+            (angle.cos(), angle.sin())
+        })
+        .collect();
+
+    let mut visibility = Vec::with_capacity(heights.len());
+    for y in 0..h as i32 {
+        for x in 0..w as i32 {
+            let center = height_at(x, y).unwrap();
+
+            let mut visible = 0.0;
+            for &(dx, dy) in directions.iter() {
+                // steepest slope towards the horizon in this direction
+                let mut max_slope = 0.0f32;
+                for &step in SEARCH_STEPS.iter() {
+                    let sx = (x as f32 + dx * step).round() as i32;
+                    let sy = (y as f32 + dy * step).round() as i32;
+                    match height_at(sx, sy) {
+                        Some(height) => max_slope = max_slope.max((height - center) / (step * scale)),
+                        None => break,
+                    }
+                }
+
+                // sky above the horizon angle, cosine weighted
+                let horizon = max_slope.atan();
+                visible += 1.0 - horizon.sin();
+            }
+
+            visibility.push(visible / DIRECTION_COUNT as f32);
+        }
+    }
+
+    visibility
+}
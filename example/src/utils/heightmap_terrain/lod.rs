@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+
+use ash::vk;
+use cgmath::{MetricSpace, Point3};
+
+use ash_render_env::culling::{Aabb, Frustum};
+
+// Quads per chunk side at the full detail, must be divisible by the coarsest step
+pub const CHUNK_SIZE: u32 = 32;
+// Level `n` takes every 2^n-th vertex
+pub const LOD_COUNT: u32 = 5;
+// Chunks closer than this are drawn at full detail, every next level starts twice as far
+const LOD_BASE_DISTANCE: f32 = 6.0;
+
+// Neighbour sides, bit set in crack mask when that neighbour is one level coarser
+const TOP: u32 = 1;
+const RIGHT: u32 = 2;
+const BOTTOM: u32 = 4;
+const LEFT: u32 = 8;
+
+#[derive(Copy, Clone, Hash, PartialEq, Eq)]
+struct PatternKey {
+    width: u32,
+    height: u32,
+    lod: u32,
+    crack_mask: u32,
+}
+
+struct Chunk {
+    // first vertex in the terrain grid
+    vertex_offset: i32,
+    width: u32,
+    height: u32,
+    max_lod: u32,
+    center: Point3<f32>,
+    bounds: Aabb,
+}
+
+// Geomipmapping: terrain grid split into chunks, each drawn at its own level of detail.
+// Index patterns are shared between chunks of the same size, they index relative to the chunk's
+// first vertex. Cracks to a coarser neighbour are closed by snapping odd edge vertices onto even ones.
+pub struct TerrainLod {
+    chunks: Vec<Chunk>,
+    chunks_x: u32,
+    chunks_y: u32,
+    patterns: HashMap<PatternKey, (u32, u32)>,
+    // first index of the 4 corners of chunks of that size, for tessellated drawing
+    patches: HashMap<(u32, u32), u32>,
+    lods: Vec<u32>,
+}
+
+impl TerrainLod {
+    // `positions` are grid vertices in the same order as terrain vertex buffer.
+    // Returns indices of all patterns, to be uploaded as the terrain index buffer
+    pub fn new(w: u32, h: u32, positions: &[Point3<f32>]) -> (TerrainLod, Vec<u32>) {
+        let chunks_x = (w - 1).div_ceil(CHUNK_SIZE);
+        let chunks_y = (h - 1).div_ceil(CHUNK_SIZE);
+
+        let mut chunks = Vec::with_capacity((chunks_x * chunks_y) as usize);
+        for cy in 0..chunks_y {
+            for cx in 0..chunks_x {
+                let x0 = cx * CHUNK_SIZE;
+                let y0 = cy * CHUNK_SIZE;
+                let width = CHUNK_SIZE.min(w - 1 - x0);
+                let height = CHUNK_SIZE.min(h - 1 - y0);
+
+                // smaller chunks at the map border get only levels fitting their size
+                let max_lod = (0..LOD_COUNT)
+                    .take_while(|lod| width.is_multiple_of(1 << lod) && height.is_multiple_of(1 << lod))
+                    .last()
+                    .unwrap_or(0);
+
+                let corner_a = positions[(y0 * w + x0) as usize];
+                let corner_b = positions[((y0 + height) * w + x0 + width) as usize];
+                let bounds = (y0..=y0 + height)
+                    .flat_map(|y| (x0..=x0 + width).map(move |x| (y * w + x) as usize))
+                    .fold(Aabb::empty(), |bounds, idx| bounds.union(&Aabb::new(positions[idx], positions[idx])));
+
+                chunks.push(Chunk {
+                    vertex_offset: (y0 * w + x0) as i32,
+                    width,
+                    height,
+                    max_lod,
+                    center: Point3::new((corner_a.x + corner_b.x) / 2.0, (corner_a.y + corner_b.y) / 2.0, (corner_a.z + corner_b.z) / 2.0),
+                    bounds,
+                });
+            }
+        }
+
+        let mut indices = vec![];
+        let mut patterns = HashMap::new();
+        for chunk in chunks.iter() {
+            for lod in 0..=chunk.max_lod {
+                for crack_mask in 0..16 {
+                    let key = PatternKey { width: chunk.width, height: chunk.height, lod, crack_mask };
+                    if patterns.contains_key(&key) {
+                        continue;
+                    }
+
+                    let first_index = indices.len() as u32;
+                    build_pattern(&key, w, &mut indices);
+                    patterns.insert(key, (first_index, indices.len() as u32 - first_index));
+                }
+            }
+        }
+
+        let mut patches = HashMap::new();
+        for chunk in chunks.iter() {
+            patches.entry((chunk.width, chunk.height)).or_insert_with(|| {
+                let first_index = indices.len() as u32;
+                // quad patch corners, counter-clockwise from the first vertex of the chunk
+                indices.extend_from_slice(&[0, chunk.width, (chunk.height * w) + chunk.width, chunk.height * w]);
+                first_index
+            });
+        }
+
+        let lods = vec![0; chunks.len()];
+        (TerrainLod { chunks, chunks_x, chunks_y, patterns, patches, lods }, indices)
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    // Picks level of every chunk by distance to the camera, one draw per chunk.
+    // Chunks outside `frustum` get zero instances, levels of all chunks still take part in stitching
+    pub fn select(&mut self, camera_pos: Point3<f32>, frustum: &Frustum) -> Vec<vk::DrawIndexedIndirectCommand> {
+        for (idx, chunk) in self.chunks.iter().enumerate() {
+            let distance = chunk.center.distance(camera_pos);
+            let lod = (distance / LOD_BASE_DISTANCE).max(1.0).log2().floor() as u32;
+            self.lods[idx] = lod.min(chunk.max_lod);
+        }
+
+        // neighbours may differ by one level at most, stitching handles only that
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for cy in 0..self.chunks_y {
+                for cx in 0..self.chunks_x {
+                    let idx = self.chunk_idx(cx, cy);
+                    let min_neighbour = self.neighbours(cx, cy).iter()
+                        .filter_map(|n| n.map(|n| self.lods[n]))
+                        .min();
+
+                    if let Some(min_neighbour) = min_neighbour {
+                        if self.lods[idx] > min_neighbour + 1 {
+                            self.lods[idx] = min_neighbour + 1;
+                            changed = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut commands = Vec::with_capacity(self.chunks.len());
+        for cy in 0..self.chunks_y {
+            for cx in 0..self.chunks_x {
+                let idx = self.chunk_idx(cx, cy);
+                let lod = self.lods[idx];
+
+                let mut crack_mask = 0;
+                for (side, neighbour) in [TOP, RIGHT, BOTTOM, LEFT].iter().zip(self.neighbours(cx, cy).iter()) {
+                    if let Some(n) = neighbour {
+                        if self.lods[*n] > lod {
+                            crack_mask |= side;
+                        }
+                    }
+                }
+
+                let chunk = &self.chunks[idx];
+                let key = PatternKey { width: chunk.width, height: chunk.height, lod, crack_mask };
+                let (first_index, index_count) = self.patterns[&key];
+
+                commands.push(vk::DrawIndexedIndirectCommand {
+                    index_count,
+                    instance_count: frustum.intersects_aabb(&chunk.bounds) as u32,
+                    first_index,
+                    vertex_offset: chunk.vertex_offset,
+                    first_instance: 0,
+                });
+            }
+        }
+
+        commands
+    }
+
+    // One quad patch per chunk, the tessellation control shader subdivides it by distance. Chunks outside
+    // `frustum` get zero instances. Also returns the triangle count the shader is expected to produce
+    pub fn select_patches(&self, camera_pos: Point3<f32>, frustum: &Frustum) -> (Vec<vk::DrawIndexedIndirectCommand>, usize) {
+        let mut triangles = 0;
+        let commands = self.chunks.iter()
+            .map(|chunk| {
+                let visible = frustum.intersects_aabb(&chunk.bounds);
+                if visible {
+                    // same levels as TESS_BASE_DISTANCE of terrain_tess.tesc, measured at the chunk center
+                    let detail = (LOD_BASE_DISTANCE / chunk.center.distance(camera_pos)).min(1.0);
+                    let level = |size: u32| (size as f32 * detail).clamp(1.0, size as f32).round() as usize;
+                    triangles += 2 * level(chunk.width) * level(chunk.height);
+                }
+
+                vk::DrawIndexedIndirectCommand {
+                    index_count: 4,
+                    instance_count: visible as u32,
+                    first_index: self.patches[&(chunk.width, chunk.height)],
+                    vertex_offset: chunk.vertex_offset,
+                    first_instance: 0,
+                }
+            })
+            .collect();
+
+        (commands, triangles)
+    }
+
+    fn chunk_idx(&self, cx: u32, cy: u32) -> usize {
+        (cy * self.chunks_x + cx) as usize
+    }
+
+    // top, right, bottom, left
+    fn neighbours(&self, cx: u32, cy: u32) -> [Option<usize>; 4] {
+        [
+            if cy > 0 { Some(self.chunk_idx(cx, cy - 1)) } else { None },
+            if cx + 1 < self.chunks_x { Some(self.chunk_idx(cx + 1, cy)) } else { None },
+            if cy + 1 < self.chunks_y { Some(self.chunk_idx(cx, cy + 1)) } else { None },
+            if cx > 0 { Some(self.chunk_idx(cx - 1, cy)) } else { None },
+        ]
+    }
+}
+
+fn build_pattern(key: &PatternKey, grid_width: u32, indices: &mut Vec<u32>) {
+    let step = 1 << key.lod;
+
+    // moves odd vertices of a stitched edge back onto the coarser neighbour's vertex
+    let snap = |x: u32, y: u32| -> u32 {
+        let coarse = step * 2;
+        let (mut x, mut y) = (x, y);
+        if (y == 0 && key.crack_mask & TOP != 0) || (y == key.height && key.crack_mask & BOTTOM != 0) {
+            x -= x % coarse;
+        }
+        if (x == 0 && key.crack_mask & LEFT != 0) || (x == key.width && key.crack_mask & RIGHT != 0) {
+            y -= y % coarse;
+        }
+        y * grid_width + x
+    };
+
+    for y in (step..=key.height).step_by(step as usize) {
+        for x in (0..key.width).step_by(step as usize) {
+            // same winding as the full resolution grid
+            let triangles = [
+                [snap(x, y - step), snap(x + step, y - step), snap(x, y)],
+                [snap(x, y), snap(x + step, y - step), snap(x + step, y)],
+            ];
+
+            for triangle in triangles.iter() {
+                let degenerate = triangle[0] == triangle[1] || triangle[1] == triangle[2] || triangle[0] == triangle[2];
+                if !degenerate {
+                    indices.extend_from_slice(triangle);
+                }
+            }
+        }
+    }
+}
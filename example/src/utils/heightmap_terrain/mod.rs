@@ -1,2 +1,6 @@
 pub mod terrain;
+pub mod raycast;
 pub mod terrain_renderer;
+pub mod horizon;
+pub mod lod;
+pub mod walkability;
@@ -0,0 +1,332 @@
+use cgmath::{InnerSpace, Point3, Vector3};
+
+use super::walkability::WalkabilitySettings;
+
+const CHUNK_SIZE: u32 = 16;
+const REFINE_STEPS: u32 = 8;
+// Keeps flat chunks from collapsing into zero-height boxes
+const HEIGHT_EPS: f32 = 0.01;
+
+#[allow(dead_code)]
+pub struct Hit {
+    pub position: Point3<f32>,
+    pub normal: Vector3<f32>,
+    pub distance: f32,
+}
+
+struct Chunk {
+    min: Point3<f32>,
+    max: Point3<f32>,
+}
+
+// CPU copy of terrain heights, used for picking without touching the mesh.
+// Grid point (x, y) lives at world ((start_x + x) * scale, height, -(start_y + y) * scale), same as TerrainData vertices.
+pub struct TerrainHeightfield {
+    w: u32,
+    h: u32,
+    scale: f32,
+    heights: Vec<f32>,
+    chunks: Vec<Chunk>,
+}
+
+impl TerrainHeightfield {
+    pub fn new(w: u32, h: u32, scale: f32, heights: Vec<f32>) -> TerrainHeightfield {
+        assert_eq!(heights.len(), (w * h) as usize);
+
+        let mut field = TerrainHeightfield {
+            w,
+            h,
+            scale,
+            heights,
+            chunks: vec![],
+        };
+
+        for cy in (0..h - 1).step_by(CHUNK_SIZE as usize) {
+            for cx in (0..w - 1).step_by(CHUNK_SIZE as usize) {
+                let x1 = (cx + CHUNK_SIZE).min(w - 1);
+                let y1 = (cy + CHUNK_SIZE).min(h - 1);
+
+                let mut min_height = f32::MAX;
+                let mut max_height = f32::MIN;
+                for y in cy..=y1 {
+                    for x in cx..=x1 {
+                        let height = field.grid_height(x, y);
+                        min_height = min_height.min(height);
+                        max_height = max_height.max(height);
+                    }
+                }
+
+                let p0 = field.grid_to_world(cx as f32, cy as f32);
+                let p1 = field.grid_to_world(x1 as f32, y1 as f32);
+                field.chunks.push(Chunk {
+                    min: Point3::new(p0.0.min(p1.0), min_height - HEIGHT_EPS, p0.1.min(p1.1)),
+                    max: Point3::new(p0.0.max(p1.0), max_height + HEIGHT_EPS, p0.1.max(p1.1)),
+                });
+            }
+        }
+
+        field
+    }
+
+    fn grid_height(&self, x: u32, y: u32) -> f32 {
+        self.heights[(y * self.w + x) as usize]
+    }
+
+    fn grid_to_world(&self, x: f32, y: f32) -> (f32, f32) {
+        let start_x = -(self.w as f32) / 2.0;
+        let start_y = -(self.h as f32) / 2.0;
+
+        ((start_x + x) * self.scale, -(start_y + y) * self.scale)
+    }
+
+    fn world_to_grid(&self, x: f32, z: f32) -> (f32, f32) {
+        let start_x = -(self.w as f32) / 2.0;
+        let start_y = -(self.h as f32) / 2.0;
+
+        (x / self.scale - start_x, -z / self.scale - start_y)
+    }
+
+    // Bilinear height at world (x, z), None outside of the terrain
+    pub fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        let (gx, gy) = self.world_to_grid(x, z);
+        if gx < 0.0 || gy < 0.0 || gx > (self.w - 1) as f32 || gy > (self.h - 1) as f32 {
+            return None;
+        }
+
+        Some(self.sample(gx, gy))
+    }
+
+    fn sample(&self, gx: f32, gy: f32) -> f32 {
+        let gx = gx.clamp(0.0, (self.w - 1) as f32);
+        let gy = gy.clamp(0.0, (self.h - 1) as f32);
+
+        let x0 = (gx.floor() as u32).min(self.w - 2);
+        let y0 = (gy.floor() as u32).min(self.h - 2);
+        let fx = gx - x0 as f32;
+        let fy = gy - y0 as f32;
+
+        let top = self.grid_height(x0, y0) * (1.0 - fx) + self.grid_height(x0 + 1, y0) * fx;
+        let bottom = self.grid_height(x0, y0 + 1) * (1.0 - fx) + self.grid_height(x0 + 1, y0 + 1) * fx;
+
+        top * (1.0 - fy) + bottom * fy
+    }
+
+    // Surface normal pointing up (world -Y) at world (x, z), from the slope of the bilinear heights.
+    // None outside of the terrain
+    pub fn normal_at(&self, x: f32, z: f32) -> Option<Vector3<f32>> {
+        self.height_at(x, z)?;
+        let (gx, gy) = self.world_to_grid(x, z);
+
+        Some(self.grid_normal(gx, gy))
+    }
+
+    fn grid_normal(&self, gx: f32, gy: f32) -> Vector3<f32> {
+        // world +z goes along grid -y
+        let dx = self.sample(gx + 1.0, gy) - self.sample(gx - 1.0, gy);
+        let dz = self.sample(gx, gy - 1.0) - self.sample(gx, gy + 1.0);
+
+        Vector3::new(dx, -2.0 * self.scale, dz).normalize()
+    }
+
+    // None outside of the terrain
+    pub fn is_walkable(&self, x: f32, z: f32, settings: &WalkabilitySettings) -> Option<bool> {
+        let height = self.height_at(x, z)?;
+
+        Some(settings.is_walkable(-height, self.normal_at(x, z)?))
+    }
+
+    // Share of grid points passing the settings, to judge generated terrain at a glance
+    pub fn walkable_fraction(&self, settings: &WalkabilitySettings) -> f32 {
+        let mut walkable = 0;
+        for y in 0..self.h {
+            for x in 0..self.w {
+                if settings.is_walkable(-self.grid_height(x, y), self.grid_normal(x as f32, y as f32)) {
+                    walkable += 1;
+                }
+            }
+        }
+
+        walkable as f32 / (self.w * self.h) as f32
+    }
+
+    // Coarse pass over chunk bounding boxes (nearest first), then fixed-step marching over the heightfield
+    // inside every candidate chunk with bisection refinement of the crossing point.
+    // None for a zero or non-finite direction and a non-finite origin
+    pub fn raycast(&self, origin: Point3<f32>, dir: Vector3<f32>) -> Option<Hit> {
+        let length = dir.magnitude();
+        let finite_origin = origin.x.is_finite() && origin.y.is_finite() && origin.z.is_finite();
+        if !(finite_origin && length.is_finite() && length > 0.0) {
+            return None;
+        }
+        let dir = dir / length;
+
+        let mut candidates: Vec<(f32, f32)> = self.chunks.iter()
+            .filter_map(|chunk| ray_aabb(origin, dir, chunk.min, chunk.max))
+            .collect();
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let step = self.scale * 0.5;
+        let side = |t: f32| -> Option<bool> {
+            let p = origin + dir * t;
+            self.height_at(p.x, p.z).map(|height| p.y < height)
+        };
+
+        for (t_enter, t_exit) in candidates {
+            let mut t0 = t_enter;
+            let mut side0 = match side(t0) {
+                Some(side) => side,
+                None => continue,
+            };
+
+            while t0 < t_exit {
+                let t1 = (t0 + step).min(t_exit);
+                let side1 = match side(t1) {
+                    Some(side) => side,
+                    None => break,
+                };
+
+                if side0 != side1 {
+                    let (mut lo, mut hi) = (t0, t1);
+                    for _ in 0..REFINE_STEPS {
+                        let mid = (lo + hi) * 0.5;
+                        if side(mid) == Some(side0) {
+                            lo = mid;
+                        } else {
+                            hi = mid;
+                        }
+                    }
+
+                    let distance = (lo + hi) * 0.5;
+                    let position = origin + dir * distance;
+                    let (gx, gy) = self.world_to_grid(position.x, position.z);
+                    let mut normal = self.grid_normal(gx, gy);
+                    if normal.dot(dir) > 0.0 {
+                        normal = -normal;
+                    }
+
+                    return Some(Hit { position, normal, distance });
+                }
+
+                t0 = t1;
+                side0 = side1;
+            }
+        }
+
+        None
+    }
+}
+
+// Slab test, returns (t_enter, t_exit) clamped to the ray start
+fn ray_aabb(origin: Point3<f32>, dir: Vector3<f32>, min: Point3<f32>, max: Point3<f32>) -> Option<(f32, f32)> {
+    let mut t_min = 0.0f32;
+    let mut t_max = f32::MAX;
+
+    for axis in 0..3 {
+        let (o, d, lo, hi) = (origin[axis], dir[axis], min[axis], max[axis]);
+        if d.abs() < f32::EPSILON {
+            if o < lo || o > hi {
+                return None;
+            }
+            continue;
+        }
+
+        let t1 = (lo - o) / d;
+        let t2 = (hi - o) / d;
+        t_min = t_min.max(t1.min(t2));
+        t_max = t_max.min(t1.max(t2));
+
+        if t_min > t_max {
+            return None;
+        }
+    }
+
+    Some((t_min, t_max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 8x8 grid points of unit scale: world x in [-4, 3], z in [-3, 4]
+    const SIZE: u32 = 8;
+
+    fn field(height: impl Fn(u32, u32) -> f32) -> TerrainHeightfield {
+        let heights = (0..SIZE).flat_map(|y| (0..SIZE).map(move |x| (x, y))).map(|(x, y)| height(x, y)).collect();
+        TerrainHeightfield::new(SIZE, SIZE, 1.0, heights)
+    }
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 0.01, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn hit_flat() {
+        let field = field(|_, _| 2.0);
+
+        let hit = field.raycast(Point3::new(0.5, -5.0, 0.5), Vector3::new(0.0, 1.0, 0.0)).unwrap();
+        assert_near(hit.position.y, 2.0);
+        assert_near(hit.distance, 7.0);
+        assert_near(hit.normal.y, -1.0);
+
+        // the direction doesn't have to be normalized
+        let hit = field.raycast(Point3::new(-3.0, -1.0, 0.5), Vector3::new(3.0, 3.0, 0.0)).unwrap();
+        assert_near(hit.position.x, 0.0);
+        assert_near(hit.position.y, 2.0);
+        assert_near(hit.distance, 3.0 * 2.0_f32.sqrt());
+    }
+
+    #[test]
+    fn hit_slope() {
+        // rises by 0.5 per grid step along x, height = 0.5 * (x + 4) in world units
+        let field = field(|x, _| x as f32 * 0.5);
+
+        let hit = field.raycast(Point3::new(0.5, -5.0, 0.5), Vector3::new(0.0, 1.0, 0.0)).unwrap();
+        assert_near(hit.position.y, 2.25);
+        let expected_normal = Vector3::new(1.0, -2.0, 0.0).normalize();
+        assert_near(hit.normal.x, expected_normal.x);
+        assert_near(hit.normal.y, expected_normal.y);
+        assert_near(hit.normal.z, expected_normal.z);
+
+        let hit = field.raycast(Point3::new(-3.0, -2.0, 0.5), Vector3::new(1.0, 1.0, 0.0)).unwrap();
+        assert_near(hit.position.x, 2.0);
+        assert_near(hit.position.y, 3.0);
+        assert_near(hit.distance, 5.0 * 2.0_f32.sqrt());
+    }
+
+    #[test]
+    fn miss_past_bounds() {
+        let field = field(|_, _| 2.0);
+
+        // straight down next to the terrain, where the plane would continue
+        assert!(field.raycast(Point3::new(10.0, -5.0, 0.5), Vector3::new(0.0, 1.0, 0.0)).is_none());
+        // descending too slowly to reach the surface before the far edge
+        assert!(field.raycast(Point3::new(-3.5, -5.0, 0.5), Vector3::new(1.0, 0.1, 0.0)).is_none());
+        // pointing away from the terrain
+        assert!(field.raycast(Point3::new(0.5, -5.0, 0.5), Vector3::new(0.0, -1.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn start_below_surface() {
+        let field = field(|_, _| 2.0);
+
+        // going up crosses the surface from below, the normal faces the ray
+        let hit = field.raycast(Point3::new(0.5, 5.0, 0.5), Vector3::new(0.0, -1.0, 0.0)).unwrap();
+        assert_near(hit.position.y, 2.0);
+        assert_near(hit.distance, 3.0);
+        assert_near(hit.normal.y, 1.0);
+
+        // going further down never crosses it
+        assert!(field.raycast(Point3::new(0.5, 5.0, 0.5), Vector3::new(0.0, 1.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn degenerate_ray_misses() {
+        let field = field(|_, _| 2.0);
+        let origin = Point3::new(0.5, -5.0, 0.5);
+
+        assert!(field.raycast(origin, Vector3::new(0.0, 0.0, 0.0)).is_none());
+        assert!(field.raycast(origin, Vector3::new(f32::NAN, 1.0, 0.0)).is_none());
+        assert!(field.raycast(origin, Vector3::new(0.0, f32::INFINITY, 0.0)).is_none());
+        assert!(field.raycast(Point3::new(f32::NAN, -5.0, 0.5), Vector3::new(0.0, 1.0, 0.0)).is_none());
+    }
+}
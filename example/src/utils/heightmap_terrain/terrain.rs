@@ -3,40 +3,118 @@ use std::sync::Arc;
 
 use ash::version::DeviceV1_0;
 use ash::vk;
-use cgmath::{InnerSpace, Vector3};
+use cgmath::{InnerSpace, Point3, Vector3};
 use memoffset::offset_of;
 
 use ash_render_env::env::RenderEnv;
-use ash_render_env::utils::texture::Texture;
+use ash_render_env::memory_tracker;
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::utils::ktx2::CompressedFormats;
+use ash_render_env::utils::texture::{Texture, TextureData};
 use ash_render_env::utils::buffer_utils::create_data_buffer;
 
+use super::horizon;
+use super::lod::TerrainLod;
+use super::raycast::{Hit, TerrainHeightfield};
+use super::walkability::WalkabilitySettings;
+
+// World size of the heightmap
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct TerrainScale {
+    // world height of the largest heightmap value
+    pub vertical: f32,
+    // distance between grid points
+    pub spacing: f32,
+}
+
+impl Default for TerrainScale {
+    fn default() -> TerrainScale {
+        TerrainScale {
+            vertical: 4.0,
+            spacing: 0.1,
+        }
+    }
+}
+
 pub struct HeightMap {
     pub w: u32,
     pub h: u32,
+    pub scale: TerrainScale,
+    // heights from 0 to 1
     height_fn: Box<dyn Fn(u32, u32) -> f32>,
 }
 
 #[allow(dead_code)]
 impl HeightMap {
-    pub fn from_png(path: &Path) -> HeightMap {
-        let image_object = image::open(path).unwrap().to_rgba8();
+    // PNG and other images by the red channel, raw heightfields by .r16/.r32 extension
+    pub fn load(path: &Path, scale: TerrainScale) -> Result<HeightMap, String> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("r16") | Some("r32") => HeightMap::from_raw(path, scale),
+            _ => HeightMap::from_png(path, scale),
+        }
+    }
+
+    // 16-bit images keep their precision, 8-bit ones are stepped by 1/255 of the vertical scale
+    pub fn from_png(path: &Path, scale: TerrainScale) -> Result<HeightMap, String> {
+        let image_object = image::open(path)
+            .map_err(|err| format!("Failed to load heightmap: {}", err))?
+            .to_rgba16();
         let w = image_object.width();
         let h = image_object.height();
 
         let image_data = image_object.into_raw();
-        HeightMap {
+        let heights = image_data.chunks_exact(4)
+            .map(|pixel| pixel[0] as f32 / u16::MAX as f32)
+            .collect();
+
+        HeightMap::from_heights(w, h, scale, heights)
+    }
+
+    // Square grid of little-endian values without a header: .r16 - unsigned 16-bit, .r32 - float from 0 to 1
+    pub fn from_raw(path: &Path, scale: TerrainScale) -> Result<HeightMap, String> {
+        let data = std::fs::read(path)
+            .map_err(|err| format!("Failed to load heightmap: {}", err))?;
+
+        let float = path.extension().is_some_and(|ext| ext == "r32");
+        let value_size = if float { 4 } else { 2 };
+        let side = ((data.len() / value_size) as f64).sqrt() as u32;
+        if (side * side) as usize * value_size != data.len() {
+            return Err(format!("Raw heightmap of {} bytes is not a square grid", data.len()));
+        }
+
+        let heights = if float {
+            data.chunks_exact(4)
+                .map(|value| f32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+                .collect()
+        } else {
+            data.chunks_exact(2)
+                .map(|value| u16::from_le_bytes([value[0], value[1]]) as f32 / u16::MAX as f32)
+                .collect()
+        };
+
+        HeightMap::from_heights(side, side, scale, heights)
+    }
+
+    fn from_heights(w: u32, h: u32, scale: TerrainScale, heights: Vec<f32>) -> Result<HeightMap, String> {
+        if w < 2 || h < 2 {
+            return Err("Heightmap must be at least 2x2".to_string());
+        }
+
+        Ok(HeightMap {
             w,
             h,
+            scale,
             height_fn: Box::new(move |x: u32, y: u32| -> f32 {
-                4.0 * (image_data[(w * y * 4 + x * 4) as usize] as f32) / 255.0
+                heights[(w * y + x) as usize]
             }),
-        }
+        })
     }
 
     pub fn empty(w: u32, h: u32) -> HeightMap {
         HeightMap {
             w,
             h,
+            scale: TerrainScale::default(),
             height_fn: Box::new(|_, _| -> f32 { 0.0 }),
         }
     }
@@ -57,7 +135,7 @@ impl HeightMap {
         let yy = clamp(y, 0, (self.h - 1) as i32);
         let fn_ = &self.height_fn;
 
-        -fn_(xx as u32, yy as u32)
+        -fn_(xx as u32, yy as u32) * self.scale.vertical
     }
 }
 
@@ -65,6 +143,8 @@ pub struct Vertex {
     position: [f32; 3],
     normal: [f32; 3],
     texcoord: [f32; 2],
+    // ambient occlusion by surrounding terrain
+    sky_visibility: f32,
 }
 
 impl Vertex {
@@ -98,6 +178,12 @@ impl Vertex {
                 format: vk::Format::R32G32_SFLOAT,
                 offset: offset_of!(Self, texcoord) as u32,
             },
+            vk::VertexInputAttributeDescription {
+                binding: 0,
+                location: 3,
+                format: vk::Format::R32_SFLOAT,
+                offset: offset_of!(Self, sky_visibility) as u32,
+            },
         ]
     }
 }
@@ -109,9 +195,14 @@ pub struct TerrainData {
 
     pub index_buffer: vk::Buffer,
     pub index_buffer_memory: vk::DeviceMemory,
-    pub index_count: usize,
+    pub lod: TerrainLod,
 
     pub(super) texture: Texture,
+    // grid heights (R32) and normal with sky visibility (RGBA8) for the tessellated drawing
+    pub(super) height_texture: Texture,
+    pub(super) surface_texture: Texture,
+    pub heightfield: TerrainHeightfield,
+    pub scale: TerrainScale,
 }
 
 impl TerrainData {
@@ -120,11 +211,12 @@ impl TerrainData {
         let h = height_map.h;
 
         let mut vertices = Vec::with_capacity((h * w) as usize);
-        let mut indices = Vec::with_capacity((h * (w - 1) * 6) as usize);
+        let mut heights = Vec::with_capacity((h * w) as usize);
+        let mut positions = Vec::with_capacity((h * w) as usize);
 
+        let scale = height_map.scale.spacing;
         let get_pos = |x: i32, y: i32| -> Vector3<f32> {
             let height = height_map.get_height(x, y);
-            let scale = 0.1 as f32;
             let start_x = -(w as f32) / 2.0;
             let start_y = -(h as f32) / 2.0;
 
@@ -151,27 +243,41 @@ impl TerrainData {
 
                 let normal = -(lb + br + rt + tl).normalize();
 
+                heights.push(pos.y);
+                positions.push(Point3::new(pos.x, pos.y, pos.z));
                 vertices.push(Vertex {
                     position: pos.into(), //[(x as f32) * 0.1, height, -(y as f32) * 0.1],
                     normal: normal.into(),
                     texcoord: [x as f32, y as f32],
+                    sky_visibility: 1.0,
                 });
             }
         }
 
-        for y in 1..(h) {
-            for x in 0..(w - 1) {
-                indices.push((y - 1) * w + x);
-                indices.push((y - 1) * w + x + 1);
-                indices.push((y) * w + x);
-
-                indices.push((y) * w + x);
-                indices.push((y - 1) * w + x + 1);
-                indices.push((y) * w + x + 1);
-            }
+        // rebuilt with the terrain, so it follows every heightmap change
+        let sky_visibility = horizon::sky_visibility(w, h, scale, &heights);
+        for (vertex, visibility) in vertices.iter_mut().zip(sky_visibility) {
+            vertex.sky_visibility = visibility;
         }
 
-        let index_count = indices.len();
+        let height_pixels: Vec<u8> = heights.iter().flat_map(|height| height.to_le_bytes()).collect();
+        let surface_pixels: Vec<u8> = vertices.iter()
+            .flat_map(|vertex| {
+                let [x, y, z] = vertex.normal;
+                [x * 0.5 + 0.5, y * 0.5 + 0.5, z * 0.5 + 0.5, vertex.sky_visibility]
+            })
+            .map(|value| (value.clamp(0.0, 1.0) * 255.0).round() as u8)
+            .collect();
+        let height_texture = Texture::from_pixels(env.device().clone(), env.command_pool(), env.queue(), &env.mem_properties,
+                                                  vk::Format::R32_SFLOAT, &height_pixels, w, h, false, 1.0)
+            .expect("Failed to create terrain height texture!");
+        let surface_texture = Texture::from_pixels(env.device().clone(), env.command_pool(), env.queue(), &env.mem_properties,
+                                                   vk::Format::R8G8B8A8_UNORM, &surface_pixels, w, h, false, 1.0)
+            .expect("Failed to create terrain surface texture!");
+        env.set_debug_name(height_texture.texture_image, "terrain heights");
+        env.set_debug_name(surface_texture.texture_image, "terrain normals");
+
+        let (lod, indices) = TerrainLod::new(w, h, &positions);
 
         let (vertex_buffer, vertex_buffer_memory) = create_data_buffer(
             env.instance(),
@@ -190,14 +296,22 @@ impl TerrainData {
             env.queue(),
             vk::BufferUsageFlags::INDEX_BUFFER,
             indices);
+        env.set_debug_name(vertex_buffer, "terrain vertices");
+        env.set_debug_name(index_buffer, "terrain indices");
 
-        let texture = Texture::new(
+        // ground.ktx2 is used instead when the device supports its format
+        let texture_data = TextureData::load(Path::new("./assets/terrain/ground.png"), true, &CompressedFormats::query(&env))
+            .expect("Failed to load terrain texture!");
+        let texture = Texture::from_data(
             env.device().clone(),
             env.command_pool(),
             env.queue(),
             &env.mem_properties,
-            Path::new("./assets/terrain/ground.png"),
-        );
+            &texture_data,
+            vk::Format::R8G8B8A8_SRGB,
+            env.max_sampler_anisotropy(),
+        ).expect("Failed to load terrain texture!");
+        env.set_debug_name(texture.texture_image, "terrain ground.png");
 
         TerrainData {
             device: env.device().clone(),
@@ -207,10 +321,36 @@ impl TerrainData {
             index_buffer,
             index_buffer_memory,
 
-            index_count,
+            lod,
             texture,
+            height_texture,
+            surface_texture,
+            heightfield: TerrainHeightfield::new(w, h, scale, heights),
+            scale: height_map.scale,
         }
     }
+
+    // World y of the ground at (x, z), bilinear over the grid (up is -Y). None outside of the terrain
+    pub fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        self.heightfield.height_at(x, z)
+    }
+
+    // Up facing normal at (x, z), None outside of the terrain
+    pub fn normal_at(&self, x: f32, z: f32) -> Option<Vector3<f32>> {
+        self.heightfield.normal_at(x, z)
+    }
+
+    pub fn raycast(&self, origin: Point3<f32>, dir: Vector3<f32>) -> Option<Hit> {
+        self.heightfield.raycast(origin, dir)
+    }
+
+    pub fn is_walkable(&self, x: f32, z: f32, settings: &WalkabilitySettings) -> Option<bool> {
+        self.heightfield.is_walkable(x, z, settings)
+    }
+
+    pub fn walkable_fraction(&self, settings: &WalkabilitySettings) -> f32 {
+        self.heightfield.walkable_fraction(settings)
+    }
 }
 
 
@@ -218,10 +358,14 @@ impl Drop for TerrainData {
     fn drop(&mut self) {
         unsafe {
             self.device.destroy_buffer(self.index_buffer, None);
+            object_tracker::destroyed(ObjectKind::Buffer);
             self.device.free_memory(self.index_buffer_memory, None);
+            memory_tracker::freed(self.index_buffer_memory);
 
             self.device.destroy_buffer(self.vertex_buffer, None);
+            object_tracker::destroyed(ObjectKind::Buffer);
             self.device.free_memory(self.vertex_buffer_memory, None);
+            memory_tracker::freed(self.vertex_buffer_memory);
         }
     }
 }
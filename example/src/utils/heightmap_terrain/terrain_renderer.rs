@@ -5,10 +5,17 @@ use ash::version::DeviceV1_0;
 use ash::vk;
 use cgmath::{Matrix4, SquareMatrix};
 
+use ash_render_env::camera::CameraDescriptor;
+use ash_render_env::culling::CullStats;
 use ash_render_env::descriptor_set::DescriptorSet;
 use ash_render_env::env::RenderEnv;
+use ash_render_env::memory_tracker;
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::utils::buffer_utils::create_buffer;
+use ash_render_env::utils::DepthMode;
 use ash_render_env::pipeline_builder::{Pipeline, PipelineBuilder};
 use ash_render_env::shader;
+use crate::utils::gbuffer::GeometryPipeline;
 use crate::utils::uniform_buffer::UboBuffers;
 
 use super::terrain::{TerrainData, Vertex};
@@ -20,34 +27,47 @@ pub struct TerrainRenderer {
 
     render_pass: vk::RenderPass,
     pipeline: Pipeline,
+    // created on the first switch to wireframe, layouts are the same as of `pipeline`
+    wireframe_pipeline: Option<Pipeline>,
+    wireframe: bool,
+    // depth only draws before the G-buffer ones, the fill pipeline then doesn't write depth
+    depth_prepass: bool,
+    depth_prepass_pipeline: Pipeline,
+    // sets of the vertex stage only, one per frame
+    depth_prepass_sets: Vec<DescriptorSet>,
+    // empty when the pre-pass is off
+    depth_prepass_cmds: Vec<vk::CommandBuffer>,
+    // chunks drawn as patches displaced by the height texture, created when turned on.
+    // Follows the wireframe mode and skips the depth pre-pass, its depth wouldn't match
+    tessellation_pipeline: Option<Pipeline>,
+    // also with the height and surface textures, one per frame
+    tessellation_sets: Vec<DescriptorSet>,
+    color_attachment_count: usize,
+    msaa_samples: vk::SampleCountFlags,
+    depth_mode: DepthMode,
 
     descriptor_sets: Vec<DescriptorSet>,
     uniforms: UboBuffers,
+    // per frame draw of every chunk at its selected LOD, rewritten each frame
+    indirect_buffers: Vec<(vk::Buffer, vk::DeviceMemory)>,
+    triangle_count: usize,
+    cull_stats: CullStats,
+    walkability_params: [f32; 4],
     env: Arc<RenderEnv>,
 
+    dimensions: [u32; 2],
     current_frame: usize,
     max_inflight_frames: usize,
 }
 
 impl TerrainRenderer {
     pub fn new(env: Arc<RenderEnv>, render_pass: vk::RenderPass, color_attachment_count: usize,
-               terrain: TerrainData, msaa_samples: vk::SampleCountFlags, max_inflight_frames: usize,
+               terrain: TerrainData, msaa_samples: vk::SampleCountFlags, depth_mode: DepthMode, max_inflight_frames: usize,
                dimensions: [u32; 2]) -> TerrainRenderer
     {
-        let pipeline = {
-            let vert_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/heightmap_terrain/terrain.vert.spv");
-            let frag_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/heightmap_terrain/terrain.frag.spv");
-
-            PipelineBuilder::new(env.device().clone(), render_pass, 0)
-                .vertex_shader(vert_shader_module)
-                .fragment_shader(frag_shader_module)
-                .vertex_input(Vertex::binding_descriptions(), Vertex::attribute_descriptions())
-                .msaa(msaa_samples)
-                .color_attachment_count(color_attachment_count)
-                .with_depth_func(vk::CompareOp::LESS_OR_EQUAL)
-                .cull_mode(vk::CullModeFlags::FRONT)
-                .build()
-        };
+        let pipeline = Self::create_pipeline(&env, render_pass, color_attachment_count, msaa_samples, depth_mode, GeometryPipeline::Fill, false);
+        let depth_prepass_pipeline = Self::create_pipeline(&env, render_pass, color_attachment_count, msaa_samples, depth_mode,
+                                                           GeometryPipeline::DepthPrepass, false);
 
         let uniforms = UboBuffers::new(
             env.instance(),
@@ -56,36 +76,232 @@ impl TerrainRenderer {
             max_inflight_frames,
         );
 
-        let mut cmd_bufs = vec![];
+        let indirect_buffers = Self::create_indirect_buffers(&env, &terrain, max_inflight_frames);
+
         let mut descriptor_sets = vec![];
         for i in 0..max_inflight_frames {
             descriptor_sets.push(
                 DescriptorSet::builder(env.device(), pipeline.descriptor_set_layouts.get(0).unwrap())
                     .add_buffer(uniforms.uniform_buffers[i])
-                    .add_image(terrain.texture.texture_image_view, terrain.texture.texture_sampler)
+                    .add_texture(&terrain.texture)
                     .build()
-            );
-            cmd_bufs.push(
-                Self::build_cmd_buf(&env, render_pass, &pipeline, &descriptor_sets[i], &terrain, dimensions)
+                    .expect("Failed to create descriptor set!")
             );
         }
 
-        TerrainRenderer {
+        let depth_prepass_sets = (0..max_inflight_frames)
+            .map(|i| {
+                DescriptorSet::builder(env.device(), &depth_prepass_pipeline.descriptor_set_layouts[0])
+                    .add_buffer(uniforms.uniform_buffers[i])
+                    .build()
+                    .expect("Failed to create descriptor set!")
+            })
+            .collect();
+
+        let mut renderer = TerrainRenderer {
             env: env.clone(),
             pipeline,
-            cmd_bufs,
+            wireframe_pipeline: None,
+            wireframe: false,
+            depth_prepass: false,
+            depth_prepass_pipeline,
+            depth_prepass_sets,
+            depth_prepass_cmds: vec![],
+            tessellation_pipeline: None,
+            tessellation_sets: vec![],
+            color_attachment_count,
+            msaa_samples,
+            depth_mode,
+            cmd_bufs: vec![],
             render_pass,
             uniforms,
             descriptor_sets,
+            indirect_buffers,
+            triangle_count: 0,
+            cull_stats: CullStats::default(),
+            walkability_params: [0.0; 4],
             vertex_buffer: terrain,
+            dimensions,
             current_frame: 0,
             max_inflight_frames,
+        };
+        renderer.resize_framebuffer(dimensions);
+
+        renderer
+    }
+
+    // `tessellated` - chunk corners are drawn as patches, needs the tessellation_shader feature
+    fn create_pipeline(env: &RenderEnv, render_pass: vk::RenderPass, color_attachment_count: usize, msaa_samples: vk::SampleCountFlags,
+                       depth_mode: DepthMode, kind: GeometryPipeline, tessellated: bool) -> Pipeline {
+        let vert_shader_path = if tessellated {
+            "assets/shaders/spv/heightmap_terrain/terrain_tess.vert.spv"
+        } else {
+            "assets/shaders/spv/heightmap_terrain/terrain.vert.spv"
+        };
+        let vert_shader_module = shader::Shader::load(env.device(), vert_shader_path).expect("Failed to load shader!");
+        let frag_shader_path = match kind {
+            GeometryPipeline::DepthPrepass => "assets/shaders/spv/depth_only.frag.spv",
+            _ => "assets/shaders/spv/heightmap_terrain/terrain.frag.spv",
+        };
+        let frag_shader_module = shader::Shader::load(env.device(), frag_shader_path).expect("Failed to load shader!");
+        let polygon_mode = if kind == GeometryPipeline::Wireframe { vk::PolygonMode::LINE } else { vk::PolygonMode::FILL };
+
+        let mut builder = PipelineBuilder::new(env.device().clone(), render_pass, 0)
+            .pipeline_cache(env.pipeline_cache())
+            .device_features(env.enabled_features())
+            .vertex_shader(vert_shader_module)
+            .fragment_shader(frag_shader_module)
+            .vertex_input(Vertex::binding_descriptions(), Vertex::attribute_descriptions())
+            .msaa(msaa_samples)
+            .color_attachment_count(color_attachment_count)
+            .with_depth_func(vk::CompareOp::LESS_OR_EQUAL)
+            .depth_write(kind != GeometryPipeline::FillAfterPrepass)
+            .depth_mode(depth_mode)
+            .polygon_mode(polygon_mode)
+            .cull_mode(vk::CullModeFlags::FRONT);
+        if kind == GeometryPipeline::DepthPrepass {
+            builder = builder.disable_color_writes();
+        }
+        if tessellated {
+            let tesc_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/heightmap_terrain/terrain_tess.tesc.spv").expect("Failed to load shader!");
+            let tese_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/heightmap_terrain/terrain_tess.tese.spv").expect("Failed to load shader!");
+            builder = builder
+                .tessellation_shaders(tesc_shader_module, tese_shader_module)
+                .patch_control_points(4);
+        }
+
+        builder
+            .build()
+            .expect("Failed to create pipeline!")
+    }
+
+    fn fill_pipeline_kind(&self) -> GeometryPipeline {
+        if self.depth_prepass { GeometryPipeline::FillAfterPrepass } else { GeometryPipeline::Fill }
+    }
+
+    // Draws triangle edges only. Command buffers are re-recorded, so the caller must make sure they are not in use
+    pub fn set_wireframe(&mut self, wireframe: bool) {
+        if wireframe && self.wireframe_pipeline.is_none() {
+            self.wireframe_pipeline = Some(Self::create_pipeline(&self.env, self.render_pass, self.color_attachment_count,
+                                                                 self.msaa_samples, self.depth_mode, GeometryPipeline::Wireframe, false));
+        }
+
+        self.wireframe = wireframe;
+        if self.tessellation_pipeline.is_some() {
+            self.tessellation_pipeline = Some(self.create_tessellation_pipeline());
         }
+        self.resize_framebuffer(self.dimensions);
+    }
+
+    pub fn tessellation_supported(&self) -> bool {
+        self.env.enabled_features().tessellation_shader == vk::TRUE
+    }
+
+    pub fn tessellation(&self) -> bool {
+        self.tessellation_pipeline.is_some()
+    }
+
+    // Subdivides chunks on the GPU by distance instead of switching index patterns, ignored when
+    // the device can't tessellate. Command buffers are re-recorded, so the caller must make sure they are not in use
+    pub fn set_tessellation(&mut self, tessellation: bool) {
+        if tessellation && self.tessellation_supported() {
+            let pipeline = self.create_tessellation_pipeline();
+            self.tessellation_sets = Self::create_tessellation_sets(self.env.device(), &pipeline, &self.uniforms, &self.vertex_buffer);
+            self.tessellation_pipeline = Some(pipeline);
+        } else {
+            self.tessellation_pipeline = None;
+            self.tessellation_sets.clear();
+        }
+
+        self.resize_framebuffer(self.dimensions);
+    }
+
+    fn create_tessellation_pipeline(&self) -> Pipeline {
+        let kind = if self.wireframe { GeometryPipeline::Wireframe } else { GeometryPipeline::Fill };
+        Self::create_pipeline(&self.env, self.render_pass, self.color_attachment_count, self.msaa_samples, self.depth_mode, kind, true)
     }
 
-    fn build_cmd_buf(env: &RenderEnv, render_pass: vk::RenderPass, pipeline: &Pipeline, descriptor_set: &DescriptorSet, vertex_buffer: &TerrainData, dimensions: [u32; 2]) -> vk::CommandBuffer {
-        let command_buffer = env.create_secondary_command_buffer();
-        let device = env.device();
+    fn create_tessellation_sets(device: &ash::Device, pipeline: &Pipeline, uniforms: &UboBuffers, terrain: &TerrainData) -> Vec<DescriptorSet> {
+        uniforms.uniform_buffers.iter()
+            .map(|&uniform_buffer| {
+                DescriptorSet::builder(device, &pipeline.descriptor_set_layouts[0])
+                    .add_buffer(uniform_buffer)
+                    .add_texture(&terrain.texture)
+                    .add_texture(&terrain.height_texture)
+                    .add_texture(&terrain.surface_texture)
+                    .build()
+                    .expect("Failed to create descriptor set!")
+            })
+            .collect()
+    }
+
+    // Pipelines for a new G-buffer render pass, e.g. with another sample count. Descriptor sets are kept,
+    // the layouts don't change. Command buffers are re-recorded, so the caller must make sure they are not in use
+    pub fn set_render_pass(&mut self, render_pass: vk::RenderPass, msaa_samples: vk::SampleCountFlags) {
+        self.render_pass = render_pass;
+        self.msaa_samples = msaa_samples;
+        self.pipeline = Self::create_pipeline(&self.env, render_pass, self.color_attachment_count, msaa_samples, self.depth_mode,
+                                              self.fill_pipeline_kind(), false);
+        self.depth_prepass_pipeline = Self::create_pipeline(&self.env, render_pass, self.color_attachment_count, msaa_samples, self.depth_mode,
+                                                            GeometryPipeline::DepthPrepass, false);
+        self.wireframe_pipeline = None;
+        self.set_wireframe(self.wireframe);
+    }
+
+    // Lays down depth with depth_prepass_draw() before the G-buffer draw, the terrain fragment shader
+    // then runs for visible pixels only. Wireframe mode draws without it.
+    // Command buffers are re-recorded, so the caller must make sure they are not in use
+    pub fn set_depth_prepass(&mut self, depth_prepass: bool) {
+        self.depth_prepass = depth_prepass;
+        self.pipeline = Self::create_pipeline(&self.env, self.render_pass, self.color_attachment_count, self.msaa_samples, self.depth_mode,
+                                              self.fill_pipeline_kind(), false);
+        self.resize_framebuffer(self.dimensions);
+    }
+
+    fn create_indirect_buffers(env: &RenderEnv, terrain: &TerrainData, count: usize) -> Vec<(vk::Buffer, vk::DeviceMemory)> {
+        let size = std::mem::size_of::<vk::DrawIndexedIndirectCommand>() * terrain.lod.chunk_count();
+
+        (0..count)
+            .map(|_| create_buffer(
+                env.device(),
+                size as u64,
+                vk::BufferUsageFlags::INDIRECT_BUFFER,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                &env.mem_properties,
+            ))
+            .collect()
+    }
+
+    fn destroy_indirect_buffers(&mut self) {
+        unsafe {
+            for &(buffer, memory) in self.indirect_buffers.iter() {
+                self.env.device().destroy_buffer(buffer, None);
+                object_tracker::destroyed(ObjectKind::Buffer);
+                self.env.device().free_memory(memory, None);
+                memory_tracker::freed(memory);
+            }
+        }
+        self.indirect_buffers.clear();
+    }
+
+    // `depth_prepass` - records the depth only draws of the same chunks
+    fn build_cmd_buf(&self, frame: usize, dimensions: [u32; 2], depth_prepass: bool) -> vk::CommandBuffer {
+        let command_buffer = self.env.create_secondary_command_buffer();
+        let device = self.env.device();
+        let render_pass = self.render_pass;
+        let (pipeline, descriptor_set) = if depth_prepass {
+            (&self.depth_prepass_pipeline, &self.depth_prepass_sets[frame])
+        } else if let Some(tessellation_pipeline) = &self.tessellation_pipeline {
+            (tessellation_pipeline, &self.tessellation_sets[frame])
+        } else {
+            let pipeline = match &self.wireframe_pipeline {
+                Some(wireframe_pipeline) if self.wireframe => wireframe_pipeline,
+                _ => &self.pipeline,
+            };
+            (pipeline, &self.descriptor_sets[frame])
+        };
+        let vertex_buffer = &self.vertex_buffer;
+        let indirect_buffer = self.indirect_buffers[frame].0;
 
         let inheritance_info = vk::CommandBufferInheritanceInfo {
             s_type: vk::StructureType::COMMAND_BUFFER_INHERITANCE_INFO,
@@ -149,12 +365,21 @@ impl TerrainRenderer {
                 &[],
             );
 
+            if !depth_prepass {
+                let walkability: Vec<u8> = self.walkability_params.iter().flat_map(|v| v.to_le_bytes()).collect();
+                device.cmd_push_constants(command_buffer, pipeline.pipeline_layout, vk::ShaderStageFlags::FRAGMENT, 0, &walkability);
+            }
+
             let vertex_buffers = [vertex_buffer.vertex_buffer];
             let offsets = [0_u64];
             device.cmd_bind_vertex_buffers(command_buffer, 0, &vertex_buffers, &offsets);
             device.cmd_bind_index_buffer(command_buffer, vertex_buffer.index_buffer, 0, vk::IndexType::UINT32);
 
-            device.cmd_draw_indexed(command_buffer, vertex_buffer.index_count as u32, 1, 0, 0, 0);
+            // one draw per chunk, multiDrawIndirect is not required
+            let stride = std::mem::size_of::<vk::DrawIndexedIndirectCommand>();
+            for i in 0..vertex_buffer.lod.chunk_count() {
+                device.cmd_draw_indexed_indirect(command_buffer, indirect_buffer, (i * stride) as u64, 1, stride as u32);
+            }
 
             device
                 .end_command_buffer(command_buffer)
@@ -164,26 +389,118 @@ impl TerrainRenderer {
         command_buffer
     }
 
-    pub fn resize_framebuffer(&mut self, dimensions: [u32; 2]) {
-        unsafe {
-            self.env.device().free_command_buffers(self.env.command_pool(), &self.cmd_bufs);
+    pub fn terrain(&self) -> &TerrainData {
+        &self.vertex_buffer
+    }
+
+    // Command buffers must not be in use
+    pub fn set_terrain(&mut self, terrain: TerrainData) {
+        self.descriptor_sets = (0..self.max_inflight_frames)
+            .map(|i| {
+                DescriptorSet::builder(self.env.device(), &self.pipeline.descriptor_set_layouts[0])
+                    .add_buffer(self.uniforms.uniform_buffers[i])
+                    .add_texture(&terrain.texture)
+                    .build()
+                    .expect("Failed to create descriptor set!")
+            })
+            .collect();
+        if let Some(tessellation_pipeline) = &self.tessellation_pipeline {
+            self.tessellation_sets = Self::create_tessellation_sets(self.env.device(), tessellation_pipeline, &self.uniforms, &terrain);
         }
+        self.destroy_indirect_buffers();
+        self.indirect_buffers = Self::create_indirect_buffers(&self.env, &terrain, self.max_inflight_frames);
+        self.vertex_buffer = terrain;
+        self.resize_framebuffer(self.dimensions);
+    }
+
+    // Overlay of WalkabilitySettings::shader_params. Command buffers are re-recorded, so they must not be in use
+    pub fn set_walkability(&mut self, params: [f32; 4]) {
+        self.walkability_params = params;
+        self.resize_framebuffer(self.dimensions);
+    }
+
+    pub fn resize_framebuffer(&mut self, dimensions: [u32; 2]) {
+        self.free_cmd_bufs();
 
         let mut cmd_bufs = vec![];
 
         for i in 0..self.max_inflight_frames {
             cmd_bufs.push(
-                Self::build_cmd_buf(&self.env, self.render_pass, &self.pipeline,
-                                    &self.descriptor_sets[i], &self.vertex_buffer, dimensions)
+                self.build_cmd_buf(i, dimensions, false)
             );
         }
 
         self.cmd_bufs = cmd_bufs;
+        if self.depth_prepass && !self.wireframe && self.tessellation_pipeline.is_none() {
+            self.depth_prepass_cmds = (0..self.max_inflight_frames)
+                .map(|i| self.build_cmd_buf(i, dimensions, true))
+                .collect();
+        }
+        self.dimensions = dimensions;
     }
 
-    pub fn draw(&mut self, view: Matrix4<f32>, proj: Matrix4<f32>) -> vk::CommandBuffer
+    fn free_cmd_bufs(&mut self) {
+        unsafe {
+            if !self.cmd_bufs.is_empty() {
+                self.env.device().free_command_buffers(self.env.command_pool(), &self.cmd_bufs);
+            }
+            if !self.depth_prepass_cmds.is_empty() {
+                self.env.device().free_command_buffers(self.env.command_pool(), &self.depth_prepass_cmds);
+            }
+        }
+        self.cmd_bufs.clear();
+        self.depth_prepass_cmds.clear();
+    }
+
+    // Depth only draws of the frame returned by the last draw(), must go before all G-buffer draws of the pass
+    pub fn depth_prepass_draw(&self) -> Option<vk::CommandBuffer> {
+        let frame = (self.current_frame + self.max_inflight_frames - 1) % self.max_inflight_frames;
+        self.depth_prepass_cmds.get(frame).copied()
+    }
+
+    // Triangles drawn in the last frame, after LOD selection. Estimated when tessellated
+    pub fn triangle_count(&self) -> usize {
+        self.triangle_count
+    }
+
+    // Chunks of the last frame, culled ones are drawn with zero instances
+    pub fn cull_stats(&self) -> CullStats {
+        self.cull_stats
+    }
+
+    // One indirect draw per chunk
+    pub fn draw_call_count(&self) -> usize {
+        self.vertex_buffer.lod.chunk_count()
+    }
+
+    pub fn draw(&mut self, camera: &CameraDescriptor) -> vk::CommandBuffer
     {
-        self.uniforms.update_uniform_buffer(self.current_frame, Matrix4::identity(), view, proj);
+        self.uniforms.update_uniform_buffer(self.current_frame, Matrix4::identity(), camera.view, camera.proj, camera);
+
+        let commands = if self.tessellation_pipeline.is_some() {
+            let (commands, triangle_count) = self.vertex_buffer.lod.select_patches(camera.position, &camera.frustum);
+            self.triangle_count = triangle_count;
+            commands
+        } else {
+            let commands = self.vertex_buffer.lod.select(camera.position, &camera.frustum);
+            self.triangle_count = commands.iter().map(|cmd| (cmd.index_count * cmd.instance_count) as usize / 3).sum();
+            commands
+        };
+        self.cull_stats = CullStats {
+            total: commands.len(),
+            culled: commands.iter().filter(|cmd| cmd.instance_count == 0).count(),
+        };
+        unsafe {
+            let memory = self.indirect_buffers[self.current_frame].1;
+            let size = std::mem::size_of_val(commands.as_slice()) as u64;
+            let data_ptr = self.env.device()
+                .map_memory(memory, 0, size, vk::MemoryMapFlags::empty())
+                .expect("Failed to Map Memory") as *mut vk::DrawIndexedIndirectCommand;
+
+            data_ptr.copy_from_nonoverlapping(commands.as_ptr(), commands.len());
+
+            self.env.device().unmap_memory(memory);
+        }
 
         let current_frame = self.current_frame;
         self.current_frame = (self.current_frame + 1) % self.max_inflight_frames;
@@ -194,10 +511,7 @@ impl TerrainRenderer {
 
 impl Drop for TerrainRenderer {
     fn drop(&mut self) {
-        unsafe {
-            if self.cmd_bufs.len() > 0 {
-                self.env.device().free_command_buffers(self.env.command_pool(), &self.cmd_bufs);
-            }
-        }
+        self.free_cmd_bufs();
+        self.destroy_indirect_buffers();
     }
 }
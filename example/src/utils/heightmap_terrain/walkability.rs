@@ -0,0 +1,49 @@
+use cgmath::{InnerSpace, Vector3};
+
+// Slope and height limits a character can stand on. Heights are measured up from zero (world -Y),
+// the same as heightmap values.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct WalkabilitySettings {
+    // degrees from horizontal
+    pub max_slope: f32,
+    pub min_height: f32,
+    pub max_height: f32,
+}
+
+impl WalkabilitySettings {
+    pub fn new() -> WalkabilitySettings {
+        WalkabilitySettings {
+            max_slope: 35.0,
+            min_height: 0.0,
+            max_height: 4.0,
+        }
+    }
+
+    // Normal may point either side of the surface
+    pub fn is_walkable(&self, height: f32, normal: Vector3<f32>) -> bool {
+        let slope = normal.normalize().y.abs().min(1.0).acos().to_degrees();
+
+        slope <= self.max_slope && height >= self.min_height && height <= self.max_height
+    }
+
+    // terrain.frag push constant: x - cos of max slope, y, z - height range, w - overlay opacity (0 - off)
+    pub fn shader_params(&self, overlay: bool) -> [f32; 4] {
+        [
+            self.max_slope.to_radians().cos(),
+            self.min_height,
+            self.max_height,
+            if overlay { 0.5 } else { 0.0 },
+        ]
+    }
+
+    // `terrain_height` - vertical scale of the terrain, the height range is picked within it. Returns true when anything changed
+    pub fn ui(&mut self, ui: &mut egui::Ui, overlay: &mut bool, terrain_height: f32) -> bool {
+        let mut changed = ui.checkbox(overlay, "Show walkable area").changed();
+
+        changed |= ui.add(egui::Slider::new(&mut self.max_slope, 0.0..=90.0).text("Max slope")).changed();
+        changed |= ui.add(egui::Slider::new(&mut self.min_height, 0.0..=terrain_height).text("Min height")).changed();
+        changed |= ui.add(egui::Slider::new(&mut self.max_height, 0.0..=terrain_height).text("Max height")).changed();
+
+        changed
+    }
+}
@@ -0,0 +1,341 @@
+use std::ptr;
+use std::sync::Arc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+use cgmath::Matrix4;
+
+use ash_render_env::descriptor_set::DescriptorSet;
+use ash_render_env::env::RenderEnv;
+use ash_render_env::frame_buffer::Framebuffer;
+use ash_render_env::memory_tracker;
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::pipeline_builder::{ComputePipelineBuilder, Pipeline};
+use ash_render_env::shader;
+use ash_render_env::utils::texture_utils::create_image;
+
+use crate::utils::gbuffer::GBufferLayout;
+
+// Must match local_size of hi_z_init.comp and hi_z_downsample.comp
+const WORKGROUP_SIZE: u32 = 8;
+
+// Image of the pyramid for one G-buffer size
+struct PyramidImage {
+    image: vk::Image,
+    memory: vk::DeviceMemory,
+    // one per level, written by the compute passes
+    level_views: Vec<vk::ImageView>,
+    // all levels, sampled by occlusion culling
+    view: vk::ImageView,
+    // of every level, level 0 is half the depth size rounded up
+    level_dimensions: Vec<[u32; 2]>,
+    // reads the G-buffer depth, writes level 0
+    init_set: DescriptorSet,
+    // N reads level N, writes level N + 1
+    downsample_sets: Vec<DescriptorSet>,
+}
+
+// Hierarchical depth: every texel of a level holds the farthest depth of the 2x2 texels under it in the previous one,
+// so one texel fetch answers whether anything in its footprint is nearer than a given depth. Built from
+// the G-buffer depth after the geometry pass, stays in GENERAL layout for storage writes and sampling
+pub struct HiZPyramid {
+    init_pipeline: Pipeline,
+    downsample_pipeline: Pipeline,
+    sampler: vk::Sampler,
+    pyramid: PyramidImage,
+    depth_dimensions: [u32; 2],
+    gbuffer_samples: vk::SampleCountFlags,
+    commands: Vec<vk::CommandBuffer>,
+    // view-projection the depth in the pyramid was drawn with, None until the first build after creation or resize
+    view_proj: Option<Matrix4<f32>>,
+
+    env: Arc<RenderEnv>,
+}
+
+impl HiZPyramid {
+    pub fn new(env: Arc<RenderEnv>, gbuffer: &Framebuffer, max_inflight_frames: usize) -> HiZPyramid {
+        let gbuffer_samples = gbuffer.attachments[GBufferLayout::DEPTH].samples;
+        let init_pipeline = Self::create_init_pipeline(&env, gbuffer);
+        let downsample_shader = shader::Shader::load(env.device(), "assets/shaders/spv/culling/hi_z_downsample.comp.spv").expect("Failed to load shader!")
+            .specialize(shader::ConstantsBuilder::new()
+                .add_u32(gbuffer.depth_mode().is_reversed() as u32));
+        let downsample_pipeline = ComputePipelineBuilder::new(env.device().clone(), downsample_shader)
+            .pipeline_cache(env.pipeline_cache())
+            .build()
+            .expect("Failed to create pipeline!");
+
+        // levels are fetched by texel, never filtered
+        let sampler_create_info = vk::SamplerCreateInfo::builder()
+            .min_filter(vk::Filter::NEAREST)
+            .mag_filter(vk::Filter::NEAREST)
+            .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .max_lod(vk::LOD_CLAMP_NONE);
+
+        let sampler = unsafe {
+            env.device().create_sampler(&sampler_create_info, None).unwrap()
+        };
+        object_tracker::created(ObjectKind::Sampler);
+
+        let pyramid = Self::create_pyramid(&env, &init_pipeline, &downsample_pipeline, sampler, gbuffer);
+        let commands = (0..max_inflight_frames)
+            .map(|_| env.create_primary_command_buffer())
+            .collect();
+
+        HiZPyramid {
+            init_pipeline,
+            downsample_pipeline,
+            sampler,
+            pyramid,
+            depth_dimensions: gbuffer.dimensions(),
+            gbuffer_samples,
+            commands,
+            view_proj: None,
+            env,
+        }
+    }
+
+    fn create_init_pipeline(env: &RenderEnv, gbuffer: &Framebuffer) -> Pipeline {
+        let samples = gbuffer.attachments[GBufferLayout::DEPTH].samples;
+        // same variants as reader_shader_path() gives for fragment shaders
+        let path = if samples == vk::SampleCountFlags::TYPE_1 {
+            "assets/shaders/spv/culling/hi_z_init_single_sample.comp.spv"
+        } else {
+            "assets/shaders/spv/culling/hi_z_init.comp.spv"
+        };
+        let shader = shader::Shader::load(env.device(), path).expect("Failed to load shader!")
+            .specialize(shader::ConstantsBuilder::new()
+                .add_u32(samples.as_raw())
+                .add_u32(gbuffer.depth_mode().is_reversed() as u32));
+
+        ComputePipelineBuilder::new(env.device().clone(), shader)
+            .pipeline_cache(env.pipeline_cache())
+            .build()
+            .expect("Failed to create pipeline!")
+    }
+
+    fn create_pyramid(env: &RenderEnv, init_pipeline: &Pipeline, downsample_pipeline: &Pipeline, sampler: vk::Sampler,
+                      gbuffer: &Framebuffer) -> PyramidImage {
+        let depth_dimensions = gbuffer.dimensions();
+        let mut level_dimensions = vec![[depth_dimensions[0].div_ceil(2).max(1), depth_dimensions[1].div_ceil(2).max(1)]];
+        while let Some(&[width, height]) = level_dimensions.last().filter(|size| size[0] > 1 || size[1] > 1) {
+            level_dimensions.push([width.div_ceil(2), height.div_ceil(2)]);
+        }
+        let level_count = level_dimensions.len() as u32;
+
+        let (image, memory) = create_image(
+            env.device(),
+            level_dimensions[0][0],
+            level_dimensions[0][1],
+            1,
+            level_count,
+            vk::SampleCountFlags::TYPE_1,
+            vk::Format::R32_SFLOAT,
+            vk::ImageTiling::OPTIMAL,
+            vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            &env.mem_properties,
+        ).expect("Failed to create Hi-Z image!");
+
+        let level_views: Vec<_> = (0..level_count)
+            .map(|level| Self::create_view(env.device(), image, level, 1))
+            .collect();
+        let view = Self::create_view(env.device(), image, 0, level_count);
+
+        let init_set = DescriptorSet::builder(env.device(), &init_pipeline.descriptor_set_layouts[0])
+            .add_attachment(&gbuffer.attachments[GBufferLayout::DEPTH], sampler)
+            .add_storage_image(level_views[0])
+            .build()
+            .expect("Failed to create descriptor set!");
+        let downsample_sets = level_views.windows(2)
+            .map(|views| {
+                DescriptorSet::builder(env.device(), &downsample_pipeline.descriptor_set_layouts[0])
+                    .add_storage_image(views[0])
+                    .add_storage_image(views[1])
+                    .build()
+                    .expect("Failed to create descriptor set!")
+            })
+            .collect();
+
+        PyramidImage {
+            image,
+            memory,
+            level_views,
+            view,
+            level_dimensions,
+            init_set,
+            downsample_sets,
+        }
+    }
+
+    fn create_view(device: &ash::Device, image: vk::Image, base_mip_level: u32, level_count: u32) -> vk::ImageView {
+        let imageview_create_info = vk::ImageViewCreateInfo {
+            s_type: vk::StructureType::IMAGE_VIEW_CREATE_INFO,
+            p_next: ptr::null(),
+            flags: vk::ImageViewCreateFlags::empty(),
+            view_type: vk::ImageViewType::TYPE_2D,
+            format: vk::Format::R32_SFLOAT,
+            components: vk::ComponentMapping::default(),
+            subresource_range: vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level,
+                level_count,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            image,
+        };
+
+        let view = unsafe {
+            device
+                .create_image_view(&imageview_create_info, None)
+                .expect("Failed to create Hi-Z view!")
+        };
+        object_tracker::created(ObjectKind::ImageView);
+
+        view
+    }
+
+    fn destroy_pyramid(&self) {
+        let device = self.env.device();
+        unsafe {
+            for &view in self.pyramid.level_views.iter().chain(std::iter::once(&self.pyramid.view)) {
+                device.destroy_image_view(view, None);
+                object_tracker::destroyed(ObjectKind::ImageView);
+            }
+            device.destroy_image(self.pyramid.image, None);
+            object_tracker::destroyed(ObjectKind::Image);
+            device.free_memory(self.pyramid.memory, None);
+            memory_tracker::freed(self.pyramid.memory);
+        }
+    }
+
+    // The G-buffer changed size or sample count, the pyramid is empty until the next build. Device must be idle
+    pub fn resize(&mut self, gbuffer: &Framebuffer) {
+        let gbuffer_samples = gbuffer.attachments[GBufferLayout::DEPTH].samples;
+        if gbuffer_samples != self.gbuffer_samples {
+            self.init_pipeline = Self::create_init_pipeline(&self.env, gbuffer);
+            self.gbuffer_samples = gbuffer_samples;
+        }
+
+        let pyramid = Self::create_pyramid(&self.env, &self.init_pipeline, &self.downsample_pipeline, self.sampler, gbuffer);
+        self.destroy_pyramid();
+        self.pyramid = pyramid;
+        self.depth_dimensions = gbuffer.dimensions();
+        self.view_proj = None;
+    }
+
+    // All levels in GENERAL layout, for sampler2D with texelFetch. Changes on resize
+    pub fn view(&self) -> vk::ImageView {
+        self.pyramid.view
+    }
+
+    pub fn sampler(&self) -> vk::Sampler {
+        self.sampler
+    }
+
+    pub fn level_count(&self) -> usize {
+        self.pyramid.level_views.len()
+    }
+
+    // Size of the depth the pyramid is built from, level 0 is half of it
+    pub fn depth_dimensions(&self) -> [u32; 2] {
+        self.depth_dimensions
+    }
+
+    // Of the depth in the pyramid as of the last recorded build, None if it is empty
+    pub fn view_proj(&self) -> Option<Matrix4<f32>> {
+        self.view_proj
+    }
+
+    // Primary command buffer building the pyramid from the G-buffer depth drawn with `view_proj`,
+    // must be submitted after the geometry pass. Compute passes submitted after it may sample the pyramid.
+    // Command buffer of `frame` must not be in use by GPU
+    pub fn build(&mut self, frame: usize, view_proj: Matrix4<f32>) -> vk::CommandBuffer {
+        let device = self.env.device();
+        let cmd = self.commands[frame];
+
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+
+        // contents of the last build are discarded, the first one after resize brings the image out of UNDEFINED
+        let old_layout = if self.view_proj.is_some() { vk::ImageLayout::GENERAL } else { vk::ImageLayout::UNDEFINED };
+        let pyramid_barrier = vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_READ)
+            .dst_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .old_layout(old_layout)
+            .new_layout(vk::ImageLayout::GENERAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(self.pyramid.image)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: self.pyramid.level_views.len() as u32,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .build();
+        // the render pass makes depth visible to fragment shaders only
+        let depth_barrier = vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .build();
+
+        unsafe {
+            device.reset_command_buffer(cmd, vk::CommandBufferResetFlags::default())
+                .expect("Failed to reset Command Buffer!");
+            device.begin_command_buffer(cmd, &command_buffer_begin_info)
+                .expect("Failed to begin recording Command Buffer at beginning!");
+
+            // waits for occlusion culling of this frame, which samples the last build
+            device.cmd_pipeline_barrier(cmd,
+                                        vk::PipelineStageFlags::LATE_FRAGMENT_TESTS | vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER,
+                                        vk::PipelineStageFlags::COMPUTE_SHADER,
+                                        vk::DependencyFlags::empty(), &[depth_barrier], &[], &[pyramid_barrier]);
+
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.init_pipeline.graphics_pipeline);
+            self.pyramid.init_set.bind(cmd, vk::PipelineBindPoint::COMPUTE, self.init_pipeline.pipeline_layout, 0, &[]);
+            let [width, height] = self.pyramid.level_dimensions[0];
+            device.cmd_dispatch(cmd, width.div_ceil(WORKGROUP_SIZE), height.div_ceil(WORKGROUP_SIZE), 1);
+
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.downsample_pipeline.graphics_pipeline);
+            for (set, &[width, height]) in self.pyramid.downsample_sets.iter().zip(self.pyramid.level_dimensions.iter().skip(1)) {
+                Self::compute_barrier(device, cmd);
+                set.bind(cmd, vk::PipelineBindPoint::COMPUTE, self.downsample_pipeline.pipeline_layout, 0, &[]);
+                device.cmd_dispatch(cmd, width.div_ceil(WORKGROUP_SIZE), height.div_ceil(WORKGROUP_SIZE), 1);
+            }
+
+            Self::compute_barrier(device, cmd);
+
+            device.end_command_buffer(cmd)
+                .expect("Failed to record Command Buffer at Ending!");
+        }
+
+        self.view_proj = Some(view_proj);
+        cmd
+    }
+
+    // Writes of the previous dispatch are visible to the following compute reads
+    unsafe fn compute_barrier(device: &ash::Device, cmd: vk::CommandBuffer) {
+        let barrier = vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .build();
+
+        device.cmd_pipeline_barrier(cmd, vk::PipelineStageFlags::COMPUTE_SHADER, vk::PipelineStageFlags::COMPUTE_SHADER, vk::DependencyFlags::empty(), &[barrier], &[], &[]);
+    }
+}
+
+impl Drop for HiZPyramid {
+    fn drop(&mut self) {
+        unsafe {
+            self.env.device().free_command_buffers(self.env.command_pool(), &self.commands);
+            self.env.device().destroy_sampler(self.sampler, None);
+            object_tracker::destroyed(ObjectKind::Sampler);
+        }
+        self.destroy_pyramid();
+    }
+}
@@ -0,0 +1,319 @@
+use std::ptr;
+use std::sync::Arc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+use cgmath::{EuclideanSpace, Matrix4, Point3, SquareMatrix, Vector3};
+
+use ash_render_env::camera::CameraDescriptor;
+use ash_render_env::culling::{Aabb, CullStats};
+use ash_render_env::descriptor_set::DescriptorSet;
+use ash_render_env::env::RenderEnv;
+use ash_render_env::pipeline_builder::Pipeline;
+use ash_render_env::utils::DepthMode;
+
+use crate::utils::gbuffer::{GBufferLayout, GeometryPipeline};
+use crate::utils::gpu_culling::GpuCulling;
+use crate::utils::mesh_render::MeshRenderer;
+use crate::utils::model::Model;
+use crate::utils::scene_buffer::GpuObject;
+use crate::utils::uniform_buffer::UboBuffers;
+
+// Capacity of the culling buffers, the instance count slider goes up to it
+pub const MAX_FIELD_INSTANCES: usize = 16384;
+
+// Grid of model instances drawn into the G-buffer with GPU culling: visible instances and the instance count
+// of every part draw are written by a compute pass, the CPU records one indirect draw per part once.
+// Shows how draw calls and culling scale to thousands of objects past the scene buffer limit
+pub struct InstanceField {
+    pub enabled: bool,
+    pub count: usize,
+    // distance between instances in model sizes
+    pub spacing: f32,
+    // grid center, the first instance of the scene stands there
+    pub center: Point3<f32>,
+
+    culling: GpuCulling,
+    // opaque parts only, transparent ones are not drawn into the G-buffer
+    parts: Vec<usize>,
+    model: Arc<Model>,
+
+    render_pass: vk::RenderPass,
+    pipeline: Pipeline,
+    msaa_samples: vk::SampleCountFlags,
+    depth_mode: DepthMode,
+    uniforms: UboBuffers,
+    // descriptor_sets[frame][part]
+    descriptor_sets: Vec<Vec<DescriptorSet>>,
+    render_cmds: Vec<vk::CommandBuffer>,
+    dimensions: [u32; 2],
+    max_inflight_frames: usize,
+
+    env: Arc<RenderEnv>,
+}
+
+impl InstanceField {
+    pub fn new(env: Arc<RenderEnv>, render_pass: vk::RenderPass, model: Arc<Model>,
+               msaa_samples: vk::SampleCountFlags, depth_mode: DepthMode, dimensions: [u32; 2], max_inflight_frames: usize) -> InstanceField
+    {
+        let pipeline = MeshRenderer::create_pipeline(&env, render_pass, GBufferLayout::COLOR_ATTACHMENT_COUNT, msaa_samples, depth_mode, GeometryPipeline::Fill);
+        let uniforms = UboBuffers::new(
+            env.instance(),
+            env.device().clone(),
+            env.physical_device(),
+            max_inflight_frames,
+        );
+        let (parts, culling) = Self::create_culling(&env, &model, max_inflight_frames);
+
+        let mut field = InstanceField {
+            enabled: false,
+            count: 4096,
+            spacing: 1.5,
+            center: Point3::new(0.0, 0.01, -10.0),
+            culling,
+            parts,
+            model,
+            render_pass,
+            pipeline,
+            msaa_samples,
+            depth_mode,
+            uniforms,
+            descriptor_sets: vec![],
+            render_cmds: vec![],
+            dimensions,
+            max_inflight_frames,
+            env,
+        };
+        field.update_descriptor_sets();
+        field.update_objects();
+        field.resize_framebuffer(dimensions);
+
+        field
+    }
+
+    // Drawing visible objects by gl_InstanceIndex needs a non zero first instance in the indirect draws
+    pub fn supported(&self) -> bool {
+        self.env.enabled_features().draw_indirect_first_instance == vk::TRUE
+    }
+
+    fn create_culling(env: &Arc<RenderEnv>, model: &Model, max_inflight_frames: usize) -> (Vec<usize>, GpuCulling) {
+        let parts: Vec<usize> = (0..model.parts.len())
+            .filter(|&idx| !model.parts[idx].material.transparent)
+            .collect();
+        let index_counts = parts.iter()
+            .map(|&idx| model.parts[idx].mesh.index_count as u32)
+            .collect();
+
+        (parts, GpuCulling::new(env.clone(), index_counts, MAX_FIELD_INSTANCES, max_inflight_frames))
+    }
+
+    fn update_descriptor_sets(&mut self) {
+        self.descriptor_sets = (0..self.max_inflight_frames)
+            .map(|i| {
+                self.parts.iter()
+                    .map(|&idx| {
+                        let material = &self.model.parts[idx].material;
+                        DescriptorSet::builder(self.env.device(), &self.pipeline.descriptor_set_layouts[0])
+                            .add_buffer(self.uniforms.uniform_buffers[i])
+                            .add_texture(&material.albedo)
+                            .add_storage_buffer(self.culling.visible_buffer(i))
+                            .add_texture(&material.normal)
+                            .add_texture(&material.metallic_roughness)
+                            .add_texture(&material.occlusion)
+                            .build()
+                            .expect("Failed to create descriptor set!")
+                    })
+                    .collect()
+            })
+            .collect();
+    }
+
+    // Lays the instances out on a square grid around `center` after count, spacing or center changes.
+    // Device must be idle
+    pub fn update_objects(&mut self) {
+        let count = self.count.min(MAX_FIELD_INSTANCES);
+        let model_bounds = self.model.parts.iter()
+            .fold(Aabb::empty(), |bounds, part| bounds.union(&Aabb::from_bounds(part.mesh.bounds).transformed(&part.transform)));
+        let size = if model_bounds.min.x <= model_bounds.max.x {
+            (model_bounds.max.x - model_bounds.min.x).max(model_bounds.max.z - model_bounds.min.z).max(0.1)
+        } else {
+            1.0
+        };
+        let step = size * self.spacing;
+        let side = (count as f32).sqrt().ceil().max(1.0) as usize;
+        let offset = (side - 1) as f32 * step * 0.5;
+
+        let transforms: Vec<Matrix4<f32>> = (0..count)
+            .map(|idx| {
+                let (row, column) = (idx / side, idx % side);
+                let position = Vector3::new(column as f32 * step - offset, 0.0, row as f32 * step - offset);
+                Matrix4::from_translation(self.center.to_vec() + position)
+            })
+            .collect();
+
+        // all instances of the first part, then of the second one and so on
+        let objects: Vec<GpuObject> = self.parts.iter()
+            .map(|&idx| &self.model.parts[idx])
+            .flat_map(|part| transforms.iter()
+                .map(move |transform| GpuObject::new(transform * part.transform, part.mesh.bounds, 0)))
+            .collect();
+        self.culling.write_objects(&objects);
+    }
+
+    // Command buffers must not be in use
+    pub fn set_model(&mut self, model: Arc<Model>) {
+        self.model = model;
+        let (parts, culling) = Self::create_culling(&self.env, &self.model, self.max_inflight_frames);
+        self.parts = parts;
+        self.culling = culling;
+        self.update_descriptor_sets();
+        self.update_objects();
+        self.resize_framebuffer(self.dimensions);
+    }
+
+    // Pipeline for a new G-buffer render pass, see MeshRenderer::set_render_pass. Command buffers must not be in use
+    pub fn set_render_pass(&mut self, render_pass: vk::RenderPass, msaa_samples: vk::SampleCountFlags) {
+        self.render_pass = render_pass;
+        self.msaa_samples = msaa_samples;
+        self.pipeline = MeshRenderer::create_pipeline(&self.env, render_pass, GBufferLayout::COLOR_ATTACHMENT_COUNT, msaa_samples, self.depth_mode,
+                                                      GeometryPipeline::Fill);
+        self.resize_framebuffer(self.dimensions);
+    }
+
+    // Instances of the last finished frame, all parts of an instance count separately
+    pub fn cull_stats(&self) -> CullStats {
+        self.culling.stats()
+    }
+
+    // One indirect draw per opaque part whatever the instance count
+    pub fn draw_call_count(&self) -> usize {
+        if self.enabled && self.supported() { self.parts.len() } else { 0 }
+    }
+
+    fn build_cmd_buf(&self, frame: usize, dimensions: [u32; 2]) -> vk::CommandBuffer {
+        let device = self.env.device();
+        let command_buffer = self.env.create_secondary_command_buffer();
+        let draw_buffer = self.culling.draw_buffer(frame);
+
+        let inheritance_info = vk::CommandBufferInheritanceInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_INHERITANCE_INFO,
+            p_next: ptr::null(),
+            render_pass: self.render_pass,
+            subpass: 0,
+            framebuffer: vk::Framebuffer::null(),
+            occlusion_query_enable: 0,
+            query_flags: Default::default(),
+            pipeline_statistics: Default::default(),
+        };
+
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_BEGIN_INFO,
+            p_next: ptr::null(),
+            p_inheritance_info: &inheritance_info,
+            flags: vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE | vk::CommandBufferUsageFlags::SIMULTANEOUS_USE,
+        };
+
+        let viewports = [vk::Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: dimensions[0] as f32,
+            height: dimensions[1] as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        }];
+
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: vk::Extent2D {
+                width: dimensions[0],
+                height: dimensions[1],
+            },
+        }];
+
+        unsafe {
+            device
+                .begin_command_buffer(command_buffer, &command_buffer_begin_info)
+                .expect("Failed to begin recording Command Buffer at beginning!");
+
+            device.cmd_set_viewport(command_buffer, 0, viewports.as_ref());
+            device.cmd_set_scissor(command_buffer, 0, scissors.as_ref());
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipeline.graphics_pipeline);
+
+            let stride = std::mem::size_of::<vk::DrawIndexedIndirectCommand>();
+            for (draw_idx, (&part_idx, descriptor_set)) in self.parts.iter().zip(self.descriptor_sets[frame].iter()).enumerate() {
+                let part = &self.model.parts[part_idx];
+                descriptor_set.bind(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipeline.pipeline_layout, 0, &[]);
+
+                self.pipeline.push_constants(command_buffer, vk::ShaderStageFlags::VERTEX, &Matrix4::<f32>::identity());
+                self.pipeline.push_constants(command_buffer, vk::ShaderStageFlags::FRAGMENT, &part.material.factors());
+
+                let vertex_buffers = [part.mesh.vertex_buffer];
+                let offsets = [0_u64];
+                device.cmd_bind_vertex_buffers(command_buffer, 0, &vertex_buffers, &offsets);
+                device.cmd_bind_index_buffer(command_buffer, part.mesh.index_buffer, 0, vk::IndexType::UINT32);
+
+                // the instance count is written by the culling pass
+                device.cmd_draw_indexed_indirect(command_buffer, draw_buffer, (draw_idx * stride) as u64, 1, stride as u32);
+            }
+
+            device
+                .end_command_buffer(command_buffer)
+                .expect("Failed to record Command Buffer at Ending!");
+        }
+
+        command_buffer
+    }
+
+    // Command buffers must not be in use
+    pub fn resize_framebuffer(&mut self, dimensions: [u32; 2]) {
+        self.free_cmd_bufs();
+        self.render_cmds = (0..self.max_inflight_frames)
+            .map(|frame| self.build_cmd_buf(frame, dimensions))
+            .collect();
+        self.dimensions = dimensions;
+    }
+
+    fn free_cmd_bufs(&mut self) {
+        if !self.render_cmds.is_empty() {
+            unsafe {
+                self.env.device().free_command_buffers(self.env.command_pool(), &self.render_cmds);
+            }
+        }
+        self.render_cmds.clear();
+    }
+
+    // Primary command buffer of the culling pass, must be submitted before the geometry pass.
+    // Resources of `frame` must not be in use by GPU
+    pub fn cull(&mut self, frame: usize, camera: &CameraDescriptor) -> vk::CommandBuffer {
+        self.uniforms.update_uniform_buffer(frame, Matrix4::identity(), camera.view, camera.proj, camera);
+        self.culling.cull(frame, camera)
+    }
+
+    // Secondary command buffer for the geometry pass of the frame given to cull()
+    pub fn draw(&self, frame: usize) -> vk::CommandBuffer {
+        self.render_cmds[frame]
+    }
+
+    // Returns true when the instances have to be laid out again with update_objects()
+    pub fn ui(&mut self, ui: &mut egui::Ui) -> bool {
+        if !self.supported() {
+            ui.label("Instance field: drawIndirectFirstInstance is not supported by the device");
+            return false;
+        }
+
+        let (count, spacing) = (self.count, self.spacing);
+        ui.checkbox(&mut self.enabled, "Instance field");
+        ui.add(egui::Slider::new(&mut self.count, 1..=MAX_FIELD_INSTANCES).logarithmic(true).text("Instances"));
+        ui.add(egui::Slider::new(&mut self.spacing, 1.0..=4.0).text("Spacing"));
+        let stats = self.cull_stats();
+        ui.label(format!("GPU culled: {} of {} part instances", stats.culled, stats.total));
+
+        count != self.count || spacing != self.spacing
+    }
+}
+
+impl Drop for InstanceField {
+    fn drop(&mut self) {
+        self.free_cmd_bufs();
+    }
+}
@@ -0,0 +1,146 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use ash::vk;
+
+use ash_render_env::env::RenderEnv;
+use ash_render_env::utils::ktx2::CompressedFormats;
+use ash_render_env::utils::texture::{ImageData, Texture, TextureData};
+
+// Surface description for the PBR geometry pass. Textures follow the glTF convention:
+// metallic-roughness has roughness in G and metallic in B, occlusion is read from R.
+pub struct Material {
+    pub(super) albedo: Texture,
+    pub(super) metallic_roughness: Texture,
+    pub(super) normal: Texture,
+    pub(super) occlusion: Texture,
+
+    // texture values are multiplied by these
+    pub metallic: f32,
+    pub roughness: f32,
+    pub occlusion_strength: f32,
+    // blended over the lit scene by the forward transparency pass instead of going to the G-buffer
+    pub transparent: bool,
+}
+
+// Decoded images of a material, missing maps are None
+pub struct MaterialData {
+    pub(super) albedo: TextureData,
+    pub(super) metallic_roughness: Option<TextureData>,
+    pub(super) normal: Option<TextureData>,
+    pub(super) occlusion: Option<TextureData>,
+
+    pub(super) metallic: f32,
+    pub(super) roughness: f32,
+    pub(super) occlusion_strength: f32,
+    pub(super) transparent: bool,
+}
+
+impl MaterialData {
+    // Textures next to the model: `<model>.png` albedo, `<model>_normal`, `<model>_mr` and `<model>_ao`.
+    // A `.ktx2` next to any of them is loaded instead when `formats` has its format
+    pub fn load(model_path: &Path, formats: &CompressedFormats) -> Result<MaterialData, String> {
+        let load_map = |suffix| find_map(model_path, suffix)
+            .map(|path| TextureData::load(&path, true, formats))
+            .transpose();
+
+        let albedo = TextureData::load(&find_albedo(model_path), true, formats)?;
+        // OBJ has no alpha mode, translucent texels of the albedo make the whole material transparent
+        let transparent = match &albedo {
+            TextureData::Pixels(image) => image.pixels.chunks_exact(4).any(|texel| texel[3] < 255),
+            TextureData::Compressed(_) => false,
+        };
+
+        Ok(MaterialData {
+            albedo,
+            metallic_roughness: load_map("mr")?,
+            normal: load_map("normal")?,
+            occlusion: load_map("ao")?,
+            metallic: 0.0,
+            roughness: 0.8,
+            occlusion_strength: 1.0,
+            transparent,
+        })
+    }
+}
+
+impl Material {
+    // Missing maps are replaced by neutral 1x1 textures
+    pub fn upload(env: &Arc<RenderEnv>, data: MaterialData) -> Material {
+        Material {
+            albedo: upload_texture(env, &data.albedo, vk::Format::R8G8B8A8_SRGB),
+            metallic_roughness: upload_or_default(env, data.metallic_roughness, [255, 255, 255, 255]),
+            normal: upload_or_default(env, data.normal, [128, 128, 255, 255]),
+            occlusion: upload_or_default(env, data.occlusion, [255, 255, 255, 255]),
+            metallic: data.metallic,
+            roughness: data.roughness,
+            occlusion_strength: data.occlusion_strength,
+            transparent: data.transparent,
+        }
+    }
+
+    // Plain white surface while the model textures are loading
+    pub fn placeholder(env: &Arc<RenderEnv>) -> Material {
+        let white = ImageData {
+            pixels: vec![255; 4],
+            width: 1,
+            height: 1,
+        };
+
+        Material::upload(env, MaterialData {
+            albedo: TextureData::Pixels(white),
+            metallic_roughness: None,
+            normal: None,
+            occlusion: None,
+            metallic: 0.0,
+            roughness: 0.8,
+            occlusion_strength: 1.0,
+            transparent: false,
+        })
+    }
+
+    // mesh.frag push constant
+    pub fn factors(&self) -> [f32; 4] {
+        [self.metallic, self.roughness, self.occlusion_strength, 0.0]
+    }
+}
+
+// Image with the same name next to the model, or the default one
+fn find_albedo(model_path: &Path) -> PathBuf {
+    ["png", "jpg"].iter()
+        .map(|ext| model_path.with_extension(ext))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| PathBuf::from("assets/chalet.jpg"))
+}
+
+// `<model>_<suffix>` image next to the model
+fn find_map(model_path: &Path, suffix: &str) -> Option<PathBuf> {
+    let stem = model_path.file_stem()?.to_string_lossy();
+
+    ["png", "jpg"].iter()
+        .map(|ext| model_path.with_file_name(format!("{}_{}.{}", stem, suffix, ext)))
+        .find(|path| path.exists())
+}
+
+fn upload_texture(env: &Arc<RenderEnv>, data: &TextureData, format: vk::Format) -> Texture {
+    Texture::from_data(
+        env.device().clone(),
+        env.transfer_command_pool(),
+        env.transfer_queue(),
+        &env.mem_properties,
+        data,
+        format,
+        env.max_sampler_anisotropy(),
+    ).expect("Failed to upload texture!")
+}
+
+// Data textures are UNORM, they must not be gamma decoded
+fn upload_or_default(env: &Arc<RenderEnv>, data: Option<TextureData>, default: [u8; 4]) -> Texture {
+    let data = data.unwrap_or_else(|| TextureData::Pixels(ImageData {
+        pixels: default.to_vec(),
+        width: 1,
+        height: 1,
+    }));
+
+    upload_texture(env, &data, vk::Format::R8G8B8A8_UNORM)
+}
@@ -4,12 +4,16 @@ use std::time;
 
 use ash::version::DeviceV1_0;
 use ash::vk;
+use cgmath::{InnerSpace, Vector2, Vector3};
 use memoffset::offset_of;
 use tobj;
 
 use ash_render_env::env::RenderEnv;
+use ash_render_env::memory_tracker;
+use ash_render_env::object_tracker::{self, ObjectKind};
 use ash_render_env::utils::buffer_utils::create_data_buffer;
-use ash_render_env::utils::texture::Texture;
+
+use crate::utils::mesh_lod::MeshLod;
 
 #[repr(C)]
 #[derive(Debug, Clone)]
@@ -18,6 +22,8 @@ pub struct Vertex {
     color: [f32; 4],
     tex_coord: [f32; 2],
     normal: [f32; 3],
+    // xyz - tangent along +u, w - bitangent sign
+    tangent: [f32; 4],
 }
 
 impl Vertex {
@@ -57,16 +63,22 @@ impl Vertex {
                 format: vk::Format::R32G32B32_SFLOAT,
                 offset: offset_of!(Self, normal) as u32,
             },
+            vk::VertexInputAttributeDescription {
+                binding: 0,
+                location: 4,
+                format: vk::Format::R32G32B32A32_SFLOAT,
+                offset: offset_of!(Self, tangent) as u32,
+            },
         ]
     }
 }
 
-fn load_model(model_path: &Path) -> (Vec<Vertex>, Vec<u32>) {
+fn load_model(model_path: &Path) -> Result<(Vec<Vertex>, Vec<u32>), String> {
     let model_obj = tobj::load_obj(model_path, &tobj::LoadOptions {
         single_index: true,
         ..Default::default()
     })
-        .expect("Failed to load model object!");
+        .map_err(|err| format!("Failed to load model object: {}", err))?;
 
     let mut vertices = vec![];
     let mut indices = vec![];
@@ -76,8 +88,11 @@ fn load_model(model_path: &Path) -> (Vec<Vertex>, Vec<u32>) {
     for m in models.iter() {
         let mesh = &m.mesh;
 
-        if mesh.texcoords.len() == 0 {
-            panic!("Missing texture coordinate for the model.")
+        if mesh.texcoords.is_empty() {
+            return Err("Missing texture coordinate for the model.".to_string());
+        }
+        if mesh.normals.is_empty() {
+            return Err("Missing normals for the model.".to_string());
         }
 
         let total_vertices_count = mesh.positions.len() / 3;
@@ -96,6 +111,7 @@ fn load_model(model_path: &Path) -> (Vec<Vertex>, Vec<u32>) {
                     mesh.normals[i * 3 + 1],
                     mesh.normals[i * 3 + 2],
                 ],
+                tangent: [0.0; 4],
             };
             vertices.push(vertex);
         }
@@ -103,7 +119,143 @@ fn load_model(model_path: &Path) -> (Vec<Vertex>, Vec<u32>) {
         indices = mesh.indices.clone();
     }
 
-    (vertices, indices)
+    if indices.is_empty() {
+        return Err("Model has no faces.".to_string());
+    }
+
+    compute_tangents(&mut vertices, &indices);
+
+    Ok((vertices, indices))
+}
+
+// Per vertex tangent frame from texture coordinate directions, averaged over adjacent triangles
+fn compute_tangents(vertices: &mut [Vertex], indices: &[u32]) {
+    let mut tangents = vec![Vector3::new(0.0f32, 0.0, 0.0); vertices.len()];
+    let mut bitangents = vec![Vector3::new(0.0f32, 0.0, 0.0); vertices.len()];
+
+    let position = |v: &Vertex| Vector3::new(v.pos[0], v.pos[1], v.pos[2]);
+    let uv = |v: &Vertex| Vector2::new(v.tex_coord[0], v.tex_coord[1]);
+
+    for triangle in indices.chunks_exact(3) {
+        let (a, b, c) = (triangle[0] as usize, triangle[1] as usize, triangle[2] as usize);
+
+        let e1 = position(&vertices[b]) - position(&vertices[a]);
+        let e2 = position(&vertices[c]) - position(&vertices[a]);
+        let d1 = uv(&vertices[b]) - uv(&vertices[a]);
+        let d2 = uv(&vertices[c]) - uv(&vertices[a]);
+
+        let det = d1.x * d2.y - d2.x * d1.y;
+        if det.abs() < 1e-8 {
+            continue;
+        }
+
+        let tangent = (e1 * d2.y - e2 * d1.y) / det;
+        let bitangent = (e2 * d1.x - e1 * d2.x) / det;
+        for &idx in [a, b, c].iter() {
+            tangents[idx] += tangent;
+            bitangents[idx] += bitangent;
+        }
+    }
+
+    for (i, vertex) in vertices.iter_mut().enumerate() {
+        let normal = Vector3::from(vertex.normal);
+
+        // Gram-Schmidt, any perpendicular direction for vertices without uv gradient
+        let mut tangent = tangents[i] - normal * normal.dot(tangents[i]);
+        if tangent.magnitude2() < 1e-12 {
+            let axis = if normal.x.abs() < 0.9 { Vector3::unit_x() } else { Vector3::unit_y() };
+            tangent = normal.cross(axis);
+        }
+        let tangent = tangent.normalize();
+
+        let handedness = if normal.cross(tangent).dot(bitangents[i]) < 0.0 { -1.0 } else { 1.0 };
+        vertex.tangent = [tangent.x, tangent.y, tangent.z, handedness];
+    }
+}
+
+// Vertices of a model decoded on the CPU, uploaded by Mesh::upload
+pub struct MeshData {
+    vertices: Vec<Vertex>,
+    // full mesh, then the simplified levels of `lod`
+    indices: Vec<u32>,
+    lod: MeshLod,
+
+    // object space bounding box
+    pub bounds: ([f32; 3], [f32; 3]),
+}
+
+impl MeshData {
+    pub fn load(path: &Path) -> Result<MeshData, String> {
+        let t1 = time::Instant::now();
+        let (vertices, indices) = load_model(path)?;
+        println!("Model loaded: {}", t1.elapsed().as_secs_f32());
+
+        Ok(MeshData::from_vertices(vertices, indices))
+    }
+
+    // Triangle list from separate attribute streams of the same length, tangents are computed
+    pub fn new(positions: &[[f32; 3]], normals: &[[f32; 3]], tex_coords: &[[f32; 2]], indices: Vec<u32>) -> Result<MeshData, String> {
+        if normals.len() != positions.len() || tex_coords.len() != positions.len() {
+            return Err("Vertex attributes have different lengths.".to_string());
+        }
+        if indices.is_empty() {
+            return Err("Mesh has no faces.".to_string());
+        }
+        if indices.iter().any(|&idx| idx as usize >= positions.len()) {
+            return Err("Vertex index out of range.".to_string());
+        }
+
+        let mut vertices: Vec<Vertex> = positions.iter().zip(normals).zip(tex_coords)
+            .map(|((pos, normal), tex_coord)| Vertex {
+                pos: [pos[0], pos[1], pos[2], 1.0],
+                color: [1.0, 1.0, 1.0, 1.0],
+                tex_coord: *tex_coord,
+                normal: *normal,
+                tangent: [0.0; 4],
+            })
+            .collect();
+        compute_tangents(&mut vertices, &indices);
+
+        Ok(MeshData::from_vertices(vertices, indices))
+    }
+
+    fn from_vertices(vertices: Vec<Vertex>, mut indices: Vec<u32>) -> MeshData {
+        let positions: Vec<[f32; 3]> = vertices.iter().map(|v| [v.pos[0], v.pos[1], v.pos[2]]).collect();
+        let lod = MeshLod::generate(&positions, &mut indices);
+
+        let mut bounds = ([f32::MAX; 3], [f32::MIN; 3]);
+        for vertex in vertices.iter() {
+            for axis in 0..3 {
+                bounds.0[axis] = bounds.0[axis].min(vertex.pos[axis]);
+                bounds.1[axis] = bounds.1[axis].max(vertex.pos[axis]);
+            }
+        }
+
+        MeshData {
+            vertices,
+            indices,
+            lod,
+            bounds,
+        }
+    }
+
+    // Degenerate triangle that draws nothing, stands in until the model is loaded
+    pub fn placeholder() -> MeshData {
+        let vertex = Vertex {
+            pos: [0.0, 0.0, 0.0, 1.0],
+            color: [1.0; 4],
+            tex_coord: [0.0; 2],
+            normal: [0.0, 1.0, 0.0],
+            tangent: [1.0, 0.0, 0.0, 1.0],
+        };
+
+        MeshData {
+            vertices: vec![vertex; 3],
+            indices: vec![0, 1, 2],
+            lod: MeshLod::single(3),
+            bounds: ([0.0; 3], [0.0; 3]),
+        }
+    }
 }
 
 pub struct Mesh {
@@ -113,47 +265,40 @@ pub struct Mesh {
 
     pub index_buffer: vk::Buffer,
     pub index_buffer_memory: vk::DeviceMemory,
+    // of the full mesh, the index buffer also has the simplified levels after it
     pub index_count: usize,
+    pub lod: MeshLod,
 
-    pub(super) texture: Texture,
+    // object space bounding box
+    pub bounds: ([f32; 3], [f32; 3]),
 }
 
 impl Mesh {
-    pub fn load_from_file(env: Arc<RenderEnv>, path: &Path) -> Mesh
+    // Copies the data to device local buffers through the transfer queue
+    pub fn upload(env: &Arc<RenderEnv>, data: MeshData) -> Mesh
     {
-        let t1 = time::Instant::now();
-        let (vertices, indices) = load_model(path);
-        println!("Model loaded: {}", t1.elapsed().as_secs_f32());
-
-        let index_count = indices.len();
+        let index_count = data.lod.levels()[0].index_count as usize;
 
         let (vertex_buffer, vertex_buffer_memory) = create_data_buffer(
             env.instance(),
             env.physical_device(),
             env.device().clone(),
-            env.command_pool(),
-            env.queue(),
+            env.transfer_command_pool(),
+            env.transfer_queue(),
             vk::BufferUsageFlags::VERTEX_BUFFER,
-            vertices);
+            data.vertices);
 
         let (index_buffer, index_buffer_memory) = create_data_buffer(
             env.instance(),
             env.physical_device(),
             env.device().clone(),
-            env.command_pool(),
-            env.queue(),
+            env.transfer_command_pool(),
+            env.transfer_queue(),
             vk::BufferUsageFlags::INDEX_BUFFER,
-            indices);
+            data.indices);
+        env.set_debug_name(vertex_buffer, "mesh vertices");
+        env.set_debug_name(index_buffer, "mesh indices");
 
-        println!("Model uploaded: {}", t1.elapsed().as_secs_f32());
-
-        let texture = Texture::new(
-            env.device().clone(),
-            env.command_pool(),
-            env.queue(),
-            &env.mem_properties,
-            Path::new("assets/chalet.jpg"),
-        );
         Mesh {
             device: env.device().clone(),
 
@@ -164,8 +309,8 @@ impl Mesh {
             index_buffer_memory,
 
             index_count,
-
-            texture,
+            lod: data.lod,
+            bounds: data.bounds,
         }
     }
 }
@@ -174,10 +319,14 @@ impl Drop for Mesh {
     fn drop(&mut self) {
         unsafe {
             self.device.destroy_buffer(self.index_buffer, None);
+            object_tracker::destroyed(ObjectKind::Buffer);
             self.device.free_memory(self.index_buffer_memory, None);
+            memory_tracker::freed(self.index_buffer_memory);
 
             self.device.destroy_buffer(self.vertex_buffer, None);
+            object_tracker::destroyed(ObjectKind::Buffer);
             self.device.free_memory(self.vertex_buffer_memory, None);
+            memory_tracker::freed(self.vertex_buffer_memory);
         }
     }
 }
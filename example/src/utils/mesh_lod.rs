@@ -0,0 +1,159 @@
+use std::collections::{HashMap, HashSet};
+
+// Cells along the longest side of the mesh bounds for the first simplified level, halved for every next one
+const FIRST_LEVEL_CELLS: f32 = 256.0;
+const MAX_LEVELS: usize = 5;
+// a level is kept when it has at most this part of the triangles of the previous one
+const MIN_REDUCTION: f32 = 0.75;
+// meshes smaller than this are not simplified, nor are levels made smaller than it
+const MIN_TRIANGLES: usize = 64;
+
+// Range of the mesh index buffer drawn at one detail level
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LodLevel {
+    pub first_index: u32,
+    pub index_count: u32,
+    // largest vertex displacement from the full mesh, in object space units
+    pub error: f32,
+}
+
+// Detail levels of a mesh, all index the same vertices and live in one index buffer one after another.
+// The first level is the full mesh. Simplified levels are made by vertex clustering: vertices in a cell
+// of a grid are welded to one of them and collapsed triangles are dropped
+#[derive(Clone, Debug)]
+pub struct MeshLod {
+    levels: Vec<LodLevel>,
+}
+
+impl MeshLod {
+    // Full mesh only
+    pub fn single(index_count: usize) -> MeshLod {
+        MeshLod {
+            levels: vec![LodLevel { first_index: 0, index_count: index_count as u32, error: 0.0 }],
+        }
+    }
+
+    // Appends indices of the simplified levels to `indices`, which are the full mesh
+    pub fn generate(positions: &[[f32; 3]], indices: &mut Vec<u32>) -> MeshLod {
+        let mut lod = MeshLod::single(indices.len());
+        if indices.len() / 3 < MIN_TRIANGLES {
+            return lod;
+        }
+
+        let mut min = [f32::MAX; 3];
+        let mut max = [f32::MIN; 3];
+        for pos in positions.iter() {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(pos[axis]);
+                max[axis] = max[axis].max(pos[axis]);
+            }
+        }
+        let extent = (0..3).map(|axis| max[axis] - min[axis]).fold(0.0, f32::max);
+        if extent <= 0.0 {
+            return lod;
+        }
+
+        let mut cell = extent / FIRST_LEVEL_CELLS;
+        let mut error = 0.0;
+        let mut previous = indices.clone();
+        while lod.levels.len() < MAX_LEVELS {
+            let simplified = cluster(positions, &previous, min, cell);
+            let (triangles, previous_triangles) = (simplified.len() / 3, previous.len() / 3);
+            if triangles < MIN_TRIANGLES {
+                break;
+            }
+
+            if triangles as f32 <= previous_triangles as f32 * MIN_REDUCTION {
+                // a vertex moves at most across its cell, levels are simplified from the previous ones
+                error += cell * 3.0f32.sqrt();
+                lod.levels.push(LodLevel {
+                    first_index: indices.len() as u32,
+                    index_count: simplified.len() as u32,
+                    error,
+                });
+                indices.extend_from_slice(&simplified);
+                previous = simplified;
+            }
+            cell *= 2.0;
+        }
+
+        lod
+    }
+
+    pub fn levels(&self) -> &[LodLevel] {
+        &self.levels
+    }
+
+    // Coarsest level with projected error under `max_error_pixels`. `distance` - from the camera to the mesh,
+    // `pixels_per_unit` - size in pixels of one object space unit at distance 1
+    pub fn select(&self, distance: f32, pixels_per_unit: f32, max_error_pixels: f32) -> &LodLevel {
+        let scale = pixels_per_unit / distance.max(1e-3);
+        self.levels.iter()
+            .rev()
+            .find(|level| level.error * scale <= max_error_pixels)
+            .unwrap_or(&self.levels[0])
+    }
+}
+
+// Welds vertices of every grid cell to the one closest to their average, returns the triangles left
+fn cluster(positions: &[[f32; 3]], indices: &[u32], origin: [f32; 3], cell: f32) -> Vec<u32> {
+    let cell_of = |pos: &[f32; 3]| {
+        [
+            ((pos[0] - origin[0]) / cell).floor() as i32,
+            ((pos[1] - origin[1]) / cell).floor() as i32,
+            ((pos[2] - origin[2]) / cell).floor() as i32,
+        ]
+    };
+
+    // average of the used vertices of every cell
+    let mut sums: HashMap<[i32; 3], ([f32; 3], u32)> = HashMap::new();
+    let mut used = vec![false; positions.len()];
+    for &idx in indices.iter() {
+        if std::mem::replace(&mut used[idx as usize], true) {
+            continue;
+        }
+        let pos = &positions[idx as usize];
+        let (sum, count) = sums.entry(cell_of(pos)).or_insert(([0.0; 3], 0));
+        for axis in 0..3 {
+            sum[axis] += pos[axis];
+        }
+        *count += 1;
+    }
+
+    let mut representatives: HashMap<[i32; 3], (u32, f32)> = HashMap::new();
+    for (idx, pos) in positions.iter().enumerate().filter(|(idx, _)| used[*idx]) {
+        let key = cell_of(pos);
+        let (sum, count) = sums[&key];
+        let distance = (0..3).map(|axis| (pos[axis] - sum[axis] / count as f32).powi(2)).sum::<f32>();
+
+        let representative = representatives.entry(key).or_insert((idx as u32, f32::MAX));
+        if distance < representative.1 {
+            *representative = (idx as u32, distance);
+        }
+    }
+
+    let mut triangles = HashSet::new();
+    let mut simplified = Vec::with_capacity(indices.len());
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [triangle[0], triangle[1], triangle[2]]
+            .map(|idx| representatives[&cell_of(&positions[idx as usize])].0);
+        if a == b || b == c || a == c {
+            continue;
+        }
+
+        // the same triangle rotated, winding is kept
+        let key = if a < b && a < c { [a, b, c] } else if b < c { [b, c, a] } else { [c, a, b] };
+        if triangles.insert(key) {
+            simplified.extend_from_slice(&[a, b, c]);
+        }
+    }
+
+    simplified
+}
+
+// Triangles of the visible meshes of a frame, with the selected levels and at full detail
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LodStats {
+    pub triangles: usize,
+    pub full_triangles: usize,
+}
@@ -3,25 +3,62 @@ use std::sync::Arc;
 
 use ash::version::DeviceV1_0;
 use ash::vk;
-use cgmath::{Matrix4, Deg, Rad, Vector3};
+use cgmath::{InnerSpace, Matrix4, SquareMatrix};
 
+use ash_render_env::camera::CameraDescriptor;
+use ash_render_env::culling::{Aabb, CullStats};
 use ash_render_env::descriptor_set::DescriptorSet;
 use ash_render_env::env::RenderEnv;
+use ash_render_env::memory_tracker;
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::utils::buffer_utils::create_buffer;
 use ash_render_env::pipeline_builder::{Pipeline, PipelineBuilder};
 use ash_render_env::shader;
+use ash_render_env::utils::DepthMode;
+use crate::utils::gbuffer::GeometryPipeline;
 use crate::utils::uniform_buffer::UboBuffers;
 use crate::utils::mesh;
-use crate::utils::mesh::Mesh;
+use crate::utils::mesh_lod::LodStats;
+use crate::utils::model::Model;
+use crate::utils::scene_buffer::SceneBuffer;
 
 pub struct MeshRenderer {
     render_cmds: Vec<vk::CommandBuffer>,
 
     render_pass: vk::RenderPass,
     pipeline: Pipeline,
-    descriptor_sets: Vec<DescriptorSet>,
+    // created on the first switch to wireframe, layouts are the same as of `pipeline`
+    wireframe_pipeline: Option<Pipeline>,
+    wireframe: bool,
+    // depth only draws before the G-buffer ones, the fill pipeline then doesn't write depth
+    depth_prepass: bool,
+    depth_prepass_pipeline: Pipeline,
+    // sets of the vertex stage only, one per frame
+    depth_prepass_sets: Vec<DescriptorSet>,
+    // empty when the pre-pass is off
+    depth_prepass_cmds: Vec<vk::CommandBuffer>,
+    color_attachment_count: usize,
+    msaa_samples: vk::SampleCountFlags,
+    depth_mode: DepthMode,
+    // descriptor_sets[frame][part]
+    descriptor_sets: Vec<Vec<DescriptorSet>>,
     uniforms: UboBuffers,
-
-    mesh: Arc<Mesh>,
+    // model matrix of every draw as push constants, applied before the scene buffer object transform
+    transform: Matrix4<f32>,
+
+    model: Arc<Model>,
+
+    object_count: usize,
+    // world bounds of scene buffer objects, see set_objects
+    object_bounds: Vec<Aabb>,
+    // per frame draw of every object of every part, objects outside the frustum get zero instances,
+    // occlusion culling zeroes them on GPU
+    indirect_buffers: Vec<(vk::Buffer, vk::DeviceMemory)>,
+    cull_stats: CullStats,
+    // projected error of the detail level a mesh is drawn with, 0 draws full meshes
+    lod_error_pixels: f32,
+    lod_stats: LodStats,
+    dimensions: [u32; 2],
 
     current_frame: usize,
     max_inflight_frames: usize,
@@ -30,23 +67,14 @@ pub struct MeshRenderer {
 }
 
 impl MeshRenderer {
-    pub fn new(env: Arc<RenderEnv>, render_pass: vk::RenderPass, mesh: Arc<Mesh>, color_attachment_count: usize,
-               msaa_samples: vk::SampleCountFlags, max_inflight_frames: usize,
+    pub fn new(env: Arc<RenderEnv>, render_pass: vk::RenderPass, model: Arc<Model>, scene_buffer: &SceneBuffer,
+               color_attachment_count: usize, msaa_samples: vk::SampleCountFlags, depth_mode: DepthMode,
                dimensions: [u32; 2]) -> MeshRenderer
     {
-        let pipeline = {
-            let vert_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/mesh/mesh.vert.spv");
-            let frag_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/mesh/mesh.frag.spv");
-
-            PipelineBuilder::new(env.device().clone(), render_pass, 0)
-                .vertex_shader(vert_shader_module)
-                .fragment_shader(frag_shader_module)
-                .vertex_input(mesh::Vertex::binding_descriptions(), mesh::Vertex::attribute_descriptions())
-                .msaa(msaa_samples)
-                .with_depth_test()
-                .color_attachment_count(color_attachment_count)
-                .build()
-        };
+        let max_inflight_frames = scene_buffer.frame_count();
+
+        let pipeline = Self::create_pipeline(&env, render_pass, color_attachment_count, msaa_samples, depth_mode, GeometryPipeline::Fill);
+        let depth_prepass_pipeline = Self::create_pipeline(&env, render_pass, color_attachment_count, msaa_samples, depth_mode, GeometryPipeline::DepthPrepass);
 
         let uniforms = UboBuffers::new(
             env.instance(),
@@ -55,36 +83,233 @@ impl MeshRenderer {
             max_inflight_frames,
         );
 
-        let mut cmd_bufs = vec![];
-        let mut descriptor_sets = vec![];
-        for i in 0..max_inflight_frames {
-            descriptor_sets.push(
-                DescriptorSet::builder(env.device(), pipeline.descriptor_set_layouts.get(0).unwrap())
-                    .add_buffer(uniforms.uniform_buffers[i])
-                    .add_image(mesh.texture.texture_image_view, mesh.texture.texture_sampler)
-                    .build()
-            );
-            cmd_bufs.push(
-                Self::build_cmd_buf(&env, render_pass, &pipeline, &descriptor_sets[i], &mesh, dimensions)
-            );
-        }
-
-        MeshRenderer {
+        let mut renderer = MeshRenderer {
             env: env.clone(),
             pipeline: pipeline,
-            render_cmds: cmd_bufs,
+            wireframe_pipeline: None,
+            wireframe: false,
+            depth_prepass: false,
+            depth_prepass_pipeline,
+            depth_prepass_sets: vec![],
+            depth_prepass_cmds: vec![],
+            color_attachment_count,
+            msaa_samples,
+            depth_mode,
+            render_cmds: vec![],
             render_pass: render_pass,
             uniforms,
-            descriptor_sets: descriptor_sets,
-            mesh,
+            descriptor_sets: vec![],
+            transform: Matrix4::identity(),
+            model,
+            object_count: 0,
+            object_bounds: vec![],
+            indirect_buffers: vec![],
+            cull_stats: CullStats::default(),
+            lod_error_pixels: 0.0,
+            lod_stats: LodStats::default(),
+            dimensions,
             current_frame: 0,
             max_inflight_frames,
+        };
+        renderer.update_descriptor_sets(scene_buffer);
+        renderer.set_objects(vec![]);
+
+        renderer
+    }
+
+    // Also draws the GPU culled instances of InstanceField
+    pub fn create_pipeline(env: &RenderEnv, render_pass: vk::RenderPass, color_attachment_count: usize, msaa_samples: vk::SampleCountFlags,
+                           depth_mode: DepthMode, kind: GeometryPipeline) -> Pipeline {
+        let vert_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/mesh/mesh.vert.spv").expect("Failed to load shader!");
+        let frag_shader_path = match kind {
+            GeometryPipeline::DepthPrepass => "assets/shaders/spv/depth_only.frag.spv",
+            _ => "assets/shaders/spv/mesh/mesh.frag.spv",
+        };
+        let frag_shader_module = shader::Shader::load(env.device(), frag_shader_path).expect("Failed to load shader!");
+        let polygon_mode = if kind == GeometryPipeline::Wireframe { vk::PolygonMode::LINE } else { vk::PolygonMode::FILL };
+
+        let mut builder = PipelineBuilder::new(env.device().clone(), render_pass, 0)
+            .pipeline_cache(env.pipeline_cache())
+            .device_features(env.enabled_features())
+            .vertex_shader(vert_shader_module)
+            .fragment_shader(frag_shader_module)
+            .vertex_input(mesh::Vertex::binding_descriptions(), mesh::Vertex::attribute_descriptions())
+            .msaa(msaa_samples)
+            .with_depth_test()
+            .depth_write(kind != GeometryPipeline::FillAfterPrepass)
+            .depth_mode(depth_mode)
+            .polygon_mode(polygon_mode)
+            .color_attachment_count(color_attachment_count);
+        if kind == GeometryPipeline::DepthPrepass {
+            builder = builder.disable_color_writes();
         }
+
+        builder
+            .build()
+            .expect("Failed to create pipeline!")
     }
 
-    fn build_cmd_buf(env: &RenderEnv, render_pass: vk::RenderPass, pipeline: &Pipeline, descriptor_set: &DescriptorSet, vertex_buffer: &Mesh, dimensions: [u32; 2]) -> vk::CommandBuffer {
-        let command_buffer = env.create_secondary_command_buffer();
-        let device = env.device();
+    fn fill_pipeline_kind(&self) -> GeometryPipeline {
+        if self.depth_prepass { GeometryPipeline::FillAfterPrepass } else { GeometryPipeline::Fill }
+    }
+
+    // Draws triangle edges only. Command buffers are re-recorded, so the caller must make sure they are not in use
+    pub fn set_wireframe(&mut self, wireframe: bool) {
+        if wireframe && self.wireframe_pipeline.is_none() {
+            self.wireframe_pipeline = Some(Self::create_pipeline(&self.env, self.render_pass, self.color_attachment_count,
+                                                                 self.msaa_samples, self.depth_mode, GeometryPipeline::Wireframe));
+        }
+
+        self.wireframe = wireframe;
+        self.resize_framebuffer(self.dimensions);
+    }
+
+    // Pipelines for a new G-buffer render pass, e.g. with another sample count. Descriptor sets are kept,
+    // the layouts don't change. Command buffers are re-recorded, so the caller must make sure they are not in use
+    pub fn set_render_pass(&mut self, render_pass: vk::RenderPass, msaa_samples: vk::SampleCountFlags) {
+        self.render_pass = render_pass;
+        self.msaa_samples = msaa_samples;
+        self.pipeline = Self::create_pipeline(&self.env, render_pass, self.color_attachment_count, msaa_samples, self.depth_mode, self.fill_pipeline_kind());
+        self.depth_prepass_pipeline = Self::create_pipeline(&self.env, render_pass, self.color_attachment_count, msaa_samples, self.depth_mode,
+                                                            GeometryPipeline::DepthPrepass);
+        self.wireframe_pipeline = None;
+        self.set_wireframe(self.wireframe);
+    }
+
+    // Lays down depth with depth_prepass_draw() before the G-buffer draw, so the heavy fragment shader runs
+    // for visible pixels only. Wireframe mode draws without it.
+    // Command buffers are re-recorded, so the caller must make sure they are not in use
+    pub fn set_depth_prepass(&mut self, depth_prepass: bool) {
+        self.depth_prepass = depth_prepass;
+        self.pipeline = Self::create_pipeline(&self.env, self.render_pass, self.color_attachment_count, self.msaa_samples, self.depth_mode,
+                                              self.fill_pipeline_kind());
+        self.resize_framebuffer(self.dimensions);
+    }
+
+    fn update_descriptor_sets(&mut self, scene_buffer: &SceneBuffer) {
+        self.descriptor_sets = (0..self.max_inflight_frames)
+            .map(|i| {
+                self.model.parts.iter()
+                    .map(|part| {
+                        let material = &part.material;
+                        DescriptorSet::builder(self.env.device(), &self.pipeline.descriptor_set_layouts[0])
+                            .add_buffer(self.uniforms.uniform_buffers[i])
+                            .add_texture(&material.albedo)
+                            .add_storage_buffer(scene_buffer.buffer(i))
+                            .add_texture(&material.normal)
+                            .add_texture(&material.metallic_roughness)
+                            .add_texture(&material.occlusion)
+                            .build()
+                            .expect("Failed to create descriptor set!")
+                    })
+                    .collect()
+            })
+            .collect();
+
+        self.depth_prepass_sets = (0..self.max_inflight_frames)
+            .map(|i| {
+                DescriptorSet::builder(self.env.device(), &self.depth_prepass_pipeline.descriptor_set_layouts[0])
+                    .add_buffer(self.uniforms.uniform_buffers[i])
+                    .add_storage_buffer(scene_buffer.buffer(i))
+                    .build()
+                    .expect("Failed to create descriptor set!")
+            })
+            .collect();
+    }
+
+    // Command buffers must not be in use
+    pub fn set_model(&mut self, model: Arc<Model>, scene_buffer: &SceneBuffer) {
+        self.model = model;
+        self.update_descriptor_sets(scene_buffer);
+        self.resize_framebuffer(self.dimensions);
+    }
+
+    // World bounds of every scene buffer object: all instances of the first model part, then of the second one and so on,
+    // so objects of part N are at N * count..(N + 1) * count where count is the number of model instances.
+    // Command buffers are re-recorded, so the caller must make sure they are not in use (same as for resize)
+    pub fn set_objects(&mut self, bounds: Vec<Aabb>) {
+        self.object_count = bounds.len() / self.model.parts.len().max(1);
+        self.object_bounds = bounds;
+        self.resize_framebuffer(self.dimensions);
+    }
+
+    // Objects of the last frame, all parts of a model instance count separately
+    pub fn cull_stats(&self) -> CullStats {
+        self.cull_stats
+    }
+
+    // Coarsest detail levels that move vertices by at most `pixels` on screen, 0 turns levels off
+    pub fn set_lod_error(&mut self, pixels: f32) {
+        self.lod_error_pixels = pixels.max(0.0);
+    }
+
+    pub fn lod_error(&self) -> f32 {
+        self.lod_error_pixels
+    }
+
+    // Triangles of the last frame, transparent parts included
+    pub fn lod_stats(&self) -> LodStats {
+        self.lod_stats
+    }
+
+    fn create_indirect_buffers(&mut self) {
+        self.destroy_indirect_buffers();
+
+        let draw_count = (self.model.parts.len() * self.object_count).max(1);
+        let size = std::mem::size_of::<vk::DrawIndexedIndirectCommand>() * draw_count;
+        self.indirect_buffers = (0..self.max_inflight_frames)
+            .map(|_| create_buffer(
+                self.env.device(),
+                size as u64,
+                vk::BufferUsageFlags::INDIRECT_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                &self.env.mem_properties,
+            ))
+            .collect();
+    }
+
+    fn destroy_indirect_buffers(&mut self) {
+        unsafe {
+            for &(buffer, memory) in self.indirect_buffers.iter() {
+                self.env.device().destroy_buffer(buffer, None);
+                object_tracker::destroyed(ObjectKind::Buffer);
+                self.env.device().free_memory(memory, None);
+                memory_tracker::freed(memory);
+            }
+        }
+        self.indirect_buffers.clear();
+    }
+
+    // Indirect draws written by the last draw() and their count, in the order of set_objects() bounds
+    pub fn indirect_draws(&self) -> (vk::Buffer, usize) {
+        let frame = (self.current_frame + self.max_inflight_frames - 1) % self.max_inflight_frames;
+        (self.indirect_buffers[frame].0, self.model.parts.len() * self.object_count)
+    }
+
+    pub fn object_bounds(&self) -> &[Aabb] {
+        &self.object_bounds
+    }
+
+    // One indirect draw per object of every opaque model part, culled ones included
+    pub fn draw_call_count(&self) -> usize {
+        self.model.parts.iter().filter(|part| !part.material.transparent).count() * self.object_count
+    }
+
+    // `depth_prepass` - records the depth only draws of the same objects
+    fn build_cmd_buf(&self, frame: usize, dimensions: [u32; 2], depth_prepass: bool) -> vk::CommandBuffer {
+        let descriptor_sets = &self.descriptor_sets[frame];
+        let indirect_buffer = self.indirect_buffers[frame].0;
+        let command_buffer = self.env.create_secondary_command_buffer();
+        let device = self.env.device();
+        let render_pass = self.render_pass;
+        let pipeline = if depth_prepass {
+            &self.depth_prepass_pipeline
+        } else {
+            match &self.wireframe_pipeline {
+                Some(wireframe_pipeline) if self.wireframe => wireframe_pipeline,
+                _ => &self.pipeline,
+            }
+        };
 
         let inheritance_info = vk::CommandBufferInheritanceInfo {
             s_type: vk::StructureType::COMMAND_BUFFER_INHERITANCE_INFO,
@@ -138,22 +363,40 @@ impl MeshRenderer {
                 pipeline.graphics_pipeline,
             );
 
-            let descriptor_sets_to_bind = [descriptor_set.set];
-            device.cmd_bind_descriptor_sets(
-                command_buffer,
-                vk::PipelineBindPoint::GRAPHICS,
-                pipeline.pipeline_layout,
-                0,
-                &descriptor_sets_to_bind,
-                &[],
-            );
-
-            let vertex_buffers = [vertex_buffer.vertex_buffer];
-            let offsets = [0_u64];
-            device.cmd_bind_vertex_buffers(command_buffer, 0, &vertex_buffers, &offsets);
-            device.cmd_bind_index_buffer(command_buffer, vertex_buffer.index_buffer, 0, vk::IndexType::UINT32);
-
-            device.cmd_draw_indexed(command_buffer, vertex_buffer.index_count as u32, 1, 0, 0, 0);
+            for (part_idx, (part, descriptor_set)) in self.model.parts.iter().zip(descriptor_sets).enumerate() {
+                // drawn by TransparentRenderer after the composite
+                if part.material.transparent {
+                    continue;
+                }
+
+                let descriptor_set = if depth_prepass { &self.depth_prepass_sets[frame] } else { descriptor_set };
+                let descriptor_sets_to_bind = [descriptor_set.set];
+                device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    pipeline.pipeline_layout,
+                    0,
+                    &descriptor_sets_to_bind,
+                    &[],
+                );
+
+                pipeline.push_constants(command_buffer, vk::ShaderStageFlags::VERTEX, &self.transform);
+                if !depth_prepass {
+                    pipeline.push_constants(command_buffer, vk::ShaderStageFlags::FRAGMENT, &part.material.factors());
+                }
+
+                let vertex_buffers = [part.mesh.vertex_buffer];
+                let offsets = [0_u64];
+                device.cmd_bind_vertex_buffers(command_buffer, 0, &vertex_buffers, &offsets);
+                device.cmd_bind_index_buffer(command_buffer, part.mesh.index_buffer, 0, vk::IndexType::UINT32);
+
+                // one draw per object, multiDrawIndirect is not required
+                let stride = std::mem::size_of::<vk::DrawIndexedIndirectCommand>();
+                for object_idx in 0..self.object_count {
+                    let offset = (part_idx * self.object_count + object_idx) * stride;
+                    device.cmd_draw_indexed_indirect(command_buffer, indirect_buffer, offset as u64, 1, stride as u32);
+                }
+            }
 
             device
                 .end_command_buffer(command_buffer)
@@ -164,27 +407,105 @@ impl MeshRenderer {
     }
 
     pub fn resize_framebuffer(&mut self, dimensions: [u32; 2]) {
-        unsafe {
-            self.env.device().free_command_buffers(self.env.command_pool(), &self.render_cmds);
-        }
+        self.free_cmd_bufs();
+        self.create_indirect_buffers();
 
         let mut cmd_bufs = vec![];
 
         for i in 0..self.max_inflight_frames {
             cmd_bufs.push(
-                Self::build_cmd_buf(&self.env, self.render_pass, &self.pipeline,
-                                    &self.descriptor_sets[i], &self.mesh, dimensions)
+                self.build_cmd_buf(i, dimensions, false)
             );
         }
 
         self.render_cmds = cmd_bufs;
+        if self.depth_prepass && !self.wireframe {
+            self.depth_prepass_cmds = (0..self.max_inflight_frames)
+                .map(|i| self.build_cmd_buf(i, dimensions, true))
+                .collect();
+        }
+        self.dimensions = dimensions;
     }
 
-    pub fn draw(&mut self, view: Matrix4<f32>, proj: Matrix4<f32>) -> vk::CommandBuffer {
-        let w1 = Matrix4::<f32>::from_angle_x(Rad::from(Deg(90.0)));
-        let world = Matrix4::<f32>::from_translation(Vector3::new(0.0, 0.01, -10.0 )) * w1;
+    fn free_cmd_bufs(&mut self) {
+        unsafe {
+            if !self.render_cmds.is_empty() {
+                self.env.device().free_command_buffers(self.env.command_pool(), &self.render_cmds);
+            }
+            if !self.depth_prepass_cmds.is_empty() {
+                self.env.device().free_command_buffers(self.env.command_pool(), &self.depth_prepass_cmds);
+            }
+        }
+        self.render_cmds.clear();
+        self.depth_prepass_cmds.clear();
+    }
 
-        self.uniforms.update_uniform_buffer(self.current_frame, world, view, proj);
+    // Depth only draws of the frame returned by the last draw(), must go before all G-buffer draws of the pass
+    pub fn depth_prepass_draw(&self) -> Option<vk::CommandBuffer> {
+        let frame = (self.current_frame + self.max_inflight_frames - 1) % self.max_inflight_frames;
+        self.depth_prepass_cmds.get(frame).copied()
+    }
+
+    pub fn draw(&mut self, camera: &CameraDescriptor) -> vk::CommandBuffer {
+        // objects of the scene buffer carry complete world transform, mesh.vert takes the model matrix
+        // from push constants, so the recorded draws keep it without touching the uniforms
+        self.uniforms.update_uniform_buffer(self.current_frame, Matrix4::identity(), camera.view, camera.proj, camera);
+
+        // size in pixels of a unit at distance 1, proj.y.y is 1 / tan(fov / 2)
+        let pixels_per_unit = camera.proj.y.y.abs() * self.dimensions[1] as f32 * 0.5;
+        let mut lod_stats = LodStats::default();
+
+        let mut commands = Vec::with_capacity(self.model.parts.len() * self.object_count);
+        for (part_idx, part) in self.model.parts.iter().enumerate() {
+            let local_bounds = Aabb::from_bounds(part.mesh.bounds);
+            let local_size = (local_bounds.max - local_bounds.min).magnitude();
+            for object_idx in 0..self.object_count {
+                let first_instance = part_idx * self.object_count + object_idx;
+                // objects without bounds are always drawn, in full detail
+                let bounds = self.object_bounds.get(first_instance);
+                let visible = bounds.is_none_or(|bounds| camera.frustum.intersects_aabb(bounds));
+
+                let level = match bounds {
+                    Some(bounds) if self.lod_error_pixels > 0.0 && local_size > 0.0 => {
+                        // lod errors are in object space, the distance is brought to it
+                        let scale = (bounds.max - bounds.min).magnitude() / local_size;
+                        part.mesh.lod.select(bounds.distance(camera.position) / scale.max(1e-6), pixels_per_unit, self.lod_error_pixels)
+                    }
+                    _ => &part.mesh.lod.levels()[0],
+                };
+                if visible {
+                    lod_stats.triangles += level.index_count as usize / 3;
+                    lod_stats.full_triangles += part.mesh.index_count / 3;
+                }
+
+                commands.push(vk::DrawIndexedIndirectCommand {
+                    index_count: level.index_count,
+                    instance_count: visible as u32,
+                    first_index: level.first_index,
+                    vertex_offset: 0,
+                    first_instance: first_instance as u32,
+                });
+            }
+        }
+        self.cull_stats = CullStats {
+            total: commands.len(),
+            culled: commands.iter().filter(|cmd| cmd.instance_count == 0).count(),
+        };
+        self.lod_stats = lod_stats;
+
+        if !commands.is_empty() {
+            unsafe {
+                let memory = self.indirect_buffers[self.current_frame].1;
+                let size = std::mem::size_of_val(commands.as_slice()) as u64;
+                let data_ptr = self.env.device()
+                    .map_memory(memory, 0, size, vk::MemoryMapFlags::empty())
+                    .expect("Failed to Map Memory") as *mut vk::DrawIndexedIndirectCommand;
+
+                data_ptr.copy_from_nonoverlapping(commands.as_ptr(), commands.len());
+
+                self.env.device().unmap_memory(memory);
+            }
+        }
 
         let current_frame = self.current_frame;
         self.current_frame = (self.current_frame + 1) % self.max_inflight_frames;
@@ -195,10 +516,7 @@ impl MeshRenderer {
 
 impl Drop for MeshRenderer {
     fn drop(&mut self) {
-        unsafe {
-            if self.render_cmds.len() > 0 {
-                self.env.device().free_command_buffers(self.env.command_pool(), &self.render_cmds);
-            }
-        }
+        self.free_cmd_bufs();
+        self.destroy_indirect_buffers();
     }
 }
@@ -1,20 +1,22 @@
 use std::ptr;
+use std::rc::Rc;
 use std::sync::Arc;
 
 use ash::version::DeviceV1_0;
 use ash::vk;
-use cgmath::{Deg, Matrix4, Point3, Rad, SquareMatrix, Vector3};
+use cgmath::Matrix4;
 
-use ash_render_env::camera::Camera;
 use ash_render_env::descriptor_set::DescriptorSet;
+use ash_render_env::dynamic_uniform_buffer::DynamicUniformRing;
 use ash_render_env::env::RenderEnv;
+use ash_render_env::object_tracker::{self, ObjectKind};
 use ash_render_env::pipeline_builder::{Pipeline, PipelineBuilder};
 use ash_render_env::shader;
 
-use crate::shadow_map::uniform_buffer::{ShadowMapData, UniformBuffer};
+use crate::shadow_map::uniform_buffer::ShadowMapData;
 use crate::utils::mesh;
-use crate::utils::mesh::Mesh;
-use crate::utils::uniform_buffer::UboBuffers;
+use crate::utils::model::Model;
+use crate::utils::scene_buffer::SceneBuffer;
 
 pub struct MeshShadowMapRenderer {
     render_cmds: Vec<vk::CommandBuffer>,
@@ -22,9 +24,14 @@ pub struct MeshShadowMapRenderer {
     render_pass: vk::RenderPass,
     pipeline: Pipeline,
     descriptor_sets: Vec<DescriptorSet>,
-    uniforms: Vec<UniformBuffer<ShadowMapData>>,
+    uniforms: Rc<DynamicUniformRing<ShadowMapData>>,
+    uniform_slot: usize,
 
-    mesh: Arc<Mesh>,
+    model: Arc<Model>,
+    object_count: usize,
+    dimensions: [u32; 2],
+    // constant and slope factors
+    depth_bias: (f32, f32),
 
     current_frame: usize,
     max_inflight_frames: usize,
@@ -34,57 +41,94 @@ pub struct MeshShadowMapRenderer {
 }
 
 impl MeshShadowMapRenderer {
-    pub fn new(env: Arc<RenderEnv>, render_pass: vk::RenderPass, mesh: Arc<Mesh>, max_inflight_frames: usize,
-               dimensions: [u32; 2]) -> MeshShadowMapRenderer
+    // `uniforms` is shared between shadow passes, every renderer writes to its own `uniform_slot`
+    pub fn new(env: Arc<RenderEnv>, render_pass: vk::RenderPass, model: Arc<Model>, scene_buffer: &SceneBuffer,
+               uniforms: Rc<DynamicUniformRing<ShadowMapData>>, uniform_slot: usize, dimensions: [u32; 2]) -> MeshShadowMapRenderer
     {
+        let max_inflight_frames = scene_buffer.frame_count();
+
         let pipeline = {
-            let vert_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/mesh/shadow_map.vert.spv");
-            let frag_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/mesh/shadow_map.frag.spv");
+            let vert_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/mesh/shadow_map.vert.spv").expect("Failed to load shader!")
+                .dynamic_uniform_buffer(0, 0);
+            let frag_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/mesh/shadow_map.frag.spv").expect("Failed to load shader!");
 
             PipelineBuilder::new(env.device().clone(), render_pass, 0)
+                .pipeline_cache(env.pipeline_cache())
                 .vertex_shader(vert_shader_module)
                 .fragment_shader(frag_shader_module)
                 .vertex_input(mesh::Vertex::binding_descriptions(), mesh::Vertex::attribute_descriptions())
                 .with_depth_func(vk::CompareOp::LESS_OR_EQUAL)
                 .depth_clamp_enable(true)
+                .device_features(env.enabled_features())
+                .depth_bias(0.0, 0.0)
+                .dynamic_state(vk::DynamicState::DEPTH_BIAS)
                 .color_attachment_count(0)
                 .build()
+                .expect("Failed to create pipeline!")
         };
 
-        let sampler = create_texture_sampler(env.device(), 1);
-        let mut cmd_bufs = Vec::with_capacity(max_inflight_frames);
+        let sampler = create_texture_sampler(env.device(), 1, env.max_sampler_anisotropy());
         let mut descriptor_sets = Vec::with_capacity(max_inflight_frames);
-        let mut uniforms = Vec::with_capacity(max_inflight_frames);
         for i in 0..max_inflight_frames {
-            let uniform_buffer = UniformBuffer::new(env.clone());
             descriptor_sets.push(
                 DescriptorSet::builder(env.device(), pipeline.descriptor_set_layouts.get(0).unwrap())
-                    .add_buffer(uniform_buffer.buffer.clone())
+                    .add_dynamic_buffer(uniforms.buffer, uniforms.stride())
+                    .add_storage_buffer(scene_buffer.buffer(i))
                     .build()
+                    .expect("Failed to create descriptor set!")
             );
-            cmd_bufs.push(
-                Self::build_cmd_buf(&env, render_pass, &pipeline, &descriptor_sets[i], &mesh, dimensions)
-            );
-            uniforms.push(uniform_buffer);
         }
 
-        MeshShadowMapRenderer {
+        let mut renderer = MeshShadowMapRenderer {
             env: env.clone(),
             pipeline,
-            render_cmds: cmd_bufs,
+            render_cmds: vec![],
             render_pass,
             uniforms,
+            uniform_slot,
             descriptor_sets,
-            mesh,
+            model,
+            object_count: 0,
+            dimensions,
+            depth_bias: (0.0, 0.0),
             current_frame: 0,
             max_inflight_frames,
             sampler,
-        }
+        };
+        renderer.resize_framebuffer(dimensions);
+
+        renderer
+    }
+
+    // Command buffers must not be in use
+    pub fn set_model(&mut self, model: Arc<Model>) {
+        self.model = model;
+        self.resize_framebuffer(self.dimensions);
+    }
+
+    // Instances per model part, same scene buffer layout as in MeshRenderer. Command buffers must not be in use
+    pub fn set_object_count(&mut self, count: usize) {
+        self.object_count = count;
+        self.resize_framebuffer(self.dimensions);
+    }
+
+    pub fn draw_call_count(&self) -> usize {
+        self.model.parts.len()
+    }
+
+    // Command buffers must not be in use
+    pub fn set_depth_bias(&mut self, constant_factor: f32, slope_factor: f32) {
+        self.depth_bias = (constant_factor, slope_factor);
+        self.resize_framebuffer(self.dimensions);
     }
 
-    fn build_cmd_buf(env: &RenderEnv, render_pass: vk::RenderPass, pipeline: &Pipeline, descriptor_set: &DescriptorSet, vertex_buffer: &Mesh, dimensions: [u32; 2]) -> vk::CommandBuffer {
-        let command_buffer = env.create_secondary_command_buffer();
-        let device = env.device();
+    fn build_cmd_buf(&self, frame: usize) -> vk::CommandBuffer {
+        let command_buffer = self.env.create_secondary_command_buffer();
+        let device = self.env.device();
+        let render_pass = self.render_pass;
+        let pipeline = &self.pipeline;
+        let dimensions = self.dimensions;
+        let uniform_offset = self.uniforms.offset(frame, self.uniform_slot);
 
         let inheritance_info = vk::CommandBufferInheritanceInfo {
             s_type: vk::StructureType::COMMAND_BUFFER_INHERITANCE_INFO,
@@ -131,6 +175,7 @@ impl MeshShadowMapRenderer {
         unsafe {
             device.cmd_set_viewport(command_buffer, 0, viewports.as_ref());
             device.cmd_set_scissor(command_buffer, 0, scissors.as_ref());
+            device.cmd_set_depth_bias(command_buffer, self.depth_bias.0, 0.0, self.depth_bias.1);
 
             device.cmd_bind_pipeline(
                 command_buffer,
@@ -138,22 +183,17 @@ impl MeshShadowMapRenderer {
                 pipeline.graphics_pipeline,
             );
 
-            let descriptor_sets_to_bind = [descriptor_set.set];
-            device.cmd_bind_descriptor_sets(
-                command_buffer,
-                vk::PipelineBindPoint::GRAPHICS,
-                pipeline.pipeline_layout,
-                0,
-                &descriptor_sets_to_bind,
-                &[],
-            );
+            self.descriptor_sets[frame].bind(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.pipeline_layout, 0, &[uniform_offset]);
 
-            let vertex_buffers = [vertex_buffer.vertex_buffer];
-            let offsets = [0_u64];
-            device.cmd_bind_vertex_buffers(command_buffer, 0, &vertex_buffers, &offsets);
-            device.cmd_bind_index_buffer(command_buffer, vertex_buffer.index_buffer, 0, vk::IndexType::UINT32);
+            for (part_idx, part) in self.model.parts.iter().enumerate() {
+                let vertex_buffers = [part.mesh.vertex_buffer];
+                let offsets = [0_u64];
+                device.cmd_bind_vertex_buffers(command_buffer, 0, &vertex_buffers, &offsets);
+                device.cmd_bind_index_buffer(command_buffer, part.mesh.index_buffer, 0, vk::IndexType::UINT32);
 
-            device.cmd_draw_indexed(command_buffer, vertex_buffer.index_count as u32, 1, 0, 0, 0);
+                let first_instance = (part_idx * self.object_count) as u32;
+                device.cmd_draw_indexed(command_buffer, part.mesh.index_count as u32, self.object_count as u32, 0, 0, first_instance);
+            }
 
             device
                 .end_command_buffer(command_buffer)
@@ -164,31 +204,29 @@ impl MeshShadowMapRenderer {
     }
 
     pub fn resize_framebuffer(&mut self, dimensions: [u32; 2]) {
-        unsafe {
-            self.env.device().free_command_buffers(self.env.command_pool(), &self.render_cmds);
+        if !self.render_cmds.is_empty() {
+            unsafe {
+                self.env.device().free_command_buffers(self.env.command_pool(), &self.render_cmds);
+            }
         }
+        self.dimensions = dimensions;
 
         let mut cmd_bufs = vec![];
 
         for i in 0..self.max_inflight_frames {
-            cmd_bufs.push(
-                Self::build_cmd_buf(&self.env, self.render_pass, &self.pipeline,
-                                    &self.descriptor_sets[i], &self.mesh, dimensions)
-            );
+            cmd_bufs.push(self.build_cmd_buf(i));
         }
 
         self.render_cmds = cmd_bufs;
     }
 
-    pub fn draw(&mut self, camera: &Camera, light_vp: Matrix4<f32>) -> vk::CommandBuffer {
+    pub fn draw(&mut self, light_vp: Matrix4<f32>) -> vk::CommandBuffer {
         let current_frame = self.current_frame;
         self.current_frame = (self.current_frame + 1) % self.max_inflight_frames;
 
-        let w1 = Matrix4::<f32>::from_angle_x(Rad::from(Deg(90.0)));
-        let world = Matrix4::<f32>::from_translation(Vector3::new(0.0, 0.01, -10.0)) * w1;
-
-        self.uniforms[current_frame].write_data(ShadowMapData {
-            light_wp: light_vp * world, //proj * view * world,
+        // world transforms come from the scene buffer
+        self.uniforms.write(current_frame, self.uniform_slot, ShadowMapData {
+            light_vp,
         });
 
         self.render_cmds[current_frame]
@@ -198,15 +236,17 @@ impl MeshShadowMapRenderer {
 impl Drop for MeshShadowMapRenderer {
     fn drop(&mut self) {
         unsafe {
+            self.env.device().destroy_sampler(self.sampler, None);
+            object_tracker::destroyed(ObjectKind::Sampler);
+
             if self.render_cmds.len() > 0 {
-                self.env.device().destroy_sampler(self.sampler, None);
                 self.env.device().free_command_buffers(self.env.command_pool(), &self.render_cmds);
             }
         }
     }
 }
 
-pub fn create_texture_sampler(device: &ash::Device, mip_levels: u32) -> vk::Sampler {
+pub fn create_texture_sampler(device: &ash::Device, mip_levels: u32, max_anisotropy: f32) -> vk::Sampler {
     let sampler_create_info = vk::SamplerCreateInfo {
         s_type: vk::StructureType::SAMPLER_CREATE_INFO,
         p_next: ptr::null(),
@@ -216,8 +256,8 @@ pub fn create_texture_sampler(device: &ash::Device, mip_levels: u32) -> vk::Samp
         address_mode_u: vk::SamplerAddressMode::CLAMP_TO_EDGE,
         address_mode_v: vk::SamplerAddressMode::CLAMP_TO_EDGE,
         address_mode_w: vk::SamplerAddressMode::CLAMP_TO_EDGE,
-        anisotropy_enable: vk::TRUE,
-        max_anisotropy: 16.0,
+        anisotropy_enable: (max_anisotropy > 1.0) as vk::Bool32,
+        max_anisotropy,
         compare_enable: vk::FALSE,
         compare_op: vk::CompareOp::NEVER,
 
@@ -230,10 +270,13 @@ pub fn create_texture_sampler(device: &ash::Device, mip_levels: u32) -> vk::Samp
         unnormalized_coordinates: vk::FALSE,
     };
 
-    unsafe {
+    let sampler = unsafe {
         device
             .create_sampler(&sampler_create_info, None)
             .expect("Failed to create Sampler!")
-    }
+    };
+    object_tracker::created(ObjectKind::Sampler);
+
+    sampler
 }
 
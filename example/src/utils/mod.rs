@@ -1,6 +1,10 @@
 pub mod render_pass;
 pub mod sync;
 pub mod mesh;
+pub mod mesh_lod;
+pub mod material;
+pub mod model;
+pub mod gltf_loader;
 pub mod uniform_buffer;
 pub mod quad_render;
 pub mod mesh_render;
@@ -9,3 +13,20 @@ pub mod skybox_render;
 pub mod cube_texture;
 pub mod heightmap_terrain;
 pub mod mesh_shadowmap_render;
+pub mod scene_buffer;
+pub mod god_rays;
+pub mod gpu_culling;
+pub mod hi_z;
+pub mod occlusion_culling;
+pub mod instance_field;
+pub mod reflective_shadow_map;
+pub mod taa;
+pub mod decals;
+pub mod gbuffer;
+pub mod bloom;
+pub mod frame_passes;
+pub mod environment_map;
+pub mod particles;
+pub mod transparent_render;
+pub mod debug_draw;
+pub mod post_process;
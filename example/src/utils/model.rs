@@ -0,0 +1,89 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use cgmath::{Deg, Matrix4, Rad, SquareMatrix};
+
+use ash_render_env::env::RenderEnv;
+use ash_render_env::utils::ktx2::CompressedFormats;
+
+use crate::utils::gltf_loader;
+use crate::utils::material::{Material, MaterialData};
+use crate::utils::mesh::{Mesh, MeshData};
+
+// One draw call of a model: a glTF primitive or a whole OBJ file
+pub struct ModelPart {
+    pub mesh: Arc<Mesh>,
+    pub material: Arc<Material>,
+    // node transform relative to the model origin, parent nodes included
+    pub transform: Matrix4<f32>,
+}
+
+// Meshes of a model with their materials, drawn as a whole at every scene object position
+pub struct Model {
+    pub parts: Vec<ModelPart>,
+}
+
+pub struct PartData {
+    pub mesh: usize,
+    pub material: usize,
+    pub transform: Matrix4<f32>,
+}
+
+// Decoded on a loader thread. Parts refer to meshes and materials by index,
+// so nodes instancing the same glTF mesh share its buffers
+pub struct ModelData {
+    pub meshes: Vec<MeshData>,
+    pub materials: Vec<MaterialData>,
+    pub parts: Vec<PartData>,
+}
+
+impl ModelData {
+    // glTF (.gltf or binary .glb), anything else is an OBJ with textures next to it
+    pub fn load(path: &Path, formats: &CompressedFormats) -> Result<ModelData, String> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gltf") | Some("glb") => gltf_loader::load(path),
+            _ => {
+                // OBJ models of the demo are Z-up, glTF is Y-up
+                let base = Matrix4::<f32>::from_angle_x(Rad::from(Deg(90.0)));
+
+                Ok(ModelData {
+                    meshes: vec![MeshData::load(path)?],
+                    materials: vec![MaterialData::load(path, formats)?],
+                    parts: vec![PartData { mesh: 0, material: 0, transform: base }],
+                })
+            }
+        }
+    }
+}
+
+impl Model {
+    pub fn upload(env: &Arc<RenderEnv>, data: ModelData) -> Model {
+        let meshes: Vec<Arc<Mesh>> = data.meshes.into_iter()
+            .map(|mesh| Arc::new(Mesh::upload(env, mesh)))
+            .collect();
+        let materials: Vec<Arc<Material>> = data.materials.into_iter()
+            .map(|material| Arc::new(Material::upload(env, material)))
+            .collect();
+
+        let parts = data.parts.iter()
+            .map(|part| ModelPart {
+                mesh: meshes[part.mesh].clone(),
+                material: materials[part.material].clone(),
+                transform: part.transform,
+            })
+            .collect();
+
+        Model { parts }
+    }
+
+    // Draws nothing, stands in until the model is loaded
+    pub fn placeholder(env: &Arc<RenderEnv>) -> Model {
+        Model {
+            parts: vec![ModelPart {
+                mesh: Arc::new(Mesh::upload(env, MeshData::placeholder())),
+                material: Arc::new(Material::placeholder(env)),
+                transform: Matrix4::identity(),
+            }],
+        }
+    }
+}
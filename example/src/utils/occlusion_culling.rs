@@ -0,0 +1,270 @@
+use std::sync::Arc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use ash_render_env::culling::{Aabb, CullStats};
+use ash_render_env::descriptor_set::DescriptorSet;
+use ash_render_env::env::RenderEnv;
+use ash_render_env::memory_tracker;
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::pipeline_builder::{ComputePipelineBuilder, Pipeline};
+use ash_render_env::shader;
+use ash_render_env::utils::buffer_utils::create_buffer;
+use ash_render_env::utils::DepthMode;
+
+use crate::utils::hi_z::HiZPyramid;
+
+// Must match local_size_x of occlusion_cull.comp
+const WORKGROUP_SIZE: u32 = 64;
+
+#[repr(C)]
+struct OcclusionConstants {
+    // of the depth in the pyramid
+    view_proj: [[f32; 4]; 4],
+    // x - draw count, y - levels of the pyramid
+    counts: [u32; 4],
+    // xy - size of the depth the pyramid was built from
+    depth_size: [f32; 4],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DrawBounds {
+    min: [f32; 4],
+    max: [f32; 4],
+}
+
+// Resources of one frame in flight
+struct OcclusionFrame {
+    // world bounds of every draw, grows with the draw count
+    bounds_buffer: (vk::Buffer, vk::DeviceMemory),
+    bounds_capacity: usize,
+    // occluded draw count, host visible to read it back
+    stats_buffer: (vk::Buffer, vk::DeviceMemory),
+    // with the draw buffer and the pyramid view it was written with, rebuilt when either changes
+    descriptor_set: Option<(DescriptorSet, vk::Buffer, vk::ImageView)>,
+    command: vk::CommandBuffer,
+    // draws tested by the last recorded pass
+    tested: usize,
+}
+
+// Occlusion culling of indirect draws against the Hi-Z pyramid of the last frame, on GPU.
+// A compute pass sets the instance count of draws whose world box is hidden behind the last frame depth to zero,
+// draws already culled by the frustum are skipped. Objects coming out from behind an occluder show up one frame late
+pub struct OcclusionCulling {
+    pub enabled: bool,
+    pipeline: Pipeline,
+    frames: Vec<OcclusionFrame>,
+    stats: CullStats,
+
+    env: Arc<RenderEnv>,
+}
+
+impl OcclusionCulling {
+    pub fn new(env: Arc<RenderEnv>, depth_mode: DepthMode, max_inflight_frames: usize) -> OcclusionCulling {
+        let comp_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/culling/occlusion_cull.comp.spv").expect("Failed to load shader!")
+            .specialize(shader::ConstantsBuilder::new()
+                .add_u32(depth_mode.is_reversed() as u32));
+        let pipeline = ComputePipelineBuilder::new(env.device().clone(), comp_shader_module)
+            .pipeline_cache(env.pipeline_cache())
+            .build()
+            .expect("Failed to create pipeline!");
+
+        let frames = (0..max_inflight_frames)
+            .map(|_| {
+                let stats_buffer = create_buffer(
+                    env.device(),
+                    std::mem::size_of::<u32>() as u64,
+                    vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+                    vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                    &env.mem_properties,
+                );
+
+                OcclusionFrame {
+                    bounds_buffer: Self::create_bounds_buffer(&env, 1),
+                    bounds_capacity: 1,
+                    stats_buffer,
+                    descriptor_set: None,
+                    command: env.create_primary_command_buffer(),
+                    tested: 0,
+                }
+            })
+            .collect();
+
+        OcclusionCulling {
+            enabled: true,
+            pipeline,
+            frames,
+            stats: CullStats::default(),
+            env,
+        }
+    }
+
+    fn create_bounds_buffer(env: &RenderEnv, capacity: usize) -> (vk::Buffer, vk::DeviceMemory) {
+        create_buffer(
+            env.device(),
+            (std::mem::size_of::<DrawBounds>() * capacity) as u64,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            &env.mem_properties,
+        )
+    }
+
+    fn destroy_buffer(env: &RenderEnv, (buffer, memory): (vk::Buffer, vk::DeviceMemory)) {
+        unsafe {
+            env.device().destroy_buffer(buffer, None);
+            object_tracker::destroyed(ObjectKind::Buffer);
+            env.device().free_memory(memory, None);
+            memory_tracker::freed(memory);
+        }
+    }
+
+    // Occluded draws of the last finished frame, of the draws tested in it
+    pub fn stats(&self) -> CullStats {
+        self.stats
+    }
+
+    fn read_stats(&mut self, frame: usize) {
+        let occlusion_frame = &self.frames[frame];
+        let memory = occlusion_frame.stats_buffer.1;
+        let occluded = unsafe {
+            let data_ptr = self.env.device()
+                .map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())
+                .expect("Failed to Map Memory") as *const u32;
+            let occluded = data_ptr.read();
+            self.env.device().unmap_memory(memory);
+            occluded
+        };
+
+        self.stats = CullStats {
+            total: occlusion_frame.tested,
+            culled: if occlusion_frame.tested > 0 { occluded as usize } else { 0 },
+        };
+    }
+
+    // Primary command buffer of the culling pass, must be submitted after the host wrote `draw_buffer`
+    // and before the pass drawing from it. `bounds` - world bounds of the first draws, the rest are never occluded.
+    // None when disabled or the pyramid is empty. Resources of `frame` must not be in use by GPU
+    pub fn cull(&mut self, frame: usize, draw_buffer: vk::Buffer, draw_count: usize, bounds: &[Aabb], hi_z: &HiZPyramid) -> Option<vk::CommandBuffer> {
+        // the buffers of the frame were used the last time max_inflight_frames ago
+        self.read_stats(frame);
+        self.frames[frame].tested = 0;
+
+        let view_proj = hi_z.view_proj()?;
+        if !self.enabled || draw_count == 0 {
+            return None;
+        }
+
+        let env = self.env.clone();
+        let occlusion_frame = &mut self.frames[frame];
+        if occlusion_frame.bounds_capacity < draw_count {
+            let capacity = draw_count.next_power_of_two();
+            Self::destroy_buffer(&env, std::mem::replace(&mut occlusion_frame.bounds_buffer, Self::create_bounds_buffer(&env, capacity)));
+            occlusion_frame.bounds_capacity = capacity;
+            occlusion_frame.descriptor_set = None;
+        }
+
+        let draw_bounds: Vec<_> = (0..draw_count)
+            .map(|idx| match bounds.get(idx) {
+                Some(aabb) => DrawBounds {
+                    min: [aabb.min.x, aabb.min.y, aabb.min.z, 1.0],
+                    max: [aabb.max.x, aabb.max.y, aabb.max.z, 1.0],
+                },
+                // empty box
+                None => DrawBounds { min: [1.0; 4], max: [-1.0; 4] },
+            })
+            .collect();
+        unsafe {
+            let memory = occlusion_frame.bounds_buffer.1;
+            let size = std::mem::size_of_val(draw_bounds.as_slice()) as u64;
+            let data_ptr = env.device()
+                .map_memory(memory, 0, size, vk::MemoryMapFlags::empty())
+                .expect("Failed to Map Memory") as *mut DrawBounds;
+
+            data_ptr.copy_from_nonoverlapping(draw_bounds.as_ptr(), draw_bounds.len());
+
+            env.device().unmap_memory(memory);
+        }
+
+        let up_to_date = matches!(&occlusion_frame.descriptor_set, Some((_, buffer, view)) if *buffer == draw_buffer && *view == hi_z.view());
+        if !up_to_date {
+            let descriptor_set = DescriptorSet::builder(env.device(), &self.pipeline.descriptor_set_layouts[0])
+                .add_storage_buffer(occlusion_frame.bounds_buffer.0)
+                .add_storage_buffer(draw_buffer)
+                .add_image_with_layout(hi_z.view(), hi_z.sampler(), vk::ImageLayout::GENERAL)
+                .add_storage_buffer(occlusion_frame.stats_buffer.0)
+                .build()
+                .expect("Failed to create descriptor set!");
+            occlusion_frame.descriptor_set = Some((descriptor_set, draw_buffer, hi_z.view()));
+        }
+        occlusion_frame.tested = draw_count;
+
+        let depth_dimensions = hi_z.depth_dimensions();
+        let constants = OcclusionConstants {
+            view_proj: view_proj.into(),
+            counts: [draw_count as u32, hi_z.level_count() as u32, 0, 0],
+            depth_size: [depth_dimensions[0] as f32, depth_dimensions[1] as f32, 0.0, 0.0],
+        };
+        let constants_bytes = unsafe {
+            std::slice::from_raw_parts(&constants as *const OcclusionConstants as *const u8, std::mem::size_of::<OcclusionConstants>())
+        };
+
+        let device = env.device();
+        let cmd = occlusion_frame.command;
+        let descriptor_set = &occlusion_frame.descriptor_set.as_ref().unwrap().0;
+
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+
+        unsafe {
+            device.reset_command_buffer(cmd, vk::CommandBufferResetFlags::default())
+                .expect("Failed to reset Command Buffer!");
+            device.begin_command_buffer(cmd, &command_buffer_begin_info)
+                .expect("Failed to begin recording Command Buffer at beginning!");
+
+            device.cmd_fill_buffer(cmd, occlusion_frame.stats_buffer.0, 0, vk::WHOLE_SIZE, 0);
+            Self::memory_barrier(device, cmd,
+                                 vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE,
+                                 vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.pipeline.graphics_pipeline);
+            descriptor_set.bind(cmd, vk::PipelineBindPoint::COMPUTE, self.pipeline.pipeline_layout, 0, &[]);
+            device.cmd_push_constants(cmd, self.pipeline.pipeline_layout, vk::ShaderStageFlags::COMPUTE, 0, constants_bytes);
+            device.cmd_dispatch(cmd, (draw_count as u32).div_ceil(WORKGROUP_SIZE), 1, 1);
+
+            Self::memory_barrier(device, cmd,
+                                 vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE,
+                                 vk::PipelineStageFlags::DRAW_INDIRECT | vk::PipelineStageFlags::HOST,
+                                 vk::AccessFlags::INDIRECT_COMMAND_READ | vk::AccessFlags::HOST_READ);
+
+            device.end_command_buffer(cmd)
+                .expect("Failed to record Command Buffer at Ending!");
+        }
+
+        Some(cmd)
+    }
+
+    unsafe fn memory_barrier(device: &ash::Device, cmd: vk::CommandBuffer,
+                             src_stage: vk::PipelineStageFlags, src_access: vk::AccessFlags,
+                             dst_stage: vk::PipelineStageFlags, dst_access: vk::AccessFlags) {
+        let barrier = vk::MemoryBarrier::builder()
+            .src_access_mask(src_access)
+            .dst_access_mask(dst_access)
+            .build();
+
+        device.cmd_pipeline_barrier(cmd, src_stage, dst_stage, vk::DependencyFlags::empty(), &[barrier], &[], &[]);
+    }
+}
+
+impl Drop for OcclusionCulling {
+    fn drop(&mut self) {
+        for frame in self.frames.iter() {
+            unsafe {
+                self.env.device().free_command_buffers(self.env.command_pool(), &[frame.command]);
+            }
+            Self::destroy_buffer(&self.env, frame.bounds_buffer);
+            Self::destroy_buffer(&self.env, frame.stats_buffer);
+        }
+    }
+}
@@ -0,0 +1,481 @@
+use std::ptr;
+use std::sync::Arc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+use cgmath::{InnerSpace, Matrix4, Point3, Vector3};
+
+use ash_render_env::camera::CameraDescriptor;
+use ash_render_env::descriptor_set::DescriptorSet;
+use ash_render_env::env::RenderEnv;
+use ash_render_env::frame_buffer::Framebuffer;
+use ash_render_env::memory_tracker;
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::pipeline_builder::{ComputePipelineBuilder, Pipeline, PipelineBuilder};
+use ash_render_env::shader;
+use ash_render_env::std140_struct;
+use ash_render_env::uniform_buffer::UniformBuffer;
+use ash_render_env::utils::buffer_utils::{begin_single_time_command, create_buffer, end_single_time_command};
+
+use crate::utils::gbuffer::reader_shader_path;
+
+// Size of the particle buffer, emission stops while all of them are alive
+pub const MAX_PARTICLES: u32 = 16384;
+
+// Must match local_size_x of particles_update.comp
+const WORKGROUP_SIZE: u32 = 64;
+
+// Particle in particles_update.comp: position + remaining life, velocity + lifetime
+const PARTICLE_SIZE: u64 = 32;
+
+// VkDrawIndirectCommand, spawn counter and padding before the list of alive particles
+const DRAW_LIST_HEADER: [u32; 8] = [6, 0, 0, 0, 0, 0, 0, 0];
+
+#[repr(C)]
+struct EmitterConstants {
+    // xyz - emitter position, w - particles to spawn this frame
+    position: [f32; 4],
+    // xyz - emission direction, w - initial speed
+    direction: [f32; 4],
+    // x - lifetime, y - cone half angle in radians, z - time step, w - time
+    params: [f32; 4],
+    // xyz - acceleration, w - particle count
+    gravity: [f32; 4],
+}
+
+std140_struct! {
+    struct VertexUniforms {
+        view: Matrix4<f32>,
+        proj: Matrix4<f32>,
+        // x - size at spawn, y - size at death
+        size: [f32; 4],
+        start_color: [f32; 4],
+        end_color: [f32; 4],
+    }
+}
+
+std140_struct! {
+    struct FragmentUniforms {
+        inv_view_proj: Matrix4<f32>,
+        view: Matrix4<f32>,
+        // x - fade distance, y - exposure
+        params: [f32; 4],
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct EmitterSettings {
+    pub position: Point3<f32>,
+    pub direction: Vector3<f32>,
+    // particles per second
+    pub rate: f32,
+    // seconds, every particle lives 50..100% of it
+    pub lifetime: f32,
+    // half angle of the emission cone, degrees
+    pub spread: f32,
+    // meters per second, 75..100% of it
+    pub speed: f32,
+    pub gravity: Vector3<f32>,
+    // billboard size in meters at spawn and at death
+    pub size: [f32; 2],
+    // linear color and opacity at spawn and at death
+    pub start_color: [f32; 4],
+    pub end_color: [f32; 4],
+    // particles fade out over this distance in front of the geometry
+    pub softness: f32,
+}
+
+impl EmitterSettings {
+    pub fn new() -> EmitterSettings {
+        EmitterSettings {
+            position: Point3::new(0.0, 1.0, -6.0),
+            direction: Vector3::unit_y(),
+            rate: 400.0,
+            lifetime: 4.0,
+            spread: 20.0,
+            speed: 1.5,
+            gravity: Vector3::new(0.0, 0.3, 0.0),
+            size: [0.15, 0.6],
+            start_color: [1.0, 0.7, 0.4, 0.8],
+            end_color: [0.3, 0.3, 0.3, 0.0],
+            softness: 0.5,
+        }
+    }
+}
+
+// Draw state of one frame in flight
+struct DrawFrame {
+    vertex_uniforms: UniformBuffer<VertexUniforms>,
+    fragment_uniforms: UniformBuffer<FragmentUniforms>,
+    descriptor_set: DescriptorSet,
+    second_buffer: vk::CommandBuffer,
+}
+
+// GPU driven particles of one emitter. A compute pass respawns and moves particles in a storage buffer
+// and builds the list of alive ones, the billboards are drawn from it with an indirect draw.
+// They are blended over the lit scene in the HDR pass, the G-buffer has no room for translucency
+pub struct ParticleSystem {
+    pub emitter: EmitterSettings,
+
+    particle_buffer: (vk::Buffer, vk::DeviceMemory),
+    // header with the indirect draw command, then indices of alive particles
+    draw_list_buffer: (vk::Buffer, vk::DeviceMemory),
+
+    update_pipeline: Pipeline,
+    update_descriptor_set: DescriptorSet,
+    update_commands: Vec<vk::CommandBuffer>,
+    // fraction of a particle left from the previous frames
+    spawn_remainder: f32,
+    time: f32,
+
+    draw_pipeline: Pipeline,
+    gbuffer_samples: vk::SampleCountFlags,
+    render_pass: vk::RenderPass,
+    sampler: vk::Sampler,
+    frames: Vec<DrawFrame>,
+
+    env: Arc<RenderEnv>,
+}
+
+impl ParticleSystem {
+    // `render_pass` is the HDR pass the particles are drawn in, `gbuffer` gives the depth to blend against
+    pub fn new(env: Arc<RenderEnv>, render_pass: vk::RenderPass, gbuffer: &Framebuffer, max_inflight_frames: usize) -> ParticleSystem {
+        let particle_buffer = create_buffer(
+            env.device(),
+            MAX_PARTICLES as u64 * PARTICLE_SIZE,
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            &env.mem_properties,
+        );
+        let draw_list_size = (DRAW_LIST_HEADER.len() as u64 + MAX_PARTICLES as u64) * 4;
+        let draw_list_buffer = create_buffer(
+            env.device(),
+            draw_list_size,
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::INDIRECT_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            &env.mem_properties,
+        );
+
+        // all particles start dead, the list is empty until the first update
+        let device = env.device();
+        let cmd = begin_single_time_command(device, env.command_pool());
+        unsafe {
+            device.cmd_fill_buffer(cmd, particle_buffer.0, 0, vk::WHOLE_SIZE, 0);
+            device.cmd_fill_buffer(cmd, draw_list_buffer.0, 0, vk::WHOLE_SIZE, 0);
+        }
+        end_single_time_command(device, env.command_pool(), env.queue(), cmd);
+
+        let update_pipeline = {
+            let comp_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/particles/particles_update.comp.spv").expect("Failed to load shader!");
+
+            ComputePipelineBuilder::new(env.device().clone(), comp_shader_module)
+                .pipeline_cache(env.pipeline_cache())
+                .build()
+                .expect("Failed to create pipeline!")
+        };
+
+        let update_descriptor_set = DescriptorSet::builder(env.device(), &update_pipeline.descriptor_set_layouts[0])
+            .add_storage_buffer(particle_buffer.0)
+            .add_storage_buffer(draw_list_buffer.0)
+            .build()
+            .expect("Failed to create descriptor set!");
+
+        let update_commands = (0..max_inflight_frames)
+            .map(|_| env.create_primary_command_buffer())
+            .collect();
+
+        let gbuffer_samples = gbuffer.attachments.last().unwrap().samples;
+        let draw_pipeline = Self::create_draw_pipeline(&env, render_pass, gbuffer);
+
+        let sampler_create_info = vk::SamplerCreateInfo::builder()
+            .min_filter(vk::Filter::NEAREST)
+            .mag_filter(vk::Filter::NEAREST)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+
+        let sampler = unsafe {
+            env.device().create_sampler(&sampler_create_info, None).unwrap()
+        };
+        object_tracker::created(ObjectKind::Sampler);
+
+        let frames = (0..max_inflight_frames)
+            .map(|_| {
+                let vertex_uniforms = UniformBuffer::new(env.clone());
+                let fragment_uniforms = UniformBuffer::new(env.clone());
+                let descriptor_set = Self::create_draw_descriptor_set(&env, &draw_pipeline, [particle_buffer.0, draw_list_buffer.0],
+                                                                      &vertex_uniforms, &fragment_uniforms, gbuffer, sampler);
+                let second_buffer = Self::build_cmd_buf(&env, &draw_pipeline, &descriptor_set, draw_list_buffer.0, render_pass, gbuffer.dimensions());
+
+                DrawFrame {
+                    vertex_uniforms,
+                    fragment_uniforms,
+                    descriptor_set,
+                    second_buffer,
+                }
+            })
+            .collect();
+
+        ParticleSystem {
+            emitter: EmitterSettings::new(),
+            particle_buffer,
+            draw_list_buffer,
+            update_pipeline,
+            update_descriptor_set,
+            update_commands,
+            spawn_remainder: 0.0,
+            time: 0.0,
+            draw_pipeline,
+            gbuffer_samples,
+            render_pass,
+            sampler,
+            frames,
+            env,
+        }
+    }
+
+    // `storage_buffers` - particles and the draw list
+    fn create_draw_descriptor_set(env: &RenderEnv, pipeline: &Pipeline, storage_buffers: [vk::Buffer; 2],
+                                  vertex_uniforms: &UniformBuffer<VertexUniforms>, fragment_uniforms: &UniformBuffer<FragmentUniforms>,
+                                  gbuffer: &Framebuffer, sampler: vk::Sampler) -> DescriptorSet {
+        DescriptorSet::builder(env.device(), &pipeline.descriptor_set_layouts[0])
+            .add_storage_buffer(storage_buffers[0])
+            .add_storage_buffer(storage_buffers[1])
+            .add_buffer(vertex_uniforms.buffer)
+            .add_attachment(gbuffer.attachments.last().unwrap(), sampler)
+            .add_buffer(fragment_uniforms.buffer)
+            .build()
+            .expect("Failed to create descriptor set!")
+    }
+
+    fn build_cmd_buf(env: &RenderEnv, pipeline: &Pipeline, descriptor_set: &DescriptorSet, draw_list_buffer: vk::Buffer,
+                     render_pass: vk::RenderPass, dimensions: [u32; 2]) -> vk::CommandBuffer {
+        let device = env.device();
+        let cmd_buf = env.create_secondary_command_buffer();
+
+        let inheritance_info = vk::CommandBufferInheritanceInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_INHERITANCE_INFO,
+            p_next: ptr::null(),
+            render_pass,
+            subpass: 0,
+            framebuffer: vk::Framebuffer::null(),
+            occlusion_query_enable: 0,
+            query_flags: Default::default(),
+            pipeline_statistics: Default::default(),
+        };
+
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_BEGIN_INFO,
+            p_next: ptr::null(),
+            p_inheritance_info: &inheritance_info,
+            flags: vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE | vk::CommandBufferUsageFlags::SIMULTANEOUS_USE,
+        };
+
+        let viewports = [vk::Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: dimensions[0] as f32,
+            height: dimensions[1] as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        }];
+
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: vk::Extent2D {
+                width: dimensions[0],
+                height: dimensions[1],
+            },
+        }];
+
+        unsafe {
+            device
+                .begin_command_buffer(cmd_buf, &command_buffer_begin_info)
+                .expect("Failed to begin recording Command Buffer at beginning!");
+
+            device.cmd_set_viewport(cmd_buf, 0, viewports.as_ref());
+            device.cmd_set_scissor(cmd_buf, 0, scissors.as_ref());
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pipeline.graphics_pipeline);
+            descriptor_set.bind(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pipeline.pipeline_layout, 0, &[]);
+
+            // vertex and instance counts are written by the update pass
+            device.cmd_draw_indirect(cmd_buf, draw_list_buffer, 0, 1, std::mem::size_of::<vk::DrawIndirectCommand>() as u32);
+
+            device
+                .end_command_buffer(cmd_buf)
+                .expect("Failed to record Command Buffer at Ending!");
+        }
+
+        cmd_buf
+    }
+
+    fn create_draw_pipeline(env: &RenderEnv, render_pass: vk::RenderPass, gbuffer: &Framebuffer) -> Pipeline {
+        let gbuffer_samples = gbuffer.attachments.last().unwrap().samples;
+        let vert_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/particles/particles.vert.spv").expect("Failed to load shader!");
+        let frag_shader_module = shader::Shader::load(env.device(), &reader_shader_path("particles/particles", gbuffer_samples)).expect("Failed to load shader!")
+            .specialize(shader::ConstantsBuilder::new()
+                .add_u32(gbuffer.depth_mode().is_reversed() as u32));
+
+        PipelineBuilder::new(env.device().clone(), render_pass, 0)
+            .pipeline_cache(env.pipeline_cache())
+            .device_features(env.enabled_features())
+            .vertex_shader(vert_shader_module)
+            .fragment_shader(frag_shader_module)
+            .disable_culling()
+            .blend()
+            .build()
+            .expect("Failed to create pipeline!")
+    }
+
+    // Device must be idle
+    pub fn resize(&mut self, gbuffer: &Framebuffer) {
+        let gbuffer_samples = gbuffer.attachments.last().unwrap().samples;
+        if gbuffer_samples != self.gbuffer_samples {
+            self.draw_pipeline = Self::create_draw_pipeline(&self.env, self.render_pass, gbuffer);
+            self.gbuffer_samples = gbuffer_samples;
+        }
+
+        for frame in self.frames.iter_mut() {
+            frame.descriptor_set = Self::create_draw_descriptor_set(&self.env, &self.draw_pipeline, [self.particle_buffer.0, self.draw_list_buffer.0],
+                                                                    &frame.vertex_uniforms, &frame.fragment_uniforms, gbuffer, self.sampler);
+
+            unsafe {
+                self.env.device().free_command_buffers(self.env.command_pool(), &[frame.second_buffer]);
+            }
+            frame.second_buffer = Self::build_cmd_buf(&self.env, &self.draw_pipeline, &frame.descriptor_set, self.draw_list_buffer.0,
+                                                      self.render_pass, gbuffer.dimensions());
+        }
+    }
+
+    // Primary command buffer of the simulation step, must be submitted before the HDR pass of the frame.
+    // Resources of `frame` must not be in use by GPU
+    pub fn update(&mut self, frame: usize, dt: f32, camera: &CameraDescriptor, exposure: f32) -> vk::CommandBuffer {
+        let emitter = self.emitter;
+        self.time += dt;
+
+        self.spawn_remainder += emitter.rate.max(0.0) * dt;
+        let spawn_count = self.spawn_remainder.floor().min(MAX_PARTICLES as f32);
+        self.spawn_remainder = (self.spawn_remainder - spawn_count).min(1.0);
+
+        let direction = if emitter.direction.magnitude2() > 0.0 { emitter.direction.normalize() } else { Vector3::unit_y() };
+        let constants = EmitterConstants {
+            position: [emitter.position.x, emitter.position.y, emitter.position.z, spawn_count],
+            direction: [direction.x, direction.y, direction.z, emitter.speed],
+            params: [emitter.lifetime, emitter.spread.to_radians(), dt, self.time],
+            gravity: [emitter.gravity.x, emitter.gravity.y, emitter.gravity.z, MAX_PARTICLES as f32],
+        };
+
+        let draw_frame = &self.frames[frame];
+        draw_frame.vertex_uniforms.write_data(VertexUniforms {
+            view: camera.view,
+            proj: camera.proj,
+            size: [emitter.size[0], emitter.size[1], 0.0, 0.0],
+            start_color: emitter.start_color,
+            end_color: emitter.end_color,
+        });
+        draw_frame.fragment_uniforms.write_data(FragmentUniforms {
+            inv_view_proj: camera.inv_view_proj,
+            view: camera.view,
+            params: [emitter.softness, exposure, 0.0, 0.0],
+        });
+
+        let device = self.env.device();
+        let cmd = self.update_commands[frame];
+
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+
+        let header: Vec<u8> = DRAW_LIST_HEADER.iter().flat_map(|word| word.to_ne_bytes()).collect();
+        let constants_bytes = unsafe {
+            std::slice::from_raw_parts(&constants as *const EmitterConstants as *const u8, std::mem::size_of::<EmitterConstants>())
+        };
+
+        unsafe {
+            device.reset_command_buffer(cmd, vk::CommandBufferResetFlags::default())
+                .expect("Failed to reset Command Buffer!");
+            device.begin_command_buffer(cmd, &command_buffer_begin_info)
+                .expect("Failed to begin recording Command Buffer at beginning!");
+
+            // the previous frame may still draw from the buffers
+            Self::memory_barrier(device, cmd,
+                                 vk::PipelineStageFlags::DRAW_INDIRECT | vk::PipelineStageFlags::VERTEX_SHADER,
+                                 vk::AccessFlags::INDIRECT_COMMAND_READ | vk::AccessFlags::SHADER_READ,
+                                 vk::PipelineStageFlags::TRANSFER | vk::PipelineStageFlags::COMPUTE_SHADER,
+                                 vk::AccessFlags::TRANSFER_WRITE | vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+
+            device.cmd_update_buffer(cmd, self.draw_list_buffer.0, 0, &header);
+            Self::memory_barrier(device, cmd,
+                                 vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE,
+                                 vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.update_pipeline.graphics_pipeline);
+            self.update_descriptor_set.bind(cmd, vk::PipelineBindPoint::COMPUTE, self.update_pipeline.pipeline_layout, 0, &[]);
+            device.cmd_push_constants(cmd, self.update_pipeline.pipeline_layout, vk::ShaderStageFlags::COMPUTE, 0, constants_bytes);
+            device.cmd_dispatch(cmd, MAX_PARTICLES.div_ceil(WORKGROUP_SIZE), 1, 1);
+
+            Self::memory_barrier(device, cmd,
+                                 vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE,
+                                 vk::PipelineStageFlags::DRAW_INDIRECT | vk::PipelineStageFlags::VERTEX_SHADER,
+                                 vk::AccessFlags::INDIRECT_COMMAND_READ | vk::AccessFlags::SHADER_READ);
+
+            device.end_command_buffer(cmd)
+                .expect("Failed to record Command Buffer at Ending!");
+        }
+
+        cmd
+    }
+
+    unsafe fn memory_barrier(device: &ash::Device, cmd: vk::CommandBuffer,
+                             src_stage: vk::PipelineStageFlags, src_access: vk::AccessFlags,
+                             dst_stage: vk::PipelineStageFlags, dst_access: vk::AccessFlags) {
+        let barrier = vk::MemoryBarrier::builder()
+            .src_access_mask(src_access)
+            .dst_access_mask(dst_access)
+            .build();
+
+        device.cmd_pipeline_barrier(cmd, src_stage, dst_stage, vk::DependencyFlags::empty(), &[barrier], &[], &[]);
+    }
+
+    // Secondary command buffer for the HDR pass, after the composite
+    pub fn draw(&self, frame: usize) -> vk::CommandBuffer {
+        self.frames[frame].second_buffer
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        let emitter = &mut self.emitter;
+        ui.label("Particles");
+        ui.horizontal(|ui| {
+            ui.label("Emitter");
+            ui.add(egui::DragValue::new(&mut emitter.position.x).speed(0.05).prefix("x: "));
+            ui.add(egui::DragValue::new(&mut emitter.position.y).speed(0.05).prefix("y: "));
+            ui.add(egui::DragValue::new(&mut emitter.position.z).speed(0.05).prefix("z: "));
+        });
+        ui.add(egui::Slider::new(&mut emitter.rate, 0.0..=4000.0).text("Rate"));
+        ui.add(egui::Slider::new(&mut emitter.lifetime, 0.1..=10.0).text("Lifetime"));
+        ui.add(egui::Slider::new(&mut emitter.spread, 0.0..=180.0).text("Spread"));
+        ui.add(egui::Slider::new(&mut emitter.speed, 0.0..=10.0).text("Speed"));
+        ui.add(egui::Slider::new(&mut emitter.softness, 0.0..=2.0).text("Softness"));
+    }
+}
+
+impl Drop for ParticleSystem {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.env.device();
+            device.free_command_buffers(self.env.command_pool(), &self.update_commands);
+            for frame in self.frames.iter() {
+                device.free_command_buffers(self.env.command_pool(), &[frame.second_buffer]);
+            }
+
+            device.destroy_sampler(self.sampler, None);
+            object_tracker::destroyed(ObjectKind::Sampler);
+
+            for &(buffer, memory) in [self.particle_buffer, self.draw_list_buffer].iter() {
+                device.destroy_buffer(buffer, None);
+                object_tracker::destroyed(ObjectKind::Buffer);
+                device.free_memory(memory, None);
+                memory_tracker::freed(memory);
+            }
+        }
+    }
+}
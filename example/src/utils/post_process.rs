@@ -0,0 +1,401 @@
+use std::ops::RangeInclusive;
+use std::ptr;
+use std::sync::Arc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use ash_render_env::attachment_texture::AttachmentImage;
+use ash_render_env::descriptor_set::DescriptorSet;
+use ash_render_env::env::RenderEnv;
+use ash_render_env::error::{RenderError, RenderResult};
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::pipeline_builder::{Pipeline, PipelineBuilder};
+use ash_render_env::primary_cmd_buffer::PrimaryCommandBuffer;
+use ash_render_env::shader::Shader;
+
+use crate::utils::{frame_passes, render_pass};
+
+// Push constants of an effect shader, a shader may declare only the first part of them
+#[repr(C)]
+struct EffectConstants {
+    params: [f32; 4],
+    // xy - 1 / size, zw - size in pixels
+    texel: [f32; 4],
+}
+
+pub struct PostEffectDesc {
+    pub name: String,
+    // samples the previous image at binding 0, gets EffectConstants as push constants
+    pub fragment_shader: Shader,
+    pub params: [f32; 4],
+    // sliders of the ui, one per used param
+    pub sliders: Vec<(&'static str, RangeInclusive<f32>)>,
+    pub enabled: bool,
+}
+
+pub struct PostEffect {
+    pub name: String,
+    pub params: [f32; 4],
+    pub enabled: bool,
+    sliders: Vec<(&'static str, RangeInclusive<f32>)>,
+
+    pipeline: Pipeline,
+    push_constants_size: usize,
+    // a set reading each of the ping-pong targets
+    descriptor_sets: Vec<DescriptorSet>,
+    // per frame in flight, recorded in draw() with the current params
+    second_buffers: Vec<vk::CommandBuffer>,
+    // used when the effect is not the last one and draws into a target
+    draw_command: PrimaryCommandBuffer,
+}
+
+struct Target {
+    image: AttachmentImage,
+    framebuffer: vk::Framebuffer,
+}
+
+// Full-screen effects applied in order between the scene and the final pass.
+// The scene is drawn into one of two ping-pong targets, every enabled effect reads the previous one
+// and the last effect draws straight into the final pass (the swapchain or the editor viewport).
+// Targets have the format of the final pass, so its secondary buffers draw into them too
+pub struct PostProcessChain {
+    effects: Vec<PostEffect>,
+    targets: Vec<Target>,
+    scene_command: PrimaryCommandBuffer,
+
+    render_pass: vk::RenderPass,
+    format: vk::Format,
+    dimensions: [u32; 2],
+    sampler: vk::Sampler,
+    max_inflight_frames: usize,
+
+    env: Arc<RenderEnv>,
+}
+
+impl PostProcessChain {
+    pub fn new(env: Arc<RenderEnv>, format: vk::Format, dimensions: [u32; 2], max_inflight_frames: usize) -> PostProcessChain {
+        let render_pass = render_pass::create_color_render_pass(&env, format, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+
+        let sampler_create_info = vk::SamplerCreateInfo::builder()
+            .min_filter(vk::Filter::LINEAR)
+            .mag_filter(vk::Filter::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+
+        let sampler = unsafe {
+            env.device().create_sampler(&sampler_create_info, None).unwrap()
+        };
+        object_tracker::created(ObjectKind::Sampler);
+
+        let targets = (0..2)
+            .map(|_| Self::create_target(&env, render_pass, format, dimensions))
+            .collect();
+
+        let mut scene_command = PrimaryCommandBuffer::new(env.clone(), max_inflight_frames);
+        scene_command.set_name("post process scene pass");
+        scene_command.set_dimensions(dimensions);
+
+        PostProcessChain {
+            effects: vec![],
+            targets,
+            scene_command,
+            render_pass,
+            format,
+            dimensions,
+            sampler,
+            max_inflight_frames,
+            env,
+        }
+    }
+
+    fn create_target(env: &RenderEnv, render_pass: vk::RenderPass, format: vk::Format, dimensions: [u32; 2]) -> Target {
+        // written by the scene pass, read by the effects up to the final pass
+        let image = AttachmentImage::new_transient(
+            env,
+            dimensions,
+            format,
+            1,
+            vk::SampleCountFlags::TYPE_1,
+            vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+            frame_passes::range(frame_passes::POST_PROCESS, frame_passes::FINAL),
+        );
+
+        let views = [image.view];
+        let framebuffer_info = vk::FramebufferCreateInfo {
+            s_type: vk::StructureType::FRAMEBUFFER_CREATE_INFO,
+            p_next: ptr::null(),
+            flags: Default::default(),
+            render_pass,
+            attachment_count: views.len() as u32,
+            p_attachments: views.as_ptr(),
+            width: dimensions[0],
+            height: dimensions[1],
+            layers: 1,
+        };
+
+        let framebuffer = unsafe {
+            env.device().create_framebuffer(&framebuffer_info, None)
+                .expect("Failed to create post process framebuffer!")
+        };
+        object_tracker::created(ObjectKind::Framebuffer);
+
+        Target {
+            image,
+            framebuffer,
+        }
+    }
+
+    fn create_descriptor_sets(&self, pipeline: &Pipeline) -> RenderResult<Vec<DescriptorSet>> {
+        self.targets.iter()
+            .map(|target| DescriptorSet::builder(self.env.device(), &pipeline.descriptor_set_layouts[0])
+                .add_image(target.image.view, self.sampler)
+                .build())
+            .collect()
+    }
+
+    // Appends an effect to the end of the chain. Device must be idle
+    pub fn push(&mut self, desc: PostEffectDesc) -> RenderResult<()> {
+        let push_constants_size = desc.fragment_shader.push_constants_range.size as usize;
+        if push_constants_size > std::mem::size_of::<EffectConstants>() {
+            return Err(RenderError::InvalidPipeline(format!(
+                "push constants of post effect {} take {} bytes, at most {} are provided",
+                desc.name, push_constants_size, std::mem::size_of::<EffectConstants>())));
+        }
+
+        let vert_shader_module = Shader::load(self.env.device(), "assets/shaders/spv/compose.vert.spv")?;
+        let pipeline = PipelineBuilder::new(self.env.device().clone(), self.render_pass, 0)
+            .pipeline_cache(self.env.pipeline_cache())
+            .vertex_shader(vert_shader_module)
+            .fragment_shader(desc.fragment_shader)
+            .build()?;
+        if pipeline.descriptor_set_layouts.is_empty() {
+            return Err(RenderError::InvalidPipeline(format!("post effect {} doesn't sample the input image", desc.name)));
+        }
+
+        let descriptor_sets = self.create_descriptor_sets(&pipeline)?;
+        let second_buffers = (0..self.max_inflight_frames)
+            .map(|_| self.env.create_secondary_command_buffer())
+            .collect();
+
+        let mut draw_command = PrimaryCommandBuffer::new(self.env.clone(), self.max_inflight_frames);
+        draw_command.set_name(&format!("{} pass", desc.name));
+        draw_command.set_dimensions(self.dimensions);
+
+        self.effects.push(PostEffect {
+            name: desc.name,
+            params: desc.params,
+            enabled: desc.enabled,
+            sliders: desc.sliders,
+            pipeline,
+            push_constants_size,
+            descriptor_sets,
+            second_buffers,
+            draw_command,
+        });
+
+        Ok(())
+    }
+
+    // Enables or disables effects with the given name, e.g. FXAA following the anti-aliasing mode
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) {
+        for effect in self.effects.iter_mut().filter(|effect| effect.name == name) {
+            effect.enabled = enabled;
+        }
+    }
+
+    // Device must be idle
+    pub fn resize(&mut self, dimensions: [u32; 2]) {
+        if dimensions == self.dimensions {
+            return;
+        }
+
+        for target in self.targets.drain(..) {
+            unsafe {
+                self.env.device().destroy_framebuffer(target.framebuffer, None);
+            }
+            object_tracker::destroyed(ObjectKind::Framebuffer);
+        }
+        self.targets = (0..2)
+            .map(|_| Self::create_target(&self.env, self.render_pass, self.format, dimensions))
+            .collect();
+        self.dimensions = dimensions;
+        self.scene_command.set_dimensions(dimensions);
+
+        let mut effects = std::mem::take(&mut self.effects);
+        for effect in effects.iter_mut() {
+            effect.descriptor_sets = self.create_descriptor_sets(&effect.pipeline)
+                .expect("Failed to create descriptor set!");
+            effect.draw_command.set_dimensions(dimensions);
+        }
+        self.effects = effects;
+    }
+
+    // `scene` - secondary buffers drawing the image to process, recorded for the final pass.
+    // Returns primary buffers to submit before the final pass and secondary buffers for the final pass:
+    // the scene itself when no effect is enabled. Buffers of `frame` must not be in use by GPU
+    pub fn draw(&mut self, frame: usize, scene: &[vk::CommandBuffer]) -> (Vec<vk::CommandBuffer>, Vec<vk::CommandBuffer>) {
+        let enabled: Vec<usize> = (0..self.effects.len())
+            .filter(|&idx| self.effects[idx].enabled)
+            .collect();
+        if enabled.is_empty() {
+            return (vec![], scene.to_vec());
+        }
+
+        let clear_values = vec![vk::ClearValue {
+            color: vk::ClearColorValue {
+                float32: [0.0, 0.0, 0.0, 1.0],
+            },
+        }];
+
+        let mut draws = vec![self.scene_command.execute_secondary(clear_values.clone(), self.targets[0].framebuffer, self.render_pass, scene)];
+        let mut input = 0;
+
+        for (order, &idx) in enabled.iter().enumerate() {
+            let second_buffer = self.record_effect(idx, frame, input);
+            if order + 1 == enabled.len() {
+                return (draws, vec![second_buffer]);
+            }
+
+            let output = 1 - input;
+            let effect = &mut self.effects[idx];
+            draws.push(effect.draw_command.execute_secondary(clear_values.clone(), self.targets[output].framebuffer, self.render_pass, &[second_buffer]));
+            input = output;
+        }
+
+        unreachable!()
+    }
+
+    fn record_effect(&self, idx: usize, frame: usize, input: usize) -> vk::CommandBuffer {
+        let device = self.env.device();
+        let effect = &self.effects[idx];
+        let cmd_buf = effect.second_buffers[frame];
+        let dimensions = self.dimensions;
+
+        let inheritance_info = vk::CommandBufferInheritanceInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_INHERITANCE_INFO,
+            p_next: ptr::null(),
+            render_pass: self.render_pass,
+            subpass: 0,
+            framebuffer: vk::Framebuffer::null(),
+            occlusion_query_enable: 0,
+            query_flags: Default::default(),
+            pipeline_statistics: Default::default(),
+        };
+
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_BEGIN_INFO,
+            p_next: ptr::null(),
+            p_inheritance_info: &inheritance_info,
+            flags: vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE | vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT,
+        };
+
+        let viewports = [vk::Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: dimensions[0] as f32,
+            height: dimensions[1] as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        }];
+
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: vk::Extent2D {
+                width: dimensions[0],
+                height: dimensions[1],
+            },
+        }];
+
+        let constants = EffectConstants {
+            params: effect.params,
+            texel: [1.0 / dimensions[0] as f32, 1.0 / dimensions[1] as f32, dimensions[0] as f32, dimensions[1] as f32],
+        };
+        let constants_bytes = unsafe {
+            std::slice::from_raw_parts(&constants as *const EffectConstants as *const u8, effect.push_constants_size)
+        };
+
+        unsafe {
+            device.reset_command_buffer(cmd_buf, vk::CommandBufferResetFlags::default())
+                .expect("Failed to reset Command Buffer!");
+            device
+                .begin_command_buffer(cmd_buf, &command_buffer_begin_info)
+                .expect("Failed to begin recording Command Buffer at beginning!");
+
+            device.cmd_set_viewport(cmd_buf, 0, viewports.as_ref());
+            device.cmd_set_scissor(cmd_buf, 0, scissors.as_ref());
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, effect.pipeline.graphics_pipeline);
+            effect.descriptor_sets[input].bind(cmd_buf, vk::PipelineBindPoint::GRAPHICS, effect.pipeline.pipeline_layout, 0, &[]);
+            if !constants_bytes.is_empty() {
+                device.cmd_push_constants(cmd_buf, effect.pipeline.pipeline_layout, vk::ShaderStageFlags::FRAGMENT, 0, constants_bytes);
+            }
+
+            device.cmd_draw(cmd_buf, 3, 1, 0, 0);
+
+            device
+                .end_command_buffer(cmd_buf)
+                .expect("Failed to record Command Buffer at Ending!");
+        }
+
+        cmd_buf
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        for effect in self.effects.iter_mut() {
+            ui.checkbox(&mut effect.enabled, effect.name.as_str());
+            if !effect.enabled {
+                continue;
+            }
+
+            for (param, (name, range)) in effect.params.iter_mut().zip(effect.sliders.iter()) {
+                ui.add(egui::Slider::new(param, range.clone()).text(*name));
+            }
+        }
+    }
+}
+
+impl Drop for PostProcessChain {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.env.device();
+            for effect in self.effects.iter() {
+                device.free_command_buffers(self.env.command_pool(), &effect.second_buffers);
+            }
+            for target in self.targets.iter() {
+                device.destroy_framebuffer(target.framebuffer, None);
+                object_tracker::destroyed(ObjectKind::Framebuffer);
+            }
+            device.destroy_sampler(self.sampler, None);
+            object_tracker::destroyed(ObjectKind::Sampler);
+        }
+    }
+}
+
+fn load_effect(device: &ash::Device, name: &str, path: &str, params: [f32; 4],
+               sliders: Vec<(&'static str, RangeInclusive<f32>)>) -> PostEffectDesc {
+    PostEffectDesc {
+        name: name.to_string(),
+        fragment_shader: Shader::load(device, path).expect("Failed to load shader!"),
+        params,
+        sliders,
+        enabled: false,
+    }
+}
+
+// Name of the demo FXAA effect, the anti-aliasing mode switches it
+pub const FXAA: &str = "FXAA";
+
+// Effects of the demo in chain order, all disabled
+pub fn demo_effects(device: &ash::Device) -> Vec<PostEffectDesc> {
+    vec![
+        load_effect(device, FXAA, "assets/shaders/spv/post/fxaa.frag.spv", [0.75, 0.125, 0.0312, 0.0],
+                    vec![("Subpixel", 0.0..=1.0), ("Edge threshold", 0.06..=0.33), ("Dark threshold", 0.0..=0.1)]),
+        load_effect(device, "Sharpen", "assets/shaders/spv/post/sharpen.frag.spv", [0.5, 0.0, 0.0, 0.0],
+                    vec![("Strength", 0.0..=2.0)]),
+        load_effect(device, "Chromatic aberration", "assets/shaders/spv/post/chromatic_aberration.frag.spv", [2.0, 0.0, 0.0, 0.0],
+                    vec![("Offset", 0.0..=10.0)]),
+        load_effect(device, "Vignette", "assets/shaders/spv/post/vignette.frag.spv", [0.5, 0.4, 0.6, 0.0],
+                    vec![("Strength", 0.0..=1.0), ("Radius", 0.0..=1.0), ("Softness", 0.01..=1.0)]),
+    ]
+}
@@ -3,49 +3,107 @@ use std::sync::Arc;
 
 use ash::version::DeviceV1_0;
 use ash::vk;
-use cgmath::{Matrix4, SquareMatrix};
+use cgmath::{Matrix4, SquareMatrix, Vector3};
 
 use ash_render_env::{descriptor_set, pipeline_builder, shader};
+use ash_render_env::camera::CameraDescriptor;
 use ash_render_env::descriptor_set::{DescriptorSet, DescriptorSetBuilder};
 use ash_render_env::env::RenderEnv;
+use ash_render_env::object_tracker::{self, ObjectKind};
 use ash_render_env::frame_buffer::Framebuffer;
 use ash_render_env::pipeline_builder::{Pipeline, PipelineBuilder};
+use ash_render_env::std140_struct;
+use ash_render_env::uniform_buffer::UniformBuffer;
+
+use crate::shadow_map::{CASCADE_COUNT, CascadeInfo, PointLight};
+use crate::utils::environment_map::EnvironmentViews;
+use crate::utils::gbuffer::{reader_shader_path, GBufferLayout};
+use crate::utils::reflective_shadow_map::RsmViews;
+
+std140_struct! {
+    struct Uniforms {
+        cascade_splits: [f32; CASCADE_COUNT],
+        view: Matrix4<f32>,
+        cascade_vp: [Matrix4<f32>; CASCADE_COUNT],
+        point_light: [f32; 4],
+        point_light_params: [f32; 4],
+        water: [f32; 4],
+        weather: [f32; 4],
+        exposure: [f32; 4],
+        shadow_params: [f32; 4],
+        ambient_params: [f32; 4],
+        god_rays: [f32; 4],
+        inv_view_proj: Matrix4<f32>,
+        debug_view: [f32; 4],
+        rsm_view_proj: Matrix4<f32>,
+        indirect_params: [f32; 4],
+        sun: [f32; 4],
+        fog: [f32; 4],
+        fog_color: [f32; 4],
+    }
+}
+
+// What the composite shows: the lit scene or raw G-buffer contents of the first MSAA sample
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum GBufferView {
+    Lit,
+    Albedo,
+    Normal,
+    Position,
+    Depth,
+}
 
-use crate::shadow_map::uniform_buffer::UniformBuffer;
-use crate::shadow_map::{CASCADE_COUNT, CascadeInfo};
+impl GBufferView {
+    pub const ALL: [GBufferView; 5] = [GBufferView::Lit, GBufferView::Albedo, GBufferView::Normal, GBufferView::Position, GBufferView::Depth];
 
-#[repr(C)]
-struct Uniforms {
-    cascade_splits: [f32; CASCADE_COUNT],
-    view: Matrix4<f32>,
-    cascade_vp: [Matrix4<f32>; CASCADE_COUNT],
+    // debugView.x of compose.frag
+    fn shader_id(&self) -> f32 {
+        *self as u32 as f32
+    }
 }
 
 
+// Uniforms and light buffers are per frame in flight, `frame` of write_shadowmap_ubo and second_buffer
+// is the frame index whose fence was waited by the caller
 pub struct QuadRenderer {
     sampler: vk::Sampler,
     shadow_sampler: vk::Sampler,
-    descriptor_set: descriptor_set::DescriptorSet,
+    descriptor_sets: Vec<descriptor_set::DescriptorSet>,
     pipeline: pipeline_builder::Pipeline,
+    // G-buffer sample count the compose shader variant was built for
+    input_samples: vk::SampleCountFlags,
     pub render_pass: vk::RenderPass,
-    pub second_buffer: vk::CommandBuffer,
-    uniform_buffer: UniformBuffer<Uniforms>,
+    second_buffers: Vec<vk::CommandBuffer>,
+    uniform_buffers: Vec<UniformBuffer<Uniforms>>,
+    point_shadow_view: vk::ImageView,
+    light_buffers: Vec<vk::Buffer>,
+    water: [f32; 4],
+    weather: [f32; 4],
+    exposure: f32,
+    cascade_blend_band: f32,
+    pcf: [f32; 2],
+    show_cascades: bool,
+    sky_occlusion: f32,
+    environment: EnvironmentViews,
+    environment_intensity: f32,
+    gbuffer_view: GBufferView,
+    god_rays: [f32; 4],
+    rsm: RsmViews,
+    rsm_view_proj: Matrix4<f32>,
+    indirect_params: [f32; 4],
+    sun_direction: Vector3<f32>,
+    fog: [[f32; 4]; 2],
     env: Arc<RenderEnv>,
 }
 
 impl QuadRenderer {
-    pub fn new(env: Arc<RenderEnv>, framebuffer: &Framebuffer, shadow_map_view: vk::ImageView, render_pass: vk::RenderPass, input_samples: vk::SampleCountFlags, dimensions: [u32; 2]) -> QuadRenderer {
-        let pipeline = {
-            let vert_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/compose.vert.spv");
-            let frag_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/compose.frag.spv")
-                .specialize(shader::ConstantsBuilder::new().add_u32(input_samples.as_raw()));
-
-
-            PipelineBuilder::new(env.device().clone(), render_pass, 0)
-                .fragment_shader(frag_shader_module)
-                .vertex_shader(vert_shader_module)
-                .build()
-        };
+    // `shadow_map_view` - 2D array view with a layer per cascade, compose.frag picks the layer by view depth
+    pub fn new(env: Arc<RenderEnv>, framebuffer: &Framebuffer, shadow_map_view: vk::ImageView, point_shadow_view: vk::ImageView,
+               god_rays_view: vk::ImageView, light_buffers: Vec<vk::Buffer>, environment: EnvironmentViews, rsm: RsmViews,
+               render_pass: vk::RenderPass, max_inflight_frames: usize) -> QuadRenderer {
+        let input_samples = framebuffer.attachments[GBufferLayout::ALBEDO].samples;
+        let dimensions = framebuffer.dimensions();
+        let pipeline = Self::create_pipeline(&env, render_pass, input_samples);
 
         let sampler_create_info = vk::SamplerCreateInfo::builder()
             .min_filter(vk::Filter::LINEAR)
@@ -58,6 +116,7 @@ impl QuadRenderer {
         let sampler = unsafe {
             env.device().create_sampler(&sampler_create_info, None).unwrap()
         };
+        object_tracker::created(ObjectKind::Sampler);
 
         let sampler_create_info = vk::SamplerCreateInfo {
             s_type: vk::StructureType::SAMPLER_CREATE_INFO,
@@ -83,50 +142,157 @@ impl QuadRenderer {
         let shadow_sampler = unsafe {
             env.device().create_sampler(&sampler_create_info, None).unwrap()
         };
-
-
-        let uniform_buffer = UniformBuffer::new(env.clone());
-
-        let descriptor_set = DescriptorSetBuilder::new(
-            env.device(), pipeline.descriptor_set_layouts.get(0).unwrap())
-            .add_image(framebuffer.attachments.get(0).unwrap().view, sampler)
-            .add_image(framebuffer.attachments.get(1).unwrap().view, sampler)
-            .add_image(framebuffer.attachments.get(2).unwrap().view, sampler)
-            .add_image_with_layout(shadow_map_view, shadow_sampler.clone(), vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
-            .add_buffer(uniform_buffer.buffer)
-            .build();
-
-        let second_buffer = Self::render_quad(&env, dimensions, &pipeline, &descriptor_set, render_pass);
-
-        QuadRenderer {
+        object_tracker::created(ObjectKind::Sampler);
+
+        assert_eq!(light_buffers.len(), max_inflight_frames);
+        let uniform_buffers = (0..max_inflight_frames)
+            .map(|_| UniformBuffer::new(env.clone()))
+            .collect();
+
+        let mut renderer = QuadRenderer {
+            point_shadow_view,
+            light_buffers,
+            water: [0.0; 4],
+            weather: [0.0; 4],
+            exposure: 1.0,
+            cascade_blend_band: 0.0,
+            pcf: [0.75, 2.0],
+            show_cascades: false,
+            sky_occlusion: 1.0,
+            environment,
+            environment_intensity: 1.0,
+            gbuffer_view: GBufferView::Lit,
+            god_rays: [0.0; 4],
+            rsm,
+            rsm_view_proj: Matrix4::identity(),
+            indirect_params: [0.0; 4],
+            sun_direction: Vector3::new(0.0, 1.0, 0.0),
+            fog: [[0.0; 4]; 2],
             pipeline,
+            input_samples,
             render_pass,
             shadow_sampler,
 
             sampler,
-            descriptor_set,
-            second_buffer,
+            descriptor_sets: vec![],
+            second_buffers: vec![],
 
-            uniform_buffer,
+            uniform_buffers,
             env: env.clone(),
-        }
+        };
+        renderer.update_framebuffer(framebuffer, shadow_map_view, god_rays_view, dimensions);
+
+        renderer
+    }
+
+    pub fn second_buffer(&self, frame: usize) -> vk::CommandBuffer {
+        self.second_buffers[frame]
     }
 
-    pub fn write_shadowmap_ubo(&mut self, view: Matrix4<f32>, cascades: &Vec<CascadeInfo>) {
-        let mut cascade_splits = [0.0; CASCADE_COUNT];
+    pub fn write_shadowmap_ubo(&mut self, frame: usize, camera: &CameraDescriptor, cascades: &Vec<CascadeInfo>, point_light: &PointLight) {
+        // compose picks the cascade by comparing view depth to the splits before the last one, a split
+        // no depth is beyond keeps it off the missing cascades
+        let mut cascade_splits = [f32::MIN; CASCADE_COUNT];
         let mut cascade_vp = [Matrix4::<f32>::identity(); CASCADE_COUNT];
 
-        for (idx, cascade) in cascades.iter().enumerate() {
+        for (idx, cascade) in cascades.iter().enumerate().take(cascades.len() - 1) {
             cascade_splits[idx] = cascade.max_z;
+        }
+        for (idx, cascade) in cascades.iter().enumerate() {
             cascade_vp[idx] = cascade.view_proj_mat;
         }
 
-        self.uniform_buffer.write_data(Uniforms {
-            view,
+        self.uniform_buffers[frame].write_data(Uniforms {
+            view: camera.view,
             cascade_vp,
-            cascade_splits
+            cascade_splits,
+            point_light: point_light.position_radius(),
+            point_light_params: [point_light.near, point_light.intensity, 0.0, 0.0],
+            water: self.water,
+            weather: self.weather,
+            exposure: [self.exposure, 0.0, 0.0, 0.0],
+            shadow_params: [self.cascade_blend_band, self.pcf[0], self.pcf[1], self.show_cascades as u32 as f32],
+            ambient_params: [self.sky_occlusion, self.environment_intensity, self.environment.specular_max_lod, 0.0],
+            god_rays: self.god_rays,
+            inv_view_proj: camera.inv_view_proj,
+            debug_view: [self.gbuffer_view.shader_id(), 0.0, 0.0, 0.0],
+            rsm_view_proj: self.rsm_view_proj,
+            indirect_params: self.indirect_params,
+            sun: self.sun_direction.extend(0.0).into(),
+            fog: self.fog[0],
+            fog_color: self.fog[1],
         })
     }
+
+    // Caustics are projected onto everything below the water plane (greater Y), `time` animates them
+    pub fn set_water(&mut self, level: f32, caustics_strength: f32, time: f32) {
+        self.water = [level, caustics_strength, time, 0.0];
+    }
+
+    // `rain` drives screen rain streaks, `wetness` darkens albedo and adds wet specular
+    pub fn set_weather(&mut self, rain: f32, wetness: f32) {
+        self.weather = [rain, wetness, 0.0, 0.0];
+    }
+
+    pub fn set_exposure(&mut self, exposure: f32) {
+        self.exposure = exposure;
+    }
+
+    // Depth range in view space before the split where neighbour cascades are blended, 0 disables blending
+    pub fn set_cascade_blend_band(&mut self, band: f32) {
+        self.cascade_blend_band = band;
+    }
+
+    // ShadowSettings::pcf_params
+    pub fn set_pcf(&mut self, params: [f32; 2]) {
+        self.pcf = params;
+    }
+
+    // Tints lit surfaces with the color of their shadow cascade
+    pub fn set_show_cascades(&mut self, show: bool) {
+        self.show_cascades = show;
+    }
+
+    // How much terrain sky visibility (normal.b of G-buffer) darkens ambient light, 0 - not at all
+    pub fn set_sky_occlusion(&mut self, strength: f32) {
+        self.sky_occlusion = strength;
+    }
+
+    // Scale of the image based ambient light of PBR surfaces
+    pub fn set_environment_intensity(&mut self, intensity: f32) {
+        self.environment_intensity = intensity;
+    }
+
+    // Maps of a new environment, bound by the next update_framebuffer
+    pub fn set_environment(&mut self, environment: EnvironmentViews) {
+        self.environment = environment;
+    }
+
+    pub fn set_gbuffer_view(&mut self, view: GBufferView) {
+        self.gbuffer_view = view;
+    }
+
+    // Parameters returned by GodRays::update
+    pub fn set_god_rays(&mut self, params: [f32; 4]) {
+        self.god_rays = params;
+    }
+
+    // Parameters returned by ReflectiveShadowMap::update
+    pub fn set_indirect_light(&mut self, rsm_view_proj: Matrix4<f32>, params: [f32; 4]) {
+        self.rsm_view_proj = rsm_view_proj;
+        self.indirect_params = params;
+    }
+
+    // Atmosphere::sun_direction, lights the scene and the fog
+    pub fn set_sun_direction(&mut self, direction: Vector3<f32>) {
+        self.sun_direction = direction;
+    }
+
+    // Atmosphere::fog_params
+    pub fn set_fog(&mut self, params: [[f32; 4]; 2]) {
+        self.fog = params;
+    }
+
     fn render_quad(env: &RenderEnv, dimensions: [u32; 2], pipeline: &Pipeline, descriptor_set: &DescriptorSet, render_pass: vk::RenderPass) -> vk::CommandBuffer {
         let device = env.device();
         let create_info = vk::CommandBufferAllocateInfo {
@@ -208,25 +374,77 @@ impl QuadRenderer {
         cmd_buf
     }
 
-    pub fn update_framebuffer(&mut self, framebuffer: &Framebuffer, shadow_map_view: vk::ImageView, dimensions: [u32; 2]) {
-        self.descriptor_set = DescriptorSetBuilder::new(
-            self.env.device(), self.pipeline.descriptor_set_layouts.get(0).unwrap())
-            .add_image(framebuffer.attachments.get(0).unwrap().view, self.sampler)
-            .add_image(framebuffer.attachments.get(1).unwrap().view, self.sampler)
-            .add_image(framebuffer.attachments.get(2).unwrap().view, self.sampler)
-            .add_image_with_layout(shadow_map_view, self.shadow_sampler, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
-            .add_buffer(self.uniform_buffer.buffer)
-            .build();
+    fn create_pipeline(env: &RenderEnv, render_pass: vk::RenderPass, input_samples: vk::SampleCountFlags) -> Pipeline {
+        let vert_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/compose.vert.spv").expect("Failed to load shader!");
+        let frag_shader_module = shader::Shader::load(env.device(), &reader_shader_path("compose", input_samples)).expect("Failed to load shader!")
+            .specialize(shader::ConstantsBuilder::new().add_u32(input_samples.as_raw()));
+
+        PipelineBuilder::new(env.device().clone(), render_pass, 0)
+            .pipeline_cache(env.pipeline_cache())
+            .fragment_shader(frag_shader_module)
+            .vertex_shader(vert_shader_module)
+            .build()
+            .expect("Failed to create pipeline!")
+    }
+
+    // None of the frames may be in flight
+    pub fn update_framebuffer(&mut self, framebuffer: &Framebuffer, shadow_map_view: vk::ImageView, god_rays_view: vk::ImageView, dimensions: [u32; 2]) {
+        self.free_second_buffers();
+
+        let input_samples = framebuffer.attachments[GBufferLayout::ALBEDO].samples;
+        if input_samples != self.input_samples {
+            self.pipeline = Self::create_pipeline(&self.env, self.render_pass, input_samples);
+            self.input_samples = input_samples;
+        }
 
-        self.second_buffer = Self::render_quad(&self.env, dimensions, &self.pipeline, &self.descriptor_set, self.render_pass);
+        self.descriptor_sets = self.uniform_buffers.iter().zip(self.light_buffers.iter())
+            .map(|(uniform_buffer, light_buffer)| {
+                DescriptorSetBuilder::new(
+                    self.env.device(), self.pipeline.descriptor_set_layouts.get(0).unwrap())
+                    .add_attachment(&framebuffer.attachments[GBufferLayout::ALBEDO], self.sampler)
+                    .add_attachment(&framebuffer.attachments[GBufferLayout::DEPTH], self.sampler)
+                    .add_attachment(&framebuffer.attachments[GBufferLayout::NORMAL], self.sampler)
+                    .add_image_array_with_layout(shadow_map_view, self.shadow_sampler, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+                    .add_buffer(uniform_buffer.buffer)
+                    .add_image_with_layout(self.point_shadow_view, self.shadow_sampler, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+                    .add_attachment(&framebuffer.attachments[GBufferLayout::MATERIAL], self.sampler)
+                    .add_image(god_rays_view, self.sampler)
+                    .add_storage_buffer(*light_buffer)
+                    .add_image(self.environment.irradiance, self.environment.sampler)
+                    .add_image(self.environment.specular, self.environment.specular_sampler)
+                    .add_image(self.environment.brdf_lut, self.environment.sampler)
+                    .add_image(self.rsm.flux, self.rsm.sampler)
+                    .add_image(self.rsm.normal, self.rsm.sampler)
+                    .add_image(self.rsm.position, self.rsm.sampler)
+                    .build()
+                    .expect("Failed to create descriptor set!")
+            })
+            .collect();
+
+        self.second_buffers = self.descriptor_sets.iter()
+            .map(|descriptor_set| Self::render_quad(&self.env, dimensions, &self.pipeline, descriptor_set, self.render_pass))
+            .collect();
+    }
+
+    fn free_second_buffers(&mut self) {
+        if !self.second_buffers.is_empty() {
+            unsafe {
+                self.env.device().free_command_buffers(self.env.command_pool(), &self.second_buffers);
+            }
+            self.second_buffers.clear();
+        }
     }
 }
 
 impl Drop for QuadRenderer {
     fn drop(&mut self) {
+        self.free_second_buffers();
+
         unsafe {
             self.env.device().destroy_sampler(self.sampler, None);
+            object_tracker::destroyed(ObjectKind::Sampler);
             self.env.device().destroy_sampler(self.shadow_sampler, None);
+            object_tracker::destroyed(ObjectKind::Sampler);
         }
     }
 }
@@ -0,0 +1,365 @@
+use std::ptr;
+use std::sync::Arc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+use cgmath::{InnerSpace, Matrix4, Point3, Vector3};
+
+use ash_render_env::camera::CameraDescriptor;
+use ash_render_env::descriptor_set::DescriptorSet;
+use ash_render_env::env::RenderEnv;
+use ash_render_env::frame_buffer::{AttachmentDesciption, Framebuffer};
+use ash_render_env::memory_tracker::MemoryCategory;
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::pipeline_builder::{Pipeline, PipelineBuilder};
+use ash_render_env::primary_cmd_buffer::PrimaryCommandBuffer;
+use ash_render_env::shader;
+use ash_render_env::std140_struct;
+use ash_render_env::uniform_buffer::UniformBuffer;
+
+use crate::utils::mesh;
+use crate::utils::model::Model;
+use crate::utils::scene_buffer::SceneBuffer;
+
+const RSM_SIZE: u32 = 256;
+const FLUX: usize = 0;
+const NORMAL: usize = 1;
+const POSITION: usize = 2;
+// depth range of the light projection, the camera is in the middle of it
+const DEPTH_RANGE: f32 = 200.0;
+
+std140_struct! {
+    struct Uniforms {
+        light_vp: Matrix4<f32>,
+        sun_direction: [f32; 4],
+    }
+}
+
+// Handles of the reflective shadow map for the composite pass, they don't change on resize
+#[derive(Copy, Clone)]
+pub struct RsmViews {
+    pub flux: vk::ImageView,
+    pub normal: vk::ImageView,
+    pub position: vk::ImageView,
+    // nearest filtering, zero outside of the map
+    pub sampler: vk::Sampler,
+}
+
+// Experimental one bounce global illumination from the sun. The model is drawn from the sun into a small
+// map of lit texels (reflected flux, world normal and position) around the camera, compose pass treats
+// the texels near a fragment as small area lights. Visibility between them is not checked, so light leaks.
+pub struct ReflectiveShadowMap {
+    pub enabled: bool,
+    pub strength: f32,
+    // half size of the covered square in world units
+    pub extent: f32,
+    // radius of the gathered disc in world units
+    pub radius: f32,
+    pub sample_count: u32,
+
+    framebuffer: Framebuffer,
+    pipeline: Pipeline,
+    sampler: vk::Sampler,
+    // descriptor_sets[frame][part]
+    descriptor_sets: Vec<Vec<DescriptorSet>>,
+    uniform_buffers: Vec<UniformBuffer<Uniforms>>,
+    second_buffers: Vec<vk::CommandBuffer>,
+    draw_command: PrimaryCommandBuffer,
+
+    model: Arc<Model>,
+    object_count: usize,
+
+    env: Arc<RenderEnv>,
+}
+
+impl ReflectiveShadowMap {
+    pub fn new(env: Arc<RenderEnv>, model: Arc<Model>, scene_buffer: &SceneBuffer) -> ReflectiveShadowMap {
+        let max_inflight_frames = scene_buffer.frame_count();
+        let dimensions = [RSM_SIZE, RSM_SIZE];
+
+        let mut framebuffer = Framebuffer::new(env.clone(), vec![
+            AttachmentDesciption {
+                format: vk::Format::R16G16B16A16_SFLOAT,
+                samples_count: vk::SampleCountFlags::TYPE_1,
+            },
+            AttachmentDesciption {
+                format: vk::Format::R16G16B16A16_SFLOAT,
+                samples_count: vk::SampleCountFlags::TYPE_1,
+            },
+            AttachmentDesciption {
+                format: vk::Format::R32G32B32A32_SFLOAT,
+                samples_count: vk::SampleCountFlags::TYPE_1,
+            },
+            AttachmentDesciption {
+                format: vk::Format::D32_SFLOAT,
+                samples_count: vk::SampleCountFlags::TYPE_1,
+            },
+        ]);
+        framebuffer.set_name("reflective shadow map");
+        framebuffer.set_memory_category(MemoryCategory::ShadowMaps);
+        framebuffer.resize_swapchain(dimensions);
+
+        let pipeline = {
+            let vert_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/reflective_shadow_map/rsm.vert.spv").expect("Failed to load shader!");
+            let frag_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/reflective_shadow_map/rsm.frag.spv").expect("Failed to load shader!");
+
+            PipelineBuilder::new(env.device().clone(), framebuffer.render_pass(), 0)
+                .pipeline_cache(env.pipeline_cache())
+                .vertex_shader(vert_shader_module)
+                .fragment_shader(frag_shader_module)
+                .vertex_input(mesh::Vertex::binding_descriptions(), mesh::Vertex::attribute_descriptions())
+                .with_depth_test()
+                .color_attachment_count(3)
+                .build()
+                .expect("Failed to create pipeline!")
+        };
+
+        let sampler_create_info = vk::SamplerCreateInfo::builder()
+            .min_filter(vk::Filter::NEAREST)
+            .mag_filter(vk::Filter::NEAREST)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+            .border_color(vk::BorderColor::FLOAT_TRANSPARENT_BLACK);
+
+        let sampler = unsafe {
+            env.device().create_sampler(&sampler_create_info, None).unwrap()
+        };
+        object_tracker::created(ObjectKind::Sampler);
+
+        let uniform_buffers = (0..max_inflight_frames)
+            .map(|_| UniformBuffer::new(env.clone()))
+            .collect();
+
+        let mut draw_command = PrimaryCommandBuffer::new(env.clone(), max_inflight_frames);
+        draw_command.set_name("reflective shadow map pass");
+        draw_command.set_dimensions(dimensions);
+
+        let mut rsm = ReflectiveShadowMap {
+            enabled: false,
+            strength: 1.0,
+            extent: 24.0,
+            radius: 4.0,
+            sample_count: 32,
+            framebuffer,
+            pipeline,
+            sampler,
+            descriptor_sets: vec![],
+            uniform_buffers,
+            second_buffers: vec![],
+            draw_command,
+            model,
+            object_count: 0,
+            env,
+        };
+        rsm.set_model(rsm.model.clone(), scene_buffer);
+
+        rsm
+    }
+
+    pub fn views(&self) -> RsmViews {
+        RsmViews {
+            flux: self.framebuffer.attachments[FLUX].view,
+            normal: self.framebuffer.attachments[NORMAL].view,
+            position: self.framebuffer.attachments[POSITION].view,
+            sampler: self.sampler,
+        }
+    }
+
+    // Command buffers must not be in use
+    pub fn set_model(&mut self, model: Arc<Model>, scene_buffer: &SceneBuffer) {
+        self.model = model;
+        self.descriptor_sets = self.uniform_buffers.iter().enumerate()
+            .map(|(frame, uniform_buffer)| {
+                self.model.parts.iter()
+                    .map(|part| {
+                        DescriptorSet::builder(self.env.device(), &self.pipeline.descriptor_set_layouts[0])
+                            .add_buffer(uniform_buffer.buffer)
+                            .add_storage_buffer(scene_buffer.buffer(frame))
+                            .add_texture(&part.material.albedo)
+                            .build()
+                            .expect("Failed to create descriptor set!")
+                    })
+                    .collect()
+            })
+            .collect();
+        self.record_cmd_bufs();
+    }
+
+    // Instances per model part, same scene buffer layout as in MeshRenderer. Command buffers must not be in use
+    pub fn set_object_count(&mut self, count: usize) {
+        self.object_count = count;
+        self.record_cmd_bufs();
+    }
+
+    pub fn draw_call_count(&self) -> usize {
+        if self.enabled { self.model.parts.len() } else { 0 }
+    }
+
+    fn record_cmd_bufs(&mut self) {
+        self.free_second_buffers();
+        self.second_buffers = (0..self.descriptor_sets.len())
+            .map(|frame| self.build_cmd_buf(frame))
+            .collect();
+    }
+
+    fn build_cmd_buf(&self, frame: usize) -> vk::CommandBuffer {
+        let device = self.env.device();
+        let command_buffer = self.env.create_secondary_command_buffer();
+
+        let inheritance_info = vk::CommandBufferInheritanceInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_INHERITANCE_INFO,
+            p_next: ptr::null(),
+            render_pass: self.framebuffer.render_pass(),
+            subpass: 0,
+            framebuffer: vk::Framebuffer::null(),
+            occlusion_query_enable: 0,
+            query_flags: Default::default(),
+            pipeline_statistics: Default::default(),
+        };
+
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_BEGIN_INFO,
+            p_next: ptr::null(),
+            p_inheritance_info: &inheritance_info,
+            flags: vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE | vk::CommandBufferUsageFlags::SIMULTANEOUS_USE,
+        };
+
+        let viewports = [vk::Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: RSM_SIZE as f32,
+            height: RSM_SIZE as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        }];
+
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: vk::Extent2D {
+                width: RSM_SIZE,
+                height: RSM_SIZE,
+            },
+        }];
+
+        unsafe {
+            device
+                .begin_command_buffer(command_buffer, &command_buffer_begin_info)
+                .expect("Failed to begin recording Command Buffer at beginning!");
+
+            device.cmd_set_viewport(command_buffer, 0, viewports.as_ref());
+            device.cmd_set_scissor(command_buffer, 0, scissors.as_ref());
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipeline.graphics_pipeline);
+
+            for (part_idx, part) in self.model.parts.iter().enumerate() {
+                self.descriptor_sets[frame][part_idx].bind(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipeline.pipeline_layout, 0, &[]);
+
+                let vertex_buffers = [part.mesh.vertex_buffer];
+                let offsets = [0_u64];
+                device.cmd_bind_vertex_buffers(command_buffer, 0, &vertex_buffers, &offsets);
+                device.cmd_bind_index_buffer(command_buffer, part.mesh.index_buffer, 0, vk::IndexType::UINT32);
+
+                let first_instance = (part_idx * self.object_count) as u32;
+                device.cmd_draw_indexed(command_buffer, part.mesh.index_count as u32, self.object_count as u32, 0, 0, first_instance);
+            }
+
+            device
+                .end_command_buffer(command_buffer)
+                .expect("Failed to record Command Buffer at Ending!");
+        }
+
+        command_buffer
+    }
+
+    // Sun projection over the square around the camera, moved in whole texels so the bounce light doesn't crawl
+    fn light_view_proj(&self, camera: &CameraDescriptor, light_dir: Vector3<f32>) -> Matrix4<f32> {
+        let texel = 2.0 * self.extent / RSM_SIZE as f32;
+        let up = Vector3::new(0.0, 1.0, 0.0);
+
+        // snapped in the light plane, the axes are the ones of look_at_rh
+        let side = light_dir.cross(up).normalize();
+        let light_up = side.cross(light_dir);
+        let position = Vector3::new(camera.position.x, camera.position.y, camera.position.z);
+        let center = side * (position.dot(side) / texel).floor() * texel
+            + light_up * (position.dot(light_up) / texel).floor() * texel
+            + light_dir * position.dot(light_dir);
+
+        let light_pos = center - light_dir * (DEPTH_RANGE / 2.0);
+        let view = Matrix4::look_at_rh(
+            Point3::new(light_pos.x, light_pos.y, light_pos.z),
+            Point3::new(center.x, center.y, center.z),
+            up,
+        );
+        let proj = cgmath::ortho(-self.extent, self.extent, -self.extent, self.extent, 0.0, DEPTH_RANGE);
+
+        // same depth range fix as of the shadow cascades
+        let corr_matrix = Matrix4::<f32>::new(
+            1.0, 0.0, 0.0, 0.0,
+            0.0, 1.0, 0.0, 0.0,
+            0.0, 0.0, 0.5, 0.0,
+            0.0, 0.0, 0.5, 1.0,
+        );
+
+        corr_matrix * proj * view
+    }
+
+    // Writes the light projection of `frame` and returns the compose pass parameters: the projection and
+    // x - strength (0 when disabled), y - gather radius in map uv, z - sample count, w - gather radius in world units.
+    // `sun_direction` - Atmosphere::sun_direction. Uniforms of `frame` must not be in use by GPU
+    pub fn update(&mut self, frame: usize, camera: &CameraDescriptor, sun_direction: Vector3<f32>) -> (Matrix4<f32>, [f32; 4]) {
+        let light_vp = self.light_view_proj(camera, sun_direction);
+        self.uniform_buffers[frame].write_data(Uniforms {
+            light_vp,
+            sun_direction: sun_direction.extend(0.0).into(),
+        });
+
+        let strength = if self.enabled { self.strength } else { 0.0 };
+        let radius_uv = self.radius / (2.0 * self.extent);
+        (light_vp, [strength, radius_uv, self.sample_count as f32, self.radius])
+    }
+
+    // Primary command buffer of the map pass, independent of the other passes of the frame
+    pub fn draw(&mut self, frame: usize) -> vk::CommandBuffer {
+        let mut clear_values = vec![vk::ClearValue {
+            color: vk::ClearColorValue {
+                float32: [0.0, 0.0, 0.0, 0.0],
+            },
+        }; 3];
+        clear_values.push(self.framebuffer.depth_clear_value());
+
+        self.draw_command.execute_secondary(
+            clear_values,
+            self.framebuffer.framebuffer.unwrap(),
+            self.framebuffer.render_pass(),
+            &[self.second_buffers[frame]],
+        )
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.checkbox(&mut self.enabled, "Bounce light (RSM)");
+        ui.add(egui::Slider::new(&mut self.strength, 0.0..=4.0).text("Strength"));
+        ui.add(egui::Slider::new(&mut self.radius, 0.5..=16.0).text("Gather radius"));
+        ui.add(egui::Slider::new(&mut self.extent, 8.0..=64.0).text("Covered area"));
+        ui.add(egui::Slider::new(&mut self.sample_count, 4..=128).text("Samples"));
+    }
+
+    fn free_second_buffers(&mut self) {
+        if !self.second_buffers.is_empty() {
+            unsafe {
+                self.env.device().free_command_buffers(self.env.command_pool(), &self.second_buffers);
+            }
+            self.second_buffers.clear();
+        }
+    }
+}
+
+impl Drop for ReflectiveShadowMap {
+    fn drop(&mut self) {
+        self.free_second_buffers();
+        unsafe {
+            self.env.device().destroy_sampler(self.sampler, None);
+            object_tracker::destroyed(ObjectKind::Sampler);
+        }
+        self.framebuffer.destroy();
+    }
+}
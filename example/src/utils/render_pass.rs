@@ -1,10 +1,18 @@
 use std::ptr;
 
-use ash::version::DeviceV1_0;
 use ash::vk;
 
+use ash_render_env::env::RenderEnv;
+
 pub fn create_quad_render_pass(
-    device: &ash::Device, surface_format: vk::Format) -> vk::RenderPass {
+    env: &RenderEnv, surface_format: vk::Format) -> vk::RenderPass {
+    create_color_render_pass(env, surface_format, vk::ImageLayout::PRESENT_SRC_KHR)
+}
+
+// Single color attachment pass. Passes differing only in `final_layout` are compatible,
+// so pipelines and secondary buffers of the final pass also work in an offscreen copy of it
+pub fn create_color_render_pass(
+    env: &RenderEnv, surface_format: vk::Format, final_layout: vk::ImageLayout) -> vk::RenderPass {
     let color_attachment = vk::AttachmentDescription {
         format: surface_format,
         flags: vk::AttachmentDescriptionFlags::empty(),
@@ -14,7 +22,7 @@ pub fn create_quad_render_pass(
         stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
         stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
         initial_layout: vk::ImageLayout::UNDEFINED,
-        final_layout: vk::ImageLayout::PRESENT_SRC_KHR,
+        final_layout,
     };
 
     let color_attachment_ref = vk::AttachmentReference {
@@ -53,9 +61,10 @@ pub fn create_quad_render_pass(
             src_subpass: 0,
             dst_subpass: vk::SUBPASS_EXTERNAL,
             src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-            dst_stage_mask: vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            // fragment shader - the offscreen copy is sampled by later passes
+            dst_stage_mask: vk::PipelineStageFlags::BOTTOM_OF_PIPE | vk::PipelineStageFlags::FRAGMENT_SHADER,
             src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
-            dst_access_mask: vk::AccessFlags::MEMORY_READ,
+            dst_access_mask: vk::AccessFlags::MEMORY_READ | vk::AccessFlags::SHADER_READ,
             dependency_flags: vk::DependencyFlags::BY_REGION,
         }
     ];
@@ -72,9 +81,7 @@ pub fn create_quad_render_pass(
         p_dependencies: subpass_dependencies.as_ptr(),
     };
 
-    unsafe {
-        device
-            .create_render_pass(&renderpass_create_info, None)
-            .expect("Failed to create render pass!")
-    }
+    env.render_passes()
+        .get_or_create(&renderpass_create_info)
+        .expect("Failed to create render pass!")
 }
@@ -0,0 +1,118 @@
+use std::sync::Arc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+use cgmath::Matrix4;
+
+use ash_render_env::env::RenderEnv;
+use ash_render_env::memory_tracker;
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::utils::buffer_utils::create_buffer;
+
+pub const MAX_SCENE_OBJECTS: usize = 1024;
+
+// One drawable object as seen by shaders (std430 layout):
+// struct GpuObject { mat4 model; vec4 boundsMin; vec4 boundsMax; uint material; };
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct GpuObject {
+    pub model: [[f32; 4]; 4],
+    // object space bounding box
+    pub bounds_min: [f32; 4],
+    pub bounds_max: [f32; 4],
+    pub material: u32,
+    _padding: [u32; 3],
+}
+
+impl GpuObject {
+    pub fn new(model: Matrix4<f32>, bounds: ([f32; 3], [f32; 3]), material: u32) -> GpuObject {
+        let (min, max) = bounds;
+        GpuObject {
+            model: model.into(),
+            bounds_min: [min[0], min[1], min[2], 1.0],
+            bounds_max: [max[0], max[1], max[2], 1.0],
+            material,
+            _padding: [0; 3],
+        }
+    }
+}
+
+// All objects of the scene in a storage buffer, one copy per frame in flight.
+// Passes index it by gl_InstanceIndex, so every pass draws the same set of objects.
+pub struct SceneBuffer {
+    buffers: Vec<vk::Buffer>,
+    memories: Vec<vk::DeviceMemory>,
+    object_counts: Vec<usize>,
+
+    device: ash::Device,
+}
+
+impl SceneBuffer {
+    pub fn new(env: Arc<RenderEnv>, max_inflight_frames: usize) -> SceneBuffer {
+        let mut buffers = Vec::with_capacity(max_inflight_frames);
+        let mut memories = Vec::with_capacity(max_inflight_frames);
+        for _ in 0..max_inflight_frames {
+            let (buffer, memory) = create_buffer(
+                env.device(),
+                (std::mem::size_of::<GpuObject>() * MAX_SCENE_OBJECTS) as u64,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                &env.mem_properties,
+            );
+            buffers.push(buffer);
+            memories.push(memory);
+        }
+
+        SceneBuffer {
+            buffers,
+            memories,
+            object_counts: vec![0; max_inflight_frames],
+            device: env.device().clone(),
+        }
+    }
+
+    // The buffer of `frame` must not be in use by GPU
+    pub fn write(&mut self, frame: usize, objects: &[GpuObject]) {
+        assert!(objects.len() <= MAX_SCENE_OBJECTS, "Too many scene objects!");
+
+        if !objects.is_empty() {
+            unsafe {
+                let size = std::mem::size_of_val(objects) as u64;
+                let data_ptr = self.device
+                    .map_memory(self.memories[frame], 0, size, vk::MemoryMapFlags::empty())
+                    .expect("Failed to Map Memory") as *mut GpuObject;
+
+                data_ptr.copy_from_nonoverlapping(objects.as_ptr(), objects.len());
+
+                self.device.unmap_memory(self.memories[frame]);
+            }
+        }
+
+        self.object_counts[frame] = objects.len();
+    }
+
+    pub fn buffer(&self, frame: usize) -> vk::Buffer {
+        self.buffers[frame]
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.buffers.len()
+    }
+
+    pub fn object_count(&self, frame: usize) -> usize {
+        self.object_counts[frame]
+    }
+}
+
+impl Drop for SceneBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            for (&buffer, &memory) in self.buffers.iter().zip(self.memories.iter()) {
+                self.device.destroy_buffer(buffer, None);
+                object_tracker::destroyed(ObjectKind::Buffer);
+                self.device.free_memory(memory, None);
+                memory_tracker::freed(memory);
+            }
+        }
+    }
+}
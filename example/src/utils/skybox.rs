@@ -3,9 +3,10 @@ use ash::vk;
 use memoffset::offset_of;
 
 use crate::utils::cube_texture::CubeTexture;
-use std::path::Path;
 use std::sync::Arc;
 use ash_render_env::env::RenderEnv;
+use ash_render_env::memory_tracker;
+use ash_render_env::object_tracker::{self, ObjectKind};
 use ash_render_env::utils::buffer_utils::create_data_buffer;
 
 #[repr(C)]
@@ -121,7 +122,7 @@ pub struct SkyboxVertexData {
 }
 
 impl SkyboxVertexData {
-    pub fn create(env: Arc<RenderEnv>) -> SkyboxVertexData
+    pub fn create(env: Arc<RenderEnv>, texture: CubeTexture) -> SkyboxVertexData
     {
         let (vertices, indices) = load_model();
 
@@ -145,14 +146,6 @@ impl SkyboxVertexData {
             vk::BufferUsageFlags::INDEX_BUFFER,
             indices);
 
-        let texture = CubeTexture::new(
-            env.device().clone(),
-            env.command_pool(),
-            env.queue(),
-            &env.mem_properties,
-            Path::new("./assets/skybox"),
-        );
-
         SkyboxVertexData {
             device: env.device().clone(),
 
@@ -172,10 +165,14 @@ impl Drop for SkyboxVertexData {
     fn drop(&mut self) {
         unsafe {
             self.device.destroy_buffer(self.index_buffer, None);
+            object_tracker::destroyed(ObjectKind::Buffer);
             self.device.free_memory(self.index_buffer_memory, None);
+            memory_tracker::freed(self.index_buffer_memory);
 
             self.device.destroy_buffer(self.vertex_buffer, None);
+            object_tracker::destroyed(ObjectKind::Buffer);
             self.device.free_memory(self.vertex_buffer_memory, None);
+            memory_tracker::freed(self.vertex_buffer_memory);
         }
     }
 }
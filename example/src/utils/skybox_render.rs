@@ -5,10 +5,13 @@ use ash::version::DeviceV1_0;
 use ash::vk;
 use cgmath::{Matrix4, SquareMatrix};
 
+use ash_render_env::camera::CameraDescriptor;
 use ash_render_env::descriptor_set::DescriptorSet;
 use ash_render_env::env::RenderEnv;
 use ash_render_env::pipeline_builder::{Pipeline, PipelineBuilder};
 use ash_render_env::shader;
+use ash_render_env::utils::DepthMode;
+use crate::utils::cube_texture::CubeTexture;
 use crate::utils::uniform_buffer::UboBuffers;
 use crate::utils::{skybox};
 use crate::utils::skybox::SkyboxVertexData;
@@ -21,6 +24,8 @@ pub struct SkyboxRenderer {
 
     render_pass: vk::RenderPass,
     pipeline: Pipeline,
+    color_attachment_count: usize,
+    depth_mode: DepthMode,
 
     descriptor_sets: Vec<DescriptorSet>,
     uniforms: UboBuffers,
@@ -28,27 +33,15 @@ pub struct SkyboxRenderer {
 
     current_frame: usize,
     max_inflight_frames: usize,
+    dimensions: [u32; 2],
 }
 
 impl SkyboxRenderer {
     pub fn new(env: Arc<RenderEnv>, render_pass: vk::RenderPass, color_attachment_count: usize,
-               msaa_samples: vk::SampleCountFlags, max_inflight_frames: usize,
+               msaa_samples: vk::SampleCountFlags, depth_mode: DepthMode, max_inflight_frames: usize,
                dimensions: [u32; 2]) -> SkyboxRenderer
     {
-        let pipeline = {
-            let vert_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/skybox.vert.spv");
-            let frag_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/skybox.frag.spv");
-
-            PipelineBuilder::new(env.device().clone(), render_pass, 0)
-                .vertex_shader(vert_shader_module)
-                .fragment_shader(frag_shader_module)
-                .vertex_input(skybox::SkyboxVertex::binding_descriptions(), skybox::SkyboxVertex::attribute_descriptions())
-                .msaa(msaa_samples)
-                .color_attachment_count(color_attachment_count)
-                .with_depth_func(vk::CompareOp::LESS_OR_EQUAL)
-                .cull_mode(vk::CullModeFlags::BACK)
-                .build()
-        };
+        let pipeline = Self::create_pipeline(&env, render_pass, color_attachment_count, msaa_samples, depth_mode);
 
         let uniforms = UboBuffers::new(
             env.instance(),
@@ -57,7 +50,8 @@ impl SkyboxRenderer {
             max_inflight_frames,
         );
 
-        let skybox_data = skybox::SkyboxVertexData::create(env.clone());
+        // the real texture is loaded in background, see set_texture
+        let skybox_data = skybox::SkyboxVertexData::create(env.clone(), CubeTexture::placeholder(&env));
 
         let mut cmd_bufs = vec![];
         let mut descriptor_sets = vec![];
@@ -67,6 +61,7 @@ impl SkyboxRenderer {
                     .add_buffer(uniforms.uniform_buffers[i])
                     .add_image(skybox_data.texture.texture_image_view, skybox_data.texture.texture_sampler)
                     .build()
+                    .expect("Failed to create descriptor set!")
             );
             cmd_bufs.push(
                 Self::build_cmd_buf(&env, render_pass, &pipeline, &descriptor_sets[i], &skybox_data, dimensions)
@@ -76,6 +71,8 @@ impl SkyboxRenderer {
         SkyboxRenderer {
             env: env.clone(),
             pipeline,
+            color_attachment_count,
+            depth_mode,
             cmd_bufs,
             render_pass,
             uniforms,
@@ -83,9 +80,59 @@ impl SkyboxRenderer {
             skybox: skybox_data,
             current_frame: 0,
             max_inflight_frames,
+            dimensions,
         }
     }
 
+    fn create_pipeline(env: &RenderEnv, render_pass: vk::RenderPass, color_attachment_count: usize,
+                       msaa_samples: vk::SampleCountFlags, depth_mode: DepthMode) -> Pipeline {
+        // the sky is put on the far plane
+        let vert_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/skybox.vert.spv").expect("Failed to load shader!")
+            .specialize(shader::ConstantsBuilder::new().add_u32(depth_mode.is_reversed() as u32));
+        let frag_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/skybox.frag.spv").expect("Failed to load shader!");
+
+        PipelineBuilder::new(env.device().clone(), render_pass, 0)
+            .pipeline_cache(env.pipeline_cache())
+            .vertex_shader(vert_shader_module)
+            .fragment_shader(frag_shader_module)
+            .vertex_input(skybox::SkyboxVertex::binding_descriptions(), skybox::SkyboxVertex::attribute_descriptions())
+            .msaa(msaa_samples)
+            .color_attachment_count(color_attachment_count)
+            .with_depth_func(vk::CompareOp::LESS_OR_EQUAL)
+            .depth_mode(depth_mode)
+            .cull_mode(vk::CullModeFlags::BACK)
+            .build()
+            .expect("Failed to create pipeline!")
+    }
+
+    // Pipeline for a new G-buffer render pass, e.g. with another sample count.
+    // Command buffers are re-recorded, so the caller must make sure they are not in use
+    pub fn set_render_pass(&mut self, render_pass: vk::RenderPass, msaa_samples: vk::SampleCountFlags) {
+        self.render_pass = render_pass;
+        self.pipeline = Self::create_pipeline(&self.env, render_pass, self.color_attachment_count, msaa_samples, self.depth_mode);
+        self.resize_framebuffer(self.dimensions);
+    }
+
+    // Command buffers are re-recorded, so the caller must make sure they are not in use
+    pub fn set_texture(&mut self, texture: CubeTexture) {
+        self.skybox.texture = texture;
+
+        self.descriptor_sets = (0..self.max_inflight_frames)
+            .map(|i| {
+                DescriptorSet::builder(self.env.device(), self.pipeline.descriptor_set_layouts.first().unwrap())
+                    .add_buffer(self.uniforms.uniform_buffers[i])
+                    .add_image(self.skybox.texture.texture_image_view, self.skybox.texture.texture_sampler)
+                    .build()
+                    .expect("Failed to create descriptor set!")
+            })
+            .collect();
+        self.resize_framebuffer(self.dimensions);
+    }
+
+    pub fn texture(&self) -> &CubeTexture {
+        &self.skybox.texture
+    }
+
     fn build_cmd_buf(env: &RenderEnv, render_pass: vk::RenderPass, pipeline: &Pipeline, descriptor_set: &DescriptorSet, vertex_buffer: &SkyboxVertexData, dimensions: [u32; 2]) -> vk::CommandBuffer {
         let command_buffer = env.create_secondary_command_buffer();
         let device = env.device();
@@ -182,11 +229,12 @@ impl SkyboxRenderer {
         }
 
         self.cmd_bufs = cmd_bufs;
+        self.dimensions = dimensions;
     }
 
-    pub fn draw(&mut self, view: Matrix4<f32>, proj: Matrix4<f32>) -> vk::CommandBuffer
+    pub fn draw(&mut self, camera: &CameraDescriptor) -> vk::CommandBuffer
     {
-        self.uniforms.update_uniform_buffer(self.current_frame, Matrix4::identity(), view, proj);
+        self.uniforms.update_uniform_buffer(self.current_frame, Matrix4::identity(), camera.rotation_view, camera.proj, camera);
 
         let current_frame = self.current_frame;
         self.current_frame = (self.current_frame + 1) % self.max_inflight_frames;
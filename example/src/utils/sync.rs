@@ -12,7 +12,7 @@ pub struct SyncObjects {
     pub image_available_semaphores: Vec<vk::Semaphore>,
     pub render_finished_semaphores: Vec<vk::Semaphore>,
     pub inflight_fences: Vec<vk::Fence>,
-    pub render_quad_semaphore: vk::Semaphore,
+    pub render_quad_semaphores: Vec<vk::Semaphore>,
 }
 
 impl SyncObjects {
@@ -30,7 +30,9 @@ impl SyncObjects {
                 self.device.destroy_fence(fence, None);
             }
 
-            self.device.destroy_semaphore(self.render_quad_semaphore, None);
+            for semphore in self.render_quad_semaphores.drain(0..) {
+                self.device.destroy_semaphore(semphore, None);
+            }
         }
     }
 }
@@ -43,7 +45,7 @@ pub fn create_sync_objects(device: &ash::Device) -> SyncObjects {
         image_available_semaphores: vec![],
         render_finished_semaphores: vec![],
         inflight_fences: vec![],
-        render_quad_semaphore: vk::Semaphore::null(),
+        render_quad_semaphores: vec![],
     };
 
     let semaphore_create_info = vk::SemaphoreCreateInfo {
@@ -70,6 +72,9 @@ pub fn create_sync_objects(device: &ash::Device) -> SyncObjects {
             let inflight_fence = device
                 .create_fence(&fence_create_info, None)
                 .expect("Failed to create Fence Object!");
+            let render_quad_semaphore = device
+                .create_semaphore(&semaphore_create_info, None)
+                .expect("Failed to create Semaphore Object!");
 
             sync_objects
                 .image_available_semaphores
@@ -78,14 +83,11 @@ pub fn create_sync_objects(device: &ash::Device) -> SyncObjects {
                 .render_finished_semaphores
                 .push(render_finished_semaphore);
             sync_objects.inflight_fences.push(inflight_fence);
+            sync_objects
+                .render_quad_semaphores
+                .push(render_quad_semaphore);
         }
     }
 
-    unsafe {
-        sync_objects.render_quad_semaphore = device
-            .create_semaphore(&semaphore_create_info, None)
-            .expect("Failed to create Semaphore Object!");
-    };
-
     sync_objects
 }
@@ -0,0 +1,335 @@
+use std::ptr;
+use std::sync::Arc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use ash_render_env::descriptor_set::DescriptorSet;
+use ash_render_env::env::RenderEnv;
+use ash_render_env::frame_buffer::{AttachmentDesciption, Framebuffer};
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::pipeline_builder::{Pipeline, PipelineBuilder};
+use ash_render_env::primary_cmd_buffer::PrimaryCommandBuffer;
+use ash_render_env::shader;
+
+use crate::utils::gbuffer::GBufferLayout;
+
+// Length of the jitter sequence, the history blends about as many frames
+const JITTER_PHASES: u32 = 8;
+
+// Push constants of taa_resolve.frag
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ResolveConstants {
+    // x - weight of the current frame, y - 0 when the history is invalid
+    params: [f32; 4],
+    // size of the clamp box around the neighborhood colors, edited in the shader tweaks panel
+    clamp_scale: f32,
+}
+
+// Temporal anti-aliasing. The camera projection is jittered by a sub-pixel offset every frame,
+// resolve pass blends the lit HDR image with the history reprojected by G-buffer motion vectors,
+// clamping the history to the colors around the pixel against ghosting.
+// The result becomes the next history and is copied back into the HDR buffer for bloom and the rest.
+// Works with a single sample G-buffer only.
+pub struct TemporalAntiAliasing {
+    // weight of the current frame in the blend, lower is smoother but ghosts more
+    pub current_weight: f32,
+
+    enabled: bool,
+    // ping-pong: one is written, the other one is the previous frame
+    history: Vec<Framebuffer>,
+    current: usize,
+    history_valid: bool,
+    jitter_index: u32,
+
+    resolve_pipeline: Pipeline,
+    copy_pipeline: Pipeline,
+    sampler: vk::Sampler,
+    // per history framebuffer written
+    resolve_sets: Vec<DescriptorSet>,
+    copy_sets: Vec<DescriptorSet>,
+    // per frame in flight, recorded in draw()
+    resolve_buffers: Vec<vk::CommandBuffer>,
+    copy_buffers: Vec<vk::CommandBuffer>,
+    resolve_command: PrimaryCommandBuffer,
+    copy_command: PrimaryCommandBuffer,
+
+    env: Arc<RenderEnv>,
+}
+
+impl TemporalAntiAliasing {
+    pub fn new(env: Arc<RenderEnv>, gbuffer: &Framebuffer, hdr_buffer: &Framebuffer, max_inflight_frames: usize) -> TemporalAntiAliasing {
+        let dimensions = hdr_buffer.dimensions();
+        let history: Vec<_> = (0..2)
+            .map(|idx| {
+                let mut framebuffer = Framebuffer::new(env.clone(), vec![
+                    AttachmentDesciption {
+                        format: vk::Format::R16G16B16A16_SFLOAT,
+                        samples_count: vk::SampleCountFlags::TYPE_1,
+                    },
+                ]);
+                framebuffer.set_name(&format!("TAA history {}", idx));
+                framebuffer.resize_swapchain(dimensions);
+                framebuffer
+            })
+            .collect();
+
+        let vert_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/compose.vert.spv").expect("Failed to load shader!");
+        let frag_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/taa/taa_resolve.frag.spv").expect("Failed to load shader!")
+            .specialize(shader::ConstantsBuilder::new()
+                .add_u32(gbuffer.depth_mode().is_reversed() as u32));
+        let resolve_pipeline = PipelineBuilder::new(env.device().clone(), history[0].render_pass(), 0)
+            .pipeline_cache(env.pipeline_cache())
+            .vertex_shader(vert_shader_module)
+            .fragment_shader(frag_shader_module)
+            .build()
+            .expect("Failed to create pipeline!");
+
+        let vert_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/compose.vert.spv").expect("Failed to load shader!");
+        let frag_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/taa/taa_copy.frag.spv").expect("Failed to load shader!");
+        let copy_pipeline = PipelineBuilder::new(env.device().clone(), hdr_buffer.render_pass(), 0)
+            .pipeline_cache(env.pipeline_cache())
+            .vertex_shader(vert_shader_module)
+            .fragment_shader(frag_shader_module)
+            .build()
+            .expect("Failed to create pipeline!");
+
+        let sampler_create_info = vk::SamplerCreateInfo::builder()
+            .min_filter(vk::Filter::LINEAR)
+            .mag_filter(vk::Filter::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+
+        let sampler = unsafe {
+            env.device().create_sampler(&sampler_create_info, None).unwrap()
+        };
+        object_tracker::created(ObjectKind::Sampler);
+
+        let resolve_buffers = (0..max_inflight_frames)
+            .map(|_| env.create_secondary_command_buffer())
+            .collect();
+        let copy_buffers = (0..max_inflight_frames)
+            .map(|_| env.create_secondary_command_buffer())
+            .collect();
+
+        let mut resolve_command = PrimaryCommandBuffer::new(env.clone(), max_inflight_frames);
+        resolve_command.set_name("TAA resolve pass");
+        resolve_command.set_dimensions(dimensions);
+        let mut copy_command = PrimaryCommandBuffer::new(env.clone(), max_inflight_frames);
+        copy_command.set_name("TAA copy pass");
+        copy_command.set_dimensions(dimensions);
+
+        let mut taa = TemporalAntiAliasing {
+            current_weight: 0.1,
+            enabled: false,
+            history,
+            current: 0,
+            history_valid: false,
+            jitter_index: 0,
+            resolve_pipeline,
+            copy_pipeline,
+            sampler,
+            resolve_sets: vec![],
+            copy_sets: vec![],
+            resolve_buffers,
+            copy_buffers,
+            resolve_command,
+            copy_command,
+            env,
+        };
+        taa.create_descriptor_sets(gbuffer, hdr_buffer);
+
+        taa
+    }
+
+    fn create_descriptor_sets(&mut self, gbuffer: &Framebuffer, hdr_buffer: &Framebuffer) {
+        self.resolve_sets = (0..self.history.len())
+            .map(|idx| DescriptorSet::builder(self.env.device(), &self.resolve_pipeline.descriptor_set_layouts[0])
+                .add_attachment(&hdr_buffer.attachments[0], self.sampler)
+                .add_attachment(&self.history[1 - idx].attachments[0], self.sampler)
+                .add_attachment(&gbuffer.attachments[GBufferLayout::VELOCITY], self.sampler)
+                .add_attachment(&gbuffer.attachments[GBufferLayout::DEPTH], self.sampler)
+                .build()
+                .expect("Failed to create descriptor set!"))
+            .collect();
+
+        self.copy_sets = self.history.iter()
+            .map(|history| DescriptorSet::builder(self.env.device(), &self.copy_pipeline.descriptor_set_layouts[0])
+                .add_attachment(&history.attachments[0], self.sampler)
+                .build()
+                .expect("Failed to create descriptor set!"))
+            .collect();
+    }
+
+    // Follows the anti-aliasing mode, the history starts over
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.history_valid = false;
+    }
+
+    // Device must be idle. Multisampled G-buffer attachments are bound too, the sets are not used then
+    pub fn resize(&mut self, gbuffer: &Framebuffer, hdr_buffer: &Framebuffer) {
+        let dimensions = hdr_buffer.dimensions();
+        for history in self.history.iter_mut() {
+            history.resize_swapchain(dimensions);
+        }
+        self.resolve_command.set_dimensions(dimensions);
+        self.copy_command.set_dimensions(dimensions);
+        self.create_descriptor_sets(gbuffer, hdr_buffer);
+        self.history_valid = false;
+    }
+
+    // Projection offset in NDC for the next frame: Halton (2, 3) points inside the pixel, [0, 0] when disabled
+    pub fn next_jitter(&mut self, dimensions: [u32; 2]) -> [f32; 2] {
+        if !self.enabled {
+            return [0.0, 0.0];
+        }
+
+        self.jitter_index = (self.jitter_index + 1) % JITTER_PHASES;
+        let index = self.jitter_index + 1;
+
+        // a pixel is 2 / size in NDC
+        [
+            (halton(index, 2) - 0.5) * 2.0 / dimensions[0].max(1) as f32,
+            (halton(index, 3) - 0.5) * 2.0 / dimensions[1].max(1) as f32,
+        ]
+    }
+
+    // Primary command buffers of the resolve and copy passes, must run after the compose pass
+    // and before anything reading the HDR buffer. Empty when disabled
+    pub fn draw(&mut self, frame: usize, hdr_buffer: &Framebuffer) -> Vec<vk::CommandBuffer> {
+        if !self.enabled {
+            return vec![];
+        }
+
+        self.current = 1 - self.current;
+        let constants = ResolveConstants {
+            params: [self.current_weight, self.history_valid as u32 as f32, 0.0, 0.0],
+            clamp_scale: 1.0,
+        };
+        self.history_valid = true;
+
+        let history = &self.history[self.current];
+        let resolve_buffer = self.resolve_buffers[frame];
+        self.record(resolve_buffer, &self.resolve_pipeline, &self.resolve_sets[self.current], history.render_pass(), Some(constants));
+        let copy_buffer = self.copy_buffers[frame];
+        self.record(copy_buffer, &self.copy_pipeline, &self.copy_sets[self.current], hdr_buffer.render_pass(), None);
+
+        let clear_values = vec![vk::ClearValue {
+            color: vk::ClearColorValue {
+                float32: [0.0, 0.0, 0.0, 1.0],
+            },
+        }];
+
+        let resolve = self.resolve_command.execute_secondary(
+            clear_values.clone(),
+            history.framebuffer.unwrap(),
+            history.render_pass(),
+            &[resolve_buffer],
+        );
+        let copy = self.copy_command.execute_secondary(
+            clear_values,
+            hdr_buffer.framebuffer.unwrap(),
+            hdr_buffer.render_pass(),
+            &[copy_buffer],
+        );
+
+        vec![resolve, copy]
+    }
+
+    fn record(&self, cmd_buf: vk::CommandBuffer, pipeline: &Pipeline, descriptor_set: &DescriptorSet, render_pass: vk::RenderPass,
+              constants: Option<ResolveConstants>) {
+        let device = self.env.device();
+        let dimensions = self.history[0].dimensions();
+
+        let inheritance_info = vk::CommandBufferInheritanceInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_INHERITANCE_INFO,
+            p_next: ptr::null(),
+            render_pass,
+            subpass: 0,
+            framebuffer: vk::Framebuffer::null(),
+            occlusion_query_enable: 0,
+            query_flags: Default::default(),
+            pipeline_statistics: Default::default(),
+        };
+
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_BEGIN_INFO,
+            p_next: ptr::null(),
+            p_inheritance_info: &inheritance_info,
+            flags: vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE | vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT,
+        };
+
+        let viewports = [vk::Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: dimensions[0] as f32,
+            height: dimensions[1] as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        }];
+
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: vk::Extent2D {
+                width: dimensions[0],
+                height: dimensions[1],
+            },
+        }];
+
+        unsafe {
+            device.reset_command_buffer(cmd_buf, vk::CommandBufferResetFlags::default())
+                .expect("Failed to reset Command Buffer!");
+            device
+                .begin_command_buffer(cmd_buf, &command_buffer_begin_info)
+                .expect("Failed to begin recording Command Buffer at beginning!");
+
+            device.cmd_set_viewport(cmd_buf, 0, viewports.as_ref());
+            device.cmd_set_scissor(cmd_buf, 0, scissors.as_ref());
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pipeline.graphics_pipeline);
+            descriptor_set.bind(cmd_buf, vk::PipelineBindPoint::GRAPHICS, pipeline.pipeline_layout, 0, &[]);
+            if let Some(constants) = constants {
+                pipeline.push_constants(cmd_buf, vk::ShaderStageFlags::FRAGMENT, &constants);
+            }
+
+            device.cmd_draw(cmd_buf, 3, 1, 0, 0);
+
+            device
+                .end_command_buffer(cmd_buf)
+                .expect("Failed to record Command Buffer at Ending!");
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.add(egui::Slider::new(&mut self.current_weight, 0.02..=0.5).text("TAA current frame weight"));
+    }
+}
+
+// Radical inverse of `index` in `base`, a low discrepancy sequence in [0, 1)
+fn halton(mut index: u32, base: u32) -> f32 {
+    let mut fraction = 1.0;
+    let mut result = 0.0;
+    while index > 0 {
+        fraction /= base as f32;
+        result += fraction * (index % base) as f32;
+        index /= base;
+    }
+
+    result
+}
+
+impl Drop for TemporalAntiAliasing {
+    fn drop(&mut self) {
+        unsafe {
+            self.env.device().free_command_buffers(self.env.command_pool(), &self.resolve_buffers);
+            self.env.device().free_command_buffers(self.env.command_pool(), &self.copy_buffers);
+            self.env.device().destroy_sampler(self.sampler, None);
+            object_tracker::destroyed(ObjectKind::Sampler);
+        }
+        for history in self.history.iter() {
+            history.destroy();
+        }
+    }
+}
@@ -0,0 +1,330 @@
+use std::ptr;
+use std::sync::Arc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+use cgmath::{Matrix4, MetricSpace, SquareMatrix, Vector3};
+
+use ash_render_env::camera::CameraDescriptor;
+use ash_render_env::culling::Aabb;
+use ash_render_env::descriptor_set::DescriptorSet;
+use ash_render_env::env::RenderEnv;
+use ash_render_env::frame_buffer::Framebuffer;
+use ash_render_env::object_tracker::{self, ObjectKind};
+use ash_render_env::pipeline_builder::{Pipeline, PipelineBuilder};
+use ash_render_env::shader;
+use ash_render_env::std140_struct;
+use ash_render_env::uniform_buffer::UniformBuffer;
+
+use crate::utils::gbuffer::reader_shader_path;
+use crate::utils::mesh;
+use crate::utils::model::Model;
+use crate::utils::scene_buffer::SceneBuffer;
+use crate::utils::uniform_buffer::UboBuffers;
+
+std140_struct! {
+    struct FragmentUniforms {
+        camera_position: [f32; 4],
+        // x - exposure, y - opacity multiplier, z - ambient strength
+        params: [f32; 4],
+        sun_direction: [f32; 4],
+        fog: [f32; 4],
+        fog_color: [f32; 4],
+    }
+}
+
+// Draw state of one frame in flight, the command buffer is re-recorded every frame in the new draw order
+struct FrameResources {
+    fragment_uniforms: UniformBuffer<FragmentUniforms>,
+    // one per model part
+    descriptor_sets: Vec<DescriptorSet>,
+    second_buffer: vk::CommandBuffer,
+}
+
+// Forward pass of model parts with transparent materials, MeshRenderer leaves them out of the G-buffer.
+// They are blended over the lit scene in the HDR pass after the composite, objects sorted back to front
+// by the distance to their bounds. Depth is tested against the G-buffer, transparent surfaces don't write it
+pub struct TransparentRenderer {
+    pub opacity: f32,
+    pub ambient: f32,
+
+    pipeline: Pipeline,
+    render_pass: vk::RenderPass,
+    gbuffer_samples: vk::SampleCountFlags,
+    sampler: vk::Sampler,
+    uniforms: UboBuffers,
+    light_buffers: Vec<vk::Buffer>,
+    frames: Vec<FrameResources>,
+    dimensions: [u32; 2],
+
+    model: Arc<Model>,
+    // world bounds of scene buffer objects in MeshRenderer::set_objects order
+    object_bounds: Vec<Aabb>,
+    object_count: usize,
+    // objects drawn in the last frame
+    draw_count: usize,
+
+    env: Arc<RenderEnv>,
+}
+
+impl TransparentRenderer {
+    // `render_pass` is the HDR pass, `light_buffers` are the dynamic lights of every frame in flight
+    pub fn new(env: Arc<RenderEnv>, render_pass: vk::RenderPass, model: Arc<Model>, scene_buffer: &SceneBuffer,
+               gbuffer: &Framebuffer, light_buffers: Vec<vk::Buffer>) -> TransparentRenderer {
+        let max_inflight_frames = scene_buffer.frame_count();
+        let pipeline = Self::create_pipeline(&env, render_pass, gbuffer);
+
+        let sampler_create_info = vk::SamplerCreateInfo::builder()
+            .min_filter(vk::Filter::NEAREST)
+            .mag_filter(vk::Filter::NEAREST)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+
+        let sampler = unsafe {
+            env.device().create_sampler(&sampler_create_info, None).unwrap()
+        };
+        object_tracker::created(ObjectKind::Sampler);
+
+        let uniforms = UboBuffers::new(
+            env.instance(),
+            env.device().clone(),
+            env.physical_device(),
+            max_inflight_frames,
+        );
+
+        let frames = (0..max_inflight_frames)
+            .map(|_| FrameResources {
+                fragment_uniforms: UniformBuffer::new(env.clone()),
+                descriptor_sets: vec![],
+                second_buffer: env.create_secondary_command_buffer(),
+            })
+            .collect();
+
+        let mut renderer = TransparentRenderer {
+            opacity: 1.0,
+            ambient: 0.3,
+            pipeline,
+            render_pass,
+            gbuffer_samples: gbuffer.attachments.last().unwrap().samples,
+            sampler,
+            uniforms,
+            light_buffers,
+            frames,
+            dimensions: gbuffer.dimensions(),
+            model,
+            object_bounds: vec![],
+            object_count: 0,
+            draw_count: 0,
+            env,
+        };
+        renderer.update_descriptor_sets(scene_buffer, gbuffer);
+
+        renderer
+    }
+
+    fn create_pipeline(env: &RenderEnv, render_pass: vk::RenderPass, gbuffer: &Framebuffer) -> Pipeline {
+        let vert_shader_module = shader::Shader::load(env.device(), "assets/shaders/spv/mesh/mesh.vert.spv").expect("Failed to load shader!");
+        let frag_shader_module = shader::Shader::load(env.device(), &reader_shader_path("transparent/transparent", gbuffer.attachments.last().unwrap().samples)).expect("Failed to load shader!")
+            .specialize(shader::ConstantsBuilder::new()
+                .add_u32(gbuffer.depth_mode().is_reversed() as u32));
+
+        PipelineBuilder::new(env.device().clone(), render_pass, 0)
+            .pipeline_cache(env.pipeline_cache())
+            .device_features(env.enabled_features())
+            .vertex_shader(vert_shader_module)
+            .fragment_shader(frag_shader_module)
+            .vertex_input(mesh::Vertex::binding_descriptions(), mesh::Vertex::attribute_descriptions())
+            .blend()
+            .build()
+            .expect("Failed to create pipeline!")
+    }
+
+    fn update_descriptor_sets(&mut self, scene_buffer: &SceneBuffer, gbuffer: &Framebuffer) {
+        let (env, pipeline, model) = (&self.env, &self.pipeline, &self.model);
+        let (sampler, uniform_buffers, light_buffers) = (self.sampler, &self.uniforms.uniform_buffers, &self.light_buffers);
+        for (frame_idx, frame) in self.frames.iter_mut().enumerate() {
+            let fragment_uniforms = frame.fragment_uniforms.buffer;
+            frame.descriptor_sets = model.parts.iter()
+                .map(|part| {
+                    let material = &part.material;
+                    DescriptorSet::builder(env.device(), &pipeline.descriptor_set_layouts[0])
+                        .add_buffer(uniform_buffers[frame_idx])
+                        .add_texture(&material.albedo)
+                        .add_storage_buffer(scene_buffer.buffer(frame_idx))
+                        .add_texture(&material.normal)
+                        .add_texture(&material.metallic_roughness)
+                        .add_texture(&material.occlusion)
+                        .add_attachment(gbuffer.attachments.last().unwrap(), sampler)
+                        .add_storage_buffer(light_buffers[frame_idx])
+                        .add_buffer(fragment_uniforms)
+                        .build()
+                        .expect("Failed to create descriptor set!")
+                })
+                .collect();
+        }
+    }
+
+    // Command buffers must not be in use
+    pub fn set_model(&mut self, model: Arc<Model>, scene_buffer: &SceneBuffer, gbuffer: &Framebuffer) {
+        self.model = model;
+        self.update_descriptor_sets(scene_buffer, gbuffer);
+    }
+
+    // Same bounds as given to MeshRenderer::set_objects
+    pub fn set_objects(&mut self, bounds: Vec<Aabb>) {
+        self.object_count = bounds.len() / self.model.parts.len().max(1);
+        self.object_bounds = bounds;
+    }
+
+    // Device must be idle
+    pub fn resize(&mut self, scene_buffer: &SceneBuffer, gbuffer: &Framebuffer) {
+        let gbuffer_samples = gbuffer.attachments.last().unwrap().samples;
+        if gbuffer_samples != self.gbuffer_samples {
+            self.pipeline = Self::create_pipeline(&self.env, self.render_pass, gbuffer);
+            self.gbuffer_samples = gbuffer_samples;
+        }
+
+        self.dimensions = gbuffer.dimensions();
+        self.update_descriptor_sets(scene_buffer, gbuffer);
+    }
+
+    // Visible objects of transparent parts, farthest first: (part, first instance)
+    fn sorted_draws(&self, camera: &CameraDescriptor) -> Vec<(usize, u32)> {
+        let mut draws: Vec<(f32, usize, u32)> = vec![];
+        for (part_idx, part) in self.model.parts.iter().enumerate() {
+            if !part.material.transparent {
+                continue;
+            }
+
+            for object_idx in 0..self.object_count {
+                let first_instance = part_idx * self.object_count + object_idx;
+                let bounds = match self.object_bounds.get(first_instance) {
+                    Some(bounds) => bounds,
+                    None => continue,
+                };
+                if !camera.frustum.intersects_aabb(bounds) {
+                    continue;
+                }
+
+                draws.push((bounds.center().distance2(camera.position), part_idx, first_instance as u32));
+            }
+        }
+
+        draws.sort_by(|a, b| b.0.total_cmp(&a.0));
+        draws.into_iter().map(|(_, part_idx, first_instance)| (part_idx, first_instance)).collect()
+    }
+
+    // Secondary command buffer for the HDR pass, after the composite.
+    // `sun_direction` - Atmosphere::sun_direction, `fog` - Atmosphere::fog_params. Resources of `frame` must not be in use by GPU
+    pub fn draw(
+        &mut self, frame: usize, camera: &CameraDescriptor, exposure: f32, sun_direction: Vector3<f32>, fog: [[f32; 4]; 2],
+    ) -> vk::CommandBuffer
+    {
+        let draws = self.sorted_draws(camera);
+        self.draw_count = draws.len();
+
+        self.uniforms.update_uniform_buffer(frame, Matrix4::identity(), camera.view, camera.proj, camera);
+        let resources = &self.frames[frame];
+        resources.fragment_uniforms.write_data(FragmentUniforms {
+            camera_position: [camera.position.x, camera.position.y, camera.position.z, 1.0],
+            params: [exposure, self.opacity, self.ambient, 0.0],
+            sun_direction: sun_direction.extend(0.0).into(),
+            fog: fog[0],
+            fog_color: fog[1],
+        });
+
+        let device = self.env.device();
+        let cmd_buf = resources.second_buffer;
+
+        let inheritance_info = vk::CommandBufferInheritanceInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_INHERITANCE_INFO,
+            p_next: ptr::null(),
+            render_pass: self.render_pass,
+            subpass: 0,
+            framebuffer: vk::Framebuffer::null(),
+            occlusion_query_enable: 0,
+            query_flags: Default::default(),
+            pipeline_statistics: Default::default(),
+        };
+
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_BEGIN_INFO,
+            p_next: ptr::null(),
+            p_inheritance_info: &inheritance_info,
+            flags: vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE | vk::CommandBufferUsageFlags::SIMULTANEOUS_USE,
+        };
+
+        let viewports = [vk::Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: self.dimensions[0] as f32,
+            height: self.dimensions[1] as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        }];
+
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: vk::Extent2D {
+                width: self.dimensions[0],
+                height: self.dimensions[1],
+            },
+        }];
+
+        unsafe {
+            device.reset_command_buffer(cmd_buf, vk::CommandBufferResetFlags::default())
+                .expect("Failed to reset Command Buffer!");
+            device
+                .begin_command_buffer(cmd_buf, &command_buffer_begin_info)
+                .expect("Failed to begin recording Command Buffer at beginning!");
+
+            device.cmd_set_viewport(cmd_buf, 0, viewports.as_ref());
+            device.cmd_set_scissor(cmd_buf, 0, scissors.as_ref());
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, self.pipeline.graphics_pipeline);
+            self.pipeline.push_constants(cmd_buf, vk::ShaderStageFlags::VERTEX, &Matrix4::<f32>::identity());
+
+            // buffers and material are bound again only when the part changes between neighbouring draws
+            let mut bound_part = None;
+            for &(part_idx, first_instance) in draws.iter() {
+                let part = &self.model.parts[part_idx];
+                if bound_part != Some(part_idx) {
+                    resources.descriptor_sets[part_idx].bind(cmd_buf, vk::PipelineBindPoint::GRAPHICS, self.pipeline.pipeline_layout, 0, &[]);
+                    self.pipeline.push_constants(cmd_buf, vk::ShaderStageFlags::FRAGMENT, &part.material.factors());
+                    device.cmd_bind_vertex_buffers(cmd_buf, 0, &[part.mesh.vertex_buffer], &[0]);
+                    device.cmd_bind_index_buffer(cmd_buf, part.mesh.index_buffer, 0, vk::IndexType::UINT32);
+                    bound_part = Some(part_idx);
+                }
+
+                device.cmd_draw_indexed(cmd_buf, part.mesh.index_count as u32, 1, 0, 0, first_instance);
+            }
+
+            device
+                .end_command_buffer(cmd_buf)
+                .expect("Failed to record Command Buffer at Ending!");
+        }
+
+        cmd_buf
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        let parts = self.model.parts.iter().filter(|part| part.material.transparent).count();
+        ui.label(format!("Transparent parts: {}, objects drawn: {}", parts, self.draw_count));
+        ui.add(egui::Slider::new(&mut self.opacity, 0.0..=1.0).text("Opacity"));
+        ui.add(egui::Slider::new(&mut self.ambient, 0.0..=1.0).text("Ambient"));
+    }
+}
+
+impl Drop for TransparentRenderer {
+    fn drop(&mut self) {
+        unsafe {
+            let device = self.env.device();
+            for frame in self.frames.iter() {
+                device.free_command_buffers(self.env.command_pool(), &[frame.second_buffer]);
+            }
+
+            device.destroy_sampler(self.sampler, None);
+            object_tracker::destroyed(ObjectKind::Sampler);
+        }
+    }
+}
@@ -1,6 +1,9 @@
 use ash::version::{DeviceV1_0, InstanceV1_0};
 use ash::vk;
 use cgmath::Matrix4;
+use ash_render_env::camera::CameraDescriptor;
+use ash_render_env::memory_tracker;
+use ash_render_env::object_tracker::{self, ObjectKind};
 use ash_render_env::utils::buffer_utils::create_buffer;
 
 #[repr(C)]
@@ -9,6 +12,9 @@ struct UniformBufferObject {
     model: Matrix4<f32>,
     view: Matrix4<f32>,
     proj: Matrix4<f32>,
+    // unjittered, for motion vectors of the G-buffer
+    view_proj: Matrix4<f32>,
+    prev_view_proj: Matrix4<f32>,
 }
 
 
@@ -52,11 +58,15 @@ impl UboBuffers {
         }
     }
 
-    pub fn update_uniform_buffer(&self, current_image: usize, world: Matrix4<f32>, view: Matrix4<f32>, proj: Matrix4<f32>) {
+    // `camera` gives the view projections of this and the previous frame for motion vectors
+    pub fn update_uniform_buffer(&self, current_image: usize, world: Matrix4<f32>, view: Matrix4<f32>, proj: Matrix4<f32>,
+                                 camera: &CameraDescriptor) {
         let ubos = [UniformBufferObject {
             model: world,
             view,
             proj,
+            view_proj: camera.unjittered_view_proj,
+            prev_view_proj: camera.prev_view_proj,
         }];
 
         let buffer_size = (std::mem::size_of::<UniformBufferObject>() * ubos.len()) as u64;
@@ -85,8 +95,10 @@ impl Drop for UboBuffers {
         unsafe {
             for i in 0..self.uniform_buffers.len() {
                 self.device.destroy_buffer(self.uniform_buffers[i], None);
+                object_tracker::destroyed(ObjectKind::Buffer);
                 self.device
                     .free_memory(self.uniform_buffers_memory[i], None);
+                memory_tracker::freed(self.uniform_buffers_memory[i]);
             }
         }
     }
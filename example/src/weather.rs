@@ -0,0 +1,82 @@
+// Rain amount reaches the target in ~3 seconds
+const RAIN_TRANSITION_SPEED: f32 = 0.35;
+// Surfaces get wet faster than they dry
+const WETTING_SPEED: f32 = 0.15;
+const DRYING_SPEED: f32 = 0.04;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum WeatherKind {
+    Clear,
+    Drizzle,
+    Rain,
+    Storm,
+}
+
+impl WeatherKind {
+    const ALL: [WeatherKind; 4] = [WeatherKind::Clear, WeatherKind::Drizzle, WeatherKind::Rain, WeatherKind::Storm];
+
+    fn rain_amount(&self) -> f32 {
+        match self {
+            WeatherKind::Clear => 0.0,
+            WeatherKind::Drizzle => 0.25,
+            WeatherKind::Rain => 0.6,
+            WeatherKind::Storm => 1.0,
+        }
+    }
+}
+
+// Animates rain and surface wetness towards the selected weather
+pub struct Weather {
+    pub kind: WeatherKind,
+
+    rain: f32,
+    wetness: f32,
+}
+
+impl Weather {
+    pub fn new() -> Weather {
+        Weather {
+            kind: WeatherKind::Clear,
+            rain: 0.0,
+            wetness: 0.0,
+        }
+    }
+
+    // `dt` - seconds since the previous update
+    pub fn update(&mut self, dt: f32) {
+
+        self.rain = approach(self.rain, self.kind.rain_amount(), RAIN_TRANSITION_SPEED * dt);
+
+        let wetness_target = (self.rain * 1.5).min(1.0);
+        let speed = if wetness_target > self.wetness { WETTING_SPEED * (0.5 + self.rain) } else { DRYING_SPEED };
+        self.wetness = approach(self.wetness, wetness_target, speed * dt);
+    }
+
+    pub fn rain(&self) -> f32 {
+        self.rain
+    }
+
+    pub fn wetness(&self) -> f32 {
+        self.wetness
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        egui::ComboBox::from_label("Weather")
+            .selected_text(format!("{:?}", self.kind))
+            .show_ui(ui, |ui| {
+                for kind in WeatherKind::ALL.iter() {
+                    ui.selectable_value(&mut self.kind, *kind, format!("{:?}", kind));
+                }
+            });
+
+        ui.label(format!("Rain: {:.2}, wetness: {:.2}", self.rain, self.wetness));
+    }
+}
+
+fn approach(value: f32, target: f32, step: f32) -> f32 {
+    if value < target {
+        (value + step).min(target)
+    } else {
+        (value - step).max(target)
+    }
+}
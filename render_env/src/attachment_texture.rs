@@ -1,22 +1,47 @@
 use std::ptr;
+use std::sync::Arc;
 
 use ash::version::DeviceV1_0;
 use ash::vk;
 
+use crate::barrier::{AccessPattern, ImageLayoutState};
 use crate::env::RenderEnv;
-use crate::utils::format_has_depth;
+use crate::memory_tracker::{self, MemoryCategory};
+use crate::object_tracker::{self, ObjectKind};
+use crate::transient_pool::{PassRange, TransientMemory};
+use crate::utils::{format_has_depth, format_has_stencil};
+
+enum ImageMemory {
+    Dedicated(vk::DeviceMemory),
+    // aliased with other attachments, see TransientAttachmentPool
+    Transient(Arc<TransientMemory>),
+}
 
 pub struct AttachmentImage {
     device: ash::Device,
-    memory: vk::DeviceMemory,
+    memory: ImageMemory,
     image: vk::Image,
     pub view: vk::ImageView,
     pub format: vk::Format,
+    pub samples: vk::SampleCountFlags,
+    mip_levels: u32,
+    pub layout: ImageLayoutState,
 }
 
 impl AttachmentImage {
     pub fn new(env: &RenderEnv, size: [u32; 2], format: vk::Format, mip_levels: u32,
                samples: vk::SampleCountFlags, usage: vk::ImageUsageFlags) -> AttachmentImage {
+        Self::create(env, size, format, mip_levels, samples, usage, None)
+    }
+
+    // Image with memory from the transient pool of the env, used only in `lifetime` passes of a frame
+    pub fn new_transient(env: &RenderEnv, size: [u32; 2], format: vk::Format, mip_levels: u32,
+                         samples: vk::SampleCountFlags, usage: vk::ImageUsageFlags, lifetime: PassRange) -> AttachmentImage {
+        Self::create(env, size, format, mip_levels, samples, usage, Some(lifetime))
+    }
+
+    fn create(env: &RenderEnv, size: [u32; 2], format: vk::Format, mip_levels: u32,
+              samples: vk::SampleCountFlags, usage: vk::ImageUsageFlags, lifetime: Option<PassRange>) -> AttachmentImage {
         let image_create_info = vk::ImageCreateInfo {
             s_type: vk::StructureType::IMAGE_CREATE_INFO,
             p_next: ptr::null(),
@@ -44,30 +69,42 @@ impl AttachmentImage {
                 .create_image(&image_create_info, None)
                 .expect("Failed to create Texture Image!")
         };
+        object_tracker::created(ObjectKind::Image);
 
         let image_memory_requirement =
             unsafe { env.device().get_image_memory_requirements(texture_image) };
 
-        let memory_allocate_info = vk::MemoryAllocateInfo {
-            s_type: vk::StructureType::MEMORY_ALLOCATE_INFO,
-            p_next: ptr::null(),
-            allocation_size: image_memory_requirement.size,
-            memory_type_index: env.find_memory_type(
-                image_memory_requirement.memory_type_bits,
-                vk::MemoryPropertyFlags::DEVICE_LOCAL,
-            ),
-        };
+        let memory = match lifetime {
+            Some(lifetime) => ImageMemory::Transient(env.transient_attachments().allocate(env, image_memory_requirement, lifetime)),
+            None => {
+                let memory_allocate_info = vk::MemoryAllocateInfo {
+                    s_type: vk::StructureType::MEMORY_ALLOCATE_INFO,
+                    p_next: ptr::null(),
+                    allocation_size: image_memory_requirement.size,
+                    memory_type_index: env.find_memory_type(
+                        image_memory_requirement.memory_type_bits,
+                        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                    ),
+                };
 
-        let texture_image_memory = unsafe {
-            env.device()
-                .allocate_memory(&memory_allocate_info, None)
-                .expect("Failed to allocate Texture Image memory!")
+                let texture_image_memory = unsafe {
+                    env.device()
+                        .allocate_memory(&memory_allocate_info, None)
+                        .expect("Failed to allocate Texture Image memory!")
+                };
+                memory_tracker::allocated(texture_image_memory, image_memory_requirement.size, MemoryCategory::RenderTargets);
+                ImageMemory::Dedicated(texture_image_memory)
+            }
+        };
+        let bound_memory = match &memory {
+            ImageMemory::Dedicated(memory) => *memory,
+            ImageMemory::Transient(memory) => memory.memory(),
         };
 
         unsafe {
             env
                 .device()
-                .bind_image_memory(texture_image, texture_image_memory, 0)
+                .bind_image_memory(texture_image, bound_memory, 0)
                 .expect("Failed to bind Image Memmory!");
         }
 
@@ -105,13 +142,57 @@ impl AttachmentImage {
                 .create_image_view(&imageview_create_info, None)
                 .expect("Failed to create Image View!")
         };
+        object_tracker::created(ObjectKind::ImageView);
 
         AttachmentImage {
             device: env.device().clone(),
-            memory: texture_image_memory,
+            memory,
             image: texture_image,
             view: image_view,
             format,
+            samples,
+            mip_levels,
+            layout: ImageLayoutState::new(AccessPattern::Undefined),
+        }
+    }
+
+    // Layout the image is sampled in by shaders
+    pub fn sampled_pattern(&self) -> AccessPattern {
+        if format_has_depth(self.format) {
+            AccessPattern::DepthSampledRead
+        } else {
+            AccessPattern::SampledRead
+        }
+    }
+
+    pub fn subresource_range(&self) -> vk::ImageSubresourceRange {
+        let aspect_mask = if format_has_stencil(self.format) {
+            vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+        } else if format_has_depth(self.format) {
+            vk::ImageAspectFlags::DEPTH
+        } else {
+            vk::ImageAspectFlags::COLOR
+        };
+
+        vk::ImageSubresourceRange {
+            aspect_mask,
+            base_mip_level: 0,
+            level_count: self.mip_levels,
+            base_array_layer: 0,
+            layer_count: 1,
+        }
+    }
+
+    #[inline]
+    pub fn image(&self) -> vk::Image {
+        self.image
+    }
+
+    // Passes the content is valid in, None when the image has its own memory
+    pub fn transient_lifetime(&self) -> Option<PassRange> {
+        match &self.memory {
+            ImageMemory::Dedicated(_) => None,
+            ImageMemory::Transient(memory) => Some(memory.lifetime()),
         }
     }
 }
@@ -120,8 +201,14 @@ impl Drop for AttachmentImage {
     fn drop(&mut self) {
         unsafe {
             self.device.destroy_image_view(self.view, None);
+            object_tracker::destroyed(ObjectKind::ImageView);
             self.device.destroy_image(self.image, None);
-            self.device.free_memory(self.memory, None);
+            object_tracker::destroyed(ObjectKind::Image);
+            // transient memory is freed with its last image
+            if let ImageMemory::Dedicated(memory) = self.memory {
+                self.device.free_memory(memory, None);
+                memory_tracker::freed(memory);
+            }
         }
     }
 }
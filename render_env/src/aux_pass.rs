@@ -0,0 +1,332 @@
+use std::ptr;
+use std::sync::Arc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use crate::attachment_texture::AttachmentImage;
+use crate::descriptor_set::DescriptorSet;
+use crate::env::RenderEnv;
+use crate::error::RenderResult;
+use crate::frame_buffer::{AttachmentDesciption, Framebuffer};
+use crate::pipeline_builder::{Pipeline, PipelineBuilder};
+use crate::primary_cmd_buffer::PrimaryCommandBuffer;
+use crate::shader::Shader;
+use crate::utils::format_has_depth;
+
+// Engine passes of the frame, an auxiliary pass is executed right after the one it is attached to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EnginePass {
+    Shadow,
+    Geometry,
+    Composite,
+    Bloom,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuxPassSize {
+    // size of the window
+    Screen,
+    // part of the window size, 0.5 - half resolution
+    Scaled(f32),
+    Fixed([u32; 2]),
+}
+
+impl AuxPassSize {
+    fn dimensions(&self, screen: [u32; 2]) -> [u32; 2] {
+        match *self {
+            AuxPassSize::Screen => screen,
+            AuxPassSize::Scaled(scale) => [
+                ((screen[0] as f32 * scale) as u32).max(1),
+                ((screen[1] as f32 * scale) as u32).max(1),
+            ],
+            AuxPassSize::Fixed(dimensions) => dimensions,
+        }
+    }
+}
+
+pub struct AuxPassDesc {
+    pub name: String,
+    pub after: EnginePass,
+    pub size: AuxPassSize,
+    // color attachments and an optional depth attachment, all of them can be sampled by later passes
+    pub attachments: Vec<AttachmentDesciption>,
+    // full-screen triangle is drawn when the vertex shader has no vertex input
+    pub vertex_shader: Shader,
+    pub fragment_shader: Shader,
+    pub vertex_count: u32,
+}
+
+// User defined offscreen pass: its own render pass, attachments and pipeline.
+// Inputs are combined image samplers of descriptor set 0 in binding order, nothing is bound until set_inputs()
+pub struct AuxPass {
+    name: String,
+    after: EnginePass,
+    size: AuxPassSize,
+    vertex_count: u32,
+
+    framebuffer: Framebuffer,
+    pipeline: Pipeline,
+    descriptor_set: Option<DescriptorSet>,
+    second_buffer: vk::CommandBuffer,
+    draw_command: PrimaryCommandBuffer,
+    clear_values: Vec<vk::ClearValue>,
+
+    env: Arc<RenderEnv>,
+}
+
+impl AuxPass {
+    pub fn new(env: Arc<RenderEnv>, desc: AuxPassDesc, screen_dimensions: [u32; 2], max_inflight_frames: usize) -> RenderResult<AuxPass> {
+        let has_depth = desc.attachments.iter().any(|attachment| format_has_depth(attachment.format));
+        let color_count = desc.attachments.len() - has_depth as usize;
+        let samples = desc.attachments.first()
+            .expect("Aux pass must have at least one attachment!")
+            .samples_count;
+
+        let clear_values = desc.attachments.iter()
+            .map(|attachment| if format_has_depth(attachment.format) {
+                vk::ClearValue {
+                    depth_stencil: vk::ClearDepthStencilValue {
+                        depth: 1.0,
+                        stencil: 0,
+                    }
+                }
+            } else {
+                vk::ClearValue {
+                    color: vk::ClearColorValue {
+                        float32: [0.0, 0.0, 0.0, 0.0],
+                    },
+                }
+            })
+            .collect();
+
+        let dimensions = desc.size.dimensions(screen_dimensions);
+        let mut framebuffer = Framebuffer::new(env.clone(), desc.attachments);
+        framebuffer.set_name(&desc.name);
+        framebuffer.resize_swapchain(dimensions);
+
+        let mut pipeline_builder = PipelineBuilder::new(env.device().clone(), framebuffer.render_pass(), 0)
+            .pipeline_cache(env.pipeline_cache())
+            .vertex_shader(desc.vertex_shader)
+            .fragment_shader(desc.fragment_shader)
+            .color_attachment_count(color_count)
+            .msaa(samples);
+        if has_depth {
+            pipeline_builder = pipeline_builder.with_depth_test();
+        }
+        let pipeline = pipeline_builder.build()?;
+
+        let mut draw_command = PrimaryCommandBuffer::new(env.clone(), max_inflight_frames);
+        draw_command.set_name(&format!("{} pass", desc.name));
+        draw_command.set_dimensions(dimensions);
+
+        let mut aux_pass = AuxPass {
+            name: desc.name,
+            after: desc.after,
+            size: desc.size,
+            vertex_count: desc.vertex_count,
+            framebuffer,
+            pipeline,
+            descriptor_set: None,
+            second_buffer: vk::CommandBuffer::null(),
+            draw_command,
+            clear_values,
+            env,
+        };
+        aux_pass.second_buffer = aux_pass.build_cmd_buf();
+
+        Ok(aux_pass)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn after(&self) -> EnginePass {
+        self.after
+    }
+
+    pub fn dimensions(&self) -> [u32; 2] {
+        self.framebuffer.dimensions()
+    }
+
+    // Attachments are recreated on resize, views taken from here must be updated after that
+    pub fn output(&self, idx: usize) -> &AttachmentImage {
+        &self.framebuffer.attachments[idx]
+    }
+
+    // Device must be idle. Engine attachments are recreated on resize too, so inputs
+    // referencing them must be set again after resize(). Inputs must match the bindings of set 0
+    pub fn set_inputs(&mut self, inputs: &[(vk::ImageView, vk::Sampler)]) -> RenderResult<()> {
+        self.descriptor_set = match self.pipeline.descriptor_set_layouts.first() {
+            Some(layout) => {
+                let mut builder = DescriptorSet::builder(self.env.device(), layout);
+                for &(view, sampler) in inputs.iter() {
+                    builder.add_image(view, sampler);
+                }
+                Some(builder.build()?)
+            }
+            None => None,
+        };
+        self.rebuild();
+
+        Ok(())
+    }
+
+    // Device must be idle
+    pub fn resize(&mut self, screen_dimensions: [u32; 2]) {
+        let dimensions = self.size.dimensions(screen_dimensions);
+        if dimensions == self.framebuffer.dimensions() {
+            return;
+        }
+
+        self.framebuffer.resize_swapchain(dimensions);
+        self.draw_command.set_dimensions(dimensions);
+        self.rebuild();
+    }
+
+    // Primary command buffer of the pass for the current frame
+    pub fn draw(&mut self) -> vk::CommandBuffer {
+        self.draw_command.execute_secondary(
+            self.clear_values.clone(),
+            self.framebuffer.framebuffer.unwrap(),
+            self.framebuffer.render_pass(),
+            &[self.second_buffer],
+        )
+    }
+
+    // Command buffer binds the current descriptor set, it is recorded again when the set or size changes
+    fn rebuild(&mut self) {
+        if self.second_buffer != vk::CommandBuffer::null() {
+            unsafe {
+                self.env.device().free_command_buffers(self.env.command_pool(), &[self.second_buffer]);
+            }
+        }
+        self.second_buffer = self.build_cmd_buf();
+    }
+
+    fn build_cmd_buf(&self) -> vk::CommandBuffer {
+        let device = self.env.device();
+        let cmd_buf = self.env.create_secondary_command_buffer();
+        let dimensions = self.framebuffer.dimensions();
+
+        let inheritance_info = vk::CommandBufferInheritanceInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_INHERITANCE_INFO,
+            p_next: ptr::null(),
+            render_pass: self.framebuffer.render_pass(),
+            subpass: 0,
+            framebuffer: vk::Framebuffer::null(),
+            occlusion_query_enable: 0,
+            query_flags: Default::default(),
+            pipeline_statistics: Default::default(),
+        };
+
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_BEGIN_INFO,
+            p_next: ptr::null(),
+            p_inheritance_info: &inheritance_info,
+            flags: vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE | vk::CommandBufferUsageFlags::SIMULTANEOUS_USE,
+        };
+
+        let viewports = [vk::Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: dimensions[0] as f32,
+            height: dimensions[1] as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        }];
+
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: vk::Extent2D {
+                width: dimensions[0],
+                height: dimensions[1],
+            },
+        }];
+
+        unsafe {
+            device
+                .begin_command_buffer(cmd_buf, &command_buffer_begin_info)
+                .expect("Failed to begin recording Command Buffer at beginning!");
+
+            device.cmd_set_viewport(cmd_buf, 0, viewports.as_ref());
+            device.cmd_set_scissor(cmd_buf, 0, scissors.as_ref());
+            device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::GRAPHICS, self.pipeline.graphics_pipeline);
+
+            if let Some(descriptor_set) = &self.descriptor_set {
+                descriptor_set.bind(cmd_buf, vk::PipelineBindPoint::GRAPHICS, self.pipeline.pipeline_layout, 0, &[]);
+            }
+
+            device.cmd_draw(cmd_buf, self.vertex_count, 1, 0, 0);
+
+            device
+                .end_command_buffer(cmd_buf)
+                .expect("Failed to record Command Buffer at Ending!");
+        }
+
+        cmd_buf
+    }
+}
+
+impl Drop for AuxPass {
+    fn drop(&mut self) {
+        unsafe {
+            self.env.device().free_command_buffers(self.env.command_pool(), &[self.second_buffer]);
+        }
+        self.framebuffer.destroy();
+    }
+}
+
+// Auxiliary passes of the app, executed in registration order after their engine pass
+pub struct AuxPasses {
+    passes: Vec<AuxPass>,
+}
+
+impl AuxPasses {
+    pub fn new() -> AuxPasses {
+        AuxPasses {
+            passes: vec![],
+        }
+    }
+
+    pub fn register(&mut self, pass: AuxPass) {
+        if self.get(pass.name()).is_some() {
+            panic!("Aux pass {} is already registered", pass.name());
+        }
+        self.passes.push(pass);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&AuxPass> {
+        self.passes.iter().find(|pass| pass.name() == name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut AuxPass> {
+        self.passes.iter_mut().find(|pass| pass.name() == name)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.passes.is_empty()
+    }
+
+    // Device must be idle
+    pub fn resize(&mut self, screen_dimensions: [u32; 2]) {
+        for pass in self.passes.iter_mut() {
+            pass.resize(screen_dimensions);
+        }
+    }
+
+    // Command buffers to submit right after `after` pass
+    pub fn draw(&mut self, after: EnginePass) -> Vec<vk::CommandBuffer> {
+        self.passes.iter_mut()
+            .filter(|pass| pass.after() == after)
+            .map(|pass| pass.draw())
+            .collect()
+    }
+}
+
+impl Default for AuxPasses {
+    fn default() -> Self {
+        Self::new()
+    }
+}
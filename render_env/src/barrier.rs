@@ -0,0 +1,207 @@
+use std::ptr;
+use std::sync::Mutex;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+// How a resource is used on one side of a barrier: pipeline stage, memory access and image layout together
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AccessPattern {
+    // previous contents are not needed
+    Undefined,
+    TransferRead,
+    TransferWrite,
+    ColorWrite,
+    DepthWrite,
+    // sampled in fragment shader
+    SampledRead,
+    // depth attachment sampled in fragment shader (shadow maps)
+    DepthSampledRead,
+    VertexRead,
+    UniformRead,
+    HostRead,
+    Present,
+}
+
+impl AccessPattern {
+    fn stage(&self) -> vk::PipelineStageFlags {
+        match self {
+            AccessPattern::Undefined => vk::PipelineStageFlags::TOP_OF_PIPE,
+            AccessPattern::TransferRead | AccessPattern::TransferWrite => vk::PipelineStageFlags::TRANSFER,
+            AccessPattern::ColorWrite => vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            AccessPattern::DepthWrite => vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            AccessPattern::SampledRead | AccessPattern::DepthSampledRead => vk::PipelineStageFlags::FRAGMENT_SHADER,
+            AccessPattern::VertexRead => vk::PipelineStageFlags::VERTEX_INPUT,
+            AccessPattern::UniformRead => vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER,
+            AccessPattern::HostRead => vk::PipelineStageFlags::HOST,
+            AccessPattern::Present => vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+        }
+    }
+
+    fn access(&self) -> vk::AccessFlags {
+        match self {
+            AccessPattern::Undefined | AccessPattern::Present => vk::AccessFlags::empty(),
+            AccessPattern::TransferRead => vk::AccessFlags::TRANSFER_READ,
+            AccessPattern::TransferWrite => vk::AccessFlags::TRANSFER_WRITE,
+            AccessPattern::ColorWrite => vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            AccessPattern::DepthWrite => vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            AccessPattern::SampledRead | AccessPattern::DepthSampledRead => vk::AccessFlags::SHADER_READ,
+            AccessPattern::VertexRead => vk::AccessFlags::VERTEX_ATTRIBUTE_READ | vk::AccessFlags::INDEX_READ,
+            AccessPattern::UniformRead => vk::AccessFlags::UNIFORM_READ,
+            AccessPattern::HostRead => vk::AccessFlags::HOST_READ,
+        }
+    }
+
+    // None for patterns which make no sense for images
+    pub fn image_layout(&self) -> Option<vk::ImageLayout> {
+        match self {
+            AccessPattern::Undefined => Some(vk::ImageLayout::UNDEFINED),
+            AccessPattern::TransferRead => Some(vk::ImageLayout::TRANSFER_SRC_OPTIMAL),
+            AccessPattern::TransferWrite => Some(vk::ImageLayout::TRANSFER_DST_OPTIMAL),
+            AccessPattern::ColorWrite => Some(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
+            AccessPattern::DepthWrite => Some(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL),
+            AccessPattern::SampledRead => Some(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+            AccessPattern::DepthSampledRead => Some(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL),
+            AccessPattern::Present => Some(vk::ImageLayout::PRESENT_SRC_KHR),
+            AccessPattern::VertexRead | AccessPattern::UniformRead | AccessPattern::HostRead => None,
+        }
+    }
+
+    fn is_buffer_access(&self) -> bool {
+        matches!(self, AccessPattern::TransferRead | AccessPattern::TransferWrite | AccessPattern::VertexRead |
+            AccessPattern::UniformRead | AccessPattern::HostRead)
+    }
+}
+
+pub fn cmd_image_barrier(device: &ash::Device, command_buffer: vk::CommandBuffer, image: vk::Image,
+                         subresource_range: vk::ImageSubresourceRange, from: AccessPattern, to: AccessPattern) {
+    let old_layout = from.image_layout()
+        .unwrap_or_else(|| panic!("{:?} is not an image access", from));
+    let new_layout = to.image_layout()
+        .unwrap_or_else(|| panic!("{:?} is not an image access", to));
+    debug_assert!(to != AccessPattern::Undefined, "Image can't be transitioned to undefined layout");
+
+    let image_barriers = [
+        vk::ImageMemoryBarrier {
+            s_type: vk::StructureType::IMAGE_MEMORY_BARRIER,
+            p_next: ptr::null(),
+            src_access_mask: from.access(),
+            dst_access_mask: to.access(),
+            old_layout,
+            new_layout,
+            src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+            dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+            image,
+            subresource_range,
+        }
+    ];
+
+    unsafe {
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            from.stage(),
+            to.stage(),
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &image_barriers,
+        );
+    }
+}
+
+// Barrier for the whole buffer
+pub fn cmd_buffer_barrier(device: &ash::Device, command_buffer: vk::CommandBuffer, buffer: vk::Buffer,
+                          from: AccessPattern, to: AccessPattern) {
+    if !from.is_buffer_access() || !to.is_buffer_access() {
+        panic!("{:?} -> {:?} is not a buffer access", from, to);
+    }
+
+    let buffer_barriers = [
+        vk::BufferMemoryBarrier {
+            s_type: vk::StructureType::BUFFER_MEMORY_BARRIER,
+            p_next: ptr::null(),
+            src_access_mask: from.access(),
+            dst_access_mask: to.access(),
+            src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+            dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+            buffer,
+            offset: 0,
+            size: vk::WHOLE_SIZE,
+        }
+    ];
+
+    unsafe {
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            from.stage(),
+            to.stage(),
+            vk::DependencyFlags::empty(),
+            &[],
+            &buffer_barriers,
+            &[],
+        );
+    }
+}
+
+// Color subresource range over `mip_levels` starting from `base_mip_level`
+pub fn color_range(base_mip_level: u32, mip_levels: u32, layer_count: u32) -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        base_mip_level,
+        level_count: mip_levels,
+        base_array_layer: 0,
+        layer_count,
+    }
+}
+
+// Layout of an image as of the last recorded command touching it.
+// Lets wrappers check the layout they are bound with and insert transitions only when needed.
+pub struct ImageLayoutState {
+    // Mutex keeps textures shareable between threads
+    current: Mutex<AccessPattern>,
+}
+
+impl ImageLayoutState {
+    pub fn new(initial: AccessPattern) -> ImageLayoutState {
+        debug_assert!(initial.image_layout().is_some(), "{:?} is not an image access", initial);
+
+        ImageLayoutState {
+            current: Mutex::new(initial),
+        }
+    }
+
+    #[inline]
+    pub fn current(&self) -> AccessPattern {
+        *self.current.lock().unwrap()
+    }
+
+    #[inline]
+    pub fn layout(&self) -> vk::ImageLayout {
+        self.current().image_layout().unwrap()
+    }
+
+    // For transitions done implicitly, e.g. by render pass final layout
+    pub fn set(&self, pattern: AccessPattern) {
+        debug_assert!(pattern.image_layout().is_some(), "{:?} is not an image access", pattern);
+        *self.current.lock().unwrap() = pattern;
+    }
+
+    // Records a barrier to `to` unless the image is already there
+    pub fn transition(&self, device: &ash::Device, command_buffer: vk::CommandBuffer, image: vk::Image,
+                      subresource_range: vk::ImageSubresourceRange, to: AccessPattern) {
+        let mut current = self.current.lock().unwrap();
+        if *current != to {
+            cmd_image_barrier(device, command_buffer, image, subresource_range, *current, to);
+            *current = to;
+        }
+    }
+
+    // Debug check that the image is used in the layout it is in
+    #[inline]
+    pub fn expect(&self, pattern: AccessPattern, usage: &str) {
+        debug_assert!(
+            self.current().image_layout() == pattern.image_layout(),
+            "Image is {} in {:?} layout, but it is in {:?}", usage, pattern.image_layout(), self.layout()
+        );
+    }
+}
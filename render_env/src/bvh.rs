@@ -0,0 +1,566 @@
+use cgmath::{EuclideanSpace, Matrix4, Point3, Transform, Vector3};
+
+// Leaves are not split further below this many items
+const MAX_LEAF_ITEMS: usize = 4;
+// Candidate split planes per axis for the surface area heuristic
+const SAH_BINS: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: Point3<f32>, max: Point3<f32>) -> Aabb {
+        Aabb { min, max }
+    }
+
+    // Union with anything gives the other box
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Point3::new(f32::MAX, f32::MAX, f32::MAX),
+            max: Point3::new(f32::MIN, f32::MIN, f32::MIN),
+        }
+    }
+
+    // (min, max) pair as stored in mesh data
+    pub fn from_bounds(bounds: ([f32; 3], [f32; 3])) -> Aabb {
+        Aabb::new(Point3::from(bounds.0), Point3::from(bounds.1))
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: Point3::new(self.min.x.min(other.min.x), self.min.y.min(other.min.y), self.min.z.min(other.min.z)),
+            max: Point3::new(self.max.x.max(other.max.x), self.max.y.max(other.max.y), self.max.z.max(other.max.z)),
+        }
+    }
+
+    pub fn center(&self) -> Point3<f32> {
+        self.min.midpoint(self.max)
+    }
+
+    // Distance from the point to the closest point of the box, 0 inside
+    pub fn distance(&self, point: Point3<f32>) -> f32 {
+        (0..3)
+            .map(|axis| (self.min[axis] - point[axis]).max(point[axis] - self.max[axis]).max(0.0))
+            .map(|d| d * d)
+            .sum::<f32>()
+            .sqrt()
+    }
+
+    pub fn contains(&self, point: Point3<f32>) -> bool {
+        (0..3).all(|axis| point[axis] >= self.min[axis] && point[axis] <= self.max[axis])
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        (0..3).all(|axis| self.min[axis] <= other.max[axis] && self.max[axis] >= other.min[axis])
+    }
+
+    pub fn corners(&self) -> [Point3<f32>; 8] {
+        let (a, b) = (self.min, self.max);
+        [
+            Point3::new(a.x, a.y, a.z), Point3::new(b.x, a.y, a.z), Point3::new(a.x, b.y, a.z), Point3::new(b.x, b.y, a.z),
+            Point3::new(a.x, a.y, b.z), Point3::new(b.x, a.y, b.z), Point3::new(a.x, b.y, b.z), Point3::new(b.x, b.y, b.z),
+        ]
+    }
+
+    // Box around all 8 transformed corners
+    pub fn transformed(&self, transform: &Matrix4<f32>) -> Aabb {
+        self.corners().iter()
+            .map(|&corner| transform.transform_point(corner))
+            .fold(Aabb::empty(), |bounds, point| bounds.union(&Aabb::new(point, point)))
+    }
+
+    fn surface_area(&self) -> f32 {
+        let size = self.max - self.min;
+        if size.x < 0.0 {
+            return 0.0;
+        }
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+
+    // Distance along the ray to the box entry, 0 when the origin is inside. `inv_dir` is 1 / dir per axis
+    pub fn ray_distance(&self, origin: Point3<f32>, inv_dir: Vector3<f32>, max_distance: f32) -> Option<f32> {
+        let mut t_min = 0.0_f32;
+        let mut t_max = max_distance;
+
+        for axis in 0..3 {
+            let t0 = (self.min[axis] - origin[axis]) * inv_dir[axis];
+            let t1 = (self.max[axis] - origin[axis]) * inv_dir[axis];
+            // NaN of 0 * inf (origin on the slab plane, ray parallel to it) is skipped by min/max
+            t_min = t_min.max(t0.min(t1));
+            t_max = t_max.min(t0.max(t1));
+        }
+
+        if t_min <= t_max {
+            Some(t_min)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BvhNode {
+    pub bounds: Aabb,
+    // leaf: range of Bvh::items, inner node: index of the left child (the right one follows it) and count 0
+    first: u32,
+    count: u32,
+}
+
+impl BvhNode {
+    pub fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+
+    // Indices of the left and right child nodes
+    pub fn children(&self) -> Option<(usize, usize)> {
+        if self.is_leaf() {
+            None
+        } else {
+            Some((self.first as usize, self.first as usize + 1))
+        }
+    }
+}
+
+// Bounding volume hierarchy over items given by their boxes (scene objects, mesh parts), item i is bounds[i].
+// Built with the surface area heuristic, refit() keeps the tree when items move
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    // item indices in leaf order
+    items: Vec<u32>,
+    item_bounds: Vec<Aabb>,
+}
+
+impl Bvh {
+    pub fn new(bounds: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: vec![],
+            items: (0..bounds.len() as u32).collect(),
+            item_bounds: bounds.to_vec(),
+        };
+
+        if !bounds.is_empty() {
+            bvh.nodes.push(BvhNode {
+                bounds: Aabb::empty(),
+                first: 0,
+                count: bounds.len() as u32,
+            });
+            bvh.subdivide(0);
+        }
+
+        bvh
+    }
+
+    pub fn len(&self) -> usize {
+        self.item_bounds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.item_bounds.is_empty()
+    }
+
+    pub fn nodes(&self) -> &[BvhNode] {
+        &self.nodes
+    }
+
+    // Items of a leaf node, empty for inner nodes
+    pub fn node_items(&self, node: &BvhNode) -> &[u32] {
+        &self.items[node.first as usize..(node.first + node.count) as usize]
+    }
+
+    pub fn item_bounds(&self, item: usize) -> Aabb {
+        self.item_bounds[item]
+    }
+
+    // Bounds of everything, None for an empty tree
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.nodes.first().map(|root| root.bounds)
+    }
+
+    fn subdivide(&mut self, node_idx: usize) {
+        let node = self.nodes[node_idx];
+        let range = node.first as usize..(node.first + node.count) as usize;
+
+        let bounds = self.items[range.clone()].iter()
+            .fold(Aabb::empty(), |bounds, &item| bounds.union(&self.item_bounds[item as usize]));
+        self.nodes[node_idx].bounds = bounds;
+
+        if range.len() <= MAX_LEAF_ITEMS {
+            return;
+        }
+
+        let (axis, split) = match self.find_split(range.clone()) {
+            Some(split) => split,
+            None => return,
+        };
+
+        // partition items of the node by centroid
+        let items = &mut self.items[range.clone()];
+        let item_bounds = &self.item_bounds;
+        items.sort_unstable_by(|&a, &b| {
+            let a = item_bounds[a as usize].center()[axis];
+            let b = item_bounds[b as usize].center()[axis];
+            a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal)
+        });
+        let left_count = items.iter()
+            .take_while(|&&item| item_bounds[item as usize].center()[axis] < split)
+            .count();
+        if left_count == 0 || left_count == items.len() {
+            return;
+        }
+
+        let left = self.nodes.len();
+        self.nodes.push(BvhNode {
+            bounds: Aabb::empty(),
+            first: node.first,
+            count: left_count as u32,
+        });
+        self.nodes.push(BvhNode {
+            bounds: Aabb::empty(),
+            first: node.first + left_count as u32,
+            count: node.count - left_count as u32,
+        });
+        self.nodes[node_idx].first = left as u32;
+        self.nodes[node_idx].count = 0;
+
+        self.subdivide(left);
+        self.subdivide(left + 1);
+    }
+
+    // Axis and centroid coordinate of the cheapest binned split, None when no split beats a leaf
+    fn find_split(&self, range: std::ops::Range<usize>) -> Option<(usize, f32)> {
+        let centroids = self.items[range.clone()].iter()
+            .map(|&item| self.item_bounds[item as usize].center())
+            .fold(Aabb::empty(), |bounds, center| bounds.union(&Aabb::new(center, center)));
+
+        let parent_area = self.items[range.clone()].iter()
+            .fold(Aabb::empty(), |bounds, &item| bounds.union(&self.item_bounds[item as usize]))
+            .surface_area();
+        let mut best = (f32::MAX, None);
+
+        for axis in 0..3 {
+            let (lo, hi) = (centroids.min[axis], centroids.max[axis]);
+            if hi - lo <= f32::EPSILON {
+                continue;
+            }
+
+            let mut bins = [(Aabb::empty(), 0_u32); SAH_BINS];
+            for &item in self.items[range.clone()].iter() {
+                let bounds = &self.item_bounds[item as usize];
+                let bin = (((bounds.center()[axis] - lo) / (hi - lo) * SAH_BINS as f32) as usize).min(SAH_BINS - 1);
+                bins[bin].0 = bins[bin].0.union(bounds);
+                bins[bin].1 += 1;
+            }
+
+            for split in 1..SAH_BINS {
+                let (left, right) = bins.split_at(split);
+                let side = |bins: &[(Aabb, u32)]| bins.iter()
+                    .fold((Aabb::empty(), 0), |(bounds, count), (bin_bounds, bin_count)| (bounds.union(bin_bounds), count + bin_count));
+                let (left_bounds, left_count) = side(left);
+                let (right_bounds, right_count) = side(right);
+                if left_count == 0 || right_count == 0 {
+                    continue;
+                }
+
+                let cost = left_bounds.surface_area() * left_count as f32 + right_bounds.surface_area() * right_count as f32;
+                if cost < best.0 {
+                    best = (cost, Some((axis, lo + (hi - lo) * split as f32 / SAH_BINS as f32)));
+                }
+            }
+        }
+
+        // a leaf costs its area times the item count
+        if best.0 >= parent_area * range.len() as f32 {
+            return None;
+        }
+        best.1
+    }
+
+    // New bounds of the same items, e.g. after objects moved: node bounds are updated, the tree is kept.
+    // Rebuild with new() when items are added or removed, or when they moved far enough to make the tree poor
+    pub fn refit(&mut self, bounds: &[Aabb]) {
+        assert_eq!(bounds.len(), self.item_bounds.len(), "Bvh::refit needs the same items, rebuild instead");
+        self.item_bounds.copy_from_slice(bounds);
+
+        // children always follow their parent, so going backwards visits them first
+        for node_idx in (0..self.nodes.len()).rev() {
+            let node = self.nodes[node_idx];
+            self.nodes[node_idx].bounds = match node.children() {
+                Some((left, right)) => self.nodes[left].bounds.union(&self.nodes[right].bounds),
+                None => self.node_items(&node).iter()
+                    .fold(Aabb::empty(), |bounds, &item| bounds.union(&self.item_bounds[item as usize])),
+            };
+        }
+    }
+
+    // Closest item whose box is hit by the ray within `max_distance`, with the distance to the box
+    pub fn raycast(&self, origin: Point3<f32>, dir: Vector3<f32>, max_distance: f32) -> Option<(usize, f32)> {
+        self.raycast_with(origin, dir, max_distance, |_, box_distance| Some(box_distance))
+    }
+
+    // Like raycast(), `hit_item(item, box_distance)` gives the exact hit distance of an item, e.g. from a triangle test
+    pub fn raycast_with<F>(&self, origin: Point3<f32>, dir: Vector3<f32>, max_distance: f32, mut hit_item: F) -> Option<(usize, f32)>
+        where F: FnMut(usize, f32) -> Option<f32>
+    {
+        let inv_dir = Vector3::new(1.0 / dir.x, 1.0 / dir.y, 1.0 / dir.z);
+        let mut closest: Option<(usize, f32)> = None;
+        let mut stack = Vec::with_capacity(32);
+
+        if self.nodes.is_empty() {
+            return None;
+        }
+        stack.push(0);
+
+        while let Some(node_idx) = stack.pop() {
+            let limit = closest.map_or(max_distance, |(_, distance)| distance);
+            let node = &self.nodes[node_idx];
+            if node.bounds.ray_distance(origin, inv_dir, limit).is_none() {
+                continue;
+            }
+
+            match node.children() {
+                Some((left, right)) => {
+                    let left_distance = self.nodes[left].bounds.ray_distance(origin, inv_dir, limit);
+                    let right_distance = self.nodes[right].bounds.ray_distance(origin, inv_dir, limit);
+                    // the nearer child is popped first, so it shrinks the limit for the other one
+                    match (left_distance, right_distance) {
+                        (Some(l), Some(r)) if l <= r => stack.extend([right, left]),
+                        (Some(_), Some(_)) => stack.extend([left, right]),
+                        (Some(_), None) => stack.push(left),
+                        (None, Some(_)) => stack.push(right),
+                        (None, None) => (),
+                    }
+                }
+                None => {
+                    for &item in self.node_items(node) {
+                        let item = item as usize;
+                        let limit = closest.map_or(max_distance, |(_, distance)| distance);
+                        let box_distance = match self.item_bounds[item].ray_distance(origin, inv_dir, limit) {
+                            Some(distance) => distance,
+                            None => continue,
+                        };
+
+                        if let Some(distance) = hit_item(item, box_distance) {
+                            if distance <= limit {
+                                closest = Some((item, distance));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        closest
+    }
+
+    // Calls `visit` for every item whose box passes `test`, subtrees failing it are skipped (frustum or overlap tests)
+    pub fn query<T, V>(&self, test: T, mut visit: V)
+        where T: Fn(&Aabb) -> bool, V: FnMut(usize)
+    {
+        let mut stack = vec![];
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+
+        while let Some(node_idx) = stack.pop() {
+            let node = &self.nodes[node_idx];
+            if !test(&node.bounds) {
+                continue;
+            }
+
+            match node.children() {
+                Some((left, right)) => stack.extend([left, right]),
+                None => {
+                    for &item in self.node_items(node) {
+                        if test(&self.item_bounds[item as usize]) {
+                            visit(item as usize);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Node boxes with their depth (root is 0) down to `max_depth`, for debug drawing
+    pub fn node_boxes(&self, max_depth: usize) -> Vec<(Aabb, usize)> {
+        let mut boxes = vec![];
+        let mut stack = vec![];
+        if !self.nodes.is_empty() {
+            stack.push((0, 0));
+        }
+
+        while let Some((node_idx, depth)) = stack.pop() {
+            let node = &self.nodes[node_idx];
+            boxes.push((node.bounds, depth));
+
+            if let Some((left, right)) = node.children() {
+                if depth < max_depth {
+                    stack.extend([(left, depth + 1), (right, depth + 1)]);
+                }
+            }
+        }
+
+        boxes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Deterministic boxes scattered over a 100 units cube, sizes up to 3 units
+    fn scattered_boxes(count: usize) -> Vec<Aabb> {
+        let mut state = 0x2545_f491_u32;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as f32 / u32::MAX as f32
+        };
+
+        (0..count)
+            .map(|_| {
+                let min = Point3::new(next() * 100.0, next() * 100.0, next() * 100.0);
+                let size = Vector3::new(0.1 + next() * 3.0, 0.1 + next() * 3.0, 0.1 + next() * 3.0);
+                Aabb::new(min, min + size)
+            })
+            .collect()
+    }
+
+    fn brute_force_raycast(bounds: &[Aabb], origin: Point3<f32>, dir: Vector3<f32>, max_distance: f32) -> Option<f32> {
+        let inv_dir = Vector3::new(1.0 / dir.x, 1.0 / dir.y, 1.0 / dir.z);
+        bounds.iter()
+            .filter_map(|bounds| bounds.ray_distance(origin, inv_dir, max_distance))
+            .min_by(|a, b| a.total_cmp(b))
+    }
+
+    // Every item is in exactly one leaf and every node contains the boxes below it
+    fn check_tree(bvh: &Bvh) {
+        let mut seen = vec![false; bvh.len()];
+        for node in bvh.nodes() {
+            match node.children() {
+                Some((left, right)) => {
+                    let children = bvh.nodes()[left].bounds.union(&bvh.nodes()[right].bounds);
+                    assert_eq!(node.bounds.union(&children), node.bounds);
+                }
+                None => {
+                    for &item in bvh.node_items(node) {
+                        assert!(!seen[item as usize], "item {} is in two leaves", item);
+                        seen[item as usize] = true;
+                        assert_eq!(node.bounds.union(&bvh.item_bounds(item as usize)), node.bounds);
+                    }
+                }
+            }
+        }
+        assert!(seen.iter().all(|&seen| seen), "an item is in no leaf");
+    }
+
+    #[test]
+    fn empty_input() {
+        let bvh = Bvh::new(&[]);
+
+        assert!(bvh.is_empty());
+        assert!(bvh.nodes().is_empty());
+        assert_eq!(bvh.bounds(), None);
+        assert_eq!(bvh.raycast(Point3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0), 100.0), None);
+
+        let mut visited = 0;
+        bvh.query(|_| true, |_| visited += 1);
+        assert_eq!(visited, 0);
+    }
+
+    #[test]
+    fn single_leaf() {
+        let bounds = [Aabb::new(Point3::new(1.0, -1.0, -1.0), Point3::new(3.0, 1.0, 1.0))];
+        let bvh = Bvh::new(&bounds);
+
+        assert_eq!(bvh.nodes().len(), 1);
+        assert!(bvh.nodes()[0].is_leaf());
+        assert_eq!(bvh.node_items(&bvh.nodes()[0]), &[0]);
+        assert_eq!(bvh.bounds(), Some(bounds[0]));
+
+        let origin = Point3::new(0.0, 0.0, 0.0);
+        assert_eq!(bvh.raycast(origin, Vector3::new(1.0, 0.0, 0.0), 100.0), Some((0, 1.0)));
+        assert_eq!(bvh.raycast(origin, Vector3::new(-1.0, 0.0, 0.0), 100.0), None);
+        assert_eq!(bvh.raycast(origin, Vector3::new(1.0, 0.0, 0.0), 0.5), None);
+    }
+
+    #[test]
+    fn splits_many_items() {
+        let bounds = scattered_boxes(500);
+        let bvh = Bvh::new(&bounds);
+
+        assert!(bvh.nodes().len() > 1);
+        assert!(bvh.nodes().iter().filter(|node| node.is_leaf()).all(|node| bvh.node_items(node).len() <= MAX_LEAF_ITEMS));
+        check_tree(&bvh);
+    }
+
+    #[test]
+    fn refit_after_move() {
+        let mut bounds = scattered_boxes(200);
+        let mut bvh = Bvh::new(&bounds);
+        let node_count = bvh.nodes().len();
+
+        let offset = Vector3::new(500.0, 0.0, 0.0);
+        bounds[17] = Aabb::new(bounds[17].min + offset, bounds[17].max + offset);
+        bvh.refit(&bounds);
+
+        assert_eq!(bvh.nodes().len(), node_count);
+        assert_eq!(bvh.item_bounds(17), bounds[17]);
+        assert!(bvh.bounds().unwrap().contains(bounds[17].center()));
+        check_tree(&bvh);
+
+        // the moved item is found at its new place only
+        let origin = Point3::new(400.0, bounds[17].center().y, bounds[17].center().z);
+        assert_eq!(bvh.raycast(origin, Vector3::new(1.0, 0.0, 0.0), 1000.0).map(|(item, _)| item), Some(17));
+        let old_center = bounds[17].center() - offset;
+        let mut found = vec![];
+        bvh.query(|bounds| bounds.contains(old_center), |item| found.push(item));
+        assert!(!found.contains(&17));
+    }
+
+    #[test]
+    #[should_panic]
+    fn refit_with_other_items() {
+        let mut bvh = Bvh::new(&scattered_boxes(10));
+        bvh.refit(&scattered_boxes(11));
+    }
+
+    #[test]
+    fn raycast_matches_brute_force() {
+        let bounds = scattered_boxes(300);
+        let bvh = Bvh::new(&bounds);
+
+        let rays = [
+            (Point3::new(-10.0, 50.0, 50.0), Vector3::new(1.0, 0.0, 0.0)),
+            (Point3::new(50.0, -10.0, 50.0), Vector3::new(0.0, 1.0, 0.0)),
+            (Point3::new(-10.0, -10.0, -10.0), Vector3::new(1.0, 1.0, 1.0)),
+            (Point3::new(50.0, 50.0, 50.0), Vector3::new(-0.3, 0.7, 0.2)),
+            (Point3::new(120.0, 20.0, 80.0), Vector3::new(-1.0, 0.1, -0.4)),
+            (Point3::new(30.0, 70.0, -5.0), Vector3::new(0.05, -0.02, 1.0)),
+            // misses: pointing away from the boxes, or passing beside them
+            (Point3::new(-10.0, 50.0, 50.0), Vector3::new(-1.0, 0.0, 0.0)),
+            (Point3::new(-10.0, 200.0, 50.0), Vector3::new(1.0, 0.0, 0.0)),
+        ];
+
+        let mut hits = 0;
+        for &(origin, dir) in rays.iter() {
+            for &max_distance in [1000.0, 30.0].iter() {
+                let expected = brute_force_raycast(&bounds, origin, dir, max_distance);
+                let result = bvh.raycast(origin, dir, max_distance);
+                assert_eq!(result.map(|(_, distance)| distance), expected, "ray from {:?} along {:?}", origin, dir);
+
+                if let Some((item, distance)) = result {
+                    let inv_dir = Vector3::new(1.0 / dir.x, 1.0 / dir.y, 1.0 / dir.z);
+                    assert_eq!(bounds[item].ray_distance(origin, inv_dir, max_distance), Some(distance));
+                    hits += 1;
+                }
+            }
+        }
+        assert!(hits > 0);
+        assert!(bvh.raycast(rays[6].0, rays[6].1, 1000.0).is_none());
+        assert!(bvh.raycast(rays[7].0, rays[7].1, 1000.0).is_none());
+    }
+}
@@ -1,17 +1,123 @@
 use std::f32;
 
-use cgmath::{Deg, EuclideanSpace, Matrix4, Point3, SquareMatrix, vec3, Vector3};
+use cgmath::{Deg, EuclideanSpace, Matrix4, Point3, SquareMatrix, vec3, Vector3, Vector4};
 use cgmath::{Angle, Rad};
 use cgmath::InnerSpace;
-use winit::event::{ElementState, KeyboardInput, MouseButton, VirtualKeyCode, WindowEvent};
+use winit::event::{ElementState, KeyboardInput, VirtualKeyCode, WindowEvent};
+
+use crate::culling::Frustum;
+use crate::utils::DepthMode;
+
+// Camera state of one frame, taken once and shared by all passes so they see the same matrices
+#[derive(Copy, Clone, Debug)]
+pub struct CameraDescriptor {
+    pub view: Matrix4<f32>,
+    // with the jitter of set_jitter()
+    pub proj: Matrix4<f32>,
+    pub view_proj: Matrix4<f32>,
+    pub inv_view_proj: Matrix4<f32>,
+    // without the jitter, this and the previous frame: motion vectors don't shake with it
+    pub unjittered_view_proj: Matrix4<f32>,
+    pub prev_view_proj: Matrix4<f32>,
+    // offset of the projection in NDC
+    pub jitter: [f32; 2],
+    // view without translation, for the skybox
+    pub rotation_view: Matrix4<f32>,
+
+    pub position: Point3<f32>,
+    pub view_dir: Vector3<f32>,
+    pub near: f32,
+    pub far: f32,
+    pub aspect: f32,
+    pub depth_mode: DepthMode,
+
+    pub frustum: Frustum,
+}
+
+
+// Movement and look settings, speeds are per second so movement doesn't depend on frame or key repeat rate
+#[derive(Copy, Clone, Debug)]
+pub struct CameraSettings {
+    // meters per second, `slow_factor` of it with H/J
+    pub speed: f32,
+    pub slow_factor: f32,
+    // meters per second squared, velocity change towards the pressed keys
+    pub acceleration: f32,
+    // degrees per pixel of mouse drag
+    pub mouse_sensitivity: f32,
+    // time constant of the look filter in seconds, 0 turns the camera right away
+    pub smoothing: f32,
+    // degrees per second at full deflection of a look stick
+    pub stick_look_speed: f32,
+}
+
+impl Default for CameraSettings {
+    fn default() -> CameraSettings {
+        CameraSettings {
+            speed: 4.0,
+            slow_factor: 0.1,
+            acceleration: 20.0,
+            mouse_sensitivity: 0.5,
+            smoothing: 0.05,
+            stick_look_speed: 120.0,
+        }
+    }
+}
+
+// Movement keys held down
+#[derive(Copy, Clone, Default)]
+struct MoveKeys {
+    forward: bool,
+    back: bool,
+    slow_forward: bool,
+    slow_back: bool,
+    left: bool,
+    right: bool,
+    up: bool,
+    down: bool,
+}
+
+impl MoveKeys {
+    fn axis(positive: bool, negative: bool) -> f32 {
+        positive as i32 as f32 - negative as i32 as f32
+    }
+}
+
+// Analog input of gamepads and alike, merged with keys and mouse by update(). Axes go from -1 to 1,
+// partial deflection moves and turns slower
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CameraInput {
+    // right, up and forward, the same directions as D, Space and W
+    pub movement: Vector3<f32>,
+    // yaw and pitch rates, the same directions as a mouse drag right and down
+    pub look: [f32; 2],
+}
+
+impl Default for CameraInput {
+    fn default() -> Self {
+        CameraInput {
+            movement: Vector3::new(0.0, 0.0, 0.0),
+            look: [0.0, 0.0],
+        }
+    }
+}
 
 pub struct Camera {
+    pub settings: CameraSettings,
+
     position: Point3<f32>,
+    velocity: Vector3<f32>,
     proj: Matrix4<f32>,
+    // current angles and the ones the mouse asked for, they meet with smoothing
     yaw: f32,
     pitch: f32,
+    target_yaw: f32,
+    target_pitch: f32,
 
-    mouse_pressed: bool,
+    keys: MoveKeys,
+    // held until the next handle_input()
+    input: CameraInput,
+    mouse_captured: bool,
     last_mouse_position: [i32; 2],
 
     view_dir: Vector3<f32>,
@@ -19,35 +125,68 @@ pub struct Camera {
 
     viewport: [u32; 2],
 
-    pub near_clip: f32,
-    pub far_clip: f32,
+    near_clip: f32,
+    far_clip: f32,
+    depth_mode: DepthMode,
+
+    jitter: [f32; 2],
+    // unjittered view projection of the previous frame, see end_frame()
+    prev_view_proj: Option<Matrix4<f32>>,
 }
 
 impl Camera {
     pub fn new() -> Camera {
         Camera {
+            settings: CameraSettings::default(),
             position: Point3::new(0.0, -0.4, 0.0),
+            velocity: vec3(0.0, 0.0, 0.0),
             proj: Matrix4::identity(),
-            mouse_pressed: false,
+            keys: MoveKeys::default(),
+            input: CameraInput::default(),
+            mouse_captured: false,
             last_mouse_position: [0, 0],
             viewport: [0, 0],
             view_dir: vec3(0.0, 0.0, -1.0),
             up_dir: vec3(0.0, 1.0, 0.0),
             yaw: -90.0,
             pitch: 0.0,
+            target_yaw: -90.0,
+            target_pitch: 0.0,
             near_clip: 0.05,
             far_clip: 48.0,
+            depth_mode: DepthMode::Standard,
+            jitter: [0.0, 0.0],
+            prev_view_proj: None,
         }
     }
 
+    pub fn set_depth_mode(&mut self, depth_mode: DepthMode) {
+        self.depth_mode = depth_mode;
+        self.set_viewport(self.viewport[0], self.viewport[1]);
+    }
+
+    pub fn depth_mode(&self) -> DepthMode {
+        self.depth_mode
+    }
+
     pub fn set_viewport(&mut self, w: u32, h: u32) {
         self.viewport = [w, h];
+        // the old projection gives wrong motion for the first frame
+        self.prev_view_proj = None;
         self.proj = cgmath::perspective(
             Rad::from(Deg(45.0)),
-            w as f32 / h as f32,
+            w as f32 / h.max(1) as f32,
             self.near_clip,
             self.far_clip,
         );
+
+        if self.depth_mode.is_reversed() {
+            // z row for the 0..1 range: depth is near / view distance scaled to the clip range,
+            // 1 at the near plane and 0 at the far one
+            let (near, far) = (self.near_clip, self.far_clip);
+            self.proj.z.z = near / (far - near);
+            self.proj.w.z = near * far / (far - near);
+        }
     }
 
     pub fn view_matrix(&self) -> Matrix4<f32> {
@@ -62,75 +201,212 @@ impl Camera {
         self.proj
     }
 
+    // Sub-pixel offset of the projection in NDC for temporal anti-aliasing, [0, 0] turns it off
+    pub fn set_jitter(&mut self, jitter: [f32; 2]) {
+        self.jitter = jitter;
+    }
+
+    // Keeps the view projection of the frame for the motion vectors of the next one,
+    // call once per frame after the last descriptor()
+    pub fn end_frame(&mut self) {
+        self.prev_view_proj = Some(self.proj * self.view_matrix());
+    }
+
+    pub fn descriptor(&self) -> CameraDescriptor {
+        let view = self.view_matrix();
+        let unjittered_view_proj = self.proj * view;
+        let proj = Matrix4::from_translation(vec3(self.jitter[0], self.jitter[1], 0.0)) * self.proj;
+        let view_proj = proj * view;
+
+        CameraDescriptor {
+            view,
+            proj,
+            view_proj,
+            inv_view_proj: view_proj.invert().unwrap_or_else(Matrix4::identity),
+            unjittered_view_proj,
+            prev_view_proj: self.prev_view_proj.unwrap_or(unjittered_view_proj),
+            jitter: self.jitter,
+            rotation_view: self.skybox_view_matrix(),
+            position: self.position,
+            view_dir: self.view_dir,
+            near: self.near_clip,
+            far: self.far_clip,
+            aspect: self.viewport[0] as f32 / self.viewport[1].max(1) as f32,
+            depth_mode: self.depth_mode,
+            frustum: Frustum::from_matrix(&unjittered_view_proj, self.depth_mode),
+        }
+    }
+
     pub fn position(&self) -> Point3<f32> {
         self.position
     }
 
+    // E.g. to undo a move into an obstacle
+    pub fn set_position(&mut self, position: Point3<f32>) {
+        self.position = position;
+    }
+
     pub fn view_dir(&self) -> Vector3<f32> {
         self.view_dir
     }
 
-    fn handle_keyboard(&mut self, input: KeyboardInput) {
-        if input.state == ElementState::Pressed {
-            match input.virtual_keycode {
-                Some(VirtualKeyCode::W) => self.position += self.view_dir * 0.3,
-                Some(VirtualKeyCode::S) => self.position -= self.view_dir * 0.3,
+    // World space ray (origin on the near plane, normalized direction) through the given window pixel
+    pub fn screen_ray(&self, x: f32, y: f32) -> (Point3<f32>, Vector3<f32>) {
+        let ndc_x = 2.0 * x / self.viewport[0].max(1) as f32 - 1.0;
+        let ndc_y = 2.0 * y / self.viewport[1].max(1) as f32 - 1.0;
 
-                Some(VirtualKeyCode::H) => self.position += self.view_dir * 0.03,
-                Some(VirtualKeyCode::J) => self.position -= self.view_dir * 0.03,
+        let inv_view_proj = (self.proj * self.view_matrix())
+            .invert()
+            .unwrap_or_else(Matrix4::identity);
 
-                Some(VirtualKeyCode::A) => self.position -= self.view_dir.cross(self.up_dir) * 0.3,
-                Some(VirtualKeyCode::D) => self.position += self.view_dir.cross(self.up_dir) * 0.3,
-                Some(VirtualKeyCode::Space) => self.position.y += 0.1,
-                Some(VirtualKeyCode::LShift) => self.position.y -= 0.1,
-                _ => (),
-            }
+        let near = inv_view_proj * Vector4::new(ndc_x, ndc_y, self.depth_mode.ndc_near(), 1.0);
+        let far = inv_view_proj * Vector4::new(ndc_x, ndc_y, self.depth_mode.ndc_far(), 1.0);
+
+        let near = Point3::from_homogeneous(near);
+        let far = Point3::from_homogeneous(far);
+
+        (near, (far - near).normalize())
+    }
+
+    // Window pixel of a world point, the inverse of screen_ray(). None behind the camera
+    pub fn world_to_screen(&self, point: Point3<f32>) -> Option<[f32; 2]> {
+        let clip = self.proj * self.view_matrix() * point.to_homogeneous();
+        if clip.w <= self.near_clip * 0.5 {
+            return None;
         }
+
+        Some([
+            (clip.x / clip.w + 1.0) * 0.5 * self.viewport[0] as f32,
+            (clip.y / clip.w + 1.0) * 0.5 * self.viewport[1] as f32,
+        ])
     }
 
+    pub fn cursor_ray(&self) -> (Point3<f32>, Vector3<f32>) {
+        self.screen_ray(self.last_mouse_position[0] as f32, self.last_mouse_position[1] as f32)
+    }
+
+    // Returns true for movement keys
+    fn handle_keyboard(&mut self, input: KeyboardInput) -> bool {
+        let pressed = input.state == ElementState::Pressed;
+        let key = match input.virtual_keycode {
+            Some(VirtualKeyCode::W) => &mut self.keys.forward,
+            Some(VirtualKeyCode::S) => &mut self.keys.back,
+            Some(VirtualKeyCode::H) => &mut self.keys.slow_forward,
+            Some(VirtualKeyCode::J) => &mut self.keys.slow_back,
+            Some(VirtualKeyCode::A) => &mut self.keys.left,
+            Some(VirtualKeyCode::D) => &mut self.keys.right,
+            Some(VirtualKeyCode::Space) => &mut self.keys.up,
+            Some(VirtualKeyCode::LShift) => &mut self.keys.down,
+            _ => return false,
+        };
+        *key = pressed;
+
+        true
+    }
+
+    // Moves by held keys and turns towards the mouse look angles, `dt` - seconds since the previous update
+    pub fn update(&mut self, dt: f32) {
+        let keys = self.keys;
+        let right_dir = self.view_dir.cross(self.up_dir).normalize();
+        let mut direction = self.view_dir * MoveKeys::axis(keys.forward, keys.back)
+            + right_dir * MoveKeys::axis(keys.right, keys.left)
+            + self.up_dir * MoveKeys::axis(keys.up, keys.down);
+        let mut speed = self.settings.speed;
+        if direction.magnitude2() == 0.0 {
+            direction = self.view_dir * MoveKeys::axis(keys.slow_forward, keys.slow_back);
+            speed *= self.settings.slow_factor;
+        }
+
+        let target_velocity = if direction.magnitude2() > 0.0 {
+            direction.normalize() * speed
+        } else {
+            // keys win over the sticks, diagonal deflection is not faster
+            let stick = self.input.movement;
+            let direction = right_dir * stick.x + self.up_dir * stick.y + self.view_dir * stick.z;
+            direction / direction.magnitude().max(1.0) * self.settings.speed
+        };
+        let velocity_change = target_velocity - self.velocity;
+        let max_change = self.settings.acceleration * dt;
+        self.velocity += if velocity_change.magnitude() > max_change {
+            velocity_change.normalize() * max_change
+        } else {
+            velocity_change
+        };
+        self.position += self.velocity * dt;
+
+        let look_step = self.settings.stick_look_speed * dt;
+        self.target_yaw += self.input.look[0] * look_step;
+        self.target_pitch = (self.target_pitch + self.input.look[1] * look_step).clamp(-89.0, 89.0);
+
+        let blend = if self.settings.smoothing > 0.0 {
+            1.0 - (-dt / self.settings.smoothing).exp()
+        } else {
+            1.0
+        };
+        self.yaw += (self.target_yaw - self.yaw) * blend;
+        self.pitch += (self.target_pitch - self.pitch) * blend;
+        self.update_view_dir();
+    }
+
+    // Stops right away, e.g. when the camera hit something
+    pub fn stop(&mut self) {
+        self.velocity = vec3(0.0, 0.0, 0.0);
+    }
+
+    fn update_view_dir(&mut self) {
+        self.view_dir = Vector3::new(
+            Rad::from(Deg(self.yaw)).cos() * Rad::from(Deg(self.pitch)).cos(),
+            Rad::from(Deg(self.pitch)).sin(),
+            Rad::from(Deg(self.yaw)).sin() * Rad::from(Deg(self.pitch)).cos(),
+        ).normalize();
+    }
+
+    // Analog state of this frame, applied by update() together with keys and mouse
+    pub fn handle_input(&mut self, input: &CameraInput) {
+        self.input = *input;
+    }
+
+    // Mouse moves turn the camera only while captured, the app decides when
     pub fn mouse_acquired(&self) -> bool {
-        self.mouse_pressed
+        self.mouse_captured
+    }
+
+    pub fn set_mouse_captured(&mut self, captured: bool) {
+        self.mouse_captured = captured;
+    }
+
+    // The cursor was moved by the app, e.g. back to the center of a locked window: no turn
+    pub fn set_cursor_position(&mut self, position: [i32; 2]) {
+        self.last_mouse_position = position;
     }
 
     pub fn handle_event(&mut self, event: &WindowEvent) -> bool {
         let mut changed = false;
         match event {
             &WindowEvent::KeyboardInput { input, .. } => {
-                self.handle_keyboard(input);
-                changed = true;
+                changed = self.handle_keyboard(input);
             }
-
-            &WindowEvent::MouseInput { state, button, .. } => {
-                self.mouse_pressed = (state == ElementState::Pressed) && (button == MouseButton::Left);
+            // releases are not delivered to an unfocused window
+            &WindowEvent::Focused(false) => {
+                self.keys = MoveKeys::default();
+                self.mouse_captured = false;
             }
+
             &WindowEvent::CursorMoved { position, .. } => {
-                if !self.mouse_pressed {
+                if !self.mouse_captured {
                     self.last_mouse_position = position.into();
                     return changed;
                 }
 
                 let pos: [i32; 2] = position.into();
-                let sensitivity = 0.5;
+                let sensitivity = self.settings.mouse_sensitivity;
                 let dx = (pos[0] - self.last_mouse_position[0]) as f32 * sensitivity;
                 let dy = (pos[1] - self.last_mouse_position[1]) as f32 * sensitivity;
                 self.last_mouse_position = position.into();
 
-                self.yaw += dx;
-                self.pitch += dy;
-
-                if self.pitch > 89.0 {
-                    self.pitch = 89.0;
-                }
-
-                if self.pitch < -89.0 {
-                    self.pitch = -89.0;
-                }
-
-                self.view_dir = Vector3::new(
-                    Rad::from(Deg(self.yaw)).cos() * Rad::from(Deg(self.pitch)).cos(),
-                    Rad::from(Deg(self.pitch)).sin(),
-                    Rad::from(Deg(self.yaw)).sin() * Rad::from(Deg(self.pitch)).cos(),
-                ).normalize();
+                // applied by update()
+                self.target_yaw += dx;
+                self.target_pitch = (self.target_pitch + dy).clamp(-89.0, 89.0);
 
                 changed = true
             }
@@ -0,0 +1,186 @@
+use std::ffi::CStr;
+
+use ash::version::InstanceV1_0;
+use ash::vk;
+
+// Formats the demo renders to or samples, reported in the system info window
+const REPORTED_FORMATS: [vk::Format; 8] = [
+    vk::Format::R8G8B8A8_SRGB,
+    vk::Format::R8G8B8A8_UNORM,
+    vk::Format::A2B10G10R10_UNORM_PACK32,
+    vk::Format::R16G16_SFLOAT,
+    vk::Format::R16G16B16A16_SFLOAT,
+    vk::Format::R32G32B32A32_SFLOAT,
+    vk::Format::D32_SFLOAT,
+    vk::Format::D16_UNORM,
+];
+
+// Extensions a renderer may use when present, RenderEnv enables the ones it needs
+const OPTIONAL_EXTENSIONS: [&str; 5] = [
+    "VK_EXT_memory_budget",
+    "VK_KHR_draw_indirect_count",
+    "VK_EXT_descriptor_indexing",
+    "VK_KHR_timeline_semaphore",
+    "VK_EXT_conservative_rasterization",
+];
+
+// What the physical device supports, queried once at RenderEnv creation.
+// Renderers pick sample counts, formats and sampler settings from it instead of assuming support
+pub struct DeviceCapabilities {
+    pub device_name: String,
+    pub device_type: vk::PhysicalDeviceType,
+    // of the device, the instance may be older, see RenderEnv::api_version
+    pub api_version: u32,
+    pub driver_version: u32,
+    pub limits: vk::PhysicalDeviceLimits,
+    // supported, not necessarily enabled, see RenderEnv::enabled_features
+    pub features: vk::PhysicalDeviceFeatures,
+    // highest count usable for color and depth attachments together
+    pub max_msaa_samples: vk::SampleCountFlags,
+    // 1.0 when anisotropic filtering is not supported, capped at 16
+    pub max_sampler_anisotropy: f32,
+    // D32_SFLOAT when it can be a sampled depth attachment, D16_UNORM otherwise
+    pub shadow_depth_format: vk::Format,
+    // optimal tiling features of REPORTED_FORMATS
+    pub formats: Vec<(vk::Format, vk::FormatFeatureFlags)>,
+    // OPTIONAL_EXTENSIONS with their support
+    pub extensions: Vec<(&'static str, bool)>,
+
+    available_extensions: Vec<String>,
+}
+
+impl DeviceCapabilities {
+    pub fn query(instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> DeviceCapabilities {
+        let properties = unsafe { instance.get_physical_device_properties(physical_device) };
+        let features = unsafe { instance.get_physical_device_features(physical_device) };
+        let device_name = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) }.to_string_lossy().into_owned();
+
+        let available_extensions: Vec<String> = unsafe {
+            instance.enumerate_device_extension_properties(physical_device).unwrap_or_default()
+        }
+            .iter()
+            .map(|ext| unsafe { CStr::from_ptr(ext.extension_name.as_ptr()) }.to_string_lossy().into_owned())
+            .collect();
+        let extensions = OPTIONAL_EXTENSIONS.iter()
+            .map(|&name| (name, available_extensions.iter().any(|ext| ext == name)))
+            .collect();
+
+        let formats: Vec<_> = REPORTED_FORMATS.iter()
+            .map(|&format| {
+                let format_properties = unsafe { instance.get_physical_device_format_properties(physical_device, format) };
+                (format, format_properties.optimal_tiling_features)
+            })
+            .collect();
+
+        let max_sampler_anisotropy = if features.sampler_anisotropy == vk::TRUE {
+            properties.limits.max_sampler_anisotropy.min(16.0)
+        } else {
+            1.0
+        };
+
+        let mut capabilities = DeviceCapabilities {
+            device_name,
+            device_type: properties.device_type,
+            api_version: properties.api_version,
+            driver_version: properties.driver_version,
+            limits: properties.limits,
+            features,
+            max_msaa_samples: Self::max_sample_count(&properties.limits),
+            max_sampler_anisotropy,
+            shadow_depth_format: vk::Format::D16_UNORM,
+            formats,
+            extensions,
+            available_extensions,
+        };
+
+        let shadow_features = vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT | vk::FormatFeatureFlags::SAMPLED_IMAGE;
+        if capabilities.supports_format(vk::Format::D32_SFLOAT, shadow_features) {
+            capabilities.shadow_depth_format = vk::Format::D32_SFLOAT;
+        }
+
+        capabilities
+    }
+
+    fn max_sample_count(limits: &vk::PhysicalDeviceLimits) -> vk::SampleCountFlags {
+        let counts = limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts;
+
+        [
+            vk::SampleCountFlags::TYPE_64,
+            vk::SampleCountFlags::TYPE_32,
+            vk::SampleCountFlags::TYPE_16,
+            vk::SampleCountFlags::TYPE_8,
+            vk::SampleCountFlags::TYPE_4,
+            vk::SampleCountFlags::TYPE_2,
+        ]
+            .iter()
+            .copied()
+            .find(|&samples| counts.contains(samples))
+            .unwrap_or(vk::SampleCountFlags::TYPE_1)
+    }
+
+    // Formats outside REPORTED_FORMATS are not supported
+    pub fn supports_format(&self, format: vk::Format, features: vk::FormatFeatureFlags) -> bool {
+        self.formats.iter()
+            .any(|&(reported, supported)| reported == format && supported.contains(features))
+    }
+
+    // Any device extension, not only OPTIONAL_EXTENSIONS
+    pub fn supports_extension(&self, name: &CStr) -> bool {
+        let name = name.to_string_lossy();
+        self.available_extensions.iter().any(|ext| *ext == name)
+    }
+
+    #[cfg(feature = "egui")]
+    pub fn ui(&self, ui: &mut egui::Ui) {
+        let version = |version: u32| format!("{}.{}.{}", vk::version_major(version), vk::version_minor(version), vk::version_patch(version));
+        let limits = &self.limits;
+
+        ui.label(format!("{} ({:?})", self.device_name, self.device_type));
+        ui.label(format!("Vulkan {}, driver {:#x}", version(self.api_version), self.driver_version));
+
+        egui::CollapsingHeader::new("Limits").default_open(true).show(ui, |ui| {
+            ui.label(format!("Max MSAA samples: {}x", self.max_msaa_samples.as_raw()));
+            ui.label(format!("Max anisotropy: {}", self.max_sampler_anisotropy));
+            ui.label(format!("Max 2D image size: {}", limits.max_image_dimension2_d));
+            ui.label(format!("Max framebuffer: {}x{}", limits.max_framebuffer_width, limits.max_framebuffer_height));
+            ui.label(format!("Max color attachments: {}", limits.max_color_attachments));
+            ui.label(format!("Max push constants: {} bytes", limits.max_push_constants_size));
+            ui.label(format!("Max uniform buffer range: {} bytes", limits.max_uniform_buffer_range));
+            ui.label(format!("Max storage buffer range: {} bytes", limits.max_storage_buffer_range));
+            ui.label(format!("Max compute workgroup invocations: {}", limits.max_compute_work_group_invocations));
+            ui.label(format!("Timestamp period: {} ns", limits.timestamp_period));
+            ui.label(format!("Shadow depth format: {:?}", self.shadow_depth_format));
+        });
+
+        egui::CollapsingHeader::new("Features").show(ui, |ui| {
+            let features = &self.features;
+            for (name, supported) in [
+                ("sampler_anisotropy", features.sampler_anisotropy),
+                ("sample_rate_shading", features.sample_rate_shading),
+                ("depth_clamp", features.depth_clamp),
+                ("fill_mode_non_solid", features.fill_mode_non_solid),
+                ("tessellation_shader", features.tessellation_shader),
+                ("draw_indirect_first_instance", features.draw_indirect_first_instance),
+                ("multi_draw_indirect", features.multi_draw_indirect),
+                ("texture_compression_bc", features.texture_compression_bc),
+                ("texture_compression_astc_ldr", features.texture_compression_astc_ldr),
+            ].iter() {
+                ui.label(format!("{}: {}", name, if *supported == vk::TRUE { "yes" } else { "no" }));
+            }
+        });
+
+        egui::CollapsingHeader::new("Formats").show(ui, |ui| {
+            for (format, supported) in self.formats.iter() {
+                let attachment = supported.intersects(vk::FormatFeatureFlags::COLOR_ATTACHMENT | vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT);
+                let sampled = supported.contains(vk::FormatFeatureFlags::SAMPLED_IMAGE);
+                ui.label(format!("{:?}: attachment {}, sampled {}", format, if attachment { "yes" } else { "no" }, if sampled { "yes" } else { "no" }));
+            }
+        });
+
+        egui::CollapsingHeader::new("Extensions").show(ui, |ui| {
+            for (name, supported) in self.extensions.iter() {
+                ui.label(format!("{}: {}", name, if *supported { "yes" } else { "no" }));
+            }
+        });
+    }
+}
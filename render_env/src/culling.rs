@@ -0,0 +1,94 @@
+use cgmath::{EuclideanSpace, InnerSpace, Matrix, Matrix4, MetricSpace, Point3, Vector4};
+
+pub use crate::bvh::Aabb;
+use crate::camera::CameraDescriptor;
+use crate::utils::DepthMode;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sphere {
+    pub center: Point3<f32>,
+    pub radius: f32,
+}
+
+impl Sphere {
+    pub fn new(center: Point3<f32>, radius: f32) -> Sphere {
+        Sphere { center, radius }
+    }
+
+    // Sphere through the box corners
+    pub fn from_aabb(aabb: &Aabb) -> Sphere {
+        let center = aabb.center();
+        Sphere { center, radius: center.distance(aabb.max) }
+    }
+}
+
+// View volume as 6 planes (normal, distance) with normals pointing inside: left, right, bottom, top, near, far.
+// Tests are conservative: volumes near frustum corners may pass while being outside
+#[derive(Clone, Copy, Debug)]
+pub struct Frustum {
+    pub planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    // Gribb-Hartmann extraction. Standard depth is the OpenGL range cgmath::perspective produces (-w..w),
+    // reversed one is w at the near plane and 0 at the far plane
+    pub fn from_matrix(view_proj: &Matrix4<f32>, depth_mode: DepthMode) -> Frustum {
+        let m = view_proj.transpose();
+        let (near, far) = match depth_mode {
+            DepthMode::Standard => (m.w + m.z, m.w - m.z),
+            DepthMode::ReverseZ => (m.w - m.z, m.z),
+        };
+        let mut planes = [
+            m.w + m.x,
+            m.w - m.x,
+            m.w + m.y,
+            m.w - m.y,
+            near,
+            far,
+        ];
+
+        for plane in planes.iter_mut() {
+            *plane /= plane.truncate().magnitude();
+        }
+
+        Frustum { planes }
+    }
+
+    pub fn from_camera(camera: &CameraDescriptor) -> Frustum {
+        Frustum::from_matrix(&camera.view_proj, camera.depth_mode)
+    }
+
+    pub fn contains_point(&self, point: Point3<f32>) -> bool {
+        self.intersects_sphere(&Sphere::new(point, 0.0))
+    }
+
+    pub fn intersects_sphere(&self, sphere: &Sphere) -> bool {
+        self.planes.iter()
+            .all(|plane| plane.truncate().dot(sphere.center.to_vec()) + plane.w >= -sphere.radius)
+    }
+
+    // Box is outside when its corner furthest along a plane normal is behind that plane
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            let corner = Point3::new(
+                if plane.x >= 0.0 { aabb.max.x } else { aabb.min.x },
+                if plane.y >= 0.0 { aabb.max.y } else { aabb.min.y },
+                if plane.z >= 0.0 { aabb.max.z } else { aabb.min.z },
+            );
+            plane.truncate().dot(corner.to_vec()) + plane.w >= 0.0
+        })
+    }
+}
+
+// Objects tested against the frustum in the last frame of a renderer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CullStats {
+    pub total: usize,
+    pub culled: usize,
+}
+
+impl CullStats {
+    pub fn visible(&self) -> usize {
+        self.total - self.culled
+    }
+}
@@ -0,0 +1,53 @@
+use std::ffi::CString;
+
+use ash::vk;
+use ash::vk::Handle;
+
+// VK_EXT_debug_utils object names and command buffer labels, RenderDoc and validation messages show them.
+// RenderEnv creates it when validation is on, see RenderEnv::debug_utils
+#[derive(Clone)]
+pub struct DebugUtils {
+    loader: ash::extensions::ext::DebugUtils,
+    device: vk::Device,
+}
+
+impl DebugUtils {
+    pub(crate) fn new(loader: ash::extensions::ext::DebugUtils, device: vk::Device) -> DebugUtils {
+        DebugUtils {
+            loader,
+            device,
+        }
+    }
+
+    // Any handle: buffers, images, pipelines, command buffers etc.
+    pub fn set_name<H: Handle>(&self, handle: H, name: &str) {
+        let name = CString::new(name).unwrap();
+        let name_info = vk::DebugUtilsObjectNameInfoEXT::builder()
+            .object_type(H::TYPE)
+            .object_handle(handle.as_raw())
+            .object_name(&name);
+
+        unsafe {
+            self.loader
+                .debug_utils_set_object_name(self.device, &name_info)
+                .expect("Failed to set object name!");
+        }
+    }
+
+    // Opens a region, closed by the matching end_label() in the same command buffer
+    pub fn begin_label(&self, command_buffer: vk::CommandBuffer, name: &str) {
+        let name = CString::new(name).unwrap();
+        let label = vk::DebugUtilsLabelEXT::builder()
+            .label_name(&name);
+
+        unsafe {
+            self.loader.cmd_begin_debug_utils_label(command_buffer, &label);
+        }
+    }
+
+    pub fn end_label(&self, command_buffer: vk::CommandBuffer) {
+        unsafe {
+            self.loader.cmd_end_debug_utils_label(command_buffer);
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use std::collections::VecDeque;
+
+// Keeps replaced resources alive while command buffers of frames in flight can still use them.
+// Call next_frame() once per recorded frame, a retired item is dropped when more than
+// `frames` frames were recorded after it
+pub struct DeletionQueue<T> {
+    frame: usize,
+    frames: usize,
+    items: VecDeque<(usize, T)>,
+}
+
+impl<T> DeletionQueue<T> {
+    pub fn new(frames: usize) -> DeletionQueue<T> {
+        DeletionQueue {
+            frame: 0,
+            frames,
+            items: VecDeque::new(),
+        }
+    }
+
+    pub fn retire(&mut self, item: T) {
+        self.items.push_back((self.frame, item));
+    }
+
+    pub fn next_frame(&mut self) {
+        self.frame += 1;
+
+        while let Some(&(retired_at, _)) = self.items.front() {
+            if self.frame - retired_at <= self.frames {
+                break;
+            }
+            self.items.pop_front();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
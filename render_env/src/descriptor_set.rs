@@ -1,164 +1,321 @@
 use core::ptr;
 
-use ash::{RawPtr, vk};
+use ash::vk;
 use ash::version::DeviceV1_0;
 
+use crate::attachment_texture::AttachmentImage;
+use crate::barrier::AccessPattern;
+use crate::error::{RenderError, RenderResult};
+use crate::object_tracker::{self, ObjectKind};
 use crate::shader;
+use crate::utils::format_has_depth;
+use crate::utils::texture::Texture;
 
 pub struct DescriptorSet {
     device: ash::Device,
     pub set: vk::DescriptorSet,
     pool: vk::DescriptorPool,
+    dynamic_offset_count: usize,
 }
 
 impl DescriptorSet {
     pub fn builder(device: &ash::Device, layout: &shader::DescriptorSetLayout) -> DescriptorSetBuilder {
         DescriptorSetBuilder::new(device, layout)
     }
+
+    // `dynamic_offsets` - one offset per dynamic buffer of the set, in binding order
+    pub fn bind(&self, command_buffer: vk::CommandBuffer, bind_point: vk::PipelineBindPoint, layout: vk::PipelineLayout,
+                set_index: u32, dynamic_offsets: &[u32]) {
+        if dynamic_offsets.len() != self.dynamic_offset_count {
+            panic!("Descriptor set has {} dynamic buffers, but {} offsets given", self.dynamic_offset_count, dynamic_offsets.len());
+        }
+
+        let descriptor_sets_to_bind = [self.set];
+        unsafe {
+            self.device.cmd_bind_descriptor_sets(
+                command_buffer,
+                bind_point,
+                layout,
+                set_index,
+                &descriptor_sets_to_bind,
+                dynamic_offsets,
+            );
+        }
+    }
 }
 
 impl Drop for DescriptorSet {
     fn drop(&mut self) {
         unsafe {
             self.device.destroy_descriptor_pool(self.pool, None);
+            object_tracker::destroyed(ObjectKind::DescriptorPool);
+        }
+    }
+}
+
+
+// Kind of resource given to DescriptorSetBuilder, checked against the reflected descriptor type in build()
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ResourceKind {
+    UniformBuffer,
+    StorageBuffer,
+    DynamicBuffer,
+    // `arrayed` - view of a 2D array or cube array image
+    Image { arrayed: bool },
+    // image2D and the like, written by compute shaders
+    StorageImage,
+    // attachment of an earlier subpass, read with subpassLoad()
+    InputAttachment,
+}
+
+impl ResourceKind {
+    fn accepts(&self, descriptor_type: vk::DescriptorType) -> bool {
+        match self {
+            ResourceKind::UniformBuffer => descriptor_type == vk::DescriptorType::UNIFORM_BUFFER,
+            ResourceKind::StorageBuffer => descriptor_type == vk::DescriptorType::STORAGE_BUFFER,
+            ResourceKind::DynamicBuffer => descriptor_type == vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC,
+            ResourceKind::Image { .. } => [vk::DescriptorType::SAMPLED_IMAGE, vk::DescriptorType::COMBINED_IMAGE_SAMPLER].contains(&descriptor_type),
+            ResourceKind::StorageImage => descriptor_type == vk::DescriptorType::STORAGE_IMAGE,
+            ResourceKind::InputAttachment => descriptor_type == vk::DescriptorType::INPUT_ATTACHMENT,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ResourceKind::UniformBuffer => "buffer",
+            ResourceKind::StorageBuffer => "storage buffer",
+            ResourceKind::DynamicBuffer => "dynamic buffer",
+            ResourceKind::Image { arrayed: false } => "image",
+            ResourceKind::Image { arrayed: true } => "image array",
+            ResourceKind::StorageImage => "storage image",
+            ResourceKind::InputAttachment => "input attachment",
         }
     }
 }
 
+enum DescriptorWrite {
+    Buffer(vk::DescriptorBufferInfo),
+    Image(vk::DescriptorImageInfo),
+}
 
 // From existing shader::DescriptorSetLayout:
-//  1. Create pool with descriptors
-//  2. Bind resources to descriptors (add_buffer(..), add_image(..) etc.) with simple validation
-//  3. allocate descriptors from pool and write it
+//  1. Bind resources to descriptors in binding order (add_buffer(..), add_image(..) etc.),
+//     an array binding takes one resource per element
+//  2. build() checks them against the shader reflection, then creates the pool, allocates the set and writes it
 pub struct DescriptorSetBuilder {
     device: ash::Device,
-    pool: vk::DescriptorPool,
-    current_binding: usize,
-    binding_desc: Vec<vk::DescriptorSetLayoutBinding>,
     layout: vk::DescriptorSetLayout,
+    set: u32,
+    binding_desc: Vec<vk::DescriptorSetLayoutBinding>,
+    binding_shaders: Vec<String>,
+    binding_arrayed: Vec<bool>,
 
-    image_writes: Vec<vk::DescriptorImageInfo>,
-    buffer_writes: Vec<vk::DescriptorBufferInfo>,
+    resources: Vec<(ResourceKind, DescriptorWrite)>,
 }
 
 impl DescriptorSetBuilder {
     pub fn new(device: &ash::Device, layout: &shader::DescriptorSetLayout) -> DescriptorSetBuilder {
-        let mut pool_sizes = Vec::<vk::DescriptorPoolSize>::new();
-        for binding in layout.binding_desc.iter() {
-            pool_sizes.push(
-                vk::DescriptorPoolSize {
-                    ty: binding.descriptor_type,
-                    descriptor_count: 1,
-                }
-            )
-        }
-
-        let descriptor_pool_create_info = vk::DescriptorPoolCreateInfo {
-            s_type: vk::StructureType::DESCRIPTOR_POOL_CREATE_INFO,
-            p_next: ptr::null(),
-            flags: vk::DescriptorPoolCreateFlags::empty(),
-            max_sets: 1,
-            pool_size_count: pool_sizes.len() as u32,
-            p_pool_sizes: pool_sizes.as_ptr(),
-        };
-
-        let pool = unsafe {
-            device
-                .create_descriptor_pool(&descriptor_pool_create_info, None)
-                .expect("Failed to create Descriptor Pool!")
-        };
-
         DescriptorSetBuilder {
             device: device.clone(),
-            current_binding: 0,
-            binding_desc: layout.binding_desc.clone(),
-            image_writes: vec!(),
-            buffer_writes: vec!(),
-            pool,
             layout: layout.layout,
+            set: layout.set,
+            binding_desc: layout.binding_desc.clone(),
+            binding_shaders: layout.binding_shaders.clone(),
+            binding_arrayed: layout.binding_arrayed.clone(),
+            resources: vec!(),
         }
     }
-    pub fn add_buffer(&mut self, buffer: vk::Buffer) -> &mut Self {
-        let desc = self.binding_desc.get(self.current_binding).unwrap();
-        if desc.descriptor_type != vk::DescriptorType::UNIFORM_BUFFER {
-            panic!("Invalid value for descriptor {}: expected {:?}, found buffer", desc.binding, desc.descriptor_type);
-        }
 
-        self.buffer_writes.push(
+    fn add_buffer_info(&mut self, kind: ResourceKind, buffer: vk::Buffer, range: vk::DeviceSize) -> &mut Self {
+        self.resources.push((kind, DescriptorWrite::Buffer(
             vk::DescriptorBufferInfo {
                 buffer,
                 offset: 0,
-                range: vk::WHOLE_SIZE,
+                range,
             }
-        );
-
-        self.current_binding += 1;
+        )));
         self
     }
 
+    pub fn add_buffer(&mut self, buffer: vk::Buffer) -> &mut Self {
+        self.add_buffer_info(ResourceKind::UniformBuffer, buffer, vk::WHOLE_SIZE)
+    }
+
+    pub fn add_storage_buffer(&mut self, buffer: vk::Buffer) -> &mut Self {
+        self.add_buffer_info(ResourceKind::StorageBuffer, buffer, vk::WHOLE_SIZE)
+    }
+
+    // Buffer with `range` bytes visible to shader, the offset is given on every bind
+    pub fn add_dynamic_buffer(&mut self, buffer: vk::Buffer, range: vk::DeviceSize) -> &mut Self {
+        self.add_buffer_info(ResourceKind::DynamicBuffer, buffer, range)
+    }
+
     pub fn add_image(&mut self, image_view: vk::ImageView, sampler: vk::Sampler) -> &mut Self {
-        let desc = self.binding_desc.get(self.current_binding).
-            expect(&format!("Shaders don't contains descriptor with index {}. Need to recompile shader?", self.current_binding));
+        self.add_image_with_layout(image_view, sampler, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+    }
 
-        if ![vk::DescriptorType::SAMPLED_IMAGE, vk::DescriptorType::COMBINED_IMAGE_SAMPLER].contains(&desc.descriptor_type) {
-            panic!("Invalid value for descriptor {}: expected {:?}, found image", desc.binding, desc.descriptor_type);
-        }
+    pub fn add_image_with_layout(&mut self, image_view: vk::ImageView, sampler: vk::Sampler, image_layout: vk::ImageLayout) -> &mut Self {
+        self.add_image_info(ResourceKind::Image { arrayed: false }, image_view, sampler, image_layout)
+    }
+
+    // View of all layers of an image (TYPE_2D_ARRAY, CUBE_ARRAY) for sampler2DArray and the like.
+    // Not an array of descriptors: those take one add_image() per element
+    pub fn add_image_array(&mut self, image_view: vk::ImageView, sampler: vk::Sampler) -> &mut Self {
+        self.add_image_array_with_layout(image_view, sampler, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+    }
+
+    pub fn add_image_array_with_layout(&mut self, image_view: vk::ImageView, sampler: vk::Sampler, image_layout: vk::ImageLayout) -> &mut Self {
+        self.add_image_info(ResourceKind::Image { arrayed: true }, image_view, sampler, image_layout)
+    }
+
+    // View of a single mip level in GENERAL layout, for imageLoad/imageStore
+    pub fn add_storage_image(&mut self, image_view: vk::ImageView) -> &mut Self {
+        self.add_image_info(ResourceKind::StorageImage, image_view, vk::Sampler::null(), vk::ImageLayout::GENERAL)
+    }
 
-        self.image_writes.push(
+    fn add_image_info(&mut self, kind: ResourceKind, image_view: vk::ImageView, sampler: vk::Sampler, image_layout: vk::ImageLayout) -> &mut Self {
+        self.resources.push((kind, DescriptorWrite::Image(
             vk::DescriptorImageInfo {
                 sampler,
                 image_view,
-                image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                image_layout,
             }
-        );
+        )));
+        self
+    }
 
-        self.current_binding += 1;
+    // Framebuffer attachment in the layout it is currently in, which must be a sampled one
+    pub fn add_attachment(&mut self, attachment: &AttachmentImage, sampler: vk::Sampler) -> &mut Self {
+        attachment.layout.expect(attachment.sampled_pattern(), "sampled");
 
-        self
+        self.add_image_with_layout(attachment.view, sampler, attachment.layout.layout())
     }
 
-    pub fn add_image_with_layout(&mut self, image_view: vk::ImageView, sampler: vk::Sampler, image_layout: vk::ImageLayout) -> &mut Self {
-        let desc = self.binding_desc.get(self.current_binding).
-            expect(&format!("Shaders don't contains descriptor with index {}. Need to recompile shader?", self.current_binding));
+    // Attachment read in a subpass of the render pass writing it, in the layout of Subpass::inputs
+    pub fn add_input_attachment(&mut self, attachment: &AttachmentImage) -> &mut Self {
+        let image_layout = if format_has_depth(attachment.format) {
+            vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL
+        } else {
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+        };
 
-        if ![vk::DescriptorType::SAMPLED_IMAGE, vk::DescriptorType::COMBINED_IMAGE_SAMPLER].contains(&desc.descriptor_type) {
-            panic!("Invalid value for descriptor {}: expected {:?}, found image", desc.binding, desc.descriptor_type);
+        self.add_image_info(ResourceKind::InputAttachment, attachment.view, vk::Sampler::null(), image_layout)
+    }
+
+    pub fn add_texture(&mut self, texture: &Texture) -> &mut Self {
+        texture.layout.expect(AccessPattern::SampledRead, "sampled");
+
+        self.add_image_with_layout(texture.texture_image_view, texture.texture_sampler, texture.layout.layout())
+    }
+
+    fn error(&self, idx: usize, message: String) -> RenderError {
+        RenderError::DescriptorSet {
+            shader: self.binding_shaders.get(idx).cloned().unwrap_or_else(|| self.binding_shaders.join(", ")),
+            set: self.set,
+            binding: self.binding_desc.get(idx).map_or(idx as u32, |binding| binding.binding),
+            message,
         }
+    }
 
-        self.image_writes.push(
-            vk::DescriptorImageInfo {
-                sampler,
-                image_view,
-                image_layout,
+    // Resources must match the reflected bindings one to one: kind, layering, array size and count
+    fn validate(&self) -> RenderResult<()> {
+        let mut resources = self.resources.iter();
+
+        for (idx, binding) in self.binding_desc.iter().enumerate() {
+            for element in 0..binding.descriptor_count {
+                let &(kind, _) = resources.next()
+                    .ok_or_else(|| self.error(idx, if binding.descriptor_count > 1 {
+                        format!("array of {} {:?} has {} resources, need to recompile shader?", binding.descriptor_count, binding.descriptor_type, element)
+                    } else {
+                        format!("no resource for {:?}, need to recompile shader?", binding.descriptor_type)
+                    }))?;
+
+                if !kind.accepts(binding.descriptor_type) {
+                    return Err(self.error(idx, format!("expected {:?}, found {}", binding.descriptor_type, kind.name())));
+                }
+
+                if let ResourceKind::Image { arrayed } = kind {
+                    if arrayed != self.binding_arrayed[idx] {
+                        let expected = if self.binding_arrayed[idx] { "layered image, use add_image_array()" } else { "single layer image" };
+                        return Err(self.error(idx, format!("expected {}, found {}", expected, kind.name())));
+                    }
+                }
             }
-        );
+        }
 
-        self.current_binding += 1;
+        let extra = resources.count();
+        if extra > 0 {
+            return Err(self.error(self.binding_desc.len(), format!("{} resources given, the set has no more bindings", extra)));
+        }
 
-        self
+        Ok(())
     }
 
+    pub fn build(&self) -> RenderResult<DescriptorSet> {
+        self.validate()?;
+
+        let pool_sizes: Vec<_> = self.binding_desc.iter()
+            .map(|binding| vk::DescriptorPoolSize {
+                ty: binding.descriptor_type,
+                descriptor_count: binding.descriptor_count,
+            })
+            .collect();
+
+        let descriptor_pool_create_info = vk::DescriptorPoolCreateInfo {
+            s_type: vk::StructureType::DESCRIPTOR_POOL_CREATE_INFO,
+            p_next: ptr::null(),
+            flags: vk::DescriptorPoolCreateFlags::empty(),
+            max_sets: 1,
+            pool_size_count: pool_sizes.len() as u32,
+            p_pool_sizes: pool_sizes.as_ptr(),
+        };
+
+        let pool = unsafe {
+            self.device
+                .create_descriptor_pool(&descriptor_pool_create_info, None)
+                .map_err(RenderError::vulkan("vkCreateDescriptorPool"))?
+        };
+        object_tracker::created(ObjectKind::DescriptorPool);
 
-    pub fn build(&self) -> DescriptorSet {
         let layouts = [self.layout];
         let descriptor_set_allocate_info = vk::DescriptorSetAllocateInfo {
             s_type: vk::StructureType::DESCRIPTOR_SET_ALLOCATE_INFO,
             p_next: ptr::null(),
-            descriptor_pool: self.pool,
+            descriptor_pool: pool,
             descriptor_set_count: 1,
             p_set_layouts: layouts.as_ptr(),
         };
 
         let descriptor_sets = unsafe {
-            self.device
-                .allocate_descriptor_sets(&descriptor_set_allocate_info)
-                .expect("Failed to allocate descriptor sets!")
+            self.device.allocate_descriptor_sets(&descriptor_set_allocate_info)
+        };
+        let descriptor_set = match descriptor_sets {
+            Ok(descriptor_sets) => descriptor_sets[0],
+            Err(result) => {
+                unsafe {
+                    self.device.destroy_descriptor_pool(pool, None);
+                }
+                object_tracker::destroyed(ObjectKind::DescriptorPool);
+                return Err(RenderError::Vulkan { call: "vkAllocateDescriptorSets", result });
+            }
         };
 
-        let &descriptor_set = descriptor_sets.get(0).unwrap();
-
-        let mut cur_img_idx = 0;
-        let mut cur_buf_idx = 0;
+        // resources of a binding are consecutive, validate() checked their kinds
+        let image_writes: Vec<_> = self.resources.iter()
+            .map(|(_, write)| match write {
+                DescriptorWrite::Image(info) => *info,
+                DescriptorWrite::Buffer(_) => vk::DescriptorImageInfo::default(),
+            })
+            .collect();
+        let buffer_writes: Vec<_> = self.resources.iter()
+            .map(|(_, write)| match write {
+                DescriptorWrite::Buffer(info) => *info,
+                DescriptorWrite::Image(_) => vk::DescriptorBufferInfo::default(),
+            })
+            .collect();
+
+        let mut first_resource = 0;
+        let mut dynamic_offset_count = 0;
 
         let mut write_sets = Vec::new();
         for binding in self.binding_desc.iter() {
@@ -167,7 +324,7 @@ impl DescriptorSetBuilder {
                 dst_set: descriptor_set,
                 dst_binding: binding.binding,
                 dst_array_element: 0,
-                descriptor_count: 1,
+                descriptor_count: binding.descriptor_count,
                 descriptor_type: binding.descriptor_type,
                 p_image_info: ptr::null(),
                 p_buffer_info: ptr::null(),
@@ -175,16 +332,16 @@ impl DescriptorSetBuilder {
                 ..vk::WriteDescriptorSet::default()
             };
 
-            if binding.descriptor_type == vk::DescriptorType::COMBINED_IMAGE_SAMPLER {
-                write_desc.p_image_info = self.image_writes.get(cur_img_idx).as_raw_ptr();
-                cur_img_idx += 1;
+            match self.resources[first_resource].1 {
+                DescriptorWrite::Image(_) => write_desc.p_image_info = image_writes[first_resource..].as_ptr(),
+                DescriptorWrite::Buffer(_) => write_desc.p_buffer_info = buffer_writes[first_resource..].as_ptr(),
             }
 
-            if binding.descriptor_type == vk::DescriptorType::UNIFORM_BUFFER {
-                write_desc.p_buffer_info = self.buffer_writes.get(cur_buf_idx).as_raw_ptr();
-                cur_buf_idx += 1;
+            if binding.descriptor_type == vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC {
+                dynamic_offset_count += binding.descriptor_count as usize;
             }
 
+            first_resource += binding.descriptor_count as usize;
             write_sets.push(write_desc);
         }
 
@@ -192,10 +349,11 @@ impl DescriptorSetBuilder {
             self.device.update_descriptor_sets(&write_sets, &[]);
         }
 
-        DescriptorSet {
+        Ok(DescriptorSet {
             device: self.device.clone(),
-            pool: self.pool,
+            pool,
             set: descriptor_set,
-        }
+            dynamic_offset_count,
+        })
     }
 }
@@ -0,0 +1,101 @@
+use std::marker::PhantomData;
+use std::mem;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use crate::env::RenderEnv;
+use crate::memory_tracker;
+use crate::object_tracker::{self, ObjectKind};
+use crate::uniform_buffer::Std140;
+use crate::utils::buffer_utils::create_buffer_;
+
+// One uniform buffer shared by many draws, bound as UNIFORM_BUFFER_DYNAMIC with per-draw offset.
+// Every frame in flight owns its own range of `slots_per_frame` slots, so data of a frame still
+// used by GPU is never overwritten by the next one.
+pub struct DynamicUniformRing<T: Std140> {
+    pub buffer: vk::Buffer,
+    memory: vk::DeviceMemory,
+    mapped: *mut u8,
+
+    stride: u64,
+    slots_per_frame: usize,
+    frames: usize,
+
+    device: ash::Device,
+    phantom: PhantomData<T>,
+}
+
+impl<T: Std140> DynamicUniformRing<T> {
+    pub fn new(env: &RenderEnv, slots_per_frame: usize, frames: usize) -> DynamicUniformRing<T> {
+        let limits = &env.capabilities().limits;
+        // alignment is always a power of two
+        let alignment = limits.min_uniform_buffer_offset_alignment.max(1);
+        let stride = (mem::size_of::<T>() as u64 + alignment - 1) & !(alignment - 1);
+
+        let (buffer, memory, size) = create_buffer_(
+            env.device(),
+            stride * (slots_per_frame * frames) as u64,
+            vk::BufferUsageFlags::UNIFORM_BUFFER,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            &env.mem_properties,
+        );
+
+        let mapped = unsafe {
+            env.device()
+                .map_memory(memory, 0, size, vk::MemoryMapFlags::empty())
+                .expect("Failed to Map Memory") as *mut u8
+        };
+
+        DynamicUniformRing {
+            buffer,
+            memory,
+            mapped,
+            stride,
+            slots_per_frame,
+            frames,
+            device: env.device().clone(),
+            phantom: PhantomData,
+        }
+    }
+
+    // Size of a single slot, used as descriptor range
+    pub fn stride(&self) -> u64 {
+        self.stride
+    }
+
+    pub fn slots_per_frame(&self) -> usize {
+        self.slots_per_frame
+    }
+
+    // Dynamic offset to pass on descriptor set bind
+    pub fn offset(&self, frame: usize, slot: usize) -> u32 {
+        assert!(frame < self.frames, "Frame {} is out of ring ({} frames)", frame, self.frames);
+        assert!(slot < self.slots_per_frame, "Slot {} is out of ring ({} slots per frame)", slot, self.slots_per_frame);
+
+        ((frame * self.slots_per_frame + slot) as u64 * self.stride) as u32
+    }
+
+    pub fn write(&self, frame: usize, slot: usize, data: T) -> u32 {
+        let offset = self.offset(frame, slot);
+
+        unsafe {
+            let data_ptr = self.mapped.add(offset as usize) as *mut T;
+            data_ptr.write(data);
+        }
+
+        offset
+    }
+}
+
+impl<T: Std140> Drop for DynamicUniformRing<T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.unmap_memory(self.memory);
+            self.device.destroy_buffer(self.buffer, None);
+            object_tracker::destroyed(ObjectKind::Buffer);
+            self.device.free_memory(self.memory, None);
+            memory_tracker::freed(self.memory);
+        }
+    }
+}
@@ -4,18 +4,40 @@ use ash::version::DeviceV1_0;
 use ash::vk;
 
 use crate::env::RenderEnv;
+use crate::memory_tracker::{self, MemoryCategory};
+use crate::object_tracker::{self, ObjectKind};
 
+// Smallest allocation, a typical side panel fits without growing
+const MIN_CAPACITY: u64 = 64 * 1024;
+
+// Host visible buffer rewritten every frame. It grows to fit the largest upload and keeps that size,
+// the buffer must not be in use by GPU while written
 pub struct CpuBuffer {
     buffer_memory: vk::DeviceMemory,
     pub buffer: vk::Buffer,
+    usage: vk::BufferUsageFlags,
+    capacity: u64,
+    allocation_size: u64,
 
     device: ash::Device,
 }
 
 impl CpuBuffer {
-    pub fn from_vec<T>(env: &RenderEnv, usage: vk::BufferUsageFlags, data: &Vec<T>) -> CpuBuffer {
-        let size = (data.len() * mem::size_of::<T>()) as u64;
+    pub fn new(env: &RenderEnv, usage: vk::BufferUsageFlags) -> CpuBuffer {
+        let (buffer, buffer_memory, allocation_size) = Self::allocate(env, usage, MIN_CAPACITY);
 
+        CpuBuffer {
+            device: env.device().clone(),
+
+            buffer_memory,
+            buffer,
+            usage,
+            capacity: MIN_CAPACITY,
+            allocation_size,
+        }
+    }
+
+    fn allocate(env: &RenderEnv, usage: vk::BufferUsageFlags, size: u64) -> (vk::Buffer, vk::DeviceMemory, u64) {
         let buffer_create_info = vk::BufferCreateInfo {
             s_type: vk::StructureType::BUFFER_CREATE_INFO,
             p_next: ptr::null(),
@@ -32,6 +54,7 @@ impl CpuBuffer {
                 .create_buffer(&buffer_create_info, None)
                 .expect("Failed to create Buffer")
         };
+        object_tracker::created(ObjectKind::Buffer);
 
         let mem_requirements = unsafe { env.device().get_buffer_memory_requirements(buffer) };
         let memory_type = env.find_memory_type(
@@ -51,14 +74,45 @@ impl CpuBuffer {
                 .allocate_memory(&allocate_info, None)
                 .expect("Failed to allocate buffer memory!")
         };
+        memory_tracker::allocated(buffer_memory, mem_requirements.size, MemoryCategory::Ui);
 
         unsafe {
             env.device().bind_buffer_memory(buffer, buffer_memory, 0).unwrap();
         }
 
+        (buffer, buffer_memory, mem_requirements.size)
+    }
+
+    fn free(&self) {
+        unsafe {
+            self.device.destroy_buffer(self.buffer, None);
+            object_tracker::destroyed(ObjectKind::Buffer);
+            self.device.free_memory(self.buffer_memory, None);
+            memory_tracker::freed(self.buffer_memory);
+        }
+    }
+
+    // Replaces the contents, `buffer` changes when the data doesn't fit
+    pub fn write<T>(&mut self, env: &RenderEnv, data: &[T]) {
+        let size = mem::size_of_val(data) as u64;
+        if size > self.capacity {
+            self.free();
+
+            let capacity = size.next_power_of_two();
+            let (buffer, buffer_memory, allocation_size) = Self::allocate(env, self.usage, capacity);
+            self.buffer = buffer;
+            self.buffer_memory = buffer_memory;
+            self.capacity = capacity;
+            self.allocation_size = allocation_size;
+        }
+
+        if data.is_empty() {
+            return;
+        }
+
         unsafe {
             let mem = env.device()
-                .map_memory(buffer_memory, 0, mem_requirements.size, vk::MemoryMapFlags::empty())
+                .map_memory(self.buffer_memory, 0, self.allocation_size, vk::MemoryMapFlags::empty())
                 .unwrap() as *mut T;
             mem.copy_from_nonoverlapping(data.as_ptr(), data.len());
 
@@ -66,29 +120,19 @@ impl CpuBuffer {
                 vk::MappedMemoryRange {
                     s_type: vk::StructureType::MAPPED_MEMORY_RANGE,
                     p_next: ptr::null(),
-                    memory: buffer_memory,
+                    memory: self.buffer_memory,
                     offset: 0,
-                    size: mem_requirements.size,
+                    size: self.allocation_size,
                 }
             ]).unwrap();
 
-            env.device().unmap_memory(buffer_memory);
+            env.device().unmap_memory(self.buffer_memory);
         };
-
-        CpuBuffer {
-            device: env.device().clone(),
-
-            buffer_memory,
-            buffer,
-        }
     }
 }
 
 impl Drop for CpuBuffer {
     fn drop(&mut self) {
-        unsafe {
-            self.device.destroy_buffer(self.buffer, None);
-            self.device.free_memory(self.buffer_memory, None);
-        }
+        self.free();
     }
 }
@@ -10,6 +10,8 @@ pub use winit_input::egui_to_winit_cursor_icon;
 use crate::egui::renderer::EguiRenderer;
 use crate::egui::winit_input::WinitInput;
 use crate::env::RenderEnv;
+use crate::error::RenderResult;
+use crate::swapchain::{OutputEncoding, SwapChain, SwapChainListener};
 
 mod cpu_buffer;
 mod winit_input;
@@ -23,11 +25,12 @@ pub struct Egui {
 
     start_time: Option<Instant>,
     dimensions: [u32; 2],
-    max_frames_in_flight: usize,
 }
 
 impl Egui {
-    pub fn new(env: Arc<RenderEnv>, output_format: vk::Format, scale_factor: f64, dimensions: [u32; 2], max_frames_in_flight: usize, msaa_samples: vk::SampleCountFlags) -> Egui {
+    // `encoding` - of the output, egui colors are written like linear scene color
+    pub fn new(env: Arc<RenderEnv>, output_format: vk::Format, encoding: OutputEncoding, scale_factor: f64, dimensions: [u32; 2],
+               max_frames_in_flight: usize, msaa_samples: vk::SampleCountFlags) -> RenderResult<Egui> {
         let mut ctx = egui::CtxRef::default();
 
         let raw_input = egui::RawInput {
@@ -44,20 +47,20 @@ impl Egui {
         ctx.begin_frame(raw_input.clone());
         let (_output, _shapes) = ctx.end_frame();
 
-        let renderer = EguiRenderer::new(env, ctx.clone(), output_format, msaa_samples);
-        let winit_input = WinitInput::new(raw_input, scale_factor);
+        let renderer = EguiRenderer::new(env, ctx.clone(), output_format, encoding, msaa_samples, max_frames_in_flight)?;
+        let winit_input = WinitInput::new(raw_input);
 
-        Egui {
+        Ok(Egui {
             ctx,
             winit_input,
             renderer,
             current_cursor_icon: egui::CursorIcon::None,
             start_time: None,
             dimensions,
-            max_frames_in_flight,
-        }
+        })
     }
 
+    // Resized and ScaleFactorChanged must always get here, even when the app doesn't pass input to gui
     pub fn handle_event(&mut self, window_event: &WindowEvent) {
         self.winit_input.handle_event(self.ctx.clone(), window_event);
     }
@@ -92,8 +95,7 @@ impl Egui {
             self.ctx.clone(),
             clipped_meshes,
             self.dimensions,
-            self.max_frames_in_flight,
-            self.winit_input.scale_factor as f32,
+            self.ctx.pixels_per_point(),
         );
 
         gui_render_op
@@ -107,11 +109,53 @@ impl Egui {
         self.ctx.clone()
     }
 
-    pub fn register_texture(&mut self, id: u64, texture: vk::ImageView, multisampled: bool) {
-        self.renderer.register_texture(id, texture, multisampled);
+    // A widget has keyboard focus, e.g. a text field: keys are typed into it and must not drive the app
+    pub fn wants_keyboard(&self) -> bool {
+        self.ctx.wants_keyboard_input()
+    }
+
+    // The pointer is over a gui area or drags a widget
+    pub fn wants_pointer(&self) -> bool {
+        self.ctx.wants_pointer_input()
+    }
+
+    pub fn add_texture(&mut self, texture: vk::ImageView, multisampled: bool) -> egui::TextureId {
+        self.renderer.add_texture(texture, multisampled)
+    }
+
+    pub fn add_texture_with_layout(&mut self, texture: vk::ImageView, layout: vk::ImageLayout) -> egui::TextureId {
+        self.renderer.add_texture_with_layout(texture, layout)
+    }
+
+    // Image drawn by the final pass, its color is already encoded for the output
+    pub fn add_output_texture(&mut self, texture: vk::ImageView) -> egui::TextureId {
+        self.renderer.add_output_texture(texture)
+    }
+
+    pub fn replace_output_texture(&mut self, id: egui::TextureId, texture: vk::ImageView) {
+        self.renderer.replace_output_texture(id, texture);
+    }
+
+    // Multisampled textures must be replaced after the call. Device must be idle
+    pub fn set_msaa_samples(&mut self, msaa_samples: vk::SampleCountFlags) -> RenderResult<()> {
+        self.renderer.set_msaa_samples(msaa_samples)
+    }
+
+    pub fn replace_texture(&mut self, id: egui::TextureId, texture: vk::ImageView, multisampled: bool) {
+        self.renderer.replace_texture(id, texture, multisampled);
     }
 
-    pub fn register_texture_layout(&mut self, id: u64, texture: vk::ImageView, layout: vk::ImageLayout) {
-        self.renderer.register_texture_layout(id, texture, layout);
+    pub fn replace_texture_with_layout(&mut self, id: egui::TextureId, texture: vk::ImageView, layout: vk::ImageLayout) {
+        self.renderer.replace_texture_with_layout(id, texture, layout);
+    }
+
+    pub fn unregister_texture(&mut self, id: egui::TextureId) {
+        self.renderer.unregister_texture(id);
+    }
+}
+
+impl SwapChainListener for Egui {
+    fn swapchain_recreated(&mut self, swapchain: &SwapChain) {
+        self.set_dimensions([swapchain.size.width, swapchain.size.height]);
     }
 }
@@ -6,85 +6,66 @@ use ash::version::DeviceV1_0;
 use ash::vk;
 use egui::TextureId;
 
+use crate::deletion_queue::DeletionQueue;
 use crate::descriptor_set::{DescriptorSet, DescriptorSetBuilder};
 use crate::egui::cpu_buffer::CpuBuffer;
 use crate::env::RenderEnv;
+use crate::error::{RenderError, RenderResult};
+use crate::memory_tracker::{self, MemoryCategory};
+use crate::object_tracker::{self, ObjectKind};
 use crate::pipeline_builder::{Pipeline, PipelineBuilder};
 use crate::shader::{ConstantsBuilder, Shader};
+use crate::swapchain::{OutputEncoding, TransferFunction};
 use crate::utils::texture::Texture;
 
-struct FontTexture(Texture, u64);
+// Atlas with its version and the pixels_per_point it was rasterized for. egui rebuilds
+// the atlas from scratch on DPI change, so the version alone can repeat
+struct FontTexture(Texture, u64, f32);
 
-#[allow(dead_code)]
-pub struct RenderOp {
+// Resources of one frame in flight, reused when the frame comes round again
+struct FrameResources {
     vb: CpuBuffer,
     ib: CpuBuffer,
-    pub cmd_buf: vk::CommandBuffer,
-    env: Arc<RenderEnv>,
-}
-
-impl Drop for RenderOp {
-    fn drop(&mut self) {
-        unsafe {
-            self.env.device().free_command_buffers(self.env.command_pool, &[self.cmd_buf]);
-        }
-    }
+    // re-recorded every frame, the pool allows resetting single buffers
+    cmd_buf: vk::CommandBuffer,
 }
 
 struct TextureInfo {
     descriptor_set: DescriptorSet,
     multisampled: bool,
+    // already encoded for the output, drawn without encoding again
+    encoded: bool,
 }
 
 pub struct EguiRenderer {
-    cmd_bufs: Vec<RenderOp>,
+    frames: Vec<FrameResources>,
+    current_frame: usize,
 
     texture: FontTexture,
     pipeline: Pipeline,
     pipeline_msaa: Pipeline,
+    pipeline_encoded: Pipeline,
+    encoding: OutputEncoding,
     render_pass: vk::RenderPass,
     env: Arc<RenderEnv>,
     descriptor_set: DescriptorSet,
     sampler: vk::Sampler,
 
     user_textures_descriptors: HashMap<u64, TextureInfo>,
+    next_user_texture_id: u64,
+
+    // replaced and unregistered textures, gui command buffers in flight still bind them
+    retired_descriptor_sets: DeletionQueue<DescriptorSet>,
+    retired_fonts: DeletionQueue<FontTexture>,
 }
 
 impl EguiRenderer {
-    pub fn new(env: Arc<RenderEnv>, ctx: egui::CtxRef, output_format: vk::Format, msaa_samples: vk::SampleCountFlags) -> EguiRenderer {
+    pub fn new(env: Arc<RenderEnv>, ctx: egui::CtxRef, output_format: vk::Format, encoding: OutputEncoding,
+               msaa_samples: vk::SampleCountFlags, max_frames_in_flight: usize) -> RenderResult<EguiRenderer> {
         ctx.set_fonts(egui::FontDefinitions::default());
         ctx.set_style(egui::Style::default());
 
-        let texture = Self::upload_font_texture(&env, ctx);
-
-        let vertex_bindings = vec![
-            vk::VertexInputBindingDescription {
-                binding: 0,
-                stride: 4 * std::mem::size_of::<f32>() as u32 + 4 * std::mem::size_of::<u8>() as u32,
-                input_rate: vk::VertexInputRate::VERTEX,
-            }
-        ];
-
-        let vert_attrs = vec![
-            vk::VertexInputAttributeDescription {
-                location: 0,
-                binding: 0,
-                format: vk::Format::R32G32_SFLOAT,
-                offset: 0,
-            },
-            vk::VertexInputAttributeDescription {
-                location: 1,
-                binding: 0,
-                format: vk::Format::R32G32_SFLOAT,
-                offset: 8,
-            },
-            vk::VertexInputAttributeDescription {
-                location: 2,
-                binding: 0,
-                format: vk::Format::R8G8B8A8_UNORM,
-                offset: 16,
-            },
-        ];
+        let texture = Self::upload_font_texture(&env, ctx)?;
 
         let sampler_create_info = vk::SamplerCreateInfo {
             s_type: vk::StructureType::SAMPLER_CREATE_INFO,
@@ -112,67 +93,121 @@ impl EguiRenderer {
         let sampler = unsafe {
             env.device()
                 .create_sampler(&sampler_create_info, None)
-                .expect("Failed to create Sampler!")
+                .map_err(RenderError::vulkan("vkCreateSampler"))?
         };
+        object_tracker::created(ObjectKind::Sampler);
 
-        let render_pass = create_render_pass(env.device(), output_format);
+        let render_pass = create_render_pass(&env, output_format)?;
 
-        let pipeline = {
-            let vs = Shader::load(env.device(), "assets/shaders/spv/egui/egui.vert.spv");
-            let ps = Shader::load(env.device(), "assets/shaders/spv/egui/egui.frag.spv");
-
-            PipelineBuilder::new(env.device().clone(), render_pass, 0)
-                .vertex_input(vertex_bindings.clone(), vert_attrs.clone())
-                .vertex_shader(vs)
-                .fragment_shader(ps)
-                .disable_culling()
-                .blend()
-                .build()
-        };
-
-        let pipeline_msaa = {
-            let vs = Shader::load(env.device(), "assets/shaders/spv/egui/egui_msaa.vert.spv");
-            let ps = Shader::load(env.device(), "assets/shaders/spv/egui/egui_msaa.frag.spv")
-                .specialize(ConstantsBuilder::new().add_u32(msaa_samples.as_raw() as u32));
-
-            PipelineBuilder::new(env.device().clone(), render_pass, 0)
-                .vertex_input(vertex_bindings.clone(), vert_attrs.clone())
-                .vertex_shader(vs)
-                .fragment_shader(ps)
-                .disable_culling()
-                .blend()
-                .build()
-        };
+        let pipeline = Self::create_pipeline(&env, render_pass, encoding)?;
+        let pipeline_encoded = Self::create_pipeline(&env, render_pass, OutputEncoding {
+            transfer: TransferFunction::HardwareSrgb,
+            ..encoding
+        })?;
+        let pipeline_msaa = Self::create_msaa_pipeline(&env, render_pass, msaa_samples, encoding)?;
 
         let descriptor_set = DescriptorSetBuilder::new(env.device(), &pipeline.descriptor_set_layouts[0])
             .add_image(texture.0.texture_image_view, sampler)
-            .build();
+            .build()?;
+
+        let frames = (0..max_frames_in_flight)
+            .map(|idx| {
+                let cmd_buf = env.create_secondary_command_buffer();
+                env.set_debug_name(cmd_buf, &format!("egui #{}", idx));
 
-        EguiRenderer {
+                FrameResources {
+                    vb: CpuBuffer::new(&env, vk::BufferUsageFlags::VERTEX_BUFFER),
+                    ib: CpuBuffer::new(&env, vk::BufferUsageFlags::INDEX_BUFFER),
+                    cmd_buf,
+                }
+            })
+            .collect();
+
+        Ok(EguiRenderer {
             env,
             texture,
             pipeline,
             pipeline_msaa,
+            pipeline_encoded,
+            encoding,
             render_pass,
-            cmd_bufs: vec![],
+            frames,
+            current_frame: 0,
             descriptor_set,
             sampler,
             user_textures_descriptors: HashMap::new(),
-        }
+            next_user_texture_id: 0,
+            retired_descriptor_sets: DeletionQueue::new(max_frames_in_flight),
+            retired_fonts: DeletionQueue::new(max_frames_in_flight),
+        })
     }
 
-    pub fn render(&mut self, ctx: egui::CtxRef, meshes: Vec<egui::ClippedMesh>, dimensions: [u32; 2], frames: usize, scale_factor: f32) -> vk::CommandBuffer {
-        // Generate cmd buf every frame
-        if self.cmd_bufs.len() > frames {
-            self.cmd_bufs.remove(0);
-        }
+    fn create_pipeline(env: &RenderEnv, render_pass: vk::RenderPass, encoding: OutputEncoding) -> RenderResult<Pipeline> {
+        let vs = Shader::load(env.device(), "assets/shaders/spv/egui/egui.vert.spv")?;
+        let ps = Shader::load(env.device(), "assets/shaders/spv/egui/egui.frag.spv")?
+            .specialize(encoding.add_constants(ConstantsBuilder::new()));
+        let (vertex_bindings, vert_attrs) = vertex_input();
+
+        PipelineBuilder::new(env.device().clone(), render_pass, 0)
+            .pipeline_cache(env.pipeline_cache())
+            .vertex_input(vertex_bindings, vert_attrs)
+            .vertex_shader(vs)
+            .fragment_shader(ps)
+            .disable_culling()
+            .blend()
+            .build()
+    }
+
+    // Pipeline drawing multisampled textures, resolves them by averaging `msaa_samples` samples
+    fn create_msaa_pipeline(env: &RenderEnv, render_pass: vk::RenderPass, msaa_samples: vk::SampleCountFlags,
+                            encoding: OutputEncoding) -> RenderResult<Pipeline> {
+        let vs = Shader::load(env.device(), "assets/shaders/spv/egui/egui_msaa.vert.spv")?;
+        let ps = Shader::load(env.device(), "assets/shaders/spv/egui/egui_msaa.frag.spv")?
+            .specialize(encoding.add_constants(ConstantsBuilder::new().add_u32(msaa_samples.as_raw() as u32)));
+        let (vertex_bindings, vert_attrs) = vertex_input();
+
+        PipelineBuilder::new(env.device().clone(), render_pass, 0)
+            .pipeline_cache(env.pipeline_cache())
+            .vertex_input(vertex_bindings, vert_attrs)
+            .vertex_shader(vs)
+            .fragment_shader(ps)
+            .disable_culling()
+            .blend()
+            .build()
+    }
+
+    // Sample count of multisampled textures added after the call, they have to be replaced with replace_texture().
+    // Device must be idle
+    pub fn set_msaa_samples(&mut self, msaa_samples: vk::SampleCountFlags) -> RenderResult<()> {
+        self.pipeline_msaa = Self::create_msaa_pipeline(&self.env, self.render_pass, msaa_samples, self.encoding)?;
+        Ok(())
+    }
 
-        if ctx.texture().version != self.texture.1 {
+    // `pixels_per_point` - the value egui laid the frame out with, physical pixels per egui point.
+    // Must be called once per frame, the buffers of the frame max_frames_in_flight calls ago are reused
+    pub fn render(&mut self, ctx: egui::CtxRef, meshes: Vec<egui::ClippedMesh>, dimensions: [u32; 2], pixels_per_point: f32) -> vk::CommandBuffer {
+        let frame_idx = self.current_frame;
+        self.current_frame = (self.current_frame + 1) % self.frames.len();
+        self.retired_descriptor_sets.next_frame();
+        self.retired_fonts.next_frame();
+
+        if ctx.texture().version != self.texture.1 || pixels_per_point != self.texture.2 {
             println!("egui: upload new texture version");
-            self.texture = Self::upload_font_texture(&self.env, ctx);
-            self.descriptor_set = DescriptorSetBuilder::new(self.env.device(), &self.pipeline.descriptor_set_layouts[0])
-                .add_image(self.texture.0.texture_image_view, self.sampler)
-                .build();
+            // the old font stays in use if the new one can't be uploaded
+            let font = Self::upload_font_texture(&self.env, ctx).and_then(|font| {
+                let descriptor_set = DescriptorSetBuilder::new(self.env.device(), &self.pipeline.descriptor_set_layouts[0])
+                    .add_image(font.0.texture_image_view, self.sampler)
+                    .build()?;
+                Ok((font, descriptor_set))
+            });
+
+            match font {
+                Ok((font, descriptor_set)) => {
+                    self.retired_fonts.retire(std::mem::replace(&mut self.texture, font));
+                    self.retired_descriptor_sets.retire(std::mem::replace(&mut self.descriptor_set, descriptor_set));
+                }
+                Err(err) => println!("egui: failed to upload font texture: {}", err),
+            }
         }
 
         let mut vertices: Vec<egui::epaint::Vertex> = Vec::new();
@@ -183,10 +218,12 @@ impl EguiRenderer {
             indices.extend(&mesh.1.indices);
         }
 
-        let vb = CpuBuffer::from_vec(&self.env, vk::BufferUsageFlags::VERTEX_BUFFER, &vertices);
-        let ib = CpuBuffer::from_vec(&self.env, vk::BufferUsageFlags::INDEX_BUFFER, &indices);
+        let frame = &mut self.frames[frame_idx];
+        frame.vb.write(&self.env, &vertices);
+        frame.ib.write(&self.env, &indices);
 
-        let cmd_buf = self.env.create_secondary_command_buffer();
+        let frame = &self.frames[frame_idx];
+        let cmd_buf = frame.cmd_buf;
         let device = self.env.device().clone();
 
         let inheritance_info = vk::CommandBufferInheritanceInfo {
@@ -216,40 +253,41 @@ impl EguiRenderer {
         }];
 
         let mut data = Vec::new();
-        data.extend((dimensions[0] as f32 / scale_factor).to_le_bytes());
-        data.extend((dimensions[1] as f32 / scale_factor).to_le_bytes());
+        data.extend((dimensions[0] as f32 / pixels_per_point).to_le_bytes());
+        data.extend((dimensions[1] as f32 / pixels_per_point).to_le_bytes());
 
         unsafe {
+            // begin resets the buffer recorded when the frame was used last time
             device.begin_command_buffer(cmd_buf, &begin_info).unwrap();
+            self.env.cmd_begin_label(cmd_buf, "egui");
 
             device.cmd_set_viewport(cmd_buf, 0, viewports.as_ref());
-            let vertex_buffers = [vb.buffer];
+            let vertex_buffers = [frame.vb.buffer];
             let offsets = [0];
             device.cmd_bind_vertex_buffers(cmd_buf, 0, &vertex_buffers, &offsets);
-            device.cmd_bind_index_buffer(cmd_buf, ib.buffer, 0, vk::IndexType::UINT32);
+            device.cmd_bind_index_buffer(cmd_buf, frame.ib.buffer, 0, vk::IndexType::UINT32);
 
             let mut index_base = 0;
             let mut vertex_base = 0;
             for egui::ClippedMesh(rect, mesh) in meshes.iter() {
-                let min = rect.min;
+                // skipped meshes still take their place in the shared buffers
+                let first_index = index_base;
+                let vertex_offset = vertex_base;
+                index_base += mesh.indices.len() as u32;
+                vertex_base += mesh.vertices.len() as i32;
 
+                // clip rect in points to whole physical pixels inside the framebuffer
                 let min = egui::Pos2 {
-                    x: min.x * scale_factor as f32,
-                    y: min.y * scale_factor as f32,
-                };
-                let min = egui::Pos2 {
-                    x: f32::clamp(min.x, 0.0, dimensions[0] as f32),
-                    y: f32::clamp(min.y, 0.0, dimensions[1] as f32),
-                };
-                let max = rect.max;
-                let max = egui::Pos2 {
-                    x: max.x * scale_factor as f32,
-                    y: max.y * scale_factor as f32,
+                    x: f32::clamp((rect.min.x * pixels_per_point).round(), 0.0, dimensions[0] as f32),
+                    y: f32::clamp((rect.min.y * pixels_per_point).round(), 0.0, dimensions[1] as f32),
                 };
                 let max = egui::Pos2 {
-                    x: f32::clamp(max.x, min.x, dimensions[0] as f32),
-                    y: f32::clamp(max.y, min.y, dimensions[1] as f32),
+                    x: f32::clamp((rect.max.x * pixels_per_point).round(), min.x, dimensions[0] as f32),
+                    y: f32::clamp((rect.max.y * pixels_per_point).round(), min.y, dimensions[1] as f32),
                 };
+                if max.x <= min.x || max.y <= min.y {
+                    continue;
+                }
 
                 device.cmd_set_scissor(
                     cmd_buf,
@@ -257,14 +295,14 @@ impl EguiRenderer {
                     &[vk::Rect2D::builder()
                         .offset(
                             vk::Offset2D::builder()
-                                .x(min.x.round() as i32)
-                                .y(min.y.round() as i32)
+                                .x(min.x as i32)
+                                .y(min.y as i32)
                                 .build(),
                         )
                         .extent(
                             vk::Extent2D::builder()
-                                .width((max.x.round() - min.x) as u32)
-                                .height((max.y.round() - min.y) as u32)
+                                .width((max.x - min.x) as u32)
+                                .height((max.y - min.y) as u32)
                                 .build(),
                         )
                         .build()],
@@ -273,13 +311,21 @@ impl EguiRenderer {
                 let (pipeline, descriptor_set) = match mesh.texture_id {
                     TextureId::Egui => (&self.pipeline, self.descriptor_set.set),
                     TextureId::User(texture_id) => {
-                        let pipeline = if self.user_textures_descriptors[&texture_id].multisampled {
+                        // texture could be unregistered while still referenced by gui code
+                        let texture = match self.user_textures_descriptors.get(&texture_id) {
+                            Some(texture) => texture,
+                            None => continue,
+                        };
+
+                        let pipeline = if texture.multisampled {
                             &self.pipeline_msaa
+                        } else if texture.encoded {
+                            &self.pipeline_encoded
                         } else {
                             &self.pipeline
                         };
 
-                        (pipeline, self.user_textures_descriptors[&texture_id].descriptor_set.set)
+                        (pipeline, texture.descriptor_set.set)
                     }
                 };
 
@@ -294,29 +340,20 @@ impl EguiRenderer {
                     cmd_buf,
                     mesh.indices.len() as u32,
                     1,
-                    index_base,
-                    vertex_base,
+                    first_index,
+                    vertex_offset,
                     0,
                 );
-
-                index_base += mesh.indices.len() as u32;
-                vertex_base += mesh.vertices.len() as i32;
             }
 
+            self.env.cmd_end_label(cmd_buf);
             device.end_command_buffer(cmd_buf).unwrap();
         }
 
-        self.cmd_bufs.push(RenderOp {
-            env: self.env.clone(),
-            ib,
-            vb,
-            cmd_buf,
-        });
-
         cmd_buf
     }
 
-    fn upload_font_texture(env: &RenderEnv, ctx: egui::CtxRef) -> FontTexture {
+    fn upload_font_texture(env: &RenderEnv, ctx: egui::CtxRef) -> RenderResult<FontTexture> {
         let font_tx = ctx.texture();
         let data = font_tx
             .pixels
@@ -324,6 +361,7 @@ impl EguiRenderer {
             .flat_map(|&r| vec![r, r, r, r])
             .collect::<Vec<_>>();
 
+        let _category = memory_tracker::category_scope(MemoryCategory::Ui);
         let texture = Texture::from_pixels(
             env.device().clone(),
             env.command_pool(),
@@ -334,12 +372,27 @@ impl EguiRenderer {
             font_tx.width as u32,
             font_tx.height as u32,
             false,
-        );
+            env.max_sampler_anisotropy(),
+        )?;
+
+        Ok(FontTexture(texture, font_tx.version, ctx.pixels_per_point()))
+    }
+
+    pub fn add_texture(&mut self, texture: vk::ImageView, multisampled: bool) -> TextureId {
+        let id = self.allocate_texture_id();
+        self.replace_texture(id, texture, multisampled);
+        id
+    }
 
-        FontTexture(texture, font_tx.version)
+    pub fn add_texture_with_layout(&mut self, texture: vk::ImageView, layout: vk::ImageLayout) -> TextureId {
+        let id = self.allocate_texture_id();
+        self.replace_texture_with_layout(id, texture, layout);
+        id
     }
 
-    pub fn register_texture(&mut self, id: u64, texture: vk::ImageView, multisampled: bool) {
+    // Points existing id to another image view, e.g. after framebuffer resize
+    pub fn replace_texture(&mut self, id: TextureId, texture: vk::ImageView, multisampled: bool) {
+        let id = Self::user_texture_id(id);
         let layout = if multisampled {
             &self.pipeline_msaa.descriptor_set_layouts[0]
         } else {
@@ -349,40 +402,130 @@ impl EguiRenderer {
         let descriptor_set = DescriptorSetBuilder::new(
             self.env.device(), layout)
             .add_image(texture, self.sampler)
-            .build();
+            .build()
+            .expect("Failed to create egui texture descriptor set!");
 
-        self.user_textures_descriptors.insert(id, TextureInfo {
+        self.set_user_texture(id, TextureInfo {
             descriptor_set,
             multisampled,
+            encoded: false,
         });
     }
 
-    pub fn register_texture_layout(&mut self, id: u64, texture: vk::ImageView, layout: vk::ImageLayout) {
+    pub fn replace_texture_with_layout(&mut self, id: TextureId, texture: vk::ImageView, layout: vk::ImageLayout) {
+        let id = Self::user_texture_id(id);
         let pipeline_layout = &self.pipeline.descriptor_set_layouts[0];
 
         let descriptor_set = DescriptorSetBuilder::new(
             self.env.device(), pipeline_layout)
             .add_image_with_layout(texture, self.sampler, layout)
-            .build();
+            .build()
+            .expect("Failed to create egui texture descriptor set!");
 
-        self.user_textures_descriptors.insert(id, TextureInfo {
+        self.set_user_texture(id, TextureInfo {
             descriptor_set,
             multisampled: false,
+            encoded: false,
         });
     }
+
+    pub fn add_output_texture(&mut self, texture: vk::ImageView) -> TextureId {
+        let id = self.allocate_texture_id();
+        self.replace_output_texture(id, texture);
+        id
+    }
+
+    // Texture with the output format holding already encoded color, e.g. the scene drawn by the final pass
+    pub fn replace_output_texture(&mut self, id: TextureId, texture: vk::ImageView) {
+        let id = Self::user_texture_id(id);
+
+        let descriptor_set = DescriptorSetBuilder::new(
+            self.env.device(), &self.pipeline_encoded.descriptor_set_layouts[0])
+            .add_image(texture, self.sampler)
+            .build()
+            .expect("Failed to create egui texture descriptor set!");
+
+        self.set_user_texture(id, TextureInfo {
+            descriptor_set,
+            multisampled: false,
+            encoded: true,
+        });
+    }
+
+    // Frees descriptor set of the texture once frames in flight are done with it, the id is never reused
+    pub fn unregister_texture(&mut self, id: TextureId) {
+        if let Some(texture) = self.user_textures_descriptors.remove(&Self::user_texture_id(id)) {
+            self.retired_descriptor_sets.retire(texture.descriptor_set);
+        }
+    }
+
+    fn set_user_texture(&mut self, id: u64, texture: TextureInfo) {
+        if let Some(old) = self.user_textures_descriptors.insert(id, texture) {
+            self.retired_descriptor_sets.retire(old.descriptor_set);
+        }
+    }
+
+    fn allocate_texture_id(&mut self) -> TextureId {
+        let id = self.next_user_texture_id;
+        self.next_user_texture_id += 1;
+        TextureId::User(id)
+    }
+
+    fn user_texture_id(id: TextureId) -> u64 {
+        match id {
+            TextureId::User(id) => id,
+            TextureId::Egui => panic!("Egui font texture is managed by renderer"),
+        }
+    }
 }
 
 impl Drop for EguiRenderer {
     fn drop(&mut self) {
+        let cmd_bufs: Vec<_> = self.frames.iter().map(|frame| frame.cmd_buf).collect();
         unsafe {
-            self.env.device().destroy_render_pass(self.render_pass, None);
+            self.env.device().free_command_buffers(self.env.command_pool(), &cmd_bufs);
             self.env.device().destroy_sampler(self.sampler, None);
+            object_tracker::destroyed(ObjectKind::Sampler);
         }
     }
 }
 
 
-fn create_render_pass(device: &ash::Device, surface_format: vk::Format) -> vk::RenderPass {
+// egui::epaint::Vertex: position, uv, sRGBA color
+fn vertex_input() -> (Vec<vk::VertexInputBindingDescription>, Vec<vk::VertexInputAttributeDescription>) {
+    let vertex_bindings = vec![
+        vk::VertexInputBindingDescription {
+            binding: 0,
+            stride: 4 * std::mem::size_of::<f32>() as u32 + 4 * std::mem::size_of::<u8>() as u32,
+            input_rate: vk::VertexInputRate::VERTEX,
+        }
+    ];
+
+    let vert_attrs = vec![
+        vk::VertexInputAttributeDescription {
+            location: 0,
+            binding: 0,
+            format: vk::Format::R32G32_SFLOAT,
+            offset: 0,
+        },
+        vk::VertexInputAttributeDescription {
+            location: 1,
+            binding: 0,
+            format: vk::Format::R32G32_SFLOAT,
+            offset: 8,
+        },
+        vk::VertexInputAttributeDescription {
+            location: 2,
+            binding: 0,
+            format: vk::Format::R8G8B8A8_UNORM,
+            offset: 16,
+        },
+    ];
+
+    (vertex_bindings, vert_attrs)
+}
+
+fn create_render_pass(env: &RenderEnv, surface_format: vk::Format) -> RenderResult<vk::RenderPass> {
     let color_attachment = vk::AttachmentDescription {
         format: surface_format,
         flags: vk::AttachmentDescriptionFlags::empty(),
@@ -448,9 +591,5 @@ fn create_render_pass(device: &ash::Device, surface_format: vk::Format) -> vk::R
         p_dependencies: subpass_dependencies.as_ptr(),
     };
 
-    unsafe {
-        device
-            .create_render_pass(&renderpass_create_info, None)
-            .expect("Failed to create render pass!")
-    }
+    env.render_passes().get_or_create(&renderpass_create_info)
 }
@@ -1,22 +1,99 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
 use egui::{Key, RawInput};
 use egui::math::{pos2, vec2};
+use winit::dpi::PhysicalPosition;
 use winit::event::{ModifiersState, VirtualKeyCode, WindowEvent};
 
 pub(crate) struct WinitInput {
-    pub scale_factor: f64,
     pub(super) raw_input: egui::RawInput,
 
     mouse_pos: egui::Pos2,
     modifiers_state: ModifiersState,
+    // finger emulating the mouse, other fingers only go to egui as touches (pinch zoom etc.)
+    pointer_touch_id: Option<u64>,
 }
 
 impl WinitInput {
-    pub fn new(init_input: RawInput, scale_factor: f64) -> WinitInput {
+    pub fn new(init_input: RawInput) -> WinitInput {
         WinitInput {
-            scale_factor: scale_factor,
             raw_input: init_input,
             mouse_pos: egui::Pos2::new(0.0, 0.0),
             modifiers_state: ModifiersState::default(),
+            pointer_touch_id: None,
+        }
+    }
+
+    fn to_points(&self, context: &egui::CtxRef, position: PhysicalPosition<f64>) -> egui::Pos2 {
+        let pixels_per_point = self
+            .raw_input
+            .pixels_per_point
+            .unwrap_or_else(|| context.pixels_per_point());
+
+        pos2(
+            position.x as f32 / pixels_per_point,
+            position.y as f32 / pixels_per_point,
+        )
+    }
+
+    // Every touch goes to egui, it tracks the fingers and computes pinch zoom from them
+    // (InputState::zoom_delta). The first finger also acts as the left mouse button
+    fn handle_touch(&mut self, context: &egui::CtxRef, touch: &winit::event::Touch) {
+        let pos = self.to_points(context, touch.location);
+
+        let mut hasher = DefaultHasher::new();
+        touch.device_id.hash(&mut hasher);
+
+        self.raw_input.events.push(egui::Event::Touch {
+            device_id: egui::TouchDeviceId(hasher.finish()),
+            id: egui::TouchId(touch.id),
+            phase: match touch.phase {
+                winit::event::TouchPhase::Started => egui::TouchPhase::Start,
+                winit::event::TouchPhase::Moved => egui::TouchPhase::Move,
+                winit::event::TouchPhase::Ended => egui::TouchPhase::End,
+                winit::event::TouchPhase::Cancelled => egui::TouchPhase::Cancel,
+            },
+            pos,
+            force: touch.force.map_or(0.0, |force| force.normalized() as f32),
+        });
+
+        if self.pointer_touch_id.is_some() && self.pointer_touch_id != Some(touch.id) {
+            return;
+        }
+
+        let modifiers = winit_to_egui_modifiers(self.modifiers_state);
+        match touch.phase {
+            winit::event::TouchPhase::Started => {
+                self.pointer_touch_id = Some(touch.id);
+                self.mouse_pos = pos;
+                self.raw_input.events.push(egui::Event::PointerMoved(pos));
+                self.raw_input.events.push(egui::Event::PointerButton {
+                    pos,
+                    button: egui::PointerButton::Primary,
+                    pressed: true,
+                    modifiers,
+                });
+            }
+            winit::event::TouchPhase::Moved => {
+                self.mouse_pos = pos;
+                self.raw_input.events.push(egui::Event::PointerMoved(pos));
+            }
+            winit::event::TouchPhase::Ended => {
+                self.pointer_touch_id = None;
+                self.raw_input.events.push(egui::Event::PointerButton {
+                    pos,
+                    button: egui::PointerButton::Primary,
+                    pressed: false,
+                    modifiers,
+                });
+                // no hover effects where the finger was lifted
+                self.raw_input.events.push(egui::Event::PointerGone);
+            }
+            winit::event::TouchPhase::Cancelled => {
+                self.pointer_touch_id = None;
+                self.raw_input.events.push(egui::Event::PointerGone);
+            }
         }
     }
 
@@ -34,9 +111,8 @@ impl WinitInput {
                         / pixels_per_point,
                 ));
             }
-            // dpi changed
+            // dpi changed, egui rebuilds its fonts for the new pixels_per_point on the next frame
             WindowEvent::ScaleFactorChanged { scale_factor, new_inner_size } => {
-                self.scale_factor = *scale_factor;
                 self.raw_input.pixels_per_point = Some(*scale_factor as f32);
                 let pixels_per_point = self
                     .raw_input
@@ -71,17 +147,12 @@ impl WinitInput {
             },
             // mouse move
             WindowEvent::CursorMoved { position, .. } => {
-                let pixels_per_point = self
-                    .raw_input
-                    .pixels_per_point
-                    .unwrap_or_else(|| context.pixels_per_point());
-                let pos = pos2(
-                    position.x as f32 / pixels_per_point,
-                    position.y as f32 / pixels_per_point,
-                );
+                let pos = self.to_points(&context, *position);
                 self.raw_input.events.push(egui::Event::PointerMoved(pos));
                 self.mouse_pos = pos;
             }
+            // touch screen, first finger emulates the mouse
+            WindowEvent::Touch(touch) => self.handle_touch(&context, touch),
             // mouse out
             WindowEvent::CursorLeft { .. } => {
                 self.raw_input.events.push(egui::Event::PointerGone);
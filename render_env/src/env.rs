@@ -1,26 +1,122 @@
 use core::ptr;
 use std::ffi::{c_void, CStr, CString};
+use std::sync::Arc;
 
-use ash::version::{DeviceV1_0, EntryV1_0, InstanceV1_0};
+use ash::version::{DeviceV1_0, EntryV1_0, InstanceV1_0, InstanceV1_1};
 use ash::vk;
 use ash::vk::{ApplicationInfo, DebugUtilsMessageSeverityFlagsEXT, DebugUtilsMessageTypeFlagsEXT, DebugUtilsMessengerCreateFlagsEXT, DebugUtilsMessengerCreateInfoEXT};
 use winit::window::Window;
 
+use super::capabilities::DeviceCapabilities;
+use super::debug_utils::DebugUtils;
+use super::pipeline_cache::PipelineCache;
+use super::render_pass_cache::RenderPassCache;
+use super::transient_pool::TransientAttachmentPool;
+use super::queue_guard::QueueGuard;
 use super::platforms;
 
+#[derive(Copy, Clone, Debug)]
+pub struct QueueFamilyIndices {
+    pub graphics: u32,
+    pub present: u32,
+}
+
+impl QueueFamilyIndices {
+    // Prefers a single family that can do both, otherwise takes the first graphics and the first present family.
+    // Without surface (headless) the graphics family is used for present.
+    fn find(instance: &ash::Instance, surface_loader: &ash::extensions::khr::Surface,
+            pdevice: vk::PhysicalDevice, surface: Option<vk::SurfaceKHR>) -> Option<QueueFamilyIndices>
+    {
+        let families = unsafe { instance.get_physical_device_queue_family_properties(pdevice) };
+
+        let mut graphics = None;
+        let mut present = None;
+        for (index, info) in families.iter().enumerate() {
+            let index = index as u32;
+            // compute passes are recorded along with the graphics ones
+            let supports_graphics = info.queue_count > 0 && info.queue_flags.contains(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE);
+            let supports_present = match surface {
+                Some(surface) => unsafe {
+                    surface_loader
+                        .get_physical_device_surface_support(pdevice, index, surface)
+                        .unwrap_or(false)
+                },
+                None => supports_graphics,
+            };
+
+            if supports_graphics && supports_present {
+                return Some(QueueFamilyIndices { graphics: index, present: index });
+            }
+
+            if supports_graphics && graphics.is_none() {
+                graphics = Some(index);
+            }
+
+            if supports_present && present.is_none() {
+                present = Some(index);
+            }
+        }
+
+        match (graphics, present) {
+            (Some(graphics), Some(present)) => Some(QueueFamilyIndices { graphics, present }),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn is_shared(&self) -> bool {
+        self.graphics == self.present
+    }
+
+    pub fn unique(&self) -> Vec<u32> {
+        if self.is_shared() {
+            vec![self.graphics]
+        } else {
+            vec![self.graphics, self.present]
+        }
+    }
+}
+
+// One memory heap as reported by VK_EXT_memory_budget, in bytes.
+// `usage` counts this process only, `budget` is how much it may use before allocations start failing or evicting
+#[derive(Copy, Clone, Debug)]
+pub struct HeapBudget {
+    pub size: u64,
+    pub device_local: bool,
+    pub usage: u64,
+    pub budget: u64,
+}
+
 #[allow(dead_code)]
 pub struct RenderEnv {
     // core
     entry: ash::Entry,
     pub(super) instance: ash::Instance,
+    // Vulkan version the instance was created with, 1.1 when available
+    api_version: u32,
     pub(super) physical_device: vk::PhysicalDevice,
     device: ash::Device,
-    queue: vk::Queue,
+    queue: QueueGuard,
+    present_queue: QueueGuard,
+    // uploads of loaded assets, second queue of the graphics family when there is one
+    transfer_queue: QueueGuard,
+    queue_family_indices: QueueFamilyIndices,
 
     pub(super) command_pool: vk::CommandPool,
+    transfer_command_pool: vk::CommandPool,
 
     // cached info
     pub mem_properties: vk::PhysicalDeviceMemoryProperties,
+    // optional features, only those the device supports are enabled
+    enabled_features: vk::PhysicalDeviceFeatures,
+    // 1.0 without the sampler_anisotropy feature
+    capabilities: DeviceCapabilities,
+    timeline_semaphores: bool,
+    // VK_EXT_memory_budget, heap usage can be queried
+    memory_budget: bool,
+    pipeline_cache: Arc<PipelineCache>,
+    render_passes: RenderPassCache,
+    transient_attachments: TransientAttachmentPool,
 
     // surface
     pub(super) surface_loader: ash::extensions::khr::Surface,
@@ -29,6 +125,8 @@ pub struct RenderEnv {
     // debug
     debug_utils_loader: ash::extensions::ext::DebugUtils,
     debug_messenger: vk::DebugUtilsMessengerEXT,
+    // object names and labels, only with validation
+    debug_utils: Option<DebugUtils>,
 }
 
 unsafe extern "system" fn vulkan_debug_utils_callback(
@@ -60,7 +158,29 @@ unsafe extern "system" fn vulkan_debug_utils_callback(
 #[allow(dead_code)]
 impl RenderEnv {
     pub fn new(window: &Window) -> RenderEnv {
+        RenderEnv::create(Some(window), 0)
+    }
+
+    // No surface and no swapchain support: render into Framebuffer and read it back
+    pub fn new_headless() -> RenderEnv {
+        RenderEnv::create(None, 0)
+    }
+
+    // Headless env on the n-th suitable GPU, see `suitable_device_count`
+    pub fn new_headless_on_device(device_index: usize) -> RenderEnv {
+        RenderEnv::create(None, device_index)
+    }
+
+    fn create(window: Option<&Window>, device_index: usize) -> RenderEnv {
         unsafe {
+            let entry = ash::Entry::new().unwrap();
+            // 1.1 is needed to query device groups, 1.2 for timeline semaphores
+            let api_version = match entry.try_enumerate_instance_version() {
+                Ok(Some(version)) if version >= vk::make_version(1, 2, 0) => vk::make_version(1, 2, 0),
+                Ok(Some(version)) if version >= vk::make_version(1, 1, 0) => vk::make_version(1, 1, 0),
+                _ => vk::make_version(1, 0, 0),
+            };
+
             let app_name = CString::new("test").unwrap();
             let engine_name = CString::new("Vulkan Engine").unwrap();
 
@@ -69,9 +189,21 @@ impl RenderEnv {
                 .application_version(0)
                 .engine_name(&engine_name)
                 .engine_version(0)
-                .api_version(vk::make_version(1, 0, 0));
+                .api_version(api_version);
 
-            let extension_names = platforms::required_extension_names();
+            let mut extension_names = match window {
+                Some(_) => platforms::required_extension_names(),
+                None => vec![ash::extensions::ext::DebugUtils::name().as_ptr()],
+            };
+            // surfaces report HDR10 and extended sRGB color spaces only with it
+            let colorspace_ext = vk::ExtSwapchainColorspaceFn::name();
+            let has_colorspace_ext = entry.enumerate_instance_extension_properties()
+                .unwrap_or_default()
+                .iter()
+                .any(|ext| CStr::from_ptr(ext.extension_name.as_ptr()) == colorspace_ext);
+            if window.is_some() && has_colorspace_ext {
+                extension_names.push(colorspace_ext.as_ptr());
+            }
 
             let mut debug_utils_create_info = DebugUtilsMessengerCreateInfoEXT {
                 s_type: vk::StructureType::DEBUG_UTILS_MESSENGER_CREATE_INFO_EXT,
@@ -98,7 +230,6 @@ impl RenderEnv {
                 .push_next(&mut debug_utils_create_info)
                 .enabled_layer_names(debug_layers.as_slice());
 
-            let entry = ash::Entry::new().unwrap();
             let instance: ash::Instance = entry
                 .create_instance(&create_info, None)
                 .expect("Failed to create instance!");
@@ -111,94 +242,161 @@ impl RenderEnv {
                 .create_debug_utils_messenger(&debug_utils_create_info, None)
                 .expect("Debug Utils Callback");
 
-            let surface = platforms::create_surface(&entry, &instance, &window).unwrap();
+            let surface = window.map(|window| platforms::create_surface(&entry, &instance, window).unwrap());
             let pdevices = instance.enumerate_physical_devices().unwrap();
-            let (physical_device, queue_family_index) = pdevices
+            let (physical_device, queue_family_indices) = pdevices
                 .iter()
-                .map(|pdevice| {
-                    instance
-                        .get_physical_device_queue_family_properties(*pdevice)
-                        .iter()
-                        .enumerate()
-                        .filter_map(|(index, ref info)| {
-                            let supports_graphic_and_surface =
-                                info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
-                                    && surface_loader
-                                    .get_physical_device_surface_support(
-                                        *pdevice,
-                                        index as u32,
-                                        surface,
-                                    )
-                                    .unwrap();
-
-                            if supports_graphic_and_surface {
-                                Some((*pdevice, index))
-                            } else {
-                                None
-                            }
-                        })
-                        .next()
+                .filter_map(|pdevice| {
+                    QueueFamilyIndices::find(&instance, &surface_loader, *pdevice, surface)
+                        .map(|indices| (*pdevice, indices))
                 })
-                .flatten()
-                .next()
+                .nth(device_index)
                 .expect("Couldn't find suitable device.");
 
             let mem_properties = instance.get_physical_device_memory_properties(physical_device);
-            let queue_family_index = queue_family_index as u32;
+            let capabilities = DeviceCapabilities::query(&instance, physical_device);
 
             // logical device
-            let queue_priorities = [1.0_f32];
-            let queue_ci = vec!(
-                vk::DeviceQueueCreateInfo::builder()
-                    .queue_family_index(0)
-                    .queue_priorities(&queue_priorities).build()
-            );
-
-            let enable_extension_names = [
-                ash::extensions::khr::Swapchain::name().as_ptr(), // currently just enable the Swapchain extension.
-            ];
+            let graphics_queue_count = instance.get_physical_device_queue_family_properties(physical_device)
+                [queue_family_indices.graphics as usize].queue_count;
+            let transfer_queue_index = if graphics_queue_count > 1 { 1 } else { 0 };
+
+            let queue_priorities = [1.0_f32, 1.0];
+            let queue_ci: Vec<_> = queue_family_indices.unique()
+                .iter()
+                .map(|&queue_family_index| {
+                    let queue_count = if queue_family_index == queue_family_indices.graphics { transfer_queue_index + 1 } else { 1 };
+                    vk::DeviceQueueCreateInfo::builder()
+                        .queue_family_index(queue_family_index)
+                        .queue_priorities(&queue_priorities[..queue_count]).build()
+                })
+                .collect();
+
+            let mut enable_extension_names = match surface {
+                Some(_) => vec![ash::extensions::khr::Swapchain::name().as_ptr()],
+                None => vec![],
+            };
+
+            // memory properties2 are core in 1.1
+            let memory_budget = api_version >= vk::make_version(1, 1, 0) && capabilities.supports_extension(vk::ExtMemoryBudgetFn::name());
+            if memory_budget {
+                enable_extension_names.push(vk::ExtMemoryBudgetFn::name().as_ptr());
+            }
+            let supported_features = capabilities.features;
             let physical_device_features = vk::PhysicalDeviceFeatures {
-                sampler_anisotropy: vk::TRUE, // enable anisotropy device feature from Chapter-24.
-                sample_rate_shading: vk::TRUE,
-                depth_clamp: vk::TRUE,
+                sampler_anisotropy: supported_features.sampler_anisotropy,
+                sample_rate_shading: supported_features.sample_rate_shading,
+                depth_clamp: supported_features.depth_clamp,
+                fill_mode_non_solid: supported_features.fill_mode_non_solid,
+                tessellation_shader: supported_features.tessellation_shader,
+                draw_indirect_first_instance: supported_features.draw_indirect_first_instance,
                 ..Default::default()
             };
+            for (name, enabled) in [
+                ("sampler_anisotropy", physical_device_features.sampler_anisotropy),
+                ("sample_rate_shading", physical_device_features.sample_rate_shading),
+                ("depth_clamp", physical_device_features.depth_clamp),
+                ("fill_mode_non_solid", physical_device_features.fill_mode_non_solid),
+                ("tessellation_shader", physical_device_features.tessellation_shader),
+                ("draw_indirect_first_instance", physical_device_features.draw_indirect_first_instance),
+            ].iter() {
+                if *enabled == vk::FALSE {
+                    println!("Device feature {} is not supported, dependent options are disabled", name);
+                }
+            }
 
-            let device_ci = vk::DeviceCreateInfo::builder()
+            // core in 1.2, users fall back to fences without them
+            let device_version = capabilities.api_version;
+            let timeline_semaphores = if api_version >= vk::make_version(1, 2, 0) && device_version >= vk::make_version(1, 2, 0) {
+                let mut timeline_features = vk::PhysicalDeviceTimelineSemaphoreFeatures::default();
+                let mut features = vk::PhysicalDeviceFeatures2 {
+                    p_next: &mut timeline_features as *mut _ as *mut c_void,
+                    ..Default::default()
+                };
+                instance.get_physical_device_features2(physical_device, &mut features);
+                timeline_features.timeline_semaphore == vk::TRUE
+            } else {
+                false
+            };
+            if !timeline_semaphores {
+                println!("Timeline semaphores are not supported, fences are used instead");
+            }
+
+            let mut timeline_features = vk::PhysicalDeviceTimelineSemaphoreFeatures::builder()
+                .timeline_semaphore(true);
+            let mut device_ci = vk::DeviceCreateInfo::builder()
                 .queue_create_infos(queue_ci.as_slice())
                 .enabled_extension_names(&enable_extension_names)
                 .enabled_features(&physical_device_features);
+            if timeline_semaphores {
+                device_ci = device_ci.push_next(&mut timeline_features);
+            }
 
             let device = instance.create_device(physical_device, &device_ci, None).unwrap();
-            let queue = device.get_device_queue(queue_family_index, 0);
+            let queue = QueueGuard::new(device.get_device_queue(queue_family_indices.graphics, 0));
+            let present_queue = queue.alias(device.get_device_queue(queue_family_indices.present, 0));
+            let transfer_queue = queue.alias(device.get_device_queue(queue_family_indices.graphics, transfer_queue_index as u32));
 
             let command_pool_create_info = vk::CommandPoolCreateInfo {
                 s_type: vk::StructureType::COMMAND_POOL_CREATE_INFO,
                 p_next: ptr::null(),
                 flags: vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER,
-                queue_family_index,
+                queue_family_index: queue_family_indices.graphics,
             };
 
             let command_pool = device
                 .create_command_pool(&command_pool_create_info, None)
                 .expect("Failed to create Command Pool!");
 
+            let transfer_command_pool_create_info = vk::CommandPoolCreateInfo {
+                s_type: vk::StructureType::COMMAND_POOL_CREATE_INFO,
+                p_next: ptr::null(),
+                flags: vk::CommandPoolCreateFlags::TRANSIENT,
+                queue_family_index: queue_family_indices.graphics,
+            };
+
+            let transfer_command_pool = device
+                .create_command_pool(&transfer_command_pool_create_info, None)
+                .expect("Failed to create Command Pool!");
+
+            let debug_utils = if debug_layers.is_empty() {
+                None
+            } else {
+                Some(DebugUtils::new(debug_utils_loader.clone(), device.handle()))
+            };
+
+            let pipeline_cache = Arc::new(PipelineCache::new(&device, &instance.get_physical_device_properties(physical_device), debug_utils.clone()));
+            let render_passes = RenderPassCache::new(&device);
+
             RenderEnv {
                 entry,
                 instance,
+                api_version,
                 physical_device,
 
-                surface,
+                surface: surface.unwrap_or_default(),
                 surface_loader,
 
                 device,
                 mem_properties,
+                enabled_features: physical_device_features,
+                capabilities,
+                timeline_semaphores,
+                memory_budget,
+                pipeline_cache,
+                render_passes,
+                transient_attachments: TransientAttachmentPool::new(),
                 queue,
+                present_queue,
+                transfer_queue,
+                queue_family_indices,
 
                 command_pool,
+                transfer_command_pool,
 
                 debug_utils_loader,
                 debug_messenger,
+                debug_utils,
             }
         }
     }
@@ -244,6 +442,30 @@ impl RenderEnv {
     }
 
 
+    // GPUs the env could be created on, with the same surface (if any)
+    pub fn suitable_device_count(&self) -> usize {
+        let surface = if self.is_headless() { None } else { Some(self.surface) };
+        let pdevices = unsafe { self.instance.enumerate_physical_devices().unwrap() };
+
+        pdevices.iter()
+            .filter(|pdevice| QueueFamilyIndices::find(&self.instance, &self.surface_loader, **pdevice, surface).is_some())
+            .count()
+    }
+
+    pub fn device_name(&self) -> String {
+        self.capabilities.device_name.clone()
+    }
+
+    // Limits, formats and extensions of the physical device, check them before relying on optional support
+    pub fn capabilities(&self) -> &DeviceCapabilities {
+        &self.capabilities
+    }
+
+    #[inline]
+    pub fn api_version(&self) -> u32 {
+        self.api_version
+    }
+
     #[inline]
     pub fn instance(&self) -> &ash::Instance {
         &self.instance
@@ -257,6 +479,11 @@ impl RenderEnv {
         self.physical_device
     }
 
+    #[inline]
+    pub fn is_headless(&self) -> bool {
+        self.surface == vk::SurfaceKHR::null()
+    }
+
     #[inline]
     pub fn surface(&self) -> vk::SurfaceKHR {
         self.surface.clone()
@@ -266,8 +493,117 @@ impl RenderEnv {
         self.command_pool.clone()
     }
 
-    pub fn queue(&self) -> vk::Queue {
-        self.queue.clone()
+    pub fn queue(&self) -> &QueueGuard {
+        &self.queue
+    }
+
+    pub fn present_queue(&self) -> &QueueGuard {
+        &self.present_queue
+    }
+
+    // Same family as the graphics queue, so uploaded resources need no ownership transfer.
+    // Falls back to the graphics queue itself on devices with a single queue
+    pub fn transfer_queue(&self) -> &QueueGuard {
+        &self.transfer_queue
+    }
+
+    // Pool for one-time upload command buffers of transfer_queue
+    pub fn transfer_command_pool(&self) -> vk::CommandPool {
+        self.transfer_command_pool
+    }
+
+    // For PipelineBuilder::device_features
+    pub fn enabled_features(&self) -> &vk::PhysicalDeviceFeatures {
+        &self.enabled_features
+    }
+
+    // Anisotropy for texture samplers, 1.0 means anisotropic filtering is off
+    pub fn max_sampler_anisotropy(&self) -> f32 {
+        self.capabilities.max_sampler_anisotropy
+    }
+
+    // Vulkan 1.2 timeline semaphores are enabled
+    pub fn supports_timeline_semaphores(&self) -> bool {
+        self.timeline_semaphores
+    }
+
+    pub fn queue_family_indices(&self) -> QueueFamilyIndices {
+        self.queue_family_indices
+    }
+
+    // Bytes used in device local heaps by this process, None without VK_EXT_memory_budget
+    pub fn device_memory_usage(&self) -> Option<u64> {
+        let heaps = self.memory_budget()?;
+        Some(heaps.iter().filter(|heap| heap.device_local).map(|heap| heap.usage).sum())
+    }
+
+    // Usage and budget of every memory heap, None without VK_EXT_memory_budget
+    pub fn memory_budget(&self) -> Option<Vec<HeapBudget>> {
+        if !self.memory_budget {
+            return None;
+        }
+
+        let mut budget = vk::PhysicalDeviceMemoryBudgetPropertiesEXT::default();
+        let mut properties = vk::PhysicalDeviceMemoryProperties2 {
+            p_next: &mut budget as *mut _ as *mut c_void,
+            ..Default::default()
+        };
+        unsafe {
+            self.instance.get_physical_device_memory_properties2(self.physical_device, &mut properties);
+        }
+
+        let heaps = &properties.memory_properties.memory_heaps[..properties.memory_properties.memory_heap_count as usize];
+        let heaps = heaps.iter()
+            .enumerate()
+            .map(|(idx, heap)| HeapBudget {
+                size: heap.size,
+                device_local: heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL),
+                usage: budget.heap_usage[idx],
+                budget: budget.heap_budget[idx],
+            })
+            .collect();
+
+        Some(heaps)
+    }
+
+    // For PipelineBuilder::pipeline_cache
+    pub fn pipeline_cache(&self) -> &Arc<PipelineCache> {
+        &self.pipeline_cache
+    }
+
+    // None when validation is off, the helpers below do nothing then
+    pub fn debug_utils(&self) -> Option<&DebugUtils> {
+        self.debug_utils.as_ref()
+    }
+
+    // Name shown for the object by RenderDoc and validation messages
+    pub fn set_debug_name<H: vk::Handle>(&self, handle: H, name: &str) {
+        if let Some(debug_utils) = &self.debug_utils {
+            debug_utils.set_name(handle, name);
+        }
+    }
+
+    // Labeled region of commands, closed by cmd_end_label()
+    pub fn cmd_begin_label(&self, command_buffer: vk::CommandBuffer, name: &str) {
+        if let Some(debug_utils) = &self.debug_utils {
+            debug_utils.begin_label(command_buffer, name);
+        }
+    }
+
+    pub fn cmd_end_label(&self, command_buffer: vk::CommandBuffer) {
+        if let Some(debug_utils) = &self.debug_utils {
+            debug_utils.end_label(command_buffer);
+        }
+    }
+
+    // Shared render passes, see RenderPassCache
+    pub fn render_passes(&self) -> &RenderPassCache {
+        &self.render_passes
+    }
+
+    // Memory aliasing of attachments, see AttachmentImage::new_transient
+    pub fn transient_attachments(&self) -> &TransientAttachmentPool {
+        &self.transient_attachments
     }
 }
 
@@ -275,12 +611,17 @@ impl Drop for RenderEnv {
     fn drop(&mut self) {
         unsafe {
             self.device.destroy_command_pool(self.command_pool, None);
+            self.device.destroy_command_pool(self.transfer_command_pool, None);
+            self.pipeline_cache.destroy();
+            self.render_passes.destroy();
 
             self.debug_utils_loader
                 .destroy_debug_utils_messenger(self.debug_messenger, None);
 
             self.device.destroy_device(None);
-            self.surface_loader.destroy_surface(self.surface, None);
+            if !self.is_headless() {
+                self.surface_loader.destroy_surface(self.surface, None);
+            }
 
             self.instance.destroy_instance(None);
         }
@@ -0,0 +1,49 @@
+use std::io;
+
+use ash::vk;
+use thiserror::Error;
+
+// Errors of the render_env constructors. The library doesn't panic on them, the application decides
+#[derive(Debug, Error)]
+pub enum RenderError {
+    #[error("failed to read {path}: {source}")]
+    Io {
+        path: String,
+        source: io::Error,
+    },
+
+    #[error("failed to reflect shader {name}: {message}")]
+    ShaderReflection {
+        name: String,
+        message: String,
+    },
+
+    #[error("invalid pipeline: {0}")]
+    InvalidPipeline(String),
+
+    #[error("descriptor (set = {set}, binding = {binding}) of {shader}: {message}")]
+    DescriptorSet {
+        shader: String,
+        set: u32,
+        binding: u32,
+        message: String,
+    },
+
+    #[error("failed to load image: {0}")]
+    Image(String),
+
+    #[error("{call} failed: {result}")]
+    Vulkan {
+        call: &'static str,
+        result: vk::Result,
+    },
+}
+
+pub type RenderResult<T> = Result<T, RenderError>;
+
+impl RenderError {
+    // For map_err of ash calls: `.map_err(RenderError::vulkan("vkCreateImage"))?`
+    pub fn vulkan(call: &'static str) -> impl FnOnce(vk::Result) -> RenderError {
+        move |result| RenderError::Vulkan { call, result }
+    }
+}
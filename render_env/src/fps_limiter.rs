@@ -1,3 +1,4 @@
+use std::collections::VecDeque;
 use std::thread;
 use std::time::Duration;
 use std::time::Instant;
@@ -5,6 +6,30 @@ use std::time::Instant;
 const SAMPLE_COUNT: usize = 5;
 const SAMPLE_COUNT_FLOAT: f32 = SAMPLE_COUNT as f32;
 
+// Frames kept for the statistics and the plot
+pub const HISTORY_FRAMES: usize = 600;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameSample {
+    // wall time between two tick_frame() calls
+    pub frame_ms: f32,
+    // time between begin_cpu_work() and end_cpu_work(), fence waits and present are not in it
+    pub cpu_ms: f32,
+    // None when the GPU time is not known, e.g. without timestamp queries
+    pub gpu_ms: Option<f32>,
+}
+
+// Statistics of the frames in the history
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameStats {
+    pub average_ms: f32,
+    pub max_ms: f32,
+    // FPS of the slowest 1% of frames, stutter shows here long before it moves the average
+    pub one_percent_low_fps: f32,
+    pub cpu_ms: f32,
+    pub gpu_ms: Option<f32>,
+}
+
 #[allow(dead_code)]
 pub struct FPSLimiter {
     counter: Instant,
@@ -12,6 +37,12 @@ pub struct FPSLimiter {
     samples: [u32; SAMPLE_COUNT],
     current_frame: usize,
     delta_frame: u32,
+
+    history: VecDeque<FrameSample>,
+    cpu_start: Option<Instant>,
+    // CPU and GPU times of the frame going to the history on the next tick
+    cpu_ms: f32,
+    gpu_ms: Option<f32>,
 }
 
 #[allow(dead_code)]
@@ -25,6 +56,10 @@ impl FPSLimiter {
             samples: [0; SAMPLE_COUNT],
             current_frame: 0,
             delta_frame: 0,
+            history: VecDeque::with_capacity(HISTORY_FRAMES),
+            cpu_start: None,
+            cpu_ms: 0.0,
+            gpu_ms: None,
         }
     }
 
@@ -37,9 +72,36 @@ impl FPSLimiter {
         let time_elapsed = self.counter.elapsed();
         self.counter = Instant::now();
 
-        self.delta_frame = time_elapsed.subsec_micros();
+        self.delta_frame = time_elapsed.as_micros().min(u32::MAX as u128) as u32;
         self.samples[self.current_frame] = self.delta_frame;
         self.current_frame = (self.current_frame + 1) % SAMPLE_COUNT;
+
+        if self.history.len() == HISTORY_FRAMES {
+            self.history.pop_front();
+        }
+        self.history.push_back(FrameSample {
+            frame_ms: self.delta_frame as f32 / 1000.0,
+            cpu_ms: self.cpu_ms,
+            gpu_ms: self.gpu_ms.take(),
+        });
+        self.cpu_ms = 0.0;
+    }
+
+    // CPU work of the frame is measured between these two, several spans of one frame add up
+    pub fn begin_cpu_work(&mut self) {
+        self.cpu_start = Some(Instant::now());
+    }
+
+    pub fn end_cpu_work(&mut self) {
+        if let Some(start) = self.cpu_start.take() {
+            self.cpu_ms += start.elapsed().as_secs_f32() * 1000.0;
+        }
+    }
+
+    // GPU time of the frame, timestamps of frames in flight arrive some frames later,
+    // so it goes to the history with the frame that read them back
+    pub fn set_gpu_time(&mut self, ms: f32) {
+        self.gpu_ms = Some(ms);
     }
 
     // TODO: this function seems not work.
@@ -62,8 +124,74 @@ impl FPSLimiter {
     }
 
     /// Return current delta time in seconds
-    /// this function ignore its second part, since the second is mostly zero.
     pub fn delta_time(&self) -> f32 {
         self.delta_frame as f32 / 1000_000.0_f32 // time in second
     }
+
+    // Oldest frame first
+    pub fn history(&self) -> impl Iterator<Item=&FrameSample> {
+        self.history.iter()
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        if self.history.is_empty() {
+            return FrameStats::default();
+        }
+
+        let count = self.history.len() as f32;
+        let mut frame_times: Vec<f32> = self.history.iter().map(|sample| sample.frame_ms).collect();
+        frame_times.sort_by(|a, b| b.total_cmp(a));
+
+        let slowest = &frame_times[..(frame_times.len() / 100).max(1)];
+        let slowest_ms = slowest.iter().sum::<f32>() / slowest.len() as f32;
+
+        let gpu_times: Vec<f32> = self.history.iter().filter_map(|sample| sample.gpu_ms).collect();
+
+        FrameStats {
+            average_ms: frame_times.iter().sum::<f32>() / count,
+            max_ms: frame_times[0],
+            one_percent_low_fps: if slowest_ms > 0.0 { 1000.0 / slowest_ms } else { 0.0 },
+            cpu_ms: self.history.iter().map(|sample| sample.cpu_ms).sum::<f32>() / count,
+            gpu_ms: if gpu_times.is_empty() { None } else { Some(gpu_times.iter().sum::<f32>() / gpu_times.len() as f32) },
+        }
+    }
+
+    // Averages of the history and a plot of frame, CPU and GPU times per frame
+    #[cfg(feature = "egui")]
+    pub fn ui(&self, ui: &mut egui::Ui) {
+        use egui::plot::{HLine, Legend, Line, Plot, Value, Values};
+
+        let stats = self.stats();
+        let average_fps = if stats.average_ms > 0.0 { 1000.0 / stats.average_ms } else { 0.0 };
+        ui.label(format!("Average: {:.2} ms ({:.1} FPS), max {:.2} ms", stats.average_ms, average_fps, stats.max_ms));
+        ui.label(format!("1% low: {:.1} FPS", stats.one_percent_low_fps));
+        match stats.gpu_ms {
+            Some(gpu_ms) => ui.label(format!("CPU: {:.2} ms, GPU: {:.2} ms", stats.cpu_ms, gpu_ms)),
+            None => ui.label(format!("CPU: {:.2} ms, GPU: -", stats.cpu_ms)),
+        };
+
+        // x is negative: frames before the last one
+        let last = self.history.len() as f64 - 1.0;
+        let line = |value: fn(&FrameSample) -> Option<f32>| {
+            let values = self.history.iter().enumerate()
+                .filter_map(|(idx, sample)| value(sample).map(|ms| Value::new(idx as f64 - last, ms)))
+                .collect();
+            Line::new(Values::from_values(values))
+        };
+
+        let mut plot = Plot::new("frame timing")
+            .height(140.0)
+            .include_y(0.0)
+            .allow_drag(false)
+            .allow_zoom(false)
+            .legend(Legend::default())
+            .line(line(|sample| Some(sample.frame_ms)).name("Frame"))
+            .line(line(|sample| Some(sample.cpu_ms)).name("CPU"))
+            .hline(HLine::new(stats.average_ms).name("Average"));
+        if stats.gpu_ms.is_some() {
+            plot = plot.line(line(|sample| sample.gpu_ms).name("GPU"));
+        }
+
+        ui.add(plot);
+    }
 }
@@ -1,12 +1,19 @@
 use core::ptr;
+#[cfg(feature = "image")]
+use std::path::Path;
 use std::sync::Arc;
 
 use ash::version::DeviceV1_0;
 use ash::vk;
 
 use crate::attachment_texture::AttachmentImage;
+use crate::barrier::AccessPattern;
 use crate::env;
-use crate::utils::format_has_depth;
+use crate::memory_tracker::{self, MemoryCategory};
+use crate::object_tracker::{self, ObjectKind};
+use crate::transient_pool::PassRange;
+use crate::utils::{DepthMode, format_has_depth, format_is_float_depth};
+use crate::utils::{buffer_utils, readback};
 
 
 #[derive(Clone)]
@@ -15,44 +22,133 @@ pub struct AttachmentDesciption {
     pub samples_count: vk::SampleCountFlags,
 }
 
+// Attachments of one subpass, by index in the framebuffer attachments
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Subpass {
+    // written, in location order
+    pub colors: Vec<usize>,
+    // read with subpassLoad(), in input_attachment_index order, written by earlier subpasses
+    pub inputs: Vec<usize>,
+    // depth attachment is tested and written, read-only when it is one of the inputs too
+    pub depth: bool,
+}
+
+impl Subpass {
+    // All color attachments and depth, the single subpass of Framebuffer::new()
+    pub fn all(attachment_desc: &[AttachmentDesciption]) -> Subpass {
+        Subpass {
+            colors: (0..attachment_desc.len()).filter(|&idx| !format_has_depth(attachment_desc[idx].format)).collect(),
+            inputs: vec![],
+            depth: attachment_desc.iter().any(|desc| format_has_depth(desc.format)),
+        }
+    }
+
+    fn uses(&self, attachment: usize, depth_attachment: Option<usize>) -> bool {
+        self.colors.contains(&attachment) || self.inputs.contains(&attachment) || (self.depth && depth_attachment == Some(attachment))
+    }
+
+    fn writes(&self, attachment: usize, depth_attachment: Option<usize>) -> bool {
+        self.colors.contains(&attachment) || (self.depth && depth_attachment == Some(attachment) && !self.inputs.contains(&attachment))
+    }
+}
+
 pub struct Framebuffer {
     attachment_desc: Vec<AttachmentDesciption>,
+    subpass_count: usize,
     pub render_pass: vk::RenderPass,
 
     pub framebuffer: Option<vk::Framebuffer>,
     pub attachments: Vec<AttachmentImage>,
     dimensions: [u32; 2],
+    depth_mode: DepthMode,
+    // prefix of the attachment names, see set_name()
+    name: Option<String>,
+    // attachments alias memory with others, see set_transient()
+    transient: Option<PassRange>,
+    // what the attachment memory is counted as, see set_memory_category()
+    memory_category: Option<MemoryCategory>,
 
     env: Arc<env::RenderEnv>,
 }
 
 impl Framebuffer {
     pub fn new(env: Arc<env::RenderEnv>, attachment_desc: Vec<AttachmentDesciption>) -> Framebuffer {
-        let render_pass = Framebuffer::_create_render_pass(env.device(), &attachment_desc);
+        let subpasses = vec![Subpass::all(&attachment_desc)];
+        Self::with_subpasses(env, attachment_desc, subpasses)
+    }
+
+    // Render pass of several subpasses, later ones may read attachments written by earlier ones as input attachments.
+    // On tiled GPUs such attachments can stay in tile memory. Draws of subpass N use pipelines built for subpass N,
+    // see PrimaryCommandBuffer::execute_subpasses()
+    pub fn with_subpasses(env: Arc<env::RenderEnv>, attachment_desc: Vec<AttachmentDesciption>, subpasses: Vec<Subpass>) -> Framebuffer {
+        let render_pass = Framebuffer::_create_render_pass(&env, &attachment_desc, &subpasses);
 
         Framebuffer {
             env,
             attachment_desc,
+            subpass_count: subpasses.len(),
             render_pass,
             framebuffer: None,
             attachments: vec![],
             dimensions: [0, 0],
+            depth_mode: DepthMode::Standard,
+            name: None,
+            transient: None,
+            memory_category: None,
+        }
+    }
+
+    // Names the framebuffer and attachments for RenderDoc and validation messages, the render pass is
+    // shared through RenderPassCache and stays unnamed. They are named when created, call before resize_swapchain()
+    pub fn set_name(&mut self, name: &str) {
+        self.name = Some(name.to_string());
+    }
+
+    // Attachments take memory from the transient pool of the env and are valid only in `lifetime` passes.
+    // The render pass clears them, so only the passes reading them must be in the range. Call before resize_swapchain()
+    pub fn set_transient(&mut self, lifetime: PassRange) {
+        self.transient = Some(lifetime);
+    }
+
+    // Attachment memory is counted as `category` instead of render targets by the memory tracker. Call before resize_swapchain()
+    pub fn set_memory_category(&mut self, category: MemoryCategory) {
+        self.memory_category = Some(category);
+    }
+
+    // Depth convention of the passes drawing into the framebuffer, see depth_clear_value()
+    pub fn set_depth_mode(&mut self, depth_mode: DepthMode) {
+        let depth_format = self.attachment_desc.iter()
+            .map(|desc| desc.format)
+            .find(|format| format_has_depth(*format));
+
+        if let Some(format) = depth_format {
+            if depth_mode.is_reversed() && !format_is_float_depth(format) {
+                println!("Reverse-Z with {:?} depth: no precision gain, use a float depth format", format);
+            }
         }
+        self.depth_mode = depth_mode;
+    }
+
+    pub fn depth_mode(&self) -> DepthMode {
+        self.depth_mode
+    }
+
+    // Clear value of the depth attachment: the far plane of the depth mode
+    pub fn depth_clear_value(&self) -> vk::ClearValue {
+        self.depth_mode.clear_value()
     }
 
     fn _create_render_pass(
-        device: &ash::Device,
-        descriptions: &Vec<AttachmentDesciption>,
+        env: &env::RenderEnv,
+        descriptions: &[AttachmentDesciption],
+        subpasses: &[Subpass],
     ) -> vk::RenderPass
     {
         let mut attachments: Vec<vk::AttachmentDescription> = vec![];
 
-        let mut color_attachments_refs: Vec<vk::AttachmentReference> = vec![];
-        let mut depth_attachment_ref: Vec<vk::AttachmentReference> = vec![];
-
-        for (attachment_idx, attachment_info) in descriptions.iter().enumerate() {
+        for attachment_info in descriptions.iter() {
             let final_layout = if format_has_depth(attachment_info.format) {
-                vk::ImageLayout::DEPTH_ATTACHMENT_STENCIL_READ_ONLY_OPTIMAL
+                vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL
             } else {
                 vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
             };
@@ -68,39 +164,64 @@ impl Framebuffer {
                 initial_layout: vk::ImageLayout::UNDEFINED,
                 final_layout,
             });
+        }
 
-            let attachment_ref = vk::AttachmentReference {
-                attachment: attachment_idx as u32,
-                layout: if format_has_depth(attachment_info.format) {
-                    vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
-                } else {
-                    vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
-                },
-            };
+        let depth_attachment = descriptions.iter().position(|desc| format_has_depth(desc.format));
+        let attachment_ref = |attachment: usize, layout: vk::ImageLayout| vk::AttachmentReference {
+            attachment: attachment as u32,
+            layout,
+        };
 
-            if format_has_depth(attachment_info.format) {
-                depth_attachment_ref.push(attachment_ref);
-            } else {
-                color_attachments_refs.push(attachment_ref);
-            }
-        }
+        // refs of every subpass: colors, inputs, depth, preserved
+        let refs: Vec<_> = subpasses.iter()
+            .enumerate()
+            .map(|(subpass_idx, subpass)| {
+                let colors: Vec<_> = subpass.colors.iter()
+                    .map(|&idx| attachment_ref(idx, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL))
+                    .collect();
+                let inputs: Vec<_> = subpass.inputs.iter()
+                    .map(|&idx| attachment_ref(idx, if Some(idx) == depth_attachment {
+                        vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL
+                    } else {
+                        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+                    }))
+                    .collect();
+                let depth: Vec<_> = depth_attachment.filter(|_| subpass.depth)
+                    .map(|idx| attachment_ref(idx, if subpass.inputs.contains(&idx) {
+                        vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL
+                    } else {
+                        vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+                    }))
+                    .into_iter()
+                    .collect();
+                // contents of attachments skipped by a subpass are kept only when it preserves them
+                let preserves: Vec<_> = (0..descriptions.len())
+                    .filter(|&idx| !subpass.uses(idx, depth_attachment))
+                    .filter(|&idx| subpasses[..subpass_idx].iter().any(|earlier| earlier.uses(idx, depth_attachment)))
+                    .filter(|&idx| subpasses[subpass_idx + 1..].iter().any(|later| later.uses(idx, depth_attachment)))
+                    .map(|idx| idx as u32)
+                    .collect();
 
-        let subpass = vec!(
-            vk::SubpassDescription {
+                (colors, inputs, depth, preserves)
+            })
+            .collect();
+
+        let subpass_descriptions: Vec<_> = refs.iter()
+            .map(|(colors, inputs, depth, preserves)| vk::SubpassDescription {
                 flags: Default::default(),
                 pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
-                input_attachment_count: 0,
-                p_input_attachments: ptr::null(),
-                color_attachment_count: color_attachments_refs.len() as u32,
-                p_color_attachments: color_attachments_refs.as_ptr(),
+                input_attachment_count: inputs.len() as u32,
+                p_input_attachments: if inputs.is_empty() { ptr::null() } else { inputs.as_ptr() },
+                color_attachment_count: colors.len() as u32,
+                p_color_attachments: colors.as_ptr(),
                 p_resolve_attachments: ptr::null(),
-                p_depth_stencil_attachment: depth_attachment_ref.as_ptr(),
-                preserve_attachment_count: 0,
-                p_preserve_attachments: ptr::null(),
-            }
-        );
+                p_depth_stencil_attachment: depth.first().map_or(ptr::null(), |r| r as *const _),
+                preserve_attachment_count: preserves.len() as u32,
+                p_preserve_attachments: if preserves.is_empty() { ptr::null() } else { preserves.as_ptr() },
+            })
+            .collect();
 
-        let subpass_deps = vec!(
+        let mut subpass_deps = vec!(
             vk::SubpassDependency {
                 src_subpass: vk::SUBPASS_EXTERNAL,
                 dst_subpass: 0,
@@ -110,32 +231,75 @@ impl Framebuffer {
                 dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
                 dependency_flags: vk::DependencyFlags::BY_REGION,
             },
-            vk::SubpassDependency {
-                src_subpass: 0,
-                dst_subpass: vk::SUBPASS_EXTERNAL,
-                src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-                dst_stage_mask: vk::PipelineStageFlags::BOTTOM_OF_PIPE,
-                src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
-                dst_access_mask: vk::AccessFlags::MEMORY_READ,
-                dependency_flags: vk::DependencyFlags::BY_REGION,
-            }
         );
 
+        // a subpass waits for the earlier ones writing what it reads or draws over, per pixel
+        for (dst_idx, dst) in subpasses.iter().enumerate() {
+            for (src_idx, src) in subpasses[..dst_idx].iter().enumerate() {
+                let shared: Vec<_> = (0..descriptions.len())
+                    .filter(|&idx| src.writes(idx, depth_attachment) && dst.uses(idx, depth_attachment))
+                    .collect();
+                if shared.is_empty() {
+                    continue;
+                }
+
+                let mut dst_stage_mask = vk::PipelineStageFlags::empty();
+                let mut dst_access_mask = vk::AccessFlags::empty();
+                if shared.iter().any(|idx| dst.inputs.contains(idx)) {
+                    dst_stage_mask |= vk::PipelineStageFlags::FRAGMENT_SHADER;
+                    dst_access_mask |= vk::AccessFlags::INPUT_ATTACHMENT_READ;
+                }
+                if shared.iter().any(|idx| dst.colors.contains(idx)) {
+                    dst_stage_mask |= vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT;
+                    dst_access_mask |= vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE;
+                }
+                if dst.depth && shared.iter().any(|&idx| Some(idx) == depth_attachment) {
+                    dst_stage_mask |= vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS;
+                    dst_access_mask |= vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE;
+                }
+
+                subpass_deps.push(vk::SubpassDependency {
+                    src_subpass: src_idx as u32,
+                    dst_subpass: dst_idx as u32,
+                    src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                    dst_stage_mask,
+                    src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                    dst_access_mask,
+                    dependency_flags: vk::DependencyFlags::BY_REGION,
+                });
+            }
+        }
+
+        // attachments are sampled by following passes
+        for (subpass_idx, subpass) in subpasses.iter().enumerate() {
+            if (0..descriptions.len()).any(|idx| subpass.writes(idx, depth_attachment)) {
+                subpass_deps.push(vk::SubpassDependency {
+                    src_subpass: subpass_idx as u32,
+                    dst_subpass: vk::SUBPASS_EXTERNAL,
+                    src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                    dst_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER,
+                    src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                    dst_access_mask: vk::AccessFlags::SHADER_READ,
+                    dependency_flags: vk::DependencyFlags::empty(),
+                });
+            }
+        }
+
         let render_pass_create_info = vk::RenderPassCreateInfo {
             s_type: vk::StructureType::RENDER_PASS_CREATE_INFO,
             p_next: ptr::null(),
             flags: Default::default(),
             attachment_count: attachments.len() as u32,
             p_attachments: attachments.as_ptr(),
-            subpass_count: subpass.len() as u32,
-            p_subpasses: subpass.as_ptr(),
+            subpass_count: subpass_descriptions.len() as u32,
+            p_subpasses: subpass_descriptions.as_ptr(),
             dependency_count: subpass_deps.len() as u32,
             p_dependencies: subpass_deps.as_ptr(),
         };
 
-        unsafe {
-            device.create_render_pass(&render_pass_create_info, None).unwrap()
-        }
+        env.render_passes()
+            .get_or_create(&render_pass_create_info)
+            .expect("Failed to create render pass!")
     }
 
     pub fn resize_swapchain(&mut self, dimensions: [u32; 2]) {
@@ -144,32 +308,45 @@ impl Framebuffer {
             unsafe {
                 self.env.device().destroy_framebuffer(self.framebuffer.unwrap(), None)
             };
+            object_tracker::destroyed(ObjectKind::Framebuffer);
         };
 
         let mut images = vec!();
         let mut views = vec!();
 
+        let _category = self.memory_category.map(memory_tracker::category_scope);
         for desc in self.attachment_desc.iter() {
             let mut usage = vk::ImageUsageFlags::INPUT_ATTACHMENT | vk::ImageUsageFlags::SAMPLED;
 
             if format_has_depth(desc.format) {
-                usage |= vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT;
+                // TRANSFER_SRC for depth readback
+                usage |= vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC;
             } else {
-                usage |= vk::ImageUsageFlags::COLOR_ATTACHMENT;
+                // TRANSFER_SRC for readback
+                usage |= vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC;
             }
 
-            let img = AttachmentImage::new(
-                &self.env,
-                dimensions,
-                desc.format,
-                1,
-                desc.samples_count,
-                usage,
-            );
+            let img = match self.transient {
+                Some(lifetime) => AttachmentImage::new_transient(&self.env, dimensions, desc.format, 1, desc.samples_count, usage, lifetime),
+                None => AttachmentImage::new(&self.env, dimensions, desc.format, 1, desc.samples_count, usage),
+            };
+
+            if let Some(name) = &self.name {
+                self.env.set_debug_name(img.image(), &format!("{} attachment {}", name, images.len()));
+            }
 
             views.push(img.view);
             images.push(img);
         }
+
+        // Outside of the render pass attachments always stay in their final (sampled) layout,
+        // so they may be sampled even before the first frame is rendered
+        let command_buffer = buffer_utils::begin_single_time_command(self.env.device(), self.env.command_pool());
+        for img in images.iter() {
+            img.layout.transition(self.env.device(), command_buffer, img.image(), img.subresource_range(), img.sampled_pattern());
+        }
+        buffer_utils::end_single_time_command(self.env.device(), self.env.command_pool(), self.env.queue(), command_buffer);
+
         self.attachments = images;
 
         let framebuffer_info = vk::FramebufferCreateInfo {
@@ -187,6 +364,10 @@ impl Framebuffer {
         let framebuffer = unsafe {
             self.env.device().create_framebuffer(&framebuffer_info, None).unwrap()
         };
+        object_tracker::created(ObjectKind::Framebuffer);
+        if let Some(name) = &self.name {
+            self.env.set_debug_name(framebuffer, name);
+        }
 
         self.framebuffer = Some(framebuffer);
         self.dimensions = dimensions;
@@ -196,9 +377,8 @@ impl Framebuffer {
         unsafe {
             if self.framebuffer.is_some() {
                 self.env.device().destroy_framebuffer(self.framebuffer.unwrap(), None);
+                object_tracker::destroyed(ObjectKind::Framebuffer);
             };
-
-            self.env.device().destroy_render_pass(self.render_pass, None);
         }
     }
 
@@ -206,4 +386,37 @@ impl Framebuffer {
     pub fn render_pass(&self) -> vk::RenderPass {
         self.render_pass
     }
+
+    #[inline]
+    pub fn dimensions(&self) -> [u32; 2] {
+        self.dimensions
+    }
+
+    #[inline]
+    pub fn subpass_count(&self) -> usize {
+        self.subpass_count
+    }
+
+    // RGBA8 pixels of a single sampled color attachment, the render pass writing it must be finished
+    pub fn read_color_attachment(&self, idx: usize) -> Vec<u8> {
+        let attachment = &self.attachments[idx];
+        assert_eq!(attachment.samples, vk::SampleCountFlags::TYPE_1, "Multisampled attachment can't be read back!");
+        attachment.layout.expect(AccessPattern::SampledRead, "read back");
+
+        readback::read_color_image(&self.env, attachment.image(), attachment.format, self.dimensions)
+    }
+
+    // Depth of a single sampled depth attachment as stored, the render pass writing it must be finished
+    pub fn read_depth_attachment(&self, idx: usize) -> Vec<f32> {
+        let attachment = &self.attachments[idx];
+        assert_eq!(attachment.samples, vk::SampleCountFlags::TYPE_1, "Multisampled attachment can't be read back!");
+        attachment.layout.expect(AccessPattern::DepthSampledRead, "read back");
+
+        readback::read_depth_image(&self.env, attachment.image(), attachment.format, self.dimensions, 0)
+    }
+
+    #[cfg(feature = "image")]
+    pub fn save_color_attachment<P: AsRef<Path>>(&self, idx: usize, path: P) -> image::ImageResult<()> {
+        readback::save_rgba(path, self.read_color_attachment(idx), self.dimensions)
+    }
 }
@@ -0,0 +1,467 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+
+use ash::version::{DeviceV1_0, DeviceV1_2};
+use ash::vk;
+
+use crate::barrier::{self, AccessPattern};
+use crate::env::RenderEnv;
+use crate::memory_tracker;
+use crate::object_tracker::{self, ObjectKind};
+use crate::queue_guard::QueueGuard;
+use crate::utils::buffer_utils;
+
+// Frames waiting for the writer thread, the capture drops frames when it falls behind
+const WRITER_QUEUE: usize = 4;
+
+pub enum CaptureOutput {
+    // one file per frame in the directory: frame_000000.png, ...
+    #[cfg(feature = "image")]
+    Png(PathBuf),
+    // uncompressed YUV 4:4:4 video, `fps` is written to the header
+    Y4m { path: PathBuf, fps: u32 },
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CaptureStats {
+    // frames copied from the swapchain
+    pub captured: u64,
+    pub written: u64,
+    // ring or writer queue was full when the frame was due
+    pub dropped: u64,
+}
+
+struct CapturedFrame {
+    index: u64,
+    // RGBA8, rows tightly packed
+    pixels: Vec<u8>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SlotState {
+    Free,
+    // copy is recorded for the frame, waits for submit()
+    Recorded { frame: u64 },
+    // timeline value (0 with fences) of the submitted copy
+    InFlight { value: u64, frame: u64 },
+}
+
+struct Slot {
+    buffer: vk::Buffer,
+    memory: vk::DeviceMemory,
+    mapped: *const u8,
+    command_buffer: vk::CommandBuffer,
+    // signaled with the copy when timeline semaphores are not supported
+    fence: vk::Fence,
+    state: SlotState,
+}
+
+// Copies every Nth presented frame into a ring of host visible buffers without waiting for the GPU.
+// A copy is done when the timeline semaphore reaches its value (or its fence is signaled),
+// then the pixels go to a writer thread. Per frame: record() before the frame submit,
+// submit() right after it, both on the queue the frame is rendered on
+pub struct FrameCapture {
+    slots: Vec<Slot>,
+    timeline: Option<vk::Semaphore>,
+    timeline_value: u64,
+    // slot recorded for the current frame, signaled by submit()
+    recorded: Option<usize>,
+
+    every_nth: u64,
+    frame: u64,
+    dimensions: [u32; 2],
+    bgra: bool,
+
+    stats: CaptureStats,
+    written: Arc<AtomicU64>,
+    sender: Option<SyncSender<CapturedFrame>>,
+    writer: Option<JoinHandle<Result<(), String>>>,
+
+    env: Arc<RenderEnv>,
+}
+
+impl FrameCapture {
+    // `format` - of the captured swapchain images, 8 bit RGBA or BGRA
+    pub fn new(env: Arc<RenderEnv>, output: CaptureOutput, format: vk::Format, dimensions: [u32; 2],
+               every_nth: u32, ring_size: usize) -> Result<FrameCapture, String> {
+        let bgra = match format {
+            vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => false,
+            vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => true,
+            _ => return Err(format!("Capture of {:?} images is not supported", format)),
+        };
+
+        let writer = FrameWriter::create(output, dimensions)?;
+        let written = Arc::new(AtomicU64::new(0));
+        let (sender, receiver) = mpsc::sync_channel(WRITER_QUEUE);
+        let writer = {
+            let written = written.clone();
+            thread::Builder::new()
+                .name("frame capture".to_string())
+                .spawn(move || writer.run(receiver, &written))
+                .expect("Failed to spawn capture thread!")
+        };
+
+        let timeline = if env.supports_timeline_semaphores() {
+            let mut type_create_info = vk::SemaphoreTypeCreateInfo::builder()
+                .semaphore_type(vk::SemaphoreType::TIMELINE)
+                .initial_value(0);
+            let create_info = vk::SemaphoreCreateInfo::builder()
+                .push_next(&mut type_create_info);
+
+            let semaphore = unsafe {
+                env.device().create_semaphore(&create_info, None)
+                    .expect("Failed to create timeline semaphore!")
+            };
+            Some(semaphore)
+        } else {
+            None
+        };
+
+        let slots = (0..ring_size.max(1))
+            .map(|_| Self::create_slot(&env, dimensions))
+            .collect();
+
+        Ok(FrameCapture {
+            slots,
+            timeline,
+            timeline_value: 0,
+            recorded: None,
+            every_nth: every_nth.max(1) as u64,
+            frame: 0,
+            dimensions,
+            bgra,
+            stats: CaptureStats::default(),
+            written,
+            sender: Some(sender),
+            writer: Some(writer),
+            env,
+        })
+    }
+
+    fn create_slot(env: &RenderEnv, dimensions: [u32; 2]) -> Slot {
+        let device = env.device();
+        let size = (dimensions[0] * dimensions[1] * 4) as vk::DeviceSize;
+        let (buffer, memory) = buffer_utils::create_buffer(
+            device,
+            size,
+            vk::BufferUsageFlags::TRANSFER_DST,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            &env.mem_properties,
+        );
+
+        let fence_create_info = vk::FenceCreateInfo {
+            s_type: vk::StructureType::FENCE_CREATE_INFO,
+            p_next: ptr::null(),
+            flags: vk::FenceCreateFlags::empty(),
+        };
+
+        unsafe {
+            let mapped = device
+                .map_memory(memory, 0, size, vk::MemoryMapFlags::empty())
+                .expect("Failed to Map Memory") as *const u8;
+
+            Slot {
+                buffer,
+                memory,
+                mapped,
+                command_buffer: env.create_primary_command_buffer(),
+                fence: device.create_fence(&fence_create_info, None).expect("Failed to create Fence Object!"),
+                state: SlotState::Free,
+            }
+        }
+    }
+
+    pub fn dimensions(&self) -> [u32; 2] {
+        self.dimensions
+    }
+
+    pub fn stats(&self) -> CaptureStats {
+        CaptureStats {
+            written: self.written.load(Ordering::Relaxed),
+            ..self.stats
+        }
+    }
+
+    // Command buffer copying the presented image, None when the frame is skipped or dropped.
+    // Submit it after the final pass and before present, the image must be in PRESENT_SRC_KHR layout
+    pub fn record(&mut self, image: vk::Image) -> Option<vk::CommandBuffer> {
+        self.poll();
+
+        let frame = self.frame;
+        self.frame += 1;
+        if !frame.is_multiple_of(self.every_nth) {
+            return None;
+        }
+
+        let slot_idx = match self.slots.iter().position(|slot| slot.state == SlotState::Free) {
+            Some(slot_idx) => slot_idx,
+            None => {
+                self.stats.dropped += 1;
+                return None;
+            }
+        };
+        let slot = &mut self.slots[slot_idx];
+        slot.state = SlotState::Recorded { frame };
+        self.recorded = Some(slot_idx);
+
+        let device = self.env.device();
+        let command_buffer = slot.command_buffer;
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_BEGIN_INFO,
+            p_next: ptr::null(),
+            p_inheritance_info: ptr::null(),
+            flags: vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT,
+        };
+
+        let region = vk::BufferImageCopy {
+            buffer_offset: 0,
+            buffer_row_length: 0,
+            buffer_image_height: 0,
+            image_subresource: vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+            image_extent: vk::Extent3D {
+                width: self.dimensions[0],
+                height: self.dimensions[1],
+                depth: 1,
+            },
+        };
+        let range = barrier::color_range(0, 1, 1);
+
+        unsafe {
+            device.reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::default())
+                .expect("Failed to reset Command Buffer!");
+            device.begin_command_buffer(command_buffer, &command_buffer_begin_info)
+                .expect("Failed to begin recording Command Buffer at beginning!");
+
+            barrier::cmd_image_barrier(device, command_buffer, image, range, AccessPattern::Present, AccessPattern::TransferRead);
+            device.cmd_copy_image_to_buffer(command_buffer, image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL, slot.buffer, &[region]);
+            barrier::cmd_image_barrier(device, command_buffer, image, range, AccessPattern::TransferRead, AccessPattern::Present);
+            barrier::cmd_buffer_barrier(device, command_buffer, slot.buffer, AccessPattern::TransferWrite, AccessPattern::HostRead);
+
+            device.end_command_buffer(command_buffer)
+                .expect("Failed to record Command Buffer at Ending!");
+        }
+
+        Some(command_buffer)
+    }
+
+    // Signals completion of the copy recorded this frame. Signal operations cover all work
+    // submitted earlier to the queue, so an empty submission is enough
+    pub fn submit(&mut self, queue: &QueueGuard) {
+        let slot_idx = match self.recorded.take() {
+            Some(slot_idx) => slot_idx,
+            None => return,
+        };
+        let device = self.env.device();
+        let slot = &mut self.slots[slot_idx];
+        let frame = match slot.state {
+            SlotState::Recorded { frame } => frame,
+            _ => panic!("Capture slot {} is not recorded", slot_idx),
+        };
+
+        match self.timeline {
+            Some(timeline) => {
+                self.timeline_value += 1;
+                let signal_values = [self.timeline_value];
+                let signal_semaphores = [timeline];
+                let mut timeline_info = vk::TimelineSemaphoreSubmitInfo::builder()
+                    .signal_semaphore_values(&signal_values);
+                let submit_info = vk::SubmitInfo::builder()
+                    .signal_semaphores(&signal_semaphores)
+                    .push_next(&mut timeline_info)
+                    .build();
+
+                queue.submit(device, &[submit_info], vk::Fence::null())
+                    .expect("Failed to execute queue submit.");
+                slot.state = SlotState::InFlight { value: self.timeline_value, frame };
+            }
+            None => {
+                unsafe {
+                    device.reset_fences(&[slot.fence]).expect("Failed to reset Fence!");
+                }
+                queue.submit(device, &[], slot.fence)
+                    .expect("Failed to execute queue submit.");
+                slot.state = SlotState::InFlight { value: 0, frame };
+            }
+        }
+        self.stats.captured += 1;
+    }
+
+    // Hands finished copies to the writer thread, never waits for the GPU
+    pub fn poll(&mut self) {
+        let device = self.env.device();
+        let timeline_value = self.timeline.map(|timeline| unsafe {
+            device.get_semaphore_counter_value(timeline)
+                .expect("Failed to get semaphore counter value!")
+        });
+
+        let finished: Vec<(usize, u64)> = self.slots.iter()
+            .enumerate()
+            .filter_map(|(slot_idx, slot)| match slot.state {
+                SlotState::InFlight { value, frame } => {
+                    let done = match timeline_value {
+                        Some(timeline_value) => timeline_value >= value,
+                        None => unsafe { device.get_fence_status(slot.fence).unwrap_or(false) },
+                    };
+                    if done { Some((slot_idx, frame)) } else { None }
+                }
+                SlotState::Free | SlotState::Recorded { .. } => None,
+            })
+            .collect();
+
+        for (slot_idx, frame) in finished {
+            self.finish_slot(slot_idx, frame);
+        }
+    }
+
+    fn finish_slot(&mut self, slot_idx: usize, frame: u64) {
+        let size = (self.dimensions[0] * self.dimensions[1] * 4) as usize;
+        let slot = &mut self.slots[slot_idx];
+
+        let mut pixels = vec![0u8; size];
+        unsafe {
+            slot.mapped.copy_to_nonoverlapping(pixels.as_mut_ptr(), size);
+        }
+        slot.state = SlotState::Free;
+
+        if self.bgra {
+            for pixel in pixels.chunks_exact_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+
+        let sender = self.sender.as_ref().unwrap();
+        match sender.try_send(CapturedFrame { index: frame / self.every_nth, pixels }) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => self.stats.dropped += 1,
+            // the writer failed, the error is returned by finish()
+            Err(TrySendError::Disconnected(_)) => self.stats.dropped += 1,
+        }
+    }
+
+    // Waits for copies in flight and for the writer to save them
+    pub fn finish(mut self) -> Result<CaptureStats, String> {
+        unsafe {
+            self.env.device().device_wait_idle().expect("Failed to wait device idle!");
+        }
+        // a copy recorded but never submitted won't finish
+        if let Some(slot_idx) = self.recorded.take() {
+            self.slots[slot_idx].state = SlotState::Free;
+        }
+        self.poll();
+
+        self.sender = None;
+        let result = self.writer.take().unwrap().join()
+            .unwrap_or_else(|_| Err("Capture thread panicked".to_string()));
+
+        result.map(|()| self.stats())
+    }
+}
+
+impl Drop for FrameCapture {
+    // Device must be idle
+    fn drop(&mut self) {
+        let device = self.env.device();
+        unsafe {
+            for slot in self.slots.iter() {
+                device.unmap_memory(slot.memory);
+                device.destroy_buffer(slot.buffer, None);
+                object_tracker::destroyed(ObjectKind::Buffer);
+                device.free_memory(slot.memory, None);
+                memory_tracker::freed(slot.memory);
+                device.destroy_fence(slot.fence, None);
+                device.free_command_buffers(self.env.command_pool(), &[slot.command_buffer]);
+            }
+
+            if let Some(timeline) = self.timeline {
+                device.destroy_semaphore(timeline, None);
+            }
+        }
+
+        // the writer stops when the channel is closed
+        self.sender = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+enum FrameWriter {
+    #[cfg(feature = "image")]
+    Png { dir: PathBuf, dimensions: [u32; 2] },
+    Y4m { file: BufWriter<File>, dimensions: [u32; 2] },
+}
+
+impl FrameWriter {
+    fn create(output: CaptureOutput, dimensions: [u32; 2]) -> Result<FrameWriter, String> {
+        match output {
+            #[cfg(feature = "image")]
+            CaptureOutput::Png(dir) => {
+                std::fs::create_dir_all(&dir)
+                    .map_err(|err| format!("Failed to create {}: {}", dir.display(), err))?;
+                Ok(FrameWriter::Png { dir, dimensions })
+            }
+            CaptureOutput::Y4m { path, fps } => {
+                let mut file = File::create(&path)
+                    .map(BufWriter::new)
+                    .map_err(|err| format!("Failed to create {}: {}", path.display(), err))?;
+                writeln!(file, "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444", dimensions[0], dimensions[1], fps.max(1))
+                    .map_err(|err| format!("Failed to write {}: {}", path.display(), err))?;
+                Ok(FrameWriter::Y4m { file, dimensions })
+            }
+        }
+    }
+
+    fn run(mut self, receiver: Receiver<CapturedFrame>, written: &AtomicU64) -> Result<(), String> {
+        for frame in receiver {
+            self.write(frame)?;
+            written.fetch_add(1, Ordering::Relaxed);
+        }
+
+        match &mut self {
+            #[cfg(feature = "image")]
+            FrameWriter::Png { .. } => Ok(()),
+            FrameWriter::Y4m { file, .. } => file.flush().map_err(|err| format!("Failed to write video: {}", err)),
+        }
+    }
+
+    fn write(&mut self, frame: CapturedFrame) -> Result<(), String> {
+        match self {
+            #[cfg(feature = "image")]
+            FrameWriter::Png { dir, dimensions } => {
+                let path = dir.join(format!("frame_{:06}.png", frame.index));
+                crate::utils::readback::save_rgba(&path, frame.pixels, *dimensions)
+                    .map_err(|err| format!("Failed to save {}: {}", path.display(), err))
+            }
+            FrameWriter::Y4m { file, dimensions } => {
+                let texel_count = (dimensions[0] * dimensions[1]) as usize;
+                let mut planes = vec![0u8; texel_count * 3];
+                let (y_plane, chroma) = planes.split_at_mut(texel_count);
+                let (u_plane, v_plane) = chroma.split_at_mut(texel_count);
+
+                // BT.601 limited range
+                for (idx, texel) in frame.pixels.chunks_exact(4).enumerate() {
+                    let (r, g, b) = (texel[0] as f32, texel[1] as f32, texel[2] as f32);
+                    y_plane[idx] = (16.0 + 0.257 * r + 0.504 * g + 0.098 * b).round() as u8;
+                    u_plane[idx] = (128.0 - 0.148 * r - 0.291 * g + 0.439 * b).round() as u8;
+                    v_plane[idx] = (128.0 + 0.439 * r - 0.368 * g - 0.071 * b).round() as u8;
+                }
+
+                file.write_all(b"FRAME\n")
+                    .and_then(|()| file.write_all(&planes))
+                    .map_err(|err| format!("Failed to write video frame {}: {}", frame.index, err))
+            }
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use std::ptr;
+use std::sync::Arc;
+
+use ash::version::{DeviceV1_0, InstanceV1_0};
+use ash::vk;
+
+use crate::env::RenderEnv;
+use crate::object_tracker::{self, ObjectKind};
+
+const MAX_MARKS: usize = 32;
+
+// GPU timestamps between passes. Every mark returns a tiny command buffer writing a timestamp, it is
+// submitted between (or executed inside a render pass among) the pass command buffers. A mark ends the
+// scope started by the previous one, the scope takes the name of the mark which ends it.
+// Each frame in flight has its own query pool, results are read back when the frame is reused.
+pub struct GpuProfiler {
+    query_pools: Vec<vk::QueryPool>,
+    primary_cmds: Vec<Vec<vk::CommandBuffer>>,
+    secondary_cmds: Vec<Vec<vk::CommandBuffer>>,
+    primary_used: usize,
+    secondary_used: usize,
+
+    // names of marks written in every frame in flight, in query order
+    frame_marks: Vec<Vec<&'static str>>,
+    current_frame: usize,
+
+    supported: bool,
+    timestamp_period: f32,
+    timings: Vec<(&'static str, f32)>,
+
+    env: Arc<RenderEnv>,
+}
+
+impl GpuProfiler {
+    pub fn new(env: Arc<RenderEnv>, max_frames_in_flight: usize) -> GpuProfiler {
+        let properties = unsafe {
+            env.instance().get_physical_device_properties(env.physical_device())
+        };
+        let families = unsafe {
+            env.instance().get_physical_device_queue_family_properties(env.physical_device())
+        };
+        let graphics_family = env.queue_family_indices().graphics as usize;
+        let supported = families[graphics_family].timestamp_valid_bits > 0 && properties.limits.timestamp_period > 0.0;
+
+        let mut query_pools = Vec::with_capacity(max_frames_in_flight);
+        let mut primary_cmds = Vec::with_capacity(max_frames_in_flight);
+        let mut secondary_cmds = Vec::with_capacity(max_frames_in_flight);
+        for _ in 0..max_frames_in_flight {
+            let query_pool_info = vk::QueryPoolCreateInfo {
+                s_type: vk::StructureType::QUERY_POOL_CREATE_INFO,
+                p_next: ptr::null(),
+                flags: vk::QueryPoolCreateFlags::empty(),
+                query_type: vk::QueryType::TIMESTAMP,
+                query_count: MAX_MARKS as u32,
+                pipeline_statistics: vk::QueryPipelineStatisticFlags::empty(),
+            };
+
+            let query_pool = unsafe {
+                env.device()
+                    .create_query_pool(&query_pool_info, None)
+                    .expect("Failed to create Query Pool!")
+            };
+            object_tracker::created(ObjectKind::QueryPool);
+            query_pools.push(query_pool);
+
+            primary_cmds.push((0..MAX_MARKS).map(|_| env.create_primary_command_buffer()).collect());
+            secondary_cmds.push((0..MAX_MARKS).map(|_| env.create_secondary_command_buffer()).collect());
+        }
+
+        GpuProfiler {
+            query_pools,
+            primary_cmds,
+            secondary_cmds,
+            primary_used: 0,
+            secondary_used: 0,
+            frame_marks: vec![vec![]; max_frames_in_flight],
+            current_frame: 0,
+            supported,
+            timestamp_period: properties.limits.timestamp_period,
+            timings: vec![],
+            env,
+        }
+    }
+
+    // Must be called after the fence of `frame` is waited: reads back its previous timestamps
+    pub fn begin_frame(&mut self, frame: usize) {
+        self.current_frame = frame;
+        self.primary_used = 0;
+        self.secondary_used = 0;
+
+        let marks = std::mem::take(&mut self.frame_marks[frame]);
+        if marks.len() < 2 {
+            return;
+        }
+
+        let mut timestamps = vec![0u64; marks.len()];
+        let result = unsafe {
+            self.env.device().get_query_pool_results(
+                self.query_pools[frame],
+                0,
+                marks.len() as u32,
+                &mut timestamps,
+                vk::QueryResultFlags::TYPE_64,
+            )
+        };
+
+        if result.is_ok() {
+            self.timings = marks.iter().enumerate().skip(1)
+                .map(|(idx, &name)| {
+                    let ticks = timestamps[idx].saturating_sub(timestamps[idx - 1]);
+                    (name, ticks as f32 * self.timestamp_period / 1_000_000.0)
+                })
+                .collect();
+        }
+    }
+
+    // Timestamp outside of render pass. The first mark of the frame also resets the query pool
+    pub fn mark(&mut self, name: &'static str) -> vk::CommandBuffer {
+        let cmd = self.primary_cmds[self.current_frame][self.primary_used];
+        self.primary_used += 1;
+
+        self.begin(cmd, None);
+        if self.supported {
+            let query_pool = self.query_pools[self.current_frame];
+            let marks = &mut self.frame_marks[self.current_frame];
+            unsafe {
+                if marks.is_empty() {
+                    self.env.device().cmd_reset_query_pool(cmd, query_pool, 0, MAX_MARKS as u32);
+                }
+                self.env.device().cmd_write_timestamp(cmd, vk::PipelineStageFlags::BOTTOM_OF_PIPE, query_pool, marks.len() as u32);
+            }
+            marks.push(name);
+        }
+        self.end(cmd);
+
+        cmd
+    }
+
+    // Timestamp between secondary command buffers inside `render_pass`
+    pub fn mark_in_pass(&mut self, name: &'static str, render_pass: vk::RenderPass) -> vk::CommandBuffer {
+        let cmd = self.secondary_cmds[self.current_frame][self.secondary_used];
+        self.secondary_used += 1;
+
+        self.begin(cmd, Some(render_pass));
+        if self.supported {
+            let marks = &mut self.frame_marks[self.current_frame];
+            if marks.is_empty() {
+                panic!("First profiler mark of the frame must be outside of render pass");
+            }
+
+            unsafe {
+                self.env.device().cmd_write_timestamp(
+                    cmd, vk::PipelineStageFlags::BOTTOM_OF_PIPE, self.query_pools[self.current_frame], marks.len() as u32);
+            }
+            marks.push(name);
+        }
+        self.end(cmd);
+
+        cmd
+    }
+
+    // Milliseconds of every scope of the last finished frame
+    pub fn timings(&self) -> &[(&'static str, f32)] {
+        &self.timings
+    }
+
+    pub fn is_supported(&self) -> bool {
+        self.supported
+    }
+
+    fn begin(&self, cmd: vk::CommandBuffer, render_pass: Option<vk::RenderPass>) {
+        let inheritance_info = vk::CommandBufferInheritanceInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_INHERITANCE_INFO,
+            p_next: ptr::null(),
+            render_pass: render_pass.unwrap_or_default(),
+            subpass: 0,
+            framebuffer: vk::Framebuffer::null(),
+            occlusion_query_enable: 0,
+            query_flags: Default::default(),
+            pipeline_statistics: Default::default(),
+        };
+
+        let flags = match render_pass {
+            Some(_) => vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT | vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE,
+            None => vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT,
+        };
+
+        let command_buffer_begin_info = vk::CommandBufferBeginInfo {
+            s_type: vk::StructureType::COMMAND_BUFFER_BEGIN_INFO,
+            p_next: ptr::null(),
+            p_inheritance_info: if render_pass.is_some() { &inheritance_info } else { ptr::null() },
+            flags,
+        };
+
+        unsafe {
+            self.env.device().reset_command_buffer(cmd, vk::CommandBufferResetFlags::default()).unwrap();
+            self.env.device()
+                .begin_command_buffer(cmd, &command_buffer_begin_info)
+                .expect("Failed to begin recording Command Buffer at beginning!");
+        }
+    }
+
+    fn end(&self, cmd: vk::CommandBuffer) {
+        unsafe {
+            self.env.device()
+                .end_command_buffer(cmd)
+                .expect("Failed to record Command Buffer at Ending!");
+        }
+    }
+}
+
+impl Drop for GpuProfiler {
+    fn drop(&mut self) {
+        unsafe {
+            for ((&query_pool, primary), secondary) in self.query_pools.iter().zip(self.primary_cmds.iter()).zip(self.secondary_cmds.iter()) {
+                self.env.device().free_command_buffers(self.env.command_pool(), primary);
+                self.env.device().free_command_buffers(self.env.command_pool(), secondary);
+                self.env.device().destroy_query_pool(query_pool, None);
+                object_tracker::destroyed(ObjectKind::QueryPool);
+            }
+        }
+    }
+}
@@ -1,19 +1,49 @@
 #[allow(dead_code)]
 pub mod attachment_texture;
 
+pub mod aux_pass;
+
+pub mod bvh;
+
+pub mod capabilities;
+
+pub mod culling;
+
+pub mod debug_utils;
+
 #[allow(dead_code)]
 pub mod env;
 
+pub mod error;
+
 #[allow(dead_code)]
 pub mod swapchain;
 
 pub mod shader;
+pub mod shader_bundle;
+pub mod shader_tweaks;
+pub mod barrier;
 pub mod descriptor_set;
+pub mod deletion_queue;
+pub mod dynamic_uniform_buffer;
+pub mod uniform_buffer;
 mod platforms;
 pub mod frame_buffer;
 pub mod pipeline_builder;
+pub mod pipeline_cache;
+pub mod render_pass_cache;
+pub mod transient_pool;
+#[cfg(feature = "egui")]
 pub mod egui;
 pub mod primary_cmd_buffer;
+pub mod queue_guard;
 pub mod utils;
 pub mod camera;
 pub mod fps_limiter;
+pub mod frame_capture;
+pub mod gpu_profiler;
+pub mod metrics;
+pub mod object_tracker;
+pub mod memory_tracker;
+pub mod multi_gpu;
+pub mod window;
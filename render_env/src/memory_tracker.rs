@@ -0,0 +1,138 @@
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use ash::vk;
+use ash::vk::Handle;
+
+// What a device memory allocation is used for. Allocation sites pass a default,
+// `category_scope` overrides it for everything allocated while the guard is alive
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MemoryCategory {
+    GBuffer,
+    ShadowMaps,
+    RenderTargets,
+    Meshes,
+    Textures,
+    Ui,
+    Buffers,
+}
+
+impl MemoryCategory {
+    pub const ALL: [MemoryCategory; 7] = [
+        MemoryCategory::GBuffer,
+        MemoryCategory::ShadowMaps,
+        MemoryCategory::RenderTargets,
+        MemoryCategory::Meshes,
+        MemoryCategory::Textures,
+        MemoryCategory::Ui,
+        MemoryCategory::Buffers,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MemoryCategory::GBuffer => "G-buffer",
+            MemoryCategory::ShadowMaps => "Shadow maps",
+            MemoryCategory::RenderTargets => "Render targets",
+            MemoryCategory::Meshes => "Meshes",
+            MemoryCategory::Textures => "Textures",
+            MemoryCategory::Ui => "UI",
+            MemoryCategory::Buffers => "Buffers",
+        }
+    }
+}
+
+struct Allocation {
+    size: vk::DeviceSize,
+    category: MemoryCategory,
+}
+
+// Alive allocations by the raw handle of their memory
+static ALLOCATIONS: Mutex<BTreeMap<u64, Allocation>> = Mutex::new(BTreeMap::new());
+
+thread_local! {
+    static SCOPE: Cell<Option<MemoryCategory>> = const { Cell::new(None) };
+}
+
+// Restores the previous category of the thread when dropped
+pub struct CategoryScope {
+    previous: Option<MemoryCategory>,
+}
+
+impl Drop for CategoryScope {
+    fn drop(&mut self) {
+        SCOPE.with(|scope| scope.set(self.previous));
+    }
+}
+
+// Allocations made by this thread until the guard is dropped go to `category`
+#[must_use]
+pub fn category_scope(category: MemoryCategory) -> CategoryScope {
+    let previous = SCOPE.with(|scope| scope.replace(Some(category)));
+    CategoryScope { previous }
+}
+
+// Paired with every allocate_memory
+pub fn allocated(memory: vk::DeviceMemory, size: vk::DeviceSize, default_category: MemoryCategory) {
+    let category = SCOPE.with(|scope| scope.get()).unwrap_or(default_category);
+    ALLOCATIONS.lock().unwrap().insert(memory.as_raw(), Allocation { size, category });
+}
+
+// Paired with every free_memory
+pub fn freed(memory: vk::DeviceMemory) {
+    ALLOCATIONS.lock().unwrap().remove(&memory.as_raw());
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CategoryUsage {
+    pub allocations: usize,
+    pub bytes: vk::DeviceSize,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStats {
+    // in MemoryCategory::ALL order
+    pub categories: Vec<(MemoryCategory, CategoryUsage)>,
+    pub total: CategoryUsage,
+}
+
+pub fn stats() -> MemoryStats {
+    let mut categories: Vec<_> = MemoryCategory::ALL.iter().map(|&category| (category, CategoryUsage::default())).collect();
+    let mut total = CategoryUsage::default();
+    for allocation in ALLOCATIONS.lock().unwrap().values() {
+        let usage = &mut categories[allocation.category as usize].1;
+        usage.allocations += 1;
+        usage.bytes += allocation.size;
+        total.allocations += 1;
+        total.bytes += allocation.size;
+    }
+
+    MemoryStats { categories, total }
+}
+
+impl MemoryStats {
+    // Breakdown by category and the tracked total next to the heaps reported by the driver, see RenderEnv::memory_budget()
+    #[cfg(feature = "egui")]
+    pub fn ui(&self, ui: &mut egui::Ui, heaps: Option<&[crate::env::HeapBudget]>) {
+        let mb = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+
+        for (category, usage) in self.categories.iter() {
+            ui.label(format!("{}: {:.1} MB in {}", category.name(), mb(usage.bytes), usage.allocations));
+        }
+        ui.separator();
+        ui.label(format!("Tracked: {:.1} MB in {} allocations", mb(self.total.bytes), self.total.allocations));
+
+        match heaps {
+            Some(heaps) => {
+                for (idx, heap) in heaps.iter().enumerate() {
+                    let kind = if heap.device_local { "device local" } else { "host" };
+                    ui.label(format!("Heap {} ({}): {:.1} of {:.1} MB budget, {:.1} MB total",
+                                     idx, kind, mb(heap.usage), mb(heap.budget), mb(heap.size)));
+                }
+            }
+            None => {
+                ui.label("No VK_EXT_memory_budget, driver numbers are unknown");
+            }
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+// Samples older than this are dropped from every channel
+const MAX_HISTORY_SECS: f64 = 600.0;
+
+// Time windows of the plots, seconds
+pub const TIME_WINDOWS: [f64; 4] = [10.0, 60.0, 180.0, 600.0];
+
+// Plot lines are reduced to about this many points, each keeps the maximum of its bucket so spikes stay visible
+#[cfg(feature = "egui")]
+const PLOT_POINTS: usize = 600;
+
+pub struct MetricChannel {
+    name: String,
+    // channels with the same unit share a plot: "ms", "count", "MB"
+    unit: &'static str,
+    // (seconds since Metrics::new, value)
+    samples: VecDeque<(f64, f64)>,
+}
+
+impl MetricChannel {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn unit(&self) -> &'static str {
+        self.unit
+    }
+
+    pub fn last(&self) -> Option<f64> {
+        self.samples.back().map(|&(_, value)| value)
+    }
+
+    // Samples of the last `window` seconds before `now`
+    pub fn samples_in(&self, now: f64, window: f64) -> impl Iterator<Item=&(f64, f64)> {
+        self.samples.iter().skip_while(move |&&(time, _)| time < now - window)
+    }
+}
+
+// History of engine metrics (frame time, GPU pass times, draw calls, memory), one sample per channel and frame.
+// Channels are created on the first record() of a name
+pub struct Metrics {
+    start: Instant,
+    channels: Vec<MetricChannel>,
+    paused: bool,
+    // time of the last sample, plots of a paused history end there
+    now: f64,
+    time_window: f64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            start: Instant::now(),
+            channels: vec![],
+            paused: false,
+            now: 0.0,
+            time_window: TIME_WINDOWS[1],
+        }
+    }
+
+    pub fn record(&mut self, name: &str, unit: &'static str, value: f64) {
+        if self.paused {
+            return;
+        }
+
+        self.now = self.start.elapsed().as_secs_f64();
+        let idx = match self.channels.iter().position(|channel| channel.name == name) {
+            Some(idx) => idx,
+            None => {
+                self.channels.push(MetricChannel {
+                    name: name.to_string(),
+                    unit,
+                    samples: VecDeque::new(),
+                });
+                self.channels.len() - 1
+            }
+        };
+
+        let oldest = self.now - MAX_HISTORY_SECS;
+        let channel = &mut self.channels[idx];
+        channel.samples.push_back((self.now, value));
+        while channel.samples.front().is_some_and(|&(time, _)| time < oldest) {
+            channel.samples.pop_front();
+        }
+    }
+
+    pub fn channels(&self) -> &[MetricChannel] {
+        &self.channels
+    }
+
+    pub fn get(&self, name: &str) -> Option<&MetricChannel> {
+        self.channels.iter().find(|channel| channel.name == name)
+    }
+
+    // Paused history keeps its samples, new ones are ignored
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_time_window(&mut self, seconds: f64) {
+        self.time_window = seconds.clamp(1.0, MAX_HISTORY_SECS);
+    }
+
+    pub fn time_window(&self) -> f64 {
+        self.time_window
+    }
+
+    // Samples of the current time window, one row per sample: time,channel,unit,value
+    pub fn export_csv(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        writeln!(writer, "time,channel,unit,value")?;
+        for channel in self.channels.iter() {
+            for &(time, value) in channel.samples_in(self.now, self.time_window) {
+                writeln!(writer, "{:.4},{},{},{}", time, channel.name, channel.unit, value)?;
+            }
+        }
+
+        writer.flush()
+    }
+
+    // Time window and pause controls with a plot per unit. Returns true when export was clicked
+    #[cfg(feature = "egui")]
+    pub fn ui(&mut self, ui: &mut egui::Ui) -> bool {
+        use egui::plot::{Legend, Line, Plot, Value, Values};
+
+        let mut export = false;
+        ui.horizontal(|ui| {
+            let mut window = self.time_window;
+            egui::ComboBox::from_label("Window")
+                .selected_text(format!("{} s", window))
+                .show_ui(ui, |ui| {
+                    for &seconds in TIME_WINDOWS.iter() {
+                        ui.selectable_value(&mut window, seconds, format!("{} s", seconds));
+                    }
+                });
+            self.set_time_window(window);
+
+            let mut paused = self.paused;
+            if ui.checkbox(&mut paused, "Pause").changed() {
+                self.set_paused(paused);
+            }
+            export = ui.button("Export CSV").clicked();
+        });
+
+        let mut units: Vec<&'static str> = vec![];
+        for channel in self.channels.iter() {
+            if !units.contains(&channel.unit) {
+                units.push(channel.unit);
+            }
+        }
+
+        for (plot_idx, unit) in units.into_iter().enumerate() {
+            ui.label(unit);
+
+            let mut plot = Plot::new(("metrics", plot_idx))
+                .height(120.0)
+                .include_y(0.0)
+                .allow_drag(false)
+                .allow_zoom(false)
+                .legend(Legend::default());
+
+            for channel in self.channels.iter().filter(|channel| channel.unit == unit) {
+                let samples: Vec<_> = channel.samples_in(self.now, self.time_window).collect();
+                let bucket = samples.len() / PLOT_POINTS + 1;
+                let values = samples.chunks(bucket)
+                    .map(|chunk| {
+                        let max = chunk.iter().map(|&&(_, value)| value).fold(f64::MIN, f64::max);
+                        // x is negative: seconds before the last sample
+                        Value::new(chunk[0].0 - self.now, max)
+                    })
+                    .collect();
+
+                plot = plot.line(Line::new(Values::from_values(values)).name(&channel.name));
+            }
+
+            ui.add(plot);
+        }
+
+        export
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,60 @@
+use ash::version::{InstanceV1_0, InstanceV1_1};
+use ash::vk;
+
+use crate::env::RenderEnv;
+
+// EXPERIMENTAL. What the machine offers for multi-GPU rendering.
+//
+// Linked GPUs show up as a device group (VK_KHR_device_group, core in 1.1) and could share memory
+// and present each other's images. Unlinked GPUs can still alternate frames as separate RenderEnv
+// devices, the images are then transferred through host memory.
+#[derive(Clone, Debug)]
+pub struct MultiGpuSupport {
+    pub device_names: Vec<String>,
+    // devices a headless RenderEnv can be created on
+    pub suitable_device_count: usize,
+    // physical device count of every group, empty when the instance is 1.0
+    pub device_group_sizes: Vec<usize>,
+}
+
+impl MultiGpuSupport {
+    pub fn detect(env: &RenderEnv) -> MultiGpuSupport {
+        let instance = env.instance();
+
+        let device_names = unsafe { instance.enumerate_physical_devices().unwrap() }
+            .iter()
+            .map(|pdevice| {
+                let properties = unsafe { instance.get_physical_device_properties(*pdevice) };
+                let name = unsafe { std::ffi::CStr::from_ptr(properties.device_name.as_ptr()) };
+                format!("{} ({:?})", name.to_string_lossy(), properties.device_type)
+            })
+            .collect();
+
+        let device_group_sizes = if env.api_version() >= vk::make_version(1, 1, 0) {
+            unsafe {
+                let group_count = instance.enumerate_physical_device_groups_len().unwrap();
+                let mut groups = vec![vk::PhysicalDeviceGroupProperties::default(); group_count];
+                instance.enumerate_physical_device_groups(&mut groups).unwrap();
+
+                groups.iter().map(|group| group.physical_device_count as usize).collect()
+            }
+        } else {
+            vec![]
+        };
+
+        MultiGpuSupport {
+            device_names,
+            suitable_device_count: env.suitable_device_count(),
+            device_group_sizes,
+        }
+    }
+
+    // At least two devices able to render a frame on their own
+    pub fn can_alternate_frames(&self) -> bool {
+        self.suitable_device_count >= 2
+    }
+
+    pub fn has_linked_group(&self) -> bool {
+        self.device_group_sizes.iter().any(|size| *size >= 2)
+    }
+}
@@ -0,0 +1,84 @@
+use std::sync::atomic::{AtomicIsize, Ordering};
+
+// Counts of alive vulkan objects, grouped by type.
+// Every create_* call for a tracked type is paired with `created(..)` and every destroy_* with `destroyed(..)`,
+// so a snapshot taken before constructing some renderer must be equal to one taken after dropping it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ObjectKind {
+    Buffer,
+    Image,
+    ImageView,
+    Sampler,
+    RenderPass,
+    Framebuffer,
+    Pipeline,
+    DescriptorPool,
+    ShaderModule,
+    QueryPool,
+}
+
+const KIND_COUNT: usize = 10;
+
+const ALL_KINDS: [ObjectKind; KIND_COUNT] = [
+    ObjectKind::Buffer,
+    ObjectKind::Image,
+    ObjectKind::ImageView,
+    ObjectKind::Sampler,
+    ObjectKind::RenderPass,
+    ObjectKind::Framebuffer,
+    ObjectKind::Pipeline,
+    ObjectKind::DescriptorPool,
+    ObjectKind::ShaderModule,
+    ObjectKind::QueryPool,
+];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicIsize = AtomicIsize::new(0);
+static ALIVE: [AtomicIsize; KIND_COUNT] = [ZERO; KIND_COUNT];
+
+#[inline]
+pub fn created(kind: ObjectKind) {
+    ALIVE[kind as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn destroyed(kind: ObjectKind) {
+    ALIVE[kind as usize].fetch_sub(1, Ordering::Relaxed);
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObjectCounts {
+    counts: [isize; KIND_COUNT],
+}
+
+impl ObjectCounts {
+    pub fn get(&self, kind: ObjectKind) -> isize {
+        self.counts[kind as usize]
+    }
+
+    // (kind, delta) for every object type which count changed since `before`
+    pub fn diff(&self, before: &ObjectCounts) -> Vec<(ObjectKind, isize)> {
+        ALL_KINDS
+            .iter()
+            .map(|&kind| (kind, self.get(kind) - before.get(kind)))
+            .filter(|&(_, delta)| delta != 0)
+            .collect()
+    }
+}
+
+pub fn snapshot() -> ObjectCounts {
+    let mut counts = [0; KIND_COUNT];
+    for (idx, alive) in ALIVE.iter().enumerate() {
+        counts[idx] = alive.load(Ordering::Relaxed);
+    }
+
+    ObjectCounts { counts }
+}
+
+// Panics if some objects were created after `before` snapshot and not destroyed yet (or destroyed twice).
+pub fn assert_no_growth(before: &ObjectCounts) {
+    let diff = snapshot().diff(before);
+    if !diff.is_empty() {
+        panic!("Vulkan objects leaked (kind, delta): {:?}", diff);
+    }
+}
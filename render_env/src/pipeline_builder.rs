@@ -1,26 +1,74 @@
 use std::ptr;
+use std::sync::Arc;
 
 use ash::version::DeviceV1_0;
 use ash::vk;
 
+use crate::error::{RenderError, RenderResult};
+use crate::object_tracker::{self, ObjectKind};
+use crate::pipeline_cache::{self, PipelineCache, PipelineRecord};
 use crate::shader::{DescriptorSetLayout, Shader};
 use crate::shader;
+use crate::shader_tweaks;
+use crate::utils::DepthMode;
 
 pub struct Pipeline {
     pub device: ash::Device,
     pub descriptor_set_layouts: Vec<DescriptorSetLayout>,
     pub pipeline_layout: vk::PipelineLayout,
     pub graphics_pipeline: vk::Pipeline,
+    // of the render pass the pipeline draws in, inherited by its secondary command buffers; 0 for compute
+    pub subpass: u32,
+    // reflected push constant blocks, one per stage using them
+    push_constant_ranges: Vec<vk::PushConstantRange>,
+    // names of the shaders with tweakable push constants, see shader_tweaks
+    tweaked_shaders: Vec<(vk::ShaderStageFlags, String)>,
+}
+
+impl Pipeline {
+    // Records `data` as the push constants of `stage`, its size must match the reflected block of the stage.
+    // Tweakable members are replaced by the values edited in the shader tweaks panel
+    pub fn push_constants<T: Copy>(&self, command_buffer: vk::CommandBuffer, stage: vk::ShaderStageFlags, data: &T) {
+        let range = self.push_constant_ranges.iter()
+            .find(|range| range.stage_flags.contains(stage))
+            .unwrap_or_else(|| panic!("Pipeline has no push constants in {:?} stage", stage));
+
+        let size = std::mem::size_of::<T>();
+        if size != range.size as usize {
+            panic!("Push constants of {:?} stage are {} bytes, but {} given", stage, range.size, size);
+        }
+
+        let bytes = unsafe { std::slice::from_raw_parts(data as *const T as *const u8, size) };
+
+        // the copy and the lock of shader_tweaks only when a shader of the range has tweakable members
+        let mut tweaked = self.tweaked_shaders.iter()
+            .filter(|(stages, _)| stages.intersects(range.stage_flags))
+            .peekable();
+        if tweaked.peek().is_none() {
+            unsafe {
+                self.device.cmd_push_constants(command_buffer, self.pipeline_layout, range.stage_flags, range.offset, bytes);
+            }
+            return;
+        }
+
+        let mut bytes = bytes.to_vec();
+        for (_, shader) in tweaked {
+            shader_tweaks::apply(shader, range.offset, &mut bytes);
+        }
+
+        unsafe {
+            self.device.cmd_push_constants(command_buffer, self.pipeline_layout, range.stage_flags, range.offset, &bytes);
+        }
+    }
 }
 
 impl Drop for Pipeline {
     fn drop(&mut self) {
         unsafe {
             self.device.destroy_pipeline(self.graphics_pipeline, None);
+            object_tracker::destroyed(ObjectKind::Pipeline);
 
-            for descriptor_set_layout in self.descriptor_set_layouts.iter() {
-                self.device.destroy_descriptor_set_layout(descriptor_set_layout.layout, None);
-            }
+            shader::destroy_descriptor_set_layouts(&self.device, &self.descriptor_set_layouts);
 
             self.device.destroy_pipeline_layout(self.pipeline_layout, None);
         }
@@ -46,8 +94,17 @@ pub struct PipelineBuilder {
     multisampling: vk::PipelineMultisampleStateCreateInfo,
     depth_stencil: vk::PipelineDepthStencilStateCreateInfo,
     color_blend_attachment_states: Vec<vk::PipelineColorBlendAttachmentState>,
+    dynamic_states: Vec<vk::DynamicState>,
+    // options missing here are turned off in build(), None - everything is assumed supported
+    features: Option<vk::PhysicalDeviceFeatures>,
+    // compare ops are given for the standard depth and flipped in build() for reverse-Z
+    depth_mode: DepthMode,
+    // compiled pipelines are looked up in and added to the cache, the pipeline is recorded for warmup
+    pipeline_cache: Option<Arc<PipelineCache>>,
 
     vertex_shader: Option<Shader>,
+    // control and evaluation stages, set together with patch_control_points
+    tessellation_shaders: Option<(Shader, Shader)>,
     fragment_shader: Option<Shader>,
 }
 
@@ -164,8 +221,13 @@ impl PipelineBuilder {
             multisampling: multisample_state_create_info,
             depth_stencil: depth_state_create_info,
             color_blend_attachment_states,
+            dynamic_states: vec![vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR],
+            features: None,
+            depth_mode: DepthMode::Standard,
+            pipeline_cache: None,
 
             vertex_shader: None,
+            tessellation_shaders: None,
             fragment_shader: None,
         }
     }
@@ -177,6 +239,12 @@ impl PipelineBuilder {
         self
     }
 
+    pub fn topology(mut self, topology: vk::PrimitiveTopology) -> Self {
+        self.input_assembly.topology = topology;
+
+        self
+    }
+
     pub fn vertex_shader(mut self, shader: Shader) -> Self {
         self.vertex_shader = Some(shader);
 
@@ -189,12 +257,58 @@ impl PipelineBuilder {
         self
     }
 
+    // Needs the tessellation_shader feature, see device_features. Vertices are drawn as patches of patch_control_points
+    pub fn tessellation_shaders(mut self, control_shader: Shader, evaluation_shader: Shader) -> Self {
+        self.tessellation_shaders = Some((control_shader, evaluation_shader));
+
+        self
+    }
+
+    // Switches the topology to patch lists of `count` vertices
+    pub fn patch_control_points(mut self, count: u32) -> Self {
+        self.input_assembly.topology = vk::PrimitiveTopology::PATCH_LIST;
+        self.tesselation = Some(vk::PipelineTessellationStateCreateInfo {
+            patch_control_points: count,
+            ..Default::default()
+        });
+
+        self
+    }
+
     pub fn msaa(mut self, sample_count: vk::SampleCountFlags) -> Self {
         self.multisampling.rasterization_samples = sample_count;
 
         self
     }
 
+    // Per sample fragment shading of MSAA targets, `min_fraction` of samples get a unique shader invocation
+    pub fn sample_shading(mut self, min_fraction: f32) -> Self {
+        self.multisampling.sample_shading_enable = vk::TRUE;
+        self.multisampling.min_sample_shading = min_fraction;
+
+        self
+    }
+
+    // Features of RenderEnv, options the device doesn't support degrade instead of failing pipeline creation
+    pub fn depth_mode(mut self, depth_mode: DepthMode) -> Self {
+        self.depth_mode = depth_mode;
+
+        self
+    }
+
+    pub fn device_features(mut self, features: &vk::PhysicalDeviceFeatures) -> Self {
+        self.features = Some(*features);
+
+        self
+    }
+
+    // Cache of RenderEnv, see PipelineCache::warmup
+    pub fn pipeline_cache(mut self, cache: &Arc<PipelineCache>) -> Self {
+        self.pipeline_cache = Some(cache.clone());
+
+        self
+    }
+
     pub fn blend(mut self) -> Self {
         let color_blend_attachments = vec![
             vk::PipelineColorBlendAttachmentState::builder()
@@ -273,6 +387,17 @@ impl PipelineBuilder {
         self
     }
 
+    // Depth is tested but not written when false, e.g. after a depth pre-pass. Call after with_depth_test / with_depth_func
+    pub fn depth_write(mut self, enable: bool) -> Self {
+        self.depth_stencil.depth_write_enable = if enable {
+            vk::TRUE
+        } else {
+            vk::FALSE
+        };
+
+        self
+    }
+
     pub fn depth_clamp_enable(mut self, enable: bool) -> Self {
         self.rasterization.depth_clamp_enable = if enable {
             vk::TRUE
@@ -283,6 +408,31 @@ impl PipelineBuilder {
         self
     }
 
+    // Depth offset of polygons, mostly for shadow maps: constant in depth units and scaled by the polygon slope
+    pub fn depth_bias(mut self, constant_factor: f32, slope_factor: f32) -> Self {
+        self.rasterization.depth_bias_enable = vk::TRUE;
+        self.rasterization.depth_bias_constant_factor = constant_factor;
+        self.rasterization.depth_bias_slope_factor = slope_factor;
+
+        self
+    }
+
+    // State set by commands while recording, viewport and scissor are always dynamic
+    pub fn dynamic_state(mut self, state: vk::DynamicState) -> Self {
+        if !self.dynamic_states.contains(&state) {
+            self.dynamic_states.push(state);
+        }
+
+        self
+    }
+
+    // LINE and POINT need the fill_mode_non_solid feature, see device_features
+    pub fn polygon_mode(mut self, mode: vk::PolygonMode) -> Self {
+        self.rasterization.polygon_mode = mode;
+
+        self
+    }
+
     pub fn disable_culling(mut self) -> Self {
         self.rasterization.cull_mode = vk::CullModeFlags::NONE;
 
@@ -318,32 +468,62 @@ impl PipelineBuilder {
         self
     }
 
-    pub fn build(&mut self) -> Pipeline {
-        let shader_stages = [
-            self.vertex_shader.as_ref().unwrap().stage(),
-            self.fragment_shader.as_ref().unwrap().stage(),
-        ];
+    // Color attachments are kept untouched, for depth only draws in a pass with color attachments.
+    // Call after color_attachment_count
+    pub fn disable_color_writes(mut self) -> Self {
+        for attachment in self.color_blend_attachment_states.iter_mut() {
+            attachment.color_write_mask = vk::ColorComponentFlags::empty();
+        }
 
-        let descriptor_set_layouts = shader::create_descriptor_set_layout(
-            &self.device,
-            vec![
-                self.vertex_shader.as_ref().unwrap(),
-                self.fragment_shader.as_ref().unwrap(),
-            ]);
+        self
+    }
+
+    pub fn build(&mut self) -> RenderResult<Pipeline> {
+        let (vertex_shader, fragment_shader) = match (self.vertex_shader.as_ref(), self.fragment_shader.as_ref()) {
+            (Some(vertex_shader), Some(fragment_shader)) => (vertex_shader, fragment_shader),
+            _ => return Err(RenderError::InvalidPipeline("vertex and fragment shaders are required".to_string())),
+        };
+        if self.tessellation_shaders.is_some() != self.tesselation.is_some() {
+            return Err(RenderError::InvalidPipeline("tessellation shaders and patch control points go together".to_string()));
+        }
+
+        if let Some(features) = self.features {
+            // the stages can't be dropped, callers check the feature and draw without tessellation
+            if features.tessellation_shader == vk::FALSE && self.tessellation_shaders.is_some() {
+                return Err(RenderError::InvalidPipeline("tessellation_shader feature is not supported".to_string()));
+            }
+            if features.depth_clamp == vk::FALSE {
+                self.rasterization.depth_clamp_enable = vk::FALSE;
+            }
+            if features.sample_rate_shading == vk::FALSE {
+                self.multisampling.sample_shading_enable = vk::FALSE;
+            }
+            if features.fill_mode_non_solid == vk::FALSE {
+                self.rasterization.polygon_mode = vk::PolygonMode::FILL;
+            }
+        }
+
+        let mut shaders = vec![vertex_shader];
+        if let Some((control_shader, evaluation_shader)) = &self.tessellation_shaders {
+            shaders.extend([control_shader, evaluation_shader]);
+        }
+        shaders.push(fragment_shader);
+
+        let shader_stages: Vec<_> = shaders.iter()
+            .map(|shader| shader.stage())
+            .collect();
+
+        let descriptor_set_layouts = shader::create_descriptor_set_layout(&self.device, shaders.clone())?;
 
         let layout_vec: Vec<_> = descriptor_set_layouts
             .iter()
             .map(|x| x.layout)
             .collect();
 
-        let mut push_constant_ranges = Vec::new();
-        if self.vertex_shader.as_ref().unwrap().push_constants_range.size > 0 {
-            push_constant_ranges.push(self.vertex_shader.as_ref().unwrap().push_constants_range);
-        };
-
-        if self.fragment_shader.as_ref().unwrap().push_constants_range.size > 0 {
-            push_constant_ranges.push(self.fragment_shader.as_ref().unwrap().push_constants_range);
-        };
+        let push_constant_ranges: Vec<_> = shaders.iter()
+            .filter(|shader| shader.push_constants_range.size > 0)
+            .map(|shader| shader.push_constants_range)
+            .collect();
 
         let pipeline_layout_create_info = vk::PipelineLayoutCreateInfo {
             s_type: vk::StructureType::PIPELINE_LAYOUT_CREATE_INFO,
@@ -355,14 +535,15 @@ impl PipelineBuilder {
             p_push_constant_ranges: push_constant_ranges.as_ptr(),
         };
 
-        let pipeline_layout = unsafe {
-            self.device
-                .create_pipeline_layout(&pipeline_layout_create_info, None)
-                .expect("Failed to create pipeline layout!")
+        let pipeline_layout = match unsafe { self.device.create_pipeline_layout(&pipeline_layout_create_info, None) } {
+            Ok(pipeline_layout) => pipeline_layout,
+            Err(result) => {
+                shader::destroy_descriptor_set_layouts(&self.device, &descriptor_set_layouts);
+                return Err(RenderError::Vulkan { call: "vkCreatePipelineLayout", result });
+            }
         };
 
-        // leaving the dynamic statue unconfigurated right now
-        let dynamic_state = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state = &self.dynamic_states;
         let dynamic_state_info = vk::PipelineDynamicStateCreateInfo {
             s_type: vk::StructureType::PIPELINE_DYNAMIC_STATE_CREATE_INFO,
             p_next: ptr::null(),
@@ -374,6 +555,9 @@ impl PipelineBuilder {
         // self.color_blend.attachment_count = self.color_blend_attachment_states.len() as u32;
         // self.color_blend.p_attachments = self.color_blend_attachment_states.as_ptr();
 
+        let mut depth_stencil = self.depth_stencil;
+        depth_stencil.depth_compare_op = self.depth_mode.compare_op(depth_stencil.depth_compare_op);
+
         let color_blend = vk::PipelineColorBlendStateCreateInfo::builder()
             .attachments(&self.color_blend_attachment_states)
             .build();
@@ -406,7 +590,7 @@ impl PipelineBuilder {
                 p_viewport_state: &self.viewport,
                 p_rasterization_state: &self.rasterization,
                 p_multisample_state: &self.multisampling,
-                p_depth_stencil_state: &self.depth_stencil,
+                p_depth_stencil_state: &depth_stencil,
                 p_color_blend_state: &color_blend,
                 p_dynamic_state: &dynamic_state_info,
                 layout: pipeline_layout,
@@ -420,20 +604,292 @@ impl PipelineBuilder {
         ];
 
         let graphics_pipelines = unsafe {
-            self.device
-                .create_graphics_pipelines(
-                    vk::PipelineCache::null(),
-                    &graphic_pipeline_create_infos,
-                    None,
-                )
-                .expect("Failed to create Graphics Pipeline!.")
+            self.device.create_graphics_pipelines(
+                self.pipeline_cache.as_ref().map_or(vk::PipelineCache::null(), |cache| cache.handle()),
+                &graphic_pipeline_create_infos,
+                None,
+            )
+        };
+        let graphics_pipelines = match graphics_pipelines {
+            Ok(pipelines) => pipelines,
+            Err((_, result)) => {
+                unsafe {
+                    self.device.destroy_pipeline_layout(pipeline_layout, None);
+                }
+                shader::destroy_descriptor_set_layouts(&self.device, &descriptor_set_layouts);
+                return Err(RenderError::Vulkan { call: "vkCreateGraphicsPipelines", result });
+            }
         };
+        object_tracker::created(ObjectKind::Pipeline);
+
+        if let Some(cache) = &self.pipeline_cache {
+            cache.set_pipeline_name(graphics_pipelines[0], &format!("{} + {}", vertex_shader.name(), fragment_shader.name()));
+
+            // pipelines of render passes created without render_pass_created() can't be warmed up
+            if let Some(render_pass) = pipeline_cache::render_pass_layout(self.render_pass) {
+                cache.record(PipelineRecord {
+                    render_pass,
+                    subpass: self.subpass,
+                    vertex_shader: vertex_shader.record(),
+                    tessellation_shaders: self.tessellation_shaders.as_ref()
+                        .map(|(control_shader, evaluation_shader)| (control_shader.record(), evaluation_shader.record())),
+                    fragment_shader: fragment_shader.record(),
+                    state: self.state_words(),
+                });
+            }
+        }
 
-        Pipeline {
+        Ok(Pipeline {
             device: self.device.clone(),
             graphics_pipeline: graphics_pipelines[0],
+            subpass: self.subpass,
             pipeline_layout,
             descriptor_set_layouts,
+            push_constant_ranges,
+            tweaked_shaders: tweaked_shaders(&shaders),
+        })
+    }
+}
+
+fn tweaked_shaders(shaders: &[&Shader]) -> Vec<(vk::ShaderStageFlags, String)> {
+    shaders.iter()
+        .filter(|shader| shader.push_constant_members.iter().any(shader_tweaks::is_tweakable))
+        .map(|shader| (shader.push_constants_range.stage_flags, shader.name().to_string()))
+        .collect()
+}
+
+// Compute pipelines share Pipeline with the graphics ones, `graphics_pipeline` holds the compute handle.
+// Layouts come from the shader reflection the same way, there is nothing to record for warmup
+pub struct ComputePipelineBuilder {
+    device: ash::Device,
+    shader: Shader,
+    pipeline_cache: Option<Arc<PipelineCache>>,
+}
+
+impl ComputePipelineBuilder {
+    pub fn new(device: ash::Device, shader: Shader) -> ComputePipelineBuilder {
+        ComputePipelineBuilder {
+            device,
+            shader,
+            pipeline_cache: None,
+        }
+    }
+
+    pub fn pipeline_cache(mut self, cache: &Arc<PipelineCache>) -> Self {
+        self.pipeline_cache = Some(cache.clone());
+        self
+    }
+
+    pub fn build(&self) -> RenderResult<Pipeline> {
+        let stage = self.shader.stage();
+        if stage.stage != vk::ShaderStageFlags::COMPUTE {
+            return Err(RenderError::InvalidPipeline("compute shader is required".to_string()));
         }
+
+        let descriptor_set_layouts = shader::create_descriptor_set_layout(&self.device, vec![&self.shader])?;
+
+        let layout_vec: Vec<_> = descriptor_set_layouts
+            .iter()
+            .map(|x| x.layout)
+            .collect();
+
+        let mut push_constant_ranges = Vec::new();
+        if self.shader.push_constants_range.size > 0 {
+            push_constant_ranges.push(self.shader.push_constants_range);
+        };
+
+        let pipeline_layout_create_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&layout_vec)
+            .push_constant_ranges(&push_constant_ranges);
+
+        let pipeline_layout = match unsafe { self.device.create_pipeline_layout(&pipeline_layout_create_info, None) } {
+            Ok(pipeline_layout) => pipeline_layout,
+            Err(result) => {
+                shader::destroy_descriptor_set_layouts(&self.device, &descriptor_set_layouts);
+                return Err(RenderError::Vulkan { call: "vkCreatePipelineLayout", result });
+            }
+        };
+
+        let compute_pipeline_create_infos = [
+            vk::ComputePipelineCreateInfo::builder()
+                .stage(stage)
+                .layout(pipeline_layout)
+                .base_pipeline_index(-1)
+                .build()
+        ];
+
+        let compute_pipelines = unsafe {
+            self.device.create_compute_pipelines(
+                self.pipeline_cache.as_ref().map_or(vk::PipelineCache::null(), |cache| cache.handle()),
+                &compute_pipeline_create_infos,
+                None,
+            )
+        };
+        let compute_pipelines = match compute_pipelines {
+            Ok(pipelines) => pipelines,
+            Err((_, result)) => {
+                unsafe {
+                    self.device.destroy_pipeline_layout(pipeline_layout, None);
+                }
+                shader::destroy_descriptor_set_layouts(&self.device, &descriptor_set_layouts);
+                return Err(RenderError::Vulkan { call: "vkCreateComputePipelines", result });
+            }
+        };
+        object_tracker::created(ObjectKind::Pipeline);
+
+        if let Some(cache) = &self.pipeline_cache {
+            cache.set_pipeline_name(compute_pipelines[0], self.shader.name());
+        }
+
+        Ok(Pipeline {
+            device: self.device.clone(),
+            graphics_pipeline: compute_pipelines[0],
+            subpass: 0,
+            pipeline_layout,
+            descriptor_set_layouts,
+            push_constant_ranges,
+            tweaked_shaders: tweaked_shaders(&[&self.shader]),
+        })
+    }
+}
+
+// Fixed function state of pipeline records, both functions must keep the same order of values
+impl PipelineBuilder {
+    fn state_words(&self) -> Vec<u32> {
+        let mut words = vec![];
+
+        words.push(self.vertex_input_bindings.len() as u32);
+        for binding in self.vertex_input_bindings.iter() {
+            words.extend([binding.binding, binding.stride, binding.input_rate.as_raw() as u32]);
+        }
+        words.push(self.vertex_input_attributes.len() as u32);
+        for attr in self.vertex_input_attributes.iter() {
+            words.extend([attr.location, attr.binding, attr.format.as_raw() as u32, attr.offset]);
+        }
+
+        words.extend([self.input_assembly.topology.as_raw() as u32, self.input_assembly.primitive_restart_enable]);
+        words.push(self.tesselation.map_or(0, |tesselation| tesselation.patch_control_points));
+
+        let rasterization = &self.rasterization;
+        words.extend([
+            rasterization.depth_clamp_enable,
+            rasterization.polygon_mode.as_raw() as u32,
+            rasterization.cull_mode.as_raw(),
+            rasterization.front_face.as_raw() as u32,
+            rasterization.line_width.to_bits(),
+            rasterization.depth_bias_enable,
+            rasterization.depth_bias_constant_factor.to_bits(),
+            rasterization.depth_bias_clamp.to_bits(),
+            rasterization.depth_bias_slope_factor.to_bits(),
+        ]);
+
+        words.extend([
+            self.multisampling.rasterization_samples.as_raw(),
+            self.multisampling.sample_shading_enable,
+            self.multisampling.min_sample_shading.to_bits(),
+        ]);
+
+        words.extend([
+            self.depth_stencil.depth_test_enable,
+            self.depth_stencil.depth_write_enable,
+            self.depth_stencil.depth_compare_op.as_raw() as u32,
+            self.depth_mode.is_reversed() as u32,
+        ]);
+
+        words.push(self.color_blend_attachment_states.len() as u32);
+        for blend in self.color_blend_attachment_states.iter() {
+            words.extend([
+                blend.blend_enable,
+                blend.src_color_blend_factor.as_raw() as u32,
+                blend.dst_color_blend_factor.as_raw() as u32,
+                blend.color_blend_op.as_raw() as u32,
+                blend.src_alpha_blend_factor.as_raw() as u32,
+                blend.dst_alpha_blend_factor.as_raw() as u32,
+                blend.alpha_blend_op.as_raw() as u32,
+                blend.color_write_mask.as_raw(),
+            ]);
+        }
+
+        words.push(self.dynamic_states.len() as u32);
+        words.extend(self.dynamic_states.iter().map(|state| state.as_raw() as u32));
+
+        words
+    }
+
+    // Builder of a recorded pipeline, shaders are set by the caller
+    pub(crate) fn from_record(device: ash::Device, render_pass: vk::RenderPass, subpass: u32, state: &[u32]) -> Result<PipelineBuilder, String> {
+        let mut words = state.iter().copied();
+        let mut next = || words.next().ok_or_else(|| "Pipeline state of the record is truncated".to_string());
+
+        let mut builder = PipelineBuilder::new(device, render_pass, subpass);
+
+        for _ in 0..next()? {
+            builder.vertex_input_bindings.push(vk::VertexInputBindingDescription {
+                binding: next()?,
+                stride: next()?,
+                input_rate: vk::VertexInputRate::from_raw(next()? as i32),
+            });
+        }
+        for _ in 0..next()? {
+            builder.vertex_input_attributes.push(vk::VertexInputAttributeDescription {
+                location: next()?,
+                binding: next()?,
+                format: vk::Format::from_raw(next()? as i32),
+                offset: next()?,
+            });
+        }
+
+        builder.input_assembly.topology = vk::PrimitiveTopology::from_raw(next()? as i32);
+        builder.input_assembly.primitive_restart_enable = next()?;
+        let patch_control_points = next()?;
+        if patch_control_points > 0 {
+            builder.tesselation = Some(vk::PipelineTessellationStateCreateInfo {
+                patch_control_points,
+                ..Default::default()
+            });
+        }
+
+        let rasterization = &mut builder.rasterization;
+        rasterization.depth_clamp_enable = next()?;
+        rasterization.polygon_mode = vk::PolygonMode::from_raw(next()? as i32);
+        rasterization.cull_mode = vk::CullModeFlags::from_raw(next()?);
+        rasterization.front_face = vk::FrontFace::from_raw(next()? as i32);
+        rasterization.line_width = f32::from_bits(next()?);
+        rasterization.depth_bias_enable = next()?;
+        rasterization.depth_bias_constant_factor = f32::from_bits(next()?);
+        rasterization.depth_bias_clamp = f32::from_bits(next()?);
+        rasterization.depth_bias_slope_factor = f32::from_bits(next()?);
+
+        builder.multisampling.rasterization_samples = vk::SampleCountFlags::from_raw(next()?);
+        builder.multisampling.sample_shading_enable = next()?;
+        builder.multisampling.min_sample_shading = f32::from_bits(next()?);
+
+        builder.depth_stencil.depth_test_enable = next()?;
+        builder.depth_stencil.depth_write_enable = next()?;
+        builder.depth_stencil.depth_compare_op = vk::CompareOp::from_raw(next()? as i32);
+        if next()? != 0 {
+            builder.depth_mode = DepthMode::ReverseZ;
+        }
+
+        builder.color_blend_attachment_states.clear();
+        for _ in 0..next()? {
+            builder.color_blend_attachment_states.push(vk::PipelineColorBlendAttachmentState {
+                blend_enable: next()?,
+                src_color_blend_factor: vk::BlendFactor::from_raw(next()? as i32),
+                dst_color_blend_factor: vk::BlendFactor::from_raw(next()? as i32),
+                color_blend_op: vk::BlendOp::from_raw(next()? as i32),
+                src_alpha_blend_factor: vk::BlendFactor::from_raw(next()? as i32),
+                dst_alpha_blend_factor: vk::BlendFactor::from_raw(next()? as i32),
+                alpha_blend_op: vk::BlendOp::from_raw(next()? as i32),
+                color_write_mask: vk::ColorComponentFlags::from_raw(next()?),
+            });
+        }
+
+        builder.dynamic_states.clear();
+        for _ in 0..next()? {
+            builder.dynamic_states.push(vk::DynamicState::from_raw(next()? as i32));
+        }
+
+        Ok(builder)
     }
 }
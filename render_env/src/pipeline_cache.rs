@@ -0,0 +1,620 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use crate::debug_utils::DebugUtils;
+use crate::object_tracker::{self, ObjectKind};
+use crate::pipeline_builder::PipelineBuilder;
+use crate::shader::{ConstantsBuilder, Shader};
+
+const PROFILE_MAGIC: &[u8; 4] = b"PPRF";
+const PROFILE_VERSION: u32 = 2;
+
+// Attachment reference of a subpass without an attachment
+const UNUSED: u32 = vk::ATTACHMENT_UNUSED;
+
+// Layouts of created render passes, by handle. Pipelines are recorded with the layout of their
+// render pass, so the warmup can create them again with a compatible one
+static RENDER_PASSES: Mutex<BTreeMap<u64, RenderPassLayout>> = Mutex::new(BTreeMap::new());
+
+/// Call right after create_render_pass, handles of destroyed passes are overwritten when reused by the driver.
+///
+/// # Safety
+/// Pointers of `info` must be valid, as for create_render_pass
+pub unsafe fn render_pass_created(render_pass: vk::RenderPass, info: &vk::RenderPassCreateInfo) {
+    let layout = RenderPassLayout::from_create_info(info);
+    RENDER_PASSES.lock().unwrap().insert(vk::Handle::as_raw(render_pass), layout);
+}
+
+pub(crate) fn render_pass_layout(render_pass: vk::RenderPass) -> Option<RenderPassLayout> {
+    RENDER_PASSES.lock().unwrap().get(&vk::Handle::as_raw(render_pass)).cloned()
+}
+
+// What makes render passes compatible: attachment formats, sample counts and references of the subpasses
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct RenderPassLayout {
+    attachments: Vec<(vk::Format, vk::SampleCountFlags)>,
+    subpasses: Vec<SubpassLayout>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct SubpassLayout {
+    inputs: Vec<u32>,
+    colors: Vec<u32>,
+    resolves: Vec<u32>,
+    depth: u32,
+}
+
+unsafe fn attachment_refs(refs: *const vk::AttachmentReference, count: u32) -> Vec<u32> {
+    if refs.is_null() || count == 0 {
+        return vec![];
+    }
+
+    slice::from_raw_parts(refs, count as usize).iter().map(|r| r.attachment).collect()
+}
+
+impl RenderPassLayout {
+    unsafe fn from_create_info(info: &vk::RenderPassCreateInfo) -> RenderPassLayout {
+        let attachments = if info.attachment_count > 0 {
+            slice::from_raw_parts(info.p_attachments, info.attachment_count as usize)
+        } else {
+            &[]
+        };
+        let subpasses = if info.subpass_count > 0 {
+            slice::from_raw_parts(info.p_subpasses, info.subpass_count as usize)
+        } else {
+            &[]
+        };
+
+        RenderPassLayout {
+            attachments: attachments.iter().map(|a| (a.format, a.samples)).collect(),
+            subpasses: subpasses.iter()
+                .map(|subpass| SubpassLayout {
+                    inputs: attachment_refs(subpass.p_input_attachments, subpass.input_attachment_count),
+                    colors: attachment_refs(subpass.p_color_attachments, subpass.color_attachment_count),
+                    // resolve attachments have color attachment count when present
+                    resolves: attachment_refs(subpass.p_resolve_attachments, subpass.color_attachment_count),
+                    depth: attachment_refs(subpass.p_depth_stencil_attachment, 1).first().copied().unwrap_or(UNUSED),
+                })
+                .collect(),
+        }
+    }
+
+    // Render pass compatible with the recorded one, load/store ops and layouts don't matter for pipelines
+    fn create_render_pass(&self, device: &ash::Device) -> vk::RenderPass {
+        let attachments: Vec<_> = self.attachments.iter()
+            .map(|&(format, samples)| vk::AttachmentDescription {
+                flags: vk::AttachmentDescriptionFlags::empty(),
+                format,
+                samples,
+                load_op: vk::AttachmentLoadOp::DONT_CARE,
+                store_op: vk::AttachmentStoreOp::DONT_CARE,
+                stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+                stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+                initial_layout: vk::ImageLayout::UNDEFINED,
+                final_layout: vk::ImageLayout::GENERAL,
+            })
+            .collect();
+
+        let to_refs = |attachments: &[u32]| -> Vec<vk::AttachmentReference> {
+            attachments.iter()
+                .map(|&attachment| vk::AttachmentReference { attachment, layout: vk::ImageLayout::GENERAL })
+                .collect()
+        };
+        let refs: Vec<_> = self.subpasses.iter()
+            .map(|subpass| (to_refs(&subpass.inputs), to_refs(&subpass.colors), to_refs(&subpass.resolves), to_refs(&[subpass.depth])))
+            .collect();
+
+        let subpasses: Vec<_> = refs.iter()
+            .zip(self.subpasses.iter())
+            .map(|((inputs, colors, resolves, depth), subpass)| vk::SubpassDescription {
+                flags: vk::SubpassDescriptionFlags::empty(),
+                pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
+                input_attachment_count: inputs.len() as u32,
+                p_input_attachments: inputs.as_ptr(),
+                color_attachment_count: colors.len() as u32,
+                p_color_attachments: colors.as_ptr(),
+                p_resolve_attachments: if resolves.is_empty() { ptr::null() } else { resolves.as_ptr() },
+                p_depth_stencil_attachment: if subpass.depth == UNUSED { ptr::null() } else { depth.as_ptr() },
+                preserve_attachment_count: 0,
+                p_preserve_attachments: ptr::null(),
+            })
+            .collect();
+
+        let render_pass_create_info = vk::RenderPassCreateInfo {
+            s_type: vk::StructureType::RENDER_PASS_CREATE_INFO,
+            p_next: ptr::null(),
+            flags: vk::RenderPassCreateFlags::empty(),
+            attachment_count: attachments.len() as u32,
+            p_attachments: attachments.as_ptr(),
+            subpass_count: subpasses.len() as u32,
+            p_subpasses: subpasses.as_ptr(),
+            dependency_count: 0,
+            p_dependencies: ptr::null(),
+        };
+
+        unsafe {
+            let render_pass = device.create_render_pass(&render_pass_create_info, None)
+                .expect("Failed to create warmup render pass!");
+            object_tracker::created(ObjectKind::RenderPass);
+            render_pass_created(render_pass, &render_pass_create_info);
+
+            render_pass
+        }
+    }
+
+    fn write(&self, writer: &mut ProfileWriter) {
+        writer.u32(self.attachments.len() as u32);
+        for &(format, samples) in self.attachments.iter() {
+            writer.u32(format.as_raw() as u32);
+            writer.u32(samples.as_raw());
+        }
+
+        writer.u32(self.subpasses.len() as u32);
+        for subpass in self.subpasses.iter() {
+            writer.words(&subpass.inputs);
+            writer.words(&subpass.colors);
+            writer.words(&subpass.resolves);
+            writer.u32(subpass.depth);
+        }
+    }
+
+    fn read(reader: &mut ProfileReader) -> Result<RenderPassLayout, String> {
+        let mut attachments = vec![];
+        for _ in 0..reader.u32()? {
+            let format = vk::Format::from_raw(reader.u32()? as i32);
+            let samples = vk::SampleCountFlags::from_raw(reader.u32()?);
+            attachments.push((format, samples));
+        }
+
+        let mut subpasses = vec![];
+        for _ in 0..reader.u32()? {
+            subpasses.push(SubpassLayout {
+                inputs: reader.words()?,
+                colors: reader.words()?,
+                resolves: reader.words()?,
+                depth: reader.u32()?,
+            });
+        }
+
+        Ok(RenderPassLayout { attachments, subpasses })
+    }
+}
+
+// Shader of a recorded pipeline, loaded from `path` again by the warmup
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ShaderRecord {
+    pub path: String,
+    // the shader was changed since recording when the code has another hash
+    pub code_hash: u64,
+    // specialization map entries: constant id, offset, size
+    pub constants: Vec<[u32; 3]>,
+    pub constants_data: Vec<u8>,
+    pub dynamic_uniform_buffers: Vec<(u32, u32)>,
+}
+
+impl ShaderRecord {
+    fn write(&self, writer: &mut ProfileWriter) {
+        writer.bytes(self.path.as_bytes());
+        writer.u32(self.code_hash as u32);
+        writer.u32((self.code_hash >> 32) as u32);
+        writer.u32(self.constants.len() as u32);
+        for entry in self.constants.iter() {
+            writer.words(entry);
+        }
+        writer.bytes(&self.constants_data);
+        writer.u32(self.dynamic_uniform_buffers.len() as u32);
+        for &(set, binding) in self.dynamic_uniform_buffers.iter() {
+            writer.u32(set);
+            writer.u32(binding);
+        }
+    }
+
+    fn read(reader: &mut ProfileReader) -> Result<ShaderRecord, String> {
+        let path = String::from_utf8(reader.bytes()?).map_err(|_| "Shader path is not utf-8".to_string())?;
+        let code_hash = reader.u32()? as u64 | (reader.u32()? as u64) << 32;
+
+        let mut constants = vec![];
+        for _ in 0..reader.u32()? {
+            let entry = reader.words()?;
+            if entry.len() != 3 {
+                return Err("Broken specialization constant in pipeline profile".to_string());
+            }
+            constants.push([entry[0], entry[1], entry[2]]);
+        }
+        let constants_data = reader.bytes()?;
+
+        let mut dynamic_uniform_buffers = vec![];
+        for _ in 0..reader.u32()? {
+            dynamic_uniform_buffers.push((reader.u32()?, reader.u32()?));
+        }
+
+        Ok(ShaderRecord { path, code_hash, constants, constants_data, dynamic_uniform_buffers })
+    }
+
+    // None when the file is gone, was changed since recording or can't be loaded
+    fn load(&self, device: &ash::Device) -> Option<Shader> {
+        let code = fs::read(&self.path).ok()?;
+        if code_hash(&code) != self.code_hash {
+            return None;
+        }
+
+        let mut shader = Shader::from_spv(device, &code, &self.path).ok()?;
+        if !self.constants.is_empty() {
+            shader = shader.specialize(ConstantsBuilder::from_raw(&self.constants, self.constants_data.clone()));
+        }
+        for &(set, binding) in self.dynamic_uniform_buffers.iter() {
+            shader = shader.dynamic_uniform_buffer(set, binding);
+        }
+
+        Some(shader)
+    }
+}
+
+// Everything to create the same pipeline again, without handles
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct PipelineRecord {
+    pub render_pass: RenderPassLayout,
+    pub subpass: u32,
+    pub vertex_shader: ShaderRecord,
+    // control and evaluation shaders of tessellated pipelines
+    pub tessellation_shaders: Option<(ShaderRecord, ShaderRecord)>,
+    pub fragment_shader: ShaderRecord,
+    // fixed function state, written and read by PipelineBuilder
+    pub state: Vec<u32>,
+}
+
+impl PipelineRecord {
+    fn write(&self, writer: &mut ProfileWriter) {
+        self.render_pass.write(writer);
+        writer.u32(self.subpass);
+        self.vertex_shader.write(writer);
+        writer.u32(self.tessellation_shaders.is_some() as u32);
+        if let Some((control_shader, evaluation_shader)) = &self.tessellation_shaders {
+            control_shader.write(writer);
+            evaluation_shader.write(writer);
+        }
+        self.fragment_shader.write(writer);
+        writer.words(&self.state);
+    }
+
+    fn read(reader: &mut ProfileReader) -> Result<PipelineRecord, String> {
+        Ok(PipelineRecord {
+            render_pass: RenderPassLayout::read(reader)?,
+            subpass: reader.u32()?,
+            vertex_shader: ShaderRecord::read(reader)?,
+            tessellation_shaders: match reader.u32()? {
+                0 => None,
+                _ => Some((ShaderRecord::read(reader)?, ShaderRecord::read(reader)?)),
+            },
+            fragment_shader: ShaderRecord::read(reader)?,
+            state: reader.words()?,
+        })
+    }
+
+    // Pipeline is created into the cache and destroyed right away, false when shaders are stale
+    fn warm(&self, device: &ash::Device, cache: &Arc<PipelineCache>) -> Result<bool, String> {
+        let (vertex_shader, fragment_shader) = match (self.vertex_shader.load(device), self.fragment_shader.load(device)) {
+            (Some(vertex_shader), Some(fragment_shader)) => (vertex_shader, fragment_shader),
+            _ => return Ok(false),
+        };
+        let tessellation_shaders = match &self.tessellation_shaders {
+            Some((control_shader, evaluation_shader)) => match (control_shader.load(device), evaluation_shader.load(device)) {
+                (Some(control_shader), Some(evaluation_shader)) => Some((control_shader, evaluation_shader)),
+                _ => return Ok(false),
+            },
+            None => None,
+        };
+
+        let render_pass = self.render_pass.create_render_pass(device);
+        // built pipeline records itself again, so the profile keeps it for the next run
+        let result = PipelineBuilder::from_record(device.clone(), render_pass, self.subpass, &self.state)
+            .map(|builder| match tessellation_shaders {
+                Some((control_shader, evaluation_shader)) => builder.tessellation_shaders(control_shader, evaluation_shader),
+                None => builder,
+            })
+            .and_then(|builder| builder
+                .vertex_shader(vertex_shader)
+                .fragment_shader(fragment_shader)
+                .pipeline_cache(cache)
+                .build()
+                .map(drop)
+                .map_err(|err| err.to_string()));
+
+        unsafe {
+            device.destroy_render_pass(render_pass, None);
+        }
+        object_tracker::destroyed(ObjectKind::RenderPass);
+
+        result.map(|_| true)
+    }
+}
+
+// Stable across runs, unlike DefaultHasher (FNV-1a)
+pub(crate) fn code_hash(code: &[u8]) -> u64 {
+    code.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+// Driver cache of compiled pipelines shared by all pipelines of the env, see PipelineBuilder::pipeline_cache.
+// Also records every pipeline created with it, so the next run can compile them ahead with warmup()
+pub struct PipelineCache {
+    device: ash::Device,
+    cache: vk::PipelineCache,
+    // vendor id, device id and cache uuid, header of the cache data must match them
+    vendor_id: u32,
+    device_id: u32,
+    cache_uuid: [u8; vk::UUID_SIZE],
+    records: Mutex<Vec<PipelineRecord>>,
+    // pipelines created with the cache are named after their shaders
+    debug_utils: Option<DebugUtils>,
+}
+
+impl PipelineCache {
+    pub(crate) fn new(device: &ash::Device, properties: &vk::PhysicalDeviceProperties, debug_utils: Option<DebugUtils>) -> PipelineCache {
+        PipelineCache {
+            device: device.clone(),
+            cache: Self::create_cache(device, &[]),
+            vendor_id: properties.vendor_id,
+            device_id: properties.device_id,
+            cache_uuid: properties.pipeline_cache_uuid,
+            records: Mutex::new(vec![]),
+            debug_utils,
+        }
+    }
+
+    fn create_cache(device: &ash::Device, data: &[u8]) -> vk::PipelineCache {
+        let cache_ci = vk::PipelineCacheCreateInfo {
+            s_type: vk::StructureType::PIPELINE_CACHE_CREATE_INFO,
+            p_next: ptr::null(),
+            flags: vk::PipelineCacheCreateFlags::empty(),
+            initial_data_size: data.len(),
+            p_initial_data: data.as_ptr() as *const _,
+        };
+
+        unsafe {
+            device.create_pipeline_cache(&cache_ci, None)
+                .expect("Failed to create pipeline cache!")
+        }
+    }
+
+    #[inline]
+    pub fn handle(&self) -> vk::PipelineCache {
+        self.cache
+    }
+
+    pub(crate) fn set_pipeline_name(&self, pipeline: vk::Pipeline, name: &str) {
+        if let Some(debug_utils) = &self.debug_utils {
+            debug_utils.set_name(pipeline, name);
+        }
+    }
+
+    // Merges driver data saved by save() before. Data of another device or driver version is rejected
+    pub fn load(&self, path: &Path) -> Result<(), String> {
+        let data = fs::read(path).map_err(|err| format!("Failed to read pipeline cache {:?}: {}", path, err))?;
+
+        // header version one: length, version, vendor id, device id, uuid
+        let header_len = 16 + vk::UUID_SIZE;
+        let word = |idx: usize| u32::from_le_bytes([data[idx * 4], data[idx * 4 + 1], data[idx * 4 + 2], data[idx * 4 + 3]]);
+        if data.len() < header_len
+            || word(1) != vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32
+            || word(2) != self.vendor_id
+            || word(3) != self.device_id
+            || data[16..header_len] != self.cache_uuid {
+            return Err(format!("Pipeline cache {:?} is from another device or driver", path));
+        }
+
+        let loaded = Self::create_cache(&self.device, &data);
+        unsafe {
+            // no wrapper for it in ash
+            let result = self.device.fp_v1_0().merge_pipeline_caches(self.device.handle(), self.cache, 1, &loaded);
+            self.device.destroy_pipeline_cache(loaded, None);
+            match result {
+                vk::Result::SUCCESS => Ok(()),
+                err => Err(format!("Failed to merge pipeline cache: {}", err)),
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let data = unsafe {
+            self.device.get_pipeline_cache_data(self.cache)
+                .map_err(|err| io::Error::other(err.to_string()))?
+        };
+
+        fs::write(path, data)
+    }
+
+    // Distinct pipelines recorded in this run and loaded by warmup()
+    pub fn pipeline_count(&self) -> usize {
+        self.records.lock().unwrap().len()
+    }
+
+    pub(crate) fn record(&self, record: PipelineRecord) {
+        let mut records = self.records.lock().unwrap();
+        if !records.contains(&record) {
+            records.push(record);
+        }
+    }
+
+    // Pipelines for warmup() of the next run
+    pub fn save_profile(&self, path: &Path) -> io::Result<()> {
+        let records = self.records.lock().unwrap();
+
+        let mut writer = ProfileWriter { data: PROFILE_MAGIC.to_vec() };
+        writer.u32(PROFILE_VERSION);
+        writer.u32(records.len() as u32);
+        for record in records.iter() {
+            record.write(&mut writer);
+        }
+
+        fs::write(path, writer.data)
+    }
+
+    // Creates pipelines of the profile saved by save_profile() on a background thread, so permutations
+    // used by the previous run are compiled before they are needed. Records with changed shaders are skipped
+    pub fn warmup(self: &Arc<Self>, path: &Path) -> Result<PipelineWarmup, String> {
+        let data = fs::read(path).map_err(|err| format!("Failed to read pipeline profile {:?}: {}", path, err))?;
+
+        let mut reader = ProfileReader { data: &data, pos: 0 };
+        if reader.take(4)? != PROFILE_MAGIC {
+            return Err(format!("{:?} is not a pipeline profile", path));
+        }
+        let version = reader.u32()?;
+        if version != PROFILE_VERSION {
+            return Err(format!("Unsupported pipeline profile version {}", version));
+        }
+
+        let mut records = vec![];
+        for _ in 0..reader.u32()? {
+            records.push(PipelineRecord::read(&mut reader)?);
+        }
+
+        let total = records.len();
+        let done = Arc::new(AtomicUsize::new(0));
+        let skipped = Arc::new(AtomicUsize::new(0));
+        let cancel = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let cache = self.clone();
+            let (done, skipped, cancel) = (done.clone(), skipped.clone(), cancel.clone());
+
+            thread::Builder::new()
+                .name("pipeline warmup".to_string())
+                .spawn(move || {
+                    for record in records.iter() {
+                        if cancel.load(Ordering::Relaxed) {
+                            break;
+                        }
+
+                        match record.warm(&cache.device, &cache) {
+                            Ok(true) => (),
+                            Ok(false) => { skipped.fetch_add(1, Ordering::Relaxed); }
+                            Err(err) => {
+                                println!("Pipeline warmup: {}", err);
+                                skipped.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                        done.fetch_add(1, Ordering::Relaxed);
+                    }
+                })
+                .expect("Failed to start pipeline warmup thread!")
+        };
+
+        Ok(PipelineWarmup {
+            total,
+            done,
+            skipped,
+            cancel,
+            thread: Some(thread),
+        })
+    }
+
+    // Called by RenderEnv before the device is destroyed, warmup must be finished
+    pub(crate) fn destroy(&self) {
+        unsafe {
+            self.device.destroy_pipeline_cache(self.cache, None);
+        }
+    }
+}
+
+// Progress of PipelineCache::warmup, the thread is stopped when dropped
+pub struct PipelineWarmup {
+    total: usize,
+    done: Arc<AtomicUsize>,
+    // stale records and failures
+    skipped: Arc<AtomicUsize>,
+    cancel: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PipelineWarmup {
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    pub fn done(&self) -> usize {
+        self.done.load(Ordering::Relaxed)
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.skipped.load(Ordering::Relaxed)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.done() == self.total
+    }
+
+    fn stop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().expect("Pipeline warmup thread panicked!");
+        }
+    }
+}
+
+impl Drop for PipelineWarmup {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+struct ProfileWriter {
+    data: Vec<u8>,
+}
+
+impl ProfileWriter {
+    fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn words(&mut self, words: &[u32]) {
+        self.u32(words.len() as u32);
+        for &word in words {
+            self.u32(word);
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+struct ProfileReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ProfileReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self.data.get(self.pos..self.pos + len)
+            .ok_or_else(|| "Pipeline profile is truncated".to_string())?;
+        self.pos += len;
+
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn words(&mut self) -> Result<Vec<u32>, String> {
+        let len = self.u32()?;
+        (0..len).map(|_| self.u32()).collect()
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+}
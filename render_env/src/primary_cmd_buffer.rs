@@ -4,6 +4,7 @@ use ash::version::DeviceV1_0;
 use ash::vk;
 
 use crate::env::RenderEnv;
+use crate::swapchain::{SwapChain, SwapChainListener};
 use std::ptr;
 
 pub struct PrimaryCommandBuffer {
@@ -12,6 +13,8 @@ pub struct PrimaryCommandBuffer {
     cmd_bufs: Vec<vk::CommandBuffer>,
     max_frame_in_flight: usize,
     current_frame: usize,
+    // label of the render pass, see set_name()
+    name: Option<String>,
 }
 
 impl PrimaryCommandBuffer {
@@ -27,14 +30,30 @@ impl PrimaryCommandBuffer {
             cmd_bufs,
             max_frame_in_flight,
             current_frame: 0,
+            name: None,
         }
     }
 
+    // Names the command buffers and wraps the render pass into a label, for RenderDoc and validation messages
+    pub fn set_name(&mut self, name: &str) {
+        for (idx, command_buffer) in self.cmd_bufs.iter().enumerate() {
+            self.env.set_debug_name(*command_buffer, &format!("{} #{}", name, idx));
+        }
+        self.name = Some(name.to_string());
+    }
+
     pub fn set_dimensions(&mut self, dims: [u32; 2]) {
         self.dimensions = dims;
     }
 
     pub fn execute_secondary(&mut self, clear_values: Vec<vk::ClearValue>, framebuffer: vk::Framebuffer, render_pass: vk::RenderPass, second_buffers: &[vk::CommandBuffer]) -> vk::CommandBuffer {
+        self.execute_subpasses(clear_values, framebuffer, render_pass, &[second_buffers])
+    }
+
+    // Secondary buffers of every subpass of a multi-subpass render pass, in subpass order.
+    // Buffers of subpass N must inherit subpass N
+    pub fn execute_subpasses(&mut self, clear_values: Vec<vk::ClearValue>, framebuffer: vk::Framebuffer, render_pass: vk::RenderPass,
+                             subpass_buffers: &[&[vk::CommandBuffer]]) -> vk::CommandBuffer {
         let command_buffer = self.cmd_bufs.get(self.current_frame).unwrap().clone();
         unsafe {
             self.env.device().reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::default()).unwrap()
@@ -69,6 +88,10 @@ impl PrimaryCommandBuffer {
             p_clear_values: clear_values.as_ptr(),
         };
 
+        if let Some(name) = &self.name {
+            self.env.cmd_begin_label(command_buffer, name);
+        }
+
         unsafe {
             self.env.device().cmd_begin_render_pass(
                 command_buffer,
@@ -76,10 +99,23 @@ impl PrimaryCommandBuffer {
                 vk::SubpassContents::SECONDARY_COMMAND_BUFFERS,
             );
 
-            self.env.device().cmd_execute_commands(command_buffer, second_buffers);
+            for (subpass_idx, second_buffers) in subpass_buffers.iter().enumerate() {
+                if subpass_idx > 0 {
+                    self.env.device().cmd_next_subpass(command_buffer, vk::SubpassContents::SECONDARY_COMMAND_BUFFERS);
+                }
+                if !second_buffers.is_empty() {
+                    self.env.device().cmd_execute_commands(command_buffer, second_buffers);
+                }
+            }
 
             self.env.device().cmd_end_render_pass(command_buffer);
+        }
+
+        if self.name.is_some() {
+            self.env.cmd_end_label(command_buffer);
+        }
 
+        unsafe {
             self.env.device()
                 .end_command_buffer(command_buffer)
                 .expect("Failed to record Command Buffer at Ending!");
@@ -90,6 +126,12 @@ impl PrimaryCommandBuffer {
     }
 }
 
+impl SwapChainListener for PrimaryCommandBuffer {
+    fn swapchain_recreated(&mut self, swapchain: &SwapChain) {
+        self.set_dimensions([swapchain.size.width, swapchain.size.height]);
+    }
+}
+
 impl Drop for PrimaryCommandBuffer {
     fn drop(&mut self) {
         unsafe {
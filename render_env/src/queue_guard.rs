@@ -0,0 +1,91 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use ash::prelude::VkResult;
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+// vk::Queue behind a mutex. vkQueueSubmit, vkQueueWaitIdle and vkQueuePresentKHR need external
+// synchronization of the queue, so uploads from worker threads and the render loop submit through here.
+// Command pools need it too: a worker thread records into its own pool.
+// Guards of aliased queues (present or transfer queue being the graphics one) share the lock
+pub struct QueueGuard {
+    queue: vk::Queue,
+    lock: Arc<Mutex<()>>,
+}
+
+// Queue locked for calls not wrapped by QueueGuard
+pub struct LockedQueue<'a> {
+    queue: vk::Queue,
+    _guard: MutexGuard<'a, ()>,
+}
+
+impl LockedQueue<'_> {
+    pub fn handle(&self) -> vk::Queue {
+        self.queue
+    }
+}
+
+impl QueueGuard {
+    pub(crate) fn new(queue: vk::Queue) -> QueueGuard {
+        QueueGuard {
+            queue,
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    // Guard of another queue handle, the lock is shared when it is the same queue
+    pub(crate) fn alias(&self, queue: vk::Queue) -> QueueGuard {
+        if queue == self.queue {
+            QueueGuard {
+                queue,
+                lock: self.lock.clone(),
+            }
+        } else {
+            QueueGuard::new(queue)
+        }
+    }
+
+    // Raw handle, e.g. for debug names. Don't submit with it
+    pub fn handle(&self) -> vk::Queue {
+        self.queue
+    }
+
+    pub fn lock(&self) -> LockedQueue<'_> {
+        LockedQueue {
+            queue: self.queue,
+            // the lock guards no data, a panic while holding it leaves nothing broken
+            _guard: self.lock.lock().unwrap_or_else(|err| err.into_inner()),
+        }
+    }
+
+    pub fn submit(&self, device: &ash::Device, submits: &[vk::SubmitInfo], fence: vk::Fence) -> VkResult<()> {
+        let queue = self.lock();
+        unsafe { device.queue_submit(queue.handle(), submits, fence) }
+    }
+
+    // Waits for this submission only, other threads can submit meanwhile
+    pub fn submit_and_wait(&self, device: &ash::Device, submits: &[vk::SubmitInfo]) -> VkResult<()> {
+        let fence_info = vk::FenceCreateInfo::builder();
+        let fence = unsafe { device.create_fence(&fence_info, None)? };
+
+        let result = self.submit(device, submits, fence)
+            .and_then(|_| unsafe { device.wait_for_fences(&[fence], true, u64::MAX) });
+
+        unsafe {
+            device.destroy_fence(fence, None);
+        }
+
+        result
+    }
+
+    pub fn wait_idle(&self, device: &ash::Device) -> VkResult<()> {
+        let queue = self.lock();
+        unsafe { device.queue_wait_idle(queue.handle()) }
+    }
+
+    // Ok(true) when the swapchain is suboptimal
+    pub fn present(&self, swapchain_api: &ash::extensions::khr::Swapchain, present_info: &vk::PresentInfoKHR) -> VkResult<bool> {
+        let queue = self.lock();
+        unsafe { swapchain_api.queue_present(queue.handle(), present_info) }
+    }
+}
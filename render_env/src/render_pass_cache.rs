@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::slice;
+use std::sync::Mutex;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use crate::error::{RenderError, RenderResult};
+use crate::object_tracker::{self, ObjectKind};
+use crate::pipeline_cache;
+
+// (attachment, layout)
+type AttachmentRef = (u32, vk::ImageLayout);
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct AttachmentKey {
+    format: vk::Format,
+    samples: vk::SampleCountFlags,
+    load_op: vk::AttachmentLoadOp,
+    store_op: vk::AttachmentStoreOp,
+    stencil_load_op: vk::AttachmentLoadOp,
+    stencil_store_op: vk::AttachmentStoreOp,
+    initial_layout: vk::ImageLayout,
+    final_layout: vk::ImageLayout,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct SubpassKey {
+    inputs: Vec<AttachmentRef>,
+    colors: Vec<AttachmentRef>,
+    resolves: Vec<AttachmentRef>,
+    depth: Option<AttachmentRef>,
+    preserves: Vec<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct DependencyKey {
+    subpasses: [u32; 2],
+    stages: [vk::PipelineStageFlags; 2],
+    access: [vk::AccessFlags; 2],
+    flags: vk::DependencyFlags,
+}
+
+// Everything of a render pass create info. Passes with equal keys behave the same, so one vk::RenderPass serves all of them
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct RenderPassKey {
+    attachments: Vec<AttachmentKey>,
+    subpasses: Vec<SubpassKey>,
+    dependencies: Vec<DependencyKey>,
+}
+
+unsafe fn as_slice<'a, T>(ptr: *const T, count: u32) -> &'a [T] {
+    if ptr.is_null() || count == 0 {
+        return &[];
+    }
+
+    slice::from_raw_parts(ptr, count as usize)
+}
+
+unsafe fn attachment_refs(refs: *const vk::AttachmentReference, count: u32) -> Vec<AttachmentRef> {
+    as_slice(refs, count).iter().map(|r| (r.attachment, r.layout)).collect()
+}
+
+impl RenderPassKey {
+    // pointers of `info` must be valid, as for create_render_pass
+    unsafe fn from_create_info(info: &vk::RenderPassCreateInfo) -> RenderPassKey {
+        RenderPassKey {
+            attachments: as_slice(info.p_attachments, info.attachment_count).iter()
+                .map(|a| AttachmentKey {
+                    format: a.format,
+                    samples: a.samples,
+                    load_op: a.load_op,
+                    store_op: a.store_op,
+                    stencil_load_op: a.stencil_load_op,
+                    stencil_store_op: a.stencil_store_op,
+                    initial_layout: a.initial_layout,
+                    final_layout: a.final_layout,
+                })
+                .collect(),
+            subpasses: as_slice(info.p_subpasses, info.subpass_count).iter()
+                .map(|subpass| SubpassKey {
+                    inputs: attachment_refs(subpass.p_input_attachments, subpass.input_attachment_count),
+                    colors: attachment_refs(subpass.p_color_attachments, subpass.color_attachment_count),
+                    // resolve attachments have color attachment count when present
+                    resolves: attachment_refs(subpass.p_resolve_attachments, subpass.color_attachment_count),
+                    depth: attachment_refs(subpass.p_depth_stencil_attachment, 1).first().copied(),
+                    preserves: as_slice(subpass.p_preserve_attachments, subpass.preserve_attachment_count).to_vec(),
+                })
+                .collect(),
+            dependencies: as_slice(info.p_dependencies, info.dependency_count).iter()
+                .map(|dep| DependencyKey {
+                    subpasses: [dep.src_subpass, dep.dst_subpass],
+                    stages: [dep.src_stage_mask, dep.dst_stage_mask],
+                    access: [dep.src_access_mask, dep.dst_access_mask],
+                    flags: dep.dependency_flags,
+                })
+                .collect(),
+        }
+    }
+}
+
+// Render passes of RenderEnv, created once per key and destroyed with the device.
+// Users don't destroy passes they got from here: the same handle may be used by others
+pub struct RenderPassCache {
+    device: ash::Device,
+    passes: Mutex<HashMap<RenderPassKey, vk::RenderPass>>,
+}
+
+impl RenderPassCache {
+    pub(crate) fn new(device: &ash::Device) -> RenderPassCache {
+        RenderPassCache {
+            device: device.clone(),
+            passes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Pass for `info`, an existing one when some pass was created with the same info before
+    pub fn get_or_create(&self, info: &vk::RenderPassCreateInfo) -> RenderResult<vk::RenderPass> {
+        let key = unsafe { RenderPassKey::from_create_info(info) };
+
+        let mut passes = self.passes.lock().unwrap();
+        if let Some(&render_pass) = passes.get(&key) {
+            return Ok(render_pass);
+        }
+
+        let render_pass = unsafe {
+            let render_pass = self.device.create_render_pass(info, None)
+                .map_err(RenderError::vulkan("vkCreateRenderPass"))?;
+            pipeline_cache::render_pass_created(render_pass, info);
+            render_pass
+        };
+        object_tracker::created(ObjectKind::RenderPass);
+
+        passes.insert(key, render_pass);
+        Ok(render_pass)
+    }
+
+    // Pipelines and secondary command buffers of one pass can be used in the other one.
+    // Only attachment formats, samples and subpass references matter, not load ops or layouts
+    pub fn compatible(&self, a: vk::RenderPass, b: vk::RenderPass) -> bool {
+        if a == b {
+            return true;
+        }
+
+        match (pipeline_cache::render_pass_layout(a), pipeline_cache::render_pass_layout(b)) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.passes.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn destroy(&self) {
+        for (_, render_pass) in self.passes.lock().unwrap().drain() {
+            unsafe {
+                self.device.destroy_render_pass(render_pass, None);
+            }
+            object_tracker::destroyed(ObjectKind::RenderPass);
+        }
+    }
+}
@@ -1,15 +1,19 @@
 use core::mem;
 use std::{ffi, ptr};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::{CString};
-use std::fs::File;
-use std::io::Read;
+use std::fs;
 
 use ash::version::DeviceV1_0;
 use ash::vk;
 use ash::vk::DescriptorSetLayoutBinding;
 use spirv_reflect::ShaderModule;
-use spirv_reflect::types::{ReflectDescriptorType, ReflectShaderStageFlags};
+use spirv_reflect::types::{ReflectBlockVariable, ReflectDescriptorType, ReflectShaderStageFlags, ReflectTypeFlags};
+
+use crate::error::{RenderError, RenderResult};
+use crate::object_tracker::{self, ObjectKind};
+use crate::pipeline_cache::{self, ShaderRecord};
+use crate::shader_tweaks;
 
 
 pub trait SpecializationConstants {
@@ -62,6 +66,42 @@ fn get_descriptor_type(reflected_type: ReflectDescriptorType) -> Option<vk::Desc
     None
 }
 
+// 32-bit scalar type and component count of a push constant member. Matrices, arrays, structs are Other
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MemberType {
+    Float(u32),
+    Int(u32),
+    UInt(u32),
+    Other,
+}
+
+fn get_member_type(member: &ReflectBlockVariable) -> MemberType {
+    let flags = member.type_description.as_ref().map_or(ReflectTypeFlags::UNDEFINED, |desc| desc.type_flags);
+    if flags.intersects(ReflectTypeFlags::MATRIX | ReflectTypeFlags::ARRAY | ReflectTypeFlags::STRUCT) || member.numeric.scalar.width != 32 {
+        return MemberType::Other;
+    }
+
+    let components = if flags.contains(ReflectTypeFlags::VECTOR) { member.numeric.vector.component_count } else { 1 };
+    if flags.contains(ReflectTypeFlags::FLOAT) {
+        MemberType::Float(components)
+    } else if flags.contains(ReflectTypeFlags::INT) && member.numeric.scalar.signedness != 0 {
+        MemberType::Int(components)
+    } else if flags.contains(ReflectTypeFlags::INT) {
+        MemberType::UInt(components)
+    } else {
+        MemberType::Other
+    }
+}
+
+// Member of a push constant block, `offset` is from the start of the push constants, not of the stage range
+#[derive(Clone, Debug)]
+pub struct PushConstantMember {
+    pub name: String,
+    pub offset: u32,
+    pub size: u32,
+    pub member_type: MemberType,
+}
+
 pub struct ConstantsBuilder {
     cur_constant: u32,
     cur_offset: u32,
@@ -96,14 +136,31 @@ impl ConstantsBuilder {
 
         self
     }
+
+    // Constants of a recorded pipeline, entries are (constant id, offset, size)
+    pub(crate) fn from_raw(entries: &[[u32; 3]], data: Vec<u8>) -> ConstantsBuilder {
+        ConstantsBuilder {
+            cur_constant: entries.len() as u32,
+            cur_offset: data.len() as u32,
+            data,
+            entry_map: entries.iter()
+                .map(|&[constant_id, offset, size]| vk::SpecializationMapEntry { constant_id, offset, size: size as usize })
+                .collect(),
+        }
+    }
 }
 
 pub struct Shader {
     device: ash::Device,
     shader_module: vk::ShaderModule,
+    // path or bundle name, with the code hash it identifies the shader in pipeline profiles
+    name: String,
+    code_hash: u64,
+    dynamic_uniform_buffers: Vec<(u32, u32)>,
 
     // descriptor_sets[set][binding] = DescriptorSetLayoutBinding
     descriptor_sets: HashMap<u32, HashMap<u32, DescriptorSetLayoutBinding>>,
+    arrayed_images: HashSet<(u32, u32)>,
     entry_point_name: CString,
 
     stage_flags: vk::ShaderStageFlags,
@@ -111,84 +168,142 @@ pub struct Shader {
     constants: Option<ConstantsBuilder>,
     spec_info: Option<vk::SpecializationInfo>,
     pub push_constants_range: vk::PushConstantRange,
+    pub push_constant_members: Vec<PushConstantMember>,
 }
 
-impl Shader {
-    pub fn load(device: &ash::Device, path: &str) -> Shader {
-        let spv_file = File::open(path)
-            .expect(&format!("Failed to find spv file at {:?}", path));
-
-        let code: Vec<u8> = spv_file.bytes().map(
-            |byte| byte.unwrap()
-        ).collect();
+// Stage, descriptor bindings and push constants of SPIR-V code
+pub struct ShaderReflection {
+    pub stage_flags: vk::ShaderStageFlags,
+    pub entry_point_name: String,
+    // descriptor_sets[set][binding] = DescriptorSetLayoutBinding
+    pub descriptor_sets: HashMap<u32, HashMap<u32, DescriptorSetLayoutBinding>>,
+    // (set, binding) of layered images: sampler2DArray, samplerCubeArray etc.
+    pub arrayed_images: HashSet<(u32, u32)>,
+    pub push_constants_range: vk::PushConstantRange,
+    // in the order of declaration
+    pub push_constant_members: Vec<PushConstantMember>,
+}
 
-        let module = ShaderModule::load_u8_data(&code).unwrap();
-        let reflected_descriptor_sets = module.enumerate_descriptor_sets(None).unwrap();
-        let shader_stage_flags = get_shader_stage_flags(module.get_shader_stage());
+impl ShaderReflection {
+    pub fn new(code: &[u8]) -> Result<ShaderReflection, String> {
+        let module = ShaderModule::load_u8_data(code)?;
+        let reflected_descriptor_sets = module.enumerate_descriptor_sets(None)?;
+        let stage_flags = get_shader_stage_flags(module.get_shader_stage());
 
         let mut sets = HashMap::<u32, HashMap<u32, DescriptorSetLayoutBinding>>::new();
+        let mut arrayed_images = HashSet::new();
         for ref_set in reflected_descriptor_sets.iter() {
-            if !sets.contains_key(&ref_set.set) {
-                sets.insert(ref_set.set, HashMap::<u32, vk::DescriptorSetLayoutBinding>::new());
-            }
+            let layout_bindings = sets.entry(ref_set.set).or_default();
 
-            let layout_bindings = sets.get_mut(&ref_set.set).unwrap();
             for ref_binding in ref_set.bindings.iter() {
                 if layout_bindings.contains_key(&ref_binding.binding) {
-                    panic!("Descriptor set {} already contains binding {}", ref_set.set,
-                           ref_binding.binding);
+                    return Err(format!("Descriptor set {} already contains binding {}", ref_set.set, ref_binding.binding));
                 }
 
+                let descriptor_type = get_descriptor_type(ref_binding.descriptor_type)
+                    .ok_or_else(|| format!("Unsupported descriptor type {:?} (set = {}, binding = {})",
+                                           ref_binding.descriptor_type, ref_set.set, ref_binding.binding))?;
+
                 layout_bindings.insert(
                     ref_binding.binding,
                     DescriptorSetLayoutBinding {
                         binding: ref_binding.binding,
-                        descriptor_type: get_descriptor_type(ref_binding.descriptor_type).unwrap(),
+                        descriptor_type,
                         descriptor_count: ref_binding.count,
-                        stage_flags: shader_stage_flags,
+                        stage_flags,
                         p_immutable_samplers: ptr::null(),
                     },
                 );
+
+                if ref_binding.image.arrayed != 0 {
+                    arrayed_images.insert((ref_set.set, ref_binding.binding));
+                }
             }
         }
 
+        let mut push_constants_range = vk::PushConstantRange {
+            stage_flags,
+            offset: 0,
+            size: 0
+        };
+
+        // members may start past 0 with layout(offset = N), when another stage uses the bytes before them
+        let mut push_constant_members = vec![];
+        for var in module.enumerate_push_constant_blocks(None)?.iter() {
+            let start = var.members.iter().map(|member| member.offset).min().unwrap_or(var.offset);
+            let end = var.members.iter().map(|member| member.offset + member.size).max().unwrap_or(var.offset + var.size);
+            push_constants_range.offset = start;
+            push_constants_range.size = end - start;
+
+            push_constant_members.extend(var.members.iter().map(|member| PushConstantMember {
+                name: member.name.clone(),
+                offset: member.offset,
+                size: member.size,
+                member_type: get_member_type(member),
+            }));
+        }
+
+        Ok(ShaderReflection {
+            stage_flags,
+            entry_point_name: module.get_entry_point_name(),
+            descriptor_sets: sets,
+            arrayed_images,
+            push_constants_range,
+            push_constant_members,
+        })
+    }
+}
+
+impl Shader {
+    pub fn load(device: &ash::Device, path: &str) -> RenderResult<Shader> {
+        let code = fs::read(path)
+            .map_err(|source| RenderError::Io { path: path.to_string(), source })?;
+
+        Self::from_spv(device, &code, path)
+    }
+
+    // SPIR-V code already in memory, e.g. from a ShaderBundle. `name` is for error messages and
+    // pipeline profiles, warmup loads shaders by it, so it should be a path when the shader comes from a file
+    pub fn from_spv(device: &ash::Device, code: &[u8], name: &str) -> RenderResult<Shader> {
+        let reflection = ShaderReflection::new(code)
+            .map_err(|message| RenderError::ShaderReflection { name: name.to_string(), message })?;
+
+        // create info wants u32 aligned code
+        let words: Vec<u32> = code.chunks_exact(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+
         let shader_module_create_info = vk::ShaderModuleCreateInfo {
             s_type: vk::StructureType::SHADER_MODULE_CREATE_INFO,
             p_next: ptr::null(),
             flags: vk::ShaderModuleCreateFlags::empty(),
-            code_size: code.len(),
-            p_code: code.as_ptr() as *const u32,
+            code_size: words.len() * 4,
+            p_code: words.as_ptr(),
         };
 
         let shader_module = unsafe {
             device
                 .create_shader_module(&shader_module_create_info, None)
-                .expect("Failed to create Shader Module!")
-        };
-
-        let mut push_constants_range = vk::PushConstantRange {
-            stage_flags: shader_stage_flags,
-            offset: 0,
-            size: 0
+                .map_err(RenderError::vulkan("vkCreateShaderModule"))?
         };
+        object_tracker::created(ObjectKind::ShaderModule);
+        shader_tweaks::register(name, &reflection.push_constant_members);
 
-        for block in module.enumerate_push_constant_blocks(None) {
-            for var in block.iter() {
-                push_constants_range.offset = var.offset.min(push_constants_range.offset);
-                push_constants_range.size += var.size;
-            }
-        }
-
-        Shader {
+        Ok(Shader {
             shader_module,
-            descriptor_sets: sets,
-            entry_point_name: CString::new(module.get_entry_point_name()).unwrap(),
-            stage_flags: shader_stage_flags,
+            name: name.to_string(),
+            code_hash: pipeline_cache::code_hash(code),
+            dynamic_uniform_buffers: vec![],
+            descriptor_sets: reflection.descriptor_sets,
+            arrayed_images: reflection.arrayed_images,
+            entry_point_name: CString::new(reflection.entry_point_name).unwrap(),
+            stage_flags: reflection.stage_flags,
             device: device.clone(),
             constants: None,
             spec_info: None,
-            push_constants_range,
-        }
+            push_constants_range: reflection.push_constants_range,
+            push_constant_members: reflection.push_constant_members,
+        })
     }
 
     pub fn specialize(mut self, constants: ConstantsBuilder) -> Shader{
@@ -207,6 +322,45 @@ impl Shader {
         self
     }
 
+    // GLSL can't declare dynamic uniform buffer, so reflected UNIFORM_BUFFER binding is switched to
+    // UNIFORM_BUFFER_DYNAMIC here, before the descriptor set layout is created
+    pub fn dynamic_uniform_buffer(mut self, set: u32, binding: u32) -> Shader {
+        let desc = self.descriptor_sets.get_mut(&set)
+            .and_then(|bindings| bindings.get_mut(&binding))
+            .unwrap_or_else(|| panic!("Shader doesn't contain descriptor (set = {}, binding = {})", set, binding));
+
+        if desc.descriptor_type != vk::DescriptorType::UNIFORM_BUFFER {
+            panic!("Descriptor (set = {}, binding = {}) is {:?}, expected uniform buffer", set, binding, desc.descriptor_type);
+        }
+        desc.descriptor_type = vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC;
+        self.dynamic_uniform_buffers.push((set, binding));
+
+        self
+    }
+
+    // Path or bundle name the shader was loaded from
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn record(&self) -> ShaderRecord {
+        let (constants, constants_data) = match &self.constants {
+            Some(constants) => (
+                constants.entry_map.iter().map(|entry| [entry.constant_id, entry.offset, entry.size as u32]).collect(),
+                constants.data.clone(),
+            ),
+            None => (vec![], vec![]),
+        };
+
+        ShaderRecord {
+            path: self.name.clone(),
+            code_hash: self.code_hash,
+            constants,
+            constants_data,
+            dynamic_uniform_buffers: self.dynamic_uniform_buffers.clone(),
+        }
+    }
+
     pub fn stage(&self) -> vk::PipelineShaderStageCreateInfo {
         if self.constants.is_none() {
             return vk::PipelineShaderStageCreateInfo {
@@ -236,23 +390,24 @@ impl Drop for Shader {
     fn drop(&mut self) {
         unsafe {
             self.device.destroy_shader_module(self.shader_module, None);
+            object_tracker::destroyed(ObjectKind::ShaderModule);
         }
     }
 }
 
 
-// mutual exclusive merge bindings of sets
-fn _merge_layout_bindings(shaders: Vec<&Shader>) -> Vec<Vec<DescriptorSetLayoutBinding>> {
+// mutual exclusive merge bindings of sets, set numbers must go from 0 without gaps
+fn merge_layout_bindings(shader_sets: Vec<&HashMap<u32, HashMap<u32, DescriptorSetLayoutBinding>>>) -> Result<Vec<Vec<DescriptorSetLayoutBinding>>, String> {
     let mut total_sets = HashMap::<u32, HashMap<u32, DescriptorSetLayoutBinding>>::new();
 
-    for shader in shaders {
-        for (&set, shader_bindings) in shader.descriptor_sets.iter() {
+    for sets in shader_sets {
+        for (&set, shader_bindings) in sets.iter() {
             let target_bindings = total_sets.entry(set)
                 .or_insert(HashMap::new());
 
             for (_, &shader_binding) in shader_bindings.iter() {
                 if target_bindings.contains_key(&shader_binding.binding) {
-                    panic!("Descriptor sets merge failed: binding {} in descriptor set {} already exists", set, shader_binding.binding);
+                    return Err(format!("binding {} in descriptor set {} already exists", shader_binding.binding, set));
                 }
 
                 target_bindings.insert(shader_binding.binding, shader_binding);
@@ -265,6 +420,13 @@ fn _merge_layout_bindings(shaders: Vec<&Shader>) -> Vec<Vec<DescriptorSetLayoutB
     let mut sorted_sets: Vec<_> = total_sets.into_iter().collect();
     sorted_sets.sort_by(|x, y| x.0.cmp(&y.0));
 
+    // layouts are bound by index, so it has to match the set number
+    for (idx, (set, _)) in sorted_sets.iter().enumerate() {
+        if *set != idx as u32 {
+            return Err(format!("descriptor set {} is missing, sets must be numbered from 0 without gaps", idx));
+        }
+    }
+
     // convert hashmap to vector
     let mut ret = Vec::<Vec<DescriptorSetLayoutBinding>>::new();
     for (_set, bindings) in sorted_sets {
@@ -274,21 +436,45 @@ fn _merge_layout_bindings(shaders: Vec<&Shader>) -> Vec<Vec<DescriptorSetLayoutB
         ret.push(ret_bindings);
     }
 
-    ret
+    Ok(ret)
+}
+
+// Checks that stages of one pipeline give a valid descriptor layout, as create_descriptor_set_layout builds it
+pub fn validate_layout(shaders: &[&ShaderReflection]) -> Result<(), String> {
+    merge_layout_bindings(shaders.iter().map(|shader| &shader.descriptor_sets).collect())
+        .map(|_| ())
 }
 
 pub struct DescriptorSetLayout {
     pub layout: vk::DescriptorSetLayout,
+    pub(super) set: u32,
     pub(super) binding_desc: Vec<vk::DescriptorSetLayoutBinding>,
+    // names of the shaders declaring each binding, for descriptor set validation errors
+    pub(super) binding_shaders: Vec<String>,
+    // bindings of layered images, they need views of an array type
+    pub(super) binding_arrayed: Vec<bool>,
 }
 
 // Merge descriptor information from shaders into general list of descriptor set layout
 // (set = 0, binding = 0) + (set = 1, binding = 1) = Vec<vk::DescriptorSetLayout>.len() == 2;
-pub fn create_descriptor_set_layout(device: &ash::Device, shaders: Vec<&Shader>) -> Vec<DescriptorSetLayout> {
-    let total_sets = _merge_layout_bindings(shaders);
+pub fn create_descriptor_set_layout(device: &ash::Device, shaders: Vec<&Shader>) -> RenderResult<Vec<DescriptorSetLayout>> {
+    let total_sets = merge_layout_bindings(shaders.iter().map(|shader| &shader.descriptor_sets).collect())
+        .map_err(|err| RenderError::InvalidPipeline(format!("descriptor sets merge failed: {}", err)))?;
 
     let mut ret_layouts = Vec::<DescriptorSetLayout>::new();
-    for bindings in total_sets {
+    for (set, bindings) in total_sets.into_iter().enumerate() {
+        let set = set as u32;
+        let binding_shaders = bindings.iter()
+            .map(|binding| shaders.iter()
+                .filter(|shader| shader.descriptor_sets.get(&set).is_some_and(|set_bindings| set_bindings.contains_key(&binding.binding)))
+                .map(|shader| shader.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", "))
+            .collect();
+        let binding_arrayed = bindings.iter()
+            .map(|binding| shaders.iter().any(|shader| shader.arrayed_images.contains(&(set, binding.binding))))
+            .collect();
+
         let descriptor_layout_create_info = vk::DescriptorSetLayoutCreateInfo {
             s_type: vk::StructureType::DESCRIPTOR_SET_LAYOUT_CREATE_INFO,
             p_next: ptr::null(),
@@ -297,19 +483,30 @@ pub fn create_descriptor_set_layout(device: &ash::Device, shaders: Vec<&Shader>)
             p_bindings: bindings.as_ptr(),
         };
 
-        let layout = unsafe {
-            device
-                .create_descriptor_set_layout(&descriptor_layout_create_info, None)
-                .expect("Failed to create Descriptor Set Layout!")
+        let layout = match unsafe { device.create_descriptor_set_layout(&descriptor_layout_create_info, None) } {
+            Ok(layout) => layout,
+            Err(result) => {
+                destroy_descriptor_set_layouts(device, &ret_layouts);
+                return Err(RenderError::Vulkan { call: "vkCreateDescriptorSetLayout", result });
+            }
         };
 
         ret_layouts.push(
             DescriptorSetLayout {
                 layout,
+                set,
                 binding_desc: bindings,
+                binding_shaders,
+                binding_arrayed,
             }
         );
     }
 
-    ret_layouts
+    Ok(ret_layouts)
+}
+
+pub(crate) fn destroy_descriptor_set_layouts(device: &ash::Device, layouts: &[DescriptorSetLayout]) {
+    for descriptor_set_layout in layouts.iter() {
+        unsafe { device.destroy_descriptor_set_layout(descriptor_set_layout.layout, None) };
+    }
 }
@@ -0,0 +1,101 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"SPVB";
+const VERSION: u32 = 1;
+
+// All compiled shaders of the app in one file, written by `cook-shaders`.
+// Little endian: magic, version, entry count, then per entry
+// name length, name (utf-8), code length, SPIR-V code.
+// Names are source paths relative to the shaders directory, e.g. "mesh/mesh.vert"
+#[derive(Default)]
+pub struct ShaderBundle {
+    entries: BTreeMap<String, Vec<u8>>,
+}
+
+impl ShaderBundle {
+    pub fn new() -> ShaderBundle {
+        ShaderBundle {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, name: &str, code: Vec<u8>) {
+        self.entries.insert(name.to_string(), code);
+    }
+
+    // Code for Shader::from_spv
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.entries.get(name).map(Vec::as_slice)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item=&str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&(self.entries.len() as u32).to_le_bytes())?;
+        for (name, code) in self.entries.iter() {
+            writer.write_all(&(name.len() as u32).to_le_bytes())?;
+            writer.write_all(name.as_bytes())?;
+            writer.write_all(&(code.len() as u32).to_le_bytes())?;
+            writer.write_all(code)?;
+        }
+
+        writer.flush()
+    }
+
+    pub fn read(path: &Path) -> io::Result<ShaderBundle> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a shader bundle"));
+        }
+
+        let version = read_u32(&mut reader)?;
+        if version != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unsupported shader bundle version {}", version)));
+        }
+
+        let mut bundle = ShaderBundle::new();
+        for _ in 0..read_u32(&mut reader)? {
+            let name = String::from_utf8(read_bytes(&mut reader)?)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Shader name is not utf-8"))?;
+            let code = read_bytes(&mut reader)?;
+            bundle.entries.insert(name, code);
+        }
+
+        Ok(bundle)
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_bytes(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let len = read_u32(reader)? as usize;
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes)?;
+
+    Ok(bytes)
+}
@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::shader::{MemberType, PushConstantMember};
+
+// Push constant members whose names start with this are tweakable, e.g. `float tweak_heightScale;`
+pub const PREFIX: &str = "tweak_";
+
+struct Tweak {
+    member: PushConstantMember,
+    // raw 32-bit components, both None until the renderer pushes the block for the first time
+    default: Option<[u32; 4]>,
+    value: Option<[u32; 4]>,
+}
+
+// Tweakable members of every loaded shader by shader name. They outlive the shaders, so edits
+// survive pipelines being rebuilt (MSAA or G-buffer changes)
+static SHADERS: Mutex<BTreeMap<String, Vec<Tweak>>> = Mutex::new(BTreeMap::new());
+
+pub fn is_tweakable(member: &PushConstantMember) -> bool {
+    member.name.starts_with(PREFIX) && member.member_type != MemberType::Other
+}
+
+fn component_count(member_type: MemberType) -> usize {
+    match member_type {
+        MemberType::Float(count) | MemberType::Int(count) | MemberType::UInt(count) => count.min(4) as usize,
+        MemberType::Other => 0,
+    }
+}
+
+// Called for every loaded shader, shaders without tweakable members are skipped
+pub(crate) fn register(shader: &str, members: &[PushConstantMember]) {
+    let tweaks: Vec<_> = members.iter()
+        .filter(|member| is_tweakable(member))
+        .map(|member| Tweak { member: member.clone(), default: None, value: None })
+        .collect();
+    if tweaks.is_empty() {
+        return;
+    }
+
+    SHADERS.lock().unwrap().entry(shader.to_string()).or_insert(tweaks);
+}
+
+// `bytes` - push constants of the shader starting at `range_offset`. The first call takes the values
+// the renderer pushes as defaults, later ones overwrite them with the edited values
+pub(crate) fn apply(shader: &str, range_offset: u32, bytes: &mut [u8]) {
+    let mut shaders = SHADERS.lock().unwrap();
+    let tweaks = match shaders.get_mut(shader) {
+        Some(tweaks) => tweaks,
+        None => return,
+    };
+
+    for tweak in tweaks.iter_mut() {
+        let start = (tweak.member.offset - range_offset) as usize;
+        let components = component_count(tweak.member.member_type);
+        let bytes = match bytes.get_mut(start..start + components * 4) {
+            Some(bytes) => bytes,
+            None => continue,
+        };
+
+        match tweak.value {
+            Some(value) => {
+                for (chunk, word) in bytes.chunks_exact_mut(4).zip(value.iter()) {
+                    chunk.copy_from_slice(&word.to_ne_bytes());
+                }
+            }
+            None => {
+                let mut value = [0; 4];
+                for (word, chunk) in value.iter_mut().zip(bytes.chunks_exact(4)) {
+                    *word = u32::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                }
+                tweak.default = Some(value);
+                tweak.value = Some(value);
+            }
+        }
+    }
+}
+
+// A drag value per component of every tweakable member, grouped by shader. Command buffers see
+// the edits when they are recorded, renderers recording once (e.g. terrain) need a rebuild
+#[cfg(feature = "egui")]
+pub fn ui(ui: &mut egui::Ui) {
+    let mut shaders = SHADERS.lock().unwrap();
+    if shaders.is_empty() {
+        ui.label(format!("No shader declares {}* push constants", PREFIX));
+        return;
+    }
+
+    for (shader, tweaks) in shaders.iter_mut() {
+        egui::CollapsingHeader::new(shader.as_str()).default_open(true).show(ui, |ui| {
+            for tweak in tweaks.iter_mut() {
+                let name = &tweak.member.name[PREFIX.len()..];
+                let member_type = tweak.member.member_type;
+                let value = match &mut tweak.value {
+                    Some(value) => value,
+                    None => {
+                        ui.label(format!("{}: not pushed yet", name));
+                        continue;
+                    }
+                };
+
+                ui.horizontal(|ui| {
+                    for word in value[..component_count(member_type)].iter_mut() {
+                        match member_type {
+                            MemberType::Float(_) => {
+                                let mut float = f32::from_bits(*word);
+                                let speed = (float.abs() * 0.01).max(0.001);
+                                ui.add(egui::DragValue::new(&mut float).speed(speed));
+                                *word = float.to_bits();
+                            }
+                            MemberType::Int(_) => {
+                                let mut int = *word as i32;
+                                ui.add(egui::DragValue::new(&mut int));
+                                *word = int as u32;
+                            }
+                            MemberType::UInt(_) => {
+                                ui.add(egui::DragValue::new(word));
+                            }
+                            MemberType::Other => {}
+                        }
+                    }
+                    ui.label(name);
+                });
+            }
+
+            if ui.button("Reset").clicked() {
+                for tweak in tweaks.iter_mut() {
+                    tweak.value = tweak.default;
+                }
+            }
+        });
+    }
+}
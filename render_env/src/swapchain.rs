@@ -6,6 +6,164 @@ use winit::dpi::PhysicalSize;
 
 use crate::{utils};
 use crate::env::RenderEnv;
+use crate::error::{RenderError, RenderResult};
+use crate::object_tracker::{self, ObjectKind};
+use crate::shader::ConstantsBuilder;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum PresentMode {
+    // vsync, always supported
+    Fifo,
+    // vsync without blocking, latest frame is shown
+    #[default]
+    Mailbox,
+    // no vsync, tearing is possible
+    Immediate,
+}
+
+impl PresentMode {
+    pub const ALL: [PresentMode; 3] = [PresentMode::Fifo, PresentMode::Mailbox, PresentMode::Immediate];
+
+    pub fn to_vk(self) -> vk::PresentModeKHR {
+        match self {
+            PresentMode::Fifo => vk::PresentModeKHR::FIFO,
+            PresentMode::Mailbox => vk::PresentModeKHR::MAILBOX,
+            PresentMode::Immediate => vk::PresentModeKHR::IMMEDIATE,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PresentMode::Fifo => "fifo",
+            PresentMode::Mailbox => "mailbox",
+            PresentMode::Immediate => "immediate",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<PresentMode> {
+        PresentMode::ALL.iter().copied().find(|mode| mode.name() == name)
+    }
+}
+
+// Kind of swapchain images the demo asks for. The surface may not support it, then the next one
+// of `fallbacks` is tried, the 8 bit sRGB one is the last resort
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    // 8 bit, sRGB encoded by hardware
+    #[default]
+    Srgb8,
+    // 10 bit, sRGB encoded by the final pass
+    Unorm10,
+    // 10 bit, BT.2020 primaries with the PQ curve
+    Hdr10,
+    // 16 bit float, linear with BT.709 primaries, 1.0 is 80 nits
+    ScRgb,
+}
+
+impl OutputFormat {
+    pub const ALL: [OutputFormat; 4] = [OutputFormat::Srgb8, OutputFormat::Unorm10, OutputFormat::Hdr10, OutputFormat::ScRgb];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputFormat::Srgb8 => "srgb8",
+            OutputFormat::Unorm10 => "unorm10",
+            OutputFormat::Hdr10 => "hdr10",
+            OutputFormat::ScRgb => "scrgb",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<OutputFormat> {
+        OutputFormat::ALL.iter().copied().find(|format| format.name() == name)
+    }
+
+    // Tried in order, starting with the format itself. HDR ones fall back to each other before SDR
+    pub fn fallbacks(self) -> &'static [OutputFormat] {
+        match self {
+            OutputFormat::Srgb8 => &[OutputFormat::Srgb8],
+            OutputFormat::Unorm10 => &[OutputFormat::Unorm10, OutputFormat::Srgb8],
+            OutputFormat::Hdr10 => &[OutputFormat::Hdr10, OutputFormat::ScRgb, OutputFormat::Unorm10, OutputFormat::Srgb8],
+            OutputFormat::ScRgb => &[OutputFormat::ScRgb, OutputFormat::Hdr10, OutputFormat::Unorm10, OutputFormat::Srgb8],
+        }
+    }
+
+    pub fn surface_formats(self) -> &'static [(vk::Format, vk::ColorSpaceKHR)] {
+        match self {
+            OutputFormat::Srgb8 => &[
+                (vk::Format::B8G8R8A8_SRGB, vk::ColorSpaceKHR::SRGB_NONLINEAR),
+                (vk::Format::R8G8B8A8_SRGB, vk::ColorSpaceKHR::SRGB_NONLINEAR),
+            ],
+            OutputFormat::Unorm10 => &[
+                (vk::Format::A2B10G10R10_UNORM_PACK32, vk::ColorSpaceKHR::SRGB_NONLINEAR),
+                (vk::Format::A2R10G10B10_UNORM_PACK32, vk::ColorSpaceKHR::SRGB_NONLINEAR),
+            ],
+            OutputFormat::Hdr10 => &[
+                (vk::Format::A2B10G10R10_UNORM_PACK32, vk::ColorSpaceKHR::HDR10_ST2084_EXT),
+                (vk::Format::A2R10G10B10_UNORM_PACK32, vk::ColorSpaceKHR::HDR10_ST2084_EXT),
+            ],
+            OutputFormat::ScRgb => &[
+                (vk::Format::R16G16B16A16_SFLOAT, vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT),
+            ],
+        }
+    }
+
+    pub fn is_hdr(self) -> bool {
+        self == OutputFormat::Hdr10 || self == OutputFormat::ScRgb
+    }
+}
+
+// How the final pass writes linear scene color into the swapchain image, OUTPUT_TRANSFER of the shaders
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransferFunction {
+    // *_SRGB image, written as is
+    HardwareSrgb = 0,
+    // UNORM image in the sRGB color space
+    Srgb = 1,
+    // HDR10, nits are relative to the paper white
+    Pq = 2,
+    // scRGB, 1.0 is 80 nits
+    Linear = 3,
+}
+
+impl TransferFunction {
+    // Any format the surface reports, not only the ones of OutputFormat
+    pub fn of(surface_format: vk::SurfaceFormatKHR) -> TransferFunction {
+        match surface_format.color_space {
+            vk::ColorSpaceKHR::HDR10_ST2084_EXT => TransferFunction::Pq,
+            vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT => TransferFunction::Linear,
+            _ if utils::format_is_srgb(surface_format.format) => TransferFunction::HardwareSrgb,
+            _ => TransferFunction::Srgb,
+        }
+    }
+}
+
+// Specialization of the shaders writing to the swapchain: the transfer function and
+// the brightness of SDR white in nits on HDR outputs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputEncoding {
+    pub transfer: TransferFunction,
+    pub paper_white_nits: u32,
+}
+
+impl OutputEncoding {
+    // Shaders declare OUTPUT_TRANSFER and PAPER_WHITE_NITS after their own constants
+    pub fn add_constants(self, constants: ConstantsBuilder) -> ConstantsBuilder {
+        constants
+            .add_u32(self.transfer as u32)
+            .add_u32(self.paper_white_nits)
+    }
+}
+
+// Follows swapchain recreation, e.g. render targets and passes sized by the window
+pub trait SwapChainListener {
+    fn swapchain_recreated(&mut self, swapchain: &SwapChain);
+}
+
+// Replaced by recreate(), presentation may still read its images
+struct RetiredSwapChain {
+    swapchain: vk::SwapchainKHR,
+    image_views: Vec<vk::ImageView>,
+    framebuffers: Vec<vk::Framebuffer>,
+}
 
 pub struct SwapChain {
     device: ash::Device,
@@ -15,53 +173,148 @@ pub struct SwapChain {
     pub images: Vec<vk::Image>,
     pub image_views: Vec<vk::ImageView>,
     pub framebuffers: Vec<vk::Framebuffer>,
+    // pass of the framebuffers, recreate() builds them again
+    framebuffer_pass: vk::RenderPass,
     pub format: vk::Format,
+    pub color_space: vk::ColorSpaceKHR,
+    // requested output, `output_format` is the one the surface supports
+    pub requested_output: OutputFormat,
+    pub output_format: Option<OutputFormat>,
+    pub transfer: TransferFunction,
     pub size: vk::Extent2D,
+    min_image_count: u32,
+    // images can be copied from, needed for frame capture
+    pub transfer_src: bool,
+    // requested mode, the swapchain falls back to fifo when it is not supported
+    pub present_mode: PresentMode,
+    pub vk_present_mode: vk::PresentModeKHR,
+    retired: Vec<RetiredSwapChain>,
 }
 
 impl SwapChain {
     pub fn new(
-        env: &RenderEnv, size: PhysicalSize<u32>,
-    ) -> SwapChain
+        env: &RenderEnv, size: PhysicalSize<u32>, present_mode: PresentMode, requested_output: OutputFormat,
+    ) -> RenderResult<SwapChain>
     {
-        let swapchain_support = utils::SwapChainSupportDetail::load(&env);
+        let swapchain_support = utils::SwapChainSupportDetail::load(env);
+
+        let (swapchain_format, output_format) = swapchain_support.format(requested_output);
+        let transfer_src = swapchain_support.capabilities.supported_usage_flags.contains(vk::ImageUsageFlags::TRANSFER_SRC);
+
+        let mut swapchain = SwapChain {
+            device: env.device().clone(),
+            swapchain_api: ash::extensions::khr::Swapchain::new(env.instance(), env.device()),
+            swapchain: vk::SwapchainKHR::null(),
+            format: swapchain_format.format,
+            color_space: swapchain_format.color_space,
+            requested_output,
+            output_format,
+            transfer: TransferFunction::of(swapchain_format),
+            size: swapchain_support.adjust_extent(size),
+            min_image_count: swapchain_support.get_image_count(),
+            transfer_src,
+            present_mode,
+            vk_present_mode: swapchain_support.present_mode(present_mode.to_vk()),
+            images: vec![],
+            image_views: vec![],
+            framebuffers: vec![],
+            framebuffer_pass: vk::RenderPass::null(),
+            retired: vec![],
+        };
+        swapchain.create_swapchain(env, &swapchain_support)?;
+
+        Ok(swapchain)
+    }
 
-        let swapchain_format = swapchain_support.format();
-        let extent = swapchain_support.adjust_extent(size);
+    // Replaces the swapchain with one of `new_size` without waiting for the device: the old handle is passed
+    // to the driver and retired, destroy_retired() frees it once frames presented from it are finished.
+    // Format, color space and image count stay, unless the surface stopped supporting the format. Present mode
+    // follows `present_mode`. Framebuffers are created again and `listeners` are told about the new swapchain.
+    // Returns true when the format changed: the pass of the framebuffers doesn't match the images anymore,
+    // none are created and the caller has to call create_framebuffers() with a pass of the new format
+    pub fn recreate(&mut self, env: &RenderEnv, new_size: PhysicalSize<u32>, listeners: &mut [&mut dyn SwapChainListener]) -> RenderResult<bool> {
+        let swapchain_support = utils::SwapChainSupportDetail::load(env);
+
+        let format_changed = !swapchain_support.supports_format(self.format, self.color_space);
+        if format_changed {
+            let (swapchain_format, output_format) = swapchain_support.format(self.requested_output);
+            self.format = swapchain_format.format;
+            self.color_space = swapchain_format.color_space;
+            self.output_format = output_format;
+            self.transfer = TransferFunction::of(swapchain_format);
+        }
+        self.size = swapchain_support.adjust_extent(new_size);
+        self.vk_present_mode = swapchain_support.present_mode(self.present_mode.to_vk());
+
+        // on failure the current handles stay in use and are destroyed with the swapchain
+        let retired = self.create_swapchain(env, &swapchain_support)?;
+        self.retired.push(retired);
+
+        if format_changed {
+            self.framebuffer_pass = vk::RenderPass::null();
+        } else if self.framebuffer_pass != vk::RenderPass::null() {
+            let device = self.device.clone();
+            self.create_framebuffers(&device, self.framebuffer_pass);
+        }
+
+        for listener in listeners.iter_mut() {
+            listener.swapchain_recreated(self);
+        }
+
+        Ok(format_changed)
+    }
+
+    // Creates the swapchain from the current settings, the current handle becomes the old one.
+    // Returns the replaced handles, they are left untouched when creation fails
+    fn create_swapchain(&mut self, env: &RenderEnv, swapchain_support: &utils::SwapChainSupportDetail) -> RenderResult<RetiredSwapChain> {
+        // Images are rendered on the graphics queue and presented from the present one, share them if families differ
+        let queue_family_indices = env.queue_family_indices();
+        let (image_sharing_mode, queue_family_indices) = if queue_family_indices.is_shared() {
+            (vk::SharingMode::EXCLUSIVE, vec![])
+        } else {
+            (vk::SharingMode::CONCURRENT, queue_family_indices.unique())
+        };
+
+        let mut image_usage = vk::ImageUsageFlags::COLOR_ATTACHMENT;
+        if self.transfer_src {
+            image_usage |= vk::ImageUsageFlags::TRANSFER_SRC;
+        }
 
-        let queue_family_indices = vec![];
         let swapchain_ci = vk::SwapchainCreateInfoKHR {
             s_type: vk::StructureType::SWAPCHAIN_CREATE_INFO_KHR,
             p_next: ptr::null(),
             flags: vk::SwapchainCreateFlagsKHR::empty(),
             surface: env.surface,
-            min_image_count: swapchain_support.get_image_count(),
-            image_color_space: swapchain_format.color_space,
-            image_format: swapchain_format.format,
-            image_extent: extent,
-            image_usage: vk::ImageUsageFlags::COLOR_ATTACHMENT,
-            image_sharing_mode: vk::SharingMode::EXCLUSIVE,
+            min_image_count: self.min_image_count,
+            image_color_space: self.color_space,
+            image_format: self.format,
+            image_extent: self.size,
+            image_usage,
+            image_sharing_mode,
             p_queue_family_indices: queue_family_indices.as_ptr(),
-            queue_family_index_count: 0,
+            queue_family_index_count: queue_family_indices.len() as u32,
             pre_transform: swapchain_support.capabilities.current_transform,
             composite_alpha: vk::CompositeAlphaFlagsKHR::OPAQUE,
-            present_mode: swapchain_support.present_mode(),
+            present_mode: self.vk_present_mode,
             clipped: vk::TRUE,
-            old_swapchain: vk::SwapchainKHR::null(),
+            old_swapchain: self.swapchain,
             image_array_layers: 1,
         };
 
-        let swapchain_api = ash::extensions::khr::Swapchain::new(env.instance(), env.device());
         let swapchain = unsafe {
-            swapchain_api
+            self.swapchain_api
                 .create_swapchain(&swapchain_ci, None)
-                .expect("Failed to create Swapchain!")
+                .map_err(RenderError::vulkan("vkCreateSwapchainKHR"))?
         };
 
-        let swapchain_images = unsafe {
-            swapchain_api
-                .get_swapchain_images(swapchain)
-                .expect("Failed to get Swapchain Images.")
+        let swapchain_images = match unsafe { self.swapchain_api.get_swapchain_images(swapchain) } {
+            Ok(swapchain_images) => swapchain_images,
+            Err(result) => {
+                unsafe {
+                    self.swapchain_api.destroy_swapchain(swapchain, None);
+                }
+                return Err(RenderError::Vulkan { call: "vkGetSwapchainImagesKHR", result });
+            }
         };
 
         let mut image_views = Vec::new();
@@ -72,7 +325,7 @@ impl SwapChain {
                 flags: Default::default(),
                 image: img,
                 view_type: vk::ImageViewType::TYPE_2D,
-                format: swapchain_format.format,
+                format: self.format,
                 components: Default::default(),
                 subresource_range: vk::ImageSubresourceRange {
                     aspect_mask: vk::ImageAspectFlags::COLOR,
@@ -83,34 +336,60 @@ impl SwapChain {
                 },
             };
 
-            let image_view = unsafe { env.device().create_image_view(&view_ci, None).unwrap() };
+            let image_view = match unsafe { self.device.create_image_view(&view_ci, None) } {
+                Ok(image_view) => image_view,
+                Err(result) => {
+                    unsafe {
+                        Self::destroy_handles(&self.device, &self.swapchain_api, swapchain, &image_views, &[]);
+                    }
+                    return Err(RenderError::Vulkan { call: "vkCreateImageView", result });
+                }
+            };
+            object_tracker::created(ObjectKind::ImageView);
             image_views.push(image_view);
         }
 
-        SwapChain {
-            device: env.device().clone(),
-            swapchain_api,
-            swapchain,
-            format: swapchain_format.format,
-            size: extent,
-            images: swapchain_images,
-            image_views,
-            framebuffers: vec![],
+        self.images = swapchain_images;
+
+        Ok(RetiredSwapChain {
+            swapchain: std::mem::replace(&mut self.swapchain, swapchain),
+            image_views: std::mem::replace(&mut self.image_views, image_views),
+            framebuffers: std::mem::take(&mut self.framebuffers),
+        })
+    }
+
+    // Frees swapchains replaced by recreate(). Frames rendered to their images must be finished
+    pub fn destroy_retired(&mut self) {
+        for retired in self.retired.drain(..) {
+            unsafe {
+                Self::destroy_handles(&self.device, &self.swapchain_api, retired.swapchain, &retired.image_views, &retired.framebuffers);
+            }
         }
     }
 
     pub fn destroy(&mut self) {
+        self.destroy_retired();
         unsafe {
-            for &framebuffer in self.framebuffers.iter() {
-                self.device.destroy_framebuffer(framebuffer, None);
-            }
+            Self::destroy_handles(&self.device, &self.swapchain_api, self.swapchain, &self.image_views, &self.framebuffers);
+        }
+    }
 
-            for &img_view in &self.image_views {
-                self.device.destroy_image_view(img_view, None);
-            }
+    unsafe fn destroy_handles(
+        device: &ash::Device, swapchain_api: &ash::extensions::khr::Swapchain,
+        swapchain: vk::SwapchainKHR, image_views: &[vk::ImageView], framebuffers: &[vk::Framebuffer],
+    )
+    {
+        for &framebuffer in framebuffers.iter() {
+            device.destroy_framebuffer(framebuffer, None);
+            object_tracker::destroyed(ObjectKind::Framebuffer);
+        }
 
-            self.swapchain_api.destroy_swapchain(self.swapchain, None);
+        for &img_view in image_views {
+            device.destroy_image_view(img_view, None);
+            object_tracker::destroyed(ObjectKind::ImageView);
         }
+
+        swapchain_api.destroy_swapchain(swapchain, None);
     }
 
     pub fn create_framebuffers(&mut self, device: &ash::Device, render_pass: vk::RenderPass) {
@@ -138,10 +417,12 @@ impl SwapChain {
                     .create_framebuffer(&framebuffer_create_info, None)
                     .expect("Failed to create Framebuffer!")
             };
+            object_tracker::created(ObjectKind::Framebuffer);
 
             framebuffers.push(framebuffer);
         }
 
         self.framebuffers = framebuffers;
+        self.framebuffer_pass = render_pass;
     }
 }
@@ -0,0 +1,264 @@
+use std::sync::{Arc, Mutex, Weak};
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use crate::env::RenderEnv;
+use crate::memory_tracker::{self, MemoryCategory};
+
+// Passes of a frame an attachment is used in, both ends inclusive. The numbers only order the passes,
+// the renderer decides what they are
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PassRange {
+    pub first: u32,
+    pub last: u32,
+}
+
+impl PassRange {
+    pub fn new(first: u32, last: u32) -> PassRange {
+        assert!(first <= last, "Pass range ends before it starts!");
+        PassRange { first, last }
+    }
+
+    pub fn overlaps(&self, other: &PassRange) -> bool {
+        self.first <= other.last && other.first <= self.last
+    }
+}
+
+// Device memory shared by attachments with disjoint pass ranges, freed with the last of them
+struct MemoryBlock {
+    device: ash::Device,
+    memory: vk::DeviceMemory,
+    size: vk::DeviceSize,
+    memory_type_index: u32,
+    users: Mutex<Vec<Weak<TransientMemory>>>,
+}
+
+impl MemoryBlock {
+    fn live_users(&self) -> Vec<Arc<TransientMemory>> {
+        let mut users = self.users.lock().unwrap();
+        users.retain(|user| user.strong_count() > 0);
+        users.iter().filter_map(Weak::upgrade).collect()
+    }
+}
+
+impl Drop for MemoryBlock {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.free_memory(self.memory, None);
+            memory_tracker::freed(self.memory);
+        }
+    }
+}
+
+// What block selection needs to know about a block: its size, memory type and pass ranges of the users
+struct BlockCandidate {
+    size: vk::DeviceSize,
+    memory_type_index: u32,
+    lifetimes: Vec<PassRange>,
+}
+
+// Index of the smallest block the attachment fits in and shares with no overlapping user, None if
+// a new block has to be allocated
+fn select_block(blocks: &[BlockCandidate], requirements: vk::MemoryRequirements, lifetime: PassRange) -> Option<usize> {
+    blocks.iter()
+        .enumerate()
+        .filter(|(_, block)| {
+            block.size >= requirements.size
+                && requirements.memory_type_bits & (1 << block.memory_type_index) != 0
+                && block.lifetimes.iter().all(|user| !user.overlaps(&lifetime))
+        })
+        .min_by_key(|(_, block)| block.size)
+        .map(|(index, _)| index)
+}
+
+// Memory of one transient attachment, bound at offset 0 of its block
+pub struct TransientMemory {
+    block: Arc<MemoryBlock>,
+    lifetime: PassRange,
+    size: vk::DeviceSize,
+}
+
+impl TransientMemory {
+    pub fn memory(&self) -> vk::DeviceMemory {
+        self.block.memory
+    }
+
+    pub fn lifetime(&self) -> PassRange {
+        self.lifetime
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TransientPoolStats {
+    pub attachments: usize,
+    pub blocks: usize,
+    // sum of the attachment sizes, what separate allocations would take
+    pub requested: vk::DeviceSize,
+    pub allocated: vk::DeviceSize,
+}
+
+// Aliases memory of attachments that are never alive in the same passes of a frame, e.g. the
+// intermediate targets of bloom and post-processing. A block is reused by an attachment when it is
+// large enough and none of its users overlaps the attachment's pass range, the smallest of such blocks
+// is taken. Blocks keep the size of the attachment they were allocated for, so larger attachments
+// should be created first. Content of a transient attachment is undefined at the start of its range:
+// the first pass must clear it (Framebuffer render passes do) and nothing may read it after the range
+pub struct TransientAttachmentPool {
+    blocks: Mutex<Vec<Weak<MemoryBlock>>>,
+}
+
+impl TransientAttachmentPool {
+    pub(crate) fn new() -> TransientAttachmentPool {
+        TransientAttachmentPool {
+            blocks: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn allocate(&self, env: &RenderEnv, requirements: vk::MemoryRequirements, lifetime: PassRange) -> Arc<TransientMemory> {
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.retain(|block| block.strong_count() > 0);
+
+        let live_blocks: Vec<_> = blocks.iter().filter_map(Weak::upgrade).collect();
+        let candidates: Vec<_> = live_blocks.iter()
+            .map(|block| BlockCandidate {
+                size: block.size,
+                memory_type_index: block.memory_type_index,
+                lifetimes: block.live_users().iter().map(|user| user.lifetime).collect(),
+            })
+            .collect();
+
+        let block = match select_block(&candidates, requirements, lifetime) {
+            Some(index) => live_blocks[index].clone(),
+            None => {
+                let block = Arc::new(Self::allocate_block(env, requirements));
+                blocks.push(Arc::downgrade(&block));
+                block
+            }
+        };
+
+        let memory = Arc::new(TransientMemory {
+            block: block.clone(),
+            lifetime,
+            size: requirements.size,
+        });
+        block.users.lock().unwrap().push(Arc::downgrade(&memory));
+
+        memory
+    }
+
+    fn allocate_block(env: &RenderEnv, requirements: vk::MemoryRequirements) -> MemoryBlock {
+        let memory_type_index = env.find_memory_type(requirements.memory_type_bits, vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let allocate_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(requirements.size)
+            .memory_type_index(memory_type_index);
+
+        let memory = unsafe {
+            env.device()
+                .allocate_memory(&allocate_info, None)
+                .expect("Failed to allocate transient attachment memory!")
+        };
+        memory_tracker::allocated(memory, requirements.size, MemoryCategory::RenderTargets);
+
+        MemoryBlock {
+            device: env.device().clone(),
+            memory,
+            size: requirements.size,
+            memory_type_index,
+            users: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn stats(&self) -> TransientPoolStats {
+        let blocks = self.blocks.lock().unwrap();
+        let mut stats = TransientPoolStats::default();
+
+        for block in blocks.iter().filter_map(Weak::upgrade) {
+            let users = block.live_users();
+            stats.blocks += 1;
+            stats.allocated += block.size;
+            stats.attachments += users.len();
+            stats.requested += users.iter().map(|user| user.size).sum::<vk::DeviceSize>();
+        }
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(size: vk::DeviceSize, memory_type_index: u32, lifetimes: &[(u32, u32)]) -> BlockCandidate {
+        BlockCandidate {
+            size,
+            memory_type_index,
+            lifetimes: lifetimes.iter().map(|&(first, last)| PassRange::new(first, last)).collect(),
+        }
+    }
+
+    fn requirements(size: vk::DeviceSize, memory_type_bits: u32) -> vk::MemoryRequirements {
+        vk::MemoryRequirements {
+            size,
+            alignment: 256,
+            memory_type_bits,
+        }
+    }
+
+    #[test]
+    fn pass_ranges_overlap_inclusively() {
+        let range = PassRange::new(1, 3);
+
+        assert!(range.overlaps(&PassRange::new(3, 4)));
+        assert!(range.overlaps(&PassRange::new(0, 1)));
+        assert!(range.overlaps(&PassRange::new(2, 2)));
+        assert!(range.overlaps(&PassRange::new(0, 5)));
+        assert!(!range.overlaps(&PassRange::new(4, 5)));
+        assert!(!PassRange::new(4, 5).overlaps(&range));
+        assert!(!range.overlaps(&PassRange::new(0, 0)));
+    }
+
+    #[test]
+    #[should_panic(expected = "ends before it starts")]
+    fn pass_range_must_not_end_before_start() {
+        PassRange::new(2, 1);
+    }
+
+    #[test]
+    fn disjoint_block_is_reused() {
+        let blocks = [block(1024, 0, &[(0, 1)])];
+
+        assert_eq!(select_block(&blocks, requirements(512, 1), PassRange::new(2, 2)), Some(0));
+        // same size fits too
+        assert_eq!(select_block(&blocks, requirements(1024, 1), PassRange::new(2, 3)), Some(0));
+    }
+
+    #[test]
+    fn overlapping_block_is_not_reused() {
+        let blocks = [block(1024, 0, &[(0, 1), (3, 4)])];
+
+        assert_eq!(select_block(&blocks, requirements(512, 1), PassRange::new(1, 2)), None);
+        assert_eq!(select_block(&blocks, requirements(512, 1), PassRange::new(4, 4)), None);
+        assert_eq!(select_block(&blocks, requirements(512, 1), PassRange::new(2, 2)), Some(0));
+    }
+
+    #[test]
+    fn small_or_incompatible_block_is_not_reused() {
+        let blocks = [block(512, 0, &[]), block(4096, 2, &[])];
+
+        // too large for the first block, the memory type of the second one isn't allowed
+        assert_eq!(select_block(&blocks, requirements(1024, 0b011), PassRange::new(0, 0)), None);
+        assert_eq!(select_block(&blocks, requirements(1024, 0b100), PassRange::new(0, 0)), Some(1));
+        assert_eq!(select_block(&[], requirements(1024, 1), PassRange::new(0, 0)), None);
+    }
+
+    #[test]
+    fn smallest_fitting_block_is_taken() {
+        let blocks = [block(4096, 0, &[(0, 0)]), block(1024, 0, &[(0, 0)]), block(2048, 0, &[(0, 0)]), block(512, 0, &[])];
+
+        // the large block stays free for a large attachment created later
+        assert_eq!(select_block(&blocks, requirements(1000, 1), PassRange::new(1, 1)), Some(1));
+        assert_eq!(select_block(&blocks, requirements(2000, 1), PassRange::new(1, 1)), Some(2));
+        assert_eq!(select_block(&blocks, requirements(4000, 1), PassRange::new(1, 1)), Some(0));
+    }
+}
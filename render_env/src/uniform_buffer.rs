@@ -0,0 +1,199 @@
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::Arc;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+use cgmath::{Matrix4, Point3, Vector2, Vector3, Vector4};
+
+use crate::env::RenderEnv;
+use crate::memory_tracker;
+use crate::object_tracker::{self, ObjectKind};
+use crate::utils::buffer_utils::create_buffer_;
+
+// Rust struct with the std140 layout of a GLSL uniform block. Implement it with std140_struct! only, the macro
+// checks the field offsets at compile time, so a misaligned field fails the build instead of the shader reading garbage
+pub trait Std140 {}
+
+// Field type of a std140 block: scalars, vectors, mat4 and arrays of 16 byte aligned elements.
+// mat3 and arrays of scalars have padded columns and elements in std140, they have no Rust counterpart
+// here, use mat4 and vec4 arrays in such blocks
+pub trait Std140Field {
+    const ALIGN: usize;
+    // std140 rounds the size of nested blocks and array elements up to 16 bytes, a Rust type that isn't
+    // padded to it would put the next field or element too early
+    const PADDED: bool = true;
+}
+
+// Element type of an array in a std140 block, the array stride is rounded up to 16 bytes
+pub trait Std140ArrayElement: Std140Field {}
+
+macro_rules! impl_std140_field {
+    ($align:expr => $($ty:ty),*) => {
+        $(impl Std140Field for $ty {
+            const ALIGN: usize = $align;
+        })*
+    };
+}
+
+impl_std140_field!(4 => f32, i32, u32);
+impl_std140_field!(8 => [f32; 2], [i32; 2], [u32; 2], Vector2<f32>);
+impl_std140_field!(16 => [f32; 3], [i32; 3], [u32; 3], Vector3<f32>, Point3<f32>);
+impl_std140_field!(16 => [f32; 4], [i32; 4], [u32; 4], Vector4<f32>, Matrix4<f32>);
+
+impl Std140ArrayElement for [f32; 4] {}
+impl Std140ArrayElement for [i32; 4] {}
+impl Std140ArrayElement for [u32; 4] {}
+impl Std140ArrayElement for Vector4<f32> {}
+impl Std140ArrayElement for Matrix4<f32> {}
+
+impl<T: Std140ArrayElement, const N: usize> Std140Field for [T; N] {
+    const ALIGN: usize = 16;
+    const PADDED: bool = T::PADDED;
+}
+
+/// Declares a #[repr(C)] struct for a uniform block and fails the build when a field is not at its std140
+/// alignment, e.g. a vec4 after a single float. Nested blocks declared with it can be fields of others
+/// and array elements, their size must be a multiple of 16 then:
+///
+/// ```
+/// ash_render_env::std140_struct! {
+///     struct Light {
+///         color: [f32; 3],
+///         intensity: f32,
+///     }
+/// }
+///
+/// ash_render_env::std140_struct! {
+///     struct Lights {
+///         lights: [Light; 4],
+///         count: u32,
+///     }
+/// }
+/// ```
+///
+/// ```compile_fail
+/// ash_render_env::std140_struct! {
+///     struct Misaligned {
+///         intensity: f32,
+///         color: [f32; 4],
+///     }
+/// }
+/// ```
+///
+/// ```compile_fail
+/// ash_render_env::std140_struct! {
+///     struct Light {
+///         color: [f32; 3],
+///     }
+/// }
+///
+/// ash_render_env::std140_struct! {
+///     struct Lights {
+///         lights: [Light; 4],
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! std140_struct {
+    ($(#[$attr:meta])* $vis:vis struct $name:ident {
+        $($(#[$field_attr:meta])* $field_vis:vis $field:ident: $ty:ty),* $(,)?
+    }) => {
+        $(#[$attr])*
+        #[repr(C)]
+        $vis struct $name {
+            $($(#[$field_attr])* $field_vis $field: $ty),*
+        }
+
+        impl $crate::uniform_buffer::Std140 for $name {}
+
+        impl $crate::uniform_buffer::Std140Field for $name {
+            const ALIGN: usize = 16;
+            const PADDED: bool = ::std::mem::size_of::<$name>() % 16 == 0;
+        }
+
+        impl $crate::uniform_buffer::Std140ArrayElement for $name {}
+
+        const _: () = {
+            $(assert!(
+                ::std::mem::offset_of!($name, $field) % <$ty as $crate::uniform_buffer::Std140Field>::ALIGN == 0,
+                concat!("std140: ", stringify!($name), "::", stringify!($field), " is not aligned"),
+            );
+            assert!(
+                <$ty as $crate::uniform_buffer::Std140Field>::PADDED,
+                concat!("std140: size of ", stringify!($name), "::", stringify!($field), " is not a multiple of 16"),
+            );)*
+        };
+    };
+}
+
+// Host visible buffer of a single uniform block
+pub struct UniformBuffer<T: Std140> {
+    pub buffer: vk::Buffer,
+    buffer_memory: vk::DeviceMemory,
+    size: u64,
+    device: ash::Device,
+
+    phantom: PhantomData<T>,
+}
+
+impl<T: Std140> Drop for UniformBuffer<T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_buffer(self.buffer, None);
+            object_tracker::destroyed(ObjectKind::Buffer);
+            self.device.free_memory(self.buffer_memory, None);
+            memory_tracker::freed(self.buffer_memory);
+        }
+    }
+}
+
+impl<T: Std140> UniformBuffer<T> {
+    pub fn new(env: Arc<RenderEnv>) -> UniformBuffer<T> {
+        let buffer_size = std::mem::size_of::<T>();
+
+        let (buffer, buffer_memory, size) = create_buffer_(
+            env.device(),
+            buffer_size as u64,
+            vk::BufferUsageFlags::UNIFORM_BUFFER,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            &env.mem_properties,
+        );
+
+        UniformBuffer {
+            buffer,
+            buffer_memory,
+            size,
+            device: env.device().clone(),
+            phantom: PhantomData
+        }
+    }
+
+    pub fn write_data(&self, data: T) {
+        unsafe {
+            let data_ptr =
+                self.device
+                    .map_memory(
+                        self.buffer_memory,
+                        0,
+                        self.size,
+                        vk::MemoryMapFlags::empty(),
+                    )
+                    .expect("Failed to Map Memory") as *mut T;
+
+            data_ptr.copy_from_nonoverlapping(&data, 1);
+
+            let range = vk::MappedMemoryRange {
+                s_type: vk::StructureType::MAPPED_MEMORY_RANGE,
+                p_next: ptr::null(),
+                memory: self.buffer_memory,
+                offset: 0,
+                size: vk::WHOLE_SIZE,
+            };
+            self.device.flush_mapped_memory_ranges(&[range])
+                .expect("Failed to flush mapped memory!");
+            self.device
+                .unmap_memory(self.buffer_memory);
+        }
+    }
+}
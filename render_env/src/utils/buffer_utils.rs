@@ -3,6 +3,11 @@ use std::ptr;
 use ash::version::{DeviceV1_0, InstanceV1_0};
 use ash::vk;
 
+use crate::barrier::{self, AccessPattern};
+use crate::memory_tracker::{self, MemoryCategory};
+use crate::object_tracker::{self, ObjectKind};
+use crate::queue_guard::QueueGuard;
+
 pub(crate) fn find_memory_type(
     type_filter: u32,
     required_properties: vk::MemoryPropertyFlags,
@@ -43,6 +48,7 @@ pub fn create_buffer_(
             .create_buffer(&buffer_create_info, None)
             .expect("Failed to create Buffer")
     };
+    object_tracker::created(ObjectKind::Buffer);
 
     let mem_requirements = unsafe { device.get_buffer_memory_requirements(buffer) };
     let memory_type = find_memory_type(
@@ -63,6 +69,12 @@ pub fn create_buffer_(
             .allocate_memory(&allocate_info, None)
             .expect("Failed to allocate buffer memory!")
     };
+    let category = if usage.intersects(vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::INDEX_BUFFER) {
+        MemoryCategory::Meshes
+    } else {
+        MemoryCategory::Buffers
+    };
+    memory_tracker::allocated(buffer_memory, mem_requirements.size, category);
 
     unsafe {
         device
@@ -123,7 +135,7 @@ pub fn begin_single_time_command(
 pub fn end_single_time_command(
     device: &ash::Device,
     command_pool: vk::CommandPool,
-    submit_queue: vk::Queue,
+    submit_queue: &QueueGuard,
     command_buffer: vk::CommandBuffer,
 ) {
     unsafe {
@@ -146,13 +158,11 @@ pub fn end_single_time_command(
         p_signal_semaphores: ptr::null(),
     }];
 
+    submit_queue
+        .submit_and_wait(device, &submit_infos)
+        .expect("Failed to Queue Submit!");
+
     unsafe {
-        device
-            .queue_submit(submit_queue, &submit_infos, vk::Fence::null())
-            .expect("Failed to Queue Submit!");
-        device
-            .queue_wait_idle(submit_queue)
-            .expect("Failed to wait Queue idle!");
         device.free_command_buffers(command_pool, &buffers_to_submit);
     }
 }
@@ -160,11 +170,12 @@ pub fn end_single_time_command(
 
 fn copy_buffer(
     device: &ash::Device,
-    submit_queue: vk::Queue,
+    submit_queue: &QueueGuard,
     command_pool: vk::CommandPool,
     src_buffer: vk::Buffer,
     dst_buffer: vk::Buffer,
     size: vk::DeviceSize,
+    dst_access: AccessPattern,
 ) {
     let command_buffer = begin_single_time_command(device, command_pool);
 
@@ -178,15 +189,28 @@ fn copy_buffer(
         device.cmd_copy_buffer(command_buffer, src_buffer, dst_buffer, &copy_regions);
     }
 
+    barrier::cmd_buffer_barrier(device, command_buffer, dst_buffer, AccessPattern::TransferWrite, dst_access);
+
     end_single_time_command(device, command_pool, submit_queue, command_buffer);
 }
 
+// How the uploaded data is read after the copy
+fn data_buffer_access(usage: vk::BufferUsageFlags) -> AccessPattern {
+    if usage.intersects(vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::INDEX_BUFFER) {
+        AccessPattern::VertexRead
+    } else if usage.contains(vk::BufferUsageFlags::UNIFORM_BUFFER) {
+        AccessPattern::UniformRead
+    } else {
+        AccessPattern::TransferRead
+    }
+}
+
 pub fn create_data_buffer<T: Sized>(
     instance: &ash::Instance,
     physical_device: vk::PhysicalDevice,
     device: ash::Device,
     command_pool: vk::CommandPool,
-    submit_queue: vk::Queue,
+    submit_queue: &QueueGuard,
     usage: vk::BufferUsageFlags,
     data: Vec<T>) -> (vk::Buffer, vk::DeviceMemory)
 {
@@ -231,11 +255,14 @@ pub fn create_data_buffer<T: Sized>(
         staging_buffer,
         vertex_buffer,
         data_size,
+        data_buffer_access(usage),
     );
 
     unsafe {
         device.destroy_buffer(staging_buffer, None);
+        object_tracker::destroyed(ObjectKind::Buffer);
         device.free_memory(staging_buffer_memory, None);
+        memory_tracker::freed(staging_buffer_memory);
     }
 
     (vertex_buffer, vertex_buffer_memory)
@@ -0,0 +1,237 @@
+use std::fs;
+use std::path::Path;
+
+use ash::version::InstanceV1_0;
+use ash::vk;
+
+use crate::env::RenderEnv;
+
+const IDENTIFIER: [u8; 12] = [0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A];
+// identifier, header and index, the level index follows
+const LEVEL_INDEX_OFFSET: usize = 80;
+// byte offset, byte length and uncompressed byte length
+const LEVEL_INDEX_ENTRY: usize = 24;
+
+// Mip levels of a KTX2 file with block compressed (BCn, ASTC) data, uploaded as is.
+// Basis Universal and supercompressed files need transcoding and are rejected
+pub struct Ktx2Data {
+    pub format: vk::Format,
+    pub width: u32,
+    pub height: u32,
+    // largest first, each one tightly packed
+    pub levels: Vec<Vec<u8>>,
+}
+
+impl Ktx2Data {
+    pub fn load(path: &Path) -> Result<Ktx2Data, String> {
+        let bytes = fs::read(path)
+            .map_err(|err| format!("Failed to read texture {}: {}", path.display(), err))?;
+
+        Ktx2Data::parse(&bytes)
+            .map_err(|err| format!("Failed to load texture {}: {}", path.display(), err))
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Ktx2Data, String> {
+        if bytes.len() < LEVEL_INDEX_OFFSET || bytes[..12] != IDENTIFIER {
+            return Err("not a KTX2 file".to_string());
+        }
+
+        let u32_at = |offset: usize| u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+        let u64_at = |offset: usize| u32_at(offset) as u64 | (u32_at(offset + 4) as u64) << 32;
+
+        let format = vk::Format::from_raw(u32_at(12) as i32);
+        let width = u32_at(20);
+        let height = u32_at(24);
+        let depth = u32_at(28);
+        let layer_count = u32_at(32);
+        let face_count = u32_at(36);
+        // 0 asks the loader to generate mips, which isn't possible for compressed data
+        let level_count = u32_at(40).max(1) as usize;
+        let supercompression = u32_at(44);
+
+        if format == vk::Format::UNDEFINED {
+            return Err("Basis Universal data needs transcoding, only BCn/ASTC formats are supported".to_string());
+        }
+        if supercompression != 0 {
+            return Err(format!("supercompression scheme {} is not supported", supercompression));
+        }
+        if !is_block_compressed(format) {
+            return Err(format!("{:?} is not a block compressed format", format));
+        }
+        if depth > 0 || layer_count > 1 || face_count != 1 || width == 0 || height == 0 {
+            return Err("only 2D textures are supported".to_string());
+        }
+        let max_levels = 32 - width.max(height).leading_zeros() as usize;
+        if level_count > max_levels {
+            return Err(format!("{} levels, a {}x{} texture has at most {}", level_count, width, height, max_levels));
+        }
+        if bytes.len() < LEVEL_INDEX_OFFSET + level_count * LEVEL_INDEX_ENTRY {
+            return Err("truncated level index".to_string());
+        }
+
+        let (block_width, block_height, block_bytes) = block_layout(format);
+        let levels = (0..level_count)
+            .map(|level| {
+                let entry = LEVEL_INDEX_OFFSET + level * LEVEL_INDEX_ENTRY;
+                let offset = u64_at(entry) as usize;
+                let length = u64_at(entry + 8) as usize;
+
+                // the upload packs levels back to back, whole blocks keep every level block aligned
+                let level_width = (width >> level).max(1);
+                let level_height = (height >> level).max(1);
+                let expected = level_width.div_ceil(block_width) as usize * level_height.div_ceil(block_height) as usize * block_bytes;
+                if length != expected {
+                    return Err(format!("level {} is {} bytes, {}x{} needs {}", level, length, level_width, level_height, expected));
+                }
+
+                offset.checked_add(length)
+                    .and_then(|end| bytes.get(offset..end))
+                    .map(|data| data.to_vec())
+                    .ok_or_else(|| format!("level {} is out of the file", level))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(Ktx2Data {
+            format,
+            width,
+            height,
+            levels,
+        })
+    }
+}
+
+// BC1..BC7 and ASTC LDR
+fn is_block_compressed(format: vk::Format) -> bool {
+    let raw = format.as_raw();
+    (vk::Format::BC1_RGB_UNORM_BLOCK.as_raw()..=vk::Format::BC7_SRGB_BLOCK.as_raw()).contains(&raw)
+        || (vk::Format::ASTC_4X4_UNORM_BLOCK.as_raw()..=vk::Format::ASTC_12X12_SRGB_BLOCK.as_raw()).contains(&raw)
+}
+
+// Block width, height and bytes of an is_block_compressed() format
+fn block_layout(format: vk::Format) -> (u32, u32, usize) {
+    match format {
+        vk::Format::BC1_RGB_UNORM_BLOCK | vk::Format::BC1_RGB_SRGB_BLOCK |
+        vk::Format::BC1_RGBA_UNORM_BLOCK | vk::Format::BC1_RGBA_SRGB_BLOCK |
+        vk::Format::BC4_UNORM_BLOCK | vk::Format::BC4_SNORM_BLOCK => (4, 4, 8),
+        vk::Format::ASTC_5X4_UNORM_BLOCK | vk::Format::ASTC_5X4_SRGB_BLOCK => (5, 4, 16),
+        vk::Format::ASTC_5X5_UNORM_BLOCK | vk::Format::ASTC_5X5_SRGB_BLOCK => (5, 5, 16),
+        vk::Format::ASTC_6X5_UNORM_BLOCK | vk::Format::ASTC_6X5_SRGB_BLOCK => (6, 5, 16),
+        vk::Format::ASTC_6X6_UNORM_BLOCK | vk::Format::ASTC_6X6_SRGB_BLOCK => (6, 6, 16),
+        vk::Format::ASTC_8X5_UNORM_BLOCK | vk::Format::ASTC_8X5_SRGB_BLOCK => (8, 5, 16),
+        vk::Format::ASTC_8X6_UNORM_BLOCK | vk::Format::ASTC_8X6_SRGB_BLOCK => (8, 6, 16),
+        vk::Format::ASTC_8X8_UNORM_BLOCK | vk::Format::ASTC_8X8_SRGB_BLOCK => (8, 8, 16),
+        vk::Format::ASTC_10X5_UNORM_BLOCK | vk::Format::ASTC_10X5_SRGB_BLOCK => (10, 5, 16),
+        vk::Format::ASTC_10X6_UNORM_BLOCK | vk::Format::ASTC_10X6_SRGB_BLOCK => (10, 6, 16),
+        vk::Format::ASTC_10X8_UNORM_BLOCK | vk::Format::ASTC_10X8_SRGB_BLOCK => (10, 8, 16),
+        vk::Format::ASTC_10X10_UNORM_BLOCK | vk::Format::ASTC_10X10_SRGB_BLOCK => (10, 10, 16),
+        vk::Format::ASTC_12X10_UNORM_BLOCK | vk::Format::ASTC_12X10_SRGB_BLOCK => (12, 10, 16),
+        vk::Format::ASTC_12X12_UNORM_BLOCK | vk::Format::ASTC_12X12_SRGB_BLOCK => (12, 12, 16),
+        // the rest of BCn and ASTC 4x4
+        _ => (4, 4, 16),
+    }
+}
+
+// Compressed formats the device samples with linear filtering. Queried once, so loader threads can
+// pick between a KTX2 file and the image fallback without the env
+#[derive(Clone, Default)]
+pub struct CompressedFormats {
+    formats: Vec<vk::Format>,
+}
+
+impl CompressedFormats {
+    pub fn query(env: &RenderEnv) -> CompressedFormats {
+        // transfers are implied before Vulkan 1.1, which reports them as TRANSFER_DST
+        let required = vk::FormatFeatureFlags::SAMPLED_IMAGE | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR;
+
+        let formats = (vk::Format::BC1_RGB_UNORM_BLOCK.as_raw()..=vk::Format::ASTC_12X12_SRGB_BLOCK.as_raw())
+            .map(vk::Format::from_raw)
+            .filter(|format| is_block_compressed(*format))
+            .filter(|format| {
+                let properties = unsafe {
+                    env.instance().get_physical_device_format_properties(env.physical_device(), *format)
+                };
+                properties.optimal_tiling_features.contains(required)
+            })
+            .collect();
+
+        CompressedFormats {
+            formats,
+        }
+    }
+
+    pub fn supports(&self, format: vk::Format) -> bool {
+        self.formats.contains(&format)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Header and level index of a 2D texture followed by `levels` back to back
+    fn ktx2(format: vk::Format, width: u32, height: u32, supercompression: u32, levels: &[usize]) -> Vec<u8> {
+        let mut bytes = IDENTIFIER.to_vec();
+        for value in [format.as_raw() as u32, 1, width, height, 0, 0, 1, levels.len() as u32, supercompression] {
+            bytes.extend(value.to_le_bytes());
+        }
+        // data format descriptor and key/value offsets, unused by the parser
+        bytes.resize(LEVEL_INDEX_OFFSET, 0);
+
+        let mut offset = LEVEL_INDEX_OFFSET + levels.len() * LEVEL_INDEX_ENTRY;
+        for &length in levels {
+            for value in [offset as u64, length as u64, length as u64] {
+                bytes.extend(value.to_le_bytes());
+            }
+            offset += length;
+        }
+        for (level, &length) in levels.iter().enumerate() {
+            bytes.extend(std::iter::repeat(level as u8).take(length));
+        }
+
+        bytes
+    }
+
+    #[test]
+    fn parses_all_levels() {
+        // 12x6 BC1: 3x2 blocks, 2x1, 1x1 and 1x1
+        let data = Ktx2Data::parse(&ktx2(vk::Format::BC1_RGBA_UNORM_BLOCK, 12, 6, 0, &[48, 16, 8, 8])).unwrap();
+
+        assert_eq!(data.format, vk::Format::BC1_RGBA_UNORM_BLOCK);
+        assert_eq!((data.width, data.height), (12, 6));
+        assert_eq!(data.levels.iter().map(|level| level.len()).collect::<Vec<_>>(), vec![48, 16, 8, 8]);
+        assert!(data.levels[2].iter().all(|&byte| byte == 2));
+    }
+
+    #[test]
+    fn parses_astc_blocks() {
+        // 20x10 ASTC 6x5: 4x2 blocks, then 10x5 - 2x1
+        let data = Ktx2Data::parse(&ktx2(vk::Format::ASTC_6X5_SRGB_BLOCK, 20, 10, 0, &[128, 32])).unwrap();
+        assert_eq!(data.levels.len(), 2);
+    }
+
+    #[test]
+    fn rejects_wrong_level_size() {
+        let mut bytes = ktx2(vk::Format::BC7_UNORM_BLOCK, 8, 8, 0, &[64, 16]);
+        bytes.pop();
+        assert!(Ktx2Data::parse(&bytes).is_err(), "truncated last level");
+
+        assert!(Ktx2Data::parse(&ktx2(vk::Format::BC7_UNORM_BLOCK, 8, 8, 0, &[60, 16])).is_err(), "partial block");
+        assert!(Ktx2Data::parse(&ktx2(vk::Format::BC7_UNORM_BLOCK, 8, 8, 0, &[64, 64])).is_err(), "level of the parent size");
+    }
+
+    #[test]
+    fn rejects_too_many_levels() {
+        // 8x8 has 8x8, 4x4, 2x2 and 1x1 levels
+        assert!(Ktx2Data::parse(&ktx2(vk::Format::BC1_RGB_UNORM_BLOCK, 8, 8, 0, &[32, 8, 8, 8])).is_ok());
+        assert!(Ktx2Data::parse(&ktx2(vk::Format::BC1_RGB_UNORM_BLOCK, 8, 8, 0, &[32, 8, 8, 8, 8])).is_err());
+    }
+
+    #[test]
+    fn rejects_unsupported_data() {
+        assert!(Ktx2Data::parse(&ktx2(vk::Format::R8G8B8A8_UNORM, 4, 4, 0, &[64])).is_err(), "uncompressed");
+        assert!(Ktx2Data::parse(&ktx2(vk::Format::UNDEFINED, 4, 4, 0, &[16])).is_err(), "Basis Universal");
+        assert!(Ktx2Data::parse(&ktx2(vk::Format::BC7_UNORM_BLOCK, 4, 4, 2, &[16])).is_err(), "zstd supercompression");
+        assert!(Ktx2Data::parse(&ktx2(vk::Format::BC7_UNORM_BLOCK, 4, 4, 0, &[16])[..70]).is_err(), "truncated header");
+        assert!(Ktx2Data::parse(b"not a ktx2 file at all, but long enough to pass the header length check of the parser").is_err());
+    }
+}
@@ -1,6 +1,8 @@
 pub mod texture;
 pub mod texture_utils;
+pub mod ktx2;
 pub mod utils;
 pub mod buffer_utils;
+pub mod readback;
 
 pub use utils::*;
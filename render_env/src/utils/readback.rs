@@ -0,0 +1,206 @@
+#[cfg(feature = "image")]
+use std::path::Path;
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use crate::barrier::{self, AccessPattern};
+use crate::env::RenderEnv;
+use crate::memory_tracker;
+use crate::object_tracker::{self, ObjectKind};
+use crate::utils::{buffer_utils, format_has_stencil};
+
+// Copies a single sampled color image into RGBA8 bytes, rows tightly packed.
+// The image must be in SHADER_READ_ONLY_OPTIMAL layout and is returned to it, the call waits for the queue.
+pub fn read_color_image(env: &RenderEnv, image: vk::Image, format: vk::Format, dimensions: [u32; 2]) -> Vec<u8> {
+    let bgra = match format {
+        vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => false,
+        vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => true,
+        _ => panic!("Unsupported readback format {:?}!", format),
+    };
+
+    let device = env.device();
+    let size = (dimensions[0] * dimensions[1] * 4) as vk::DeviceSize;
+    let (buffer, memory) = buffer_utils::create_buffer(
+        device,
+        size,
+        vk::BufferUsageFlags::TRANSFER_DST,
+        vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        &env.mem_properties,
+    );
+
+    let command_buffer = buffer_utils::begin_single_time_command(device, env.command_pool());
+    let range = barrier::color_range(0, 1, 1);
+
+    barrier::cmd_image_barrier(device, command_buffer, image, range, AccessPattern::SampledRead, AccessPattern::TransferRead);
+
+    let region = vk::BufferImageCopy {
+        buffer_offset: 0,
+        buffer_row_length: 0,
+        buffer_image_height: 0,
+        image_subresource: vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        },
+        image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+        image_extent: vk::Extent3D {
+            width: dimensions[0],
+            height: dimensions[1],
+            depth: 1,
+        },
+    };
+
+    unsafe {
+        device.cmd_copy_image_to_buffer(command_buffer, image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL, buffer, &[region]);
+    }
+
+    barrier::cmd_image_barrier(device, command_buffer, image, range, AccessPattern::TransferRead, AccessPattern::SampledRead);
+    barrier::cmd_buffer_barrier(device, command_buffer, buffer, AccessPattern::TransferWrite, AccessPattern::HostRead);
+
+    buffer_utils::end_single_time_command(device, env.command_pool(), env.queue(), command_buffer);
+
+    let mut pixels = vec![0u8; size as usize];
+    unsafe {
+        let data_ptr = device
+            .map_memory(memory, 0, size, vk::MemoryMapFlags::empty())
+            .expect("Failed to Map Memory") as *const u8;
+
+        data_ptr.copy_to_nonoverlapping(pixels.as_mut_ptr(), pixels.len());
+
+        device.unmap_memory(memory);
+        device.destroy_buffer(buffer, None);
+        object_tracker::destroyed(ObjectKind::Buffer);
+        device.free_memory(memory, None);
+        memory_tracker::freed(memory);
+    }
+
+    if bgra {
+        for pixel in pixels.chunks_exact_mut(4) {
+            pixel.swap(0, 2);
+        }
+    }
+
+    pixels
+}
+
+// Copies the depth of one layer of a single sampled depth image into floats, rows tightly packed. UNORM depth
+// is converted to 0..1, stencil is dropped. The image must be in DEPTH_STENCIL_READ_ONLY_OPTIMAL layout
+// and is returned to it, the call waits for the queue.
+pub fn read_depth_image(env: &RenderEnv, image: vk::Image, format: vk::Format, dimensions: [u32; 2], layer: u32) -> Vec<f32> {
+    // texel size in the buffer, D24 is copied into the low bits of 32
+    let texel_size = match format {
+        vk::Format::D16_UNORM | vk::Format::D16_UNORM_S8_UINT => 2,
+        vk::Format::D32_SFLOAT | vk::Format::D32_SFLOAT_S8_UINT
+        | vk::Format::X8_D24_UNORM_PACK32 | vk::Format::D24_UNORM_S8_UINT => 4,
+        _ => panic!("Unsupported depth readback format {:?}!", format),
+    };
+
+    let device = env.device();
+    let texel_count = (dimensions[0] * dimensions[1]) as usize;
+    let size = (texel_count * texel_size) as vk::DeviceSize;
+    let (buffer, memory) = buffer_utils::create_buffer(
+        device,
+        size,
+        vk::BufferUsageFlags::TRANSFER_DST,
+        vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        &env.mem_properties,
+    );
+
+    let command_buffer = buffer_utils::begin_single_time_command(device, env.command_pool());
+    // layouts of depth/stencil images change for both aspects
+    let range = vk::ImageSubresourceRange {
+        aspect_mask: if format_has_stencil(format) {
+            vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+        } else {
+            vk::ImageAspectFlags::DEPTH
+        },
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer: layer,
+        layer_count: 1,
+    };
+
+    barrier::cmd_image_barrier(device, command_buffer, image, range, AccessPattern::DepthSampledRead, AccessPattern::TransferRead);
+
+    let region = vk::BufferImageCopy {
+        buffer_offset: 0,
+        buffer_row_length: 0,
+        buffer_image_height: 0,
+        image_subresource: vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::DEPTH,
+            mip_level: 0,
+            base_array_layer: layer,
+            layer_count: 1,
+        },
+        image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+        image_extent: vk::Extent3D {
+            width: dimensions[0],
+            height: dimensions[1],
+            depth: 1,
+        },
+    };
+
+    unsafe {
+        device.cmd_copy_image_to_buffer(command_buffer, image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL, buffer, &[region]);
+    }
+
+    barrier::cmd_image_barrier(device, command_buffer, image, range, AccessPattern::TransferRead, AccessPattern::DepthSampledRead);
+    barrier::cmd_buffer_barrier(device, command_buffer, buffer, AccessPattern::TransferWrite, AccessPattern::HostRead);
+
+    buffer_utils::end_single_time_command(device, env.command_pool(), env.queue(), command_buffer);
+
+    let mut bytes = vec![0u8; size as usize];
+    unsafe {
+        let data_ptr = device
+            .map_memory(memory, 0, size, vk::MemoryMapFlags::empty())
+            .expect("Failed to Map Memory") as *const u8;
+
+        data_ptr.copy_to_nonoverlapping(bytes.as_mut_ptr(), bytes.len());
+
+        device.unmap_memory(memory);
+        device.destroy_buffer(buffer, None);
+        object_tracker::destroyed(ObjectKind::Buffer);
+        device.free_memory(memory, None);
+        memory_tracker::freed(memory);
+    }
+
+    match format {
+        vk::Format::D16_UNORM | vk::Format::D16_UNORM_S8_UINT => bytes.chunks_exact(2)
+            .map(|texel| u16::from_ne_bytes([texel[0], texel[1]]) as f32 / u16::MAX as f32)
+            .collect(),
+        vk::Format::D32_SFLOAT | vk::Format::D32_SFLOAT_S8_UINT => bytes.chunks_exact(4)
+            .map(|texel| f32::from_ne_bytes([texel[0], texel[1], texel[2], texel[3]]))
+            .collect(),
+        _ => bytes.chunks_exact(4)
+            .map(|texel| (u32::from_ne_bytes([texel[0], texel[1], texel[2], texel[3]]) & 0x00ff_ffff) as f32 / 0x00ff_ffff as f32)
+            .collect(),
+    }
+}
+
+// Gray RGBA8 pixels of `values` stretched over their range, near values are dark.
+// Returns the pixels and the range, infinite and NaN values are left black
+pub fn grayscale_rgba(values: &[f32]) -> (Vec<u8>, [f32; 2]) {
+    let finite = values.iter().copied().filter(|value| value.is_finite());
+    let min = finite.clone().fold(f32::MAX, f32::min);
+    let max = finite.fold(f32::MIN, f32::max);
+    let scale = if max > min { 255.0 / (max - min) } else { 0.0 };
+
+    let mut pixels = Vec::with_capacity(values.len() * 4);
+    for &value in values {
+        let gray = if value.is_finite() { ((value - min) * scale).round() as u8 } else { 0 };
+        pixels.extend_from_slice(&[gray, gray, gray, 255]);
+    }
+
+    (pixels, [min, max])
+}
+
+// Image format is taken from the file extension
+#[cfg(feature = "image")]
+pub fn save_rgba<P: AsRef<Path>>(path: P, pixels: Vec<u8>, dimensions: [u32; 2]) -> image::ImageResult<()> {
+    let image = image::RgbaImage::from_raw(dimensions[0], dimensions[1], pixels)
+        .expect("Pixel data doesn't match image size!");
+
+    image.save(path)
+}
@@ -1,12 +1,75 @@
+#[cfg(feature = "image")]
 use std::path::Path;
 
 use ash::version::DeviceV1_0;
 use ash::vk;
+#[cfg(feature = "image")]
 use image::GenericImageView;
 
-use crate::utils::texture_utils::{create_image_view, create_texture_image, create_texture_sampler, create_texture_sampler2};
+use crate::barrier::{AccessPattern, ImageLayoutState};
+#[cfg(feature = "image")]
+use crate::error::RenderError;
+use crate::error::RenderResult;
+use crate::memory_tracker;
+use crate::object_tracker::{self, ObjectKind};
+use crate::queue_guard::QueueGuard;
+#[cfg(feature = "image")]
+use crate::utils::ktx2::CompressedFormats;
+use crate::utils::ktx2::Ktx2Data;
+use crate::utils::texture_utils::{create_compressed_texture_image, create_image_view, create_texture_image, create_texture_sampler2, destroy_image};
 
 
+// Decoded RGBA8 pixels, can be produced off the render thread and uploaded later
+pub struct ImageData {
+    pub pixels: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ImageData {
+    // Textures are flipped vertically, cube faces are not
+    #[cfg(feature = "image")]
+    pub fn load(image_path: &Path, flip_v: bool) -> Result<ImageData, String> {
+        let mut image_object = image::open(image_path)
+            .map_err(|err| format!("Failed to load image {}: {}", image_path.display(), err))?;
+        if flip_v {
+            image_object = image_object.flipv();
+        }
+
+        let (width, height) = (image_object.width(), image_object.height());
+
+        Ok(ImageData {
+            pixels: image_object.to_rgba8().into_raw(),
+            width,
+            height,
+        })
+    }
+}
+
+// Texture read off the render thread: decoded RGBA8 pixels or compressed levels of a KTX2 file
+pub enum TextureData {
+    Pixels(ImageData),
+    Compressed(Ktx2Data),
+}
+
+impl TextureData {
+    // `<image>.ktx2` next to the image is used instead when the device samples its format, the image
+    // is the fallback. KTX2 data is never flipped, files for flipped textures are stored flipped
+    #[cfg(feature = "image")]
+    pub fn load(image_path: &Path, flip_v: bool, formats: &CompressedFormats) -> Result<TextureData, String> {
+        let ktx2_path = image_path.with_extension("ktx2");
+        if ktx2_path.exists() {
+            match Ktx2Data::load(&ktx2_path) {
+                Ok(data) if formats.supports(data.format) => return Ok(TextureData::Compressed(data)),
+                Ok(data) => println!("{:?} of {} is not supported, loading {}", data.format, ktx2_path.display(), image_path.display()),
+                Err(err) => println!("{}, loading {}", err, image_path.display()),
+            }
+        }
+
+        ImageData::load(image_path, flip_v).map(TextureData::Pixels)
+    }
+}
+
 #[allow(dead_code)]
 pub struct Texture {
     device: ash::Device,
@@ -17,48 +80,112 @@ pub struct Texture {
     pub texture_sampler: vk::Sampler,
     _mip_levels: u32,
     format: vk::Format,
+    pub layout: ImageLayoutState,
 }
 
 impl Texture {
+    #[cfg(feature = "image")]
     pub fn new(
         device: ash::Device,
         command_pool: vk::CommandPool,
-        submit_queue: vk::Queue,
+        submit_queue: &QueueGuard,
         device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
         image_path: &Path,
-    ) -> Texture {
-        let mut image_object = image::open(image_path).unwrap();
-        image_object = image_object.flipv();
-
-        let image_data = match &image_object {
-            image::DynamicImage::ImageLumaA8(_)
-            | image::DynamicImage::ImageBgra8(_)
-            | image::DynamicImage::ImageRgba8(_) => image_object.to_rgba8().into_raw(),
-            _ => image_object.to_rgba8().into_raw(),
-        };
+        max_anisotropy: f32,
+    ) -> RenderResult<Texture> {
+        Texture::with_format(device, command_pool, submit_queue, device_memory_properties, image_path, vk::Format::R8G8B8A8_SRGB, max_anisotropy)
+    }
 
-        let (image_width, image_height) = (image_object.width(), image_object.height());
+    // Data textures (normal maps, masks) are loaded as UNORM, so they are not gamma decoded on sampling
+    #[cfg(feature = "image")]
+    pub fn with_format(
+        device: ash::Device,
+        command_pool: vk::CommandPool,
+        submit_queue: &QueueGuard,
+        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+        image_path: &Path,
+        format: vk::Format,
+        max_anisotropy: f32,
+    ) -> RenderResult<Texture> {
+        let image = ImageData::load(image_path, true).map_err(RenderError::Image)?;
 
-        Texture::from_pixels(device, command_pool, submit_queue, device_memory_properties, vk::Format::R8G8B8A8_SRGB,
-                             &image_data, image_width, image_height, true)
+        Texture::from_pixels(device, command_pool, submit_queue, device_memory_properties, format,
+                             &image.pixels, image.width, image.height, true, max_anisotropy)
     }
 
     pub fn from_pixels(device: ash::Device,
                        command_pool: vk::CommandPool,
-                       submit_queue: vk::Queue,
+                       submit_queue: &QueueGuard,
                        device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
                        format: vk::Format,
-                       pixel_data: &Vec<u8>, width: u32, height: u32, create_mips: bool) -> Texture
+                       pixel_data: &Vec<u8>, width: u32, height: u32, create_mips: bool, max_anisotropy: f32) -> RenderResult<Texture>
     {
         let (texture_image, texture_image_memory, mip_levels) = create_texture_image(
-            &device, command_pool, submit_queue, device_memory_properties, format, pixel_data, width, height, 1, create_mips);
+            &device, command_pool, submit_queue, device_memory_properties, format, pixel_data, width, height, 1, create_mips)?;
 
-        let texture_image_view = create_image_view(
-            &device, texture_image, format,
-            vk::ImageAspectFlags::COLOR, mip_levels, 1);
-        let texture_sampler = create_texture_sampler2(&device, mip_levels);
+        Texture::from_image(device, texture_image, texture_image_memory, mip_levels, format, max_anisotropy)
+    }
 
-        Texture {
+    // `pixels_format` is for decoded pixels, compressed data has the format of its file
+    pub fn from_data(device: ash::Device,
+                     command_pool: vk::CommandPool,
+                     submit_queue: &QueueGuard,
+                     device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+                     data: &TextureData,
+                     pixels_format: vk::Format,
+                     max_anisotropy: f32) -> RenderResult<Texture>
+    {
+        match data {
+            TextureData::Pixels(image) => Texture::from_pixels(
+                device, command_pool, submit_queue, device_memory_properties, pixels_format,
+                &image.pixels, image.width, image.height, image.width > 1 || image.height > 1, max_anisotropy),
+            TextureData::Compressed(ktx2) => Texture::from_ktx2(
+                device, command_pool, submit_queue, device_memory_properties, ktx2, max_anisotropy),
+        }
+    }
+
+    // Format and mips come from the file, nothing is decoded or generated
+    pub fn from_ktx2(device: ash::Device,
+                     command_pool: vk::CommandPool,
+                     submit_queue: &QueueGuard,
+                     device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+                     data: &Ktx2Data,
+                     max_anisotropy: f32) -> RenderResult<Texture>
+    {
+        let (texture_image, texture_image_memory, mip_levels) = create_compressed_texture_image(
+            &device, command_pool, submit_queue, device_memory_properties, data)?;
+
+        Texture::from_image(device, texture_image, texture_image_memory, mip_levels, data.format, max_anisotropy)
+    }
+
+    // Takes the uploaded image, it is destroyed when the view or the sampler can't be created
+    fn from_image(device: ash::Device,
+                  texture_image: vk::Image,
+                  texture_image_memory: vk::DeviceMemory,
+                  mip_levels: u32,
+                  format: vk::Format,
+                  max_anisotropy: f32) -> RenderResult<Texture>
+    {
+        let texture_image_view = match create_image_view(&device, texture_image, format, vk::ImageAspectFlags::COLOR, mip_levels, 1) {
+            Ok(view) => view,
+            Err(err) => {
+                unsafe { destroy_image(&device, texture_image, texture_image_memory) };
+                return Err(err);
+            }
+        };
+        let texture_sampler = match create_texture_sampler2(&device, mip_levels, max_anisotropy) {
+            Ok(sampler) => sampler,
+            Err(err) => {
+                unsafe {
+                    device.destroy_image_view(texture_image_view, None);
+                    object_tracker::destroyed(ObjectKind::ImageView);
+                    destroy_image(&device, texture_image, texture_image_memory);
+                }
+                return Err(err);
+            }
+        };
+
+        Ok(Texture {
             device,
             texture_image,
             texture_image_memory,
@@ -66,7 +193,9 @@ impl Texture {
             texture_sampler,
             _mip_levels: mip_levels,
             format,
-        }
+            // upload leaves all mips ready for sampling
+            layout: ImageLayoutState::new(AccessPattern::SampledRead),
+        })
     }
 }
 
@@ -74,9 +203,13 @@ impl Drop for Texture {
     fn drop(&mut self) {
         unsafe {
             self.device.destroy_sampler(self.texture_sampler, None);
+            object_tracker::destroyed(ObjectKind::Sampler);
             self.device.destroy_image_view(self.texture_image_view, None);
+            object_tracker::destroyed(ObjectKind::ImageView);
             self.device.destroy_image(self.texture_image, None);
+            object_tracker::destroyed(ObjectKind::Image);
             self.device.free_memory(self.texture_image_memory, None);
+            memory_tracker::freed(self.texture_image_memory);
         }
     }
 }
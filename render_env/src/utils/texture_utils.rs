@@ -1,5 +1,11 @@
 use ash::vk;
+use crate::error::{RenderError, RenderResult};
+use crate::memory_tracker::{self, MemoryCategory};
+use crate::object_tracker::{self, ObjectKind};
+use crate::queue_guard::QueueGuard;
 use crate::utils::buffer_utils;
+use crate::utils::ktx2::Ktx2Data;
+use crate::barrier::{self, AccessPattern};
 use ash::version::DeviceV1_0;
 use std::ptr;
 use std::cmp::max;
@@ -7,7 +13,7 @@ use std::cmp::max;
 pub fn create_texture_image(
     device: &ash::Device,
     command_pool: vk::CommandPool,
-    submit_queue: vk::Queue,
+    submit_queue: &QueueGuard,
     device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
     format: vk::Format,
     image_data: &Vec<u8>,
@@ -15,7 +21,7 @@ pub fn create_texture_image(
     image_height: u32,
     array_size: u32,
     create_mips: bool,
-) -> (vk::Image, vk::DeviceMemory, u32)
+) -> RenderResult<(vk::Image, vk::DeviceMemory, u32)>
 {
     let mem_size = (std::mem::size_of::<u8>() as u32 * 4 * image_width * image_height * array_size) as vk::DeviceSize;
 
@@ -32,8 +38,12 @@ pub fn create_texture_image(
     // let mip_levels = 1;
 
 
-    if mem_size <= 0 {
-        panic!("Failed to load texture image!")
+    if mem_size == 0 {
+        return Err(RenderError::Image("texture has no pixels".to_string()));
+    }
+    if image_data.len() as vk::DeviceSize != mem_size {
+        return Err(RenderError::Image(format!(
+            "{}x{}x{} RGBA texture needs {} bytes, got {}", image_width, image_height, array_size, mem_size, image_data.len())));
     }
 
     let (staging_buffer, staging_buffer_memory) = buffer_utils::create_buffer(
@@ -45,21 +55,20 @@ pub fn create_texture_image(
     );
 
     unsafe {
-        let data_ptr = device
-            .map_memory(
-                staging_buffer_memory,
-                0,
-                mem_size,
-                vk::MemoryMapFlags::empty(),
-            )
-            .expect("Failed to Map Memory") as *mut u8;
+        let data_ptr = match device.map_memory(staging_buffer_memory, 0, mem_size, vk::MemoryMapFlags::empty()) {
+            Ok(data_ptr) => data_ptr as *mut u8,
+            Err(result) => {
+                destroy_buffer(device, staging_buffer, staging_buffer_memory);
+                return Err(RenderError::Vulkan { call: "vkMapMemory", result });
+            }
+        };
 
         data_ptr.copy_from_nonoverlapping(image_data.as_ptr(), image_data.len());
 
         device.unmap_memory(staging_buffer_memory);
     }
 
-    let (texture_image, texture_image_memory) = create_image(
+    let image = create_image(
         device,
         image_width,
         image_height,
@@ -72,15 +81,21 @@ pub fn create_texture_image(
         vk::MemoryPropertyFlags::DEVICE_LOCAL,
         device_memory_properties,
     );
+    let (texture_image, texture_image_memory) = match image {
+        Ok(image) => image,
+        Err(err) => {
+            unsafe { destroy_buffer(device, staging_buffer, staging_buffer_memory) };
+            return Err(err);
+        }
+    };
 
     transition_image_layout(
         device,
         command_pool,
         submit_queue,
         texture_image,
-        format,
-        vk::ImageLayout::UNDEFINED,
-        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        AccessPattern::Undefined,
+        AccessPattern::TransferWrite,
         mip_levels,
         array_size,
     );
@@ -96,10 +111,7 @@ pub fn create_texture_image(
         array_size,
     );
 
-    unsafe {
-        device.destroy_buffer(staging_buffer, None);
-        device.free_memory(staging_buffer_memory, None);
-    }
+    unsafe { destroy_buffer(device, staging_buffer, staging_buffer_memory) };
 
 
     generate_mipmaps(
@@ -113,13 +125,130 @@ pub fn create_texture_image(
         array_size,
     );
 
-    (texture_image, texture_image_memory, mip_levels)
+    Ok((texture_image, texture_image_memory, mip_levels))
+}
+
+// All levels of the file are uploaded, compressed images can't be blitted to generate mips
+pub fn create_compressed_texture_image(
+    device: &ash::Device,
+    command_pool: vk::CommandPool,
+    submit_queue: &QueueGuard,
+    device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    data: &Ktx2Data,
+) -> RenderResult<(vk::Image, vk::DeviceMemory, u32)>
+{
+    let mip_levels = data.levels.len() as u32;
+    let mem_size = data.levels.iter().map(|level| level.len()).sum::<usize>() as vk::DeviceSize;
+    if mem_size == 0 {
+        return Err(RenderError::Image("texture has no data".to_string()));
+    }
+
+    let (staging_buffer, staging_buffer_memory) = buffer_utils::create_buffer(
+        device,
+        mem_size,
+        vk::BufferUsageFlags::TRANSFER_SRC,
+        vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        device_memory_properties,
+    );
+
+    let mut regions = Vec::with_capacity(data.levels.len());
+    unsafe {
+        let data_ptr = match device.map_memory(staging_buffer_memory, 0, mem_size, vk::MemoryMapFlags::empty()) {
+            Ok(data_ptr) => data_ptr as *mut u8,
+            Err(result) => {
+                destroy_buffer(device, staging_buffer, staging_buffer_memory);
+                return Err(RenderError::Vulkan { call: "vkMapMemory", result });
+            }
+        };
+
+        // Ktx2Data::parse checked that level sizes are whole blocks, so every offset stays block aligned
+        let mut offset = 0;
+        for (mip_level, level) in data.levels.iter().enumerate() {
+            data_ptr.add(offset).copy_from_nonoverlapping(level.as_ptr(), level.len());
+
+            regions.push(vk::BufferImageCopy {
+                buffer_offset: offset as vk::DeviceSize,
+                buffer_row_length: 0,
+                buffer_image_height: 0,
+                image_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: mip_level as u32,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                },
+                image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+                image_extent: vk::Extent3D {
+                    width: max(data.width >> mip_level, 1),
+                    height: max(data.height >> mip_level, 1),
+                    depth: 1,
+                },
+            });
+            offset += level.len();
+        }
+
+        device.unmap_memory(staging_buffer_memory);
+    }
+
+    let image = create_image(
+        device,
+        data.width,
+        data.height,
+        1,
+        mip_levels,
+        vk::SampleCountFlags::TYPE_1,
+        data.format,
+        vk::ImageTiling::OPTIMAL,
+        vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        device_memory_properties,
+    );
+    let (texture_image, texture_image_memory) = match image {
+        Ok(image) => image,
+        Err(err) => {
+            unsafe { destroy_buffer(device, staging_buffer, staging_buffer_memory) };
+            return Err(err);
+        }
+    };
+
+    let command_buffer = buffer_utils::begin_single_time_command(device, command_pool);
+    let range = barrier::color_range(0, mip_levels, 1);
+    barrier::cmd_image_barrier(device, command_buffer, texture_image, range, AccessPattern::Undefined, AccessPattern::TransferWrite);
+    unsafe {
+        device.cmd_copy_buffer_to_image(
+            command_buffer,
+            staging_buffer,
+            texture_image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &regions,
+        );
+    }
+    barrier::cmd_image_barrier(device, command_buffer, texture_image, range, AccessPattern::TransferWrite, AccessPattern::SampledRead);
+    buffer_utils::end_single_time_command(device, command_pool, submit_queue, command_buffer);
+
+    unsafe { destroy_buffer(device, staging_buffer, staging_buffer_memory) };
+
+    Ok((texture_image, texture_image_memory, mip_levels))
+}
+
+unsafe fn destroy_buffer(device: &ash::Device, buffer: vk::Buffer, memory: vk::DeviceMemory) {
+    device.destroy_buffer(buffer, None);
+    object_tracker::destroyed(ObjectKind::Buffer);
+    device.free_memory(memory, None);
+    memory_tracker::freed(memory);
+}
+
+// Frees an image of create_image() whose texture wasn't completed
+pub(crate) unsafe fn destroy_image(device: &ash::Device, image: vk::Image, memory: vk::DeviceMemory) {
+    device.destroy_image(image, None);
+    object_tracker::destroyed(ObjectKind::Image);
+    device.free_memory(memory, None);
+    memory_tracker::freed(memory);
 }
 
 fn generate_mipmaps(
     device: &ash::Device,
     command_pool: vk::CommandPool,
-    submit_queue: vk::Queue,
+    submit_queue: &QueueGuard,
     image: vk::Image,
     tex_width: u32,
     tex_height: u32,
@@ -128,46 +257,12 @@ fn generate_mipmaps(
 ) {
     let command_buffer = buffer_utils::begin_single_time_command(device, command_pool);
 
-    let mut image_barrier = vk::ImageMemoryBarrier {
-        s_type: vk::StructureType::IMAGE_MEMORY_BARRIER,
-        p_next: ptr::null(),
-        src_access_mask: vk::AccessFlags::empty(),
-        dst_access_mask: vk::AccessFlags::empty(),
-        old_layout: vk::ImageLayout::UNDEFINED,
-        new_layout: vk::ImageLayout::UNDEFINED,
-        src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
-        dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
-        image,
-        subresource_range: vk::ImageSubresourceRange {
-            aspect_mask: vk::ImageAspectFlags::COLOR,
-            base_mip_level: 0,
-            level_count: 1,
-            base_array_layer: 0,
-            layer_count,
-        },
-    };
-
     let mut mip_width = tex_width as i32;
     let mut mip_height = tex_height as i32;
 
     for i in 1..mip_levels {
-        image_barrier.subresource_range.base_mip_level = i - 1;
-        image_barrier.old_layout = vk::ImageLayout::TRANSFER_DST_OPTIMAL;
-        image_barrier.new_layout = vk::ImageLayout::TRANSFER_SRC_OPTIMAL;
-        image_barrier.src_access_mask = vk::AccessFlags::TRANSFER_WRITE;
-        image_barrier.dst_access_mask = vk::AccessFlags::TRANSFER_READ;
-
-        unsafe {
-            device.cmd_pipeline_barrier(
-                command_buffer,
-                vk::PipelineStageFlags::TRANSFER,
-                vk::PipelineStageFlags::TRANSFER,
-                vk::DependencyFlags::empty(),
-                &[],
-                &[],
-                &[image_barrier.clone()],
-            );
-        }
+        barrier::cmd_image_barrier(device, command_buffer, image, barrier::color_range(i - 1, 1, layer_count),
+                                   AccessPattern::TransferWrite, AccessPattern::TransferRead);
 
         let blits = [vk::ImageBlit {
             src_subresource: vk::ImageSubresourceLayers {
@@ -212,44 +307,15 @@ fn generate_mipmaps(
             );
         }
 
-        image_barrier.old_layout = vk::ImageLayout::TRANSFER_SRC_OPTIMAL;
-        image_barrier.new_layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
-        image_barrier.src_access_mask = vk::AccessFlags::TRANSFER_READ;
-        image_barrier.dst_access_mask = vk::AccessFlags::SHADER_READ;
-
-        unsafe {
-            device.cmd_pipeline_barrier(
-                command_buffer,
-                vk::PipelineStageFlags::TRANSFER,
-                vk::PipelineStageFlags::FRAGMENT_SHADER,
-                vk::DependencyFlags::empty(),
-                &[],
-                &[],
-                &[image_barrier.clone()],
-            );
-        }
+        barrier::cmd_image_barrier(device, command_buffer, image, barrier::color_range(i - 1, 1, layer_count),
+                                   AccessPattern::TransferRead, AccessPattern::SampledRead);
 
         mip_width = max(mip_width / 2, 1);
         mip_height = max(mip_height / 2, 1);
     }
 
-    image_barrier.subresource_range.base_mip_level = mip_levels - 1;
-    image_barrier.old_layout = vk::ImageLayout::TRANSFER_DST_OPTIMAL;
-    image_barrier.new_layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
-    image_barrier.src_access_mask = vk::AccessFlags::TRANSFER_WRITE;
-    image_barrier.dst_access_mask = vk::AccessFlags::SHADER_READ;
-
-    unsafe {
-        device.cmd_pipeline_barrier(
-            command_buffer,
-            vk::PipelineStageFlags::TRANSFER,
-            vk::PipelineStageFlags::FRAGMENT_SHADER,
-            vk::DependencyFlags::empty(),
-            &[],
-            &[],
-            &[image_barrier.clone()],
-        );
-    }
+    barrier::cmd_image_barrier(device, command_buffer, image, barrier::color_range(mip_levels - 1, 1, layer_count),
+                               AccessPattern::TransferWrite, AccessPattern::SampledRead);
 
     buffer_utils::end_single_time_command(device, command_pool, submit_queue, command_buffer);
 }
@@ -266,7 +332,7 @@ pub fn create_image(
     usage: vk::ImageUsageFlags,
     required_memory_properties: vk::MemoryPropertyFlags,
     device_memory_properties: &vk::PhysicalDeviceMemoryProperties,
-) -> (vk::Image, vk::DeviceMemory) {
+) -> RenderResult<(vk::Image, vk::DeviceMemory)> {
     let flags = if array_size == 6 {
         vk::ImageCreateFlags::CUBE_COMPATIBLE
     } else {
@@ -298,8 +364,9 @@ pub fn create_image(
     let texture_image = unsafe {
         device
             .create_image(&image_create_info, None)
-            .expect("Failed to create Texture Image!")
+            .map_err(RenderError::vulkan("vkCreateImage"))?
     };
+    object_tracker::created(ObjectKind::Image);
 
     let image_memory_requirement =
         unsafe { device.get_image_memory_requirements(texture_image) };
@@ -314,98 +381,38 @@ pub fn create_image(
         ),
     };
 
-    let texture_image_memory = unsafe {
-        device
-            .allocate_memory(&memory_allocate_info, None)
-            .expect("Failed to allocate Texture Image memory!")
+    let texture_image_memory = match unsafe { device.allocate_memory(&memory_allocate_info, None) } {
+        Ok(memory) => memory,
+        Err(result) => {
+            unsafe { device.destroy_image(texture_image, None) };
+            object_tracker::destroyed(ObjectKind::Image);
+            return Err(RenderError::Vulkan { call: "vkAllocateMemory", result });
+        }
     };
+    memory_tracker::allocated(texture_image_memory, image_memory_requirement.size, MemoryCategory::Textures);
 
-    unsafe {
-        device
-            .bind_image_memory(texture_image, texture_image_memory, 0)
-            .expect("Failed to bind Image Memmory!");
+    if let Err(result) = unsafe { device.bind_image_memory(texture_image, texture_image_memory, 0) } {
+        unsafe { destroy_image(device, texture_image, texture_image_memory) };
+        return Err(RenderError::Vulkan { call: "vkBindImageMemory", result });
     }
 
-    (texture_image, texture_image_memory)
+    Ok((texture_image, texture_image_memory))
 }
 
 
 fn transition_image_layout(
     device: &ash::Device,
     command_pool: vk::CommandPool,
-    submit_queue: vk::Queue,
+    submit_queue: &QueueGuard,
     image: vk::Image,
-    _format: vk::Format,
-    old_layout: vk::ImageLayout,
-    new_layout: vk::ImageLayout,
+    from: AccessPattern,
+    to: AccessPattern,
     mip_levels: u32,
     layer_count: u32,
 ) {
     let command_buffer = buffer_utils::begin_single_time_command(device, command_pool);
 
-    let src_access_mask;
-    let dst_access_mask;
-    let source_stage;
-    let destination_stage;
-
-    if old_layout == vk::ImageLayout::UNDEFINED
-        && new_layout == vk::ImageLayout::TRANSFER_DST_OPTIMAL
-    {
-        src_access_mask = vk::AccessFlags::empty();
-        dst_access_mask = vk::AccessFlags::TRANSFER_WRITE;
-        source_stage = vk::PipelineStageFlags::TOP_OF_PIPE;
-        destination_stage = vk::PipelineStageFlags::TRANSFER;
-    } else if old_layout == vk::ImageLayout::TRANSFER_DST_OPTIMAL
-        && new_layout == vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
-    {
-        src_access_mask = vk::AccessFlags::TRANSFER_WRITE;
-        dst_access_mask = vk::AccessFlags::SHADER_READ;
-        source_stage = vk::PipelineStageFlags::TRANSFER;
-        destination_stage = vk::PipelineStageFlags::FRAGMENT_SHADER;
-    } else if old_layout == vk::ImageLayout::UNDEFINED
-        && new_layout == vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
-    {
-        src_access_mask = vk::AccessFlags::empty();
-        dst_access_mask =
-            vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE;
-        source_stage = vk::PipelineStageFlags::TOP_OF_PIPE;
-        destination_stage = vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT;
-    } else {
-        panic!("Unsupported layout transition!")
-    }
-
-    let image_barriers = [
-        vk::ImageMemoryBarrier {
-            s_type: vk::StructureType::IMAGE_MEMORY_BARRIER,
-            p_next: ptr::null(),
-            src_access_mask,
-            dst_access_mask,
-            old_layout,
-            new_layout,
-            src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
-            dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
-            image,
-            subresource_range: vk::ImageSubresourceRange {
-                aspect_mask: vk::ImageAspectFlags::COLOR,
-                base_mip_level: 0,
-                level_count: mip_levels,
-                base_array_layer: 0,
-                layer_count,
-            },
-        }
-    ];
-
-    unsafe {
-        device.cmd_pipeline_barrier(
-            command_buffer,
-            source_stage,
-            destination_stage,
-            vk::DependencyFlags::empty(),
-            &[],
-            &[],
-            &image_barriers,
-        );
-    }
+    barrier::cmd_image_barrier(device, command_buffer, image, barrier::color_range(0, mip_levels, layer_count), from, to);
 
     buffer_utils::end_single_time_command(device, command_pool, submit_queue, command_buffer);
 }
@@ -414,7 +421,7 @@ fn transition_image_layout(
 fn copy_buffer_to_image(
     device: &ash::Device,
     command_pool: vk::CommandPool,
-    submit_queue: vk::Queue,
+    submit_queue: &QueueGuard,
     buffer: vk::Buffer,
     image: vk::Image,
     width: u32,
@@ -461,7 +468,7 @@ pub fn create_image_view(
     aspect_mask: vk::ImageAspectFlags,
     mip_levels: u32,
     layer_count: u32,
-) -> vk::ImageView {
+) -> RenderResult<vk::ImageView> {
     let view_type = if layer_count == 6 {
         vk::ImageViewType::CUBE
     } else {
@@ -489,15 +496,19 @@ pub fn create_image_view(
         image,
     };
 
-    unsafe {
+    let image_view = unsafe {
         device
             .create_image_view(&imageview_create_info, None)
-            .expect("Failed to create Image View!")
-    }
+            .map_err(RenderError::vulkan("vkCreateImageView"))?
+    };
+    object_tracker::created(ObjectKind::ImageView);
+
+    Ok(image_view)
 }
 
 
-pub fn create_texture_sampler(device: &ash::Device, mip_levels: u32) -> vk::Sampler {
+// `max_anisotropy` of 1.0 turns anisotropic filtering off, see RenderEnv::max_sampler_anisotropy
+pub fn create_texture_sampler(device: &ash::Device, mip_levels: u32, max_anisotropy: f32) -> RenderResult<vk::Sampler> {
     let sampler_create_info = vk::SamplerCreateInfo {
         s_type: vk::StructureType::SAMPLER_CREATE_INFO,
         p_next: ptr::null(),
@@ -507,8 +518,8 @@ pub fn create_texture_sampler(device: &ash::Device, mip_levels: u32) -> vk::Samp
         address_mode_u: vk::SamplerAddressMode::CLAMP_TO_EDGE,
         address_mode_v: vk::SamplerAddressMode::CLAMP_TO_EDGE,
         address_mode_w: vk::SamplerAddressMode::CLAMP_TO_EDGE,
-        anisotropy_enable: vk::TRUE,
-        max_anisotropy: 16.0,
+        anisotropy_enable: (max_anisotropy > 1.0) as vk::Bool32,
+        max_anisotropy,
         compare_enable: vk::FALSE,
         compare_op: vk::CompareOp::NEVER,
 
@@ -521,14 +532,17 @@ pub fn create_texture_sampler(device: &ash::Device, mip_levels: u32) -> vk::Samp
         unnormalized_coordinates: vk::FALSE,
     };
 
-    unsafe {
+    let sampler = unsafe {
         device
             .create_sampler(&sampler_create_info, None)
-            .expect("Failed to create Sampler!")
-    }
+            .map_err(RenderError::vulkan("vkCreateSampler"))?
+    };
+    object_tracker::created(ObjectKind::Sampler);
+
+    Ok(sampler)
 }
 
-pub fn create_texture_sampler2(device: &ash::Device, mip_levels: u32) -> vk::Sampler {
+pub fn create_texture_sampler2(device: &ash::Device, mip_levels: u32, max_anisotropy: f32) -> RenderResult<vk::Sampler> {
     let sampler_create_info = vk::SamplerCreateInfo {
         s_type: vk::StructureType::SAMPLER_CREATE_INFO,
         p_next: ptr::null(),
@@ -538,8 +552,8 @@ pub fn create_texture_sampler2(device: &ash::Device, mip_levels: u32) -> vk::Sam
         address_mode_u: vk::SamplerAddressMode::REPEAT,
         address_mode_v: vk::SamplerAddressMode::REPEAT,
         address_mode_w: vk::SamplerAddressMode::REPEAT,
-        anisotropy_enable: vk::TRUE,
-        max_anisotropy: 16.0,
+        anisotropy_enable: (max_anisotropy > 1.0) as vk::Bool32,
+        max_anisotropy,
         compare_enable: vk::FALSE,
         compare_op: vk::CompareOp::NEVER,
 
@@ -552,9 +566,12 @@ pub fn create_texture_sampler2(device: &ash::Device, mip_levels: u32) -> vk::Sam
         unnormalized_coordinates: vk::FALSE,
     };
 
-    unsafe {
+    let sampler = unsafe {
         device
             .create_sampler(&sampler_create_info, None)
-            .expect("Failed to create Sampler!")
-    }
+            .map_err(RenderError::vulkan("vkCreateSampler"))?
+    };
+    object_tracker::created(ObjectKind::Sampler);
+
+    Ok(sampler)
 }
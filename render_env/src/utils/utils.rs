@@ -1,41 +1,9 @@
-use ash::version::InstanceV1_0;
 use ash::vk;
 
 use crate::env::RenderEnv;
+use crate::swapchain::OutputFormat;
 use winit::dpi::PhysicalSize;
 
-pub fn get_max_usable_sample_count(env: &RenderEnv) -> vk::SampleCountFlags {
-    let physical_device_properties =
-        unsafe { env.instance.get_physical_device_properties(env.physical_device) };
-
-    let max_sample_count = std::cmp::min(
-        physical_device_properties
-            .limits
-            .framebuffer_color_sample_counts,
-        physical_device_properties
-            .limits
-            .framebuffer_depth_sample_counts,
-    );
-
-    let all_samples = [
-        vk::SampleCountFlags::TYPE_64,
-        vk::SampleCountFlags::TYPE_32,
-        vk::SampleCountFlags::TYPE_16,
-        vk::SampleCountFlags::TYPE_8,
-        vk::SampleCountFlags::TYPE_4,
-        vk::SampleCountFlags::TYPE_2,
-    ];
-
-    for candidate in all_samples {
-        if max_sample_count.contains(candidate) {
-            return candidate;
-        }
-    }
-
-    vk::SampleCountFlags::TYPE_1
-}
-
-
 #[derive(Clone)]
 pub struct SwapChainSupportDetail {
     pub capabilities: vk::SurfaceCapabilitiesKHR,
@@ -67,29 +35,42 @@ impl SwapChainSupportDetail {
         }
     }
 
-    pub fn format(&self) -> vk::SurfaceFormatKHR {
-        for available_format in self.formats.iter() {
-            if available_format.format == vk::Format::B8G8R8A8_SRGB
-                && available_format.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
-            {
-                return available_format.clone();
+    // The surface still reports this format and color space, e.g. after the window moved to another monitor
+    pub fn supports_format(&self, format: vk::Format, color_space: vk::ColorSpaceKHR) -> bool {
+        self.formats.iter().any(|f| f.format == format && f.color_space == color_space)
+    }
+
+    // The first supported format of the fallback chain of `preferred`, with the output it belongs to.
+    // None when the surface supports none of them and its first format is used
+    pub fn format(&self, preferred: OutputFormat) -> (vk::SurfaceFormatKHR, Option<OutputFormat>) {
+        for &output in preferred.fallbacks() {
+            for &(format, color_space) in output.surface_formats() {
+                if let Some(available_format) = self.formats.iter().find(|f| f.format == format && f.color_space == color_space) {
+                    if output != preferred {
+                        println!("Output format {} is not supported, using {}", preferred.name(), output.name());
+                    }
+                    return (*available_format, Some(output));
+                }
             }
         }
 
         // return the first format from the list
-        return self.formats.first().unwrap().clone();
+        (*self.formats.first().unwrap(), None)
     }
 
-    pub fn present_mode(&self) -> vk::PresentModeKHR {
-        if self.present_modes.contains(&vk::PresentModeKHR::MAILBOX) {
-            return vk::PresentModeKHR::MAILBOX;
+    // FIFO is the only mode required by the spec
+    pub fn present_mode(&self, preferred: vk::PresentModeKHR) -> vk::PresentModeKHR {
+        if self.present_modes.contains(&preferred) {
+            return preferred;
         }
 
+        println!("Present mode {:?} is not supported, using FIFO", preferred);
         return vk::PresentModeKHR::FIFO;
     }
 
+    // Never zero: minimized windows report a 0x0 extent, callers shouldn't create a swapchain for them at all
     pub fn adjust_extent(&self, size: PhysicalSize<u32>) -> vk::Extent2D {
-        if self.capabilities.current_extent.width != u32::MAX {
+        let extent = if self.capabilities.current_extent.width != u32::MAX {
             self.capabilities.current_extent
         } else {
             use num::clamp;
@@ -106,6 +87,11 @@ impl SwapChainSupportDetail {
                     self.capabilities.max_image_extent.height,
                 ),
             }
+        };
+
+        vk::Extent2D {
+            width: extent.width.max(1),
+            height: extent.height.max(1),
         }
     }
 
@@ -133,3 +119,93 @@ pub fn format_has_depth(format: vk::Format) -> bool {
         vk::Format::D32_SFLOAT_S8_UINT,
     ].contains(&format)
 }
+
+#[inline]
+pub fn format_has_stencil(format: vk::Format) -> bool {
+    [
+        vk::Format::S8_UINT,
+        vk::Format::D16_UNORM_S8_UINT,
+        vk::Format::D24_UNORM_S8_UINT,
+        vk::Format::D32_SFLOAT_S8_UINT,
+    ].contains(&format)
+}
+
+#[inline]
+pub fn format_is_srgb(format: vk::Format) -> bool {
+    [
+        vk::Format::B8G8R8A8_SRGB,
+        vk::Format::R8G8B8A8_SRGB,
+        vk::Format::A8B8G8R8_SRGB_PACK32,
+        vk::Format::B8G8R8_SRGB,
+        vk::Format::R8G8B8_SRGB,
+    ].contains(&format)
+}
+
+// Depth convention of the camera passes. Reverse-Z maps the near plane to 1 and the far plane to 0:
+// float depth precision grows towards zero, so it compensates the perspective and far geometry
+// stops fighting. Only makes sense with a float depth format (D32_SFLOAT)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DepthMode {
+    #[default]
+    Standard,
+    ReverseZ,
+}
+
+impl DepthMode {
+    pub fn is_reversed(&self) -> bool {
+        *self == DepthMode::ReverseZ
+    }
+
+    // Compare op written for the standard depth
+    pub fn compare_op(&self, op: vk::CompareOp) -> vk::CompareOp {
+        if !self.is_reversed() {
+            return op;
+        }
+
+        match op {
+            vk::CompareOp::LESS => vk::CompareOp::GREATER,
+            vk::CompareOp::LESS_OR_EQUAL => vk::CompareOp::GREATER_OR_EQUAL,
+            vk::CompareOp::GREATER => vk::CompareOp::LESS,
+            vk::CompareOp::GREATER_OR_EQUAL => vk::CompareOp::LESS_OR_EQUAL,
+            op => op,
+        }
+    }
+
+    // Depth buffer value of the far plane, what is left where nothing was drawn
+    pub fn clear_depth(&self) -> f32 {
+        if self.is_reversed() { 0.0 } else { 1.0 }
+    }
+
+    pub fn clear_value(&self) -> vk::ClearValue {
+        vk::ClearValue {
+            depth_stencil: vk::ClearDepthStencilValue {
+                depth: self.clear_depth(),
+                stencil: 0,
+            }
+        }
+    }
+
+    // NDC z of the near and far planes. The standard projection is cgmath's OpenGL one (-1..1),
+    // the reversed one is built for the Vulkan 0..1 range
+    pub fn ndc_near(&self) -> f32 {
+        if self.is_reversed() { 1.0 } else { -1.0 }
+    }
+
+    pub fn ndc_far(&self) -> f32 {
+        if self.is_reversed() { 0.0 } else { 1.0 }
+    }
+
+    // View distance of a perspective depth buffer value, the inverse of the camera projection
+    pub fn view_distance(&self, depth: f32, near: f32, far: f32) -> f32 {
+        if self.is_reversed() {
+            near * far / (depth * (far - near) + near)
+        } else {
+            2.0 * near * far / (far + near - depth * (far - near))
+        }
+    }
+}
+
+#[inline]
+pub fn format_is_float_depth(format: vk::Format) -> bool {
+    format == vk::Format::D32_SFLOAT || format == vk::Format::D32_SFLOAT_S8_UINT
+}
@@ -0,0 +1,200 @@
+#[cfg(feature = "image")]
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use winit::dpi::PhysicalSize;
+use winit::window::{Fullscreen, Window};
+
+// set_title is slow on some platforms, the title is changed at most this often
+const TITLE_UPDATE_INTERVAL: Duration = Duration::from_millis(500);
+
+// Title and icon of the app window. The title is made of the base title, scene name,
+// FPS and loading progress. winit has no taskbar progress, so progress goes to the title too
+pub struct WindowDecorations {
+    base_title: String,
+    scene: Option<String>,
+    fps: Option<f32>,
+    // 0..1, None when nothing is loading
+    progress: Option<f32>,
+
+    current_title: String,
+    last_update: Option<Instant>,
+}
+
+impl WindowDecorations {
+    pub fn new(base_title: &str) -> WindowDecorations {
+        WindowDecorations {
+            base_title: base_title.to_string(),
+            scene: None,
+            fps: None,
+            progress: None,
+            current_title: base_title.to_string(),
+            last_update: None,
+        }
+    }
+
+    pub fn set_scene(&mut self, scene: Option<&str>) {
+        self.scene = scene.map(str::to_string);
+    }
+
+    pub fn set_fps(&mut self, fps: f32) {
+        self.fps = Some(fps);
+    }
+
+    pub fn set_progress(&mut self, progress: Option<f32>) {
+        self.progress = progress.map(|progress| progress.clamp(0.0, 1.0));
+    }
+
+    pub fn title(&self) -> String {
+        let mut title = self.base_title.clone();
+
+        if let Some(scene) = &self.scene {
+            title += &format!(" - {}", scene);
+        }
+        if let Some(progress) = self.progress {
+            title += &format!(" - loading {:.0}%", progress * 100.0);
+        } else if let Some(fps) = self.fps {
+            title += &format!(" - {:.0} fps", fps);
+        }
+
+        title
+    }
+
+    // Call once per frame, the window is touched only when the title changed
+    pub fn update(&mut self, wnd: &Window) {
+        if self.last_update.is_some_and(|time| time.elapsed() < TITLE_UPDATE_INTERVAL) {
+            return;
+        }
+
+        let title = self.title();
+        if title != self.current_title {
+            wnd.set_title(&title);
+            self.current_title = title;
+        }
+        self.last_update = Some(Instant::now());
+    }
+
+    // Any image the `image` crate reads, 32-64px squares look right on all platforms
+    #[cfg(feature = "image")]
+    pub fn set_icon(wnd: &Window, path: &Path) -> Result<(), String> {
+        let image = image::open(path)
+            .map_err(|err| format!("Failed to load icon {}: {}", path.display(), err))?
+            .to_rgba8();
+        let (width, height) = image.dimensions();
+
+        let icon = winit::window::Icon::from_rgba(image.into_raw(), width, height)
+            .map_err(|err| format!("Bad icon {}: {}", path.display(), err))?;
+        wnd.set_window_icon(Some(icon));
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum WindowMode {
+    Windowed,
+    // fullscreen window at the desktop resolution, switches fast
+    Borderless,
+    // the monitor is switched to the picked resolution
+    Exclusive,
+}
+
+impl WindowMode {
+    pub const ALL: [WindowMode; 3] = [WindowMode::Windowed, WindowMode::Borderless, WindowMode::Exclusive];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WindowMode::Windowed => "windowed",
+            WindowMode::Borderless => "borderless",
+            WindowMode::Exclusive => "exclusive",
+        }
+    }
+}
+
+// Window mode and resolution picked by the app, applied to the window by apply(). The swapchain
+// follows through the resize events the window gets
+pub struct WindowModeSwitch {
+    pub mode: WindowMode,
+    // inner size when windowed, video mode when exclusive, borderless uses the desktop one
+    pub resolution: PhysicalSize<u32>,
+    // fullscreen mode F11 goes back to
+    last_fullscreen: WindowMode,
+    // video mode sizes of the monitor of the window, largest first
+    resolutions: Vec<PhysicalSize<u32>>,
+    applied: (WindowMode, PhysicalSize<u32>),
+}
+
+impl WindowModeSwitch {
+    pub fn new(wnd: &Window) -> WindowModeSwitch {
+        let mut resolutions: Vec<_> = wnd.current_monitor()
+            .map(|monitor| monitor.video_modes().map(|mode| mode.size()).collect())
+            .unwrap_or_default();
+        resolutions.sort_by_key(|size| std::cmp::Reverse((size.width, size.height)));
+        resolutions.dedup();
+
+        WindowModeSwitch {
+            mode: WindowMode::Windowed,
+            resolution: wnd.inner_size(),
+            last_fullscreen: WindowMode::Borderless,
+            resolutions,
+            applied: (WindowMode::Windowed, wnd.inner_size()),
+        }
+    }
+
+    pub fn resolutions(&self) -> &[PhysicalSize<u32>] {
+        &self.resolutions
+    }
+
+    // Window size set by the user in windowed mode is kept for switching back from fullscreen
+    pub fn window_resized(&mut self, size: PhysicalSize<u32>) {
+        if self.mode == WindowMode::Windowed && self.applied.0 == WindowMode::Windowed && size.width > 0 && size.height > 0 {
+            self.resolution = size;
+            self.applied.1 = size;
+        }
+    }
+
+    // Between windowed and the last used fullscreen mode, for a hotkey
+    pub fn toggle_fullscreen(&mut self) {
+        self.mode = match self.mode {
+            WindowMode::Windowed => self.last_fullscreen,
+            _ => WindowMode::Windowed,
+        };
+    }
+
+    // Call with the window once per frame, returns true when the window was changed
+    pub fn apply(&mut self, wnd: &Window) -> bool {
+        if (self.mode, self.resolution) == self.applied {
+            return false;
+        }
+
+        match self.mode {
+            WindowMode::Windowed => {
+                wnd.set_fullscreen(None);
+                wnd.set_inner_size(self.resolution);
+            }
+            WindowMode::Borderless => wnd.set_fullscreen(Some(Fullscreen::Borderless(wnd.current_monitor()))),
+            WindowMode::Exclusive => {
+                let resolution = self.resolution;
+                let video_mode = wnd.current_monitor().and_then(|monitor| monitor.video_modes()
+                    .filter(|mode| mode.size() == resolution)
+                    .max_by_key(|mode| (mode.bit_depth(), mode.refresh_rate())));
+
+                match video_mode {
+                    Some(video_mode) => wnd.set_fullscreen(Some(Fullscreen::Exclusive(video_mode))),
+                    None => {
+                        println!("No {}x{} video mode, using borderless fullscreen", resolution.width, resolution.height);
+                        self.mode = WindowMode::Borderless;
+                        wnd.set_fullscreen(Some(Fullscreen::Borderless(wnd.current_monitor())));
+                    }
+                }
+            }
+        }
+
+        if self.mode != WindowMode::Windowed {
+            self.last_fullscreen = self.mode;
+        }
+        self.applied = (self.mode, self.resolution);
+
+        true
+    }
+}